@@ -0,0 +1,102 @@
+//! Component for a BLE GATT server driven by apps.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!     let ble_ll = nrf52_components::BLELinkLayerComponent::new(
+//!         &base_peripherals.ble_radio,
+//!         mux_alarm,
+//!     )
+//!     .finalize(());
+//!
+//!     let gatt = components::ble_gatt::BleGattComponent::new(board_kernel, ble_ll, b"Tock")
+//!         .finalize(components::ble_gatt_component_helper!(
+//!             capsules::ble_link_layer::BleLinkLayer<
+//!                 'static,
+//!                 nrf52::ble_radio::Radio,
+//!                 VirtualMuxAlarm<'static, nrf52::rtc::Rtc>,
+//!             >
+//!         ));
+//! ```
+
+use capsules::ble_gatt::server::{self, Attribute, GattServer};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::ble_connection::BleConnection;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ble_gatt_component_helper {
+    ($B:ty $(,)?) => {{
+        use capsules::ble_gatt::server;
+        use core::mem::MaybeUninit;
+        static mut SERVER: MaybeUninit<server::GattServer<'static, $B>> = MaybeUninit::uninit();
+        static mut ATTRIBUTES: [server::Attribute; server::MAX_ATTRIBUTES] =
+            [server::Attribute::EMPTY; server::MAX_ATTRIBUTES];
+        static mut RX_BUFFER: [u8; server::L2CAP_BUFFER_LEN] = [0; server::L2CAP_BUFFER_LEN];
+        static mut TX_BUFFER: [u8; server::L2CAP_BUFFER_LEN] = [0; server::L2CAP_BUFFER_LEN];
+        static mut FRAGMENT: [u8; server::FRAGMENT_LEN] = [0; server::FRAGMENT_LEN];
+        (
+            &mut SERVER,
+            &mut ATTRIBUTES,
+            &mut RX_BUFFER,
+            &mut TX_BUFFER,
+            &mut FRAGMENT,
+        )
+    };};
+}
+
+pub struct BleGattComponent<B: 'static + BleConnection<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    ble: &'static B,
+    device_name: &'static [u8],
+}
+
+impl<B: 'static + BleConnection<'static>> BleGattComponent<B> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        ble: &'static B,
+        device_name: &'static [u8],
+    ) -> BleGattComponent<B> {
+        BleGattComponent {
+            board_kernel,
+            ble,
+            device_name,
+        }
+    }
+}
+
+impl<B: 'static + BleConnection<'static>> Component for BleGattComponent<B> {
+    type StaticInput = (
+        &'static mut MaybeUninit<GattServer<'static, B>>,
+        &'static mut [Attribute; server::MAX_ATTRIBUTES],
+        &'static mut [u8; server::L2CAP_BUFFER_LEN],
+        &'static mut [u8; server::L2CAP_BUFFER_LEN],
+        &'static mut [u8; server::FRAGMENT_LEN],
+    );
+    type Output = &'static GattServer<'static, B>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let gatt = static_init_half!(
+            s.0,
+            GattServer<'static, B>,
+            GattServer::new(
+                self.ble,
+                self.board_kernel.create_grant(&grant_cap),
+                self.device_name,
+                s.1,
+                s.2,
+                s.3,
+                s.4,
+            )
+        );
+        self.ble.set_client(gatt);
+
+        gatt
+    }
+}
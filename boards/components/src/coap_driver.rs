@@ -0,0 +1,164 @@
+//! Component to initialize the userland CoAP driver.
+//!
+//! This provides one Component, CoapDriverComponent. This component binds the
+//! CoAP port (5683) and initializes a userspace CoAP driver that allows apps
+//! to serve resources and send requests.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let coap_driver = CoapDriverComponent::new(
+//!        board_kernel,
+//!        udp_send_mux,
+//!        udp_recv_mux,
+//!        udp_port_table,
+//!        mux_alarm,
+//!        rng,
+//!     )
+//!     .finalize(components::coap_driver_component_helper!(sam4l::ast::Ast));
+//! ```
+
+use capsules;
+use capsules::net::coap::driver::{CoapDriver, COAP_BUF_SIZE};
+use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules::net::network_capabilities::{
+    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::udp::udp_port_table::UdpPortManager;
+use capsules::net::udp::udp_recv::MuxUdpReceiver;
+use capsules::net::udp::udp_recv::UDPReceiver;
+use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::mem::MaybeUninit;
+use kernel;
+use kernel::capabilities;
+use kernel::capabilities::NetworkCapabilityCreationCapability;
+use kernel::component::Component;
+use kernel::hil::rng::Random;
+use kernel::hil::time::Alarm;
+use kernel::{create_capability, static_init, static_init_half};
+
+static mut TX_BUF: [u8; COAP_BUF_SIZE] = [0; COAP_BUF_SIZE];
+static mut CON_BUF: [u8; COAP_BUF_SIZE] = [0; COAP_BUF_SIZE];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! coap_driver_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::net::coap::driver::CoapDriver;
+        use capsules::net::udp::udp_send::UDPSendStruct;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<
+            UDPSendStruct<
+                'static,
+                capsules::net::ipv6::ipv6_send::IP6SendStruct<
+                    'static,
+                    VirtualMuxAlarm<'static, $A>,
+                >,
+            >,
+        > = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<CoapDriver<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct CoapDriverComponent<A: Alarm<'static> + 'static> {
+    board_kernel: &'static kernel::Kernel,
+    udp_send_mux:
+        &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    port_table: &'static UdpPortManager,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    rng: &'static dyn Random<'static>,
+}
+
+impl<A: Alarm<'static>> CoapDriverComponent<A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        udp_send_mux: &'static MuxUdpSender<
+            'static,
+            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+        >,
+        udp_recv_mux: &'static MuxUdpReceiver<'static>,
+        port_table: &'static UdpPortManager,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        rng: &'static dyn Random<'static>,
+    ) -> Self {
+        Self {
+            board_kernel,
+            udp_send_mux,
+            udp_recv_mux,
+            port_table,
+            alarm_mux,
+            rng,
+        }
+    }
+}
+
+impl<A: Alarm<'static>> Component for CoapDriverComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<
+            UDPSendStruct<
+                'static,
+                capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+            >,
+        >,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<CoapDriver<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static CoapDriver<'static, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let create_cap = create_capability!(NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        let udp_send = static_init_half!(
+            static_buffer.0,
+            UDPSendStruct<
+                'static,
+                capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+            >,
+            UDPSendStruct::new(self.udp_send_mux, udp_vis)
+        );
+        let udp_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new());
+        self.udp_recv_mux.add_client(udp_recv);
+
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(AddrRange::Any, PortRange::Any, PortRange::Any, &create_cap)
+        );
+
+        let coap_alarm = static_init_half!(
+            static_buffer.1,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        let coap_driver = static_init_half!(
+            static_buffer.2,
+            CoapDriver<'static, VirtualMuxAlarm<'static, A>>,
+            CoapDriver::new(
+                udp_send,
+                udp_recv,
+                self.port_table,
+                coap_alarm,
+                self.rng,
+                self.board_kernel.create_grant(&grant_cap),
+                &mut TX_BUF,
+                &mut CON_BUF,
+                net_cap,
+            )
+        );
+        udp_send.set_client(coap_driver);
+        udp_recv.set_client(coap_driver);
+        coap_alarm.set_alarm_client(coap_driver);
+        coap_driver.start().expect("CoAP: failed to bind port 5683");
+        coap_driver
+    }
+}
@@ -22,35 +22,57 @@
 //!         board_kernel,
 //!         ctap_send_buffer,
 //!         ctap_recv_buffer,
+//!         mux_alarm,
+//!         dynamic_deferred_caller,
 //!     )
-//!     .finalize(components::usb_ctap_component_helper!(lowrisc::usbdev::Usb));
+//!     .finalize(components::usb_ctap_component_helper!(
+//!         lowrisc::usbdev::Usb,
+//!         earlgrey::timer::RvTimer
+//!     ));
 //!
 //!     ctap.enable();
 //!     ctap.attach();
 //! ```
 
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil;
+use kernel::hil::time::Alarm;
 use kernel::static_init_half;
 
 // Setup static space for the objects.
 #[macro_export]
 macro_rules! usb_ctap_component_helper {
-    ($U:ty $(,)?) => {{
+    ($U:ty, $A:ty $(,)?) => {{
+        use capsules::virtual_alarm::VirtualMuxAlarm;
         use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
         static mut BUF1: MaybeUninit<capsules::usb::ctap::CtapHid<'static, $U>> =
             MaybeUninit::uninit();
         static mut BUF2: MaybeUninit<
-            capsules::ctap::CtapDriver<'static, capsules::usb::ctap::CtapHid<'static, $U>>,
+            capsules::ctap::CtapHidTransport<
+                'static,
+                capsules::usb::ctap::CtapHid<'static, $U>,
+                VirtualMuxAlarm<'static, $A>,
+            >,
         > = MaybeUninit::uninit();
-        (&mut BUF1, &mut BUF2)
+        static mut BUF3: MaybeUninit<
+            capsules::ctap::CtapDriver<
+                'static,
+                capsules::usb::ctap::CtapHid<'static, $U>,
+                VirtualMuxAlarm<'static, $A>,
+            >,
+        > = MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2, &mut BUF3)
     };};
 }
 
-pub struct CtapComponent<U: 'static + hil::usb::UsbController<'static>> {
+pub struct CtapComponent<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>>
+{
     usb: &'static U,
     vendor_id: u16,
     product_id: u16,
@@ -58,9 +80,13 @@ pub struct CtapComponent<U: 'static + hil::usb::UsbController<'static>> {
     board_kernel: &'static kernel::Kernel,
     send_buffer: &'static mut [u8; 64],
     recv_buffer: &'static mut [u8; 64],
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    deferred_caller: &'static DynamicDeferredCall,
 }
 
-impl<U: 'static + hil::usb::UsbController<'static>> CtapComponent<U> {
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>>
+    CtapComponent<U, A>
+{
     pub fn new(
         usb: &'static U,
         vendor_id: u16,
@@ -69,7 +95,9 @@ impl<U: 'static + hil::usb::UsbController<'static>> CtapComponent<U> {
         board_kernel: &'static kernel::Kernel,
         send_buffer: &'static mut [u8; 64],
         recv_buffer: &'static mut [u8; 64],
-    ) -> CtapComponent<U> {
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> CtapComponent<U, A> {
         CtapComponent {
             usb,
             vendor_id,
@@ -78,25 +106,51 @@ impl<U: 'static + hil::usb::UsbController<'static>> CtapComponent<U> {
             board_kernel,
             send_buffer,
             recv_buffer,
+            alarm_mux,
+            deferred_caller,
         }
     }
 }
 
-impl<U: 'static + hil::usb::UsbController<'static>> Component for CtapComponent<U> {
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>> Component
+    for CtapComponent<U, A>
+{
     type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
         &'static mut MaybeUninit<capsules::usb::ctap::CtapHid<'static, U>>,
         &'static mut MaybeUninit<
-            capsules::ctap::CtapDriver<'static, capsules::usb::ctap::CtapHid<'static, U>>,
+            capsules::ctap::CtapHidTransport<
+                'static,
+                capsules::usb::ctap::CtapHid<'static, U>,
+                VirtualMuxAlarm<'static, A>,
+            >,
+        >,
+        &'static mut MaybeUninit<
+            capsules::ctap::CtapDriver<
+                'static,
+                capsules::usb::ctap::CtapHid<'static, U>,
+                VirtualMuxAlarm<'static, A>,
+            >,
         >,
     );
     type Output = (
         &'static capsules::usb::ctap::CtapHid<'static, U>,
-        &'static capsules::ctap::CtapDriver<'static, capsules::usb::ctap::CtapHid<'static, U>>,
+        &'static capsules::ctap::CtapDriver<
+            'static,
+            capsules::usb::ctap::CtapHid<'static, U>,
+            VirtualMuxAlarm<'static, A>,
+        >,
     );
 
     unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
-        let ctap = static_init_half!(
+        let ctap_alarm = static_init_half!(
             s.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        let ctap = static_init_half!(
+            s.1,
             capsules::usb::ctap::CtapHid<'static, U>,
             capsules::usb::ctap::CtapHid::new(
                 self.usb,
@@ -109,18 +163,43 @@ impl<U: 'static + hil::usb::UsbController<'static>> Component for CtapComponent<
 
         let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
 
-        let ctap_driver = static_init_half!(
-            s.1,
-            capsules::ctap::CtapDriver<'static, capsules::usb::ctap::CtapHid<'static, U>>,
-            capsules::ctap::CtapDriver::new(
-                Some(ctap),
+        let ctap_transport = static_init_half!(
+            s.2,
+            capsules::ctap::CtapHidTransport<
+                'static,
+                capsules::usb::ctap::CtapHid<'static, U>,
+                VirtualMuxAlarm<'static, A>,
+            >,
+            capsules::ctap::CtapHidTransport::new(
+                ctap,
                 self.send_buffer,
                 self.recv_buffer,
+                ctap_alarm,
+                self.deferred_caller,
+            )
+        );
+
+        let ctap_driver = static_init_half!(
+            s.3,
+            capsules::ctap::CtapDriver<
+                'static,
+                capsules::usb::ctap::CtapHid<'static, U>,
+                VirtualMuxAlarm<'static, A>,
+            >,
+            capsules::ctap::CtapDriver::new(
+                ctap_transport,
                 self.board_kernel.create_grant(&grant_cap),
             )
         );
 
-        ctap.set_client(ctap_driver);
+        ctap.set_client(ctap_transport);
+        ctap_alarm.set_alarm_client(ctap_transport);
+        ctap_transport.set_client(ctap_driver);
+        ctap_transport.initialize_callback_handle(
+            self.deferred_caller
+                .register(ctap_transport)
+                .expect("no deferred call slot available for CTAP"),
+        );
 
         (ctap, ctap_driver)
     }
@@ -0,0 +1,219 @@
+//! Component to add a DTLS session to the userland UDP driver.
+//!
+//! This provides one Component, DtlsComponent. It binds `local_port` for the
+//! session, derives keys with the given SHA-256 digest engine, protects
+//! records with the given AES-CCM engine and hands the session to the UDP
+//! driver, which lets one app at a time use it. The session can only reach
+//! the servers that the board's `net_cap` allows, and `local_port` must be
+//! allowed by it too.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let dtls = DtlsComponent::new(
+//!        udp_driver,
+//!        udp_send_mux,
+//!        udp_recv_mux,
+//!        udp_port_table,
+//!        mux_alarm,
+//!        aes_ccm,
+//!        sha256,
+//!        rng,
+//!        net_cap,
+//!        5684,
+//!     )
+//!     .finalize(components::dtls_component_helper!(
+//!         sam4l::ast::Ast,
+//!         capsules::virtual_aes_ccm::VirtualAES128CCM<'static, sam4l::aes::Aes<'static>>,
+//!         capsules::virtual_digest::VirtualMuxDigest<'static, lowrisc::hmac::Hmac, [u8; 32]>,
+//!     ));
+//! ```
+
+use capsules;
+use capsules::net::dtls::prf::{Prf, HASH_BUF_LEN, SHA256_LEN};
+use capsules::net::dtls::session::DTLS_BUF_SIZE;
+use capsules::net::dtls::{DtlsSession, DtlsTransport};
+use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules::net::network_capabilities::{NetworkCapability, UdpVisibilityCapability};
+use capsules::net::udp::driver::UDPDriver;
+use capsules::net::udp::udp_port_table::UdpPortManager;
+use capsules::net::udp::udp_recv::MuxUdpReceiver;
+use capsules::net::udp::udp_recv::UDPReceiver;
+use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::mem::MaybeUninit;
+use kernel;
+use kernel::capabilities::NetworkCapabilityCreationCapability;
+use kernel::component::Component;
+use kernel::hil::digest::{Digest, Sha256};
+use kernel::hil::rng::Random;
+use kernel::hil::symmetric_encryption::AES128CCM;
+use kernel::hil::time::Alarm;
+use kernel::{create_capability, static_init, static_init_half};
+
+static mut TX_BUF: [u8; DTLS_BUF_SIZE] = [0; DTLS_BUF_SIZE];
+static mut FLIGHT_BUF: [u8; DTLS_BUF_SIZE] = [0; DTLS_BUF_SIZE];
+static mut RX_BUF: [u8; DTLS_BUF_SIZE] = [0; DTLS_BUF_SIZE];
+static mut HASH_BUF: [u8; HASH_BUF_LEN] = [0; HASH_BUF_LEN];
+static mut DIGEST_BUF: [u8; SHA256_LEN] = [0; SHA256_LEN];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! dtls_component_helper {
+    ($A:ty, $C:ty, $D:ty $(,)?) => {{
+        use capsules::net::dtls::prf::Prf;
+        use capsules::net::dtls::DtlsSession;
+        use capsules::net::udp::udp_send::UDPSendStruct;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<
+            UDPSendStruct<
+                'static,
+                capsules::net::ipv6::ipv6_send::IP6SendStruct<
+                    'static,
+                    VirtualMuxAlarm<'static, $A>,
+                >,
+            >,
+        > = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<Prf<'static, $D>> = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<DtlsSession<'static, VirtualMuxAlarm<'static, $A>, $C, $D>> =
+            MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2, &mut BUF3)
+    };};
+}
+
+pub struct DtlsComponent<
+    A: Alarm<'static> + 'static,
+    C: AES128CCM<'static> + 'static,
+    D: Digest<'static, [u8; SHA256_LEN]> + Sha256 + 'static,
+> {
+    udp_driver: &'static UDPDriver<'static>,
+    udp_send_mux:
+        &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    port_table: &'static UdpPortManager,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    ccm: &'static C,
+    digest: &'static D,
+    rng: &'static dyn Random<'static>,
+    net_cap: &'static NetworkCapability,
+    local_port: u16,
+}
+
+impl<A: Alarm<'static>, C: AES128CCM<'static>, D: Digest<'static, [u8; SHA256_LEN]> + Sha256>
+    DtlsComponent<A, C, D>
+{
+    pub fn new(
+        udp_driver: &'static UDPDriver<'static>,
+        udp_send_mux: &'static MuxUdpSender<
+            'static,
+            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+        >,
+        udp_recv_mux: &'static MuxUdpReceiver<'static>,
+        port_table: &'static UdpPortManager,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        ccm: &'static C,
+        digest: &'static D,
+        rng: &'static dyn Random<'static>,
+        net_cap: &'static NetworkCapability,
+        local_port: u16,
+    ) -> Self {
+        Self {
+            udp_driver,
+            udp_send_mux,
+            udp_recv_mux,
+            port_table,
+            alarm_mux,
+            ccm,
+            digest,
+            rng,
+            net_cap,
+            local_port,
+        }
+    }
+}
+
+impl<A: Alarm<'static>, C: AES128CCM<'static>, D: Digest<'static, [u8; SHA256_LEN]> + Sha256>
+    Component for DtlsComponent<A, C, D>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<
+            UDPSendStruct<
+                'static,
+                capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+            >,
+        >,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<Prf<'static, D>>,
+        &'static mut MaybeUninit<DtlsSession<'static, VirtualMuxAlarm<'static, A>, C, D>>,
+    );
+    type Output = &'static DtlsSession<'static, VirtualMuxAlarm<'static, A>, C, D>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let create_cap = create_capability!(NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        let udp_send = static_init_half!(
+            static_buffer.0,
+            UDPSendStruct<
+                'static,
+                capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+            >,
+            UDPSendStruct::new(self.udp_send_mux, udp_vis)
+        );
+        let udp_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new());
+        self.udp_recv_mux.add_client(udp_recv);
+
+        let socket = self
+            .port_table
+            .create_socket()
+            .expect("DTLS: no free UDP socket");
+        let (send_binding, recv_binding) = self
+            .port_table
+            .bind(socket, self.local_port, self.net_cap)
+            .map_err(|_| ())
+            .expect("DTLS: failed to bind local port");
+        udp_send.set_binding(send_binding);
+        udp_recv.set_binding(recv_binding);
+
+        let dtls_alarm = static_init_half!(
+            static_buffer.1,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        let prf = static_init_half!(
+            static_buffer.2,
+            Prf<'static, D>,
+            Prf::new(self.digest, &mut HASH_BUF, &mut DIGEST_BUF)
+        );
+
+        let session = static_init_half!(
+            static_buffer.3,
+            DtlsSession<'static, VirtualMuxAlarm<'static, A>, C, D>,
+            DtlsSession::new(
+                udp_send,
+                dtls_alarm,
+                self.ccm,
+                prf,
+                self.rng,
+                &mut TX_BUF,
+                &mut FLIGHT_BUF,
+                &mut RX_BUF,
+                self.net_cap,
+            )
+        );
+        self.digest.set_client(prf);
+        prf.set_client(session);
+        self.ccm.set_client(session);
+        udp_send.set_client(session);
+        udp_recv.set_client(session);
+        dtls_alarm.set_alarm_client(session);
+        session.set_client(self.udp_driver);
+        self.udp_driver.set_dtls(session);
+        session
+    }
+}
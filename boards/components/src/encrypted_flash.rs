@@ -0,0 +1,132 @@
+//! Component for a layer that encrypts and authenticates every page written
+//! to a flash with AES-CCM.
+//!
+//! The key must be unique to the device. The AES-CCM implementation and the
+//! random number generator are used exclusively by this layer. The table of
+//! page generations must be kept where it can not be rolled back along with
+//! the flash, see `capsules::encrypted_flash`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let encrypted_flash = components::encrypted_flash::EncryptedFlashComponent::new(
+//!     mx25r6435f,
+//!     ccm,
+//!     &base_peripherals.trng,
+//!     &DEVICE_KEY,
+//!     &GENERATIONS,
+//! )
+//! .finalize(components::encrypted_flash_component_helper!(
+//!     capsules::mx25r6435f::MX25R6435F<'static, ...>,
+//!     capsules::virtual_aes_ccm::VirtualAES128CCM<'static, nrf52840::aes::AesECB<'static>>,
+//!     nrf52840::trng::Trng<'static>,
+//! ));
+//! ```
+
+use capsules::encrypted_flash::EncryptedFlash;
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::symmetric_encryption::{AES128CCM, AES128_KEY_SIZE};
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! encrypted_flash_component_helper {
+    ($F:ty, $A:ty, $R:ty $(,)?) => {{
+        use capsules::encrypted_flash::EncryptedFlash;
+        use core::mem::MaybeUninit;
+        use kernel::hil;
+        static mut PAGE: MaybeUninit<<$F as hil::flash::Flash>::Page> = MaybeUninit::uninit();
+        static mut CRYPT_PAGE: MaybeUninit<<$F as hil::flash::Flash>::Page> = MaybeUninit::uninit();
+        static mut ENCRYPTED: MaybeUninit<EncryptedFlash<'static, $F, $A, $R>> =
+            MaybeUninit::uninit();
+        (&mut PAGE, &mut CRYPT_PAGE, &mut ENCRYPTED)
+    };};
+}
+
+pub struct EncryptedFlashComponent<
+    F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, EncryptedFlash<'static, F, A, R>>,
+    A: 'static + AES128CCM<'static>,
+    R: 'static + hil::rng::Rng<'static>,
+> {
+    flash: &'static F,
+    ccm: &'static A,
+    rng: &'static R,
+    key: &'static [u8; AES128_KEY_SIZE],
+    generations: &'static [Cell<u32>],
+}
+
+impl<
+        F: 'static
+            + hil::flash::Flash
+            + hil::flash::HasClient<'static, EncryptedFlash<'static, F, A, R>>,
+        A: 'static + AES128CCM<'static>,
+        R: 'static + hil::rng::Rng<'static>,
+    > EncryptedFlashComponent<F, A, R>
+{
+    pub fn new(
+        flash: &'static F,
+        ccm: &'static A,
+        rng: &'static R,
+        key: &'static [u8; AES128_KEY_SIZE],
+        generations: &'static [Cell<u32>],
+    ) -> Self {
+        Self {
+            flash,
+            ccm,
+            rng,
+            key,
+            generations,
+        }
+    }
+}
+
+impl<
+        F: 'static
+            + hil::flash::Flash
+            + hil::flash::HasClient<'static, EncryptedFlash<'static, F, A, R>>,
+        A: 'static + AES128CCM<'static>,
+        R: 'static + hil::rng::Rng<'static>,
+    > Component for EncryptedFlashComponent<F, A, R>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<EncryptedFlash<'static, F, A, R>>,
+    );
+    type Output = &'static EncryptedFlash<'static, F, A, R>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let page = static_init_half!(
+            static_buffer.0,
+            <F as hil::flash::Flash>::Page,
+            <F as hil::flash::Flash>::Page::default()
+        );
+        let crypt_page = static_init_half!(
+            static_buffer.1,
+            <F as hil::flash::Flash>::Page,
+            <F as hil::flash::Flash>::Page::default()
+        );
+
+        let encrypted_flash = static_init_half!(
+            static_buffer.2,
+            EncryptedFlash<'static, F, A, R>,
+            EncryptedFlash::new(
+                self.flash,
+                self.ccm,
+                self.rng,
+                self.key,
+                self.generations,
+                page,
+                crypt_page
+            )
+        );
+        hil::flash::HasClient::set_client(self.flash, encrypted_flash);
+        self.ccm.set_client(encrypted_flash);
+        self.rng.set_client(encrypted_flash);
+
+        encrypted_flash
+    }
+}
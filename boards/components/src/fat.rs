@@ -0,0 +1,83 @@
+//! Component for a FAT filesystem on a block storage device, with a syscall
+//! driver that gives every app its own directory.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!     sdcard.initialize();
+//!     let fat = components::fat::FatComponent::new(board_kernel, sdcard).finalize(
+//!         components::fat_component_helper!(
+//!             capsules::sdcard::SDCard<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>
+//!         ),
+//!     );
+//! ```
+
+use capsules::fat::cache::BLOCK_SIZE;
+use capsules::fat::driver::{FatDriver, CACHE_LEN};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::block_storage::BlockStorage;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! fat_component_helper {
+    ($B:ty $(,)?) => {{
+        use capsules::fat::cache::BLOCK_SIZE;
+        use capsules::fat::driver::{FatDriver, CACHE_LEN};
+        use core::mem::MaybeUninit;
+        static mut DRIVER: MaybeUninit<FatDriver<'static, $B, components::fat::Capability>> =
+            MaybeUninit::uninit();
+        static mut CACHE: [u8; CACHE_LEN] = [0; CACHE_LEN];
+        static mut BUFFER: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+        (&mut DRIVER, &mut CACHE, &mut BUFFER)
+    };};
+}
+
+pub struct Capability;
+unsafe impl capabilities::ProcessManagementCapability for Capability {}
+
+pub struct FatComponent<B: 'static + BlockStorage<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    storage: &'static B,
+}
+
+impl<B: 'static + BlockStorage<'static>> FatComponent<B> {
+    pub fn new(board_kernel: &'static kernel::Kernel, storage: &'static B) -> FatComponent<B> {
+        FatComponent {
+            board_kernel,
+            storage,
+        }
+    }
+}
+
+impl<B: 'static + BlockStorage<'static>> Component for FatComponent<B> {
+    type StaticInput = (
+        &'static mut MaybeUninit<FatDriver<'static, B, Capability>>,
+        &'static mut [u8; CACHE_LEN],
+        &'static mut [u8; BLOCK_SIZE],
+    );
+    type Output = &'static FatDriver<'static, B, Capability>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let fat = static_init_half!(
+            s.0,
+            FatDriver<'static, B, Capability>,
+            FatDriver::new(
+                self.storage,
+                s.1,
+                s.2,
+                self.board_kernel.create_grant(&grant_cap),
+                self.board_kernel,
+                Capability,
+            )
+        );
+        self.storage.set_client(fat);
+
+        fat
+    }
+}
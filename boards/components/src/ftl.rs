@@ -0,0 +1,101 @@
+//! Component for a flash translation layer that presents a wear-leveled
+//! region of flash as nonvolatile storage.
+//!
+//! The region starts at flash address `start` and spans as many pages as the
+//! page table passed to the helper macro has entries. Pages are erase units of
+//! the flash, of the size passed to the helper macro. The layer is mounted
+//! before it is returned.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let ftl = components::ftl::FtlComponent::new(
+//!     &sam4l::flashcalw::FLASH_CONTROLLER,
+//!     0x60000,
+//!     2,
+//!     dynamic_deferred_caller,
+//! )
+//! .finalize(components::ftl_component_helper!(
+//!     sam4l::flashcalw::FLASHCALW,
+//!     16,
+//!     512
+//! ));
+//! ```
+
+use capsules::ftl::{FlashTranslationLayer, PageInfo};
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ftl_component_helper {
+    ($F:ty, $N:expr, $PAGE_SIZE:expr $(,)?) => {{
+        use capsules::ftl::{FlashTranslationLayer, PageInfo, UNUSED_PAGE};
+        use core::cell::Cell;
+        use core::mem::MaybeUninit;
+        static mut PAGEBUFFER: [u8; $PAGE_SIZE] = [0; $PAGE_SIZE];
+        static mut PAGES: [Cell<PageInfo>; $N] = [UNUSED_PAGE; $N];
+        static mut FTL: MaybeUninit<FlashTranslationLayer<'static, $F>> = MaybeUninit::uninit();
+        (&mut PAGEBUFFER, &PAGES[..], &mut FTL)
+    };};
+}
+
+pub struct FtlComponent<F: 'static + hil::flash::FlashRange<'static>> {
+    flash: &'static F,
+    start: usize,
+    spare: usize,
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl<F: 'static + hil::flash::FlashRange<'static>> FtlComponent<F> {
+    pub fn new(
+        flash: &'static F,
+        start: usize,
+        spare: usize,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> Self {
+        Self {
+            flash,
+            start,
+            spare,
+            deferred_caller,
+        }
+    }
+}
+
+impl<F: 'static + hil::flash::FlashRange<'static>> Component for FtlComponent<F> {
+    type StaticInput = (
+        &'static mut [u8],
+        &'static [Cell<PageInfo>],
+        &'static mut MaybeUninit<FlashTranslationLayer<'static, F>>,
+    );
+    type Output = &'static FlashTranslationLayer<'static, F>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let ftl = static_init_half!(
+            static_buffer.2,
+            FlashTranslationLayer<'static, F>,
+            FlashTranslationLayer::new(
+                self.flash,
+                static_buffer.0,
+                self.start,
+                static_buffer.1,
+                self.spare,
+                self.deferred_caller,
+            )
+        );
+        self.flash.set_range_client(ftl);
+        ftl.initialize_callback_handle(
+            self.deferred_caller
+                .register(ftl)
+                .expect("no deferred call slot available for the FTL"),
+        );
+        ftl.mount().expect("unable to mount the FTL");
+
+        ftl
+    }
+}
@@ -5,15 +5,21 @@ pub mod adc_microphone;
 pub mod alarm;
 pub mod analog_comparator;
 pub mod app_flash_driver;
+pub mod ble_gatt;
 pub mod bus;
 pub mod button;
 pub mod cdc;
+pub mod coap_driver;
 pub mod console;
 pub mod crc;
 pub mod ctap;
 pub mod debug_queue;
 pub mod debug_writer;
+pub mod dtls;
+pub mod encrypted_flash;
+pub mod fat;
 pub mod ft6x06;
+pub mod ftl;
 pub mod fxos8700;
 pub mod gpio;
 pub mod hd44780;
@@ -27,6 +33,7 @@ pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
 pub mod lldb;
+pub mod log_driver;
 pub mod lsm303agr;
 pub mod lsm303dlhc;
 pub mod mlx90614;
@@ -44,6 +51,7 @@ pub mod sht3x;
 pub mod si7021;
 pub mod sound_pressure;
 pub mod spi;
+pub mod spi_nor;
 pub mod st77xx;
 pub mod temperature;
 pub mod temperature_stm;
@@ -53,3 +61,7 @@ pub mod tickv;
 pub mod touch;
 pub mod udp_driver;
 pub mod udp_mux;
+pub mod usb_composite;
+pub mod usb_dfu;
+pub mod usb_hid;
+pub mod usb_msc;
@@ -0,0 +1,109 @@
+//! Component for the syscall driver that gives every app its own named logs
+//! on a shared persistent log.
+//!
+//! The log itself is set up by the board (see `capsules::log`). Dumps of the
+//! log are written to a new device on `uart_mux`; attach the driver to the
+//! process console to print it with the `log` command.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!     let log_driver = components::log_driver::LogDriverComponent::new(
+//!         board_kernel,
+//!         log,
+//!         1024,
+//!         uart_mux,
+//!     )
+//!     .finalize(components::log_driver_component_helper!(
+//!         capsules::log::Log<'static, nrf52840::nvmc::Nvmc>
+//!     ));
+//!     process_console.set_log(log_driver);
+//! ```
+
+use capsules::log_driver::{LogDriver, BUFFER_LEN, DUMP_BUFFER_LEN};
+use capsules::virtual_uart::{MuxUart, UartDevice};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::hil::log::{LogRead, LogWrite};
+use kernel::{static_init, static_init_half};
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! log_driver_component_helper {
+    ($L:ty $(,)?) => {{
+        use capsules::log_driver::{LogDriver, BUFFER_LEN, DUMP_BUFFER_LEN};
+        use core::mem::MaybeUninit;
+        static mut DRIVER: MaybeUninit<LogDriver<'static, $L, components::log_driver::Capability>> =
+            MaybeUninit::uninit();
+        static mut BUFFER: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+        static mut DUMP_BUFFER: [u8; DUMP_BUFFER_LEN] = [0; DUMP_BUFFER_LEN];
+        (&mut DRIVER, &mut BUFFER, &mut DUMP_BUFFER)
+    };};
+}
+
+pub struct Capability;
+unsafe impl capabilities::ProcessManagementCapability for Capability {}
+
+pub struct LogDriverComponent<L: 'static + LogRead<'static, EntryID = usize> + LogWrite<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    log: &'static L,
+    quota: usize,
+    uart_mux: &'static MuxUart<'static>,
+}
+
+impl<L: 'static + LogRead<'static, EntryID = usize> + LogWrite<'static>> LogDriverComponent<L> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        log: &'static L,
+        quota: usize,
+        uart_mux: &'static MuxUart<'static>,
+    ) -> LogDriverComponent<L> {
+        LogDriverComponent {
+            board_kernel,
+            log,
+            quota,
+            uart_mux,
+        }
+    }
+}
+
+impl<L: 'static + LogRead<'static, EntryID = usize> + LogWrite<'static>> Component
+    for LogDriverComponent<L>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<LogDriver<'static, L, Capability>>,
+        &'static mut [u8; BUFFER_LEN],
+        &'static mut [u8; DUMP_BUFFER_LEN],
+    );
+    type Output = &'static LogDriver<'static, L, Capability>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let dump_uart = static_init!(UartDevice, UartDevice::new(self.uart_mux, false));
+        dump_uart.setup();
+
+        let log_driver = static_init_half!(
+            s.0,
+            LogDriver<'static, L, Capability>,
+            LogDriver::new(
+                self.log,
+                s.1,
+                self.board_kernel.create_grant(&grant_cap),
+                self.quota,
+                dump_uart,
+                s.2,
+                self.board_kernel,
+                Capability,
+            )
+        );
+        self.log.set_read_client(log_driver);
+        self.log.set_append_client(log_driver);
+        hil::uart::Transmit::set_transmit_client(dump_uart, log_driver);
+
+        log_driver
+    }
+}
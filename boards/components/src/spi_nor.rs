@@ -0,0 +1,108 @@
+//! Component for SPI NOR flash chips that are discovered through SFDP.
+//!
+//! Discovery of the chip is started before the driver is returned, and
+//! finishes asynchronously. Flash operations fail with `OFF` until then.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let spi_nor = components::spi_nor::SpiNorComponent::new(
+//!     &gpio_port[driver.chip_select] as &dyn kernel::hil::gpio::Pin,
+//!     mux_alarm,
+//!     mux_spi,
+//! )
+//! .finalize(components::spi_nor_component_helper!(
+//!     nrf52::spi::SPIM,
+//!     nrf52::rtc::Rtc
+//! ));
+//! ```
+use capsules::spi_nor::SpiNor;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules::virtual_spi::{MuxSpiMaster, VirtualSpiMasterDevice};
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::time::Alarm;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! spi_nor_component_helper {
+    ($S:ty, $A:ty $(,)?) => {{
+        use capsules::spi_nor::SpiNor;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use capsules::virtual_spi::VirtualSpiMasterDevice;
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<VirtualSpiMasterDevice<'static, $S>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<
+            SpiNor<'static, VirtualSpiMasterDevice<'static, $S>, VirtualMuxAlarm<'static, $A>>,
+        > = MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2, &mut BUF3)
+    };};
+}
+
+pub struct SpiNorComponent<S: 'static + hil::spi::SpiMaster, A: 'static + hil::time::Alarm<'static>>
+{
+    chip_select: S::ChipSelect,
+    mux_alarm: &'static MuxAlarm<'static, A>,
+    mux_spi: &'static MuxSpiMaster<'static, S>,
+}
+
+impl<S: 'static + hil::spi::SpiMaster, A: 'static + hil::time::Alarm<'static>>
+    SpiNorComponent<S, A>
+{
+    pub fn new(
+        chip_select: S::ChipSelect,
+        mux_alarm: &'static MuxAlarm<'static, A>,
+        mux_spi: &'static MuxSpiMaster<'static, S>,
+    ) -> SpiNorComponent<S, A> {
+        SpiNorComponent {
+            chip_select,
+            mux_alarm,
+            mux_spi,
+        }
+    }
+}
+
+impl<S: 'static + hil::spi::SpiMaster, A: 'static + hil::time::Alarm<'static>> Component
+    for SpiNorComponent<S, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualSpiMasterDevice<'static, S>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<
+            SpiNor<'static, VirtualSpiMasterDevice<'static, S>, VirtualMuxAlarm<'static, A>>,
+        >,
+    );
+    type Output =
+        &'static SpiNor<'static, VirtualSpiMasterDevice<'static, S>, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let spi_nor_spi = static_init_half!(
+            static_buffer.0,
+            VirtualSpiMasterDevice<'static, S>,
+            VirtualSpiMasterDevice::new(self.mux_spi, self.chip_select)
+        );
+        let spi_nor_virtual_alarm = static_init_half!(
+            static_buffer.1,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.mux_alarm)
+        );
+
+        let spi_nor = static_init_half!(
+            static_buffer.2,
+            SpiNor<'static, VirtualSpiMasterDevice<'static, S>, VirtualMuxAlarm<'static, A>>,
+            SpiNor::new(
+                spi_nor_spi,
+                spi_nor_virtual_alarm,
+                &mut capsules::spi_nor::TXBUFFER,
+                &mut capsules::spi_nor::RXBUFFER,
+            )
+        );
+        spi_nor_spi.set_client(spi_nor);
+        spi_nor_virtual_alarm.set_alarm_client(spi_nor);
+        let _ = spi_nor.initialize();
+        spi_nor
+    }
+}
@@ -21,7 +21,7 @@ use capsules;
 use capsules::net::ipv6::ip_utils::IPAddr;
 use capsules::net::ipv6::ipv6_send::IP6SendStruct;
 use capsules::net::network_capabilities::{
-    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+    AddrRange, IpVisibilityCapability, NetworkCapability, PortRange, UdpVisibilityCapability,
 };
 use capsules::net::udp::udp_port_table::UdpPortManager;
 use capsules::net::udp::udp_recv::MuxUdpReceiver;
@@ -108,6 +108,10 @@ impl<A: Alarm<'static>> Component for UDPDriverComponent<A> {
             UdpVisibilityCapability,
             UdpVisibilityCapability::new(&create_cap)
         );
+        let ip_vis = static_init!(
+            IpVisibilityCapability,
+            IpVisibilityCapability::new(&create_cap)
+        );
         let udp_send = static_init_half!(
             static_buffer.0,
             UDPSendStruct<
@@ -139,6 +143,8 @@ impl<A: Alarm<'static>> Component for UDPDriverComponent<A> {
                 kernel::common::leasable_buffer::LeasableBuffer::new(&mut DRIVER_BUF),
                 &DRIVER_CAP,
                 net_cap,
+                ip_vis,
+                udp_vis,
             )
         );
         udp_send.set_client(udp_driver);
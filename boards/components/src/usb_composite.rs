@@ -0,0 +1,154 @@
+//! Components for composite USB devices.
+//!
+//! `UsbCompositeComponent` makes the composite device the client of the USB
+//! controller, and `UsbFunctionComponent` creates a function of it to pass to
+//! a USB class component in place of the controller.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let composite = components::usb_composite::UsbCompositeComponent::new(
+//!     &nrf52840::usbd::USBD,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x1915,
+//!     0x503a,
+//!     STRINGS,
+//! )
+//! .finalize(components::usb_composite_component_helper!(
+//!     nrf52840::usbd::Usbd<'static>
+//! ));
+//!
+//! // CDC-ACM uses endpoints 2 to 4 of the device
+//! let cdc_function = components::usb_composite::UsbFunctionComponent::new(composite, 0)
+//!     .finalize(components::usb_function_component_helper!(
+//!         nrf52840::usbd::Usbd<'static>
+//!     ));
+//! let cdc = components::cdc::CdcAcmComponent::new(cdc_function, ...)
+//!     .finalize(components::usb_cdc_acm_component_helper!(
+//!         capsules::usb::composite::UsbFunction<'static, nrf52840::usbd::Usbd<'static>>,
+//!         nrf52840::rtc::Rtc
+//!     ));
+//!
+//! // CTAP uses endpoint 5 of the device
+//! let ctap_function = components::usb_composite::UsbFunctionComponent::new(composite, 4)
+//!     .finalize(components::usb_function_component_helper!(
+//!         nrf52840::usbd::Usbd<'static>
+//!     ));
+//! ...
+//!
+//! composite
+//!     .enable()
+//!     .expect("USB functions use the same endpoints");
+//! composite.attach();
+//! ```
+
+use capsules::usb::composite::{CompositeDevice, UsbFunction};
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_composite_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<capsules::usb::composite::CompositeDevice<'static, $U>> =
+            MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_function_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<capsules::usb::composite::UsbFunction<'static, $U>> =
+            MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct UsbCompositeComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbCompositeComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> UsbCompositeComponent<U> {
+        UsbCompositeComponent {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbCompositeComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<CompositeDevice<'static, U>>;
+    type Output = &'static CompositeDevice<'static, U>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let composite = static_init_half!(
+            s,
+            CompositeDevice<'static, U>,
+            CompositeDevice::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings
+            )
+        );
+        self.usb.set_client(composite);
+
+        composite
+    }
+}
+
+pub struct UsbFunctionComponent<U: 'static + hil::usb::UsbController<'static>> {
+    composite: &'static CompositeDevice<'static, U>,
+    endpoint_offset: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbFunctionComponent<U> {
+    pub fn new(
+        composite: &'static CompositeDevice<'static, U>,
+        endpoint_offset: usize,
+    ) -> UsbFunctionComponent<U> {
+        UsbFunctionComponent {
+            composite,
+            endpoint_offset,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbFunctionComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<UsbFunction<'static, U>>;
+    type Output = &'static UsbFunction<'static, U>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let function = static_init_half!(
+            s,
+            UsbFunction<'static, U>,
+            UsbFunction::new(self.composite, self.endpoint_offset)
+        );
+        self.composite
+            .add_function(function)
+            .expect("USB function endpoint offset out of range");
+
+        function
+    }
+}
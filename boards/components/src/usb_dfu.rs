@@ -0,0 +1,130 @@
+//! Component for USB Device Firmware Upgrade support.
+//!
+//! This provides a component that lets a USB host download the kernel or apps
+//! to, and upload them from, regions of the flash.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 5] = &[
+//!     "XYZ Corp.",     // Manufacturer
+//!     "Data Logger",   // Product
+//!     "Serial No. 5",  // Serial number
+//!     "Kernel",        // Region 0
+//!     "Apps",          // Region 1
+//! ];
+//! static REGIONS: &'static [capsules::usb::dfu::DfuRegion] = &[
+//!     capsules::usb::dfu::DfuRegion { address: 0x00000, length: 0x40000, apps: false },
+//!     capsules::usb::dfu::DfuRegion { address: 0x40000, length: 0x40000, apps: true },
+//! ];
+//!     let dfu_buffer = static_init!([u8; 1024], [0; 1024]);
+//!
+//!     let dfu = components::usb_dfu::UsbDfuComponent::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x521f,
+//!         STRINGS,
+//!         &nrf52840::nvmc::NVMC,
+//!         REGIONS,
+//!         dfu_buffer,
+//!         None, // No bootloader to detach to
+//!     )
+//!     .finalize(components::usb_dfu_component_helper!(
+//!         nrf52840::usbd::Usbd<'static>,
+//!         nrf52840::nvmc::Nvmc,
+//!     ));
+//!
+//!     dfu.enable();
+//!     dfu.attach();
+//! ```
+
+use capsules::usb::dfu::{Dfu, DfuRegion};
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::flash::FlashRange;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_dfu_component_helper {
+    ($U:ty, $S:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<capsules::usb::dfu::Dfu<'static, $U, $S>> =
+            MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct UsbDfuComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    S: 'static + FlashRange<'static>,
+> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str],
+    storage: &'static S,
+    regions: &'static [DfuRegion],
+    buffer: &'static mut [u8],
+    detach_function: Option<&'static (dyn Fn() + 'static)>,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, S: 'static + FlashRange<'static>>
+    UsbDfuComponent<U, S>
+{
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str],
+        storage: &'static S,
+        regions: &'static [DfuRegion],
+        buffer: &'static mut [u8],
+        detach_function: Option<&'static (dyn Fn() + 'static)>,
+    ) -> UsbDfuComponent<U, S> {
+        UsbDfuComponent {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            storage,
+            regions,
+            buffer,
+            detach_function,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, S: 'static + FlashRange<'static>> Component
+    for UsbDfuComponent<U, S>
+{
+    type StaticInput = &'static mut MaybeUninit<Dfu<'static, U, S>>;
+    type Output = &'static Dfu<'static, U, S>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let dfu = static_init_half!(
+            s,
+            Dfu<'static, U, S>,
+            Dfu::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                self.storage,
+                self.regions,
+                self.buffer,
+                self.detach_function,
+            )
+        );
+        self.storage.set_range_client(dfu);
+        self.usb.set_client(dfu);
+
+        dfu
+    }
+}
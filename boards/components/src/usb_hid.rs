@@ -0,0 +1,134 @@
+//! Component for a USB HID device, such as a keyboard or a mouse, driven by
+//! an app.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!     let hid_send_buffer = static_init!([u8; 64], [0; 64]);
+//!     let hid_recv_buffer = static_init!([u8; 64], [0; 64]);
+//!
+//!     let (hid, hid_driver) = components::usb_hid::UsbHidComponent::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x521a,
+//!         strings,
+//!         capsules::usb::hid::KEYBOARD,
+//!         board_kernel,
+//!         hid_send_buffer,
+//!         hid_recv_buffer,
+//!     )
+//!     .finalize(components::usb_hid_component_helper!(nrf52840::usbd::Usbd<'static>));
+//!
+//!     hid.enable();
+//!     hid.attach();
+//! ```
+
+use capsules::usb::hid::{Hid, HidReports};
+use capsules::usb_hid_driver::UsbHidDriver;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_hid_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<capsules::usb::hid::Hid<'static, $U>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<capsules::usb_hid_driver::UsbHidDriver<'static, $U>> =
+            MaybeUninit::uninit();
+        static mut REPORT_DESCRIPTOR: [u8; capsules::usb::hid::REPORT_DESCRIPTOR_BUFLEN] =
+            [0; capsules::usb::hid::REPORT_DESCRIPTOR_BUFLEN];
+        (&mut BUF1, &mut BUF2, &mut REPORT_DESCRIPTOR)
+    };};
+}
+
+pub struct UsbHidComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    reports: HidReports,
+    board_kernel: &'static kernel::Kernel,
+    send_buffer: &'static mut [u8; 64],
+    recv_buffer: &'static mut [u8; 64],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbHidComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        reports: HidReports,
+        board_kernel: &'static kernel::Kernel,
+        send_buffer: &'static mut [u8; 64],
+        recv_buffer: &'static mut [u8; 64],
+    ) -> UsbHidComponent<U> {
+        UsbHidComponent {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            reports,
+            board_kernel,
+            send_buffer,
+            recv_buffer,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbHidComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<Hid<'static, U>>,
+        &'static mut MaybeUninit<UsbHidDriver<'static, U>>,
+        &'static mut [u8],
+    );
+    type Output = (&'static Hid<'static, U>, &'static UsbHidDriver<'static, U>);
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let hid = static_init_half!(
+            s.0,
+            Hid<'static, U>,
+            Hid::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                s.2,
+            )
+        );
+        hid.set_reports(
+            self.reports.descriptor,
+            self.reports.boot_protocol,
+            self.reports.input_size,
+            self.reports.output_size,
+        )
+        .expect("HID report descriptor does not fit its buffer");
+        self.usb.set_client(hid);
+
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let hid_driver = static_init_half!(
+            s.1,
+            UsbHidDriver<'static, U>,
+            UsbHidDriver::new(
+                hid,
+                self.send_buffer,
+                self.recv_buffer,
+                self.board_kernel.create_grant(&grant_cap),
+            )
+        );
+        hid.set_client(hid_driver);
+
+        (hid, hid_driver)
+    }
+}
@@ -0,0 +1,122 @@
+//! Component for USB Mass Storage support.
+//!
+//! This provides a component that exposes a nonvolatile storage device, such
+//! as an SD card or external flash, to a USB host as a drive.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",     // Manufacturer
+//!     "Data Logger",   // Product
+//!     "Serial No. 5",  // Serial number
+//! ];
+//!     let msc_buffer = static_init!([u8; 2048], [0; 2048]);
+//!
+//!     let msc = components::usb_msc::MassStorageComponent::new(
+//!         &nrf52840::usbd::USBD,
+//!         nv_to_page,
+//!         msc_buffer,
+//!         8192,  // Number of 512 byte blocks
+//!         false, // Not read only
+//!         0x1915,
+//!         0x503a,
+//!         STRINGS,
+//!     )
+//!     .finalize(components::usb_msc_component_helper!(
+//!         nrf52840::usbd::Usbd<'static>,
+//!         capsules::nonvolatile_to_pages::NonvolatileToPages<
+//!             'static,
+//!             capsules::mx25r6435f::MX25R6435F<'static, ...>,
+//!         >,
+//!     ));
+//!
+//!     msc.enable();
+//!     msc.attach();
+//! ```
+
+use capsules::usb::msc::MassStorage;
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::NonvolatileStorage;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_msc_component_helper {
+    ($U:ty, $S:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<capsules::usb::msc::MassStorage<'static, $U, $S>> =
+            MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct MassStorageComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    S: 'static + NonvolatileStorage<'static>,
+> {
+    usb: &'static U,
+    storage: &'static S,
+    buffer: &'static mut [u8],
+    num_blocks: u32,
+    read_only: bool,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, S: 'static + NonvolatileStorage<'static>>
+    MassStorageComponent<U, S>
+{
+    pub fn new(
+        usb: &'static U,
+        storage: &'static S,
+        buffer: &'static mut [u8],
+        num_blocks: u32,
+        read_only: bool,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> MassStorageComponent<U, S> {
+        MassStorageComponent {
+            usb,
+            storage,
+            buffer,
+            num_blocks,
+            read_only,
+            vendor_id,
+            product_id,
+            strings,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, S: 'static + NonvolatileStorage<'static>>
+    Component for MassStorageComponent<U, S>
+{
+    type StaticInput = &'static mut MaybeUninit<MassStorage<'static, U, S>>;
+    type Output = &'static MassStorage<'static, U, S>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let msc = static_init_half!(
+            s,
+            MassStorage<'static, U, S>,
+            MassStorage::new(
+                self.usb,
+                self.storage,
+                self.buffer,
+                self.num_blocks,
+                self.read_only,
+                self.vendor_id,
+                self.product_id,
+                self.strings
+            )
+        );
+        self.storage.set_client(msc);
+        self.usb.set_client(msc);
+
+        msc
+    }
+}
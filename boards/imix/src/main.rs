@@ -29,6 +29,7 @@ use kernel::hil::symmetric_encryption::AES128;
 //use kernel::hil::time::Alarm;
 use kernel::hil::led::LedHigh;
 use kernel::hil::Controller;
+use kernel::partitions::{PartitionError, PartitionTable};
 #[allow(unused_imports)]
 use kernel::{create_capability, debug, debug_gpio, static_init};
 use sam4l::chip::Sam4lDefaultPeripherals;
@@ -479,11 +480,29 @@ pub unsafe fn main() {
         static _estorage: u8;
     }
 
+    // The userspace accessible region is the "userspace" partition of the
+    // partition table in the last page of flash, if one was written. Without
+    // a table, it is the last 128 KiB of flash.
+    //
+    // Safety: the SAM4L maps its 512 KiB of flash at address 0, and flash is
+    // only written through the flash controller.
+    let (userspace_start, userspace_length) =
+        match PartitionTable::new(0x0000_0000, 0x80000, 0x7FE00, 0x200) {
+            Ok(partitions) => {
+                let userspace = partitions
+                    .lookup("userspace", "nvstore")
+                    .expect("no userspace partition");
+                (userspace.offset(), userspace.size())
+            }
+            Err(PartitionError::NoTable) => (0x60000, 0x20000),
+            Err(error) => panic!("invalid partition table: {:?}", error),
+        };
+
     let nonvolatile_storage = components::nonvolatile_storage::NonvolatileStorageComponent::new(
         board_kernel,
         &peripherals.flash_controller,
-        0x60000,                          // Start address for userspace accessible region
-        0x20000,                          // Length of userspace accessible region
+        userspace_start,  // Start address for userspace accessible region
+        userspace_length, // Length of userspace accessible region
         &_sstorage as *const u8 as usize, //start address of kernel region
         &_estorage as *const u8 as usize - &_sstorage as *const u8 as usize, // length of kernel region
     )
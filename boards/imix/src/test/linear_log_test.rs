@@ -38,20 +38,24 @@ pub unsafe fn run(
 ) {
     // Set up flash controller.
     flash_controller.configure();
-    let pagebuffer = static_init!(flashcalw::Sam4lPage, flashcalw::Sam4lPage::default());
+    // Pages of the log are erase units of the flash.
+    let pagebuffer = static_init!([u8; 512], [0; 512]);
 
     // Create actual log storage abstraction on top of flash.
     let log = static_init!(
         Log,
         log::Log::new(
             &LINEAR_TEST_LOG,
+            // The SAM4L maps its flash at address 0.
+            LINEAR_TEST_LOG.as_ptr() as usize,
             &flash_controller,
             pagebuffer,
             deferred_caller,
+            false,
             false
         )
     );
-    flash::HasClient::set_client(flash_controller, log);
+    flash::FlashRange::set_range_client(flash_controller, log);
     log.initialize_callback_handle(
         deferred_caller
             .register(log)
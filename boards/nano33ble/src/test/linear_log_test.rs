@@ -31,10 +31,7 @@ use kernel::hil::time::{Alarm, AlarmClient};
 use kernel::static_init;
 use kernel::storage_volume;
 use kernel::ErrorCode;
-use nrf52840::{
-    nvmc::{NrfPage, Nvmc},
-    rtc::Rtc,
-};
+use nrf52840::{nvmc::Nvmc, rtc::Rtc};
 
 // Allocate 8 KiB volume for log storage (the nano33ble page size is 4 KiB).
 storage_volume!(LINEAR_TEST_LOG, 8);
@@ -47,20 +44,24 @@ pub unsafe fn run(
     // Set up flash controller.
     flash_controller.configure_writeable();
     flash_controller.configure_eraseable();
-    let pagebuffer = static_init!(NrfPage, NrfPage::default());
+    // Pages of the log are erase units of the flash.
+    let pagebuffer = static_init!([u8; 4096], [0; 4096]);
 
     // Create actual log storage abstraction on top of flash.
     let log = static_init!(
         Log,
         log::Log::new(
             &LINEAR_TEST_LOG,
+            // The nRF52840 maps its flash at address 0.
+            LINEAR_TEST_LOG.as_ptr() as usize,
             &flash_controller,
             pagebuffer,
             deferred_caller,
+            false,
             false
         )
     );
-    flash::HasClient::set_client(flash_controller, log);
+    flash::FlashRange::set_range_client(flash_controller, log);
     log.initialize_callback_handle(
         deferred_caller
             .register(log)
@@ -44,7 +44,7 @@ use kernel::storage_volume;
 use kernel::ErrorCode;
 use nrf52840::{
     gpio::{GPIOPin, Pin},
-    nvmc::{NrfPage, Nvmc},
+    nvmc::Nvmc,
     rtc::Rtc,
 };
 
@@ -59,20 +59,24 @@ pub unsafe fn run(
     // Set up flash controller.
     flash_controller.configure_writeable();
     flash_controller.configure_eraseable();
-    let pagebuffer = static_init!(NrfPage, NrfPage::default());
+    // Pages of the log are erase units of the flash.
+    let pagebuffer = static_init!([u8; 4096], [0; 4096]);
 
     // Create actual log storage abstraction on top of flash.
     let log = static_init!(
         Log,
         log::Log::new(
             &TEST_LOG,
+            // The nRF52840 maps its flash at address 0.
+            TEST_LOG.as_ptr() as usize,
             &flash_controller,
             pagebuffer,
             deferred_caller,
+            true,
             true
         )
     );
-    flash::HasClient::set_client(flash_controller, log);
+    flash::FlashRange::set_range_client(flash_controller, log);
     log.initialize_callback_handle(
         deferred_caller
             .register(log)
@@ -529,8 +529,13 @@ pub unsafe fn main() {
     //     board_kernel,
     //     ctap_send_buffer,
     //     ctap_recv_buffer,
+    //     mux_alarm,
+    //     dynamic_deferred_caller,
     // )
-    // .finalize(components::usb_ctap_component_helper!(nrf52840::usbd::Usbd));
+    // .finalize(components::usb_ctap_component_helper!(
+    //     nrf52840::usbd::Usbd,
+    //     nrf52840::rtc::Rtc
+    // ));
 
     // ctap.enable();
     // ctap.attach();
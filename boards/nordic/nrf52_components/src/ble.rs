@@ -78,3 +78,56 @@ impl Component for BLEComponent {
         ble_radio
     }
 }
+
+/// Link layer in the peripheral role, for L2CAP and the layers above it.
+///
+/// ```rust
+/// let ble_ll =
+///     BLELinkLayerComponent::new(&base_peripherals.ble_radio, mux_alarm).finalize(());
+/// ```
+pub struct BLELinkLayerComponent {
+    radio: &'static nrf52::ble_radio::Radio<'static>,
+    mux_alarm: &'static capsules::virtual_alarm::MuxAlarm<'static, nrf52::rtc::Rtc<'static>>,
+}
+
+impl BLELinkLayerComponent {
+    pub fn new(
+        radio: &'static nrf52::ble_radio::Radio,
+        mux_alarm: &'static capsules::virtual_alarm::MuxAlarm<'static, nrf52::rtc::Rtc>,
+    ) -> BLELinkLayerComponent {
+        BLELinkLayerComponent { radio, mux_alarm }
+    }
+}
+
+impl Component for BLELinkLayerComponent {
+    type StaticInput = ();
+    type Output = &'static capsules::ble_link_layer::BleLinkLayer<
+        'static,
+        nrf52::ble_radio::Radio<'static>,
+        VirtualMuxAlarm<'static, Rtc<'static>>,
+    >;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let ble_ll_virtual_alarm = static_init!(
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52::rtc::Rtc>,
+            capsules::virtual_alarm::VirtualMuxAlarm::new(self.mux_alarm)
+        );
+
+        let ble_ll = static_init!(
+            capsules::ble_link_layer::BleLinkLayer<
+                'static,
+                nrf52::ble_radio::Radio,
+                VirtualMuxAlarm<'static, Rtc>,
+            >,
+            capsules::ble_link_layer::BleLinkLayer::new(
+                self.radio,
+                ble_ll_virtual_alarm,
+                &mut capsules::ble_link_layer::BUF
+            )
+        );
+        kernel::hil::ble_connection::BleDataRadio::set_data_client(self.radio, ble_ll);
+        ble_ll_virtual_alarm.set_alarm_client(ble_ll);
+
+        ble_ll
+    }
+}
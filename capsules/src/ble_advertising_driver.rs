@@ -7,15 +7,21 @@
 //! driver but processes can request an advertising or scanning interval.
 //! Processes can also control the TX power used for their advertisements.
 //!
-//! Data payloads are limited to 31 bytes since the maximum advertising channel
-//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
+//! Data payloads of legacy advertisements are limited to 31 bytes since the
+//! maximum advertising channel protocol data unit (PDU) is 37 bytes and
+//! includes a 6-byte header. Bluetooth 5 extended advertisements carry up to
+//! 245 bytes in an auxiliary PDU on a secondary advertising channel, which can
+//! use the LE 2M or LE Coded PHY if the radio supports them.
 //!
 //! ### Allow system calls
 //!
-//! There is one ReadWrite and one ReadOnly allow buffers, both at index `0`.
+//! There is one ReadWrite allow buffer at index `0` and two ReadOnly allow buffers at indices `0`
+//! and `1`.
 //!
-//! * ReadOnly: Advertising data, containing the full _payload_ (i.e. excluding the header) the
-//!             process wishes to advertise.
+//! * ReadOnly 0: Advertising data, containing the full _payload_ (i.e. excluding the header) the
+//!               process wishes to advertise.
+//! * ReadOnly 1: Scanning allow-list of advertiser addresses, 6 bytes each. If set, only
+//!               advertisements from these addresses are reported.
 //! * ReadWrite: Passive scanning buffer, which is populated during BLE scans with complete (i.e.
 //!              including headers) advertising packets received on channels 37, 38 and 39.
 //!
@@ -34,7 +40,13 @@
 //!  The `subscribe` is used to specify the specific operation, currently:
 //!
 //! * 0: provides a callback user-space when a device scanning for advertisements
-//!      and the callback is used to invoke user-space processes.
+//!      and the callback is used to invoke user-space processes. The callback
+//!      receives the status, the length of the packet and its RSSI in dBm as a
+//!      signed 8-bit value (-128 if the radio does not measure it).
+//!
+//! Packets received while any process scans are reported to all scanning
+//! processes whose filters accept them, so several processes can scan with
+//! different filters at the same time.
 //!
 //! The possible return codes from the `allow` system call indicate the following:
 //!
@@ -47,9 +59,22 @@
 //! `command number` is used to specify the specific operation, currently
 //! the following commands are supported:
 //!
-//! * 0: start advertisement
+//! * 0: start advertisement, with the PDU type (`ADV_IND`, `ADV_NONCONN_IND`,
+//!      `ADV_SCAN_IND` or `ADV_EXT_IND` for extended advertising) and the
+//!      advertising interval in ms
 //! * 1: stop advertisement or scanning
+//! * 2: configure the TX power in dBm
+//! * 3: configure the PHY of extended advertisements: 1 for LE 1M, 2 for LE 2M
+//!      and 3 for LE Coded
 //! * 5: start scanning
+//! * 6: only report advertisements that contain the AD type given as first
+//!      argument (0 reports all). For manufacturer specific data (0xff), if bit
+//!      16 of the second argument is set, its lower 16 bits are the company
+//!      identifier that must match.
+//! * 7: only report advertisements received with at least the RSSI given as
+//!      signed 8-bit value in dBm
+//! * 8: report the same advertisement from an advertiser at most once within
+//!      the window given in ms (0 reports all, at most 10 s)
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
@@ -97,9 +122,14 @@
 // payload, generated address and PDU type) and perform one advertising event (on each of three
 // channels).
 //
-// This means that advertising events can collide. In this case, we just defer one of the
-// advertisements. Because we add a pseudo random pad to the timer interval each time (as required
-// by the Bluetooth specification) multiple collisions of the same processes are highly unlikely.
+// This means that advertising events can collide. Whenever the radio becomes idle, the expired
+// timer that expired first is served, so each process keeps advertising at close to its own
+// interval, regardless of the intervals of the others. Because we add a pseudo random pad to the
+// timer interval each time (as required by the Bluetooth specification) the order in which
+// processes are served varies.
+//
+// Extended advertising events send an ADV_EXT_IND on each primary channel, each followed by an
+// AUX_ADV_IND with the advertising data on a randomly chosen secondary channel.
 
 use core::cell::Cell;
 use core::cmp;
@@ -107,7 +137,7 @@ use core::mem;
 use kernel::common::cells::OptionalCell;
 use kernel::debug;
 use kernel::hil::ble_advertising;
-use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_advertising::{Phy, RadioChannel};
 use kernel::hil::time::{Frequency, Ticks};
 use kernel::{CommandReturn, ErrorCode, Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
 
@@ -116,11 +146,31 @@ use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::BleAdvertising as usize;
 
 /// Advertisement Buffer
-pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+pub static mut BUF: [u8; BUF_LENGTH] = [0; BUF_LENGTH];
 
 const PACKET_ADDR_LEN: usize = 6;
 const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
+const LEGACY_ADV_DATA_LEN: usize = 31;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.4 Common Extended
+// Advertising Payload Format
+//
+// The ADV_EXT_IND holds the ADI and AuxPtr fields, the AUX_ADV_IND the AdvA and ADI fields
+// followed by the advertising data.
+const EXT_HEADER_ADV_A: u8 = 1 << 0;
+const EXT_HEADER_ADI: u8 = 1 << 3;
+const EXT_HEADER_AUX_PTR: u8 = 1 << 4;
+const ADI_LEN: usize = 2;
+const AUX_PTR_LEN: usize = 3;
+const EXT_IND_HEADER_LEN: usize = 1 + ADI_LEN + AUX_PTR_LEN;
+const EXT_IND_LENGTH: usize = 2 + 1 + EXT_IND_HEADER_LEN;
+const AUX_HEADER_LEN: usize = 1 + PACKET_ADDR_LEN + ADI_LEN;
+const AUX_DATA_OFFSET: usize = 2 + 1 + AUX_HEADER_LEN;
+const EXT_ADV_DATA_LEN: usize = 255 - 1 - AUX_HEADER_LEN;
+/// AUX Offset unit of 30 µs
+const AUX_OFFSET_UNIT_US: u32 = 30;
+const BUF_LENGTH: usize = EXT_IND_LENGTH + AUX_DATA_OFFSET + EXT_ADV_DATA_LEN;
 
 #[derive(PartialEq, Debug)]
 enum BLEState {
@@ -155,16 +205,171 @@ type AdvPduType = u8;
 
 // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.3
 const ADV_IND: AdvPduType = 0b0000;
-#[allow(dead_code)]
 const ADV_DIRECTED_IND: AdvPduType = 0b0001;
 const ADV_NONCONN_IND: AdvPduType = 0b0010;
 #[allow(dead_code)]
 const SCAN_REQ: AdvPduType = 0b0011;
-#[allow(dead_code)]
 const SCAN_RESP: AdvPduType = 0b0100;
 #[allow(dead_code)]
 const CONNECT_IND: AdvPduType = 0b0101;
 const ADV_SCAN_IND: AdvPduType = 0b0110;
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3
+const ADV_EXT_IND: AdvPduType = 0b0111;
+
+// AD type of manufacturer specific data, which starts with the company identifier
+const AD_MANUFACTURER_SPECIFIC_DATA: u8 = 0xff;
+const COMPANY_ID_FILTER_FLAG: usize = 1 << 16;
+/// Number of advertisers remembered for duplicate suppression
+const DUPLICATE_CACHE_LEN: usize = 8;
+const MAX_DUPLICATE_WINDOW_MS: usize = 10_000;
+/// Reported if the radio does not measure the RSSI
+const RSSI_UNKNOWN: i8 = i8::MIN;
+
+// Returns the advertiser address (AdvA) and the advertising data of PDUs that carry them.
+fn advertising_fields(pdu: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = cmp::min(pdu.get(1).map_or(0, |len| *len as usize + 2), pdu.len());
+    if len < 2 + PACKET_ADDR_LEN {
+        return None;
+    }
+    let (address, data) = pdu[2..len].split_at(PACKET_ADDR_LEN);
+    match pdu[0] & 0x0f {
+        ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | SCAN_RESP => Some((address, data)),
+        ADV_DIRECTED_IND => Some((address, &[])),
+        _ => None,
+    }
+}
+
+#[derive(Copy, Clone)]
+struct SeenAdvertisement {
+    address: [u8; PACKET_ADDR_LEN],
+    hash: u32,
+    time: u32,
+}
+
+/// Scanning filters of a process
+struct ScanFilter {
+    ad_type: Option<u8>,
+    company_id: Option<u16>,
+    rssi_threshold: i8,
+    duplicate_window_ms: u32,
+    seen: [Option<SeenAdvertisement>; DUPLICATE_CACHE_LEN],
+}
+
+impl Default for ScanFilter {
+    fn default() -> ScanFilter {
+        ScanFilter {
+            ad_type: None,
+            company_id: None,
+            rssi_threshold: RSSI_UNKNOWN,
+            duplicate_window_ms: 0,
+            seen: [None; DUPLICATE_CACHE_LEN],
+        }
+    }
+}
+
+impl ScanFilter {
+    // Whether the advertisement in `pdu` passes the filters. `allow_list` holds the allowed
+    // advertiser addresses, or nothing to allow all.
+    fn matches(&self, pdu: &[u8], rssi: Option<i8>, allow_list: &[u8]) -> bool {
+        if rssi.map_or(false, |rssi| rssi < self.rssi_threshold) {
+            return false;
+        }
+        let (address, data) = match advertising_fields(pdu) {
+            Some(fields) => fields,
+            None => return allow_list.is_empty() && self.ad_type.is_none(),
+        };
+        if !allow_list.is_empty()
+            && !allow_list
+                .chunks_exact(PACKET_ADDR_LEN)
+                .any(|allowed| allowed == address)
+        {
+            return false;
+        }
+        self.ad_type
+            .map_or(true, |ad_type| self.contains_ad_type(data, ad_type))
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part C], section 11 Advertising and Scan
+    // Response Data Format
+    //
+    // The data is a sequence of AD structures, each made of a length byte, the AD type and the
+    // AD data.
+    fn contains_ad_type(&self, mut data: &[u8], ad_type: u8) -> bool {
+        while data.len() >= 2 {
+            let len = data[0] as usize;
+            if len == 0 || len >= data.len() {
+                break;
+            }
+            let (structure, rest) = data.split_at(len + 1);
+            if structure[1] == ad_type {
+                let company_matches = match self.company_id {
+                    Some(company_id) if ad_type == AD_MANUFACTURER_SPECIFIC_DATA => {
+                        structure.len() >= 4
+                            && u16::from_le_bytes([structure[2], structure[3]]) == company_id
+                    }
+                    _ => true,
+                };
+                if company_matches {
+                    return true;
+                }
+            }
+            data = rest;
+        }
+        false
+    }
+
+    // Whether the same advertiser sent the same advertisement less than `window` ticks before
+    // `now`. Otherwise the advertisement is remembered, replacing the one seen longest ago.
+    fn is_duplicate<T: Ticks>(&mut self, pdu: &[u8], now: T, window: u32) -> bool {
+        if window == 0 {
+            return false;
+        }
+        let (address, data) = match advertising_fields(pdu) {
+            Some(fields) => fields,
+            None => return false,
+        };
+        // FNV-1a over the PDU type and the advertising data
+        let hash = core::iter::once(&(pdu[0] & 0x0f))
+            .chain(data.iter())
+            .fold(0x811c9dc5u32, |hash, byte| {
+                (hash ^ *byte as u32).wrapping_mul(0x01000193)
+            });
+
+        let mut oldest = 0;
+        let mut oldest_age = 0;
+        for (i, entry) in self.seen.iter_mut().enumerate() {
+            match entry {
+                Some(seen) => {
+                    let age = now.wrapping_sub(T::from(seen.time)).into_u32();
+                    if seen.address == address {
+                        if seen.hash == hash && age < window {
+                            return true;
+                        }
+                        seen.hash = hash;
+                        seen.time = now.into_u32();
+                        return false;
+                    }
+                    if age >= oldest_age {
+                        oldest = i;
+                        oldest_age = age;
+                    }
+                }
+                None => {
+                    oldest = i;
+                    oldest_age = u32::max_value();
+                }
+            }
+        }
+        let mut seen = SeenAdvertisement {
+            address: [0; PACKET_ADDR_LEN],
+            hash,
+            time: now.into_u32(),
+        };
+        seen.address.copy_from_slice(address);
+        self.seen[oldest] = Some(seen);
+        false
+    }
+}
 
 /// Process specific memory
 pub struct App {
@@ -177,6 +382,12 @@ pub struct App {
     pdu_type: AdvPduType,
     advertisement_interval_ms: u32,
     tx_power: u8,
+    // Extended advertising
+    phy: Phy,
+    /// Advertising Data ID, changed for every advertising event so that
+    /// scanners do not drop updated data as duplicates
+    data_id: u16,
+    aux_channel: RadioChannel,
     /// The state of an app-specific pseudo random number.
     ///
     /// For example, it can be used for the pseudo-random `advDelay` parameter.
@@ -187,6 +398,8 @@ pub struct App {
     // Scanning meta-data
     scan_buffer: ReadWriteAppSlice,
     scan_callback: kernel::Upcall,
+    scan_filter: ScanFilter,
+    allow_list: ReadOnlyAppSlice,
 }
 
 impl Default for App {
@@ -198,8 +411,13 @@ impl Default for App {
             address: [0; PACKET_ADDR_LEN],
             pdu_type: ADV_NONCONN_IND,
             scan_callback: kernel::Upcall::default(),
+            scan_filter: ScanFilter::default(),
+            allow_list: ReadOnlyAppSlice::default(),
             process_status: Some(BLEState::NotInitialized),
             tx_power: 0,
+            phy: Phy::Le1M,
+            data_id: 0,
+            aux_channel: RadioChannel::DataChannel0,
             advertisement_interval_ms: 200,
             // Just use any non-zero starting value by default
             random_nonce: 0xdeadbeef,
@@ -242,15 +460,17 @@ impl App {
         channel: RadioChannel,
     ) -> Result<(), ErrorCode>
     where
-        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        B: ble_advertising::BleExtendedAdvertisementDriver<'a> + ble_advertising::BleConfig,
         A: kernel::hil::time::Alarm<'a>,
     {
+        if self.pdu_type == ADV_EXT_IND {
+            return self.send_extended_advertisement(ble, channel);
+        }
         self.adv_data.map_or(Err(ErrorCode::FAIL), |adv_data| {
             ble.kernel_tx
                 .take()
                 .map_or(Err(ErrorCode::FAIL), |kernel_tx| {
-                    let adv_data_len =
-                        cmp::min(kernel_tx.len() - PACKET_ADDR_LEN - 2, adv_data.len());
+                    let adv_data_len = cmp::min(LEGACY_ADV_DATA_LEN, adv_data.len());
                     let adv_data_corrected = &adv_data.as_ref()[..adv_data_len];
                     let payload_len = adv_data_corrected.len() + PACKET_ADDR_LEN;
                     {
@@ -279,6 +499,74 @@ impl App {
         })
     }
 
+    fn send_extended_advertisement<'a, B, A>(
+        &self,
+        ble: &BLE<'a, B, A>,
+        channel: RadioChannel,
+    ) -> Result<(), ErrorCode>
+    where
+        B: ble_advertising::BleExtendedAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        let aux_offset = ble.radio.aux_offset_us().ok_or(ErrorCode::NOSUPPORT)?;
+        self.adv_data.map_or(Err(ErrorCode::FAIL), |adv_data| {
+            ble.kernel_tx
+                .take()
+                .map_or(Err(ErrorCode::FAIL), |kernel_tx| {
+                    let adv_data_len = cmp::min(EXT_ADV_DATA_LEN, adv_data.len());
+                    // Advertising Data Info: DID and the advertising set (SID), which is the
+                    // same for all advertisements of a process
+                    let adi = (self.data_id & 0x0fff) | ((self.address[1] as u16 & 0xf) << 12);
+                    // AuxPtr: channel index, 30 µs offset units, AUX Offset and AUX PHY
+                    let aux_phy = match self.phy {
+                        Phy::Le1M => 0,
+                        Phy::Le2M => 1,
+                        Phy::LeCoded => 2,
+                    };
+                    let aux_offset = (aux_offset / AUX_OFFSET_UNIT_US) as u16 & 0x1fff;
+
+                    let (primary, aux) = kernel_tx.split_at_mut(EXT_IND_LENGTH);
+                    // ADV_EXT_IND, non-connectable and non-scannable
+                    primary[0] = ADV_EXT_IND;
+                    primary[1] = (1 + EXT_IND_HEADER_LEN) as u8;
+                    primary[2] = EXT_IND_HEADER_LEN as u8;
+                    primary[3] = EXT_HEADER_ADI | EXT_HEADER_AUX_PTR;
+                    primary[4..6].copy_from_slice(&adi.to_le_bytes());
+                    primary[6] = self.aux_channel.get_channel_index() as u8;
+                    primary[7..9].copy_from_slice(&(aux_offset | aux_phy << 13).to_le_bytes());
+
+                    // AUX_ADV_IND, whose AdvA is a "random" address
+                    aux[0] = ADV_EXT_IND | 1 << ADV_HEADER_TXADD_OFFSET;
+                    aux[1] = (1 + AUX_HEADER_LEN + adv_data_len) as u8;
+                    aux[2] = AUX_HEADER_LEN as u8;
+                    aux[3] = EXT_HEADER_ADV_A | EXT_HEADER_ADI;
+                    aux[4..10].copy_from_slice(&self.address);
+                    aux[10..12].copy_from_slice(&adi.to_le_bytes());
+                    aux[AUX_DATA_OFFSET..AUX_DATA_OFFSET + adv_data_len]
+                        .copy_from_slice(&adv_data.as_ref()[..adv_data_len]);
+
+                    ble.radio.transmit_extended_advertisement(
+                        kernel_tx,
+                        EXT_IND_LENGTH,
+                        AUX_DATA_OFFSET + adv_data_len,
+                        channel,
+                        self.aux_channel,
+                    );
+                    Ok(())
+                })
+        })
+    }
+
+    // Prepares the per-event fields of extended advertisements: a new Advertising Data ID and
+    // a random secondary advertising channel.
+    fn start_extended_event(&mut self) {
+        self.data_id = self.data_id.wrapping_add(1);
+        let index = (self.random_nonce() % 37) as u8;
+        if let Some(channel) = RadioChannel::from_data_channel_index(index) {
+            self.aux_channel = channel;
+        }
+    }
+
     // Returns a new pseudo-random number and updates the randomness state.
     //
     // Uses the [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm to
@@ -304,7 +592,7 @@ impl App {
 
 pub struct BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleExtendedAdvertisementDriver<'a> + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     radio: &'a B,
@@ -318,7 +606,7 @@ where
 
 impl<'a, B, A> BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleExtendedAdvertisementDriver<'a> + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     pub fn new(
@@ -339,7 +627,8 @@ where
     }
 
     // Determines which app timer will expire next and sets the underlying alarm
-    // to it.
+    // to it. Timers that already expired are served once the radio is idle
+    // again, so they are skipped.
     //
     // This method iterates through all grants so it should be used somewhat
     // sparingly. Moreover, it should _not_ be called from within a grant,
@@ -355,7 +644,8 @@ where
                 Expiration::Enabled(reference, dt) => {
                     let exp = reference.wrapping_add(dt);
                     let t_dist = exp.wrapping_sub(now.into_u32());
-                    if next_dist > t_dist {
+                    let expired = !now.within_range(A::Ticks::from(reference), A::Ticks::from(exp));
+                    if !expired && next_dist > t_dist {
                         next_ref = reference;
                         next_dt = dt;
                         next_dist = t_dist;
@@ -369,12 +659,110 @@ where
                 .set_alarm(A::Ticks::from(next_ref), A::Ticks::from(next_dt));
         }
     }
+
+    // Reports a received advertisement to every scanning app whose filters
+    // accept it.
+    fn deliver_scan_result(&self, pdu: &[u8], rssi: Option<i8>) {
+        let now = self.alarm.now();
+        let frequency = <A::Frequency as Frequency>::frequency() as u64;
+        self.app.each(|_, app| {
+            match app.process_status {
+                Some(BLEState::ScanningIdle) | Some(BLEState::Scanning(_)) => {}
+                _ => return,
+            }
+            let filter = &app.scan_filter;
+            let accepted = app
+                .allow_list
+                .map_or(None, |allow_list| {
+                    Some(filter.matches(pdu, rssi, allow_list.as_ref()))
+                })
+                .unwrap_or_else(|| filter.matches(pdu, rssi, &[]));
+            let window = (app.scan_filter.duplicate_window_ms as u64 * frequency / 1000) as u32;
+            if !accepted || app.scan_filter.is_duplicate(pdu, now, window) {
+                return;
+            }
+
+            // write to buffer in userland
+            let success = app.scan_buffer.mut_map_or(false, |userland| {
+                if userland.len() < pdu.len() {
+                    return false;
+                }
+                userland[0..pdu.len()].copy_from_slice(pdu);
+                true
+            });
+
+            if success {
+                app.scan_callback.schedule(
+                    kernel::into_statuscode(Ok(())),
+                    pdu.len(),
+                    rssi.unwrap_or(RSSI_UNKNOWN) as u8 as usize,
+                );
+            }
+        });
+    }
+
+    // If the radio is idle, starts the operation of the app whose timer
+    // expired first.
+    //
+    // Like `reset_active_alarm`, this must not be called from within a grant.
+    fn start_next_operation(&self) {
+        if self.busy.get() {
+            return;
+        }
+        let now = self.alarm.now();
+        let mut next: Option<(kernel::ProcessId, u32)> = None;
+        for app in self.app.iter() {
+            let appid = app.processid();
+            app.enter(|app| {
+                if let Expiration::Enabled(reference, dt) = app.alarm_data.expiration {
+                    let exp = A::Ticks::from(reference.wrapping_add(dt));
+                    if !now.within_range(A::Ticks::from(reference), exp) {
+                        let overdue = now.wrapping_sub(exp).into_u32();
+                        if next.map_or(true, |(_, max)| overdue > max) {
+                            next = Some((appid, overdue));
+                        }
+                    }
+                }
+            });
+        }
+
+        next.map(|(appid, _)| {
+            let _ = self.app.enter(appid, |app| {
+                app.alarm_data.expiration = Expiration::Disabled;
+
+                match app.process_status {
+                    Some(BLEState::AdvertisingIdle) => {
+                        self.busy.set(true);
+                        app.process_status =
+                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
+                        self.sending_app.set(appid);
+                        let _ = self.radio.set_tx_power(app.tx_power);
+                        if app.pdu_type == ADV_EXT_IND {
+                            app.start_extended_event();
+                            let _ = self.radio.set_phy(app.phy);
+                        }
+                        let _ = app.send_advertisement(&self, RadioChannel::AdvertisingChannel37);
+                    }
+                    Some(BLEState::ScanningIdle) => {
+                        self.busy.set(true);
+                        app.process_status =
+                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
+                        self.receiving_app.set(appid);
+                        let _ = self.radio.set_tx_power(app.tx_power);
+                        self.radio
+                            .receive_advertisement(RadioChannel::AdvertisingChannel37);
+                    }
+                    _ => debug!("app: {:?} \t invalid state {:?}", appid, app.process_status),
+                }
+            });
+        });
+    }
 }
 
 // Timer alarm
 impl<'a, B, A> kernel::hil::time::AlarmClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleExtendedAdvertisementDriver<'a> + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     // When an alarm is fired, we find which apps have expired timers. Expired
@@ -382,58 +770,11 @@ where
     // advertising or scanning event). We know which operation based on the
     // current app's state.
     //
-    // In case of collision---if there is already an event happening---we'll
-    // just delay the operation for next time and hope for the best. Since some
-    // randomness is added for each period in an app's timer, collisions should
-    // be rare in practice.
-    //
-    // TODO: perhaps break ties more fairly by prioritizing apps that have least
-    // recently performed an operation.
+    // In case of collision---if there is already an event happening---the
+    // expired timers stay pending and are served in the order they expired
+    // once the radio is idle again.
     fn alarm(&self) {
-        let now = self.alarm.now();
-
-        self.app.each(|appid, app| {
-            if let Expiration::Enabled(reference, dt) = app.alarm_data.expiration {
-                let exp = A::Ticks::from(reference.wrapping_add(dt));
-                let t0 = A::Ticks::from(reference);
-                let expired = !now.within_range(t0, exp);
-                if expired {
-                    if self.busy.get() {
-                        // The radio is currently busy, so we won't be able to start the
-                        // operation at the appropriate time. Instead, reschedule the
-                        // operation for later. This is _kind_ of simulating actual
-                        // on-air interference. 3 seems like a small number of ticks.
-                        debug!("BLE: operation delayed for app {:?}", appid);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
-                        return;
-                    }
-
-                    app.alarm_data.expiration = Expiration::Disabled;
-
-                    match app.process_status {
-                        Some(BLEState::AdvertisingIdle) => {
-                            self.busy.set(true);
-                            app.process_status =
-                                Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
-                            self.sending_app.set(appid);
-                            let _ = self.radio.set_tx_power(app.tx_power);
-                            let _ =
-                                app.send_advertisement(&self, RadioChannel::AdvertisingChannel37);
-                        }
-                        Some(BLEState::ScanningIdle) => {
-                            self.busy.set(true);
-                            app.process_status =
-                                Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
-                            self.receiving_app.set(appid);
-                            let _ = self.radio.set_tx_power(app.tx_power);
-                            self.radio
-                                .receive_advertisement(RadioChannel::AdvertisingChannel37);
-                        }
-                        _ => debug!("app: {:?} \t invalid state {:?}", appid, app.process_status),
-                    }
-                }
-            }
-        });
+        self.start_next_operation();
         self.reset_active_alarm();
     }
 }
@@ -441,37 +782,30 @@ where
 // Callback from the radio once a RX event occur
 impl<'a, B, A> ble_advertising::RxClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleExtendedAdvertisementDriver<'a> + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
-    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: Result<(), ErrorCode>) {
+    fn receive_event(
+        &self,
+        buf: &'static mut [u8],
+        len: u8,
+        rssi: Option<i8>,
+        result: Result<(), ErrorCode>,
+    ) {
+        // Validate the received data, because ordinary BLE packets can be bigger than 39
+        // bytes. Thus, we need to check for that!
+        // Moreover, we use the packet header to find size but the radio reads maximum
+        // 39 bytes.
+        // Therefore, we ignore payloads with a header size bigger than 39 because the
+        // channels 37, 38 and 39 should only be used for advertisements!
+        // Packets that are bigger than 39 bytes are likely `Channel PDUs` which should
+        // only be sent on the other 37 RadioChannel channels.
+        if len <= PACKET_LENGTH as u8 && result == Ok(()) {
+            self.deliver_scan_result(&buf[..len as usize], rssi);
+        }
+
         self.receiving_app.map(|appid| {
             let _ = self.app.enter(*appid, |app| {
-                // Validate the received data, because ordinary BLE packets can be bigger than 39
-                // bytes. Thus, we need to check for that!
-                // Moreover, we use the packet header to find size but the radio reads maximum
-                // 39 bytes.
-                // Therefore, we ignore payloads with a header size bigger than 39 because the
-                // channels 37, 38 and 39 should only be used for advertisements!
-                // Packets that are bigger than 39 bytes are likely `Channel PDUs` which should
-                // only be sent on the other 37 RadioChannel channels.
-
-                if len <= PACKET_LENGTH as u8 && result == Ok(()) {
-                    // write to buffer in userland
-                    let success = app.scan_buffer.mut_map_or(false, |userland| {
-                        userland[0..len as usize].copy_from_slice(&buf[0..len as usize]);
-                        true
-                    });
-
-                    if success {
-                        app.scan_callback.schedule(
-                            kernel::into_statuscode(result),
-                            len as usize,
-                            0,
-                        );
-                    }
-                }
-
                 match app.process_status {
                     Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
                         app.process_status =
@@ -497,6 +831,7 @@ where
                     _ => (),
                 }
             });
+            self.start_next_operation();
             self.reset_active_alarm();
         });
     }
@@ -505,7 +840,7 @@ where
 // Callback from the radio once a TX event occur
 impl<'a, B, A> ble_advertising::TxClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleExtendedAdvertisementDriver<'a> + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     // The Result<(), ErrorCode> indicates valid CRC or not, not used yet but could be used for
@@ -539,6 +874,7 @@ where
                     _ => (),
                 }
             });
+            self.start_next_operation();
             self.reset_active_alarm();
         });
     }
@@ -547,14 +883,14 @@ where
 // System Call implementation
 impl<'a, B, A> kernel::Driver for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleExtendedAdvertisementDriver<'a> + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     fn command(
         &self,
         command_num: usize,
         data: usize,
-        interval: usize,
+        arg2: usize,
         appid: kernel::ProcessId,
     ) -> CommandReturn {
         match command_num {
@@ -564,16 +900,21 @@ where
                     .enter(appid, |app| {
                         if let Some(BLEState::Initialized) = app.process_status {
                             let pdu_type = data as AdvPduType;
-                            match pdu_type {
-                                ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
-                                    app.pdu_type = pdu_type;
-                                    app.process_status = Some(BLEState::AdvertisingIdle);
-                                    app.random_nonce = self.alarm.now().into_u32();
-                                    app.advertisement_interval_ms = cmp::max(20, interval as u32);
-                                    app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
-                                    Ok(())
-                                }
-                                _ => Err(ErrorCode::INVAL),
+                            let supported = match pdu_type {
+                                ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => true,
+                                // Extended advertising needs radio support
+                                ADV_EXT_IND => self.radio.aux_offset_us().is_some(),
+                                _ => false,
+                            };
+                            if supported {
+                                app.pdu_type = pdu_type;
+                                app.process_status = Some(BLEState::AdvertisingIdle);
+                                app.random_nonce = self.alarm.now().into_u32();
+                                app.advertisement_interval_ms = cmp::max(20, arg2 as u32);
+                                app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                                Ok(())
+                            } else {
+                                Err(ErrorCode::INVAL)
                             }
                         } else {
                             Err(ErrorCode::BUSY)
@@ -598,6 +939,7 @@ where
                 .enter(appid, |app| match app.process_status {
                     Some(BLEState::AdvertisingIdle) | Some(BLEState::ScanningIdle) => {
                         app.process_status = Some(BLEState::Initialized);
+                        app.alarm_data.expiration = Expiration::Disabled;
                         CommandReturn::success()
                     }
                     _ => CommandReturn::failure(ErrorCode::BUSY),
@@ -635,6 +977,32 @@ where
                     .unwrap_or_else(|err| err.into())
             }
 
+            // Configure the PHY of the auxiliary PDUs of extended advertisements
+            //
+            // data - 1: LE 1M, 2: LE 2M, 3: LE Coded
+            3 => {
+                self.app
+                    .enter(appid, |app| {
+                        if app.process_status != Some(BLEState::AdvertisingIdle) {
+                            let phy = match data {
+                                1 => Phy::Le1M,
+                                2 => Phy::Le2M,
+                                3 => Phy::LeCoded,
+                                _ => return CommandReturn::failure(ErrorCode::INVAL),
+                            };
+                            // query the underlying chip if the PHY is supported
+                            let status = self.radio.set_phy(phy);
+                            if let Ok(()) = status {
+                                app.phy = phy;
+                            }
+                            status.into()
+                        } else {
+                            CommandReturn::failure(ErrorCode::BUSY)
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
             // Passive scanning mode
             5 => {
                 self.app
@@ -660,6 +1028,50 @@ where
                     )
             }
 
+            // Filter scan results by AD type
+            //
+            // data - AD type that reported advertisements must contain, 0 for all
+            // arg2 - if bit 16 is set, the company identifier of manufacturer specific data
+            6 => self
+                .app
+                .enter(appid, |app| {
+                    app.scan_filter.ad_type = if data == 0 { None } else { Some(data as u8) };
+                    app.scan_filter.company_id = if arg2 & COMPANY_ID_FILTER_FLAG != 0 {
+                        Some(arg2 as u16)
+                    } else {
+                        None
+                    };
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Filter scan results by RSSI
+            //
+            // data - minimum RSSI in dBm, as signed 8-bit value
+            7 => self
+                .app
+                .enter(appid, |app| {
+                    app.scan_filter.rssi_threshold = data as u8 as i8;
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Suppress duplicate scan results
+            //
+            // data - window in ms, 0 to report all
+            8 => {
+                if data > MAX_DUPLICATE_WINDOW_MS {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.app
+                    .enter(appid, |app| {
+                        app.scan_filter.duplicate_window_ms = data as u32;
+                        app.scan_filter.seen = [None; DUPLICATE_CACHE_LEN];
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
         .into()
@@ -683,6 +1095,12 @@ where
                 })
                 .unwrap_or_else(|err| Err(err.into())),
 
+            // Scanning allow-list
+            1 => self
+                .app
+                .enter(appid, |app| mem::swap(&mut app.allow_list, &mut slice))
+                .map_err(ErrorCode::from),
+
             // Operation not supported
             _ => Err(ErrorCode::NOSUPPORT),
         };
@@ -0,0 +1,581 @@
+//! Attribute Protocol (ATT) PDUs.
+//!
+//! Decodes the requests a server receives and encodes its responses. This
+//! module only works on plain byte slices so it can be tested on the host;
+//! `server.rs` looks up the attributes.
+//!
+//! Reference: BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F]
+
+use core::cmp;
+
+/// ATT_MTU of every LE connection until it is exchanged
+pub const DEFAULT_MTU: u16 = 23;
+
+pub mod opcode {
+    pub const ERROR_RSP: u8 = 0x01;
+    pub const EXCHANGE_MTU_REQ: u8 = 0x02;
+    pub const EXCHANGE_MTU_RSP: u8 = 0x03;
+    pub const FIND_INFORMATION_REQ: u8 = 0x04;
+    pub const FIND_INFORMATION_RSP: u8 = 0x05;
+    pub const FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+    pub const FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+    pub const READ_BY_TYPE_REQ: u8 = 0x08;
+    pub const READ_BY_TYPE_RSP: u8 = 0x09;
+    pub const READ_REQ: u8 = 0x0a;
+    pub const READ_RSP: u8 = 0x0b;
+    pub const READ_BLOB_REQ: u8 = 0x0c;
+    pub const READ_BLOB_RSP: u8 = 0x0d;
+    pub const READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+    pub const READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+    pub const WRITE_REQ: u8 = 0x12;
+    pub const WRITE_RSP: u8 = 0x13;
+    pub const HANDLE_VALUE_NTF: u8 = 0x1b;
+    pub const HANDLE_VALUE_IND: u8 = 0x1d;
+    pub const HANDLE_VALUE_CFM: u8 = 0x1e;
+    pub const WRITE_CMD: u8 = 0x52;
+
+    /// Commands are never answered, not even with an error
+    pub const COMMAND_FLAG: u8 = 0x40;
+}
+
+/// Error codes of the Error Response
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AttError {
+    InvalidHandle = 0x01,
+    ReadNotPermitted = 0x02,
+    WriteNotPermitted = 0x03,
+    InvalidPdu = 0x04,
+    RequestNotSupported = 0x06,
+    InvalidOffset = 0x07,
+    AttributeNotFound = 0x0a,
+    InvalidAttributeValueLength = 0x0d,
+    UnlikelyError = 0x0e,
+    UnsupportedGroupType = 0x10,
+}
+
+/// Bluetooth Base UUID 00000000-0000-1000-8000-00805F9B34FB, least
+/// significant byte first
+const BASE_UUID: [u8; 16] = [
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Attribute type. 16-bit UUIDs are aliases of 128-bit UUIDs based on the
+/// Bluetooth Base UUID; all UUIDs are little endian on the air.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Uuid {
+    Uuid16(u16),
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    /// Parses a 2 or 16 byte UUID.
+    pub fn parse(buf: &[u8]) -> Option<Uuid> {
+        match buf.len() {
+            2 => Some(Uuid::Uuid16(get_u16(buf))),
+            16 => {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(buf);
+                Some(Uuid::Uuid128(uuid))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Uuid::Uuid16(_) => 2,
+            Uuid::Uuid128(_) => 16,
+        }
+    }
+
+    /// Writes the UUID to the start of `buf` and returns its length.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        match self {
+            Uuid::Uuid16(uuid) => put_u16(buf, *uuid),
+            Uuid::Uuid128(uuid) => buf[..16].copy_from_slice(uuid),
+        }
+        self.len()
+    }
+
+    fn to_128(&self) -> [u8; 16] {
+        match self {
+            Uuid::Uuid16(uuid) => {
+                let mut full = BASE_UUID;
+                put_u16(&mut full[12..14], *uuid);
+                full
+            }
+            Uuid::Uuid128(uuid) => *uuid,
+        }
+    }
+
+    /// Whether both UUIDs name the same type, also if one of them is the
+    /// 128-bit form of a 16-bit UUID.
+    pub fn matches(&self, other: &Uuid) -> bool {
+        self.to_128() == other.to_128()
+    }
+}
+
+/// The requests and commands a server handles.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Request<'a> {
+    ExchangeMtu {
+        mtu: u16,
+    },
+    FindInformation {
+        start: u16,
+        end: u16,
+    },
+    FindByTypeValue {
+        start: u16,
+        end: u16,
+        attribute_type: u16,
+        value: &'a [u8],
+    },
+    ReadByType {
+        start: u16,
+        end: u16,
+        attribute_type: Uuid,
+    },
+    Read {
+        handle: u16,
+    },
+    ReadBlob {
+        handle: u16,
+        offset: u16,
+    },
+    ReadByGroupType {
+        start: u16,
+        end: u16,
+        group_type: Uuid,
+    },
+    Write {
+        handle: u16,
+        value: &'a [u8],
+    },
+    WriteCommand {
+        handle: u16,
+        value: &'a [u8],
+    },
+    HandleValueConfirmation,
+}
+
+/// A request that cannot be served; the contents of the Error Response.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RequestError {
+    pub opcode: u8,
+    pub handle: u16,
+    pub error: AttError,
+}
+
+impl RequestError {
+    /// Whether the failed PDU was a command, which must be dropped silently.
+    pub fn is_command(&self) -> bool {
+        self.opcode & opcode::COMMAND_FLAG != 0
+    }
+}
+
+impl<'a> Request<'a> {
+    /// Parses the ATT PDU in `pdu`.
+    pub fn parse(pdu: &'a [u8]) -> Result<Request<'a>, RequestError> {
+        let op = *pdu.first().ok_or(RequestError {
+            opcode: 0,
+            handle: 0,
+            error: AttError::InvalidPdu,
+        })?;
+        let error = |handle, error| RequestError {
+            opcode: op,
+            handle,
+            error,
+        };
+        let params = &pdu[1..];
+        let len_is = |len: usize| {
+            if params.len() == len {
+                Ok(())
+            } else {
+                Err(error(0, AttError::InvalidPdu))
+            }
+        };
+        // Handle ranges must start at a valid handle and not be reversed
+        let range = || {
+            let (start, end) = (get_u16(&params[0..2]), get_u16(&params[2..4]));
+            if start == 0 || start > end {
+                Err(error(start, AttError::InvalidHandle))
+            } else {
+                Ok((start, end))
+            }
+        };
+
+        match op {
+            opcode::EXCHANGE_MTU_REQ => {
+                len_is(2)?;
+                Ok(Request::ExchangeMtu {
+                    mtu: get_u16(params),
+                })
+            }
+            opcode::FIND_INFORMATION_REQ => {
+                len_is(4)?;
+                let (start, end) = range()?;
+                Ok(Request::FindInformation { start, end })
+            }
+            opcode::FIND_BY_TYPE_VALUE_REQ => {
+                if params.len() < 6 {
+                    return Err(error(0, AttError::InvalidPdu));
+                }
+                let (start, end) = range()?;
+                Ok(Request::FindByTypeValue {
+                    start,
+                    end,
+                    attribute_type: get_u16(&params[4..6]),
+                    value: &params[6..],
+                })
+            }
+            opcode::READ_BY_TYPE_REQ | opcode::READ_BY_GROUP_TYPE_REQ => {
+                let uuid = if params.len() < 4 {
+                    None
+                } else {
+                    Uuid::parse(&params[4..])
+                };
+                let uuid = uuid.ok_or_else(|| error(0, AttError::InvalidPdu))?;
+                let (start, end) = range()?;
+                if op == opcode::READ_BY_TYPE_REQ {
+                    Ok(Request::ReadByType {
+                        start,
+                        end,
+                        attribute_type: uuid,
+                    })
+                } else {
+                    Ok(Request::ReadByGroupType {
+                        start,
+                        end,
+                        group_type: uuid,
+                    })
+                }
+            }
+            opcode::READ_REQ => {
+                len_is(2)?;
+                Ok(Request::Read {
+                    handle: get_u16(params),
+                })
+            }
+            opcode::READ_BLOB_REQ => {
+                len_is(4)?;
+                Ok(Request::ReadBlob {
+                    handle: get_u16(&params[0..2]),
+                    offset: get_u16(&params[2..4]),
+                })
+            }
+            opcode::WRITE_REQ | opcode::WRITE_CMD => {
+                if params.len() < 2 {
+                    return Err(error(0, AttError::InvalidPdu));
+                }
+                let handle = get_u16(&params[0..2]);
+                let value = &params[2..];
+                if op == opcode::WRITE_REQ {
+                    Ok(Request::Write { handle, value })
+                } else {
+                    Ok(Request::WriteCommand { handle, value })
+                }
+            }
+            opcode::HANDLE_VALUE_CFM => {
+                len_is(0)?;
+                Ok(Request::HandleValueConfirmation)
+            }
+            _ => Err(error(0, AttError::RequestNotSupported)),
+        }
+    }
+}
+
+/// Writes an Error Response and returns its length.
+pub fn encode_error(buf: &mut [u8], request: &RequestError) -> usize {
+    buf[0] = opcode::ERROR_RSP;
+    buf[1] = request.opcode;
+    put_u16(&mut buf[2..4], request.handle);
+    buf[4] = request.error as u8;
+    5
+}
+
+/// Writes an Exchange MTU Response with our receive MTU.
+pub fn encode_exchange_mtu(buf: &mut [u8], mtu: u16) -> usize {
+    buf[0] = opcode::EXCHANGE_MTU_RSP;
+    put_u16(&mut buf[1..3], mtu);
+    3
+}
+
+/// Writes a Read or Read Blob Response, truncating `value` to what fits in
+/// `mtu`.
+pub fn encode_read(buf: &mut [u8], blob: bool, value: &[u8], mtu: u16) -> usize {
+    buf[0] = if blob {
+        opcode::READ_BLOB_RSP
+    } else {
+        opcode::READ_RSP
+    };
+    let len = cmp::min(value.len(), cmp::min(mtu as usize, buf.len()) - 1);
+    buf[1..1 + len].copy_from_slice(&value[..len]);
+    1 + len
+}
+
+pub fn encode_write_response(buf: &mut [u8]) -> usize {
+    buf[0] = opcode::WRITE_RSP;
+    1
+}
+
+/// Writes a Handle Value Notification or Indication, truncating `value` to
+/// what fits in `mtu`.
+pub fn encode_handle_value(
+    buf: &mut [u8],
+    indication: bool,
+    handle: u16,
+    value: &[u8],
+    mtu: u16,
+) -> usize {
+    buf[0] = if indication {
+        opcode::HANDLE_VALUE_IND
+    } else {
+        opcode::HANDLE_VALUE_NTF
+    };
+    put_u16(&mut buf[1..3], handle);
+    let len = cmp::min(value.len(), cmp::min(mtu as usize, buf.len()) - 3);
+    buf[3..3 + len].copy_from_slice(&value[..len]);
+    3 + len
+}
+
+/// Responses that carry a list of attributes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ListKind {
+    /// (handle, UUID) pairs
+    FindInformation,
+    /// (handle, group end handle) pairs
+    FindByTypeValue,
+    /// (handle, value) pairs
+    ReadByType,
+    /// (handle, group end handle, value) triples
+    ReadByGroupType,
+}
+
+/// Builds a list response. All entries of a list have the same length, so
+/// the list ends at the first attribute that differs or does not fit.
+pub struct ListEncoder<'b> {
+    buf: &'b mut [u8],
+    kind: ListKind,
+    limit: usize,
+    len: usize,
+    entry_len: usize,
+}
+
+impl<'b> ListEncoder<'b> {
+    pub fn new(buf: &'b mut [u8], kind: ListKind, mtu: u16) -> ListEncoder<'b> {
+        buf[0] = match kind {
+            ListKind::FindInformation => opcode::FIND_INFORMATION_RSP,
+            ListKind::FindByTypeValue => opcode::FIND_BY_TYPE_VALUE_RSP,
+            ListKind::ReadByType => opcode::READ_BY_TYPE_RSP,
+            ListKind::ReadByGroupType => opcode::READ_BY_GROUP_TYPE_RSP,
+        };
+        let len = match kind {
+            ListKind::FindByTypeValue => 1,
+            // Format or length field
+            _ => 2,
+        };
+        let limit = cmp::min(mtu as usize, buf.len());
+        ListEncoder {
+            buf,
+            kind,
+            limit,
+            len,
+            entry_len: 0,
+        }
+    }
+
+    /// Adds an attribute. `value` is the UUID for `FindInformation`, and
+    /// ignored for `FindByTypeValue`; `group_end` is ignored for
+    /// `FindInformation` and `ReadByType`. Returns false if the attribute was
+    /// not added and the list is complete.
+    pub fn push(&mut self, handle: u16, group_end: u16, value: &[u8]) -> bool {
+        let (has_end, max_value) = match self.kind {
+            ListKind::FindInformation => (false, 16),
+            ListKind::FindByTypeValue => (true, 0),
+            ListKind::ReadByType => (false, cmp::min(self.limit - 4, 253)),
+            ListKind::ReadByGroupType => (true, cmp::min(self.limit - 6, 251)),
+        };
+        let value = &value[..cmp::min(value.len(), max_value)];
+        let entry_len = if has_end { 4 } else { 2 } + value.len();
+
+        if self.entry_len == 0 {
+            self.entry_len = entry_len;
+            match self.kind {
+                ListKind::FindInformation => self.buf[1] = if value.len() == 2 { 1 } else { 2 },
+                ListKind::FindByTypeValue => {}
+                _ => self.buf[1] = entry_len as u8,
+            }
+        } else if entry_len != self.entry_len {
+            return false;
+        }
+        if self.len + entry_len > self.limit {
+            return false;
+        }
+
+        let entry = &mut self.buf[self.len..self.len + entry_len];
+        put_u16(&mut entry[0..2], handle);
+        let value_start = if has_end {
+            put_u16(&mut entry[2..4], group_end);
+            4
+        } else {
+            2
+        };
+        entry[value_start..].copy_from_slice(value);
+        self.len += entry_len;
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entry_len == 0
+    }
+
+    /// Returns the length of the response.
+    pub fn finish(self) -> usize {
+        self.len
+    }
+}
+
+fn get_u16(buf: &[u8]) -> u16 {
+    u16::from_le_bytes([buf[0], buf[1]])
+}
+
+fn put_u16(buf: &mut [u8], val: u16) {
+    buf[0..2].copy_from_slice(&val.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_requests() {
+        assert_eq!(
+            Request::parse(&[0x02, 0xf7, 0x00]),
+            Ok(Request::ExchangeMtu { mtu: 247 })
+        );
+        assert_eq!(
+            Request::parse(&[0x10, 0x01, 0x00, 0xff, 0xff, 0x00, 0x28]),
+            Ok(Request::ReadByGroupType {
+                start: 1,
+                end: 0xffff,
+                group_type: Uuid::Uuid16(0x2800)
+            })
+        );
+        assert_eq!(
+            Request::parse(&[0x06, 0x01, 0x00, 0xff, 0xff, 0x00, 0x28, 0x0d, 0x18]),
+            Ok(Request::FindByTypeValue {
+                start: 1,
+                end: 0xffff,
+                attribute_type: 0x2800,
+                value: &[0x0d, 0x18]
+            })
+        );
+        assert_eq!(
+            Request::parse(&[0x0c, 0x05, 0x00, 0x16, 0x00]),
+            Ok(Request::ReadBlob {
+                handle: 5,
+                offset: 22
+            })
+        );
+        assert_eq!(
+            Request::parse(&[0x52, 0x07, 0x00, 0xaa, 0xbb]),
+            Ok(Request::WriteCommand {
+                handle: 7,
+                value: &[0xaa, 0xbb]
+            })
+        );
+        assert_eq!(
+            Request::parse(&[0x1e]),
+            Ok(Request::HandleValueConfirmation)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        // Reversed handle range
+        assert_eq!(
+            Request::parse(&[0x04, 0x05, 0x00, 0x01, 0x00]),
+            Err(RequestError {
+                opcode: 0x04,
+                handle: 5,
+                error: AttError::InvalidHandle
+            })
+        );
+        // UUID of invalid length
+        assert_eq!(
+            Request::parse(&[0x08, 0x01, 0x00, 0xff, 0xff, 0x03]).map_err(|e| e.error),
+            Err(AttError::InvalidPdu)
+        );
+        assert_eq!(
+            Request::parse(&[0x0a, 0x01]).map_err(|e| e.error),
+            Err(AttError::InvalidPdu)
+        );
+        // Prepare Write Request
+        let err = Request::parse(&[0x16, 0x01, 0x00, 0x00, 0x00]).unwrap_err();
+        assert_eq!(err.error, AttError::RequestNotSupported);
+        assert!(!err.is_command());
+        // Signed Write Command
+        assert!(Request::parse(&[0xd2, 0x01, 0x00])
+            .unwrap_err()
+            .is_command());
+    }
+
+    #[test]
+    fn matches_uuids() {
+        let mut heart_rate = BASE_UUID;
+        heart_rate[12] = 0x0d;
+        heart_rate[13] = 0x18;
+        assert!(Uuid::Uuid16(0x180d).matches(&Uuid::Uuid128(heart_rate)));
+        assert!(!Uuid::Uuid16(0x180f).matches(&Uuid::Uuid128(heart_rate)));
+        assert_eq!(Uuid::parse(&[0x0d, 0x18]), Some(Uuid::Uuid16(0x180d)));
+        assert_eq!(Uuid::parse(&[0x0d]), None);
+    }
+
+    #[test]
+    fn encodes_responses() {
+        let mut buf = [0; 23];
+        let err = RequestError {
+            opcode: opcode::READ_REQ,
+            handle: 0x0102,
+            error: AttError::ReadNotPermitted,
+        };
+        assert_eq!(encode_error(&mut buf, &err), 5);
+        assert_eq!(buf[..5], [0x01, 0x0a, 0x02, 0x01, 0x02]);
+
+        // Values are truncated to ATT_MTU - 1
+        let value = [0x55; 30];
+        assert_eq!(encode_read(&mut buf, false, &value, 23), 23);
+        assert_eq!(buf[0], opcode::READ_RSP);
+
+        assert_eq!(encode_handle_value(&mut buf, true, 3, &[1, 2], 23), 5);
+        assert_eq!(buf[..5], [0x1d, 0x03, 0x00, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn encodes_lists() {
+        let mut buf = [0; 23];
+        let mut list = ListEncoder::new(&mut buf, ListKind::ReadByGroupType, 23);
+        assert!(list.push(1, 5, &[0x00, 0x18]));
+        assert!(list.push(6, 9, &[0x01, 0x18]));
+        // Different length ends the list
+        assert!(!list.push(10, 12, &[0; 16]));
+        assert_eq!(list.finish(), 14);
+        assert_eq!(
+            buf[..14],
+            [0x11, 0x06, 0x01, 0x00, 0x05, 0x00, 0x00, 0x18, 0x06, 0x00, 0x09, 0x00, 0x01, 0x18]
+        );
+
+        let mut buf = [0; 23];
+        let mut list = ListEncoder::new(&mut buf, ListKind::FindInformation, 23);
+        assert!(list.is_empty());
+        assert!(list.push(1, 0, &[0x00, 0x28]));
+        for handle in 2..6 {
+            assert!(list.push(handle, 0, &[0x03, 0x28]));
+        }
+        // 2 + 5 * 4 bytes, the next one does not fit
+        assert!(!list.push(6, 0, &[0x03, 0x28]));
+        assert_eq!(list.finish(), 22);
+        assert_eq!(buf[1], 1);
+    }
+}
@@ -0,0 +1,8 @@
+//! Bluetooth Low Energy GATT server.
+//!
+//! `att` encodes and decodes Attribute Protocol PDUs and does not depend on
+//! the rest of the stack. `server` implements the attribute database and the
+//! syscall driver on top of a `BleConnection`.
+
+pub mod att;
+pub mod server;
@@ -0,0 +1,1099 @@
+//! GATT server on the ATT channel of a `BleConnection`.
+//!
+//! Apps register primary services and characteristics, which the kernel
+//! serves on its own: it answers discovery, reads and writes, keeps the
+//! client characteristic configuration of each notifying characteristic, and
+//! exchanges the MTU. Apps are only involved when the client writes a value
+//! or when they notify or indicate a new value.
+//!
+//! The attribute database starts with the GAP service, with the device name,
+//! and the GATT service. Services and characteristics added by apps follow
+//! in the order they are added; handles are the same for every connection.
+//! The database can only change while disconnected.
+//!
+//! Characteristic values live in the read-write buffer each app allows. Every
+//! characteristic uses a fixed region of that buffer, given when it is added;
+//! the app updates a value by writing its region and setting its length.
+//!
+//! Only the fixed ATT channel of L2CAP is implemented. Pairing requests are
+//! refused and LE signaling commands rejected.
+//!
+//! ### `allow_readwrite`
+//!
+//! - `0`: Buffer that holds the values of the app's characteristics
+//!
+//! ### `allow_readonly`
+//!
+//! - `0`: UUID of the service or characteristic to add, 2 or 16 bytes, least
+//!        significant byte first
+//! - `1`: Advertising data. If not set, the kernel advertises the device name.
+//!
+//! ### `subscribe`
+//!
+//! - `0`: Events. The callback signature is `fn(event, arg1, arg2)`:
+//!   - `fn(0, handle, len)`: the client wrote `len` bytes to a value
+//!   - `fn(1, handle, 0)`: a notification was sent or an indication confirmed
+//!   - `fn(2, connected, reason)`: a client connected (1) or disconnected (0)
+//!   - `fn(3, handle, configuration)`: the client (un)subscribed from the
+//!     value with `handle`; bit 0 is notifications, bit 1 indications
+//!
+//! ### `command`
+//!
+//! - `0`: Driver check.
+//! - `1`: Add a primary service with the UUID in read-only buffer 0. Returns
+//!        the handle of the service.
+//! - `2`: Add a characteristic with the UUID in read-only buffer 0 to the
+//!        app's last service. `arg1` holds the properties (`READ`, `WRITE`,
+//!        ...), `arg2` the offset of the value in the read-write buffer in the
+//!        lower 16 bits and its maximum length in the upper 16 bits. Returns
+//!        the value handle.
+//! - `3`: Set the length of the value with handle `arg1` to `arg2`.
+//! - `4`: Notify or indicate the value with handle `arg1`, as configured by
+//!        the client. Fails with `OFF` if the client did not subscribe.
+//! - `5`: Start advertising, and restart after every disconnection.
+//! - `6`: Stop advertising.
+//! - `7`: Disconnect.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ble_ll = nrf52_components::BLELinkLayerComponent::new(
+//!     &base_peripherals.ble_radio,
+//!     mux_alarm,
+//! )
+//! .finalize(());
+//! let gatt = components::ble_gatt::BleGattComponent::new(board_kernel, ble_ll, b"Tock")
+//!     .finalize(components::ble_gatt_component_helper!(
+//!         capsules::ble_link_layer::BleLinkLayer<
+//!             'static,
+//!             nrf52::ble_radio::Radio,
+//!             VirtualMuxAlarm<'static, nrf52::rtc::Rtc>,
+//!         >
+//!     ));
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ble_connection::{BleConnection, ConnectionClient};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+use super::att::{self, AttError, ListEncoder, ListKind, Request, RequestError, Uuid};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleGatt as usize;
+
+/// Largest ATT_MTU we accept
+pub const MAX_MTU: u16 = 247;
+const L2CAP_HEADER_LEN: usize = 4;
+/// Size of the buffers for complete L2CAP PDUs
+pub const L2CAP_BUFFER_LEN: usize = MAX_MTU as usize + L2CAP_HEADER_LEN;
+/// Size of the buffer for link layer fragments
+pub const FRAGMENT_LEN: usize = 27;
+/// Number of attributes in the database
+pub const MAX_ATTRIBUTES: usize = 64;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part A], section 2.1 Channel
+// Identifiers
+const ATT_CID: u16 = 0x0004;
+const SIGNALING_CID: u16 = 0x0005;
+const SMP_CID: u16 = 0x0006;
+const SIGNALING_COMMAND_REJECT: u8 = 0x01;
+const SIGNALING_CONNECTION_PARAMETER_UPDATE_RSP: u8 = 0x13;
+const SMP_PAIRING_REQUEST: u8 = 0x01;
+const SMP_PAIRING_FAILED: u8 = 0x05;
+const SMP_PAIRING_NOT_SUPPORTED: u8 = 0x05;
+/// Reason sent to the peer when an app disconnects
+const REMOTE_USER_TERMINATED: u8 = 0x13;
+
+// GATT attribute types and services
+const PRIMARY_SERVICE: u16 = 0x2800;
+const CHARACTERISTIC: u16 = 0x2803;
+const CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+const GAP_SERVICE: u16 = 0x1800;
+const GATT_SERVICE: u16 = 0x1801;
+const DEVICE_NAME: u16 = 0x2a00;
+
+/// Characteristic properties
+pub mod properties {
+    pub const READ: u8 = 0x02;
+    pub const WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+    pub const WRITE: u8 = 0x08;
+    pub const NOTIFY: u8 = 0x10;
+    pub const INDICATE: u8 = 0x20;
+    pub(super) const SUPPORTED: u8 = READ | WRITE_WITHOUT_RESPONSE | WRITE | NOTIFY | INDICATE;
+}
+
+const CCCD_NOTIFY: u16 = 0x0001;
+const CCCD_INDICATE: u16 = 0x0002;
+
+// Advertising data types
+const AD_FLAGS: u8 = 0x01;
+const AD_SHORTENED_LOCAL_NAME: u8 = 0x08;
+const AD_COMPLETE_LOCAL_NAME: u8 = 0x09;
+/// LE General Discoverable Mode, BR/EDR Not Supported
+const AD_FLAGS_VALUE: u8 = 0x06;
+const MAX_ADV_DATA_LEN: usize = 31;
+
+#[derive(Copy, Clone)]
+enum Kind {
+    Unused,
+    Service {
+        uuid: Uuid,
+        app: Option<ProcessId>,
+    },
+    /// Characteristic declaration, the value follows at the next handle
+    Characteristic {
+        properties: u8,
+        uuid: Uuid,
+    },
+    Value {
+        uuid: Uuid,
+        properties: u8,
+        app: ProcessId,
+        offset: usize,
+        max_len: usize,
+        len: usize,
+    },
+    /// Client characteristic configuration of the value before it
+    ClientConfiguration {
+        value: u16,
+    },
+    DeviceName,
+}
+
+/// An entry of the attribute database, whose handle is its index plus one.
+#[derive(Copy, Clone)]
+pub struct Attribute {
+    kind: Kind,
+}
+
+impl Attribute {
+    pub const EMPTY: Attribute = Attribute { kind: Kind::Unused };
+
+    fn new(kind: Kind) -> Attribute {
+        Attribute { kind }
+    }
+
+    fn attribute_type(&self) -> Uuid {
+        Uuid::Uuid16(match self.kind {
+            Kind::Unused => 0,
+            Kind::Service { .. } => PRIMARY_SERVICE,
+            Kind::Characteristic { .. } => CHARACTERISTIC,
+            Kind::Value { uuid, .. } => return uuid,
+            Kind::ClientConfiguration { .. } => CLIENT_CHARACTERISTIC_CONFIGURATION,
+            Kind::DeviceName => DEVICE_NAME,
+        })
+    }
+
+    fn readable(&self) -> bool {
+        match self.kind {
+            Kind::Value { properties, .. } => properties & properties::READ != 0,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    values: ReadWriteAppSlice,
+    uuid: ReadOnlyAppSlice,
+    adv_data: ReadOnlyAppSlice,
+}
+
+pub struct GattServer<'a, B: BleConnection<'a>> {
+    ble: &'a B,
+    apps: Grant<App>,
+    device_name: &'static [u8],
+    attributes: TakeCell<'static, [Attribute]>,
+    advertise: Cell<bool>,
+
+    mtu: Cell<u16>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    /// Length of the L2CAP PDU being reassembled, 0 if none
+    rx_expected: Cell<usize>,
+    /// A complete PDU waits in `rx_buffer` until the response can be sent
+    rx_pending: Cell<bool>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    tx_fragment_len: Cell<usize>,
+    fragment: TakeCell<'static, [u8]>,
+    /// Notification in `tx_buffer`, reported once sent
+    notification: OptionalCell<(ProcessId, u16)>,
+    /// Indication waiting for its confirmation
+    indication: OptionalCell<(ProcessId, u16)>,
+}
+
+impl<'a, B: BleConnection<'a>> GattServer<'a, B> {
+    pub fn new(
+        ble: &'a B,
+        grant: Grant<App>,
+        device_name: &'static [u8],
+        attributes: &'static mut [Attribute],
+        rx_buffer: &'static mut [u8],
+        tx_buffer: &'static mut [u8],
+        fragment: &'static mut [u8],
+    ) -> GattServer<'a, B> {
+        // GAP service with the device name and the GATT service
+        attributes[0] = Attribute::new(Kind::Service {
+            uuid: Uuid::Uuid16(GAP_SERVICE),
+            app: None,
+        });
+        attributes[1] = Attribute::new(Kind::Characteristic {
+            properties: properties::READ,
+            uuid: Uuid::Uuid16(DEVICE_NAME),
+        });
+        attributes[2] = Attribute::new(Kind::DeviceName);
+        attributes[3] = Attribute::new(Kind::Service {
+            uuid: Uuid::Uuid16(GATT_SERVICE),
+            app: None,
+        });
+
+        GattServer {
+            ble,
+            apps: grant,
+            device_name,
+            attributes: TakeCell::new(attributes),
+            advertise: Cell::new(false),
+            mtu: Cell::new(att::DEFAULT_MTU),
+            rx_buffer: TakeCell::new(rx_buffer),
+            rx_len: Cell::new(0),
+            rx_expected: Cell::new(0),
+            rx_pending: Cell::new(false),
+            tx_buffer: TakeCell::new(tx_buffer),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            tx_fragment_len: Cell::new(0),
+            fragment: TakeCell::new(fragment),
+            notification: OptionalCell::empty(),
+            indication: OptionalCell::empty(),
+        }
+    }
+
+    fn tx_busy(&self) -> bool {
+        self.tx_len.get() != 0
+    }
+
+    // L2CAP
+
+    // Sends the L2CAP PDU whose payload of `len` bytes is already in
+    // `tx_buffer`
+    fn send_pdu(&self, cid: u16, len: usize) {
+        self.tx_buffer.map(|tx| {
+            tx[0..2].copy_from_slice(&(len as u16).to_le_bytes());
+            tx[2..4].copy_from_slice(&cid.to_le_bytes());
+        });
+        self.tx_len.set(L2CAP_HEADER_LEN + len);
+        self.tx_offset.set(0);
+        self.send_fragment();
+    }
+
+    fn send_fragment(&self) {
+        self.fragment.take().map(|fragment| {
+            let offset = self.tx_offset.get();
+            let len = cmp::min(
+                cmp::min(self.ble.max_payload(), fragment.len()),
+                self.tx_len.get() - offset,
+            );
+            self.tx_buffer
+                .map(|tx| fragment[..len].copy_from_slice(&tx[offset..offset + len]));
+            self.tx_fragment_len.set(len);
+            if let Err((_, fragment)) = self.ble.transmit(fragment, len, offset == 0) {
+                self.fragment.replace(fragment);
+                self.pdu_sent();
+            }
+        });
+    }
+
+    fn pdu_sent(&self) {
+        self.tx_len.set(0);
+        self.notification.take().map(|(appid, handle)| {
+            let _ = self
+                .apps
+                .enter(appid, |app| app.callback.schedule(1, handle as usize, 0));
+        });
+        if self.rx_pending.take() {
+            self.process_pdu();
+        }
+    }
+
+    fn reset_bearer(&self) {
+        self.mtu.set(att::DEFAULT_MTU);
+        self.rx_len.set(0);
+        self.rx_expected.set(0);
+        self.rx_pending.set(false);
+        self.tx_len.set(0);
+        self.notification.clear();
+        self.indication.clear();
+    }
+
+    // Handles the complete L2CAP PDU in `rx_buffer`
+    fn process_pdu(&self) {
+        let response = self.rx_buffer.map_or(None, |rx| {
+            self.tx_buffer.map_or(None, |tx| {
+                let len = u16::from_le_bytes([rx[0], rx[1]]) as usize;
+                let cid = u16::from_le_bytes([rx[2], rx[3]]);
+                let payload = &rx[L2CAP_HEADER_LEN..L2CAP_HEADER_LEN + len];
+                let out = &mut tx[L2CAP_HEADER_LEN..];
+                match cid {
+                    ATT_CID => self.handle_att(payload, out).map(|len| (ATT_CID, len)),
+                    SMP_CID if payload.first() == Some(&SMP_PAIRING_REQUEST) => {
+                        out[0] = SMP_PAIRING_FAILED;
+                        out[1] = SMP_PAIRING_NOT_SUPPORTED;
+                        Some((SMP_CID, 2))
+                    }
+                    SIGNALING_CID if payload.len() >= 2 => match payload[0] {
+                        SIGNALING_COMMAND_REJECT | SIGNALING_CONNECTION_PARAMETER_UPDATE_RSP => {
+                            None
+                        }
+                        _ => {
+                            // Command not understood
+                            out[0] = SIGNALING_COMMAND_REJECT;
+                            out[1] = payload[1];
+                            out[2..6].copy_from_slice(&[2, 0, 0, 0]);
+                            Some((SIGNALING_CID, 6))
+                        }
+                    },
+                    _ => None,
+                }
+            })
+        });
+        self.rx_len.set(0);
+        self.rx_expected.set(0);
+        if let Some((cid, len)) = response {
+            self.send_pdu(cid, len);
+        }
+    }
+
+    // ATT
+
+    // Handles an ATT PDU and writes the response to `out`. Returns the length
+    // of the response, or None if there is none.
+    fn handle_att(&self, pdu: &[u8], out: &mut [u8]) -> Option<usize> {
+        let mtu = self.mtu.get();
+        let result = Request::parse(pdu).and_then(|request| {
+            self.attributes.map_or(
+                Err(RequestError {
+                    opcode: pdu[0],
+                    handle: 0,
+                    error: AttError::UnlikelyError,
+                }),
+                |attributes| {
+                    self.handle_request(attributes, request, out, mtu)
+                        .map_err(|(handle, error)| RequestError {
+                            opcode: pdu[0],
+                            handle,
+                            error,
+                        })
+                },
+            )
+        });
+        match result {
+            Ok(len) => len,
+            Err(error) if error.is_command() => None,
+            Err(error) => Some(att::encode_error(out, &error)),
+        }
+    }
+
+    fn handle_request(
+        &self,
+        attributes: &mut [Attribute],
+        request: Request,
+        out: &mut [u8],
+        mtu: u16,
+    ) -> Result<Option<usize>, (u16, AttError)> {
+        // Handles of all used attributes
+        let count = attributes
+            .iter()
+            .take_while(|attribute| !matches!(attribute.kind, Kind::Unused))
+            .count() as u16;
+        let range = |start: u16, end: u16| start..=cmp::min(end, count);
+        let mut value = [0; MAX_MTU as usize];
+
+        match request {
+            Request::ExchangeMtu { mtu } => {
+                self.mtu
+                    .set(cmp::max(att::DEFAULT_MTU, cmp::min(mtu, MAX_MTU)));
+                Ok(Some(att::encode_exchange_mtu(out, MAX_MTU)))
+            }
+            Request::FindInformation { start, end } => {
+                let mut list = ListEncoder::new(out, ListKind::FindInformation, mtu);
+                for handle in range(start, end) {
+                    let uuid = attributes[handle as usize - 1].attribute_type();
+                    let len = uuid.encode(&mut value);
+                    if !list.push(handle, 0, &value[..len]) {
+                        break;
+                    }
+                }
+                if list.is_empty() {
+                    Err((start, AttError::AttributeNotFound))
+                } else {
+                    Ok(Some(list.finish()))
+                }
+            }
+            Request::FindByTypeValue {
+                start,
+                end,
+                attribute_type,
+                value: wanted,
+            } => {
+                let mut list = ListEncoder::new(out, ListKind::FindByTypeValue, mtu);
+                for handle in range(start, end) {
+                    let attribute = &attributes[handle as usize - 1];
+                    if attribute.attribute_type() != Uuid::Uuid16(attribute_type)
+                        || !attribute.readable()
+                    {
+                        continue;
+                    }
+                    let len = self.read_value(attribute, handle, 0, &mut value)?;
+                    if &value[..len] == wanted {
+                        let end = group_end(attributes, handle);
+                        if !list.push(handle, end, &[]) {
+                            break;
+                        }
+                    }
+                }
+                if list.is_empty() {
+                    Err((start, AttError::AttributeNotFound))
+                } else {
+                    Ok(Some(list.finish()))
+                }
+            }
+            Request::ReadByType {
+                start,
+                end,
+                attribute_type,
+            } => {
+                let mut list = ListEncoder::new(out, ListKind::ReadByType, mtu);
+                for handle in range(start, end) {
+                    let attribute = &attributes[handle as usize - 1];
+                    if !attribute.attribute_type().matches(&attribute_type) {
+                        continue;
+                    }
+                    if !attribute.readable() {
+                        if list.is_empty() {
+                            return Err((handle, AttError::ReadNotPermitted));
+                        }
+                        break;
+                    }
+                    let len = self.read_value(attribute, handle, 0, &mut value)?;
+                    if !list.push(handle, 0, &value[..len]) {
+                        break;
+                    }
+                }
+                if list.is_empty() {
+                    Err((start, AttError::AttributeNotFound))
+                } else {
+                    Ok(Some(list.finish()))
+                }
+            }
+            Request::Read { handle } | Request::ReadBlob { handle, .. } => {
+                let offset = match request {
+                    Request::ReadBlob { offset, .. } => offset as usize,
+                    _ => 0,
+                };
+                if handle == 0 || handle > count {
+                    return Err((handle, AttError::InvalidHandle));
+                }
+                let attribute = &attributes[handle as usize - 1];
+                if !attribute.readable() {
+                    return Err((handle, AttError::ReadNotPermitted));
+                }
+                let len = self.read_value(attribute, handle, offset, &mut value)?;
+                let blob = offset != 0 || matches!(request, Request::ReadBlob { .. });
+                Ok(Some(att::encode_read(out, blob, &value[..len], mtu)))
+            }
+            Request::ReadByGroupType {
+                start,
+                end,
+                group_type,
+            } => {
+                if !group_type.matches(&Uuid::Uuid16(PRIMARY_SERVICE)) {
+                    return Err((start, AttError::UnsupportedGroupType));
+                }
+                let mut list = ListEncoder::new(out, ListKind::ReadByGroupType, mtu);
+                for handle in range(start, end) {
+                    if let Kind::Service { uuid, .. } = attributes[handle as usize - 1].kind {
+                        let len = uuid.encode(&mut value);
+                        let end = group_end(attributes, handle);
+                        if !list.push(handle, end, &value[..len]) {
+                            break;
+                        }
+                    }
+                }
+                if list.is_empty() {
+                    Err((start, AttError::AttributeNotFound))
+                } else {
+                    Ok(Some(list.finish()))
+                }
+            }
+            Request::Write { handle, value } => {
+                self.write_value(attributes, handle, count, value, false)?;
+                Ok(Some(att::encode_write_response(out)))
+            }
+            Request::WriteCommand { handle, value } => {
+                // Commands have no response, not even errors
+                let _ = self.write_value(attributes, handle, count, value, true);
+                Ok(None)
+            }
+            Request::HandleValueConfirmation => {
+                self.indication.take().map(|(appid, handle)| {
+                    let _ = self
+                        .apps
+                        .enter(appid, |app| app.callback.schedule(1, handle as usize, 0));
+                });
+                Ok(None)
+            }
+        }
+    }
+
+    // Copies the value of `attribute` from `offset` on to `out` and returns
+    // its length
+    fn read_value(
+        &self,
+        attribute: &Attribute,
+        handle: u16,
+        offset: usize,
+        out: &mut [u8],
+    ) -> Result<usize, (u16, AttError)> {
+        let mut full = [0; 2 + 1 + 2 + 16];
+        let value: &[u8] = match attribute.kind {
+            Kind::Unused => return Err((handle, AttError::InvalidHandle)),
+            Kind::Service { uuid, .. } => {
+                let len = uuid.encode(&mut full);
+                &full[..len]
+            }
+            Kind::Characteristic { properties, uuid } => {
+                full[0] = properties;
+                full[1..3].copy_from_slice(&(handle + 1).to_le_bytes());
+                let len = uuid.encode(&mut full[3..]);
+                &full[..3 + len]
+            }
+            Kind::ClientConfiguration { value } => {
+                full[0..2].copy_from_slice(&value.to_le_bytes());
+                &full[..2]
+            }
+            Kind::DeviceName => self.device_name,
+            Kind::Value {
+                app,
+                offset: start,
+                len,
+                ..
+            } => {
+                return self
+                    .apps
+                    .enter(app, |app| {
+                        app.values.map_or(Err(AttError::UnlikelyError), |values| {
+                            let values = values.as_ref();
+                            if start + len > values.len() {
+                                Err(AttError::UnlikelyError)
+                            } else if offset > len {
+                                Err(AttError::InvalidOffset)
+                            } else {
+                                let copy = cmp::min(len - offset, out.len());
+                                out[..copy].copy_from_slice(
+                                    &values[start + offset..start + offset + copy],
+                                );
+                                Ok(copy)
+                            }
+                        })
+                    })
+                    .unwrap_or(Err(AttError::UnlikelyError))
+                    .map_err(|error| (handle, error));
+            }
+        };
+        if offset > value.len() {
+            return Err((handle, AttError::InvalidOffset));
+        }
+        let len = cmp::min(value.len() - offset, out.len());
+        out[..len].copy_from_slice(&value[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_value(
+        &self,
+        attributes: &mut [Attribute],
+        handle: u16,
+        count: u16,
+        value: &[u8],
+        command: bool,
+    ) -> Result<(), (u16, AttError)> {
+        if handle == 0 || handle > count {
+            return Err((handle, AttError::InvalidHandle));
+        }
+        let index = handle as usize - 1;
+        match attributes[index].kind {
+            Kind::Value {
+                properties,
+                app,
+                offset,
+                max_len,
+                ..
+            } => {
+                let permission = if command {
+                    properties::WRITE_WITHOUT_RESPONSE
+                } else {
+                    properties::WRITE
+                };
+                if properties & permission == 0 {
+                    return Err((handle, AttError::WriteNotPermitted));
+                }
+                if value.len() > max_len {
+                    return Err((handle, AttError::InvalidAttributeValueLength));
+                }
+                self.apps
+                    .enter(app, |app| {
+                        let written = app.values.mut_map_or(false, |values| {
+                            let values = values.as_mut();
+                            if offset + value.len() > values.len() {
+                                return false;
+                            }
+                            values[offset..offset + value.len()].copy_from_slice(value);
+                            true
+                        });
+                        if written {
+                            app.callback.schedule(0, handle as usize, value.len());
+                        }
+                        written
+                    })
+                    .ok()
+                    .filter(|written| *written)
+                    .ok_or((handle, AttError::UnlikelyError))?;
+                if let Kind::Value { ref mut len, .. } = attributes[index].kind {
+                    *len = value.len();
+                }
+                Ok(())
+            }
+            Kind::ClientConfiguration { .. } => {
+                if value.len() != 2 {
+                    return Err((handle, AttError::InvalidAttributeValueLength));
+                }
+                let configuration = u16::from_le_bytes([value[0], value[1]]);
+                attributes[index].kind = Kind::ClientConfiguration {
+                    value: configuration,
+                };
+                // The descriptor directly follows its value
+                if let Kind::Value { app, .. } = attributes[index - 1].kind {
+                    let _ = self.apps.enter(app, |app| {
+                        app.callback
+                            .schedule(3, handle as usize - 1, configuration as usize)
+                    });
+                }
+                Ok(())
+            }
+            _ => Err((handle, AttError::WriteNotPermitted)),
+        }
+    }
+
+    // Database changes from apps
+
+    fn uuid(app: &App) -> Result<Uuid, ErrorCode> {
+        app.uuid
+            .map_or(None, |uuid| Uuid::parse(uuid.as_ref()))
+            .ok_or(ErrorCode::INVAL)
+    }
+
+    fn add_service(&self, appid: ProcessId, app: &App) -> Result<u32, ErrorCode> {
+        let uuid = Self::uuid(app)?;
+        if self.ble.is_connected() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.attributes.map_or(Err(ErrorCode::FAIL), |attributes| {
+            let index = first_unused(attributes).ok_or(ErrorCode::NOMEM)?;
+            attributes[index] = Attribute::new(Kind::Service {
+                uuid,
+                app: Some(appid),
+            });
+            Ok(index as u32 + 1)
+        })
+    }
+
+    fn add_characteristic(
+        &self,
+        appid: ProcessId,
+        app: &App,
+        properties: u8,
+        location: usize,
+    ) -> Result<u32, ErrorCode> {
+        let uuid = Self::uuid(app)?;
+        let offset = location & 0xffff;
+        let max_len = location >> 16;
+        if max_len == 0 || properties & !properties::SUPPORTED != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.ble.is_connected() {
+            return Err(ErrorCode::BUSY);
+        }
+        let notifies = properties & (properties::NOTIFY | properties::INDICATE) != 0;
+
+        self.attributes.map_or(Err(ErrorCode::FAIL), |attributes| {
+            let index = first_unused(attributes).ok_or(ErrorCode::NOMEM)?;
+            // The characteristic belongs to the last service, which must be
+            // one of the app's
+            let owner = attributes[..index].iter().rev().find_map(|attribute| {
+                if let Kind::Service { app, .. } = attribute.kind {
+                    Some(app)
+                } else {
+                    None
+                }
+            });
+            if owner != Some(Some(appid)) {
+                return Err(ErrorCode::INVAL);
+            }
+            let needed = if notifies { 3 } else { 2 };
+            if index + needed > attributes.len() {
+                return Err(ErrorCode::NOMEM);
+            }
+
+            attributes[index] = Attribute::new(Kind::Characteristic { properties, uuid });
+            attributes[index + 1] = Attribute::new(Kind::Value {
+                uuid,
+                properties,
+                app: appid,
+                offset,
+                max_len,
+                len: 0,
+            });
+            if notifies {
+                attributes[index + 2] = Attribute::new(Kind::ClientConfiguration { value: 0 });
+            }
+            Ok(index as u32 + 2)
+        })
+    }
+
+    // Returns the value attribute with `handle` if it belongs to `appid`
+    fn app_value(
+        attributes: &mut [Attribute],
+        appid: ProcessId,
+        handle: usize,
+    ) -> Result<&mut Attribute, ErrorCode> {
+        match attributes.get_mut(handle.wrapping_sub(1)) {
+            Some(attribute) => match attribute.kind {
+                Kind::Value { app, .. } if app == appid => Ok(attribute),
+                _ => Err(ErrorCode::INVAL),
+            },
+            None => Err(ErrorCode::INVAL),
+        }
+    }
+
+    fn set_value_len(
+        &self,
+        appid: ProcessId,
+        handle: usize,
+        new_len: usize,
+    ) -> Result<(), ErrorCode> {
+        self.attributes.map_or(Err(ErrorCode::FAIL), |attributes| {
+            let attribute = Self::app_value(attributes, appid, handle)?;
+            match attribute.kind {
+                Kind::Value {
+                    max_len,
+                    ref mut len,
+                    ..
+                } if new_len <= max_len => {
+                    *len = new_len;
+                    Ok(())
+                }
+                _ => Err(ErrorCode::SIZE),
+            }
+        })
+    }
+
+    fn notify(&self, appid: ProcessId, app: &App, handle: usize) -> Result<(), ErrorCode> {
+        if !self.ble.is_connected() {
+            return Err(ErrorCode::OFF);
+        }
+        if self.tx_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        let mtu = self.mtu.get();
+        let pdu_len = self.attributes.map_or(Err(ErrorCode::FAIL), |attributes| {
+            let (properties, offset, len) = match Self::app_value(attributes, appid, handle)?.kind {
+                Kind::Value {
+                    properties,
+                    offset,
+                    len,
+                    ..
+                } => (properties, offset, len),
+                _ => return Err(ErrorCode::INVAL),
+            };
+            let configuration = match attributes.get(handle).map(|attribute| attribute.kind) {
+                Some(Kind::ClientConfiguration { value }) => value,
+                _ => 0,
+            };
+            let indicate =
+                if configuration & CCCD_INDICATE != 0 && properties & properties::INDICATE != 0 {
+                    true
+                } else if configuration & CCCD_NOTIFY != 0 && properties & properties::NOTIFY != 0 {
+                    false
+                } else {
+                    return Err(ErrorCode::OFF);
+                };
+            if indicate && self.indication.is_some() {
+                return Err(ErrorCode::BUSY);
+            }
+
+            let pdu_len = app.values.map_or(Err(ErrorCode::INVAL), |values| {
+                let values = values.as_ref();
+                if offset + len > values.len() {
+                    return Err(ErrorCode::SIZE);
+                }
+                self.tx_buffer.map_or(Err(ErrorCode::FAIL), |tx| {
+                    Ok(att::encode_handle_value(
+                        &mut tx[L2CAP_HEADER_LEN..],
+                        indicate,
+                        handle as u16,
+                        &values[offset..offset + len],
+                        mtu,
+                    ))
+                })
+            })?;
+            if indicate {
+                self.indication.set((appid, handle as u16));
+            } else {
+                self.notification.set((appid, handle as u16));
+            }
+            Ok(pdu_len)
+        })?;
+        self.send_pdu(ATT_CID, pdu_len);
+        Ok(())
+    }
+
+    // Advertising
+
+    fn start_advertising(&self, app: &App) -> Result<(), ErrorCode> {
+        let mut adv_data = [0; MAX_ADV_DATA_LEN];
+        let len = app.adv_data.map_or(0, |data| {
+            let data = data.as_ref();
+            let len = cmp::min(data.len(), MAX_ADV_DATA_LEN);
+            adv_data[..len].copy_from_slice(&data[..len]);
+            len
+        });
+        let len = if len > 0 {
+            len
+        } else {
+            // Flags and the (possibly shortened) device name
+            let name_len = cmp::min(self.device_name.len(), MAX_ADV_DATA_LEN - 5);
+            adv_data[..3].copy_from_slice(&[2, AD_FLAGS, AD_FLAGS_VALUE]);
+            adv_data[3] = name_len as u8 + 1;
+            adv_data[4] = if name_len < self.device_name.len() {
+                AD_SHORTENED_LOCAL_NAME
+            } else {
+                AD_COMPLETE_LOCAL_NAME
+            };
+            adv_data[5..5 + name_len].copy_from_slice(&self.device_name[..name_len]);
+            5 + name_len
+        };
+        self.ble.set_advertising_data(&adv_data[..len])?;
+        self.advertise.set(true);
+        match self.ble.start_advertising() {
+            // Advertising restarts after the connection closes
+            Err(ErrorCode::BUSY) | Err(ErrorCode::ALREADY) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+// The last handle of the group that starts at `handle`
+fn group_end(attributes: &[Attribute], handle: u16) -> u16 {
+    match attributes[handle as usize - 1].kind {
+        Kind::Service { .. } => attributes[handle as usize..]
+            .iter()
+            .position(|attribute| matches!(attribute.kind, Kind::Service { .. } | Kind::Unused))
+            .map_or(attributes.len() as u16, |position| handle + position as u16),
+        _ => handle,
+    }
+}
+
+fn first_unused(attributes: &[Attribute]) -> Option<usize> {
+    attributes
+        .iter()
+        .position(|attribute| matches!(attribute.kind, Kind::Unused))
+}
+
+impl<'a, B: BleConnection<'a>> ConnectionClient for GattServer<'a, B> {
+    fn connected(&self, _peer: [u8; 6], _peer_random: bool) {
+        self.reset_bearer();
+        // Subscriptions do not persist without bonding
+        self.attributes.map(|attributes| {
+            for attribute in attributes.iter_mut() {
+                if let Kind::ClientConfiguration { ref mut value } = attribute.kind {
+                    *value = 0;
+                }
+            }
+        });
+        self.apps.each(|_, app| {
+            app.callback.schedule(2, 1, 0);
+        });
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.reset_bearer();
+        self.apps.each(|_, app| {
+            app.callback.schedule(2, 0, reason as usize);
+        });
+        if self.advertise.get() {
+            let _ = self.ble.start_advertising();
+        }
+    }
+
+    fn received(&self, data: &[u8], start: bool) {
+        if self.rx_pending.get() {
+            // The client must wait for our response
+            return;
+        }
+        let complete = self.rx_buffer.map_or(false, |rx| {
+            if start {
+                if data.len() < L2CAP_HEADER_LEN {
+                    self.rx_expected.set(0);
+                    return false;
+                }
+                let len = u16::from_le_bytes([data[0], data[1]]) as usize + L2CAP_HEADER_LEN;
+                // Drop PDUs we cannot hold
+                self.rx_expected.set(if len <= rx.len() { len } else { 0 });
+                self.rx_len.set(0);
+            }
+            let expected = self.rx_expected.get();
+            if expected == 0 {
+                return false;
+            }
+            let offset = self.rx_len.get();
+            let len = cmp::min(data.len(), expected - offset);
+            rx[offset..offset + len].copy_from_slice(&data[..len]);
+            self.rx_len.set(offset + len);
+            offset + len == expected
+        });
+        if complete {
+            if self.tx_busy() {
+                self.rx_pending.set(true);
+            } else {
+                self.process_pdu();
+            }
+        }
+    }
+
+    fn transmitted(&self, buf: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.fragment.replace(buf);
+        if result.is_err() || !self.tx_busy() {
+            return;
+        }
+        self.tx_offset
+            .set(self.tx_offset.get() + self.tx_fragment_len.get());
+        if self.tx_offset.get() < self.tx_len.get() {
+            self.send_fragment();
+        } else {
+            self.pdu_sent();
+        }
+    }
+}
+
+impl<'a, B: BleConnection<'a>> Driver for GattServer<'a, B> {
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut slice, &mut app.values))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut slice, &mut app.uuid))
+                .map_err(ErrorCode::from),
+            1 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut slice, &mut app.adv_data))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app| mem::swap(&mut app.callback, &mut callback))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        if command_num == 0 {
+            return CommandReturn::success();
+        }
+
+        let res = self
+            .apps
+            .enter(appid, |app| match command_num {
+                1 => self.add_service(appid, app).map(Some),
+                2 => self
+                    .add_characteristic(appid, app, arg1 as u8, arg2)
+                    .map(Some),
+                3 => self.set_value_len(appid, arg1, arg2).map(|_| None),
+                4 => self.notify(appid, app, arg1).map(|_| None),
+                5 => self.start_advertising(app).map(|_| None),
+                6 => {
+                    self.advertise.set(false);
+                    self.ble.stop_advertising().map(|_| None)
+                }
+                7 => self.ble.disconnect(REMOTE_USER_TERMINATED).map(|_| None),
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(Some(handle)) => CommandReturn::success_u32(handle),
+            Ok(None) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
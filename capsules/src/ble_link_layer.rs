@@ -0,0 +1,1109 @@
+//! Bluetooth Low Energy Link Layer, peripheral role
+//!
+//! Implements connectable undirected advertising and the slave side of a
+//! connection on top of a radio that provides `hil::ble_connection::BleDataRadio`.
+//! Established connections are exposed to L2CAP through
+//! `hil::ble_connection::BleConnection`.
+//!
+//! The link layer
+//!
+//! * advertises with `ADV_IND` on channels 37, 38 and 39, answers `SCAN_REQ`
+//!   with an empty `SCAN_RSP` and accepts `CONNECT_IND` addressed to it,
+//! * schedules connection events from the anchor point of the last event,
+//!   widening the receive window by the sleep clock accuracy of both sides,
+//! * hops channels with channel selection algorithm #1,
+//! * acknowledges and retransmits data channel PDUs with the SN and NESN bits,
+//! * handles the connection update, channel map update and termination
+//!   procedures, and answers feature, version, ping and length requests.
+//!
+//! The link layer listens in every connection event, i.e. it does not make
+//! use of the slave latency, and it does not support data length extension,
+//! so L2CAP fragments are at most 27 bytes.
+//!
+//! It must not be used at the same time as the advertising driver on the same
+//! radio.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let ble_ll_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let ble_ll = static_init!(
+//!     capsules::ble_link_layer::BleLinkLayer<
+//!         'static,
+//!         nrf52::ble_radio::Radio,
+//!         VirtualMuxAlarm<'static, nrf52::rtc::Rtc>,
+//!     >,
+//!     capsules::ble_link_layer::BleLinkLayer::new(
+//!         &nrf52::ble_radio::RADIO,
+//!         ble_ll_alarm,
+//!         &mut capsules::ble_link_layer::BUF,
+//!     )
+//! );
+//! kernel::hil::ble_connection::BleDataRadio::set_data_client(&nrf52::ble_radio::RADIO, ble_ll);
+//! ble_ll_alarm.set_alarm_client(ble_ll);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_connection::{self, BleConnection, BleDataRadio, ConnectionClient};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ErrorCode;
+
+/// Radio transmit buffer
+pub static mut BUF: [u8; PDU_LENGTH] = [0; PDU_LENGTH];
+
+/// Largest PDU that is sent or received: an advertising channel PDU with 31
+/// bytes of advertising data.
+const PDU_LENGTH: usize = 39;
+const ADDRESS_LEN: usize = 6;
+const MAX_ADV_DATA_LEN: usize = 31;
+/// Maximum payload of a data channel PDU without data length extension
+const MAX_PAYLOAD: usize = 27;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3
+const ADV_IND: u8 = 0b0000;
+const SCAN_REQ: u8 = 0b0011;
+const SCAN_RSP: u8 = 0b0100;
+const CONNECT_IND: u8 = 0b0101;
+const ADV_PDU_TYPE_MASK: u8 = 0x0f;
+const ADV_TXADD: u8 = 1 << 6;
+const ADV_RXADD: u8 = 1 << 7;
+const SCAN_REQ_LEN: usize = 12;
+const CONNECT_IND_LEN: usize = 34;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4 Data
+// Channel PDU
+const LLID_CONTINUATION: u8 = 0b01;
+const LLID_START: u8 = 0b10;
+const LLID_CONTROL: u8 = 0b11;
+const LLID_MASK: u8 = 0b11;
+const NESN: u8 = 1 << 2;
+const SN: u8 = 1 << 3;
+const MD: u8 = 1 << 4;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4.2 LL
+// Control PDU
+const LL_CONNECTION_UPDATE_IND: u8 = 0x00;
+const LL_CHANNEL_MAP_IND: u8 = 0x01;
+const LL_TERMINATE_IND: u8 = 0x02;
+const LL_UNKNOWN_RSP: u8 = 0x07;
+const LL_FEATURE_REQ: u8 = 0x08;
+const LL_FEATURE_RSP: u8 = 0x09;
+const LL_VERSION_IND: u8 = 0x0C;
+const LL_SLAVE_FEATURE_REQ: u8 = 0x0E;
+const LL_PING_REQ: u8 = 0x12;
+const LL_PING_RSP: u8 = 0x13;
+const LL_LENGTH_REQ: u8 = 0x14;
+const LL_LENGTH_RSP: u8 = 0x15;
+const MAX_CONTROL_LEN: usize = 23;
+
+/// Bluetooth Core Specification 4.2
+const LL_VERSION: u8 = 0x08;
+/// Company identifier reserved for devices that do not have one
+const COMPANY_ID: u16 = 0xffff;
+
+// HCI error codes reported to the client
+const LOCAL_HOST_TERMINATED: u8 = 0x16;
+const CONNECTION_TIMEOUT: u8 = 0x08;
+const INSTANT_PASSED: u8 = 0x28;
+const CONNECTION_FAILED_TO_ESTABLISH: u8 = 0x3E;
+
+/// Time to listen for `SCAN_REQ` or `CONNECT_IND` after each `ADV_IND`
+const ADVERTISING_LISTEN_US: u32 = 1000;
+/// Time needed to configure the radio and ramp it up before an event
+const RADIO_SETUP_US: u32 = 250;
+/// Time to keep listening after the expected start of a packet. This covers
+/// the longest data channel PDU without data length extension.
+const RECEIVE_TIMEOUT_US: u32 = 400;
+/// Time to wait for the next packet of the central within an event
+const RESPONSE_TIMEOUT_US: u32 = 150 + RECEIVE_TIMEOUT_US;
+/// Accuracy of our sleep clock
+const SLEEP_CLOCK_ACCURACY_PPM: u32 = 50;
+/// Maximum sleep clock accuracy of the central, indexed by the SCA field of
+/// `CONNECT_IND`
+const CENTRAL_SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+/// Random delay added to each advertising interval, as `advDelay`
+const MAX_ADVERTISING_DELAY_MS: u32 = 10;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Idle,
+    /// Waiting for the next advertising event
+    AdvertisingIdle,
+    /// Sending `ADV_IND`
+    Advertising(RadioChannel),
+    /// Listening for `SCAN_REQ` or `CONNECT_IND`
+    AdvertisingListen(RadioChannel),
+    /// Sending `SCAN_RSP`
+    ScanResponse(RadioChannel),
+    /// Waiting for the next connection event
+    ConnectionIdle,
+    /// Listening for a packet from the central
+    ConnectionReceive,
+    /// Sending the response to the central
+    ConnectionTransmit,
+}
+
+/// The PDU that was last sent and has not been acknowledged yet
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Pdu {
+    Empty,
+    Data,
+    Control,
+}
+
+/// Pending `LL_CONNECTION_UPDATE_IND`
+#[derive(Copy, Clone)]
+struct ConnectionUpdate {
+    win_size_us: u32,
+    win_offset_us: u32,
+    interval_us: u32,
+    timeout_ms: u32,
+    instant: u16,
+}
+
+// Instants are in the past if they are more than 32767 events ahead
+fn instant_passed(instant: u16, event_counter: u16) -> bool {
+    instant.wrapping_sub(event_counter) >= 32767
+}
+
+/// Sequence numbers of a connection
+///
+/// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.9
+/// Acknowledgement and Flow Control
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+struct SequenceNumbers {
+    /// SN of the PDU we send
+    sn: bool,
+    /// SN of the next PDU we expect
+    nesn: bool,
+}
+
+impl SequenceNumbers {
+    /// Updates the sequence numbers from the header of a received PDU.
+    /// Returns whether the PDU acknowledges the last PDU we sent, and
+    /// whether it is a new PDU rather than a retransmission.
+    fn receive(&mut self, header: u8) -> (bool, bool) {
+        let acked = (header & NESN != 0) != self.sn;
+        if acked {
+            self.sn = !self.sn;
+        }
+        let new = (header & SN != 0) == self.nesn;
+        if new {
+            self.nesn = !self.nesn;
+        }
+        (acked, new)
+    }
+
+    /// The SN and NESN bits of the header of the PDU we send.
+    fn header(&self) -> u8 {
+        let mut header = 0;
+        if self.nesn {
+            header |= NESN;
+        }
+        if self.sn {
+            header |= SN;
+        }
+        header
+    }
+}
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.8.2
+// Channel Selection. Returns the next unmapped channel and the data channel
+// index it maps to.
+fn select_channel(last_unmapped_channel: u8, hop: u8, channel_map: &[u8; 5]) -> (u8, u8) {
+    let unmapped = (last_unmapped_channel + hop) % 37;
+    if channel_used(channel_map, unmapped) {
+        return (unmapped, unmapped);
+    }
+    let remapping_index = unmapped as usize % used_channels(channel_map);
+    let index = (0..37)
+        .filter(|i| channel_used(channel_map, *i))
+        .nth(remapping_index)
+        .unwrap_or(0);
+    (unmapped, index)
+}
+
+fn channel_used(channel_map: &[u8; 5], index: u8) -> bool {
+    channel_map[(index / 8) as usize] & (1 << (index % 8)) != 0
+}
+
+fn used_channels(channel_map: &[u8; 5]) -> usize {
+    (0..37).filter(|i| channel_used(channel_map, *i)).count()
+}
+
+// Time on air of a PDU of `len` bytes, including preamble, access address
+// and CRC
+fn air_time_us(len: u8) -> u32 {
+    (1 + 4 + len as u32 + 3) * 8
+}
+
+pub struct BleLinkLayer<'a, R, A>
+where
+    R: BleDataRadio<'a>,
+    A: Alarm<'a>,
+{
+    radio: &'a R,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn ConnectionClient>,
+    radio_buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+
+    // Advertising
+    advertising: Cell<bool>,
+    address: Cell<[u8; ADDRESS_LEN]>,
+    adv_data: Cell<[u8; MAX_ADV_DATA_LEN]>,
+    adv_data_len: Cell<usize>,
+    advertising_interval_ms: Cell<u32>,
+    random_nonce: Cell<u32>,
+
+    // Connection parameters
+    interval_us: Cell<u32>,
+    timeout_ms: Cell<u32>,
+    central_sca_ppm: Cell<u32>,
+    channel_map: Cell<[u8; 5]>,
+    hop: Cell<u8>,
+    last_unmapped_channel: Cell<u8>,
+    /// Channel of the next or current connection event
+    channel: Cell<RadioChannel>,
+    event_counter: Cell<u16>,
+    connection_update: Cell<Option<ConnectionUpdate>>,
+    channel_map_update: Cell<Option<(u16, [u8; 5])>>,
+
+    // Connection timing
+    anchor: Cell<A::Ticks>,
+    events_since_anchor: Cell<u32>,
+    /// Size of the transmit window, while waiting for the first packet after
+    /// the connection was created or updated
+    window_us: Cell<u32>,
+    first_packet: Cell<bool>,
+    established: Cell<bool>,
+    last_valid: Cell<A::Ticks>,
+    crc_errors: Cell<u8>,
+
+    // Acknowledgement and flow control
+    sequence_numbers: Cell<SequenceNumbers>,
+    peer_more_data: Cell<bool>,
+    last_sent: Cell<Option<Pdu>>,
+    tx_data: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_start: Cell<bool>,
+    control: Cell<[u8; MAX_CONTROL_LEN]>,
+    control_len: Cell<usize>,
+    version_sent: Cell<bool>,
+    /// Reason of our own `LL_TERMINATE_IND`
+    terminating: Cell<Option<u8>>,
+    /// Close the connection with this reason after the current packet
+    disconnect_reason: Cell<Option<u8>>,
+}
+
+impl<'a, R, A> BleLinkLayer<'a, R, A>
+where
+    R: BleDataRadio<'a>,
+    A: Alarm<'a>,
+{
+    pub fn new(radio: &'a R, alarm: &'a A, radio_buffer: &'static mut [u8]) -> Self {
+        BleLinkLayer {
+            radio,
+            alarm,
+            client: OptionalCell::empty(),
+            radio_buffer: TakeCell::new(radio_buffer),
+            state: Cell::new(State::Idle),
+            advertising: Cell::new(false),
+            // Static device address, the two most significant bits are set
+            address: Cell::new([0x00, 0x00, 0x00, 0x00, 0x00, 0xf0]),
+            adv_data: Cell::new([0; MAX_ADV_DATA_LEN]),
+            adv_data_len: Cell::new(0),
+            advertising_interval_ms: Cell::new(100),
+            // Just use any non-zero starting value by default
+            random_nonce: Cell::new(0xdeadbeef),
+            interval_us: Cell::new(0),
+            timeout_ms: Cell::new(0),
+            central_sca_ppm: Cell::new(CENTRAL_SCA_PPM[0]),
+            channel_map: Cell::new([0; 5]),
+            hop: Cell::new(0),
+            last_unmapped_channel: Cell::new(0),
+            channel: Cell::new(RadioChannel::DataChannel0),
+            event_counter: Cell::new(0),
+            connection_update: Cell::new(None),
+            channel_map_update: Cell::new(None),
+            anchor: Cell::new(A::Ticks::from(0)),
+            events_since_anchor: Cell::new(0),
+            window_us: Cell::new(0),
+            first_packet: Cell::new(false),
+            established: Cell::new(false),
+            last_valid: Cell::new(A::Ticks::from(0)),
+            crc_errors: Cell::new(0),
+            sequence_numbers: Cell::new(SequenceNumbers::default()),
+            peer_more_data: Cell::new(false),
+            last_sent: Cell::new(None),
+            tx_data: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_start: Cell::new(false),
+            control: Cell::new([0; MAX_CONTROL_LEN]),
+            control_len: Cell::new(0),
+            version_sent: Cell::new(false),
+            terminating: Cell::new(None),
+            disconnect_reason: Cell::new(None),
+        }
+    }
+
+    /// Set the static device address used for advertising, least significant
+    /// byte first. The two most significant bits of the last byte must be
+    /// set.
+    pub fn set_address(&self, address: [u8; ADDRESS_LEN]) {
+        self.address.set(address);
+    }
+
+    pub fn set_advertising_interval(&self, interval_ms: u32) {
+        self.advertising_interval_ms.set(interval_ms);
+    }
+
+    // Returns a new pseudo-random number, see `ble_advertising_driver`
+    fn random_nonce(&self) -> u32 {
+        let mut next_nonce = ::core::num::Wrapping(self.random_nonce.get());
+        next_nonce ^= next_nonce << 13;
+        next_nonce ^= next_nonce >> 17;
+        next_nonce ^= next_nonce << 5;
+        self.random_nonce.set(next_nonce.0);
+        next_nonce.0
+    }
+
+    fn ticks_from_us(us: u32) -> A::Ticks {
+        <A as time::Time>::ticks_from_us(us)
+    }
+
+    // Advertising
+
+    fn advertise(&self, channel: RadioChannel) {
+        self.radio_buffer.take().map(|buf| {
+            let address = self.address.get();
+            let data_len = self.adv_data_len.get();
+            buf[0] = ADV_IND | ADV_TXADD;
+            buf[1] = (ADDRESS_LEN + data_len) as u8;
+            buf[2..2 + ADDRESS_LEN].copy_from_slice(&address);
+            buf[2 + ADDRESS_LEN..2 + ADDRESS_LEN + data_len]
+                .copy_from_slice(&self.adv_data.get()[..data_len]);
+
+            self.state.set(State::Advertising(channel));
+            self.radio.set_access_address(
+                ble_connection::ADVERTISING_ACCESS_ADDRESS,
+                ble_connection::ADVERTISING_CRC_INIT,
+            );
+            self.radio
+                .transmit_data(buf, 2 + ADDRESS_LEN + data_len, channel);
+        });
+    }
+
+    fn next_advertising_channel(&self, channel: RadioChannel) {
+        // Abort the turnaround after the last packet, it would stay on the
+        // current channel
+        self.radio.disable();
+        match channel {
+            RadioChannel::AdvertisingChannel37 => {
+                self.advertise(RadioChannel::AdvertisingChannel38)
+            }
+            RadioChannel::AdvertisingChannel38 => {
+                self.advertise(RadioChannel::AdvertisingChannel39)
+            }
+            _ => {
+                if self.advertising.get() {
+                    self.state.set(State::AdvertisingIdle);
+                    let delay = self.random_nonce() % MAX_ADVERTISING_DELAY_MS;
+                    self.alarm.set_alarm(
+                        self.alarm.now(),
+                        A::ticks_from_ms(self.advertising_interval_ms.get() + delay),
+                    );
+                } else {
+                    self.state.set(State::Idle);
+                }
+            }
+        }
+    }
+
+    fn advertising_packet(&self, buf: &[u8], result: Result<(), ErrorCode>, channel: RadioChannel) {
+        let _ = self.alarm.disarm();
+        if result.is_err() {
+            self.next_advertising_channel(channel);
+            return;
+        }
+
+        let pdu_type = buf[0] & ADV_PDU_TYPE_MASK;
+        let len = buf[1] as usize;
+        // Both SCAN_REQ and CONNECT_IND have AdvA after the address of the
+        // sender and must address our random address
+        let for_us = len >= SCAN_REQ_LEN
+            && buf[0] & ADV_RXADD != 0
+            && buf[2 + ADDRESS_LEN..2 + 2 * ADDRESS_LEN] == self.address.get();
+
+        if for_us && pdu_type == SCAN_REQ && len == SCAN_REQ_LEN {
+            self.radio_buffer.take().map(|tx| {
+                tx[0] = SCAN_RSP | ADV_TXADD;
+                tx[1] = ADDRESS_LEN as u8;
+                tx[2..2 + ADDRESS_LEN].copy_from_slice(&self.address.get());
+                self.state.set(State::ScanResponse(channel));
+                self.radio.transmit_data(tx, 2 + ADDRESS_LEN, channel);
+            });
+        } else if for_us && pdu_type == CONNECT_IND && len == CONNECT_IND_LEN {
+            let mut init_a = [0; ADDRESS_LEN];
+            init_a.copy_from_slice(&buf[2..2 + ADDRESS_LEN]);
+            let mut ll_data = [0; 22];
+            ll_data.copy_from_slice(&buf[2 + 2 * ADDRESS_LEN..2 + CONNECT_IND_LEN]);
+            if !self.connect(init_a, buf[0] & ADV_TXADD != 0, &ll_data) {
+                self.next_advertising_channel(channel);
+            }
+        } else {
+            self.next_advertising_channel(channel);
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.3.1
+    // CONNECT_IND LLData. Returns false if the parameters are invalid.
+    fn connect(&self, peer: [u8; ADDRESS_LEN], peer_random: bool, ll_data: &[u8; 22]) -> bool {
+        let now = self.alarm.now();
+        let read_u16 = |i: usize| u16::from_le_bytes([ll_data[i], ll_data[i + 1]]);
+        let access_address = u32::from_le_bytes([ll_data[0], ll_data[1], ll_data[2], ll_data[3]]);
+        let crc_init = u32::from_le_bytes([ll_data[4], ll_data[5], ll_data[6], 0]);
+        let win_size = ll_data[7] as u32;
+        let win_offset = read_u16(8) as u32;
+        let interval = read_u16(10) as u32;
+        let timeout = read_u16(14) as u32;
+        let mut channel_map = [0; 5];
+        channel_map.copy_from_slice(&ll_data[16..21]);
+        channel_map[4] &= 0x1f;
+        let hop = ll_data[21] & 0x1f;
+        let sca = (ll_data[21] >> 5) as usize;
+
+        if !(6..=3200).contains(&interval)
+            || !(5..=16).contains(&hop)
+            || timeout == 0
+            || used_channels(&channel_map) < 2
+        {
+            return false;
+        }
+
+        self.radio.disable();
+        self.advertising.set(false);
+        self.radio.set_access_address(access_address, crc_init);
+
+        self.interval_us.set(interval * 1250);
+        self.timeout_ms.set(timeout * 10);
+        self.central_sca_ppm.set(CENTRAL_SCA_PPM[sca]);
+        self.channel_map.set(channel_map);
+        self.hop.set(hop);
+        self.last_unmapped_channel.set(0);
+        self.event_counter.set(0);
+        self.connection_update.set(None);
+        self.channel_map_update.set(None);
+
+        // The transmit window of the first event starts 1.25 ms plus the
+        // window offset after the end of CONNECT_IND
+        self.anchor
+            .set(now.wrapping_add(Self::ticks_from_us(1250 + win_offset * 1250)));
+        self.events_since_anchor.set(0);
+        self.window_us.set(win_size * 1250);
+        self.established.set(false);
+        self.last_valid.set(now);
+
+        self.sequence_numbers.set(SequenceNumbers::default());
+        self.last_sent.set(None);
+        self.control_len.set(0);
+        self.version_sent.set(false);
+        self.terminating.set(None);
+        self.disconnect_reason.set(None);
+
+        self.channel.set(self.next_channel());
+        self.schedule_event();
+        self.client
+            .map(|client| client.connected(peer, peer_random));
+        true
+    }
+
+    // Connection events
+
+    fn next_channel(&self) -> RadioChannel {
+        let (unmapped, index) = select_channel(
+            self.last_unmapped_channel.get(),
+            self.hop.get(),
+            &self.channel_map.get(),
+        );
+        self.last_unmapped_channel.set(unmapped);
+        RadioChannel::from_data_channel_index(index).unwrap_or(RadioChannel::DataChannel0)
+    }
+
+    // Expected start of the next connection event
+    fn event_time(&self) -> A::Ticks {
+        self.anchor.get().wrapping_add(Self::ticks_from_us(
+            self.interval_us.get() * self.events_since_anchor.get(),
+        ))
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.7
+    // Window Widening
+    fn window_widening_us(&self) -> u32 {
+        let elapsed_us = self.interval_us.get() as u64 * self.events_since_anchor.get() as u64;
+        let drift_ppm = (self.central_sca_ppm.get() + SLEEP_CLOCK_ACCURACY_PPM) as u64;
+        let widening = (drift_ppm * elapsed_us / 1_000_000) as u32 + 16;
+        cmp::min(widening, self.interval_us.get() / 2 - 150)
+    }
+
+    fn advance_event(&self) {
+        let event_counter = self.event_counter.get().wrapping_add(1);
+        self.event_counter.set(event_counter);
+        self.events_since_anchor
+            .set(self.events_since_anchor.get() + 1);
+
+        if let Some((instant, channel_map)) = self.channel_map_update.get() {
+            if instant == event_counter {
+                self.channel_map.set(channel_map);
+                self.channel_map_update.set(None);
+            }
+        }
+
+        if let Some(update) = self.connection_update.get() {
+            if update.instant == event_counter {
+                // The transmit window is relative to the old anchor point of
+                // the instant
+                let widening = self.window_widening_us();
+                self.anchor.set(
+                    self.event_time()
+                        .wrapping_add(Self::ticks_from_us(update.win_offset_us)),
+                );
+                self.events_since_anchor.set(0);
+                self.window_us.set(update.win_size_us + widening);
+                self.interval_us.set(update.interval_us);
+                self.timeout_ms.set(update.timeout_ms);
+                self.connection_update.set(None);
+            }
+        }
+
+        self.channel.set(self.next_channel());
+    }
+
+    // Sets the alarm to open the receive window of the next event. Events that
+    // can no longer be reached are skipped.
+    fn schedule_event(&self) {
+        let now = self.alarm.now();
+        loop {
+            let lead = Self::ticks_from_us(self.window_widening_us() + RADIO_SETUP_US);
+            let open = self.event_time().wrapping_sub(lead);
+            let until_open = open.wrapping_sub(now);
+            let late = now.wrapping_sub(open);
+            if until_open.into_u32() < A::Ticks::max_value().into_u32() / 2 {
+                self.state.set(State::ConnectionIdle);
+                self.alarm.set_alarm(now, until_open);
+                return;
+            } else if late.into_u32() < lead.into_u32() {
+                // Still before the anchor point, open immediately
+                self.state.set(State::ConnectionIdle);
+                self.alarm.set_alarm(now, A::Ticks::from(0));
+                return;
+            }
+            self.advance_event();
+        }
+    }
+
+    fn open_event(&self) {
+        self.crc_errors.set(0);
+        self.first_packet.set(true);
+        self.peer_more_data.set(false);
+        self.state.set(State::ConnectionReceive);
+        self.radio.receive_data(self.channel.get());
+
+        let close = self.event_time().wrapping_add(Self::ticks_from_us(
+            self.window_widening_us() + self.window_us.get() + RECEIVE_TIMEOUT_US,
+        ));
+        let now = self.alarm.now();
+        self.alarm.set_alarm(now, close.wrapping_sub(now));
+    }
+
+    fn close_event(&self) {
+        let now = self.alarm.now();
+        let (timeout_us, reason) = if self.established.get() {
+            (self.timeout_ms.get() * 1000, CONNECTION_TIMEOUT)
+        } else {
+            (6 * self.interval_us.get(), CONNECTION_FAILED_TO_ESTABLISH)
+        };
+        if now.wrapping_sub(self.last_valid.get()).into_u32()
+            >= Self::ticks_from_us(timeout_us).into_u32()
+        {
+            self.close_connection(reason);
+            return;
+        }
+
+        self.advance_event();
+        self.schedule_event();
+    }
+
+    fn close_connection(&self, reason: u8) {
+        self.radio.disable();
+        let _ = self.alarm.disarm();
+        self.state.set(State::Idle);
+        self.control_len.set(0);
+        self.terminating.set(None);
+        self.disconnect_reason.set(None);
+        self.client.map(|client| {
+            self.tx_data
+                .take()
+                .map(|buf| client.transmitted(buf, Err(ErrorCode::CANCEL)));
+            client.disconnected(reason);
+        });
+    }
+
+    fn connection_packet(&self, buf: &[u8], len: u8, result: Result<(), ErrorCode>) {
+        let _ = self.alarm.disarm();
+        let now = self.alarm.now();
+        if self.first_packet.take() {
+            self.anchor
+                .set(now.wrapping_sub(Self::ticks_from_us(air_time_us(len))));
+            self.events_since_anchor.set(0);
+            self.window_us.set(0);
+        }
+
+        // Copy the PDU, the radio reuses its buffer for the response
+        let mut pdu = [0; 2 + MAX_PAYLOAD];
+        let len = cmp::min(len as usize, pdu.len());
+        pdu[..len].copy_from_slice(&buf[..len]);
+
+        let mut acked_data = None;
+        let mut new_pdu = false;
+        if result.is_ok() {
+            self.crc_errors.set(0);
+            self.last_valid.set(now);
+            self.established.set(true);
+            self.peer_more_data.set(pdu[0] & MD != 0);
+
+            let mut sequence_numbers = self.sequence_numbers.get();
+            let (acked, new) = sequence_numbers.receive(pdu[0]);
+            self.sequence_numbers.set(sequence_numbers);
+            if acked {
+                match self.last_sent.take() {
+                    Some(Pdu::Data) => acked_data = self.tx_data.take(),
+                    Some(Pdu::Control) => {
+                        self.control_len.set(0);
+                        if let Some(reason) = self.terminating.take() {
+                            self.disconnect_reason.set(Some(reason));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            new_pdu = new;
+        } else {
+            self.crc_errors.set(self.crc_errors.get() + 1);
+            // The central retransmits
+            self.peer_more_data.set(true);
+        }
+
+        if self.crc_errors.get() >= 2 {
+            self.radio.disable();
+            self.close_event();
+        } else {
+            self.transmit_response();
+        }
+
+        if let Some(buf) = acked_data {
+            self.client
+                .map(move |client| client.transmitted(buf, Ok(())));
+        }
+        if new_pdu && pdu[1] as usize <= MAX_PAYLOAD {
+            self.handle_pdu(&pdu[..2 + pdu[1] as usize]);
+        }
+    }
+
+    fn transmit_response(&self) {
+        let pdu = self.last_sent.get().unwrap_or_else(|| {
+            if self.control_len.get() > 0 {
+                Pdu::Control
+            } else if self.tx_data.is_some() {
+                Pdu::Data
+            } else {
+                Pdu::Empty
+            }
+        });
+        self.last_sent.set(Some(pdu));
+
+        self.radio_buffer.take().map(|buf| {
+            let (llid, len) = match pdu {
+                Pdu::Empty => (LLID_CONTINUATION, 0),
+                Pdu::Data => {
+                    let len = self.tx_len.get();
+                    self.tx_data
+                        .map(|data| buf[2..2 + len].copy_from_slice(&data[..len]));
+                    let llid = if self.tx_start.get() {
+                        LLID_START
+                    } else {
+                        LLID_CONTINUATION
+                    };
+                    (llid, len)
+                }
+                Pdu::Control => {
+                    let len = self.control_len.get();
+                    buf[2..2 + len].copy_from_slice(&self.control.get()[..len]);
+                    (LLID_CONTROL, len)
+                }
+            };
+            let more_data = (pdu != Pdu::Control && self.control_len.get() > 0)
+                || (pdu != Pdu::Data && self.tx_data.is_some());
+
+            buf[0] = llid | self.sequence_numbers.get().header();
+            if more_data {
+                buf[0] |= MD;
+            }
+            buf[1] = len as u8;
+
+            self.state.set(State::ConnectionTransmit);
+            self.radio.transmit_data(buf, 2 + len, self.channel.get());
+        });
+    }
+
+    fn transmitted_response(&self) {
+        if let Some(reason) = self.disconnect_reason.take() {
+            self.close_connection(reason);
+            return;
+        }
+
+        let more_data = self.peer_more_data.get()
+            || self.last_sent.get().map_or(false, |pdu| pdu != Pdu::Empty)
+            || self.control_len.get() > 0
+            || self.tx_data.is_some();
+        if more_data {
+            self.state.set(State::ConnectionReceive);
+            self.radio.receive_data(self.channel.get());
+            self.alarm
+                .set_alarm(self.alarm.now(), Self::ticks_from_us(RESPONSE_TIMEOUT_US));
+        } else {
+            self.radio.disable();
+            self.close_event();
+        }
+    }
+
+    fn handle_pdu(&self, pdu: &[u8]) {
+        let payload = &pdu[2..];
+        match pdu[0] & LLID_MASK {
+            LLID_CONTINUATION if !payload.is_empty() => {
+                self.client.map(|client| client.received(payload, false));
+            }
+            LLID_START => {
+                self.client.map(|client| client.received(payload, true));
+            }
+            LLID_CONTROL if !payload.is_empty() => self.handle_control(payload),
+            _ => {}
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4.2 LL
+    // Control PDU
+    fn handle_control(&self, pdu: &[u8]) {
+        let read_u16 = |i: usize| u16::from_le_bytes([pdu[i], pdu[i + 1]]);
+        match pdu[0] {
+            LL_CONNECTION_UPDATE_IND if pdu.len() == 12 => {
+                let update = ConnectionUpdate {
+                    win_size_us: pdu[1] as u32 * 1250,
+                    win_offset_us: read_u16(2) as u32 * 1250,
+                    interval_us: read_u16(4) as u32 * 1250,
+                    timeout_ms: read_u16(8) as u32 * 10,
+                    instant: read_u16(10),
+                };
+                if instant_passed(update.instant, self.event_counter.get()) {
+                    self.disconnect_reason.set(Some(INSTANT_PASSED));
+                } else {
+                    self.connection_update.set(Some(update));
+                }
+            }
+            LL_CHANNEL_MAP_IND if pdu.len() == 8 => {
+                let mut channel_map = [0; 5];
+                channel_map.copy_from_slice(&pdu[1..6]);
+                channel_map[4] &= 0x1f;
+                let instant = read_u16(6);
+                if instant_passed(instant, self.event_counter.get()) {
+                    self.disconnect_reason.set(Some(INSTANT_PASSED));
+                } else if used_channels(&channel_map) >= 2 {
+                    self.channel_map_update.set(Some((instant, channel_map)));
+                }
+            }
+            LL_TERMINATE_IND if pdu.len() == 2 => {
+                // Closed after acknowledging it
+                self.disconnect_reason.set(Some(pdu[1]));
+            }
+            LL_FEATURE_REQ | LL_SLAVE_FEATURE_REQ => {
+                // No optional features are supported
+                self.queue_control(&[LL_FEATURE_RSP, 0, 0, 0, 0, 0, 0, 0, 0]);
+            }
+            LL_VERSION_IND => {
+                if !self.version_sent.get() {
+                    let company = COMPANY_ID.to_le_bytes();
+                    if self.queue_control(&[
+                        LL_VERSION_IND,
+                        LL_VERSION,
+                        company[0],
+                        company[1],
+                        0,
+                        0,
+                    ]) {
+                        self.version_sent.set(true);
+                    }
+                }
+            }
+            LL_PING_REQ => {
+                self.queue_control(&[LL_PING_RSP]);
+            }
+            LL_LENGTH_REQ => {
+                let octets = (MAX_PAYLOAD as u16).to_le_bytes();
+                let time = (air_time_us(2 + MAX_PAYLOAD as u8) as u16 + 8).to_le_bytes();
+                self.queue_control(&[
+                    LL_LENGTH_RSP,
+                    octets[0],
+                    octets[1],
+                    time[0],
+                    time[1],
+                    octets[0],
+                    octets[1],
+                    time[0],
+                    time[1],
+                ]);
+            }
+            LL_UNKNOWN_RSP | LL_FEATURE_RSP | LL_PING_RSP | LL_LENGTH_RSP => {}
+            opcode => {
+                self.queue_control(&[LL_UNKNOWN_RSP, opcode]);
+            }
+        }
+    }
+
+    // Only one control procedure is active at a time, so a single PDU is
+    // queued. Returns false if another one is still pending.
+    fn queue_control(&self, pdu: &[u8]) -> bool {
+        if self.control_len.get() > 0 {
+            return false;
+        }
+        let mut control = [0; MAX_CONTROL_LEN];
+        control[..pdu.len()].copy_from_slice(pdu);
+        self.control.set(control);
+        self.control_len.set(pdu.len());
+        true
+    }
+}
+
+impl<'a, R, A> time::AlarmClient for BleLinkLayer<'a, R, A>
+where
+    R: BleDataRadio<'a>,
+    A: Alarm<'a>,
+{
+    fn alarm(&self) {
+        match self.state.get() {
+            State::AdvertisingIdle => self.advertise(RadioChannel::AdvertisingChannel37),
+            State::AdvertisingListen(channel) => self.next_advertising_channel(channel),
+            State::ConnectionIdle => self.open_event(),
+            State::ConnectionReceive => {
+                self.radio.disable();
+                self.close_event();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, R, A> ble_connection::DataRadioClient for BleLinkLayer<'a, R, A>
+where
+    R: BleDataRadio<'a>,
+    A: Alarm<'a>,
+{
+    fn packet_received(&self, buf: &'static mut [u8], len: u8, result: Result<(), ErrorCode>) {
+        match self.state.get() {
+            State::AdvertisingListen(channel) => self.advertising_packet(buf, result, channel),
+            State::ConnectionReceive => self.connection_packet(buf, len, result),
+            _ => self.radio.disable(),
+        }
+    }
+
+    fn packet_transmitted(&self, buf: &'static mut [u8], _result: Result<(), ErrorCode>) {
+        self.radio_buffer.replace(buf);
+        match self.state.get() {
+            State::Advertising(channel) => {
+                self.state.set(State::AdvertisingListen(channel));
+                self.radio.receive_data(channel);
+                self.alarm
+                    .set_alarm(self.alarm.now(), Self::ticks_from_us(ADVERTISING_LISTEN_US));
+            }
+            State::ScanResponse(channel) => self.next_advertising_channel(channel),
+            State::ConnectionTransmit => self.transmitted_response(),
+            _ => self.radio.disable(),
+        }
+    }
+}
+
+impl<'a, R, A> BleConnection<'a> for BleLinkLayer<'a, R, A>
+where
+    R: BleDataRadio<'a>,
+    A: Alarm<'a>,
+{
+    fn set_client(&self, client: &'a dyn ConnectionClient) {
+        self.client.set(client);
+    }
+
+    fn set_advertising_data(&self, data: &[u8]) -> Result<(), ErrorCode> {
+        if data.len() > MAX_ADV_DATA_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        let mut adv_data = [0; MAX_ADV_DATA_LEN];
+        adv_data[..data.len()].copy_from_slice(data);
+        self.adv_data.set(adv_data);
+        self.adv_data_len.set(data.len());
+        Ok(())
+    }
+
+    fn start_advertising(&self) -> Result<(), ErrorCode> {
+        if self.is_connected() {
+            return Err(ErrorCode::BUSY);
+        }
+        if self.advertising.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.advertising.set(true);
+        // Otherwise the current advertising event reschedules itself
+        if self.state.get() == State::Idle {
+            self.state.set(State::AdvertisingIdle);
+            self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(1));
+        }
+        Ok(())
+    }
+
+    fn stop_advertising(&self) -> Result<(), ErrorCode> {
+        if !self.advertising.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.advertising.set(false);
+        if self.state.get() == State::AdvertisingIdle {
+            let _ = self.alarm.disarm();
+            self.state.set(State::Idle);
+        }
+        Ok(())
+    }
+
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        start: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.is_connected() {
+            Err((ErrorCode::OFF, buf))
+        } else if len > MAX_PAYLOAD || len > buf.len() {
+            Err((ErrorCode::SIZE, buf))
+        } else if self.tx_data.is_some() {
+            Err((ErrorCode::BUSY, buf))
+        } else {
+            self.tx_len.set(len);
+            self.tx_start.set(start);
+            self.tx_data.replace(buf);
+            Ok(())
+        }
+    }
+
+    fn disconnect(&self, reason: u8) -> Result<(), ErrorCode> {
+        if !self.is_connected() {
+            return Err(ErrorCode::OFF);
+        }
+        if self.terminating.get().is_some() {
+            return Err(ErrorCode::ALREADY);
+        }
+        if !self.queue_control(&[LL_TERMINATE_IND, reason]) {
+            return Err(ErrorCode::BUSY);
+        }
+        self.terminating.set(Some(LOCAL_HOST_TERMINATED));
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        match self.state.get() {
+            State::ConnectionIdle | State::ConnectionReceive | State::ConnectionTransmit => true,
+            _ => false,
+        }
+    }
+
+    fn max_payload(&self) -> usize {
+        MAX_PAYLOAD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The data channel indices of the next `n` connection events.
+    fn channels(hop: u8, channel_map: &[u8; 5], n: usize) -> [u8; 8] {
+        let mut channels = [0; 8];
+        let mut unmapped = 0;
+        for channel in channels.iter_mut().take(n) {
+            let (next, index) = select_channel(unmapped, hop, channel_map);
+            unmapped = next;
+            *channel = index;
+        }
+        channels
+    }
+
+    #[test]
+    fn csa1_hops_over_used_channels() {
+        let all = [0xff, 0xff, 0xff, 0xff, 0x1f];
+        assert_eq!(channels(7, &all, 8), [7, 14, 21, 28, 35, 5, 12, 19]);
+    }
+
+    #[test]
+    fn csa1_remaps_unused_channels() {
+        // Only channels 0 to 8 are used: unmapped channels 10, 15 and 20 are
+        // remapped to the used channels 10 % 9, 15 % 9 and 20 % 9
+        let low = [0xff, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(channels(5, &low, 4), [5, 1, 6, 2, 0, 0, 0, 0]);
+        // Channels 1, 20 and 36 are used
+        let sparse = [0x02, 0x00, 0x10, 0x00, 0x10];
+        assert_eq!(channels(16, &sparse, 3), [20, 36, 36, 0, 0, 0, 0, 0]);
+    }
+
+    /// The header of a PDU of the central with the given SN and NESN.
+    fn header(sn: bool, nesn: bool) -> u8 {
+        let mut header = LLID_CONTINUATION;
+        if sn {
+            header |= SN;
+        }
+        if nesn {
+            header |= NESN;
+        }
+        header
+    }
+
+    #[test]
+    fn sequence_numbers_acknowledge_and_filter_retransmissions() {
+        let mut seq = SequenceNumbers::default();
+
+        // The first PDU of the central is new and does not acknowledge ours
+        assert_eq!(seq.receive(header(false, false)), (false, true));
+        assert_eq!(seq.header(), NESN);
+
+        // The central did not get our acknowledgement and retransmits: the
+        // PDU is not new, and our PDU is still not acknowledged
+        assert_eq!(seq.receive(header(false, false)), (false, false));
+        assert_eq!(seq.header(), NESN);
+
+        // The central acknowledges our PDU and sends a new one
+        assert_eq!(seq.receive(header(true, true)), (true, true));
+        assert_eq!(seq.header(), SN);
+
+        // The central sends a new PDU but missed our next one, which we
+        // retransmit with the same SN
+        assert_eq!(seq.receive(header(false, true)), (false, true));
+        assert_eq!(seq.header(), SN | NESN);
+
+        // Acknowledged on the next PDU, which wraps the sequence numbers
+        assert_eq!(seq.receive(header(true, false)), (true, true));
+        assert_eq!(seq, SequenceNumbers::default());
+    }
+}
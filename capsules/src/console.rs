@@ -36,13 +36,50 @@
 //! When the buffer has been written successfully, the buffer is released from
 //! the driver. Successive writes must call `allow` each time a buffer is to be
 //! written.
+//!
+//! Multiplexing
+//! ------------
+//!
+//! By default, output from every app is written as is and input goes to
+//! whichever app started a read first. With `enable_multiplexing()`, each line
+//! of output is prefixed with the name of the process that wrote it, and a
+//! partial line at the end of a write is kept in the app's grant until the
+//! app finishes it, so lines from different apps do not interleave. A partial
+//! line is written on its own, ended by a newline, when the app starts a read
+//! (it is likely a prompt) or when it was not finished within 100 to 200 ms,
+//! as timed by an alarm.
+//!
+//! Input goes only to the selected process. To select a process, type
+//! `SELECT_INPUT` (Ctrl-A), its name and a newline; Ctrl-A and a newline
+//! alone select the process console, which has to be told about selections
+//! with `ProcessConsole::enable_input_selection()`.
+//!
+//! ```rust
+//! let console_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! console_alarm.set_alarm_client(console);
+//! console
+//!     .enable_multiplexing(
+//!         board_kernel,
+//!         &components::process_console::Capability,
+//!         console_alarm,
+//!     )
+//!     .expect("console write buffer too short");
+//! pconsole.enable_input_selection();
+//! ```
 
+use core::cell::Cell;
 use core::convert::TryFrom;
-use core::{cmp, mem};
+use core::{cmp, mem, str};
 
+use kernel::capabilities::ProcessManagementCapability;
 use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::time::{Alarm, AlarmClient};
 use kernel::hil::uart;
-use kernel::{CommandReturn, Driver};
+use kernel::introspection::KernelInfo;
+use kernel::{CommandReturn, Driver, Kernel};
 use kernel::{ErrorCode, Grant, ProcessId, Upcall};
 use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
 
@@ -50,6 +87,19 @@ use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Console as usize;
 
+/// Starts selecting the process that console input goes to, when
+/// multiplexing.
+pub const SELECT_INPUT: u8 = 0x01;
+
+/// Longest partial line that is kept for an app when multiplexing.
+const LINE_LEN: usize = 32;
+/// Longest part of a process name that is used as a prefix.
+const PREFIX_NAME_LEN: usize = 10;
+/// Write buffer needed for a prefix, a partial line and a newline.
+const MULTIPLEX_BUFFER_LEN: usize = PREFIX_NAME_LEN + 3 + LINE_LEN + 1;
+/// Period of the alarm that flushes partial lines.
+const FLUSH_MS: u32 = 100;
+
 #[derive(Default)]
 pub struct App {
     write_callback: Upcall,
@@ -61,11 +111,226 @@ pub struct App {
     read_callback: Upcall,
     read_buffer: ReadWriteAppSlice,
     read_len: usize,
+
+    // Only used when multiplexing.
+    line: PartialLine,
+    /// The partial line waits for the UART to be flushed.
+    flush_pending: bool,
+    /// The UART sends the flushed partial line.
+    flushing: bool,
+    read_received: usize,
+}
+
+/// Writes the prefix of a line of `name` to `buffer` and returns its
+/// length.
+fn write_prefix(buffer: &mut [u8], name: &str) -> usize {
+    let mut len = 0;
+    for &byte in b"["
+        .iter()
+        .chain(name.as_bytes().iter().take(PREFIX_NAME_LEN))
+        .chain(b"] ")
+    {
+        buffer[len] = byte;
+        len += 1;
+    }
+    len
+}
+
+/// The start of a line of output that an app has not finished yet, when
+/// multiplexing.
+#[derive(Default)]
+struct PartialLine {
+    bytes: [u8; LINE_LEN],
+    len: usize,
+    /// Whether the line was kept through a whole flush period.
+    aged: bool,
+}
+
+impl PartialLine {
+    /// Writes the next line of output to `buffer`, which must hold
+    /// `MULTIPLEX_BUFFER_LEN` bytes: the prefix, the kept start of the line
+    /// and `data` up to its first newline. Lines that do not fit in
+    /// `buffer` or are longer than `LINE_LEN` are split. Returns how many
+    /// bytes of `data` were used and, if the line is complete, its length in
+    /// `buffer`. Otherwise, the line is kept.
+    fn next(&mut self, name: &str, data: &[u8], buffer: &mut [u8]) -> (usize, Option<usize>) {
+        let prefix_len = write_prefix(buffer, name);
+        let mut len = prefix_len;
+        buffer[len..len + self.len].copy_from_slice(&self.bytes[..self.len]);
+        len += self.len;
+
+        // Leave room for a newline if the line has to be split.
+        let space = buffer.len() - 1;
+        let mut used = data.len();
+        let mut complete = false;
+        for (i, &byte) in data.iter().enumerate() {
+            if len == space {
+                used = i;
+                complete = true;
+                break;
+            }
+            buffer[len] = byte;
+            len += 1;
+            if byte == b'\n' {
+                used = i + 1;
+                complete = true;
+                break;
+            }
+        }
+        complete |= len - prefix_len > LINE_LEN;
+
+        if complete {
+            if buffer[len - 1] != b'\n' {
+                buffer[len] = b'\n';
+                len += 1;
+            }
+            self.len = 0;
+            self.aged = false;
+            (used, Some(len))
+        } else {
+            self.len = len - prefix_len;
+            self.bytes[..self.len].copy_from_slice(&buffer[prefix_len..len]);
+            (used, None)
+        }
+    }
+
+    /// Writes the kept start of a line to `buffer`, ended by a newline, and
+    /// returns its length. Returns `None` if no line was kept.
+    fn flush(&mut self, name: &str, buffer: &mut [u8]) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut len = write_prefix(buffer, name);
+        buffer[len..len + self.len].copy_from_slice(&self.bytes[..self.len]);
+        len += self.len;
+        buffer[len] = b'\n';
+        self.len = 0;
+        self.aged = false;
+        Some(len + 1)
+    }
 }
 
 pub static mut WRITE_BUF: [u8; 64] = [0; 64];
 pub static mut READ_BUF: [u8; 64] = [0; 64];
 
+/// What a byte of console input was used for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Input {
+    /// Input for the selected process.
+    Byte(u8),
+    /// Part of a selection.
+    Selecting,
+    /// The end of a selection.
+    Selected,
+}
+
+const SELECT_NAME_LEN: usize = 16;
+
+/// Follows the selections typed on the console.
+///
+/// Everything that reads the console input keeps its own `InputSelector`, and
+/// they all come to the same selection because they see the same bytes.
+pub struct InputSelector {
+    selecting: Cell<bool>,
+    typed: Cell<[u8; SELECT_NAME_LEN]>,
+    typed_len: Cell<usize>,
+    selected: Cell<[u8; SELECT_NAME_LEN]>,
+    selected_len: Cell<usize>,
+}
+
+impl InputSelector {
+    pub const fn new() -> InputSelector {
+        InputSelector {
+            selecting: Cell::new(false),
+            typed: Cell::new([0; SELECT_NAME_LEN]),
+            typed_len: Cell::new(0),
+            selected: Cell::new([0; SELECT_NAME_LEN]),
+            selected_len: Cell::new(0),
+        }
+    }
+
+    pub fn receive(&self, byte: u8) -> Input {
+        if byte == SELECT_INPUT {
+            self.selecting.set(true);
+            self.typed_len.set(0);
+            return Input::Selecting;
+        }
+        if !self.selecting.get() {
+            return Input::Byte(byte);
+        }
+        let len = self.typed_len.get();
+        match byte {
+            b'\r' | b'\n' => {
+                self.selecting.set(false);
+                self.selected.set(self.typed.get());
+                self.selected_len.set(len);
+                return Input::Selected;
+            }
+            // Backspace and delete.
+            0x08 | 0x7F => self.typed_len.set(len.saturating_sub(1)),
+            _ if len < SELECT_NAME_LEN => {
+                let mut typed = self.typed.get();
+                typed[len] = byte;
+                self.typed.set(typed);
+                self.typed_len.set(len + 1);
+            }
+            _ => {}
+        }
+        Input::Selecting
+    }
+
+    /// Whether input goes to the process console.
+    pub fn console_selected(&self) -> bool {
+        self.selected_len.get() == 0
+    }
+
+    /// Whether input goes to the process called `name`.
+    pub fn is_selected(&self, name: &str) -> bool {
+        let len = self.selected_len.get();
+        len > 0 && self.selected.get()[..len] == *name.as_bytes()
+    }
+
+    /// Call `f` with the name of the selected process, which is empty if the
+    /// process console is selected.
+    pub fn map_selected<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&str) -> R,
+    {
+        let selected = self.selected.get();
+        f(str::from_utf8(&selected[..self.selected_len.get()]).unwrap_or(""))
+    }
+}
+
+/// The alarm a multiplexing console flushes partial lines with. Every
+/// `Alarm` is one.
+pub trait FlushAlarm {
+    /// Fire after `ms` milliseconds, unless the alarm is armed already.
+    fn start(&self, ms: u32);
+}
+
+impl<'a, A: Alarm<'a>> FlushAlarm for A {
+    fn start(&self, ms: u32) {
+        if !self.is_armed() {
+            self.set_alarm(self.now(), A::ticks_from_ms(ms));
+        }
+    }
+}
+
+/// What a multiplexing console needs to find the names of processes and to
+/// flush partial lines.
+#[derive(Copy, Clone)]
+struct Multiplexer<'a> {
+    kernel: &'static Kernel,
+    capability: &'a dyn ProcessManagementCapability,
+    alarm: &'a dyn FlushAlarm,
+}
+
+impl Multiplexer<'_> {
+    fn process_name(&self, app_id: ProcessId) -> &'static str {
+        KernelInfo::new(self.kernel).process_name(app_id, self.capability)
+    }
+}
+
 pub struct Console<'a> {
     uart: &'a dyn uart::UartData<'a>,
     apps: Grant<App>,
@@ -73,6 +338,8 @@ pub struct Console<'a> {
     tx_buffer: TakeCell<'static, [u8]>,
     rx_in_progress: OptionalCell<ProcessId>,
     rx_buffer: TakeCell<'static, [u8]>,
+    multiplexer: OptionalCell<Multiplexer<'a>>,
+    input: InputSelector,
 }
 
 impl<'a> Console<'a> {
@@ -89,7 +356,38 @@ impl<'a> Console<'a> {
             tx_buffer: TakeCell::new(tx_buffer),
             rx_in_progress: OptionalCell::empty(),
             rx_buffer: TakeCell::new(rx_buffer),
+            multiplexer: OptionalCell::empty(),
+            input: InputSelector::new(),
+        }
+    }
+
+    /// Prefix output with process names and route input to the selected
+    /// process. The write buffer must be at least 46 bytes long. The console
+    /// must be the client of `alarm`.
+    pub fn enable_multiplexing(
+        &self,
+        kernel: &'static Kernel,
+        capability: &'a dyn ProcessManagementCapability,
+        alarm: &'a dyn FlushAlarm,
+    ) -> Result<(), ErrorCode> {
+        if self.tx_buffer.map_or(0, |buffer| buffer.len()) < MULTIPLEX_BUFFER_LEN {
+            return Err(ErrorCode::SIZE);
         }
+        self.multiplexer.set(Multiplexer {
+            kernel,
+            capability,
+            alarm,
+        });
+
+        // Input is routed one byte at a time.
+        self.rx_buffer
+            .take()
+            .map_or(Err(ErrorCode::BUSY), |buffer| {
+                self.uart.receive_buffer(buffer, 1).map_err(|(e, buffer)| {
+                    self.rx_buffer.replace(buffer);
+                    e
+                })
+            })
     }
 
     /// Internal helper function for setting up a new send transaction
@@ -118,7 +416,13 @@ impl<'a> Console<'a> {
     /// Internal helper function for sending data for an existing transaction.
     /// Cannot fail. If can't send now, it will schedule for sending later.
     fn send(&self, app_id: ProcessId, app: &mut App) {
-        if self.tx_in_progress.is_none() {
+        if let Some(multiplexer) = self.multiplexer.extract() {
+            if self.tx_in_progress.is_none() {
+                self.send_line(app_id, app, multiplexer);
+            } else {
+                app.pending_write = true;
+            }
+        } else if self.tx_in_progress.is_none() {
             self.tx_in_progress.set(app_id);
             self.tx_buffer.take().map(|buffer| {
                 let len = app.write_buffer.map_or(0, |data| data.len());
@@ -148,8 +452,84 @@ impl<'a> Console<'a> {
         }
     }
 
+    /// Internal helper function for sending the next line of a write when
+    /// multiplexing. If the rest of the write is a partial line, it is kept
+    /// until the app finishes it or it is flushed, and the write completes
+    /// without sending.
+    fn send_line(&self, app_id: ProcessId, app: &mut App, multiplexer: Multiplexer) {
+        self.tx_buffer.take().map(|buffer| {
+            let name = multiplexer.process_name(app_id);
+            let write_len = app.write_len;
+            let start = write_len - app.write_remaining;
+            let line = &mut app.line;
+            let (used, len) = app.write_buffer.map_or((0, None), |data| {
+                let end = cmp::min(write_len, data.len());
+                line.next(name, &data[cmp::min(start, end)..end], buffer)
+            });
+
+            if let Some(len) = len {
+                app.write_remaining -= used;
+                self.tx_in_progress.set(app_id);
+                let _ = self.uart.transmit_buffer(buffer, len);
+            } else {
+                self.tx_buffer.replace(buffer);
+                multiplexer.alarm.start(FLUSH_MS);
+
+                let written = app.write_len;
+                app.write_len = 0;
+                app.write_remaining = 0;
+                app.write_callback.schedule(written, 0, 0);
+            }
+        });
+    }
+
+    /// Internal helper function for sending the partial line of an app on
+    /// its own when multiplexing, once the UART is free. Returns whether the
+    /// line is being sent.
+    fn flush_line(&self, app_id: ProcessId, app: &mut App, multiplexer: Multiplexer) -> bool {
+        if app.write_len > 0 {
+            // The write continues the line.
+            app.flush_pending = false;
+            return false;
+        }
+        if self.tx_in_progress.is_some() {
+            app.flush_pending = app.line.len > 0;
+            return false;
+        }
+        app.flush_pending = false;
+        self.tx_buffer.take().map_or(false, |buffer| {
+            match app.line.flush(multiplexer.process_name(app_id), buffer) {
+                Some(len) => {
+                    app.flushing = true;
+                    self.tx_in_progress.set(app_id);
+                    let _ = self.uart.transmit_buffer(buffer, len);
+                    true
+                }
+                None => {
+                    self.tx_buffer.replace(buffer);
+                    false
+                }
+            }
+        })
+    }
+
     /// Internal helper function for starting a receive operation
     fn receive_new(&self, app_id: ProcessId, app: &mut App, len: usize) -> Result<(), ErrorCode> {
+        if let Some(multiplexer) = self.multiplexer.extract() {
+            // Input is routed to the app as it is typed.
+            if app.read_len > 0 {
+                return Err(ErrorCode::BUSY);
+            }
+            let read_len = cmp::min(len, app.read_buffer.len());
+            if read_len == 0 {
+                return Err(ErrorCode::INVAL);
+            }
+            app.read_len = read_len;
+            app.read_received = 0;
+            // Show the prompt the app is likely waiting at.
+            self.flush_line(app_id, app, multiplexer);
+            return Ok(());
+        }
         if self.rx_buffer.is_none() {
             // For now, we tolerate only one concurrent receive operation on this console.
             // Competing apps will have to retry until success.
@@ -171,6 +551,51 @@ impl<'a> Console<'a> {
             Ok(())
         }
     }
+
+    /// Internal helper function for giving a byte of input to the selected
+    /// process when multiplexing.
+    fn route_input(&self, byte: u8, multiplexer: Multiplexer) {
+        let byte = match self.input.receive(byte) {
+            Input::Byte(byte) => byte,
+            _ => return,
+        };
+        for cntr in self.apps.iter() {
+            if !self
+                .input
+                .is_selected(multiplexer.process_name(cntr.processid()))
+            {
+                continue;
+            }
+            cntr.enter(|app| {
+                if app.read_len == 0 {
+                    // Nothing is reading, so the input is dropped.
+                    return;
+                }
+                let index = app.read_received;
+                let stored = app.read_buffer.mut_map_or(false, |data| {
+                    data.get_mut(index).map_or(false, |slot| {
+                        *slot = byte;
+                        true
+                    })
+                });
+                let ret = if stored {
+                    app.read_received += 1;
+                    Ok(())
+                } else {
+                    // The buffer was replaced by a smaller one.
+                    Err(ErrorCode::NOMEM)
+                };
+                let received = app.read_received;
+                if !stored || received == app.read_len {
+                    app.read_callback
+                        .schedule(kernel::into_statuscode(ret), received, 0);
+                    app.read_len = 0;
+                    app.read_received = 0;
+                }
+            });
+            break;
+        }
+    }
 }
 
 impl Driver for Console<'_> {
@@ -297,8 +722,26 @@ impl Driver for Console<'_> {
             }
             3 => {
                 // Abort RX
-                let _ = self.uart.receive_abort();
-                Ok(Ok(()))
+                if self.multiplexer.is_some() {
+                    self.apps
+                        .enter(appid, |app| {
+                            if app.read_len > 0 {
+                                let received = app.read_received;
+                                app.read_callback.schedule(
+                                    kernel::into_statuscode(Err(ErrorCode::CANCEL)),
+                                    received,
+                                    0,
+                                );
+                                app.read_len = 0;
+                                app.read_received = 0;
+                            }
+                            Ok(())
+                        })
+                        .map_err(ErrorCode::from)
+                } else {
+                    let _ = self.uart.receive_abort();
+                    Ok(Ok(()))
+                }
             }
             _ => Err(ErrorCode::NOSUPPORT),
         };
@@ -327,6 +770,11 @@ impl uart::TransmitClient for Console<'_> {
         self.tx_buffer.replace(buffer);
         self.tx_in_progress.take().map(|appid| {
             self.apps.enter(appid, |app| {
+                if app.flushing {
+                    // A flushed line is not part of a write.
+                    app.flushing = false;
+                    return;
+                }
                 match self.send_continue(appid, app) {
                     Ok(more_to_send) => {
                         if !more_to_send {
@@ -371,11 +819,16 @@ impl uart::TransmitClient for Console<'_> {
                                 false
                             }
                         }
+                    } else if app.flush_pending {
+                        self.multiplexer.extract().map_or(false, |multiplexer| {
+                            self.flush_line(appid, app, multiplexer)
+                        })
                     } else {
                         false
                     }
                 });
-                if started_tx {
+                // When multiplexing, a write can end without sending.
+                if started_tx && self.tx_in_progress.is_some() {
                     break;
                 }
             }
@@ -383,6 +836,34 @@ impl uart::TransmitClient for Console<'_> {
     }
 }
 
+impl AlarmClient for Console<'_> {
+    fn alarm(&self) {
+        let multiplexer = match self.multiplexer.extract() {
+            Some(multiplexer) => multiplexer,
+            None => return,
+        };
+        // Lines are flushed once they were kept through a whole period.
+        let mut waiting = false;
+        for cntr in self.apps.iter() {
+            let app_id = cntr.processid();
+            cntr.enter(|app| {
+                if app.line.len == 0 || app.write_len > 0 {
+                    return;
+                }
+                if app.line.aged {
+                    self.flush_line(app_id, app, multiplexer);
+                } else {
+                    app.line.aged = true;
+                    waiting = true;
+                }
+            });
+        }
+        if waiting {
+            multiplexer.alarm.start(FLUSH_MS);
+        }
+    }
+}
+
 impl uart::ReceiveClient for Console<'_> {
     fn received_buffer(
         &self,
@@ -391,6 +872,14 @@ impl uart::ReceiveClient for Console<'_> {
         rcode: Result<(), ErrorCode>,
         error: uart::Error,
     ) {
+        if let Some(multiplexer) = self.multiplexer.extract() {
+            if error == uart::Error::None && rx_len == 1 {
+                self.route_input(buffer[0], multiplexer);
+            }
+            let _ = self.uart.receive_buffer(buffer, 1);
+            return;
+        }
+
         self.rx_in_progress
             .take()
             .map(|appid| {
@@ -468,3 +957,132 @@ impl uart::ReceiveClient for Console<'_> {
         self.rx_buffer.replace(buffer);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn feed(selector: &InputSelector, bytes: &[u8]) -> Vec<Input> {
+        bytes.iter().map(|&byte| selector.receive(byte)).collect()
+    }
+
+    /// Feeds `data` to `line` and returns how much was used and the line
+    /// that was completed, if any.
+    fn next(line: &mut PartialLine, name: &str, data: &[u8]) -> (usize, Option<Vec<u8>>) {
+        let mut buffer = [0; MULTIPLEX_BUFFER_LEN];
+        let (used, len) = line.next(name, data, &mut buffer);
+        (used, len.map(|len| buffer[..len].to_vec()))
+    }
+
+    fn flush(line: &mut PartialLine, name: &str) -> Option<Vec<u8>> {
+        let mut buffer = [0; MULTIPLEX_BUFFER_LEN];
+        line.flush(name, &mut buffer)
+            .map(|len| buffer[..len].to_vec())
+    }
+
+    #[test]
+    fn lines_are_prefixed_and_kept() {
+        let mut line = PartialLine::default();
+        assert_eq!(
+            next(&mut line, "blink", b"hello\nworld"),
+            (6, Some(b"[blink] hello\n".to_vec()))
+        );
+        assert_eq!(next(&mut line, "blink", b"world"), (5, None));
+        assert_eq!(next(&mut line, "blink", b"!"), (1, None));
+        assert_eq!(
+            next(&mut line, "blink", b"\nmore"),
+            (1, Some(b"[blink] world!\n".to_vec()))
+        );
+
+        // Long names are cut short.
+        assert_eq!(
+            next(&mut line, "sensor_reader", b"x\n"),
+            (2, Some(b"[sensor_rea] x\n".to_vec()))
+        );
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let mut line = PartialLine::default();
+        let data = [b'a'; LINE_LEN + 1];
+        // Up to `LINE_LEN` bytes are kept.
+        assert_eq!(next(&mut line, "app", &data[..LINE_LEN]), (LINE_LEN, None));
+        assert_eq!(flush(&mut line, "app").unwrap().len(), 6 + LINE_LEN + 1);
+        let (used, sent) = next(&mut line, "app", &data);
+        assert_eq!(used, LINE_LEN + 1);
+        let sent = sent.unwrap();
+        assert_eq!(&sent[..6], b"[app] ");
+        assert_eq!(&sent[6..], &[&data[..], b"\n"].concat()[..]);
+
+        // A longer line is split where the buffer is full.
+        let long = [b'b'; 2 * MULTIPLEX_BUFFER_LEN];
+        let (used, sent) = next(&mut line, "app", &long);
+        assert_eq!(used, MULTIPLEX_BUFFER_LEN - 1 - 6);
+        assert_eq!(sent.unwrap().len(), MULTIPLEX_BUFFER_LEN);
+
+        // A kept line that leaves no room is sent on its own.
+        let name = "0123456789";
+        assert_eq!(next(&mut line, name, &data[..LINE_LEN]), (LINE_LEN, None));
+        let (used, sent) = next(&mut line, name, b"bc\n");
+        assert_eq!(used, 0);
+        assert_eq!(
+            sent.unwrap(),
+            [&b"[0123456789] "[..], &data[..LINE_LEN], b"\n"].concat()
+        );
+        assert_eq!(
+            next(&mut line, name, b"bc\n"),
+            (3, Some(b"[0123456789] bc\n".to_vec()))
+        );
+    }
+
+    #[test]
+    fn partial_lines_are_flushed() {
+        let mut line = PartialLine::default();
+        assert_eq!(flush(&mut line, "app"), None);
+        assert_eq!(next(&mut line, "app", b"> "), (2, None));
+        line.aged = true;
+        assert_eq!(flush(&mut line, "app"), Some(b"[app] > \n".to_vec()));
+        assert!(!line.aged);
+        assert_eq!(flush(&mut line, "app"), None);
+
+        // The rest of the line starts a new one.
+        assert_eq!(
+            next(&mut line, "app", b"yes\n"),
+            (4, Some(b"[app] yes\n".to_vec()))
+        );
+    }
+
+    #[test]
+    fn selects_process_by_name() {
+        let selector = InputSelector::new();
+        assert!(selector.console_selected());
+        assert_eq!(feed(&selector, b"a"), [Input::Byte(b'a')]);
+
+        let inputs = feed(&selector, b"\x01blinx\x08k\r");
+        assert_eq!(inputs.last(), Some(&Input::Selected));
+        assert!(inputs[..inputs.len() - 1]
+            .iter()
+            .all(|&input| input == Input::Selecting));
+        assert!(selector.is_selected("blink"));
+        assert!(!selector.is_selected("blinker"));
+        assert!(!selector.console_selected());
+        assert_eq!(feed(&selector, b"\n"), [Input::Byte(b'\n')]);
+    }
+
+    #[test]
+    fn empty_selection_selects_console() {
+        let selector = InputSelector::new();
+        feed(&selector, b"\x01hello\n");
+        assert!(selector.is_selected("hello"));
+        assert_eq!(
+            feed(&selector, b"\x01\n"),
+            [Input::Selecting, Input::Selected]
+        );
+        assert!(selector.console_selected());
+        assert!(!selector.is_selected(""));
+        selector.map_selected(|name| assert_eq!(name, ""));
+    }
+}
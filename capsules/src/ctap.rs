@@ -1,6 +1,20 @@
 //! Provides userspace with access CTAP devices over any transport
 //! layer (USB HID, BLE, NFC). Currently only USB HID is supported.
 //!
+//! `CtapHidTransport` implements the CTAPHID transport framing of the FIDO
+//! Client to Authenticator Protocol. It allocates channels, reassembles
+//! requests from their initialization and continuation packets and answers
+//! `CTAPHID_INIT`, `CTAPHID_PING` and `CTAPHID_LOCK` itself. Its client, the
+//! `CtapDriver`, passes complete requests (e.g. `CTAPHID_CBOR` or
+//! `CTAPHID_MSG` messages) to the app and complete responses back, which the
+//! transport splits into packets. While the app processes a request, the
+//! transport sends `CTAPHID_KEEPALIVE` packets to the host and forwards
+//! `CTAPHID_CANCEL` to the app.
+//!
+//! As required by the specification, only one transaction is handled at a
+//! time. Requests on other channels meanwhile fail with `ERR_CHANNEL_BUSY`,
+//! as do requests on all other channels while a channel holds the lock.
+//!
 //! Setup
 //! -----
 //!
@@ -19,8 +33,13 @@
 //!         board_kernel,
 //!         ctap_send_buffer,
 //!         ctap_recv_buffer,
+//!         mux_alarm,
+//!         dynamic_deferred_caller,
 //!     )
-//!     .finalize(components::usb_ctap_component_helper!(lowrisc::usbdev::Usb));
+//!     .finalize(components::usb_ctap_component_helper!(
+//!         lowrisc::usbdev::Usb,
+//!         earlgrey::timer::RvTimer
+//!     ));
 //!
 //!     ctap.enable();
 //!     ctap.attach();
@@ -28,9 +47,13 @@
 //!
 
 use core::cell::Cell;
-use core::marker::PhantomData;
+use core::cmp;
 use core::mem;
 use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::time::{Alarm, AlarmClient};
 use kernel::hil::usb_hid;
 use kernel::{
     CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadWrite, ReadWriteAppSlice, Upcall,
@@ -40,118 +63,664 @@ use kernel::{
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::CtapHid as usize;
 
-pub struct App {
-    callback: Upcall,
-    recv_buf: ReadWriteAppSlice,
-    send_buf: ReadWriteAppSlice,
-    can_receive: Cell<bool>,
+/// Size of a CTAPHID packet.
+const PACKET_SIZE: usize = 64;
+/// Payload bytes of an initialization packet.
+const INIT_DATA_SIZE: usize = PACKET_SIZE - 7;
+/// Payload bytes of a continuation packet.
+const CONT_DATA_SIZE: usize = PACKET_SIZE - 5;
+/// The largest message, spread over an initialization packet and 128
+/// continuation packets.
+pub const MAX_MESSAGE_SIZE: usize = INIT_DATA_SIZE + 128 * CONT_DATA_SIZE;
+
+/// The channel used by the host to allocate a channel.
+const BROADCAST_CID: u32 = 0xffff_ffff;
+/// How many allocated channels we remember. When we run out, the oldest
+/// channel is replaced.
+const MAX_CHANNELS: usize = 8;
+/// How many packets of our own can wait for the send buffer. No packets are
+/// received while one is waiting, so a packet adds at most one, and a
+/// timeout or the app giving up on a request at most one more.
+const MAX_REPLIES: usize = 2;
+
+/// Period of the timer driving keepalives and timeouts.
+const TICK_MS: u32 = 100;
+/// Ticks the host has to send the next continuation packet.
+const MESSAGE_TIMEOUT_TICKS: u8 = 5;
+/// Longest lock a host can ask for.
+const MAX_LOCK_SECONDS: u8 = 10;
+
+// CTAPHID commands, without the bit marking initialization packets.
+const CTAPHID_PING: u8 = 0x01;
+const CTAPHID_MSG: u8 = 0x03;
+const CTAPHID_LOCK: u8 = 0x04;
+const CTAPHID_INIT: u8 = 0x06;
+const CTAPHID_WINK: u8 = 0x08;
+const CTAPHID_CBOR: u8 = 0x10;
+const CTAPHID_CANCEL: u8 = 0x11;
+const CTAPHID_KEEPALIVE: u8 = 0x3b;
+const CTAPHID_ERROR: u8 = 0x3f;
+const CTAPHID_VENDOR_FIRST: u8 = 0x40;
+const CTAPHID_VENDOR_LAST: u8 = 0x7f;
+
+/// Bit set in the command byte of initialization packets.
+const INIT_PACKET: u8 = 0x80;
+
+const PROTOCOL_VERSION: u8 = 2;
+/// Major, minor and build version of the device, reported by `CTAPHID_INIT`.
+const DEVICE_VERSION: [u8; 3] = [1, 0, 0];
+const CAPABILITY_WINK: u8 = 0x01;
+const CAPABILITY_CBOR: u8 = 0x04;
+const INIT_RESPONSE_SIZE: usize = 17;
+
+/// Keepalive status while the app processes a request.
+const STATUS_PROCESSING: usize = 1;
+/// Keepalive status while the app waits for the user.
+const STATUS_UPNEEDED: usize = 2;
+
+// Events passed to the app callback.
+const EVENT_RECEIVED: usize = 0;
+const EVENT_SENT: usize = 1;
+const EVENT_CANCELLED: usize = 2;
+const EVENT_ABORTED: usize = 3;
+
+/// Errors sent to the host in `CTAPHID_ERROR` packets.
+#[derive(Copy, Clone, Debug)]
+enum CtapHidError {
+    InvalidCmd = 0x01,
+    InvalidPar = 0x02,
+    InvalidLen = 0x03,
+    InvalidSeq = 0x04,
+    MsgTimeout = 0x05,
+    ChannelBusy = 0x06,
+    InvalidChannel = 0x0b,
+    Other = 0x7f,
 }
 
-impl Default for App {
-    fn default() -> App {
-        App {
-            callback: Upcall::default(),
-            recv_buf: ReadWriteAppSlice::default(),
-            send_buf: ReadWriteAppSlice::default(),
-            can_receive: Cell::new(false),
-        }
+/// The state of the single transaction the transport handles at a time.
+#[derive(Copy, Clone, Debug)]
+enum Transaction {
+    Idle,
+    /// Reassembling a request into the client.
+    Receiving {
+        cid: u32,
+        cmd: u8,
+        len: usize,
+        offset: usize,
+        seq: u8,
+    },
+    /// The client processes a request.
+    Processing {
+        cid: u32,
+        cmd: u8,
+    },
+    /// Sending a response. The response to `CTAPHID_PING` echoes the request.
+    Sending {
+        cid: u32,
+        cmd: u8,
+        len: usize,
+        offset: usize,
+        packets: usize,
+        echo: bool,
+    },
+}
+
+/// A response of the transport itself, which fits in a single packet.
+#[derive(Copy, Clone)]
+struct Reply {
+    cid: u32,
+    cmd: u8,
+    len: usize,
+    data: [u8; INIT_RESPONSE_SIZE],
+}
+
+impl Reply {
+    fn new(cid: u32, cmd: u8, data: &[u8]) -> Reply {
+        let mut reply = Reply {
+            cid,
+            cmd,
+            len: data.len(),
+            data: [0; INIT_RESPONSE_SIZE],
+        };
+        reply.data[..data.len()].copy_from_slice(data);
+        reply
     }
 }
 
-pub struct CtapDriver<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> {
-    usb: Option<&'a U>,
+fn write_init_header(packet: &mut [u8; 64], cid: u32, cmd: u8, len: usize) {
+    packet[0..4].copy_from_slice(&cid.to_be_bytes());
+    packet[4] = cmd | INIT_PACKET;
+    packet[5..7].copy_from_slice(&(len as u16).to_be_bytes());
+}
 
-    app: Grant<App>,
-    appid: OptionalCell<ProcessId>,
-    phantom: PhantomData<&'a U>,
+/// The client of `CtapHidTransport`, which stores requests and provides the
+/// responses.
+pub trait Client {
+    /// Start receiving a request of `len` bytes. Returns `BUSY` if no
+    /// request can be received now and `SIZE` if the request does not fit.
+    fn request_start(&self, len: usize) -> Result<(), ErrorCode>;
+
+    /// Store part of the request at `offset`. Returns false if the request
+    /// can no longer be stored.
+    fn request_data(&self, offset: usize, data: &[u8]) -> bool;
+
+    /// Copy part of the response, or of the request if the response echoes
+    /// it, from `offset` into `dest`. Returns false if it is gone.
+    fn response_data(&self, echo: bool, offset: usize, dest: &mut [u8]) -> bool;
+
+    /// The whole request was stored and must be answered with
+    /// `CtapHidTransport::send_response`.
+    fn request_received(&self, len: usize, cmd: u8);
+
+    /// The last packet of the response was sent.
+    fn response_sent(&self, len: usize);
+
+    /// The host cancelled the request, which should be answered with
+    /// `CTAP2_ERR_KEEPALIVE_CANCEL`.
+    fn request_cancelled(&self);
+
+    /// The transaction was aborted and must not be answered.
+    fn transaction_aborted(&self);
+}
+
+pub struct CtapHidTransport<'a, U: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> {
+    usb: &'a U,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn Client>,
 
     send_buffer: TakeCell<'static, [u8; 64]>,
     recv_buffer: TakeCell<'static, [u8; 64]>,
+
+    transaction: Cell<Transaction>,
+    /// Packets of our own waiting for the send buffer, oldest first.
+    replies: [OptionalCell<Reply>; MAX_REPLIES],
+
+    /// The allocated channels. Zero marks a free slot.
+    channels: [Cell<u32>; MAX_CHANNELS],
+    /// The slot of the next channel to allocate.
+    next_slot: Cell<usize>,
+    last_cid: Cell<u32>,
+
+    /// The channel holding the lock, if any.
+    lock: OptionalCell<u32>,
+    lock_ticks: Cell<u8>,
+    message_ticks: Cell<u8>,
+    keepalive_status: Cell<usize>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
 }
 
-impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> CtapDriver<'a, U> {
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> CtapHidTransport<'a, U, A> {
     pub fn new(
-        usb: Option<&'a U>,
+        usb: &'a U,
         send_buffer: &'static mut [u8; 64],
         recv_buffer: &'static mut [u8; 64],
-        grant: Grant<App>,
-    ) -> CtapDriver<'a, U> {
-        CtapDriver {
-            usb: usb,
-            app: grant,
-            appid: OptionalCell::empty(),
-            phantom: PhantomData,
+        alarm: &'a A,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> CtapHidTransport<'a, U, A> {
+        const FREE: Cell<u32> = Cell::new(0);
+        const NO_REPLY: OptionalCell<Reply> = OptionalCell::empty();
+        CtapHidTransport {
+            usb,
+            alarm,
+            client: OptionalCell::empty(),
             send_buffer: TakeCell::new(send_buffer),
             recv_buffer: TakeCell::new(recv_buffer),
+            transaction: Cell::new(Transaction::Idle),
+            replies: [NO_REPLY; MAX_REPLIES],
+            channels: [FREE; MAX_CHANNELS],
+            next_slot: Cell::new(0),
+            last_cid: Cell::new(0),
+            lock: OptionalCell::empty(),
+            lock_ticks: Cell::new(0),
+            message_ticks: Cell::new(0),
+            keepalive_status: Cell::new(STATUS_PROCESSING),
+            deferred_caller,
+            handle: OptionalCell::empty(),
         }
     }
-}
 
-impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> usb_hid::UsbHid<'a, [u8; 64]> for CtapDriver<'a, U> {
-    fn send_buffer(
-        &'a self,
-        send: &'static mut [u8; 64],
-    ) -> Result<usize, (ErrorCode, &'static mut [u8; 64])> {
-        if let Some(usb) = self.usb {
-            usb.send_buffer(send)
+    pub fn set_client(&self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+
+    /// Sets the deferred call handle and starts listening to the host.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+        self.deferred_caller.set(handle);
+    }
+
+    /// Answer the request the client received with a response of `len`
+    /// bytes, which the client provides through `response_data`.
+    pub fn send_response(&self, len: usize) -> Result<(), ErrorCode> {
+        let (cid, cmd) = match self.transaction.get() {
+            Transaction::Processing { cid, cmd } => (cid, cmd),
+            _ => return Err(ErrorCode::INVAL),
+        };
+        if len > MAX_MESSAGE_SIZE {
+            return Err(ErrorCode::SIZE);
+        }
+
+        self.transaction.set(Transaction::Sending {
+            cid,
+            cmd,
+            len,
+            offset: 0,
+            packets: 0,
+            echo: false,
+        });
+        self.send_next();
+        Ok(())
+    }
+
+    /// Give up on the request being received, if any, because the client
+    /// can no longer store it.
+    pub fn stop_receiving(&self) {
+        if let Transaction::Receiving { cid, .. } = self.transaction.get() {
+            self.transaction.set(Transaction::Idle);
+            self.queue_error(cid, CtapHidError::Other);
+            self.send_next();
+        }
+    }
+
+    /// Set the status reported by keepalives while the client processes a
+    /// request, `1` for processing and `2` for waiting for user presence.
+    pub fn set_keepalive_status(&self, status: usize) -> Result<(), ErrorCode> {
+        match status {
+            STATUS_PROCESSING | STATUS_UPNEEDED => {
+                self.keepalive_status.set(status);
+                Ok(())
+            }
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+
+    /// Pass the receive buffer back to the USB layer, so we get the next
+    /// packet.
+    fn receive(&self) {
+        self.recv_buffer.take().map(|buf| {
+            if let Err((_, buf)) = self.usb.receive_buffer(buf) {
+                self.recv_buffer.replace(buf);
+            }
+        });
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    fn is_channel(&self, cid: u32) -> bool {
+        cid != 0 && cid != BROADCAST_CID && self.channels.iter().any(|c| c.get() == cid)
+    }
+
+    fn allocate_channel(&self) -> u32 {
+        let mut cid = self.last_cid.get().wrapping_add(1);
+        if cid == 0 || cid == BROADCAST_CID {
+            cid = 1;
+        }
+        self.last_cid.set(cid);
+
+        let slot = self.next_slot.get();
+        self.abort(self.channels[slot].get());
+        self.channels[slot].set(cid);
+        self.next_slot.set((slot + 1) % MAX_CHANNELS);
+        cid
+    }
+
+    /// Abort the transaction of a channel, if it has one.
+    fn abort(&self, cid: u32) {
+        match self.transaction.get() {
+            Transaction::Receiving { cid: c, .. } if c == cid => {
+                self.transaction.set(Transaction::Idle);
+            }
+            Transaction::Processing { cid: c, .. } if c == cid => {
+                self.transaction.set(Transaction::Idle);
+                self.client.map(|client| client.transaction_aborted());
+            }
+            Transaction::Sending { cid: c, echo, .. } if c == cid => {
+                self.transaction.set(Transaction::Idle);
+                if !echo {
+                    self.client.map(|client| client.transaction_aborted());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Queue a packet of our own behind the ones already waiting.
+    fn queue_reply(&self, reply: Reply) {
+        // The queue cannot overflow, see `MAX_REPLIES`.
+        if let Some(slot) = self.replies.iter().find(|slot| slot.is_none()) {
+            slot.set(reply);
+        }
+    }
+
+    fn queue_error(&self, cid: u32, error: CtapHidError) {
+        self.queue_reply(Reply::new(cid, CTAPHID_ERROR, &[error as u8]));
+    }
+
+    /// Take the oldest packet of our own.
+    fn next_reply(&self) -> Option<Reply> {
+        let reply = self.replies[0].take();
+        for i in 1..MAX_REPLIES {
+            self.replies[i]
+                .take()
+                .map(|next| self.replies[i - 1].set(next));
+        }
+        reply
+    }
+
+    fn handle_packet(&self, packet: &[u8; 64]) {
+        let cid = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        if packet[4] & INIT_PACKET == 0 {
+            self.handle_continuation(cid, packet[4], &packet[5..]);
+            return;
+        }
+
+        let cmd = packet[4] & !INIT_PACKET;
+        let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+        let data = &packet[7..];
+
+        if cmd == CTAPHID_INIT {
+            self.handle_init(cid, len, data);
+            return;
+        }
+        if !self.is_channel(cid) {
+            self.queue_error(cid, CtapHidError::InvalidChannel);
+            return;
+        }
+        if self.lock.map_or(false, |lock| *lock != cid) {
+            if cmd != CTAPHID_CANCEL {
+                self.queue_error(cid, CtapHidError::ChannelBusy);
+            }
+            return;
+        }
+
+        match (self.transaction.get(), cmd) {
+            (Transaction::Idle, _) => {}
+            (Transaction::Processing { cid: c, .. }, CTAPHID_CANCEL) if c == cid => {
+                self.client.map(|client| client.request_cancelled());
+                return;
+            }
+            (Transaction::Receiving { cid: c, .. }, CTAPHID_CANCEL) if c == cid => {
+                self.transaction.set(Transaction::Idle);
+                return;
+            }
+            (_, CTAPHID_CANCEL) => return,
+            (Transaction::Receiving { cid: c, .. }, _) if c == cid => {
+                // A new request before the last one was complete
+                self.transaction.set(Transaction::Idle);
+                self.queue_error(cid, CtapHidError::InvalidSeq);
+                return;
+            }
+            _ => {
+                self.queue_error(cid, CtapHidError::ChannelBusy);
+                return;
+            }
+        }
+
+        match cmd {
+            // There is nothing to cancel
+            CTAPHID_CANCEL => {}
+            CTAPHID_LOCK => self.handle_lock(cid, len, data),
+            CTAPHID_PING
+            | CTAPHID_MSG
+            | CTAPHID_WINK
+            | CTAPHID_CBOR
+            | CTAPHID_VENDOR_FIRST..=CTAPHID_VENDOR_LAST => self.start_request(cid, cmd, len, data),
+            _ => self.queue_error(cid, CtapHidError::InvalidCmd),
+        }
+    }
+
+    fn handle_init(&self, cid: u32, len: usize, data: &[u8]) {
+        if len != 8 {
+            self.queue_error(cid, CtapHidError::InvalidLen);
+            return;
+        }
+        if self.lock.map_or(false, |lock| *lock != cid) {
+            self.queue_error(cid, CtapHidError::ChannelBusy);
+            return;
+        }
+
+        let new_cid = if cid == BROADCAST_CID {
+            self.allocate_channel()
+        } else if self.is_channel(cid) {
+            // Resynchronize the channel
+            self.abort(cid);
+            cid
+        } else {
+            self.queue_error(cid, CtapHidError::InvalidChannel);
+            return;
+        };
+
+        let mut response = [0; INIT_RESPONSE_SIZE];
+        // The nonce
+        response[..8].copy_from_slice(&data[..8]);
+        response[8..12].copy_from_slice(&new_cid.to_be_bytes());
+        response[12] = PROTOCOL_VERSION;
+        response[13..16].copy_from_slice(&DEVICE_VERSION);
+        response[16] = CAPABILITY_WINK | CAPABILITY_CBOR;
+        self.queue_reply(Reply::new(cid, CTAPHID_INIT, &response));
+    }
+
+    fn handle_lock(&self, cid: u32, len: usize, data: &[u8]) {
+        if len != 1 {
+            self.queue_error(cid, CtapHidError::InvalidLen);
+            return;
+        }
+        let seconds = data[0];
+        if seconds > MAX_LOCK_SECONDS {
+            self.queue_error(cid, CtapHidError::InvalidPar);
+            return;
+        }
+
+        if seconds == 0 {
+            self.lock.clear();
         } else {
-            Err((ErrorCode::NOSUPPORT, send))
+            self.lock.set(cid);
+            self.lock_ticks.set(seconds * (1000 / TICK_MS) as u8);
+            self.start_timer();
         }
+        self.queue_reply(Reply::new(cid, CTAPHID_LOCK, &[]));
     }
 
-    fn send_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
-        if let Some(usb) = self.usb {
-            usb.send_cancel()
+    /// Start reassembling a request in the client.
+    fn start_request(&self, cid: u32, cmd: u8, len: usize, data: &[u8]) {
+        if len > MAX_MESSAGE_SIZE {
+            self.queue_error(cid, CtapHidError::InvalidLen);
+            return;
+        }
+
+        let copy_len = cmp::min(len, INIT_DATA_SIZE);
+        let started = self
+            .client
+            .map_or(Err(CtapHidError::ChannelBusy), |client| {
+                match client.request_start(len) {
+                    Ok(()) if client.request_data(0, &data[..copy_len]) => Ok(()),
+                    Ok(()) | Err(ErrorCode::SIZE) => Err(CtapHidError::InvalidLen),
+                    Err(_) => Err(CtapHidError::ChannelBusy),
+                }
+            });
+        if let Err(error) = started {
+            self.queue_error(cid, error);
+            return;
+        }
+
+        self.transaction.set(Transaction::Receiving {
+            cid,
+            cmd,
+            len,
+            offset: copy_len,
+            seq: 0,
+        });
+        if copy_len == len {
+            self.request_complete();
         } else {
-            Err(ErrorCode::NOSUPPORT)
+            self.message_ticks.set(MESSAGE_TIMEOUT_TICKS);
+            self.start_timer();
         }
     }
 
-    fn receive_buffer(
-        &'a self,
-        recv: &'static mut [u8; 64],
-    ) -> Result<(), (ErrorCode, &'static mut [u8; 64])> {
-        if let Some(usb) = self.usb {
-            usb.receive_buffer(recv)
+    fn handle_continuation(&self, cid: u32, seq: u8, data: &[u8]) {
+        let (cmd, len, offset, expected) = match self.transaction.get() {
+            Transaction::Receiving {
+                cid: c,
+                cmd,
+                len,
+                offset,
+                seq,
+            } if c == cid => (cmd, len, offset, seq),
+            // Spurious continuation packets are ignored.
+            _ => return,
+        };
+
+        if seq != expected {
+            self.transaction.set(Transaction::Idle);
+            self.queue_error(cid, CtapHidError::InvalidSeq);
+            return;
+        }
+
+        let copy_len = cmp::min(len - offset, CONT_DATA_SIZE);
+        let copied = self.client.map_or(false, |client| {
+            client.request_data(offset, &data[..copy_len])
+        });
+        if !copied {
+            // The app took its buffer back.
+            self.transaction.set(Transaction::Idle);
+            self.queue_error(cid, CtapHidError::Other);
+            return;
+        }
+
+        self.transaction.set(Transaction::Receiving {
+            cid,
+            cmd,
+            len,
+            offset: offset + copy_len,
+            seq: seq + 1,
+        });
+        if offset + copy_len == len {
+            self.request_complete();
         } else {
-            Err((ErrorCode::NODEVICE, recv))
+            self.message_ticks.set(MESSAGE_TIMEOUT_TICKS);
         }
     }
 
-    fn receive_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
-        if let Some(usb) = self.usb {
-            usb.receive_cancel()
+    fn request_complete(&self) {
+        if let Transaction::Receiving { cid, cmd, len, .. } = self.transaction.get() {
+            if cmd == CTAPHID_PING {
+                self.transaction.set(Transaction::Sending {
+                    cid,
+                    cmd,
+                    len,
+                    offset: 0,
+                    packets: 0,
+                    echo: true,
+                });
+            } else {
+                self.transaction.set(Transaction::Processing { cid, cmd });
+                self.keepalive_status.set(STATUS_PROCESSING);
+                self.client.map(|client| client.request_received(len, cmd));
+                self.start_timer();
+            }
+        }
+    }
+
+    /// Send the next packet, if the send buffer is not in use.
+    fn send_next(&self) {
+        let buf = match self.send_buffer.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+
+        let ready = if let Some(reply) = self.next_reply() {
+            write_init_header(buf, reply.cid, reply.cmd, reply.len);
+            buf[7..7 + reply.len].copy_from_slice(&reply.data[..reply.len]);
+            for b in buf[7 + reply.len..].iter_mut() {
+                *b = 0;
+            }
+            true
+        } else if let Transaction::Sending {
+            cid,
+            cmd,
+            len,
+            offset,
+            packets,
+            echo,
+        } = self.transaction.get()
+        {
+            if packets > 0 && offset == len {
+                // The last packet went out.
+                self.transaction.set(Transaction::Idle);
+                if !echo {
+                    self.client.map(|client| client.response_sent(len));
+                }
+                false
+            } else {
+                let header_len = if packets == 0 { 7 } else { 5 };
+                let copy_len = cmp::min(len - offset, PACKET_SIZE - header_len);
+                let copied = self.client.map_or(false, |client| {
+                    client.response_data(echo, offset, &mut buf[header_len..header_len + copy_len])
+                });
+                if copied {
+                    if packets == 0 {
+                        write_init_header(buf, cid, cmd, len);
+                    } else {
+                        buf[0..4].copy_from_slice(&cid.to_be_bytes());
+                        buf[4] = (packets - 1) as u8;
+                    }
+                    for b in buf[header_len + copy_len..].iter_mut() {
+                        *b = 0;
+                    }
+                    self.transaction.set(Transaction::Sending {
+                        cid,
+                        cmd,
+                        len,
+                        offset: offset + copy_len,
+                        packets: packets + 1,
+                        echo,
+                    });
+                    true
+                } else {
+                    // The app took its buffer back.
+                    self.abort(cid);
+                    false
+                }
+            }
+        } else {
+            false
+        };
+
+        if ready {
+            if let Err((_, buf)) = self.usb.send_buffer(buf) {
+                self.send_buffer.replace(buf);
+            }
         } else {
-            Err(ErrorCode::NOSUPPORT)
+            self.send_buffer.replace(buf);
         }
     }
 }
 
-impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> usb_hid::Client<'a, [u8; 64]> for CtapDriver<'a, U> {
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> usb_hid::Client<'a, [u8; 64]>
+    for CtapHidTransport<'a, U, A>
+{
     fn packet_received(
         &'a self,
         _result: Result<(), ErrorCode>,
         buffer: &'static mut [u8; 64],
         _endpoint: usize,
     ) {
-        self.appid.map(|id| {
-            self.app
-                .enter(*id, |app| {
-                    app.recv_buf.mut_map_or((), |dest| {
-                        dest.as_mut().copy_from_slice(buffer.as_ref());
-                    });
-
-                    app.callback.schedule(0, 0, 0);
-                    app.can_receive.set(false);
-                })
-                .map_err(|err| {
-                    if err == kernel::procs::Error::NoSuchApp
-                        || err == kernel::procs::Error::InactiveApp
-                    {}
-                })
-        });
+        self.handle_packet(buffer);
 
+        // The USB layer holds back the next packet until we pass the buffer
+        // back, which we cannot do from this callback.
         self.recv_buffer.replace(buffer);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+
+        self.send_next();
     }
 
     fn packet_transmitted(
@@ -160,34 +729,192 @@ impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> usb_hid::Client<'a, [u8; 64]> for Cta
         buffer: &'static mut [u8; 64],
         _endpoint: usize,
     ) {
+        self.send_buffer.replace(buffer);
+        self.send_next();
+
+        // Reception waits for our packets to go out.
+        if self.replies[0].is_none() && self.recv_buffer.is_some() {
+            self.handle.map(|handle| self.deferred_caller.set(*handle));
+        }
+    }
+
+    fn can_receive(&'a self) -> bool {
+        // We always take packets, to answer them even while busy.
+        true
+    }
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> AlarmClient
+    for CtapHidTransport<'a, U, A>
+{
+    fn alarm(&self) {
+        match self.transaction.get() {
+            Transaction::Receiving { cid, .. } => {
+                let ticks = self.message_ticks.get().saturating_sub(1);
+                self.message_ticks.set(ticks);
+                if ticks == 0 {
+                    self.transaction.set(Transaction::Idle);
+                    self.queue_error(cid, CtapHidError::MsgTimeout);
+                }
+            }
+            Transaction::Processing { cid, .. } => {
+                if self.replies[0].is_none() {
+                    self.queue_reply(Reply::new(
+                        cid,
+                        CTAPHID_KEEPALIVE,
+                        &[self.keepalive_status.get() as u8],
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        if self.lock.is_some() {
+            let ticks = self.lock_ticks.get().saturating_sub(1);
+            self.lock_ticks.set(ticks);
+            if ticks == 0 {
+                self.lock.clear();
+            }
+        }
+
+        self.send_next();
+
+        let active = match self.transaction.get() {
+            Transaction::Receiving { .. } | Transaction::Processing { .. } => true,
+            Transaction::Idle | Transaction::Sending { .. } => false,
+        };
+        if active || self.lock.is_some() {
+            self.start_timer();
+        }
+    }
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> DynamicDeferredCallClient
+    for CtapHidTransport<'a, U, A>
+{
+    fn call(&self, _handle: DeferredCallHandle) {
+        // Packets that would queue more replies wait for the pending ones.
+        if self.replies[0].is_none() {
+            self.receive();
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    recv_buf: ReadWriteAppSlice,
+    send_buf: ReadWriteAppSlice,
+    receiving: bool,
+}
+
+pub struct CtapDriver<'a, U: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> {
+    transport: &'a CtapHidTransport<'a, U, A>,
+
+    app: Grant<App>,
+    appid: OptionalCell<ProcessId>,
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> CtapDriver<'a, U, A> {
+    pub fn new(
+        transport: &'a CtapHidTransport<'a, U, A>,
+        grant: Grant<App>,
+    ) -> CtapDriver<'a, U, A> {
+        CtapDriver {
+            transport,
+            app: grant,
+            appid: OptionalCell::empty(),
+        }
+    }
+
+    fn notify(&self, event: usize, arg1: usize, arg2: usize) {
         self.appid.map(|id| {
+            let _ = self.app.enter(*id, |app| {
+                if event == EVENT_RECEIVED {
+                    app.receiving = false;
+                }
+                app.callback.schedule(event, arg1, arg2);
+            });
+        });
+    }
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> Client for CtapDriver<'a, U, A> {
+    fn request_start(&self, len: usize) -> Result<(), ErrorCode> {
+        self.appid.map_or(Err(ErrorCode::BUSY), |id| {
+            self.app
+                .enter(*id, |app| {
+                    if !app.receiving {
+                        Err(ErrorCode::BUSY)
+                    } else if app.recv_buf.map_or(0, |buf| buf.len()) < len {
+                        Err(ErrorCode::SIZE)
+                    } else {
+                        Ok(())
+                    }
+                })
+                .unwrap_or(Err(ErrorCode::BUSY))
+        })
+    }
+
+    fn request_data(&self, offset: usize, data: &[u8]) -> bool {
+        self.appid.map_or(false, |id| {
             self.app
                 .enter(*id, |app| {
-                    app.callback.schedule(1, 0, 0);
+                    app.recv_buf.mut_map_or(false, |dest| {
+                        let dest = dest.as_mut();
+                        if dest.len() < offset + data.len() {
+                            return false;
+                        }
+                        dest[offset..offset + data.len()].copy_from_slice(data);
+                        true
+                    })
                 })
-                .map_err(|err| {
-                    if err == kernel::procs::Error::NoSuchApp
-                        || err == kernel::procs::Error::InactiveApp
-                    {}
+                .unwrap_or(false)
+        })
+    }
+
+    fn response_data(&self, echo: bool, offset: usize, dest: &mut [u8]) -> bool {
+        self.appid.map_or(false, |id| {
+            self.app
+                .enter(*id, |app| {
+                    let src = if echo { &app.recv_buf } else { &app.send_buf };
+                    src.map_or(false, |data| {
+                        let data = data.as_ref();
+                        if data.len() < offset + dest.len() {
+                            return false;
+                        }
+                        dest.copy_from_slice(&data[offset..offset + dest.len()]);
+                        true
+                    })
                 })
-        });
+                .unwrap_or(false)
+        })
+    }
 
-        // Save our send buffer so we can use it later
-        self.send_buffer.replace(buffer);
+    fn request_received(&self, len: usize, cmd: u8) {
+        self.notify(EVENT_RECEIVED, len, cmd as usize);
     }
 
-    fn can_receive(&'a self) -> bool {
-        self.appid
-            .map(|id| {
-                self.app
-                    .enter(*id, |app| app.can_receive.get())
-                    .unwrap_or(false)
-            })
-            .unwrap_or(false)
+    fn response_sent(&self, len: usize) {
+        self.notify(EVENT_SENT, len, 0);
+    }
+
+    fn request_cancelled(&self) {
+        self.notify(EVENT_CANCELLED, 0, 0);
+    }
+
+    fn transaction_aborted(&self) {
+        self.notify(EVENT_ABORTED, 0, 0);
     }
 }
 
-impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> Driver for CtapDriver<'a, U> {
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> Driver for CtapDriver<'a, U, A> {
+    /// Share a buffer with the driver.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer for received requests
+    /// - `1`: Buffer for the responses to send
     fn allow_readwrite(
         &self,
         appid: ProcessId,
@@ -195,25 +922,14 @@ impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> Driver for CtapDriver<'a, U> {
         mut slice: ReadWriteAppSlice,
     ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
         let res = match allow_num {
-            // Pass buffer for the recvieved data to be stored in
             0 => self
                 .app
-                .enter(appid, |app| {
-                    mem::swap(&mut slice, &mut app.recv_buf);
-                    Ok(())
-                })
-                .unwrap_or(Err(ErrorCode::FAIL)),
-
-            // Pass buffer for the sent data to be stored in
+                .enter(appid, |app| mem::swap(&mut slice, &mut app.recv_buf))
+                .map_err(ErrorCode::from),
             1 => self
                 .app
-                .enter(appid, |app| {
-                    mem::swap(&mut slice, &mut app.send_buf);
-                    Ok(())
-                })
-                .unwrap_or(Err(ErrorCode::FAIL)),
-
-            // default
+                .enter(appid, |app| mem::swap(&mut slice, &mut app.send_buf))
+                .map_err(ErrorCode::from),
             _ => Err(ErrorCode::NOSUPPORT),
         };
 
@@ -227,10 +943,14 @@ impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> Driver for CtapDriver<'a, U> {
     ///
     /// ### `subscribe_num`
     ///
-    /// - `0`: Subscribe to interrupts from Ctap events.
-    ///        The callback signature is `fn(direction: u32)`
-    ///        `fn(0)` indicates a packet was recieved
-    ///        `fn(1)` indicates a packet was transmitted
+    /// - `0`: Subscribe to CTAPHID transactions. The callback signature is
+    ///        `fn(event: u32, len: u32, cmd: u32)`
+    ///        `fn(0, len, cmd)` indicates a request was received
+    ///        `fn(1, len, 0)` indicates the response was sent
+    ///        `fn(2, 0, 0)` indicates the host cancelled the request, which
+    ///        should be answered with `CTAP2_ERR_KEEPALIVE_CANCEL`
+    ///        `fn(3, 0, 0)` indicates the transaction was aborted and must
+    ///        not be answered
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -238,17 +958,10 @@ impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> Driver for CtapDriver<'a, U> {
         appid: ProcessId,
     ) -> Result<Upcall, (Upcall, ErrorCode)> {
         let res = match subscribe_num {
-            0 => {
-                // set callback
-                self.app
-                    .enter(appid, |app| {
-                        mem::swap(&mut app.callback, &mut callback);
-                        Ok(())
-                    })
-                    .unwrap_or(Err(ErrorCode::FAIL))
-            }
-
-            // default
+            0 => self
+                .app
+                .enter(appid, |app| mem::swap(&mut app.callback, &mut callback))
+                .map_err(ErrorCode::from),
             _ => Err(ErrorCode::NOSUPPORT),
         };
 
@@ -258,178 +971,79 @@ impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> Driver for CtapDriver<'a, U> {
         }
     }
 
+    /// Handle CTAPHID transactions.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Receive the next request into the read-write buffer 0.
+    /// - `2`: Answer the current request with the first `arg1` bytes of the
+    ///        read-write buffer 1. The response uses the command of the
+    ///        request.
+    /// - `3`: Stop receiving requests.
+    /// - `4`: Set the status reported by keepalives while processing the
+    ///        request, `1` for processing and `2` for waiting for user
+    ///        presence.
     fn command(
         &self,
         command_num: usize,
-        _data1: usize,
-        _data2: usize,
+        arg1: usize,
+        _arg2: usize,
         appid: ProcessId,
     ) -> CommandReturn {
-        let can_access = self.appid.map_or(true, |owning_app| {
-            if owning_app == &appid {
-                // We own the Ctap device
-                true
-            } else {
-                false
-            }
-        });
+        if command_num == 0 {
+            return CommandReturn::success();
+        }
 
+        let can_access = self.appid.map_or(true, |owning_app| owning_app == &appid);
         if !can_access {
             return CommandReturn::failure(ErrorCode::BUSY);
         }
 
-        match command_num {
-            // Send data
-            0 => self
-                .app
-                .enter(appid, |app| {
-                    self.appid.set(appid);
-                    if let Some(usb) = self.usb {
-                        app.send_buf
-                            .map_or(CommandReturn::failure(ErrorCode::RESERVE), |d| {
-                                self.send_buffer.take().map_or(
-                                    CommandReturn::failure(ErrorCode::RESERVE),
-                                    |buf| {
-                                        let data = d.as_ref();
-
-                                        // Copy the data into the static buffer
-                                        buf.copy_from_slice(&data[0..]);
-
-                                        let _ = usb.send_buffer(buf);
-                                        CommandReturn::success()
-                                    },
-                                )
-                            })
-                    } else {
-                        CommandReturn::failure(ErrorCode::NOSUPPORT)
+        let res = self
+            .app
+            .enter(appid, |app| {
+                self.appid.set(appid);
+                match command_num {
+                    1 => {
+                        app.receiving = true;
+                        Ok(())
                     }
-                })
-                .unwrap_or_else(|err| err.into()),
-            // Allow receive
-            1 => self
-                .app
-                .enter(appid, |app| {
-                    self.appid.set(appid);
-                    if let Some(usb) = self.usb {
-                        app.can_receive.set(true);
-                        if let Some(buf) = self.recv_buffer.take() {
-                            match usb.receive_buffer(buf) {
-                                Ok(_) => CommandReturn::success(),
-                                Err((err, buffer)) => {
-                                    self.recv_buffer.replace(buffer);
-                                    CommandReturn::failure(err)
-                                }
-                            }
+                    2 => {
+                        if app.send_buf.map_or(0, |buf| buf.len()) < arg1 {
+                            Err(ErrorCode::SIZE)
                         } else {
-                            CommandReturn::failure(ErrorCode::BUSY)
+                            Ok(())
                         }
-                    } else {
-                        CommandReturn::failure(ErrorCode::NOSUPPORT)
                     }
-                })
-                .unwrap_or_else(|err| err.into()),
-            // Cancel send
-            2 => self
-                .app
-                .enter(appid, |_app| {
-                    self.appid.set(appid);
-                    if let Some(usb) = self.usb {
-                        match usb.receive_cancel() {
-                            Ok(buf) => {
-                                self.recv_buffer.replace(buf);
-                                CommandReturn::success()
-                            }
-                            Err(err) => CommandReturn::failure(err),
-                        }
-                    } else {
-                        CommandReturn::failure(ErrorCode::NOSUPPORT)
-                    }
-                })
-                .unwrap_or_else(|err| err.into()),
-            // Cancel receive
-            3 => self
-                .app
-                .enter(appid, |_app| {
-                    self.appid.set(appid);
-                    if let Some(usb) = self.usb {
-                        match usb.receive_cancel() {
-                            Ok(buf) => {
-                                self.recv_buffer.replace(buf);
-                                CommandReturn::success()
-                            }
-                            Err(err) => CommandReturn::failure(err),
-                        }
-                    } else {
-                        CommandReturn::failure(ErrorCode::NOSUPPORT)
-                    }
-                })
-                .unwrap_or_else(|err| err.into()),
-            // Send or receive
-            // This command has two parts.
-            //    Part 1: Receive
-            //            This will allow receives, the same as the Allow
-            //            receive command above. If data is ready to receive
-            //            the `packet_received()` callback will be called.
-            //            When this happens the client callback will be
-            //            scheduled and no send event will occur.
-            //    Part 2: Send
-            //            If no receive occurs we will be left in a start where
-            //            future recieves will be allowed. This is the same
-            //            outcome as calling the Allow receive command.
-            //            As well as that we will then send the data in the
-            //            send buffer.
-            4 => self
-                .app
-                .enter(appid, |app| {
-                    if let Some(usb) = self.usb {
-                        if app.can_receive.get() {
-                            // We are already receiving
-                            CommandReturn::failure(ErrorCode::BUSY)
-                        } else {
-                            app.can_receive.set(true);
-                            if let Some(buf) = self.recv_buffer.take() {
-                                match usb.receive_buffer(buf) {
-                                    Ok(_) => CommandReturn::success(),
-                                    Err((err, buffer)) => {
-                                        self.recv_buffer.replace(buffer);
-                                        return CommandReturn::failure(err);
-                                    }
-                                }
-                            } else {
-                                return CommandReturn::failure(ErrorCode::BUSY);
-                            };
-
-                            if !app.can_receive.get() {
-                                // The call to receive_buffer() collected a pending packet.
-                                CommandReturn::failure(ErrorCode::BUSY)
-                            } else {
-                                app.send_buf.map_or(
-                                    CommandReturn::failure(ErrorCode::RESERVE),
-                                    |d| {
-                                        self.send_buffer.take().map_or(
-                                            CommandReturn::failure(ErrorCode::RESERVE),
-                                            |buf| {
-                                                let data = d.as_ref();
-
-                                                // Copy the data into the static buffer
-                                                buf.copy_from_slice(&data[0..]);
-
-                                                let _ = usb.send_buffer(buf);
-                                                CommandReturn::success()
-                                            },
-                                        )
-                                    },
-                                )
-                            }
-                        }
-                    } else {
-                        CommandReturn::failure(ErrorCode::NOSUPPORT)
+                    3 => {
+                        app.receiving = false;
+                        Ok(())
                     }
-                })
-                .unwrap_or_else(|err| err.into()),
+                    4 => Ok(()),
+                    _ => Err(ErrorCode::NOSUPPORT),
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        // The transport reads the buffers of the app, so it must not run
+        // while the grant is entered.
+        let res = res.and_then(|()| match command_num {
+            2 => self.transport.send_response(arg1),
+            3 => {
+                self.transport.stop_receiving();
+                Ok(())
+            }
+            4 => self.transport.set_keepalive_status(arg1),
+            _ => Ok(()),
+        });
 
-            // default
-            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
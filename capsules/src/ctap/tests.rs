@@ -0,0 +1,602 @@
+//! Tests of the CTAPHID framing against an emulated host.
+//!
+//! The host sends packets whenever the transport passed its receive buffer to
+//! the USB layer, and packets of the transport stay in the send buffer until
+//! the test lets them go out, so a test can hold back the send buffer while
+//! the host keeps talking.
+
+extern crate std;
+
+use core::convert::TryInto;
+use std::cell::{Cell, RefCell};
+use std::vec;
+use std::vec::Vec;
+
+use kernel::common::cells::TakeCell;
+use kernel::common::dynamic_deferred_call::{DeferredCallHandle, DynamicDeferredCallClient};
+use kernel::hil::usb_hid::{self, Client as _};
+use kernel::ErrorCode;
+
+use super::*;
+use crate::emulated_alarm::EmulatedAlarm;
+use crate::test_support::{deferred_caller, leak, leak_mut, Events};
+
+type Packet = [u8; 64];
+type Transport = CtapHidTransport<'static, Usb, EmulatedAlarm<'static>>;
+
+const NONCE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+const REQUEST_CAPACITY: usize = 1024;
+
+struct Usb {
+    /// The buffer the next packet of the host goes to.
+    receiving: TakeCell<'static, Packet>,
+    /// The packet being sent to the host.
+    sending: TakeCell<'static, Packet>,
+}
+
+impl usb_hid::UsbHid<'static, Packet> for Usb {
+    fn send_buffer(
+        &self,
+        send: &'static mut Packet,
+    ) -> Result<usize, (ErrorCode, &'static mut Packet)> {
+        if self.sending.is_some() {
+            return Err((ErrorCode::BUSY, send));
+        }
+        self.sending.replace(send);
+        Ok(PACKET_SIZE)
+    }
+
+    fn send_cancel(&self) -> Result<&'static mut Packet, ErrorCode> {
+        self.sending.take().ok_or(ErrorCode::INVAL)
+    }
+
+    fn receive_buffer(
+        &self,
+        recv: &'static mut Packet,
+    ) -> Result<(), (ErrorCode, &'static mut Packet)> {
+        if self.receiving.is_some() {
+            return Err((ErrorCode::BUSY, recv));
+        }
+        self.receiving.replace(recv);
+        Ok(())
+    }
+
+    fn receive_cancel(&self) -> Result<&'static mut Packet, ErrorCode> {
+        self.receiving.take().ok_or(ErrorCode::INVAL)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Received(usize, u8),
+    Sent(usize),
+    Cancelled,
+    Aborted,
+}
+
+/// Stands in for the app, behind the driver.
+struct App {
+    receiving: Cell<bool>,
+    request: RefCell<Vec<u8>>,
+    response: RefCell<Vec<u8>>,
+    events: Events<Event>,
+}
+
+impl Client for App {
+    fn request_start(&self, len: usize) -> Result<(), ErrorCode> {
+        if !self.receiving.get() {
+            Err(ErrorCode::BUSY)
+        } else if len > REQUEST_CAPACITY {
+            Err(ErrorCode::SIZE)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn request_data(&self, offset: usize, data: &[u8]) -> bool {
+        self.request.borrow_mut()[offset..offset + data.len()].copy_from_slice(data);
+        true
+    }
+
+    fn response_data(&self, echo: bool, offset: usize, dest: &mut [u8]) -> bool {
+        let src = if echo { &self.request } else { &self.response };
+        dest.copy_from_slice(&src.borrow()[offset..offset + dest.len()]);
+        true
+    }
+
+    fn request_received(&self, len: usize, cmd: u8) {
+        self.receiving.set(false);
+        self.events.push(Event::Received(len, cmd));
+    }
+
+    fn response_sent(&self, len: usize) {
+        self.events.push(Event::Sent(len));
+    }
+
+    fn request_cancelled(&self) {
+        self.events.push(Event::Cancelled);
+    }
+
+    fn transaction_aborted(&self) {
+        self.events.push(Event::Aborted);
+    }
+}
+
+struct Device {
+    usb: &'static Usb,
+    alarm: &'static EmulatedAlarm<'static>,
+    app: &'static App,
+    transport: &'static Transport,
+    handle: DeferredCallHandle,
+}
+
+impl Device {
+    fn new() -> Device {
+        let usb = leak(Usb {
+            receiving: TakeCell::empty(),
+            sending: TakeCell::empty(),
+        });
+        let alarm = leak(EmulatedAlarm::new());
+        let app = leak(App {
+            receiving: Cell::new(true),
+            request: RefCell::new(vec![0; REQUEST_CAPACITY]),
+            response: RefCell::new(Vec::new()),
+            events: Events::new(),
+        });
+        let deferred_caller = deferred_caller();
+        let transport: &'static Transport = leak(CtapHidTransport::new(
+            usb,
+            leak_mut([0; PACKET_SIZE]),
+            leak_mut([0; PACKET_SIZE]),
+            alarm,
+            deferred_caller,
+        ));
+        alarm.set_alarm_client(transport);
+        transport.set_client(app);
+        let handle = deferred_caller.register(transport).unwrap();
+        transport.initialize_callback_handle(handle);
+        transport.call(handle);
+        Device {
+            usb,
+            alarm,
+            app,
+            transport,
+            handle,
+        }
+    }
+
+    /// Send a packet from the host. Returns false if the transport does not
+    /// take packets now.
+    fn send(&self, packet: Packet) -> bool {
+        match self.usb.receiving.take() {
+            Some(buffer) => {
+                *buffer = packet;
+                self.transport.packet_received(Ok(()), buffer, 0);
+                self.transport.call(self.handle);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Let the packets of the transport go out, and collect them.
+    fn receive(&self) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        while let Some(buffer) = self.usb.sending.take() {
+            packets.push(*buffer);
+            self.transport.packet_transmitted(Ok(()), buffer, 0);
+            self.transport.call(self.handle);
+        }
+        packets
+    }
+
+    /// Send a packet and collect the answer.
+    fn exchange(&self, packet: Packet) -> Vec<Packet> {
+        assert!(self.send(packet));
+        self.receive()
+    }
+
+    fn request(&self, cid: u32, cmd: u8, data: &[u8]) {
+        assert!(self.send(init_packet(cid, cmd, data.len(), data)));
+        for (seq, chunk) in data[data.len().min(INIT_DATA_SIZE)..]
+            .chunks(CONT_DATA_SIZE)
+            .enumerate()
+        {
+            assert!(self.send(cont_packet(cid, seq as u8, chunk)));
+        }
+    }
+
+    /// Allocate a channel on the broadcast channel.
+    fn allocate(&self) -> u32 {
+        assert!(self.send(init_packet(BROADCAST_CID, CTAPHID_INIT, 8, &NONCE)));
+        let packets = self.receive();
+        assert_eq!(packets.len(), 1);
+        let response = init_response(&packets[0]);
+        assert_eq!(response[..8], NONCE);
+        u32::from_be_bytes([response[8], response[9], response[10], response[11]])
+    }
+
+    fn events(&self) -> Vec<Event> {
+        self.app.events.take()
+    }
+}
+
+fn init_packet(cid: u32, cmd: u8, len: usize, data: &[u8]) -> Packet {
+    let mut packet = [0; PACKET_SIZE];
+    write_init_header(&mut packet, cid, cmd, len);
+    let data = &data[..data.len().min(INIT_DATA_SIZE)];
+    packet[7..7 + data.len()].copy_from_slice(data);
+    packet
+}
+
+fn cont_packet(cid: u32, seq: u8, data: &[u8]) -> Packet {
+    let mut packet = [0; PACKET_SIZE];
+    packet[0..4].copy_from_slice(&cid.to_be_bytes());
+    packet[4] = seq;
+    packet[5..5 + data.len()].copy_from_slice(data);
+    packet
+}
+
+fn cid(packet: &Packet) -> u32 {
+    u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]])
+}
+
+/// The payload of the response to `CTAPHID_INIT`.
+fn init_response(packet: &Packet) -> &[u8] {
+    assert_eq!(packet[4], CTAPHID_INIT | INIT_PACKET);
+    assert_eq!(packet[5..7], [0, INIT_RESPONSE_SIZE as u8]);
+    &packet[7..7 + INIT_RESPONSE_SIZE]
+}
+
+fn error(cid: u32, error: CtapHidError) -> Packet {
+    init_packet(cid, CTAPHID_ERROR, 1, &[error as u8])
+}
+
+fn assert_errors(packets: &[Packet], errors: &[(u32, CtapHidError)]) {
+    let expected: Vec<Packet> = errors.iter().map(|&(c, e)| error(c, e)).collect();
+    assert!(packets == &expected[..], "unexpected packets");
+}
+
+#[test]
+fn init_allocates_channels() {
+    let device = Device::new();
+    let first = device.allocate();
+    let second = device.allocate();
+    assert!(first != 0 && first != BROADCAST_CID);
+    assert!(second != first);
+
+    // The response goes to the broadcast channel and describes the device.
+    device.send(init_packet(BROADCAST_CID, CTAPHID_INIT, 8, &NONCE));
+    let packets = device.receive();
+    assert_eq!(cid(&packets[0]), BROADCAST_CID);
+    let response = init_response(&packets[0]);
+    assert_eq!(response[12], PROTOCOL_VERSION);
+    assert_eq!(response[13..16], DEVICE_VERSION);
+    assert_eq!(response[16], CAPABILITY_WINK | CAPABILITY_CBOR);
+
+    // Initializing an allocated channel keeps it.
+    device.send(init_packet(first, CTAPHID_INIT, 8, &NONCE));
+    let packets = device.receive();
+    assert_eq!(cid(&packets[0]), first);
+    assert_eq!(init_response(&packets[0])[8..12], first.to_be_bytes());
+
+    assert_errors(
+        &device.exchange(init_packet(0x1234, CTAPHID_INIT, 8, &NONCE)),
+        &[(0x1234, CtapHidError::InvalidChannel)],
+    );
+    assert_errors(
+        &device.exchange(init_packet(first, CTAPHID_INIT, 7, &NONCE)),
+        &[(first, CtapHidError::InvalidLen)],
+    );
+    assert_errors(
+        &device.exchange(init_packet(0x1234, CTAPHID_PING, 1, &[0])),
+        &[(0x1234, CtapHidError::InvalidChannel)],
+    );
+}
+
+#[test]
+fn oldest_channel_is_replaced() {
+    let device = Device::new();
+    let channels: Vec<u32> = (0..MAX_CHANNELS + 1).map(|_| device.allocate()).collect();
+    device.request(channels[0], CTAPHID_PING, b"ping");
+    assert_errors(
+        &device.receive(),
+        &[(channels[0], CtapHidError::InvalidChannel)],
+    );
+    device.request(channels[1], CTAPHID_PING, b"ping");
+    assert_eq!(
+        device.receive(),
+        [init_packet(channels[1], CTAPHID_PING, 4, b"ping")]
+    );
+}
+
+#[test]
+fn requests_and_responses_span_packets() {
+    let device = Device::new();
+    let cid = device.allocate();
+    let request: Vec<u8> = (0..100).collect();
+    device.request(cid, CTAPHID_CBOR, &request);
+    assert!(device.receive().is_empty());
+    assert_eq!(device.events(), [Event::Received(100, CTAPHID_CBOR)]);
+    assert_eq!(device.app.request.borrow()[..100], request[..]);
+
+    let response: Vec<u8> = (0..150).map(|i| i as u8 ^ 0xa5).collect();
+    device.app.response.replace(response.clone());
+    assert_eq!(device.transport.send_response(150), Ok(()));
+    assert_eq!(
+        device.receive(),
+        [
+            init_packet(cid, CTAPHID_CBOR, 150, &response),
+            cont_packet(cid, 0, &response[57..116]),
+            cont_packet(cid, 1, &response[116..]),
+        ]
+    );
+    assert_eq!(device.events(), [Event::Sent(150)]);
+    assert_eq!(device.transport.send_response(1), Err(ErrorCode::INVAL));
+
+    // Pings are echoed without the app.
+    device.app.receiving.set(true);
+    device.request(cid, CTAPHID_PING, &request);
+    assert_eq!(
+        device.receive(),
+        [
+            init_packet(cid, CTAPHID_PING, 100, &request),
+            cont_packet(cid, 0, &request[57..]),
+        ]
+    );
+    assert!(device.events().is_empty());
+}
+
+#[test]
+fn requests_must_fit() {
+    let device = Device::new();
+    let cid = device.allocate();
+    assert_errors(
+        &device.exchange(init_packet(cid, CTAPHID_CBOR, MAX_MESSAGE_SIZE + 1, &[])),
+        &[(cid, CtapHidError::InvalidLen)],
+    );
+    assert_errors(
+        &device.exchange(init_packet(cid, CTAPHID_CBOR, REQUEST_CAPACITY + 1, &[])),
+        &[(cid, CtapHidError::InvalidLen)],
+    );
+    assert_errors(
+        &device.exchange(init_packet(cid, 0x20, 1, &[0])),
+        &[(cid, CtapHidError::InvalidCmd)],
+    );
+    device.app.receiving.set(false);
+    assert_errors(
+        &device.exchange(init_packet(cid, CTAPHID_CBOR, 1, &[0])),
+        &[(cid, CtapHidError::ChannelBusy)],
+    );
+}
+
+#[test]
+fn continuation_sequence_errors() {
+    let device = Device::new();
+    let cid = device.allocate();
+    let other = device.allocate();
+    let request = [7; 200];
+    device.send(init_packet(cid, CTAPHID_CBOR, 200, &request));
+    device.send(cont_packet(cid, 0, &request[57..116]));
+    // Continuation packets of other channels are ignored.
+    device.send(cont_packet(other, 1, &request[116..175]));
+    device.send(cont_packet(cid, 2, &request[116..175]));
+    assert_errors(&device.receive(), &[(cid, CtapHidError::InvalidSeq)]);
+
+    // The request was dropped, so its remaining packets are ignored.
+    device.send(cont_packet(cid, 1, &request[116..175]));
+    assert!(device.receive().is_empty());
+
+    // A new request before the last one was complete
+    device.send(init_packet(cid, CTAPHID_CBOR, 200, &request));
+    device.send(init_packet(cid, CTAPHID_CBOR, 200, &request));
+    assert_errors(&device.receive(), &[(cid, CtapHidError::InvalidSeq)]);
+
+    device.request(cid, CTAPHID_CBOR, &request);
+    assert_eq!(device.events(), [Event::Received(200, CTAPHID_CBOR)]);
+}
+
+#[test]
+fn other_channels_are_busy() {
+    let device = Device::new();
+    let cid = device.allocate();
+    let other = device.allocate();
+    device.request(cid, CTAPHID_CBOR, b"request");
+    assert_eq!(device.events(), [Event::Received(7, CTAPHID_CBOR)]);
+
+    device.request(other, CTAPHID_PING, b"ping");
+    device.request(cid, CTAPHID_PING, b"ping");
+    assert_errors(
+        &device.receive(),
+        &[
+            (other, CtapHidError::ChannelBusy),
+            (cid, CtapHidError::ChannelBusy),
+        ],
+    );
+
+    device.app.response.replace(b"response".to_vec());
+    device.transport.send_response(8).unwrap();
+    device.receive();
+    assert_eq!(device.events(), [Event::Sent(8)]);
+    device.app.receiving.set(true);
+    device.request(other, CTAPHID_PING, b"ping");
+    assert_eq!(
+        device.receive(),
+        [init_packet(other, CTAPHID_PING, 4, b"ping")]
+    );
+}
+
+#[test]
+fn lock_holds_off_other_channels() {
+    let device = Device::new();
+    let cid = device.allocate();
+    let other = device.allocate();
+    device.send(init_packet(cid, CTAPHID_LOCK, 1, &[MAX_LOCK_SECONDS + 1]));
+    device.send(init_packet(cid, CTAPHID_LOCK, 2, &[1, 0]));
+    assert_errors(
+        &device.receive(),
+        &[
+            (cid, CtapHidError::InvalidPar),
+            (cid, CtapHidError::InvalidLen),
+        ],
+    );
+
+    device.send(init_packet(cid, CTAPHID_LOCK, 1, &[1]));
+    assert_eq!(device.receive(), [init_packet(cid, CTAPHID_LOCK, 0, &[])]);
+    assert_errors(
+        &device.exchange(init_packet(other, CTAPHID_PING, 0, &[])),
+        &[(other, CtapHidError::ChannelBusy)],
+    );
+    assert_errors(
+        &device.exchange(init_packet(BROADCAST_CID, CTAPHID_INIT, 8, &NONCE)),
+        &[(BROADCAST_CID, CtapHidError::ChannelBusy)],
+    );
+    // Cancelling is never answered.
+    assert!(device
+        .exchange(init_packet(other, CTAPHID_CANCEL, 0, &[]))
+        .is_empty());
+    device.request(cid, CTAPHID_PING, b"ping");
+    assert_eq!(device.receive().len(), 1);
+
+    // The lock expires.
+    device.alarm.advance(1000);
+    device.request(other, CTAPHID_PING, b"ping");
+    assert_eq!(
+        device.receive(),
+        [init_packet(other, CTAPHID_PING, 4, b"ping")]
+    );
+
+    // Or is released by its channel.
+    device.exchange(init_packet(cid, CTAPHID_LOCK, 1, &[MAX_LOCK_SECONDS]));
+    device.exchange(init_packet(cid, CTAPHID_LOCK, 1, &[0]));
+    assert_eq!(
+        device.exchange(init_packet(other, CTAPHID_PING, 0, &[])),
+        [init_packet(other, CTAPHID_PING, 0, &[])]
+    );
+}
+
+#[test]
+fn cancel() {
+    let device = Device::new();
+    let cid = device.allocate();
+    let other = device.allocate();
+    device.request(cid, CTAPHID_CBOR, b"request");
+    device.events();
+
+    // Only the channel of the request can cancel it.
+    device.send(init_packet(other, CTAPHID_CANCEL, 0, &[]));
+    assert!(device.events().is_empty());
+    device.send(init_packet(cid, CTAPHID_CANCEL, 0, &[]));
+    assert_eq!(device.events(), [Event::Cancelled]);
+    assert!(device.receive().is_empty());
+
+    // The app answers the cancelled request.
+    device.app.response.replace(vec![0x2d]);
+    device.transport.send_response(1).unwrap();
+    assert_eq!(
+        device.receive(),
+        [init_packet(cid, CTAPHID_CBOR, 1, &[0x2d])]
+    );
+    assert_eq!(device.events(), [Event::Sent(1)]);
+
+    // A request being received is dropped without an answer.
+    device.app.receiving.set(true);
+    device.send(init_packet(cid, CTAPHID_CBOR, 100, &[0; 57]));
+    device.send(init_packet(cid, CTAPHID_CANCEL, 0, &[]));
+    device.send(cont_packet(cid, 0, &[0; 43]));
+    assert!(device.receive().is_empty());
+    assert!(device.events().is_empty());
+}
+
+#[test]
+fn resynchronizing_aborts_the_transaction() {
+    let device = Device::new();
+    let cid = device.allocate();
+    device.request(cid, CTAPHID_CBOR, b"request");
+    device.events();
+    device.send(init_packet(cid, CTAPHID_INIT, 8, &NONCE));
+    assert_eq!(device.receive().len(), 1);
+    assert_eq!(device.events(), [Event::Aborted]);
+    assert_eq!(device.transport.send_response(1), Err(ErrorCode::INVAL));
+}
+
+#[test]
+fn keepalive_and_timeout() {
+    let device = Device::new();
+    let cid = device.allocate();
+    device.request(cid, CTAPHID_CBOR, b"request");
+    device.alarm.advance(TICK_MS);
+    assert_eq!(
+        device.receive(),
+        [init_packet(cid, CTAPHID_KEEPALIVE, 1, &[1])]
+    );
+    device.transport.set_keepalive_status(2).unwrap();
+    assert_eq!(
+        device.transport.set_keepalive_status(3),
+        Err(ErrorCode::INVAL)
+    );
+    device.alarm.advance(TICK_MS);
+    assert_eq!(
+        device.receive(),
+        [init_packet(cid, CTAPHID_KEEPALIVE, 1, &[2])]
+    );
+
+    device.app.response.replace(vec![0]);
+    device.transport.send_response(1).unwrap();
+    device.receive();
+    device.app.receiving.set(true);
+    device.send(init_packet(cid, CTAPHID_CBOR, 100, &[0; 57]));
+    device
+        .alarm
+        .advance(TICK_MS * (MESSAGE_TIMEOUT_TICKS as u32 - 1));
+    assert!(device.receive().is_empty());
+    device.alarm.advance(TICK_MS);
+    assert_errors(&device.receive(), &[(cid, CtapHidError::MsgTimeout)]);
+    assert!(!device.alarm.is_armed());
+}
+
+#[test]
+fn replies_wait_for_the_send_buffer() {
+    let device = Device::new();
+
+    // The first response occupies the send buffer, so the second one waits,
+    // and the host is held off until both went out.
+    assert!(device.send(init_packet(BROADCAST_CID, CTAPHID_INIT, 8, &NONCE)));
+    assert!(device.send(init_packet(BROADCAST_CID, CTAPHID_INIT, 8, &[9; 8])));
+    assert!(!device.send(init_packet(0x1234, CTAPHID_PING, 0, &[])));
+    let packets = device.receive();
+    assert_eq!(packets.len(), 2);
+    assert_eq!(init_response(&packets[0])[..8], NONCE);
+    assert_eq!(init_response(&packets[1])[..8], [9; 8]);
+    let cid = u32::from_be_bytes(init_response(&packets[1])[8..12].try_into().unwrap());
+
+    // A timeout does not replace the error waiting for the send buffer.
+    let other = device.allocate();
+    device.send(init_packet(cid, CTAPHID_CBOR, 100, &[0; 57]));
+    assert!(device.send(init_packet(other, CTAPHID_INIT, 8, &NONCE)));
+    assert!(device.send(init_packet(other, CTAPHID_PING, 0, &[])));
+    assert!(!device.send(init_packet(other, CTAPHID_PING, 0, &[])));
+    device.alarm.advance(TICK_MS * MESSAGE_TIMEOUT_TICKS as u32);
+    let packets = device.receive();
+    assert_eq!(packets.len(), 3);
+    assert_eq!(init_response(&packets[0])[8..12], other.to_be_bytes());
+    assert_errors(
+        &packets[1..],
+        &[
+            (other, CtapHidError::ChannelBusy),
+            (cid, CtapHidError::MsgTimeout),
+        ],
+    );
+    assert!(device.send(init_packet(other, CTAPHID_PING, 0, &[])));
+}
+
+#[test]
+fn stopping_the_app_fails_the_request() {
+    let device = Device::new();
+    let cid = device.allocate();
+    device.send(init_packet(cid, CTAPHID_CBOR, 100, &[0; 57]));
+    device.transport.stop_receiving();
+    assert_errors(&device.receive(), &[(cid, CtapHidError::Other)]);
+    device.send(cont_packet(cid, 0, &[0; 43]));
+    assert!(device.receive().is_empty());
+}
@@ -26,11 +26,14 @@ pub enum NUM {
     I2cMaster             = 0x20003,
     UsbUser               = 0x20005,
     I2cMasterSlave        = 0x20006,
+    UsbHid                = 0x20007,
 
     // Radio
     BleAdvertising        = 0x30000,
     Ieee802154            = 0x30001,
     Udp                   = 0x30002,
+    Coap                  = 0x30003,
+    BleGatt               = 0x30004,
 
     // Cryptography
     Rng                   = 0x40001,
@@ -42,6 +45,8 @@ pub enum NUM {
     AppFlash              = 0x50000,
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
+    Fat                   = 0x50003,
+    Log                   = 0x50004,
 
     // Sensors
     Temperature           = 0x60000,
@@ -0,0 +1,87 @@
+//! Emulated alarm shared by the tests of timed capsules.
+//!
+//! Time only passes when the test advances it, and the alarm fires from
+//! `advance()` once time reaches it. The alarm counts milliseconds.
+
+use core::cell::Cell;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm, AlarmClient, Ticks, Ticks32, Time};
+use kernel::ErrorCode;
+
+pub struct EmulatedAlarm<'a> {
+    now: Cell<u32>,
+    /// The reference and interval of the armed alarm
+    armed: Cell<Option<(u32, u32)>>,
+    client: OptionalCell<&'a dyn AlarmClient>,
+}
+
+impl<'a> EmulatedAlarm<'a> {
+    pub fn new() -> EmulatedAlarm<'a> {
+        EmulatedAlarm {
+            now: Cell::new(0),
+            armed: Cell::new(None),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Milliseconds until the alarm fires, if it is armed.
+    pub fn remaining(&self) -> Option<u32> {
+        self.armed
+            .get()
+            .map(|(reference, dt)| dt.saturating_sub(self.now.get().wrapping_sub(reference)))
+    }
+
+    /// Let `ms` milliseconds pass, firing the alarm every time it expires.
+    pub fn advance(&self, ms: u32) {
+        let end = self.now.get().wrapping_add(ms);
+        while let Some(remaining) = self.remaining() {
+            if remaining > end.wrapping_sub(self.now.get()) {
+                break;
+            }
+            self.now.set(self.now.get().wrapping_add(remaining));
+            self.armed.set(None);
+            self.client.map(|client| client.alarm());
+        }
+        self.now.set(end);
+    }
+}
+
+impl Time for EmulatedAlarm<'_> {
+    type Frequency = time::Freq1KHz;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        self.now.get().into()
+    }
+}
+
+impl<'a> Alarm<'a> for EmulatedAlarm<'a> {
+    fn set_alarm_client(&'a self, client: &'a dyn AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+        self.armed.set(Some((reference.into_u32(), dt.into_u32())));
+    }
+
+    fn get_alarm(&self) -> Ticks32 {
+        self.armed
+            .get()
+            .map_or(0, |(reference, dt)| reference.wrapping_add(dt))
+            .into()
+    }
+
+    fn disarm(&self) -> Result<(), ErrorCode> {
+        self.armed.set(None);
+        Ok(())
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get().is_some()
+    }
+
+    fn minimum_dt(&self) -> Ticks32 {
+        1.into()
+    }
+}
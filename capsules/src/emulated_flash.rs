@@ -0,0 +1,277 @@
+//! Emulated flash shared by the tests of the layers built on flash.
+//!
+//! Pages live in a byte image the test can inspect, corrupt and copy to
+//! "reboot" the device. The flash implements both `Flash` and `FlashRange`,
+//! with pages as erase units. Operations complete when the test calls
+//! `run()`, and a write can lose power after a number of bytes, in which case
+//! it never completes.
+
+extern crate std;
+
+use std::cell::{Cell, RefCell};
+use std::vec;
+use std::vec::Vec;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::flash::{self, FlashRange, RangeClient};
+use kernel::ErrorCode;
+
+use crate::test_support::leak;
+
+pub struct Page<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> Default for Page<N> {
+    fn default() -> Page<N> {
+        Page([0; N])
+    }
+}
+
+impl<const N: usize> AsMut<[u8]> for Page<N> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// How a page write changes the bytes already in flash. Range writes always
+/// clear bits, as `FlashRange` requires.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Programming {
+    /// The page is erased first, then programmed.
+    Replace,
+    /// Programming can only clear bits, like NOR flash.
+    ClearBits,
+}
+
+enum Operation<const N: usize> {
+    Read(&'static mut Page<N>),
+    Write(&'static mut Page<N>),
+    Erase,
+    RangeRead(&'static mut [u8], usize),
+    RangeWrite(&'static mut [u8], usize),
+    RangeErase,
+}
+
+/// Writes through `FlashRange` must be aligned to this.
+pub const WRITE_SIZE: usize = 4;
+
+pub struct EmulatedFlash<const N: usize> {
+    pub image: RefCell<Vec<u8>>,
+    /// Number of erases of every page of `image`
+    pub erases: RefCell<Vec<u32>>,
+    /// Page number of the start of `image`
+    first_page: usize,
+    programming: Programming,
+    pub client: OptionalCell<&'static dyn flash::Client<EmulatedFlash<N>>>,
+    range_client: OptionalCell<&'static dyn RangeClient>,
+    pending: RefCell<Option<Operation<N>>>,
+    /// Bytes of the next page write that reach the flash before power fails
+    pub tear_after: Cell<Option<usize>>,
+}
+
+impl<const N: usize> EmulatedFlash<N> {
+    pub fn new(image: Vec<u8>, first_page: usize, programming: Programming) -> EmulatedFlash<N> {
+        let pages = image.len() / N;
+        EmulatedFlash {
+            image: RefCell::new(image),
+            erases: RefCell::new(vec![0; pages]),
+            first_page,
+            programming,
+            client: OptionalCell::empty(),
+            range_client: OptionalCell::empty(),
+            pending: RefCell::new(None),
+            tear_after: Cell::new(None),
+        }
+    }
+
+    /// Leak a flash for the `'static` lifetime the layers above require.
+    pub fn leak(image: Vec<u8>, first_page: usize, programming: Programming) -> &'static Self {
+        leak(EmulatedFlash::new(image, first_page, programming))
+    }
+
+    /// A flash with the contents and erase counts of this one, as after the
+    /// device rebooted. Pending operations are lost.
+    pub fn power_cycle(&self) -> &'static Self {
+        let flash = EmulatedFlash::leak(
+            self.image.borrow().clone(),
+            self.first_page,
+            self.programming,
+        );
+        flash.erases.replace(self.erases.borrow().clone());
+        flash
+    }
+
+    /// The range of `image` that holds a page.
+    pub fn page(&self, page_number: usize) -> core::ops::Range<usize> {
+        let offset = (page_number - self.first_page) * N;
+        offset..offset + N
+    }
+
+    /// The range of `image` at a flash address.
+    fn bytes(&self, address: usize, length: usize) -> Option<core::ops::Range<usize>> {
+        let offset = address.checked_sub(self.first_page * N)?;
+        if offset + length <= self.image.borrow().len() {
+            Some(offset..offset + length)
+        } else {
+            None
+        }
+    }
+
+    /// Program bytes of `image`, which can only clear bits. Returns whether
+    /// all bytes were programmed before power failed.
+    fn program(&self, range: core::ops::Range<usize>, data: &[u8]) -> bool {
+        let written = self.tear_after.take().unwrap_or(range.len());
+        for (byte, data) in self.image.borrow_mut()[range.clone()]
+            .iter_mut()
+            .zip(data.iter())
+            .take(written)
+        {
+            *byte &= data;
+        }
+        written >= range.len()
+    }
+
+    fn erase_bytes(&self, range: core::ops::Range<usize>) {
+        self.erases.borrow_mut()[range.start / N] += 1;
+        self.image.borrow_mut()[range]
+            .iter_mut()
+            .for_each(|byte| *byte = 0xFF);
+    }
+
+    /// Complete pending operations until none are left. Returns whether any
+    /// operation completed.
+    pub fn run(&self) -> bool {
+        let mut completed = false;
+        loop {
+            let operation = self.pending.borrow_mut().take();
+            match operation {
+                Some(Operation::Read(buf)) => self
+                    .client
+                    .map(move |client| client.read_complete(buf, flash::Error::CommandComplete)),
+                Some(Operation::Write(buf)) => self
+                    .client
+                    .map(move |client| client.write_complete(buf, flash::Error::CommandComplete)),
+                Some(Operation::Erase) => self
+                    .client
+                    .map(|client| client.erase_complete(flash::Error::CommandComplete)),
+                Some(Operation::RangeRead(buf, length)) => self
+                    .range_client
+                    .map(move |client| client.read_done(buf, length, Ok(()))),
+                Some(Operation::RangeWrite(buf, length)) => self
+                    .range_client
+                    .map(move |client| client.write_done(buf, length, Ok(()))),
+                Some(Operation::RangeErase) => {
+                    self.range_client.map(|client| client.erase_done(Ok(())))
+                }
+                None => break,
+            };
+            completed = true;
+        }
+        completed
+    }
+}
+
+impl<const N: usize> flash::Flash for EmulatedFlash<N> {
+    type Page = Page<N>;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Page<N>,
+    ) -> Result<(), (ErrorCode, &'static mut Page<N>)> {
+        buf.0
+            .copy_from_slice(&self.image.borrow()[self.page(page_number)]);
+        self.pending.replace(Some(Operation::Read(buf)));
+        Ok(())
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Page<N>,
+    ) -> Result<(), (ErrorCode, &'static mut Page<N>)> {
+        let page = self.page(page_number);
+        if self.programming == Programming::Replace {
+            self.image.borrow_mut()[page.clone()]
+                .iter_mut()
+                .for_each(|byte| *byte = 0xFF);
+        }
+        if self.program(page, &buf.0) {
+            self.pending.replace(Some(Operation::Write(buf)));
+        }
+        Ok(())
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        self.erase_bytes(self.page(page_number));
+        self.pending.replace(Some(Operation::Erase));
+        Ok(())
+    }
+}
+
+impl<const N: usize> FlashRange<'static> for EmulatedFlash<N> {
+    fn set_range_client(&self, client: &'static dyn RangeClient) {
+        self.range_client.set(client);
+    }
+
+    fn geometry(&self) -> flash::Geometry {
+        flash::Geometry {
+            size: (self.first_page * N) + self.image.borrow().len(),
+            write_size: WRITE_SIZE,
+            min_erase_size: N,
+            max_erase_size: N,
+        }
+    }
+
+    fn erase_unit(&self, address: usize) -> Option<(usize, usize)> {
+        self.bytes(address, 1).map(|_| (address - address % N, N))
+    }
+
+    fn read(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        match self.bytes(address, length) {
+            Some(range) if length <= buffer.len() => {
+                buffer[..length].copy_from_slice(&self.image.borrow()[range]);
+                self.pending
+                    .replace(Some(Operation::RangeRead(buffer, length)));
+                Ok(())
+            }
+            _ => Err((ErrorCode::INVAL, buffer)),
+        }
+    }
+
+    fn write(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if address % WRITE_SIZE != 0 || length % WRITE_SIZE != 0 || length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        match self.bytes(address, length) {
+            Some(range) => {
+                if self.program(range, &buffer[..length]) {
+                    self.pending
+                        .replace(Some(Operation::RangeWrite(buffer, length)));
+                }
+                Ok(())
+            }
+            None => Err((ErrorCode::INVAL, buffer)),
+        }
+    }
+
+    fn erase(&self, address: usize) -> Result<(), ErrorCode> {
+        match self.bytes(address, N) {
+            Some(range) if address % N == 0 => {
+                self.erase_bytes(range);
+                self.pending.replace(Some(Operation::RangeErase));
+                Ok(())
+            }
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Emulated USB controller shared by the tests of USB classes.
+//!
+//! The controller records the endpoints its client enables, and the test
+//! plays the host: `control_in()` runs a control read on endpoint 0 through
+//! the client, the way a controller driver would.
+
+extern crate std;
+
+use core::cell::Cell;
+use std::vec::Vec;
+
+use kernel::common::cells::{OptionalCell, VolatileCell};
+use kernel::hil::usb::{self, CtrlInResult, CtrlSetupResult, TransferType};
+use kernel::ErrorCode;
+
+pub struct EmulatedUsb<'a> {
+    client: OptionalCell<&'a dyn usb::Client<'a>>,
+    ctrl_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    /// Bitmasks of the IN and OUT endpoints the client enabled
+    pub in_endpoints: Cell<u16>,
+    pub out_endpoints: Cell<u16>,
+}
+
+impl<'a> EmulatedUsb<'a> {
+    pub fn new() -> EmulatedUsb<'a> {
+        EmulatedUsb {
+            client: OptionalCell::empty(),
+            ctrl_buffer: OptionalCell::empty(),
+            in_endpoints: Cell::new(0),
+            out_endpoints: Cell::new(0),
+        }
+    }
+
+    /// Runs a control transfer with an IN data stage. Returns the data, or
+    /// `None` if the client stalled the transfer.
+    pub fn control_in(&self, setup: [u8; 8]) -> Option<Vec<u8>> {
+        let client = self.client.extract()?;
+        let buf = self.ctrl_buffer.extract()?;
+        for (b, s) in buf.iter().zip(setup.iter()) {
+            b.set(*s);
+        }
+        if !matches!(client.ctrl_setup(0), CtrlSetupResult::Ok) {
+            return None;
+        }
+        let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+        let mut data = Vec::new();
+        loop {
+            match client.ctrl_in(0) {
+                CtrlInResult::Packet(n, last) => {
+                    data.extend(buf.iter().take(n).map(|b| b.get()));
+                    if last || n < buf.len() || data.len() >= length {
+                        break;
+                    }
+                }
+                CtrlInResult::Delay | CtrlInResult::Error => return None,
+            }
+        }
+        client.ctrl_status(0);
+        client.ctrl_status_complete(0);
+        Some(data)
+    }
+}
+
+impl<'a> usb::UsbController<'a> for EmulatedUsb<'a> {
+    fn set_client(&self, client: &'a dyn usb::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn endpoint_set_ctrl_buffer(&self, buf: &'a [VolatileCell<u8>]) {
+        self.ctrl_buffer.set(buf);
+    }
+
+    fn endpoint_set_in_buffer(&self, _endpoint: usize, _buf: &'a [VolatileCell<u8>]) {}
+
+    fn endpoint_set_out_buffer(&self, _endpoint: usize, _buf: &'a [VolatileCell<u8>]) {}
+
+    fn enable_as_device(&self, _speed: usb::DeviceSpeed) {}
+
+    fn attach(&self) {}
+
+    fn detach(&self) {}
+
+    fn set_address(&self, _addr: u16) {}
+
+    fn enable_address(&self) {}
+
+    fn endpoint_in_enable(&self, _transfer_type: TransferType, endpoint: usize) {
+        self.in_endpoints
+            .set(self.in_endpoints.get() | 1 << endpoint);
+    }
+
+    fn endpoint_out_enable(&self, _transfer_type: TransferType, endpoint: usize) {
+        self.out_endpoints
+            .set(self.out_endpoints.get() | 1 << endpoint);
+    }
+
+    fn endpoint_in_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoint_in_enable(transfer_type, endpoint);
+        self.endpoint_out_enable(transfer_type, endpoint);
+    }
+
+    fn endpoint_resume_in(&self, _endpoint: usize) {}
+
+    fn endpoint_resume_out(&self, _endpoint: usize) {}
+
+    fn remote_wakeup(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
@@ -0,0 +1,495 @@
+//! Transparent encryption of flash pages at rest.
+//!
+//! This sits between a flash driver and the capsules storing data on it, such
+//! as `NonvolatileToPages` or TicKV, and encrypts and authenticates every page
+//! with AES-CCM under a device-unique key. The end of each physical page holds
+//! the CCM tag, the generation of the page and the random part of the nonce,
+//! so the pages it provides are `TRAILER_LEN` bytes shorter than the pages of
+//! the underlying flash. It uses whole pages of `Flash` on both sides, since
+//! every page is encrypted and authenticated as a unit.
+//!
+//! ```plain
+//!             hil::flash::Flash
+//!                ┌─────────────┐
+//!                │             │
+//!                │ This module │ ── hil::symmetric_encryption::AES128CCM
+//!                │             │ ── hil::rng::Rng
+//!                └─────────────┘
+//!             hil::flash::Flash
+//! ```
+//!
+//! Physical page layout:
+//!
+//! ```plain
+//! +----------------+---------------+----------------------+-----------------+
+//! | encrypted data | tag (8 bytes) | generation (4 bytes) | nonce (4 bytes) |
+//! +----------------+---------------+----------------------+-----------------+
+//! ```
+//!
+//! The CCM nonce combines the page number, the generation of the page and 4
+//! random bytes drawn for every write, so that a page copied to a different
+//! address, or carrying another generation than the one it was written with,
+//! fails to authenticate.
+//!
+//! The generation of a page counts its writes. The board provides a table
+//! with the current generation of every page, which this layer updates as
+//! pages are written and erased, and checks pages against when they are read:
+//! an older copy of a page written back to the same address is reported like
+//! a page whose tag does not match, and so is a page that should hold data
+//! but is erased, or that holds data but should be erased. Either way,
+//! reading it reports `Error::FlashError` and hands back a page filled with
+//! 0xFF. A generation of 0, or with `ERASED` set, marks an erased page.
+//!
+//! This only detects rollback as well as the table is protected: the board
+//! must keep it where whoever can write the flash can not roll it back, e.g.
+//! restore it at boot from the internal flash when the pages are on an
+//! external flash chip, and save it after the pages changed. With a table
+//! that lives in RAM only, rollback is detected until the next reboot, and
+//! after a reboot every page that held data is reported as corrupted.
+//!
+//! Users must read through `read_page`, so this does not work below capsules
+//! that read flash directly from memory, like `capsules::log`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! let encrypted_flash = static_init!(
+//!     capsules::encrypted_flash::EncryptedFlash<'static, Mx25r6435f, AESCCM, Rng>,
+//!     capsules::encrypted_flash::EncryptedFlash::new(
+//!         mx25r6435f,
+//!         ccm,
+//!         rng,
+//!         &DEVICE_KEY,
+//!         &GENERATIONS,
+//!         static_init!(Mx25r6435fSector, Mx25r6435fSector::default()),
+//!         static_init!(Mx25r6435fSector, Mx25r6435fSector::default()),
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(mx25r6435f, encrypted_flash);
+//! ccm.set_client(encrypted_flash);
+//! rng.set_client(encrypted_flash);
+//! ```
+//!
+//! The CCM implementation must be able to process a whole page; for
+//! `VirtualAES128CCM` its crypt buffer needs three blocks more than a page.
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::symmetric_encryption::{AES128CCM, AES128_KEY_SIZE, CCM_NONCE_LENGTH};
+use kernel::ErrorCode;
+
+/// Length of the CCM tag stored with every page.
+pub const TAG_LEN: usize = 8;
+/// Length of the generation stored with every page.
+pub const GENERATION_LEN: usize = 4;
+/// Length of the random part of the nonce stored with every page.
+pub const NONCE_LEN: usize = 4;
+/// Bytes at the end of every physical page used for the tag, generation and
+/// nonce.
+pub const TRAILER_LEN: usize = TAG_LEN + GENERATION_LEN + NONCE_LEN;
+/// Set in the generation of a page that was erased since it was last
+/// written.
+pub const ERASED: u32 = 1 << 31;
+
+/// A page of the encrypted flash, which hides the trailer of the underlying
+/// page.
+pub struct EncryptedPage<P: AsMut<[u8]> + Default>(P);
+
+impl<P: AsMut<[u8]> + Default> Default for EncryptedPage<P> {
+    fn default() -> Self {
+        EncryptedPage(P::default())
+    }
+}
+
+impl<P: AsMut<[u8]> + Default> AsMut<[u8]> for EncryptedPage<P> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        let page = self.0.as_mut();
+        let len = page.len().saturating_sub(TRAILER_LEN);
+        &mut page[..len]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    Read,
+    Decrypt,
+    Nonce,
+    Encrypt,
+    Write,
+    Erase,
+}
+
+pub struct EncryptedFlash<
+    'a,
+    F: hil::flash::Flash + 'static,
+    A: AES128CCM<'a>,
+    R: hil::rng::Rng<'a>,
+> {
+    flash: &'a F,
+    ccm: &'a A,
+    rng: &'a R,
+    key: [u8; AES128_KEY_SIZE],
+    /// The generation of every page.
+    generations: &'a [Cell<u32>],
+    client: OptionalCell<&'a dyn hil::flash::Client<EncryptedFlash<'a, F, A, R>>>,
+    state: Cell<State>,
+    page_number: Cell<usize>,
+    /// Generation and random nonce of the page being read or written.
+    generation: Cell<u32>,
+    nonce: Cell<[u8; NONCE_LEN]>,
+    /// Page of the underlying flash.
+    page: TakeCell<'static, F::Page>,
+    /// Buffer for CCM, the length of a page of the underlying flash.
+    crypt_buffer: TakeCell<'static, [u8]>,
+    /// Page of the client while it is read or written.
+    client_page: TakeCell<'static, EncryptedPage<F::Page>>,
+}
+
+impl<'a, F: hil::flash::Flash, A: AES128CCM<'a>, R: hil::rng::Rng<'a>> EncryptedFlash<'a, F, A, R> {
+    /// `key` must be unique to the device, and kept secret from anyone who can
+    /// read the flash. `generations` holds the generation of every page that
+    /// can be used, see the module documentation.
+    pub fn new(
+        flash: &'a F,
+        ccm: &'a A,
+        rng: &'a R,
+        key: &[u8; AES128_KEY_SIZE],
+        generations: &'a [Cell<u32>],
+        page: &'static mut F::Page,
+        crypt_page: &'static mut F::Page,
+    ) -> EncryptedFlash<'a, F, A, R> {
+        EncryptedFlash {
+            flash,
+            ccm,
+            rng,
+            key: *key,
+            generations,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            page_number: Cell::new(0),
+            generation: Cell::new(0),
+            nonce: Cell::new([0; NONCE_LEN]),
+            page: TakeCell::new(page),
+            crypt_buffer: TakeCell::new(crypt_page.as_mut()),
+            client_page: TakeCell::empty(),
+        }
+    }
+
+    /// Length of the data stored in a page.
+    fn data_len(&self) -> usize {
+        self.crypt_buffer
+            .map_or(0, |buffer| buffer.len().saturating_sub(TRAILER_LEN))
+    }
+
+    /// Whether the generation of a page marks it as erased.
+    fn erased(generation: u32) -> bool {
+        generation == 0 || generation & ERASED != 0
+    }
+
+    /// Sets up the key and the nonce for the current page.
+    fn configure_ccm(&self) -> Result<(), ErrorCode> {
+        let mut nonce = [0; CCM_NONCE_LENGTH];
+        nonce[..4].copy_from_slice(&(self.page_number.get() as u32).to_le_bytes());
+        nonce[4..4 + GENERATION_LEN].copy_from_slice(&self.generation.get().to_le_bytes());
+        nonce[4 + GENERATION_LEN..4 + GENERATION_LEN + NONCE_LEN]
+            .copy_from_slice(&self.nonce.get());
+        self.ccm.set_key(&self.key)?;
+        self.ccm.set_nonce(&nonce)
+    }
+
+    /// Runs CCM over the data and tag in the crypt buffer.
+    fn crypt(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        self.configure_ccm()?;
+        let data_len = self.data_len();
+        self.crypt_buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                self.ccm
+                    .crypt(buffer, 0, 0, data_len, TAG_LEN, true, encrypting)
+                    .map_err(|(e, buffer)| {
+                        self.crypt_buffer.replace(buffer);
+                        e
+                    })
+            })
+    }
+
+    /// Hands the client's page back after a read.
+    fn read_done(&self, error: hil::flash::Error) {
+        self.state.set(State::Idle);
+        self.client_page.take().map(|page| {
+            if error != hil::flash::Error::CommandComplete {
+                page.as_mut().iter_mut().for_each(|byte| *byte = 0xFF);
+            }
+            self.client
+                .map(move |client| client.read_complete(page, error));
+        });
+    }
+
+    /// Hands the client's page back after a write.
+    fn write_done(&self, error: hil::flash::Error) {
+        self.state.set(State::Idle);
+        self.client_page.take().map(|page| {
+            self.client
+                .map(move |client| client.write_complete(page, error));
+        });
+    }
+
+    /// Encrypts the client's page once the nonce has been drawn.
+    fn encrypt(&self) {
+        self.state.set(State::Encrypt);
+        let data_len = self.data_len();
+        self.client_page.map(|page| {
+            self.crypt_buffer.map(|buffer| {
+                buffer[..data_len].copy_from_slice(page.as_mut());
+            });
+        });
+        if self.crypt(true).is_err() {
+            self.write_done(hil::flash::Error::FlashError);
+        }
+    }
+}
+
+impl<'a, F: hil::flash::Flash, A: AES128CCM<'a>, R: hil::rng::Rng<'a>> hil::flash::Flash
+    for EncryptedFlash<'a, F, A, R>
+{
+    type Page = EncryptedPage<F::Page>;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if self.state.get() != State::Idle {
+            return Err((ErrorCode::BUSY, buf));
+        }
+        if page_number >= self.generations.len() {
+            return Err((ErrorCode::INVAL, buf));
+        }
+        let page = match self.page.take() {
+            Some(page) => page,
+            None => return Err((ErrorCode::RESERVE, buf)),
+        };
+        match self.flash.read_page(page_number, page) {
+            Ok(()) => {
+                self.state.set(State::Read);
+                self.page_number.set(page_number);
+                self.client_page.replace(buf);
+                Ok(())
+            }
+            Err((e, page)) => {
+                self.page.replace(page);
+                Err((e, buf))
+            }
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if self.state.get() != State::Idle {
+            return Err((ErrorCode::BUSY, buf));
+        }
+        let generation = match self.generations.get(page_number) {
+            Some(generation) => (generation.get() & !ERASED) + 1,
+            None => return Err((ErrorCode::INVAL, buf)),
+        };
+        if generation & ERASED != 0 {
+            // Worn out long before, but never reuse a generation
+            return Err((ErrorCode::FAIL, buf));
+        }
+        if let Err(e) = self.rng.get() {
+            return Err((e, buf));
+        }
+        self.state.set(State::Nonce);
+        self.page_number.set(page_number);
+        self.generation.set(generation);
+        self.client_page.replace(buf);
+        Ok(())
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        if page_number >= self.generations.len() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.flash.erase_page(page_number)?;
+        self.state.set(State::Erase);
+        self.page_number.set(page_number);
+        Ok(())
+    }
+}
+
+impl<'a, F: hil::flash::Flash, A: AES128CCM<'a>, R: hil::rng::Rng<'a>> hil::flash::Client<F>
+    for EncryptedFlash<'a, F, A, R>
+{
+    fn read_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        if self.state.get() != State::Read {
+            self.page.replace(page);
+            return;
+        }
+        if error != hil::flash::Error::CommandComplete {
+            self.page.replace(page);
+            return self.read_done(error);
+        }
+
+        let data_len = self.data_len();
+        let contents = page.as_mut();
+        let expected = self.generations[self.page_number.get()].get();
+        if contents.iter().all(|&byte| byte == 0xFF) {
+            // Erased, nothing to decrypt, but it must be erased
+            self.page.replace(page);
+            if !Self::erased(expected) {
+                return self.read_done(hil::flash::Error::FlashError);
+            }
+            self.client_page.map(|client_page| {
+                client_page
+                    .as_mut()
+                    .iter_mut()
+                    .for_each(|byte| *byte = 0xFF)
+            });
+            return self.read_done(hil::flash::Error::CommandComplete);
+        }
+
+        let trailer = &contents[data_len + TAG_LEN..data_len + TRAILER_LEN];
+        let generation = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        if Self::erased(expected) || generation != expected {
+            // Left over from before an erase, or an older copy of the page
+            self.page.replace(page);
+            return self.read_done(hil::flash::Error::FlashError);
+        }
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&trailer[GENERATION_LEN..]);
+        self.generation.set(generation);
+        self.nonce.set(nonce);
+        self.crypt_buffer.map(|buffer| {
+            buffer[..data_len + TAG_LEN].copy_from_slice(&contents[..data_len + TAG_LEN]);
+        });
+        self.page.replace(page);
+
+        self.state.set(State::Decrypt);
+        if self.crypt(false).is_err() {
+            self.read_done(hil::flash::Error::FlashError);
+        }
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        self.page.replace(page);
+        if self.state.get() == State::Write {
+            if error == hil::flash::Error::CommandComplete {
+                self.generations[self.page_number.get()].set(self.generation.get());
+            }
+            self.write_done(error);
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        if self.state.get() == State::Erase {
+            if error == hil::flash::Error::CommandComplete {
+                let generation = &self.generations[self.page_number.get()];
+                generation.set(generation.get() | ERASED);
+            }
+            self.state.set(State::Idle);
+            self.client.map(|client| client.erase_complete(error));
+        }
+    }
+}
+
+impl<'a, F: hil::flash::Flash, A: AES128CCM<'a>, R: hil::rng::Rng<'a>>
+    hil::symmetric_encryption::CCMClient for EncryptedFlash<'a, F, A, R>
+{
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        let data_len = buf.len().saturating_sub(TRAILER_LEN);
+        match self.state.get() {
+            State::Decrypt => {
+                if res.is_ok() && tag_is_valid {
+                    self.client_page.map(|page| {
+                        page.as_mut().copy_from_slice(&buf[..data_len]);
+                    });
+                    self.crypt_buffer.replace(buf);
+                    self.read_done(hil::flash::Error::CommandComplete);
+                } else {
+                    // Tampered with or corrupted
+                    self.crypt_buffer.replace(buf);
+                    self.read_done(hil::flash::Error::FlashError);
+                }
+            }
+            State::Encrypt => {
+                if res.is_err() {
+                    self.crypt_buffer.replace(buf);
+                    return self.write_done(hil::flash::Error::FlashError);
+                }
+                let page = match self.page.take() {
+                    Some(page) => page,
+                    None => {
+                        self.crypt_buffer.replace(buf);
+                        return self.write_done(hil::flash::Error::FlashError);
+                    }
+                };
+                let contents = page.as_mut();
+                contents[..data_len + TAG_LEN].copy_from_slice(&buf[..data_len + TAG_LEN]);
+                contents[data_len + TAG_LEN..data_len + TAG_LEN + GENERATION_LEN]
+                    .copy_from_slice(&self.generation.get().to_le_bytes());
+                contents[data_len + TAG_LEN + GENERATION_LEN..data_len + TRAILER_LEN]
+                    .copy_from_slice(&self.nonce.get());
+                self.crypt_buffer.replace(buf);
+
+                self.state.set(State::Write);
+                if let Err((_, page)) = self.flash.write_page(self.page_number.get(), page) {
+                    self.page.replace(page);
+                    self.write_done(hil::flash::Error::FlashError);
+                }
+            }
+            _ => {
+                self.crypt_buffer.replace(buf);
+            }
+        }
+    }
+}
+
+impl<'a, F: hil::flash::Flash, A: AES128CCM<'a>, R: hil::rng::Rng<'a>> hil::rng::Client
+    for EncryptedFlash<'a, F, A, R>
+{
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> hil::rng::Continue {
+        if self.state.get() != State::Nonce {
+            return hil::rng::Continue::Done;
+        }
+        if error.is_err() {
+            self.write_done(hil::flash::Error::FlashError);
+            return hil::rng::Continue::Done;
+        }
+
+        match randomness.next() {
+            Some(random) => self.nonce.set(random.to_le_bytes()),
+            None => return hil::rng::Continue::More,
+        }
+
+        self.encrypt();
+        hil::rng::Continue::Done
+    }
+}
+
+impl<'a, F: hil::flash::Flash, A: AES128CCM<'a>, R: hil::rng::Rng<'a>, C>
+    hil::flash::HasClient<'a, C> for EncryptedFlash<'a, F, A, R>
+where
+    C: hil::flash::Client<Self>,
+{
+    fn set_client(&'a self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,345 @@
+//! Tests of the encryption layer over an emulated flash, with a stand-in for
+//! AES-CCM that keeps the same interface: a keystream and a tag that both
+//! depend on the key and the nonce.
+
+extern crate std;
+
+use std::cell::{Cell, RefCell};
+use std::vec;
+use std::vec::Vec;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::flash::{self, Flash};
+use kernel::hil::rng;
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM};
+use kernel::ErrorCode;
+
+use super::{EncryptedFlash, EncryptedPage, TAG_LEN, TRAILER_LEN};
+use crate::emulated_flash::{self, Programming};
+use crate::test_support::{leak, leak_mut, run, CountingRng, Events, Pending};
+
+const PAGE_SIZE: usize = 64;
+const PAGES: usize = 4;
+const DATA_LEN: usize = PAGE_SIZE - TRAILER_LEN;
+
+type EmulatedFlash = emulated_flash::EmulatedFlash<PAGE_SIZE>;
+type Page = emulated_flash::Page<PAGE_SIZE>;
+
+/// Not AES, but encrypts and authenticates with the key and nonce like it.
+struct FakeCcm {
+    client: OptionalCell<&'static dyn CCMClient>,
+    key: Cell<[u8; 16]>,
+    nonce: RefCell<Vec<u8>>,
+    buf: TakeCell<'static, [u8]>,
+    job: Cell<(usize, usize, bool)>,
+}
+
+impl FakeCcm {
+    fn keystream(&self) -> impl Iterator<Item = u8> {
+        let seed = self
+            .key
+            .get()
+            .iter()
+            .chain(self.nonce.borrow().iter())
+            .fold(0x811c9dc5u32, |h, &b| {
+                (h ^ b as u32).wrapping_mul(0x01000193)
+            });
+        (0u32..).map(move |i| {
+            (seed ^ i.wrapping_mul(0x9e3779b9))
+                .wrapping_mul(0x01000193)
+                .to_le_bytes()[1]
+        })
+    }
+
+    fn tag(&self, plaintext: &[u8]) -> [u8; TAG_LEN] {
+        let mut tag = [0u8; TAG_LEN];
+        for (i, (&byte, key)) in plaintext.iter().zip(self.keystream()).enumerate() {
+            tag[i % TAG_LEN] = tag[i % TAG_LEN].rotate_left(3) ^ byte ^ key;
+        }
+        tag
+    }
+}
+
+impl Pending for FakeCcm {
+    fn complete(&self) -> bool {
+        let buf = match self.buf.take() {
+            Some(buf) => buf,
+            None => return false,
+        };
+        let (m_len, mic_len, encrypting) = self.job.get();
+        let valid = if encrypting {
+            let tag = self.tag(&buf[..m_len]);
+            buf[m_len..m_len + mic_len].copy_from_slice(&tag[..mic_len]);
+            buf[..m_len]
+                .iter_mut()
+                .zip(self.keystream().skip(1000))
+                .for_each(|(byte, key)| *byte ^= key);
+            true
+        } else {
+            buf[..m_len]
+                .iter_mut()
+                .zip(self.keystream().skip(1000))
+                .for_each(|(byte, key)| *byte ^= key);
+            self.tag(&buf[..m_len])[..mic_len] == buf[m_len..m_len + mic_len]
+        };
+        self.client
+            .map(move |client| client.crypt_done(buf, Ok(()), valid));
+        true
+    }
+}
+
+impl AES128CCM<'static> for FakeCcm {
+    fn set_client(&'static self, client: &'static dyn CCMClient) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        let mut k = [0; 16];
+        k.copy_from_slice(key);
+        self.key.set(k);
+        Ok(())
+    }
+
+    fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode> {
+        *self.nonce.borrow_mut() = nonce.to_vec();
+        Ok(())
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        mic_len: usize,
+        _confidential: bool,
+        encrypting: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        assert_eq!((a_off, m_off), (0, 0));
+        if self.buf.is_some() {
+            return Err((ErrorCode::BUSY, buf));
+        }
+        self.job.set((m_len, mic_len, encrypting));
+        self.buf.replace(buf);
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Read(Vec<u8>, flash::Error),
+    Written(flash::Error),
+    Erased(flash::Error),
+}
+
+struct Client {
+    page: TakeCell<'static, EncryptedPage<Page>>,
+    events: Events<Event>,
+}
+
+impl flash::Client<Encrypted> for Client {
+    fn read_complete(&self, page: &'static mut EncryptedPage<Page>, error: flash::Error) {
+        self.events.push(Event::Read(page.as_mut().to_vec(), error));
+        self.page.replace(page);
+    }
+
+    fn write_complete(&self, page: &'static mut EncryptedPage<Page>, error: flash::Error) {
+        self.events.push(Event::Written(error));
+        self.page.replace(page);
+    }
+
+    fn erase_complete(&self, error: flash::Error) {
+        self.events.push(Event::Erased(error));
+    }
+}
+
+type Encrypted = EncryptedFlash<'static, EmulatedFlash, FakeCcm, CountingRng>;
+
+struct Device {
+    encrypted: &'static Encrypted,
+    generations: &'static [Cell<u32>],
+    flash: &'static EmulatedFlash,
+    ccm: &'static FakeCcm,
+    rng: &'static CountingRng,
+    client: &'static Client,
+}
+
+impl Device {
+    fn new() -> Device {
+        let flash = EmulatedFlash::leak(vec![0xFF; PAGE_SIZE * PAGES], 0, Programming::Replace);
+        let ccm = leak(FakeCcm {
+            client: OptionalCell::empty(),
+            key: Cell::new([0; 16]),
+            nonce: RefCell::new(Vec::new()),
+            buf: TakeCell::empty(),
+            job: Cell::new((0, 0, false)),
+        });
+        let rng = leak(CountingRng::new(1, 0x01010101));
+        let generations: &'static [Cell<u32>] =
+            leak([Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0)]);
+        let encrypted: &'static Encrypted = leak(EncryptedFlash::new(
+            flash,
+            ccm,
+            rng,
+            &[0x42; 16],
+            generations,
+            leak_mut(Page::default()),
+            leak_mut(Page::default()),
+        ));
+        flash.client.set(encrypted);
+        ccm.set_client(encrypted);
+        rng::Rng::set_client(rng, encrypted);
+
+        let client = leak(Client {
+            page: TakeCell::new(leak_mut(EncryptedPage::default())),
+            events: Events::new(),
+        });
+        flash::HasClient::set_client(encrypted, client);
+
+        Device {
+            encrypted,
+            generations,
+            flash,
+            ccm,
+            rng,
+            client,
+        }
+    }
+
+    /// Complete pending operations until none are left.
+    fn run(&self) -> Event {
+        run(&[self.flash, self.ccm, self.rng]);
+        let mut events = self.client.events.take();
+        assert_eq!(events.len(), 1);
+        events.pop().unwrap()
+    }
+
+    fn write(&self, page_number: usize, data: &[u8]) {
+        let page = self.client.page.take().unwrap();
+        page.as_mut().copy_from_slice(data);
+        assert!(self.encrypted.write_page(page_number, page).is_ok());
+        assert_eq!(self.run(), Event::Written(flash::Error::CommandComplete));
+    }
+
+    fn read(&self, page_number: usize) -> Event {
+        let page = self.client.page.take().unwrap();
+        assert!(self.encrypted.read_page(page_number, page).is_ok());
+        self.run()
+    }
+
+    fn physical(&self, page_number: usize) -> Vec<u8> {
+        self.flash.image.borrow()[self.flash.page(page_number)].to_vec()
+    }
+}
+
+fn pattern(seed: u8) -> Vec<u8> {
+    (0..DATA_LEN).map(|i| seed.wrapping_add(i as u8)).collect()
+}
+
+#[test]
+fn pages_round_trip_encrypted() {
+    let device = Device::new();
+    device.write(1, &pattern(1));
+    device.write(2, &pattern(1));
+
+    let stored = device.physical(1);
+    assert_ne!(&stored[..DATA_LEN], &pattern(1)[..]);
+    // Fresh nonces make identical pages differ
+    assert_ne!(stored, device.physical(2));
+
+    assert_eq!(
+        device.read(1),
+        Event::Read(pattern(1), flash::Error::CommandComplete)
+    );
+    assert_eq!(
+        device.read(0),
+        Event::Read(vec![0xFF; DATA_LEN], flash::Error::CommandComplete)
+    );
+
+    device.encrypted.erase_page(1).unwrap();
+    assert_eq!(device.run(), Event::Erased(flash::Error::CommandComplete));
+    assert_eq!(
+        device.read(1),
+        Event::Read(vec![0xFF; DATA_LEN], flash::Error::CommandComplete)
+    );
+}
+
+#[test]
+fn tampering_is_reported() {
+    let device = Device::new();
+    device.write(1, &pattern(1));
+
+    device.flash.image.borrow_mut()[PAGE_SIZE + 5] ^= 0x01;
+    assert_eq!(
+        device.read(1),
+        Event::Read(vec![0xFF; DATA_LEN], flash::Error::FlashError)
+    );
+
+    // A valid page copied to another address of the same generation does
+    // not authenticate either
+    device.write(1, &pattern(1));
+    device.write(3, &pattern(3));
+    device.write(3, &pattern(3));
+    let stored = device.physical(1);
+    device.flash.image.borrow_mut()[3 * PAGE_SIZE..].copy_from_slice(&stored);
+    assert_eq!(
+        device.read(3),
+        Event::Read(vec![0xFF; DATA_LEN], flash::Error::FlashError)
+    );
+    assert_eq!(
+        device.read(1),
+        Event::Read(pattern(1), flash::Error::CommandComplete)
+    );
+}
+
+#[test]
+fn one_operation_at_a_time() {
+    let device = Device::new();
+    let page = device.client.page.take().unwrap();
+    assert!(device.encrypted.write_page(1, page).is_ok());
+    assert_eq!(device.encrypted.erase_page(2), Err(ErrorCode::BUSY));
+    assert_eq!(device.run(), Event::Written(flash::Error::CommandComplete));
+}
+
+#[test]
+fn rollback_is_reported() {
+    let device = Device::new();
+    device.write(1, &pattern(1));
+    let old = device.physical(1);
+    device.write(1, &pattern(2));
+    assert_eq!(device.generations[1].get(), 2);
+
+    // An older copy of the page written back
+    let page = device.flash.page(1);
+    device.flash.image.borrow_mut()[page.clone()].copy_from_slice(&old);
+    assert_eq!(
+        device.read(1),
+        Event::Read(vec![0xFF; DATA_LEN], flash::Error::FlashError)
+    );
+
+    // A page that should hold data but was erased
+    device.flash.image.borrow_mut()[page.clone()]
+        .iter_mut()
+        .for_each(|byte| *byte = 0xFF);
+    assert_eq!(
+        device.read(1),
+        Event::Read(vec![0xFF; DATA_LEN], flash::Error::FlashError)
+    );
+
+    // A page that holds data but was erased through the layer
+    device.encrypted.erase_page(1).unwrap();
+    assert_eq!(device.run(), Event::Erased(flash::Error::CommandComplete));
+    device.flash.image.borrow_mut()[page].copy_from_slice(&old);
+    assert_eq!(
+        device.read(1),
+        Event::Read(vec![0xFF; DATA_LEN], flash::Error::FlashError)
+    );
+
+    // Generations keep counting after an erase
+    device.write(1, &pattern(3));
+    assert_eq!(device.generations[1].get(), 3);
+    assert_eq!(
+        device.read(1),
+        Event::Read(pattern(3), flash::Error::CommandComplete)
+    );
+}
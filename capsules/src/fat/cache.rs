@@ -0,0 +1,260 @@
+//! Write-back block cache used by the FAT filesystem.
+//!
+//! The filesystem never talks to the block device directly. Every sector it
+//! touches goes through this cache, and when a sector is not resident the
+//! cache records a single pending I/O request and fails the access with
+//! `Error::Io`. The owner of the cache performs the request, reports
+//! completion with `complete_io()`, and then re-runs the filesystem
+//! operation, which now finds the block in the cache.
+//!
+//! Blocks that belong to the first file allocation table are mirrored to the
+//! other FAT copies when they are written back, so the filesystem only ever
+//! updates one copy.
+
+use kernel::ErrorCode;
+
+/// Size of a cached block. Only 512 byte sectors are supported.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Upper bound on the number of cache slots.
+pub const MAX_SLOTS: usize = 8;
+
+/// Failure of a filesystem operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// A block has to be read or written before the operation can make
+    /// progress. Perform the request from `next_io()` and retry.
+    Io,
+    /// The operation failed.
+    Fs(ErrorCode),
+}
+
+impl From<ErrorCode> for Error {
+    fn from(e: ErrorCode) -> Error {
+        Error::Fs(e)
+    }
+}
+
+/// A block transfer the cache needs performed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoRequest {
+    pub block: u32,
+    pub write: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    block: u32,
+    valid: bool,
+    dirty: bool,
+    used: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Pending {
+    Read { slot: usize, block: u32 },
+    Write { slot: usize, copy: u32 },
+}
+
+pub struct BlockCache<'a> {
+    data: &'a mut [u8],
+    slots: [Slot; MAX_SLOTS],
+    num_slots: usize,
+    clock: u32,
+    pending: Option<Pending>,
+    fat_start: u32,
+    fat_size: u32,
+    fat_copies: u32,
+}
+
+impl<'a> BlockCache<'a> {
+    /// Create a cache backed by `data`, which holds one slot per
+    /// `BLOCK_SIZE` bytes (at most `MAX_SLOTS`).
+    pub fn new(data: &'a mut [u8]) -> BlockCache<'a> {
+        let num_slots = core::cmp::min(data.len() / BLOCK_SIZE, MAX_SLOTS);
+        BlockCache {
+            data,
+            slots: [Slot {
+                block: 0,
+                valid: false,
+                dirty: false,
+                used: 0,
+            }; MAX_SLOTS],
+            num_slots,
+            clock: 0,
+            pending: None,
+            fat_start: 0,
+            fat_size: 0,
+            fat_copies: 1,
+        }
+    }
+
+    /// Describe the FAT region so that writes to it are mirrored.
+    pub fn set_fat_region(&mut self, start: u32, size: u32, copies: u32) {
+        self.fat_start = start;
+        self.fat_size = size;
+        self.fat_copies = core::cmp::max(copies, 1);
+    }
+
+    /// Drop all cached blocks, including unwritten changes.
+    pub fn invalidate(&mut self) {
+        for slot in self.slots.iter_mut() {
+            slot.valid = false;
+            slot.dirty = false;
+        }
+        self.pending = None;
+    }
+
+    /// Get a block for reading.
+    pub fn read(&mut self, block: u32) -> Result<&[u8], Error> {
+        let slot = self.lookup(block)?;
+        Ok(self.slot_data(slot))
+    }
+
+    /// Get a block for modification. The block is written back later.
+    pub fn modify(&mut self, block: u32) -> Result<&mut [u8], Error> {
+        let slot = self.lookup(block)?;
+        self.slots[slot].dirty = true;
+        Ok(self.slot_data(slot))
+    }
+
+    /// Get a zero-filled block that will replace the contents on disk,
+    /// without reading it first.
+    pub fn overwrite(&mut self, block: u32) -> Result<&mut [u8], Error> {
+        let slot = match self.find(block) {
+            Some(slot) => slot,
+            None => {
+                let slot = self.victim()?;
+                self.slots[slot].block = block;
+                self.slots[slot].valid = true;
+                slot
+            }
+        };
+        self.touch(slot);
+        self.slots[slot].dirty = true;
+        let data = self.slot_data(slot);
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+        Ok(data)
+    }
+
+    /// Write back every modified block.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        match (0..self.num_slots).find(|&i| self.slots[i].valid && self.slots[i].dirty) {
+            Some(slot) => {
+                self.pending = Some(Pending::Write { slot, copy: 0 });
+                Err(Error::Io)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a transfer is waiting to be performed.
+    pub fn has_pending_io(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// The transfer that has to happen next, if any. For writes the block
+    /// contents are copied into `buffer`.
+    pub fn next_io(&self, buffer: &mut [u8]) -> Option<IoRequest> {
+        match self.pending? {
+            Pending::Read { block, .. } => Some(IoRequest {
+                block,
+                write: false,
+            }),
+            Pending::Write { slot, copy } => {
+                let start = slot * BLOCK_SIZE;
+                buffer[..BLOCK_SIZE].copy_from_slice(&self.data[start..start + BLOCK_SIZE]);
+                Some(IoRequest {
+                    block: self.slots[slot].block + copy * self.fat_size,
+                    write: true,
+                })
+            }
+        }
+    }
+
+    /// Report the outcome of the transfer returned by `next_io()`. For reads
+    /// `buffer` holds the block contents.
+    pub fn complete_io(&mut self, buffer: &[u8], result: Result<(), ErrorCode>) {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        if result.is_err() {
+            return;
+        }
+        match pending {
+            Pending::Read { slot, block } => {
+                let start = slot * BLOCK_SIZE;
+                self.data[start..start + BLOCK_SIZE].copy_from_slice(&buffer[..BLOCK_SIZE]);
+                self.slots[slot].block = block;
+                self.slots[slot].valid = true;
+                self.slots[slot].dirty = false;
+                self.touch(slot);
+            }
+            Pending::Write { slot, copy } => {
+                let block = self.slots[slot].block;
+                let in_fat = block >= self.fat_start && block < self.fat_start + self.fat_size;
+                if in_fat && copy + 1 < self.fat_copies {
+                    self.pending = Some(Pending::Write {
+                        slot,
+                        copy: copy + 1,
+                    });
+                } else {
+                    self.slots[slot].dirty = false;
+                }
+            }
+        }
+    }
+
+    fn find(&self, block: u32) -> Option<usize> {
+        (0..self.num_slots).find(|&i| self.slots[i].valid && self.slots[i].block == block)
+    }
+
+    fn touch(&mut self, slot: usize) {
+        self.clock = self.clock.wrapping_add(1);
+        self.slots[slot].used = self.clock;
+    }
+
+    /// Pick a slot to reuse. Fails with `Error::Io` if the least recently
+    /// used slot has to be written back first.
+    fn victim(&mut self) -> Result<usize, Error> {
+        if self.num_slots == 0 {
+            return Err(Error::Fs(ErrorCode::NOMEM));
+        }
+        let clock = self.clock;
+        let slot = (0..self.num_slots)
+            .find(|&i| !self.slots[i].valid)
+            .unwrap_or_else(|| {
+                (0..self.num_slots)
+                    .max_by_key(|&i| clock.wrapping_sub(self.slots[i].used))
+                    .unwrap_or(0)
+            });
+        if self.slots[slot].valid && self.slots[slot].dirty {
+            self.pending = Some(Pending::Write { slot, copy: 0 });
+            return Err(Error::Io);
+        }
+        self.slots[slot].valid = false;
+        Ok(slot)
+    }
+
+    fn lookup(&mut self, block: u32) -> Result<usize, Error> {
+        match self.find(block) {
+            Some(slot) => {
+                self.touch(slot);
+                Ok(slot)
+            }
+            None => {
+                let slot = self.victim()?;
+                self.pending = Some(Pending::Read { slot, block });
+                Err(Error::Io)
+            }
+        }
+    }
+
+    fn slot_data(&mut self, slot: usize) -> &mut [u8] {
+        let start = slot * BLOCK_SIZE;
+        &mut self.data[start..start + BLOCK_SIZE]
+    }
+}
@@ -0,0 +1,669 @@
+//! Syscall driver for the FAT filesystem.
+//!
+//! Each process gets its own directory, `/APPS/<NAME>~<N>`, where `NAME` is
+//! derived from the process name (see `ShortName::for_process()`). Processes
+//! whose names map to the same `NAME` get different suffixes `N`, and a file
+//! next to each directory records the full name of the process that owns it
+//! (see `FatFs::claim_dir()`). All file
+//! names an app passes are resolved inside that directory, and names are
+//! plain 8.3 names without any path separators, so an app cannot reach files
+//! outside of it. The directory is created the first time the app uses the
+//! filesystem.
+//!
+//! The filesystem is mounted lazily by the first operation, so the block
+//! device must be ready (e.g. the SD card initialized) by then. Only one
+//! operation runs at a time; commands issued while another one is in
+//! progress fail with `BUSY`.
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! * read-only 0: file name, terminated by a NUL byte or the end of the
+//!   buffer
+//! * read-only 1: data to write
+//! * read-write 0: buffer for data read from a file, or for the name of a
+//!   listed entry
+//!
+//! ### Subscribe
+//!
+//! * 0: completion of an operation. The first argument is the status (0 or
+//!   an `ErrorCode`), the other two depend on the command.
+//!
+//! ### Command
+//!
+//! * 0: driver check.
+//! * 1: open the named file. Bit 0 of the argument creates it if it does not
+//!   exist, bit 1 fails with `ALREADY` if it does. The upcall carries the file
+//!   handle and size. A missing file is reported as `NODEVICE`.
+//! * 2: read up to `arg2` bytes from handle `arg1` at its position. The
+//!   upcall carries the number of bytes read, 0 at the end of the file.
+//! * 3: write `arg2` bytes to handle `arg1` at its position. The upcall
+//!   carries the number of bytes written.
+//! * 4: append `arg2` bytes to the end of handle `arg1`.
+//! * 5: set the position of handle `arg1` to `arg2`, which may be at most the
+//!   file size.
+//! * 6: close handle `arg1`, writing back all changes.
+//! * 7: list the directory. `arg1` is 0 for the first entry, otherwise the
+//!   value returned by the previous listing. The name is written NUL
+//!   terminated to the read-write buffer, and the upcall carries the value to
+//!   continue from (0 once there are no more entries) and the file size.
+//! * 8: delete the named file. Open files cannot be deleted.
+//! * 9: size of handle `arg1`.
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+use kernel::introspection::KernelInfo;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+
+use super::cache::{Error, BLOCK_SIZE};
+use super::volume::{Claim, Create, Delete, Dir, FatFs, File, Scan, ShortName, Write};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Fat as usize;
+
+/// Number of files each app can have open at once.
+pub const MAX_OPEN_FILES: usize = 4;
+
+/// Number of blocks kept in the filesystem cache.
+pub const CACHE_BLOCKS: usize = 4;
+
+pub const CACHE_LEN: usize = CACHE_BLOCKS * BLOCK_SIZE;
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    file: File,
+    position: u32,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    name: ReadOnlyAppSlice,
+    write_buffer: ReadOnlyAppSlice,
+    read_buffer: ReadWriteAppSlice,
+    dir: Option<Dir>,
+    files: [Option<OpenFile>; MAX_OPEN_FILES],
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Idle,
+    Open {
+        name: ShortName,
+        create: bool,
+        exclusive: bool,
+        scan: Scan,
+        state: Create,
+    },
+    Read {
+        handle: usize,
+        len: usize,
+        done: usize,
+    },
+    Write {
+        handle: usize,
+        len: usize,
+        state: Write,
+    },
+    Close {
+        handle: usize,
+    },
+    List {
+        scan: Scan,
+    },
+    Delete {
+        name: ShortName,
+        checked: bool,
+        scan: Scan,
+        state: Delete,
+    },
+}
+
+pub struct FatDriver<'a, B: BlockStorage<'a>, C: ProcessManagementCapability> {
+    storage: &'a B,
+    fs: MapCell<FatFs<'static>>,
+    buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    current_app: OptionalCell<ProcessId>,
+    operation: Cell<Operation>,
+    /// Value to report once all changes are written back
+    result: Cell<Option<(usize, usize)>>,
+    /// Progress of creating the `APPS` directory
+    setup: Cell<Create>,
+    /// Progress of finding or creating the directory of the current app
+    claim: Cell<Claim>,
+    apps_dir: Cell<Option<Dir>>,
+    kernel: &'static Kernel,
+    capability: C,
+}
+
+impl<'a, B: BlockStorage<'a>, C: ProcessManagementCapability> FatDriver<'a, B, C> {
+    pub fn new(
+        storage: &'a B,
+        cache: &'static mut [u8; CACHE_LEN],
+        buffer: &'static mut [u8; BLOCK_SIZE],
+        grant: Grant<App>,
+        kernel: &'static Kernel,
+        capability: C,
+    ) -> FatDriver<'a, B, C> {
+        FatDriver {
+            storage,
+            fs: MapCell::new(FatFs::new(cache)),
+            buffer: TakeCell::new(buffer),
+            apps: grant,
+            current_app: OptionalCell::empty(),
+            operation: Cell::new(Operation::Idle),
+            result: Cell::new(None),
+            setup: Cell::new(Create::default()),
+            claim: Cell::new(Claim::default()),
+            apps_dir: Cell::new(None),
+            kernel,
+            capability,
+        }
+    }
+
+    /// Start `operation` for `appid`.
+    fn start(&self, appid: ProcessId, operation: Operation) -> CommandReturn {
+        if !matches!(self.operation.get(), Operation::Idle) {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+        self.operation.set(operation);
+        self.current_app.set(appid);
+        self.result.set(None);
+        self.setup.set(Create::default());
+        self.claim.set(Claim::default());
+        self.run();
+        CommandReturn::success()
+    }
+
+    /// Drive the current operation until it needs a block transfer or
+    /// finishes.
+    fn run(&self) {
+        loop {
+            let result = match self.result.get() {
+                Some(value) => self
+                    .fs
+                    .map_or(Err(Error::Fs(ErrorCode::FAIL)), |fs| fs.flush())
+                    .map(|()| value),
+                None => self.step(),
+            };
+            match result {
+                Err(Error::Io) => {
+                    if let Err(e) = self.start_io() {
+                        self.finish(Err(e));
+                    }
+                    return;
+                }
+                Err(Error::Fs(e)) => {
+                    self.finish(Err(e));
+                    return;
+                }
+                Ok(value) => {
+                    if self.result.get().is_some() {
+                        self.finish(Ok(value));
+                        return;
+                    }
+                    // write back all changes before reporting
+                    self.result.set(Some(value));
+                }
+            }
+        }
+    }
+
+    fn start_io(&self) -> Result<(), ErrorCode> {
+        self.buffer.take().map_or(Err(ErrorCode::BUSY), |buffer| {
+            match self.fs.map(|fs| fs.next_io(buffer)).flatten() {
+                Some(request) => {
+                    let result = if request.write {
+                        self.storage.write_blocks(buffer, request.block, 1)
+                    } else {
+                        self.storage.read_blocks(buffer, request.block, 1)
+                    };
+                    result.map_err(|(e, buffer)| {
+                        self.fs.map(|fs| fs.complete_io(buffer, Err(e)));
+                        self.buffer.replace(buffer);
+                        e
+                    })
+                }
+                None => {
+                    self.buffer.replace(buffer);
+                    Err(ErrorCode::FAIL)
+                }
+            }
+        })
+    }
+
+    fn io_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.fs.map(|fs| fs.complete_io(buffer, result));
+        self.buffer.replace(buffer);
+        if let Err(e) = result {
+            self.finish(Err(e));
+        } else if self.fs.map_or(false, |fs| fs.has_pending_io()) {
+            // further copies of the FAT still have to be written
+            if let Err(e) = self.start_io() {
+                self.finish(Err(e));
+            }
+        } else {
+            self.run();
+        }
+    }
+
+    fn finish(&self, result: Result<(usize, usize), ErrorCode>) {
+        self.operation.set(Operation::Idle);
+        self.result.set(None);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                let (a, b) = result.unwrap_or((0, 0));
+                app.callback
+                    .schedule(kernel::into_statuscode(result.map(|_| ())), a, b);
+            });
+        });
+    }
+
+    fn step(&self) -> Result<(usize, usize), Error> {
+        let appid = self
+            .current_app
+            .extract()
+            .ok_or(Error::Fs(ErrorCode::FAIL))?;
+        self.fs.map_or(Err(Error::Fs(ErrorCode::FAIL)), |fs| {
+            fs.mount()?;
+            let dir = self.app_dir(fs, appid)?;
+            self.apps
+                .enter(appid, |app| {
+                    let mut operation = self.operation.get();
+                    let result = self.perform(fs, dir, app, &mut operation);
+                    self.operation.set(operation);
+                    result
+                })
+                .unwrap_or(Err(Error::Fs(ErrorCode::NOMEM)))
+        })
+    }
+
+    /// The sandbox directory of `appid`, creating it if needed.
+    fn app_dir(&self, fs: &mut FatFs, appid: ProcessId) -> Result<Dir, Error> {
+        if let Some(dir) = self.apps.enter(appid, |app| app.dir).unwrap_or(None) {
+            return Ok(dir);
+        }
+        let apps_dir = match self.apps_dir.get() {
+            Some(dir) => dir,
+            None => {
+                let mut state = self.setup.get();
+                let result = fs.create(
+                    fs.root()?,
+                    &ShortName::parse(b"APPS")?,
+                    true,
+                    false,
+                    &mut state,
+                );
+                self.setup.set(match result {
+                    Err(Error::Io) => state,
+                    _ => Create::default(),
+                });
+                let dir = result?.dir();
+                self.apps_dir.set(Some(dir));
+                dir
+            }
+        };
+        let info = KernelInfo::new(self.kernel);
+        let name = info.process_name(appid, &self.capability);
+        let mut state = self.claim.get();
+        let result = fs.claim_dir(apps_dir, name, &mut state);
+        self.claim.set(match result {
+            Err(Error::Io) => state,
+            _ => Claim::default(),
+        });
+        let dir = result?;
+        let _ = self.apps.enter(appid, |app| app.dir = Some(dir));
+        Ok(dir)
+    }
+
+    fn perform(
+        &self,
+        fs: &mut FatFs,
+        dir: Dir,
+        app: &mut App,
+        operation: &mut Operation,
+    ) -> Result<(usize, usize), Error> {
+        match operation {
+            Operation::Idle => Err(ErrorCode::FAIL.into()),
+            Operation::Open {
+                name,
+                create,
+                exclusive,
+                scan,
+                state,
+            } => {
+                let entry = if *create {
+                    fs.create(dir, name, false, *exclusive, state)?
+                } else {
+                    fs.find(dir, name, scan)?
+                        .ok_or(Error::Fs(ErrorCode::NODEVICE))?
+                };
+                if app.files.iter().flatten().any(|f| f.file.same_file(&entry)) {
+                    return Err(ErrorCode::BUSY.into());
+                }
+                let file = fs.open(&entry)?;
+                let handle = app
+                    .files
+                    .iter()
+                    .position(|f| f.is_none())
+                    .ok_or(Error::Fs(ErrorCode::NOMEM))?;
+                app.files[handle] = Some(OpenFile { file, position: 0 });
+                Ok((handle, file.size() as usize))
+            }
+            Operation::Read { handle, len, done } => {
+                let open = app.files[*handle]
+                    .as_mut()
+                    .ok_or(Error::Fs(ErrorCode::INVAL))?;
+                let read = app
+                    .read_buffer
+                    .mut_map_or(Err(ErrorCode::NOMEM.into()), |buf| {
+                        let len = cmp::min(buf.len(), *len);
+                        fs.read(&mut open.file, open.position, &mut buf[..len], done)
+                    })?;
+                open.position += read as u32;
+                Ok((read, 0))
+            }
+            Operation::Write { handle, len, state } => {
+                let open = app.files[*handle]
+                    .as_mut()
+                    .ok_or(Error::Fs(ErrorCode::INVAL))?;
+                let written = app
+                    .write_buffer
+                    .map_or(Err(ErrorCode::NOMEM.into()), |data| {
+                        let len = cmp::min(data.len(), *len);
+                        fs.write(&mut open.file, open.position, &data[..len], state)
+                    })?;
+                open.position += written as u32;
+                Ok((written, 0))
+            }
+            Operation::Close { handle } => {
+                app.files[*handle] = None;
+                Ok((0, 0))
+            }
+            Operation::List { scan } => match fs.next_entry(dir, scan)? {
+                Some((entry, next)) => {
+                    app.read_buffer.mut_map_or((), |buf| {
+                        let len = entry.name.display(buf);
+                        if len < buf.len() {
+                            buf[len] = 0;
+                        }
+                    });
+                    Ok((next as usize, entry.size as usize))
+                }
+                None => Ok((0, 0)),
+            },
+            Operation::Delete {
+                name,
+                checked,
+                scan,
+                state,
+            } => {
+                if !*checked {
+                    let entry = fs
+                        .find(dir, name, scan)?
+                        .ok_or(Error::Fs(ErrorCode::NODEVICE))?;
+                    if app.files.iter().flatten().any(|f| f.file.same_file(&entry)) {
+                        return Err(ErrorCode::BUSY.into());
+                    }
+                    *checked = true;
+                }
+                fs.delete(dir, name, state)?;
+                Ok((0, 0))
+            }
+        }
+    }
+
+    /// Parse the file name the app shared with read-only allow 0.
+    fn app_file_name(app: &App) -> Result<ShortName, ErrorCode> {
+        app.name
+            .map_or(Err(ErrorCode::NOMEM), |name| ShortName::parse(name))
+    }
+}
+
+impl<'a, B: BlockStorage<'a>, C: ProcessManagementCapability> BlockStorageClient
+    for FatDriver<'a, B, C>
+{
+    fn read_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.io_done(buffer, result);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.io_done(buffer, result);
+    }
+}
+
+impl<'a, B: BlockStorage<'a>, C: ProcessManagementCapability> Driver for FatDriver<'a, B, C> {
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.read_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|e| e.into());
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.name, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.write_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|e| e.into());
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|e| e.into());
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        // Commands that take a handle need it to refer to an open file
+        let open_file = |handle: usize| -> Result<OpenFile, ErrorCode> {
+            self.apps
+                .enter(appid, |app| {
+                    app.files
+                        .get(handle)
+                        .copied()
+                        .flatten()
+                        .ok_or(ErrorCode::INVAL)
+                })
+                .unwrap_or(Err(ErrorCode::NOMEM))
+        };
+
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // open
+            1 => {
+                let name = self
+                    .apps
+                    .enter(appid, |app| {
+                        if app.files.iter().all(|f| f.is_some()) {
+                            Err(ErrorCode::NOMEM)
+                        } else {
+                            Self::app_file_name(app)
+                        }
+                    })
+                    .unwrap_or(Err(ErrorCode::NOMEM));
+                match name {
+                    Ok(name) => self.start(
+                        appid,
+                        Operation::Open {
+                            name,
+                            create: arg1 & 1 != 0,
+                            exclusive: arg1 & 2 != 0,
+                            scan: Scan::default(),
+                            state: Create::default(),
+                        },
+                    ),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            // read
+            2 => match open_file(arg1) {
+                Ok(_) => self.start(
+                    appid,
+                    Operation::Read {
+                        handle: arg1,
+                        len: arg2,
+                        done: 0,
+                    },
+                ),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // write, append
+            3 | 4 => match open_file(arg1) {
+                Ok(_) if !matches!(self.operation.get(), Operation::Idle) => {
+                    CommandReturn::failure(ErrorCode::BUSY)
+                }
+                Ok(open) => {
+                    if command_num == 4 {
+                        let _ = self.apps.enter(appid, |app| {
+                            app.files[arg1] = Some(OpenFile {
+                                position: open.file.size(),
+                                ..open
+                            });
+                        });
+                    }
+                    self.start(
+                        appid,
+                        Operation::Write {
+                            handle: arg1,
+                            len: arg2,
+                            state: Write::default(),
+                        },
+                    )
+                }
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // seek
+            5 => match open_file(arg1) {
+                Ok(_) if self.current_app.contains(&appid) => {
+                    CommandReturn::failure(ErrorCode::BUSY)
+                }
+                Ok(open) if arg2 <= open.file.size() as usize => {
+                    let _ = self.apps.enter(appid, |app| {
+                        app.files[arg1] = Some(OpenFile {
+                            position: arg2 as u32,
+                            ..open
+                        });
+                    });
+                    CommandReturn::success()
+                }
+                Ok(_) => CommandReturn::failure(ErrorCode::INVAL),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // close
+            6 => match open_file(arg1) {
+                Ok(_) => self.start(appid, Operation::Close { handle: arg1 }),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // list
+            7 => self.start(
+                appid,
+                Operation::List {
+                    scan: Scan::from(arg1 as u32),
+                },
+            ),
+
+            // delete
+            8 => {
+                let name = self
+                    .apps
+                    .enter(appid, |app| Self::app_file_name(app))
+                    .unwrap_or(Err(ErrorCode::NOMEM));
+                match name {
+                    Ok(name) => self.start(
+                        appid,
+                        Operation::Delete {
+                            name,
+                            checked: false,
+                            scan: Scan::default(),
+                            state: Delete::default(),
+                        },
+                    ),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            // size
+            9 => match open_file(arg1) {
+                Ok(open) => CommandReturn::success_u32(open.file.size()),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! FAT16/FAT32 filesystem on top of a `BlockStorage` device.
+//!
+//! - `cache`: write-back cache through which all block accesses go.
+//! - `volume`: the filesystem itself, independent of the kernel, so it can be
+//!   tested against a disk image in memory.
+//! - `driver`: syscall driver giving every app its own directory.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::fat::driver::{FatDriver, CACHE_LEN};
+//! # use capsules::fat::cache::BLOCK_SIZE;
+//!
+//! let fat = static_init!(
+//!     FatDriver<'static, SDCard<'static, VirtualMuxAlarm<'static, Rtc>>, Capability>,
+//!     FatDriver::new(
+//!         sdcard,
+//!         static_init!([u8; CACHE_LEN], [0; CACHE_LEN]),
+//!         static_init!([u8; BLOCK_SIZE], [0; BLOCK_SIZE]),
+//!         board_kernel.create_grant(&grant_cap),
+//!         board_kernel,
+//!         Capability,
+//!     )
+//! );
+//! kernel::hil::block_storage::BlockStorage::set_client(sdcard, fat);
+//! ```
+
+pub mod cache;
+pub mod driver;
+pub mod volume;
+
+#[cfg(test)]
+mod tests;
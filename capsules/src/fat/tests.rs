@@ -0,0 +1,530 @@
+//! Tests of the FAT filesystem against disk images held in memory.
+
+extern crate std;
+
+use std::collections::HashMap;
+use std::vec::Vec;
+
+use kernel::ErrorCode;
+
+use super::cache::{Error, BLOCK_SIZE};
+use super::volume::{Claim, Create, Delete, Dir, FatFs, FatType, File, Scan, ShortName, Write};
+
+/// Sparse disk, blocks that were never written read as zeros.
+#[derive(Default)]
+struct Disk {
+    blocks: HashMap<u32, [u8; BLOCK_SIZE]>,
+}
+
+impl Disk {
+    fn block(&self, block: u32) -> [u8; BLOCK_SIZE] {
+        self.blocks.get(&block).copied().unwrap_or([0; BLOCK_SIZE])
+    }
+
+    fn set(&mut self, block: u32, data: [u8; BLOCK_SIZE]) {
+        self.blocks.insert(block, data);
+    }
+}
+
+/// Layout of a freshly formatted volume.
+struct Format {
+    start: u32,
+    total: u32,
+    sectors_per_cluster: u8,
+    root_entries: u16,
+    fat32: bool,
+}
+
+impl Format {
+    fn fat16() -> Format {
+        Format {
+            start: 0,
+            total: 8192,
+            sectors_per_cluster: 1,
+            root_entries: 512,
+            fat32: false,
+        }
+    }
+
+    fn fat32() -> Format {
+        Format {
+            start: 0,
+            total: 70000,
+            sectors_per_cluster: 1,
+            root_entries: 0,
+            fat32: true,
+        }
+    }
+
+    fn reserved(&self) -> u32 {
+        if self.fat32 {
+            32
+        } else {
+            1
+        }
+    }
+
+    fn fat_size(&self) -> u32 {
+        let root_sectors = (self.root_entries as u32 * 32 + 511) / 512;
+        let clusters =
+            (self.total - self.reserved() - root_sectors) / self.sectors_per_cluster as u32;
+        let entry = if self.fat32 { 4 } else { 2 };
+        ((clusters + 2) * entry + 511) / 512
+    }
+
+    fn write(&self, disk: &mut Disk) {
+        let mut boot = [0; BLOCK_SIZE];
+        boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        boot[3..11].copy_from_slice(b"MSWIN4.1");
+        boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot[13] = self.sectors_per_cluster;
+        boot[14..16].copy_from_slice(&(self.reserved() as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[17..19].copy_from_slice(&self.root_entries.to_le_bytes());
+        boot[21] = 0xF8;
+        if self.fat32 {
+            boot[32..36].copy_from_slice(&self.total.to_le_bytes());
+            boot[36..40].copy_from_slice(&self.fat_size().to_le_bytes());
+            boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+        } else {
+            boot[19..21].copy_from_slice(&(self.total as u16).to_le_bytes());
+            boot[22..24].copy_from_slice(&(self.fat_size() as u16).to_le_bytes());
+        }
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+        disk.set(self.start, boot);
+
+        let mut fat = [0; BLOCK_SIZE];
+        if self.fat32 {
+            fat[..12].copy_from_slice(&[
+                0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F,
+            ]);
+        } else {
+            fat[..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+        }
+        let fat_start = self.start + self.reserved();
+        disk.set(fat_start, fat);
+        disk.set(fat_start + self.fat_size(), fat);
+    }
+
+    fn fat_start(&self) -> u32 {
+        self.start + self.reserved()
+    }
+}
+
+/// Run a restartable filesystem operation to completion, serving its block
+/// requests from `disk`.
+fn run<T, F>(fs: &mut FatFs, disk: &mut Disk, mut op: F) -> Result<T, ErrorCode>
+where
+    F: FnMut(&mut FatFs) -> Result<T, Error>,
+{
+    let mut buffer = [0; BLOCK_SIZE];
+    loop {
+        match op(fs) {
+            Ok(value) => return Ok(value),
+            Err(Error::Fs(e)) => return Err(e),
+            Err(Error::Io) => {
+                while let Some(request) = fs.next_io(&mut buffer) {
+                    if request.write {
+                        disk.set(request.block, buffer);
+                    } else {
+                        buffer = disk.block(request.block);
+                    }
+                    fs.complete_io(&buffer, Ok(()));
+                }
+            }
+        }
+    }
+}
+
+fn mount<'a>(cache: &'a mut [u8], disk: &mut Disk) -> FatFs<'a> {
+    let mut fs = FatFs::new(cache);
+    run(&mut fs, disk, |fs| fs.mount()).unwrap();
+    fs
+}
+
+fn name(s: &str) -> ShortName {
+    ShortName::parse(s.as_bytes()).unwrap()
+}
+
+fn root(fs: &FatFs) -> Dir {
+    fs.root().unwrap()
+}
+
+fn create(fs: &mut FatFs, disk: &mut Disk, dir: Dir, n: &str) -> Result<File, ErrorCode> {
+    let mut st = Create::default();
+    let entry = run(fs, disk, |fs| {
+        fs.create(dir, &name(n), false, true, &mut st)
+    })?;
+    run(fs, disk, |fs| fs.open(&entry))
+}
+
+fn open(fs: &mut FatFs, disk: &mut Disk, dir: Dir, n: &str) -> Option<File> {
+    let mut scan = Scan::default();
+    run(fs, disk, |fs| fs.find(dir, &name(n), &mut scan))
+        .unwrap()
+        .map(|entry| fs.open(&entry).unwrap())
+}
+
+fn write(fs: &mut FatFs, disk: &mut Disk, file: &mut File, pos: u32, data: &[u8]) {
+    let mut st = Write::default();
+    let written = run(fs, disk, |fs| fs.write(file, pos, data, &mut st)).unwrap();
+    assert_eq!(written, data.len());
+}
+
+fn read_all(fs: &mut FatFs, disk: &mut Disk, file: &mut File) -> Vec<u8> {
+    let mut buf = std::vec![0; file.size() as usize + 10];
+    let mut done = 0;
+    let len = run(fs, disk, |fs| fs.read(file, 0, &mut buf, &mut done)).unwrap();
+    buf.truncate(len);
+    buf
+}
+
+fn list(fs: &mut FatFs, disk: &mut Disk, dir: Dir) -> Vec<std::string::String> {
+    let mut names = Vec::new();
+    let mut next = 0;
+    loop {
+        let mut scan = Scan::from(next);
+        match run(fs, disk, |fs| fs.next_entry(dir, &mut scan)).unwrap() {
+            Some((entry, cookie)) => {
+                names.push(shown(&entry.name));
+                next = cookie;
+            }
+            None => return names,
+        }
+    }
+}
+
+fn shown(name: &ShortName) -> std::string::String {
+    let mut out = [0; 12];
+    let len = name.display(&mut out);
+    std::string::String::from_utf8(out[..len].to_vec()).unwrap()
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+fn free_clusters(disk: &Disk, format: &Format) -> usize {
+    let mut free = 0;
+    for block in format.fat_start()..format.fat_start() + format.fat_size() {
+        let data = disk.block(block);
+        if format.fat32 {
+            free += data.chunks(4).filter(|e| e == &[0, 0, 0, 0]).count();
+        } else {
+            free += data.chunks(2).filter(|e| e == &[0, 0]).count();
+        }
+    }
+    free
+}
+
+#[test]
+fn mounts_fat16_and_fat32() {
+    let mut disk = Disk::default();
+    Format::fat16().write(&mut disk);
+    let mut cache = [0; 4 * BLOCK_SIZE];
+    let fs = mount(&mut cache, &mut disk);
+    assert_eq!(fs.fat_type(), Some(FatType::Fat16));
+
+    let mut disk = Disk::default();
+    Format::fat32().write(&mut disk);
+    let mut cache = [0; 4 * BLOCK_SIZE];
+    let fs = mount(&mut cache, &mut disk);
+    assert_eq!(fs.fat_type(), Some(FatType::Fat32));
+}
+
+#[test]
+fn rejects_unformatted_disk() {
+    let mut disk = Disk::default();
+    let mut cache = [0; 4 * BLOCK_SIZE];
+    let mut fs = FatFs::new(&mut cache);
+    assert_eq!(
+        run(&mut fs, &mut disk, |fs| fs.mount()),
+        Err(ErrorCode::INVAL)
+    );
+    assert!(!fs.is_mounted());
+}
+
+#[test]
+fn mounts_first_partition() {
+    let mut disk = Disk::default();
+    let format = Format {
+        start: 63,
+        ..Format::fat16()
+    };
+    format.write(&mut disk);
+    let mut mbr = [0; BLOCK_SIZE];
+    mbr[0x1BE + 4] = 0x0E;
+    mbr[0x1BE + 8..0x1BE + 12].copy_from_slice(&63u32.to_le_bytes());
+    mbr[0x1BE + 12..0x1BE + 16].copy_from_slice(&format.total.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    disk.set(0, mbr);
+
+    let mut cache = [0; 4 * BLOCK_SIZE];
+    let mut fs = mount(&mut cache, &mut disk);
+    let dir = root(&fs);
+    let mut file = create(&mut fs, &mut disk, dir, "a.txt").unwrap();
+    write(&mut fs, &mut disk, &mut file, 0, b"partitioned");
+    run(&mut fs, &mut disk, |fs| fs.flush()).unwrap();
+
+    // nothing may land in front of the partition
+    assert!(disk.blocks.keys().all(|&b| b == 0 || b >= 63));
+}
+
+#[test]
+fn writes_and_reads_back_across_remount() {
+    for format in [Format::fat16(), Format::fat32()].iter() {
+        let mut disk = Disk::default();
+        format.write(&mut disk);
+        let data = pattern(3000);
+        {
+            // Two slots is the smallest cache that makes progress
+            let mut cache = [0; 2 * BLOCK_SIZE];
+            let mut fs = mount(&mut cache, &mut disk);
+            let dir = root(&fs);
+            let mut file = create(&mut fs, &mut disk, dir, "log.txt").unwrap();
+            write(&mut fs, &mut disk, &mut file, 0, &data[..1000]);
+            write(&mut fs, &mut disk, &mut file, 1000, &data[1000..]);
+            assert_eq!(file.size(), 3000);
+            assert_eq!(read_all(&mut fs, &mut disk, &mut file), data);
+            run(&mut fs, &mut disk, |fs| fs.flush()).unwrap();
+        }
+
+        let mut cache = [0; 4 * BLOCK_SIZE];
+        let mut fs = mount(&mut cache, &mut disk);
+        let dir = root(&fs);
+        let mut file = open(&mut fs, &mut disk, dir, "LOG.TXT").unwrap();
+        assert_eq!(file.size(), 3000);
+        assert_eq!(read_all(&mut fs, &mut disk, &mut file), data);
+    }
+}
+
+#[test]
+fn overwrites_in_place() {
+    let mut disk = Disk::default();
+    Format::fat16().write(&mut disk);
+    let mut cache = [0; 4 * BLOCK_SIZE];
+    let mut fs = mount(&mut cache, &mut disk);
+    let dir = root(&fs);
+    let mut file = create(&mut fs, &mut disk, dir, "data.bin").unwrap();
+    write(&mut fs, &mut disk, &mut file, 0, &[1; 1024]);
+    write(&mut fs, &mut disk, &mut file, 500, &[2; 100]);
+    assert_eq!(file.size(), 1024);
+
+    let contents = read_all(&mut fs, &mut disk, &mut file);
+    assert!(contents[..500].iter().all(|&b| b == 1));
+    assert!(contents[500..600].iter().all(|&b| b == 2));
+    assert!(contents[600..].iter().all(|&b| b == 1));
+
+    // writing past the end would leave a hole
+    let mut st = Write::default();
+    assert_eq!(
+        run(&mut fs, &mut disk, |fs| fs
+            .write(&mut file, 2000, b"x", &mut st)),
+        Err(ErrorCode::INVAL)
+    );
+}
+
+#[test]
+fn large_file_spans_fat_blocks() {
+    let format = Format::fat16();
+    let mut disk = Disk::default();
+    format.write(&mut disk);
+    let mut cache = [0; 2 * BLOCK_SIZE];
+    let mut fs = mount(&mut cache, &mut disk);
+    let dir = root(&fs);
+    let mut file = create(&mut fs, &mut disk, dir, "big").unwrap();
+
+    // 300 clusters need entries from two FAT blocks
+    let data = pattern(300 * BLOCK_SIZE + 17);
+    for (i, chunk) in data.chunks(4096).enumerate() {
+        write(&mut fs, &mut disk, &mut file, (i * 4096) as u32, chunk);
+    }
+    run(&mut fs, &mut disk, |fs| fs.flush()).unwrap();
+    assert_eq!(read_all(&mut fs, &mut disk, &mut file), data);
+
+    // both FAT copies agree
+    for i in 0..format.fat_size() {
+        assert_eq!(
+            disk.block(format.fat_start() + i)[..],
+            disk.block(format.fat_start() + format.fat_size() + i)[..]
+        );
+    }
+}
+
+#[test]
+fn lists_and_deletes() {
+    let format = Format::fat16();
+    let mut disk = Disk::default();
+    format.write(&mut disk);
+    let mut cache = [0; 4 * BLOCK_SIZE];
+    let mut fs = mount(&mut cache, &mut disk);
+    let dir = root(&fs);
+    run(&mut fs, &mut disk, |fs| fs.flush()).unwrap();
+    let free = free_clusters(&disk, &format);
+
+    for n in ["one.txt", "TWO.TXT", "three"].iter() {
+        let mut file = create(&mut fs, &mut disk, dir, n).unwrap();
+        write(&mut fs, &mut disk, &mut file, 0, &pattern(700));
+    }
+    assert_eq!(
+        create(&mut fs, &mut disk, dir, "one.txt").err(),
+        Some(ErrorCode::ALREADY)
+    );
+    assert_eq!(
+        list(&mut fs, &mut disk, dir),
+        ["one.txt", "TWO.TXT", "three"]
+    );
+
+    let mut st = Delete::default();
+    run(&mut fs, &mut disk, |fs| {
+        fs.delete(dir, &name("two.txt"), &mut st)
+    })
+    .unwrap();
+    assert_eq!(list(&mut fs, &mut disk, dir), ["one.txt", "three"]);
+    assert!(open(&mut fs, &mut disk, dir, "two.txt").is_none());
+    let mut st = Delete::default();
+    assert_eq!(
+        run(&mut fs, &mut disk, |fs| fs.delete(
+            dir,
+            &name("two.txt"),
+            &mut st
+        )),
+        Err(ErrorCode::NODEVICE)
+    );
+
+    // the deleted entry slot is reused
+    create(&mut fs, &mut disk, dir, "four").unwrap();
+    assert_eq!(list(&mut fs, &mut disk, dir), ["one.txt", "four", "three"]);
+
+    run(&mut fs, &mut disk, |fs| fs.flush()).unwrap();
+    assert_eq!(free_clusters(&disk, &format), free - 4);
+}
+
+#[test]
+fn directories_grow() {
+    let mut disk = Disk::default();
+    Format::fat32().write(&mut disk);
+    let mut cache = [0; 3 * BLOCK_SIZE];
+    let mut fs = mount(&mut cache, &mut disk);
+
+    let mut st = Create::default();
+    let apps = run(&mut fs, &mut disk, |fs| {
+        let root = fs.root()?;
+        fs.create(root, &name("APPS"), true, false, &mut st)
+    })
+    .unwrap();
+    assert!(apps.is_dir());
+    let dir = apps.dir();
+
+    // 16 entries fit in a one block cluster, two of them are . and ..
+    let names: Vec<_> = (0..40).map(|i| std::format!("f{}.dat", i)).collect();
+    for n in names.iter() {
+        create(&mut fs, &mut disk, dir, n).unwrap();
+    }
+    assert_eq!(list(&mut fs, &mut disk, dir), names);
+
+    // creating an existing directory returns it
+    let mut st = Create::default();
+    let again = run(&mut fs, &mut disk, |fs| {
+        let root = fs.root()?;
+        fs.create(root, &name("apps"), true, false, &mut st)
+    })
+    .unwrap();
+    assert_eq!(again.dir(), dir);
+    let root = root(&fs);
+    assert_eq!(list(&mut fs, &mut disk, root), ["APPS"]);
+}
+
+#[test]
+fn fixed_root_is_bounded() {
+    let mut disk = Disk::default();
+    Format {
+        root_entries: 16,
+        ..Format::fat16()
+    }
+    .write(&mut disk);
+    let mut cache = [0; 4 * BLOCK_SIZE];
+    let mut fs = mount(&mut cache, &mut disk);
+    let dir = root(&fs);
+    for i in 0..16 {
+        create(&mut fs, &mut disk, dir, &std::format!("f{}", i)).unwrap();
+    }
+    assert_eq!(
+        create(&mut fs, &mut disk, dir, "f16").err(),
+        Some(ErrorCode::NOMEM)
+    );
+}
+
+#[test]
+fn short_names() {
+    let n = name("log.txt");
+    assert_eq!(shown(&n), "log.txt");
+    let n = name("Mixed.Txt");
+    assert_eq!(shown(&n), "MIXED.TXT");
+    let n = name("README");
+    assert_eq!(shown(&n), "README");
+
+    for bad in [
+        "",
+        ".",
+        "..",
+        "toolongname",
+        "a.long",
+        "a/b",
+        "a b",
+        "trail.",
+    ]
+    .iter()
+    {
+        assert_eq!(ShortName::parse(bad.as_bytes()), Err(ErrorCode::INVAL));
+    }
+    assert_eq!(shown(&ShortName::parse(b"nul\0garbage").unwrap()), "nul");
+
+    let n = ShortName::for_process("my-sensor_app", 1);
+    assert_eq!(shown(&n), "MYSENS~1");
+    let n = ShortName::for_process("my-sensor_app", 42);
+    assert_eq!(shown(&n), "MYSEN~42");
+    let n = ShortName::for_process("__", 3);
+    assert_eq!(shown(&n), "APP~3");
+}
+
+#[test]
+fn colliding_process_names_get_separate_directories() {
+    let mut disk = Disk::default();
+    Format::fat32().write(&mut disk);
+    let mut cache = [0; 4 * BLOCK_SIZE];
+    let mut fs = mount(&mut cache, &mut disk);
+    let apps = root(&fs);
+
+    let claim = |fs: &mut FatFs, disk: &mut Disk, owner: &str| {
+        let mut st = Claim::default();
+        run(fs, disk, |fs| fs.claim_dir(apps, owner, &mut st)).unwrap()
+    };
+    let first = claim(&mut fs, &mut disk, "temperature1");
+    let second = claim(&mut fs, &mut disk, "temperature2");
+    assert_ne!(first, second);
+    assert_eq!(
+        list(&mut fs, &mut disk, apps),
+        ["TEMPER~1.OWN", "TEMPER~1", "TEMPER~2.OWN", "TEMPER~2"]
+    );
+
+    // Files of one process are not visible to the other
+    create(&mut fs, &mut disk, first, "data.txt").unwrap();
+    assert!(open(&mut fs, &mut disk, second, "data.txt").is_none());
+
+    // Claims are found again after remounting, whatever the order
+    run(&mut fs, &mut disk, |fs| fs.flush()).unwrap();
+    drop(fs);
+    let mut cache = [0; 4 * BLOCK_SIZE];
+    let mut fs = mount(&mut cache, &mut disk);
+    assert_eq!(claim(&mut fs, &mut disk, "temperature2"), second);
+    assert_eq!(claim(&mut fs, &mut disk, "temperature1"), first);
+    let mut owner = open(&mut fs, &mut disk, apps, "TEMPER~2.OWN").unwrap();
+    assert_eq!(read_all(&mut fs, &mut disk, &mut owner), b"temperature2");
+
+    // A process whose name is a prefix of another one's is different too
+    let third = claim(&mut fs, &mut disk, "temper");
+    assert!(third != first && third != second);
+}
@@ -0,0 +1,1036 @@
+//! FAT16 and FAT32 volume logic.
+//!
+//! Every operation is restartable: it may fail with `Error::Io` whenever a
+//! block it needs is not in the cache, and the caller then performs the
+//! cache's I/O request and calls the operation again with the same state.
+//! Operations that touch more than one block keep their progress in a small
+//! `Copy` state value owned by the caller (`Create`, `Write`, `Delete`,
+//! `Scan`, or the cursor inside a `File`), so a retry resumes where the last
+//! attempt stopped instead of repeating work. Each step that changes the
+//! disk is either idempotent or records its result in that state before the
+//! next block access, so replaying a step never allocates twice.
+//!
+//! Only 8.3 short names are supported; long file name entries are skipped.
+
+use core::cmp;
+
+use kernel::ErrorCode;
+
+use super::cache::{BlockCache, Error, IoRequest, BLOCK_SIZE};
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_BLOCK: u32 = (BLOCK_SIZE / ENTRY_SIZE) as u32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+
+/// First byte of a deleted directory entry.
+const DELETED: u8 = 0xE5;
+
+/// Windows NT case flags for names that are entirely lower case.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// FAT date of 1980-01-01. There is no wall clock, so all timestamps use it.
+const DEFAULT_DATE: u16 = 0x0021;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// Layout of a mounted volume, in absolute block numbers.
+#[derive(Clone, Copy, Debug)]
+struct Geometry {
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    num_fats: u32,
+    fat_start: u32,
+    fat_size: u32,
+    root_start: u32,
+    root_sectors: u32,
+    data_start: u32,
+    cluster_count: u32,
+    root_cluster: u32,
+}
+
+impl Geometry {
+    fn parse(boot: &[u8], start: u32) -> Result<Geometry, ErrorCode> {
+        if get_u16(boot, 11) as usize != BLOCK_SIZE {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        let sectors_per_cluster = boot[13] as u32;
+        if sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two() {
+            return Err(ErrorCode::INVAL);
+        }
+        let reserved = get_u16(boot, 14) as u32;
+        let num_fats = boot[16] as u32;
+        let root_entries = get_u16(boot, 17) as u32;
+        let total = match get_u16(boot, 19) {
+            0 => get_u32(boot, 32),
+            n => n as u32,
+        };
+        let fat_size = match get_u16(boot, 22) {
+            0 => get_u32(boot, 36),
+            n => n as u32,
+        };
+        if num_fats == 0 || fat_size == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+
+        let root_sectors =
+            (root_entries * ENTRY_SIZE as u32 + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32;
+        let meta = num_fats
+            .checked_mul(fat_size)
+            .and_then(|fats| fats.checked_add(reserved))
+            .and_then(|meta| meta.checked_add(root_sectors))
+            .ok_or(ErrorCode::INVAL)?;
+        if meta >= total {
+            return Err(ErrorCode::INVAL);
+        }
+        let cluster_count = (total - meta) / sectors_per_cluster;
+        let fat_type = if cluster_count < 4085 {
+            // FAT12 is not supported
+            return Err(ErrorCode::NOSUPPORT);
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        let entry_size = match fat_type {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        };
+        if (cluster_count as u64 + 2) * entry_size > fat_size as u64 * BLOCK_SIZE as u64 {
+            return Err(ErrorCode::INVAL);
+        }
+        let root_cluster = match fat_type {
+            FatType::Fat16 => 0,
+            FatType::Fat32 => get_u32(boot, 44),
+        };
+        if fat_type == FatType::Fat32 && (root_cluster < 2 || root_cluster >= cluster_count + 2) {
+            return Err(ErrorCode::INVAL);
+        }
+
+        let fat_start = start.checked_add(reserved).ok_or(ErrorCode::INVAL)?;
+        let root_start = fat_start
+            .checked_add(num_fats * fat_size)
+            .ok_or(ErrorCode::INVAL)?;
+        let data_start = root_start
+            .checked_add(root_sectors)
+            .ok_or(ErrorCode::INVAL)?;
+        Ok(Geometry {
+            fat_type,
+            sectors_per_cluster,
+            num_fats,
+            fat_start,
+            fat_size,
+            root_start,
+            root_sectors,
+            data_start,
+            cluster_count,
+            root_cluster,
+        })
+    }
+
+    fn cluster_block(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * BLOCK_SIZE as u32
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat16 => value >= 0xFFF8,
+            FatType::Fat32 => value >= 0x0FFF_FFF8,
+        }
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    /// Block and byte offset of the FAT entry for `cluster`.
+    fn fat_position(&self, cluster: u32) -> (u32, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster as usize * 2,
+            FatType::Fat32 => cluster as usize * 4,
+        };
+        (
+            self.fat_start + (offset / BLOCK_SIZE) as u32,
+            offset % BLOCK_SIZE,
+        )
+    }
+}
+
+/// An 8.3 file name in on-disk form.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShortName {
+    raw: [u8; 11],
+    case: u8,
+}
+
+impl ShortName {
+    /// Parse a name such as `log.txt`. The name ends at the first NUL byte.
+    /// Names that are entirely lower case are shown in lower case again when
+    /// listed; other names are stored upper case.
+    pub fn parse(name: &[u8]) -> Result<ShortName, ErrorCode> {
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        let name = &name[..len];
+        let (base, ext) = match name.iter().rposition(|&b| b == b'.') {
+            Some(dot) => (&name[..dot], &name[dot + 1..]),
+            None => (name, &name[len..]),
+        };
+        if base.is_empty()
+            || base.len() > 8
+            || ext.len() > 3
+            || (ext.is_empty() && len > base.len())
+        {
+            return Err(ErrorCode::INVAL);
+        }
+
+        let mut raw = [b' '; 11];
+        for (dst, &src) in raw[..8].iter_mut().zip(base.iter()) {
+            *dst = valid_char(src)?;
+        }
+        for (dst, &src) in raw[8..].iter_mut().zip(ext.iter()) {
+            *dst = valid_char(src)?;
+        }
+        let mut case = 0;
+        if is_lower(base) {
+            case |= CASE_LOWER_BASE;
+        }
+        if is_lower(ext) {
+            case |= CASE_LOWER_EXT;
+        }
+        Ok(ShortName { raw, case })
+    }
+
+    /// Directory name used to sandbox the files of the process `name`: its
+    /// first alphanumeric characters, upper cased, followed by `~suffix`.
+    /// Different processes can map to the same name for the same suffix, see
+    /// `FatFs::claim_dir()`.
+    pub fn for_process(name: &str, suffix: u8) -> ShortName {
+        let mut tail = [b'~', 0, 0];
+        let tail_len = if suffix < 10 {
+            tail[1] = b'0' + suffix;
+            2
+        } else {
+            tail[1] = b'0' + suffix / 10 % 10;
+            tail[2] = b'0' + suffix % 10;
+            3
+        };
+        let mut raw = [b' '; 11];
+        let chars = name.bytes().filter(|b| b.is_ascii_alphanumeric());
+        let mut len = 0;
+        for (dst, src) in raw[..8 - tail_len].iter_mut().zip(chars) {
+            *dst = src.to_ascii_uppercase();
+            len += 1;
+        }
+        if len == 0 {
+            raw[..3].copy_from_slice(b"APP");
+            len = 3;
+        }
+        raw[len..len + tail_len].copy_from_slice(&tail[..tail_len]);
+        ShortName { raw, case: 0 }
+    }
+
+    /// The same name with the extension `ext`.
+    fn with_extension(&self, ext: &[u8; 3]) -> ShortName {
+        let mut raw = self.raw;
+        raw[8..].copy_from_slice(ext);
+        ShortName { raw, case: 0 }
+    }
+
+    /// Write the printable form of the name (`BASE.EXT`) into `out`,
+    /// returning the number of bytes written.
+    pub fn display(&self, out: &mut [u8]) -> usize {
+        let mut len = 0;
+        let mut push = |byte: u8, lower: bool| {
+            if len < out.len() {
+                out[len] = if lower {
+                    byte.to_ascii_lowercase()
+                } else {
+                    byte
+                };
+                len += 1;
+            }
+        };
+        for &b in self.raw[..8].iter().take_while(|&&b| b != b' ') {
+            push(b, self.case & CASE_LOWER_BASE != 0);
+        }
+        if self.raw[8] != b' ' {
+            push(b'.', false);
+            for &b in self.raw[8..].iter().take_while(|&&b| b != b' ') {
+                push(b, self.case & CASE_LOWER_EXT != 0);
+            }
+        }
+        len
+    }
+}
+
+fn valid_char(c: u8) -> Result<u8, ErrorCode> {
+    if c.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&c) {
+        Ok(c.to_ascii_uppercase())
+    } else {
+        Err(ErrorCode::INVAL)
+    }
+}
+
+fn is_lower(s: &[u8]) -> bool {
+    s.iter().any(|c| c.is_ascii_lowercase()) && !s.iter().any(|c| c.is_ascii_uppercase())
+}
+
+/// A directory, identified by its first cluster. Cluster 0 is the fixed
+/// root directory of a FAT16 volume.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dir {
+    cluster: u32,
+}
+
+/// Location of a 32 byte directory entry on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EntryLoc {
+    block: u32,
+    offset: u16,
+}
+
+/// Position within a cluster chain, so walks can resume where they stopped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cursor {
+    index: u32,
+    cluster: u32,
+}
+
+/// Progress of a directory scan. Starts at the entry index given to
+/// `Scan::from()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Scan {
+    entry: u32,
+    cursor: Cursor,
+    free: Option<EntryLoc>,
+}
+
+impl Scan {
+    pub fn from(entry: u32) -> Scan {
+        Scan {
+            entry,
+            ..Scan::default()
+        }
+    }
+}
+
+/// A decoded directory entry.
+#[derive(Clone, Copy, Debug)]
+pub struct DirEntry {
+    pub name: ShortName,
+    pub size: u32,
+    attr: u8,
+    cluster: u32,
+    loc: EntryLoc,
+}
+
+impl DirEntry {
+    fn parse(e: &[u8], loc: EntryLoc) -> DirEntry {
+        let mut raw = [0; 11];
+        raw.copy_from_slice(&e[..11]);
+        DirEntry {
+            name: ShortName { raw, case: e[12] },
+            size: get_u32(e, 28),
+            attr: e[11],
+            cluster: (get_u16(e, 20) as u32) << 16 | get_u16(e, 26) as u32,
+            loc,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn dir(&self) -> Dir {
+        Dir {
+            cluster: self.cluster,
+        }
+    }
+}
+
+/// An open file. Holds the size and a cursor into the cluster chain, which
+/// operations update in place.
+#[derive(Clone, Copy, Debug)]
+pub struct File {
+    entry: EntryLoc,
+    first_cluster: u32,
+    size: u32,
+    cursor: Cursor,
+}
+
+impl File {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Whether both handles refer to the same directory entry.
+    pub fn same_file(&self, other: &DirEntry) -> bool {
+        self.entry == other.loc
+    }
+}
+
+/// Progress of `FatFs::create()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Create {
+    scan: Scan,
+    slot: Option<EntryLoc>,
+    extension: u32,
+    extension_zeroed: u32,
+    cluster: u32,
+    zeroed: u32,
+}
+
+/// Progress of `FatFs::write()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Write {
+    done: usize,
+    cluster: u32,
+}
+
+/// Progress of `FatFs::delete()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Delete {
+    scan: Scan,
+    next: Option<u32>,
+}
+
+/// Largest suffix `FatFs::claim_dir()` tries.
+pub const MAX_CLAIM_SUFFIX: u8 = 99;
+
+/// Progress of `FatFs::claim_dir()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Claim {
+    suffix: u8,
+    owner: Option<File>,
+    written: bool,
+    write: Write,
+    create: Create,
+}
+
+enum ScanResult {
+    Found(DirEntry, u32),
+    End(Option<EntryLoc>),
+}
+
+pub struct FatFs<'a> {
+    cache: BlockCache<'a>,
+    geometry: Option<Geometry>,
+    free_hint: u32,
+    free_scanned: u32,
+}
+
+impl<'a> FatFs<'a> {
+    /// Create a filesystem that caches blocks in `cache`, which should hold
+    /// at least two 512 byte blocks.
+    pub fn new(cache: &'a mut [u8]) -> FatFs<'a> {
+        FatFs {
+            cache: BlockCache::new(cache),
+            geometry: None,
+            free_hint: 2,
+            free_scanned: 0,
+        }
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.geometry.is_some()
+    }
+
+    pub fn fat_type(&self) -> Option<FatType> {
+        self.geometry.map(|g| g.fat_type)
+    }
+
+    /// Read the boot sector, following the first MBR partition if the disk
+    /// is partitioned.
+    pub fn mount(&mut self) -> Result<(), Error> {
+        if self.geometry.is_some() {
+            return Ok(());
+        }
+        let boot = self.cache.read(0)?;
+        if boot[510..512] != [0x55, 0xAA] {
+            return Err(ErrorCode::INVAL.into());
+        }
+        let start = if is_boot_sector(boot) {
+            0
+        } else {
+            match boot[0x1BE + 4] {
+                0x04 | 0x06 | 0x0B | 0x0C | 0x0E => get_u32(boot, 0x1BE + 8),
+                _ => return Err(ErrorCode::NOSUPPORT.into()),
+            }
+        };
+        let boot = self.cache.read(start)?;
+        if !is_boot_sector(boot) {
+            return Err(ErrorCode::INVAL.into());
+        }
+        let geometry = Geometry::parse(boot, start)?;
+        self.cache
+            .set_fat_region(geometry.fat_start, geometry.fat_size, geometry.num_fats);
+        self.geometry = Some(geometry);
+        self.free_hint = 2;
+        self.free_scanned = 0;
+        Ok(())
+    }
+
+    /// Forget the mounted volume and drop all cached blocks.
+    pub fn unmount(&mut self) {
+        self.geometry = None;
+        self.cache.invalidate();
+    }
+
+    pub fn root(&self) -> Result<Dir, Error> {
+        let g = self.geometry()?;
+        Ok(Dir {
+            cluster: g.root_cluster,
+        })
+    }
+
+    /// Write all modified blocks back to the disk.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.cache.flush()
+    }
+
+    pub fn has_pending_io(&self) -> bool {
+        self.cache.has_pending_io()
+    }
+
+    pub fn next_io(&self, buffer: &mut [u8]) -> Option<IoRequest> {
+        self.cache.next_io(buffer)
+    }
+
+    pub fn complete_io(&mut self, buffer: &[u8], result: Result<(), ErrorCode>) {
+        self.cache.complete_io(buffer, result)
+    }
+
+    /// Look up `name` in `dir`.
+    pub fn find(
+        &mut self,
+        dir: Dir,
+        name: &ShortName,
+        scan: &mut Scan,
+    ) -> Result<Option<DirEntry>, Error> {
+        match self.scan(dir, scan, |e| e[..11] == name.raw)? {
+            ScanResult::Found(entry, _) => Ok(Some(entry)),
+            ScanResult::End(_) => Ok(None),
+        }
+    }
+
+    /// Return the first entry at or after the scan position, skipping `.`
+    /// and `..`, together with the index to continue listing from.
+    pub fn next_entry(
+        &mut self,
+        dir: Dir,
+        scan: &mut Scan,
+    ) -> Result<Option<(DirEntry, u32)>, Error> {
+        match self.scan(dir, scan, |e| e[0] != b'.')? {
+            ScanResult::Found(entry, index) => Ok(Some((entry, index + 1))),
+            ScanResult::End(_) => Ok(None),
+        }
+    }
+
+    /// Create a file or directory called `name` in `dir`. If it already
+    /// exists the existing entry is returned, or `ALREADY` if `exclusive`.
+    pub fn create(
+        &mut self,
+        dir: Dir,
+        name: &ShortName,
+        directory: bool,
+        exclusive: bool,
+        st: &mut Create,
+    ) -> Result<DirEntry, Error> {
+        let g = self.geometry()?;
+        let slot = match st.slot {
+            Some(slot) => slot,
+            None => {
+                let slot = match self.scan(dir, &mut st.scan, |e| e[..11] == name.raw)? {
+                    ScanResult::Found(entry, _) => {
+                        return if exclusive || entry.is_dir() != directory {
+                            Err(ErrorCode::ALREADY.into())
+                        } else {
+                            Ok(entry)
+                        };
+                    }
+                    ScanResult::End(Some(slot)) => slot,
+                    ScanResult::End(None) => {
+                        // The directory is full. The fixed FAT16 root cannot
+                        // grow, others get another cluster.
+                        if dir.cluster == 0 {
+                            return Err(ErrorCode::NOMEM.into());
+                        }
+                        if st.extension == 0 {
+                            st.extension = self.allocate()?;
+                        }
+                        self.zero_cluster(st.extension, &mut st.extension_zeroed)?;
+                        self.set_fat_entry(st.scan.cursor.cluster, st.extension)?;
+                        EntryLoc {
+                            block: g.cluster_block(st.extension),
+                            offset: 0,
+                        }
+                    }
+                };
+                st.slot = Some(slot);
+                slot
+            }
+        };
+
+        let attr = if directory {
+            if st.cluster == 0 {
+                st.cluster = self.allocate()?;
+            }
+            self.zero_cluster(st.cluster, &mut st.zeroed)?;
+            let parent = if dir.cluster == g.root_cluster {
+                0
+            } else {
+                dir.cluster
+            };
+            let data = self.cache.modify(g.cluster_block(st.cluster))?;
+            write_entry(
+                &mut data[..ENTRY_SIZE],
+                b".          ",
+                0,
+                ATTR_DIRECTORY,
+                st.cluster,
+            );
+            write_entry(
+                &mut data[ENTRY_SIZE..2 * ENTRY_SIZE],
+                b"..         ",
+                0,
+                ATTR_DIRECTORY,
+                parent,
+            );
+            ATTR_DIRECTORY
+        } else {
+            ATTR_ARCHIVE
+        };
+
+        let data = self.cache.modify(slot.block)?;
+        let offset = slot.offset as usize;
+        write_entry(
+            &mut data[offset..offset + ENTRY_SIZE],
+            &name.raw,
+            name.case,
+            attr,
+            st.cluster,
+        );
+        Ok(DirEntry {
+            name: *name,
+            size: 0,
+            attr,
+            cluster: st.cluster,
+            loc: slot,
+        })
+    }
+
+    /// Open a file found with `find()` or `create()`.
+    pub fn open(&self, entry: &DirEntry) -> Result<File, Error> {
+        if entry.is_dir() {
+            return Err(ErrorCode::INVAL.into());
+        }
+        Ok(File {
+            entry: entry.loc,
+            first_cluster: entry.cluster,
+            size: entry.size,
+            cursor: Cursor::default(),
+        })
+    }
+
+    /// Read from `file` at `pos` into `buf`, returning the number of bytes
+    /// read. `done` counts the bytes already copied and must start at 0.
+    pub fn read(
+        &mut self,
+        file: &mut File,
+        pos: u32,
+        buf: &mut [u8],
+        done: &mut usize,
+    ) -> Result<usize, Error> {
+        let g = self.geometry()?;
+        let total = if pos >= file.size {
+            0
+        } else {
+            cmp::min(buf.len(), (file.size - pos) as usize)
+        };
+        while *done < total {
+            let position = pos + *done as u32;
+            let cluster = self
+                .seek(
+                    file.first_cluster,
+                    &mut file.cursor,
+                    position / g.cluster_bytes(),
+                )?
+                .ok_or(Error::Fs(ErrorCode::FAIL))?;
+            let block =
+                g.cluster_block(cluster) + (position % g.cluster_bytes()) / BLOCK_SIZE as u32;
+            let offset = position as usize % BLOCK_SIZE;
+            let len = cmp::min(BLOCK_SIZE - offset, total - *done);
+
+            let data = self.cache.read(block)?;
+            buf[*done..*done + len].copy_from_slice(&data[offset..offset + len]);
+            *done += len;
+        }
+        Ok(total)
+    }
+
+    /// Write `data` to `file` at `pos`, growing the file as needed. `pos`
+    /// may be at most the current size.
+    pub fn write(
+        &mut self,
+        file: &mut File,
+        pos: u32,
+        data: &[u8],
+        st: &mut Write,
+    ) -> Result<usize, Error> {
+        let g = self.geometry()?;
+        if pos > file.size {
+            return Err(ErrorCode::INVAL.into());
+        }
+        if (u32::MAX - pos) < data.len() as u32 || data.len() > u32::MAX as usize {
+            return Err(ErrorCode::SIZE.into());
+        }
+        while st.done < data.len() {
+            if file.first_cluster == 0 {
+                if st.cluster == 0 {
+                    st.cluster = self.allocate()?;
+                }
+                self.update_entry(file.entry, st.cluster, file.size)?;
+                file.first_cluster = st.cluster;
+                file.cursor = Cursor::default();
+                st.cluster = 0;
+            }
+
+            let position = pos + st.done as u32;
+            let index = position / g.cluster_bytes();
+            let cluster = match self.seek(file.first_cluster, &mut file.cursor, index)? {
+                Some(cluster) => cluster,
+                None => {
+                    // Writing past the last cluster, link a new one
+                    if st.cluster == 0 {
+                        st.cluster = self.allocate()?;
+                    }
+                    self.set_fat_entry(file.cursor.cluster, st.cluster)?;
+                    st.cluster = 0;
+                    continue;
+                }
+            };
+            let block =
+                g.cluster_block(cluster) + (position % g.cluster_bytes()) / BLOCK_SIZE as u32;
+            let offset = position as usize % BLOCK_SIZE;
+            let len = cmp::min(BLOCK_SIZE - offset, data.len() - st.done);
+
+            // Blocks past the end of the file hold nothing worth reading
+            let buf = if position - offset as u32 >= file.size {
+                self.cache.overwrite(block)?
+            } else {
+                self.cache.modify(block)?
+            };
+            buf[offset..offset + len].copy_from_slice(&data[st.done..st.done + len]);
+            st.done += len;
+            file.size = cmp::max(file.size, position + len as u32);
+        }
+        self.update_entry(file.entry, file.first_cluster, file.size)?;
+        Ok(data.len())
+    }
+
+    /// Find or create the directory of the process called `owner` in `dir`.
+    ///
+    /// Directory names are derived from the process name with
+    /// `ShortName::for_process()`, so they can collide. Next to every
+    /// directory, a file with the same name and the extension `OWN` holds
+    /// the full name of the process that claimed it. Suffixes are tried in
+    /// order until one is either unclaimed or claimed by `owner`.
+    pub fn claim_dir(&mut self, dir: Dir, owner: &str, st: &mut Claim) -> Result<Dir, Error> {
+        let owner_bytes = owner.as_bytes();
+        loop {
+            let name = ShortName::for_process(owner, st.suffix + 1);
+            if st.owner.is_none() {
+                let owner_name = name.with_extension(b"OWN");
+                match self.find(dir, &owner_name, &mut Scan::default())? {
+                    Some(entry) => {
+                        let file = self.open(&entry)?;
+                        // An empty file is a claim that was interrupted
+                        // before the name was written.
+                        if file.size != 0 && !self.holds(file, owner_bytes)? {
+                            if st.suffix + 1 >= MAX_CLAIM_SUFFIX {
+                                return Err(ErrorCode::NOMEM.into());
+                            }
+                            st.suffix += 1;
+                            continue;
+                        }
+                        st.written = file.size != 0;
+                        st.owner = Some(file);
+                    }
+                    None => {
+                        let entry = self.create(dir, &owner_name, false, true, &mut st.create)?;
+                        st.create = Create::default();
+                        st.owner = Some(self.open(&entry)?);
+                    }
+                }
+            }
+            if !st.written {
+                if let Some(mut file) = st.owner {
+                    let result = self.write(&mut file, 0, owner_bytes, &mut st.write);
+                    st.owner = Some(file);
+                    result?;
+                    st.written = true;
+                }
+            }
+            return self
+                .create(dir, &name, true, false, &mut st.create)
+                .map(|entry| entry.dir());
+        }
+    }
+
+    /// Whether the contents of `file` are `data`. Restarts from the
+    /// beginning on every retry, so `file` should fit in the cache.
+    fn holds(&mut self, mut file: File, data: &[u8]) -> Result<bool, Error> {
+        if file.size as usize != data.len() {
+            return Ok(false);
+        }
+        let mut chunk = [0; 32];
+        for (i, expected) in data.chunks(chunk.len()).enumerate() {
+            let buf = &mut chunk[..expected.len()];
+            let mut done = 0;
+            self.read(&mut file, (i * 32) as u32, buf, &mut done)?;
+            if buf != expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Remove the file `name` from `dir` and free its clusters.
+    pub fn delete(&mut self, dir: Dir, name: &ShortName, st: &mut Delete) -> Result<(), Error> {
+        let g = self.geometry()?;
+        if st.next.is_none() {
+            let entry = match self.scan(dir, &mut st.scan, |e| e[..11] == name.raw)? {
+                ScanResult::Found(entry, _) => entry,
+                ScanResult::End(_) => return Err(ErrorCode::NODEVICE.into()),
+            };
+            if entry.is_dir() {
+                return Err(ErrorCode::INVAL.into());
+            }
+            let data = self.cache.modify(entry.loc.block)?;
+            data[entry.loc.offset as usize] = DELETED;
+            st.next = Some(entry.cluster);
+        }
+        while let Some(cluster) = st.next {
+            if !g.is_valid_cluster(cluster) {
+                break;
+            }
+            let next = self.fat_entry(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            st.next = Some(next);
+        }
+        Ok(())
+    }
+
+    fn geometry(&self) -> Result<Geometry, Error> {
+        self.geometry.ok_or(Error::Fs(ErrorCode::OFF))
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, Error> {
+        let g = self.geometry()?;
+        let (block, offset) = g.fat_position(cluster);
+        let data = self.cache.read(block)?;
+        Ok(match g.fat_type {
+            FatType::Fat16 => get_u16(data, offset) as u32,
+            FatType::Fat32 => get_u32(data, offset) & 0x0FFF_FFFF,
+        })
+    }
+
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error> {
+        let g = self.geometry()?;
+        let (block, offset) = g.fat_position(cluster);
+        let data = self.cache.modify(block)?;
+        match g.fat_type {
+            FatType::Fat16 => put_u16(data, offset, value as u16),
+            FatType::Fat32 => {
+                // The top four bits are reserved and must be preserved
+                let old = get_u32(data, offset);
+                put_u32(data, offset, (old & 0xF000_0000) | (value & 0x0FFF_FFFF));
+            }
+        }
+        Ok(())
+    }
+
+    /// Find a free cluster and mark it as the end of a chain.
+    fn allocate(&mut self) -> Result<u32, Error> {
+        let g = self.geometry()?;
+        while self.free_scanned < g.cluster_count {
+            let cluster = self.free_hint;
+            let next = if cluster + 1 >= g.cluster_count + 2 {
+                2
+            } else {
+                cluster + 1
+            };
+            if self.fat_entry(cluster)? == 0 {
+                self.set_fat_entry(cluster, g.end_of_chain())?;
+                self.free_hint = next;
+                self.free_scanned = 0;
+                return Ok(cluster);
+            }
+            self.free_hint = next;
+            self.free_scanned += 1;
+        }
+        self.free_scanned = 0;
+        Err(ErrorCode::NOMEM.into())
+    }
+
+    fn zero_cluster(&mut self, cluster: u32, zeroed: &mut u32) -> Result<(), Error> {
+        let g = self.geometry()?;
+        while *zeroed < g.sectors_per_cluster {
+            self.cache.overwrite(g.cluster_block(cluster) + *zeroed)?;
+            *zeroed += 1;
+        }
+        Ok(())
+    }
+
+    /// Follow the chain starting at `first` to its `index`th cluster. On
+    /// `None` the chain is shorter and the cursor rests on its last cluster.
+    fn seek(&mut self, first: u32, cursor: &mut Cursor, index: u32) -> Result<Option<u32>, Error> {
+        let g = self.geometry()?;
+        if !g.is_valid_cluster(first) {
+            return Ok(None);
+        }
+        if cursor.cluster == 0 || index < cursor.index {
+            *cursor = Cursor {
+                index: 0,
+                cluster: first,
+            };
+        }
+        while cursor.index < index {
+            let next = self.fat_entry(cursor.cluster)?;
+            if g.is_end_of_chain(next) {
+                return Ok(None);
+            }
+            if !g.is_valid_cluster(next) {
+                return Err(ErrorCode::FAIL.into());
+            }
+            cursor.cluster = next;
+            cursor.index += 1;
+        }
+        Ok(Some(cursor.cluster))
+    }
+
+    /// Block holding the `sector`th block of a directory.
+    fn dir_block(
+        &mut self,
+        dir: Dir,
+        cursor: &mut Cursor,
+        sector: u32,
+    ) -> Result<Option<u32>, Error> {
+        let g = self.geometry()?;
+        if dir.cluster == 0 {
+            return Ok(if sector < g.root_sectors {
+                Some(g.root_start + sector)
+            } else {
+                None
+            });
+        }
+        let spc = g.sectors_per_cluster;
+        Ok(self
+            .seek(dir.cluster, cursor, sector / spc)?
+            .map(|cluster| g.cluster_block(cluster) + sector % spc))
+    }
+
+    /// Walk the entries of `dir` until `matches` accepts one. Reports the
+    /// first reusable slot if the end of the directory is reached.
+    fn scan<F: Fn(&[u8]) -> bool>(
+        &mut self,
+        dir: Dir,
+        scan: &mut Scan,
+        matches: F,
+    ) -> Result<ScanResult, Error> {
+        loop {
+            let sector = scan.entry / ENTRIES_PER_BLOCK;
+            let block = match self.dir_block(dir, &mut scan.cursor, sector)? {
+                Some(block) => block,
+                None => return Ok(ScanResult::End(scan.free)),
+            };
+            let data = self.cache.read(block)?;
+            for i in (scan.entry % ENTRIES_PER_BLOCK)..ENTRIES_PER_BLOCK {
+                let offset = i as usize * ENTRY_SIZE;
+                let e = &data[offset..offset + ENTRY_SIZE];
+                let loc = EntryLoc {
+                    block,
+                    offset: offset as u16,
+                };
+                if e[0] == 0 {
+                    // Marks the end of the directory
+                    return Ok(ScanResult::End(scan.free.or(Some(loc))));
+                } else if e[0] == DELETED {
+                    scan.free = scan.free.or(Some(loc));
+                } else if e[11] & ATTR_VOLUME_ID == 0 && matches(e) {
+                    // Volume labels and long name entries are skipped
+                    let index = sector * ENTRIES_PER_BLOCK + i;
+                    return Ok(ScanResult::Found(DirEntry::parse(e, loc), index));
+                }
+            }
+            scan.entry = (sector + 1) * ENTRIES_PER_BLOCK;
+        }
+    }
+
+    fn update_entry(&mut self, loc: EntryLoc, cluster: u32, size: u32) -> Result<(), Error> {
+        let data = self.cache.modify(loc.block)?;
+        let e = &mut data[loc.offset as usize..loc.offset as usize + ENTRY_SIZE];
+        e[11] |= ATTR_ARCHIVE;
+        put_u16(e, 20, (cluster >> 16) as u16);
+        put_u16(e, 26, cluster as u16);
+        put_u32(e, 28, size);
+        Ok(())
+    }
+}
+
+fn is_boot_sector(b: &[u8]) -> bool {
+    (b[0] == 0xEB || b[0] == 0xE9) && get_u16(b, 11) as usize == BLOCK_SIZE && b[16] != 0
+}
+
+fn write_entry(e: &mut [u8], name: &[u8; 11], case: u8, attr: u8, cluster: u32) {
+    for byte in e.iter_mut() {
+        *byte = 0;
+    }
+    e[..11].copy_from_slice(name);
+    e[11] = attr;
+    e[12] = case;
+    put_u16(e, 16, DEFAULT_DATE);
+    put_u16(e, 18, DEFAULT_DATE);
+    put_u16(e, 20, (cluster >> 16) as u16);
+    put_u16(e, 24, DEFAULT_DATE);
+    put_u16(e, 26, cluster as u16);
+}
+
+fn get_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, val: u16) {
+    buf[offset..offset + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, val: u32) {
+    buf[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+}
@@ -0,0 +1,749 @@
+//! Flash translation layer that spreads writes evenly over a flash region.
+//!
+//! This presents a region of flash erase units, called pages here, as
+//! byte-addressed nonvolatile
+//! storage. Unlike `NonvolatileToPages`, a logical page is never rewritten in
+//! place: every write programs a free physical page, and the page holding the
+//! previous copy only becomes free once the new copy is complete. Frequently
+//! written data therefore moves around the whole region instead of wearing out
+//! a single page, and a write interrupted by a power loss leaves the previous
+//! copy intact. Pages are read, erased and written whole through the
+//! `FlashRange` interface. While it is handling a read or write it returns
+//! `BUSY` to all additional requests.
+//!
+//! ```plain
+//! hil::nonvolatile_storage::NonvolatileStorage
+//!                ┌─────────────┐
+//!                │             │
+//!                │ This module │
+//!                │             │
+//!                └─────────────┘
+//!             hil::flash::FlashRange
+//! ```
+//!
+//! Page format
+//! -----------
+//!
+//! Every physical page starts with a header, followed by the data of one
+//! logical page:
+//!
+//! ```plain
+//! 0       4         6          8          12            16       20
+//! +-------+---------+----------+----------+-------------+--------+---------
+//! | magic | logical | reserved | sequence | erase count | CRC-32 | data ...
+//! +-------+---------+----------+----------+-------------+--------+---------
+//! ```
+//!
+//! The headers are the persisted mapping table. `mount()` reads every page and
+//! maps each logical page to the valid page with the highest sequence number.
+//! The CRC covers the header and the data, so a torn page is ignored.
+//!
+//! Each logical page holds `HEADER_LEN` bytes less than a flash page, and the
+//! region provides `spare` fewer logical pages than it has physical pages.
+//! Logical pages that were never written read as 0xFF.
+//!
+//! Wear leveling
+//! -------------
+//!
+//! Writes go to the free page that was erased the fewest times. Once the most
+//! worn page has been erased `WEAR_THRESHOLD` times more than the least worn
+//! page holding data, that data is moved to the most worn free page after the
+//! next write, so that data which rarely changes does not keep a barely used
+//! page to itself. Pages without a valid header are assumed to be as worn as
+//! the most worn page.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use core::cell::Cell;
+//! # use kernel::{hil, static_init};
+//!
+//! static mut PAGES: [Cell<capsules::ftl::PageInfo>; 16] = [capsules::ftl::UNUSED_PAGE; 16];
+//! pub static mut PAGEBUFFER: [u8; 512] = [0; 512];
+//! let ftl = static_init!(
+//!     capsules::ftl::FlashTranslationLayer<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::ftl::FlashTranslationLayer::new(
+//!         &sam4l::flashcalw::FLASH_CONTROLLER,
+//!         &mut PAGEBUFFER,
+//!         0x60000,
+//!         &PAGES,
+//!         2,
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! hil::flash::FlashRange::set_range_client(&sam4l::flashcalw::FLASH_CONTROLLER, ftl);
+//! ftl.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ftl)
+//!         .expect("no deferred call slot available for the FTL"),
+//! );
+//! ftl.mount();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::math::crc32;
+use kernel::hil;
+use kernel::ErrorCode;
+
+/// Length of the header at the start of every physical page.
+pub const HEADER_LEN: usize = 20;
+/// Difference in erase counts at which data that rarely changes is moved.
+pub const WEAR_THRESHOLD: u32 = 8;
+
+const MAGIC: u32 = 0x314C_5446; // "FTL1"
+const CRC_OFFSET: usize = 16;
+
+/// What is known about a physical page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PageInfo {
+    /// Logical page of which this page holds the current copy.
+    logical: Option<u16>,
+    sequence: u32,
+    erase_count: u32,
+    /// Whether the page had a valid header when it was last read.
+    valid: bool,
+}
+
+/// Initial value for the page table passed to `FlashTranslationLayer::new`.
+pub const UNUSED_PAGE: Cell<PageInfo> = Cell::new(PageInfo {
+    logical: None,
+    sequence: 0,
+    erase_count: 0,
+    valid: false,
+});
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Unmounted,
+    /// Reading the header of a physical page.
+    Mount {
+        page: usize,
+    },
+    Idle,
+    /// Reading, between logical pages or waiting for a page from flash.
+    Read,
+    /// Writing, between logical pages.
+    Write,
+    /// Reading the current copy of a page that is partially overwritten.
+    WriteRead,
+    /// Reading a page with data that rarely changes to move it.
+    Relocate,
+    Erase,
+    Program,
+}
+
+fn page_crc(page: &[u8]) -> u32 {
+    !crc32(crc32(0xFFFF_FFFF, &page[..CRC_OFFSET]), &page[HEADER_LEN..])
+}
+
+fn read_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        page[offset],
+        page[offset + 1],
+        page[offset + 2],
+        page[offset + 3],
+    ])
+}
+
+/// Parses the header of a page read from flash.
+fn parse_header(page: &[u8]) -> PageInfo {
+    let valid = page.len() > HEADER_LEN
+        && read_u32(page, 0) == MAGIC
+        && read_u32(page, CRC_OFFSET) == page_crc(page);
+    if valid {
+        PageInfo {
+            logical: Some(u16::from_le_bytes([page[4], page[5]])),
+            sequence: read_u32(page, 8),
+            erase_count: read_u32(page, 12),
+            valid: true,
+        }
+    } else {
+        PageInfo {
+            logical: None,
+            sequence: 0,
+            erase_count: 0,
+            valid: false,
+        }
+    }
+}
+
+pub struct FlashTranslationLayer<'a, F: hil::flash::FlashRange<'a>> {
+    /// The module providing a `FlashRange` interface.
+    driver: &'a F,
+    /// Callback to the user of this capsule.
+    client: OptionalCell<&'static dyn hil::nonvolatile_storage::NonvolatileStorageClient<'static>>,
+    /// Buffer the size of an erase unit of the flash.
+    pagebuffer: TakeCell<'static, [u8]>,
+    page_size: usize,
+    /// Flash address of the start of the region.
+    start: usize,
+    /// One entry per physical page of the region.
+    pages: &'a [Cell<PageInfo>],
+    /// Number of logical pages provided.
+    logical_pages: usize,
+    /// Sequence number of the next page written.
+    sequence: Cell<u32>,
+    state: Cell<State>,
+    /// Physical page being written.
+    target: Cell<usize>,
+    /// Logical page being written.
+    logical: Cell<usize>,
+    /// Temporary holding place for the user's buffer.
+    buffer: TakeCell<'static, [u8]>,
+    /// Logical address of where we are reading or writing.
+    address: Cell<usize>,
+    /// Total length to read or write.
+    length: Cell<usize>,
+    /// How many bytes are left to read or write.
+    remaining_length: Cell<usize>,
+    /// Where we are in the user buffer.
+    buffer_index: Cell<usize>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a, F: hil::flash::FlashRange<'a>> FlashTranslationLayer<'a, F> {
+    /// Creates a translation layer over the flash pages starting at address
+    /// `start`, one for every entry of `pages`. Pages are the size of
+    /// `pagebuffer`, which must be the size of the erase units of the region.
+    /// `spare` of them are not used for logical pages; at least one is needed
+    /// to be able to write.
+    pub fn new(
+        driver: &'a F,
+        pagebuffer: &'static mut [u8],
+        start: usize,
+        pages: &'a [Cell<PageInfo>],
+        spare: usize,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> FlashTranslationLayer<'a, F> {
+        let page_size = pagebuffer.len();
+        FlashTranslationLayer {
+            driver,
+            client: OptionalCell::empty(),
+            pagebuffer: TakeCell::new(pagebuffer),
+            page_size,
+            start,
+            pages,
+            logical_pages: cmp::min(
+                pages.len().saturating_sub(cmp::max(spare, 1)),
+                u16::MAX as usize,
+            ),
+            sequence: Cell::new(0),
+            state: Cell::new(State::Unmounted),
+            target: Cell::new(0),
+            logical: Cell::new(0),
+            buffer: TakeCell::empty(),
+            address: Cell::new(0),
+            length: Cell::new(0),
+            remaining_length: Cell::new(0),
+            buffer_index: Cell::new(0),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Number of bytes stored per logical page.
+    pub fn data_size(&self) -> usize {
+        self.page_size.saturating_sub(HEADER_LEN)
+    }
+
+    /// Number of bytes that can be stored.
+    pub fn capacity(&self) -> usize {
+        self.logical_pages * self.data_size()
+    }
+
+    /// Number of times a physical page of the region has been erased.
+    pub fn erase_count(&self, page: usize) -> Option<u32> {
+        self.pages.get(page).map(|info| info.get().erase_count)
+    }
+
+    /// Flash address of a physical page of the region.
+    fn page_address(&self, page: usize) -> usize {
+        self.start + page * self.page_size
+    }
+
+    /// Reads the headers of all pages to rebuild the mapping. Reads and writes
+    /// fail with `RESERVE` before and `BUSY` while mounting. Fails with
+    /// `INVAL` if the pages are not erase units of the flash.
+    pub fn mount(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Unmounted | State::Idle => {}
+            _ => return Err(ErrorCode::BUSY),
+        }
+        if self.data_size() == 0 || self.pages.is_empty() {
+            return Err(ErrorCode::SIZE);
+        }
+        let erase_units = (0..self.pages.len()).all(|page| {
+            let address = self.page_address(page);
+            self.driver.erase_unit(address) == Some((address, self.page_size))
+        });
+        if !erase_units {
+            return Err(ErrorCode::INVAL);
+        }
+        for info in self.pages.iter() {
+            info.set(UNUSED_PAGE.get());
+        }
+        self.mount_page(0)
+    }
+
+    fn mount_page(&self, page: usize) -> Result<(), ErrorCode> {
+        self.state.set(State::Mount { page });
+        self.pagebuffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), move |pagebuffer| {
+                let address = self.page_address(page);
+                match self.driver.read(address, pagebuffer, self.page_size) {
+                    Ok(()) => Ok(()),
+                    Err((return_code, pagebuffer)) => {
+                        self.pagebuffer.replace(pagebuffer);
+                        self.state.set(State::Unmounted);
+                        Err(return_code)
+                    }
+                }
+            })
+    }
+
+    /// Keeps the newest copy of every logical page once all headers are read.
+    fn finish_mount(&self) {
+        let mut max_erase_count = 0;
+        let mut max_sequence = 0;
+        for (i, page) in self.pages.iter().enumerate() {
+            let mut info = page.get();
+            if !info.valid {
+                continue;
+            }
+            max_erase_count = cmp::max(max_erase_count, info.erase_count);
+            max_sequence = cmp::max(max_sequence, info.sequence);
+            let superseded = info.logical.map_or(true, |logical| {
+                logical as usize >= self.logical_pages
+                    || self.pages.iter().enumerate().any(|(j, other)| {
+                        let other = other.get();
+                        j != i
+                            && other.valid
+                            && other.logical == Some(logical)
+                            && (other.sequence > info.sequence
+                                || (other.sequence == info.sequence && j > i))
+                    })
+            });
+            if superseded {
+                info.logical = None;
+                page.set(info);
+            }
+        }
+        for page in self.pages.iter() {
+            let mut info = page.get();
+            if !info.valid {
+                info.erase_count = max_erase_count;
+                page.set(info);
+            }
+        }
+        self.sequence.set(max_sequence.wrapping_add(1));
+        self.state.set(State::Idle);
+    }
+
+    /// Physical page holding the current copy of a logical page.
+    fn physical_page(&self, logical: usize) -> Option<usize> {
+        self.pages
+            .iter()
+            .position(|info| info.get().logical == Some(logical as u16))
+    }
+
+    /// Free page with the fewest or the most erases.
+    fn free_page(&self, most_worn: bool) -> Option<usize> {
+        let free = self
+            .pages
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| info.get().logical.is_none());
+        if most_worn {
+            free.max_by_key(|(_, info)| info.get().erase_count)
+                .map(|(i, _)| i)
+        } else {
+            free.min_by_key(|(_, info)| info.get().erase_count)
+                .map(|(i, _)| i)
+        }
+    }
+
+    fn start_operation(
+        &self,
+        state: State,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Idle => {}
+            State::Unmounted => return Err(ErrorCode::RESERVE),
+            _ => return Err(ErrorCode::BUSY),
+        }
+        if length > buffer.len()
+            || address
+                .checked_add(length)
+                .map_or(true, |end| end > self.capacity())
+        {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.handle.is_none() {
+            return Err(ErrorCode::FAIL);
+        }
+
+        self.state.set(state);
+        self.buffer.replace(buffer);
+        self.address.set(address);
+        self.length.set(length);
+        self.remaining_length.set(length);
+        self.buffer_index.set(0);
+
+        // Start from a deferred call so the client never gets a callback
+        // before `read` or `write` returns.
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
+    }
+
+    /// Offset into the current logical page and number of bytes of the
+    /// operation that fall within it.
+    fn current_span(&self) -> (usize, usize) {
+        let offset = self.address.get() % self.data_size();
+        let len = cmp::min(self.data_size() - offset, self.remaining_length.get());
+        (offset, len)
+    }
+
+    fn advance(&self, len: usize) {
+        self.address.set(self.address.get() + len);
+        self.remaining_length.set(self.remaining_length.get() - len);
+        self.buffer_index.set(self.buffer_index.get() + len);
+    }
+
+    /// Continues a read or write with the next logical page.
+    fn step(&self) {
+        loop {
+            match self.state.get() {
+                State::Read => {
+                    if self.remaining_length.get() == 0 {
+                        self.finish(false);
+                        return;
+                    }
+                    let logical = self.address.get() / self.data_size();
+                    match self.physical_page(logical) {
+                        Some(page) => {
+                            self.read_page(page, false);
+                            return;
+                        }
+                        None => {
+                            // Never written, reads as erased flash
+                            let (_, len) = self.current_span();
+                            let index = self.buffer_index.get();
+                            self.buffer.map(|buffer| {
+                                buffer[index..index + len]
+                                    .iter_mut()
+                                    .for_each(|byte| *byte = 0xFF)
+                            });
+                            self.advance(len);
+                        }
+                    }
+                }
+                State::Write => {
+                    if self.remaining_length.get() == 0 {
+                        if !self.start_relocation() {
+                            self.finish(true);
+                        }
+                        return;
+                    }
+                    let logical = self.address.get() / self.data_size();
+                    let (_, len) = self.current_span();
+                    self.logical.set(logical);
+                    match self.physical_page(logical) {
+                        Some(page) if len < self.data_size() => {
+                            // Keep the rest of the current copy
+                            self.state.set(State::WriteRead);
+                            self.read_page(page, true);
+                        }
+                        _ => {
+                            self.pagebuffer.map(|pagebuffer| {
+                                pagebuffer.iter_mut().for_each(|byte| *byte = 0xFF);
+                                self.merge(pagebuffer);
+                            });
+                            self.erase_target(false);
+                        }
+                    }
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn read_page(&self, page: usize, write: bool) {
+        match self.pagebuffer.take() {
+            Some(pagebuffer) => {
+                if let Err((_, pagebuffer)) =
+                    self.driver
+                        .read(self.page_address(page), pagebuffer, self.page_size)
+                {
+                    self.pagebuffer.replace(pagebuffer);
+                    self.finish(write);
+                }
+            }
+            None => self.finish(write),
+        }
+    }
+
+    /// Copies the bytes of the write that fall within the current logical
+    /// page into the page buffer.
+    fn merge(&self, page: &mut [u8]) {
+        let (offset, len) = self.current_span();
+        let index = self.buffer_index.get();
+        self.buffer.map(|buffer| {
+            page[HEADER_LEN + offset..HEADER_LEN + offset + len]
+                .copy_from_slice(&buffer[index..index + len]);
+        });
+    }
+
+    /// Moves the data of the least worn page holding data to the most worn
+    /// free page, if their erase counts have drifted too far apart.
+    fn start_relocation(&self) -> bool {
+        let max_erase_count = self
+            .pages
+            .iter()
+            .map(|info| info.get().erase_count)
+            .max()
+            .unwrap_or(0);
+        let coldest = self
+            .pages
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| info.get().logical.is_some())
+            .min_by_key(|(_, info)| info.get().erase_count);
+        match coldest {
+            Some((page, info))
+                if max_erase_count - info.get().erase_count >= WEAR_THRESHOLD
+                    && self.free_page(true).is_some() =>
+            {
+                self.logical
+                    .set(info.get().logical.map_or(0, |logical| logical as usize));
+                self.state.set(State::Relocate);
+                self.read_page(page, true);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Erases the page that the page buffer will be written to.
+    fn erase_target(&self, relocating: bool) {
+        let target = match self.free_page(relocating) {
+            Some(target) => target,
+            None => return self.finish(true),
+        };
+        self.target.set(target);
+        self.state.set(State::Erase);
+        if self.driver.erase(self.page_address(target)).is_err() {
+            self.finish(true);
+        }
+    }
+
+    /// Writes the page buffer to the erased target page.
+    fn program_target(&self) {
+        let target = self.target.get();
+        let mut info = self.pages[target].get();
+        info.erase_count = info.erase_count.saturating_add(1);
+        info.sequence = self.sequence.get();
+        self.pages[target].set(info);
+        self.sequence.set(info.sequence.wrapping_add(1));
+
+        match self.pagebuffer.take() {
+            Some(page) => {
+                page[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+                page[4..6].copy_from_slice(&(self.logical.get() as u16).to_le_bytes());
+                page[6..8].copy_from_slice(&[0xFF, 0xFF]);
+                page[8..12].copy_from_slice(&info.sequence.to_le_bytes());
+                page[12..16].copy_from_slice(&info.erase_count.to_le_bytes());
+                let crc = page_crc(page);
+                page[CRC_OFFSET..HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+
+                self.state.set(State::Program);
+                if let Err((_, pagebuffer)) =
+                    self.driver
+                        .write(self.page_address(target), page, self.page_size)
+                {
+                    self.pagebuffer.replace(pagebuffer);
+                    self.finish(true);
+                }
+            }
+            None => self.finish(true),
+        }
+    }
+
+    /// Maps the logical page to the page that was just written and frees the
+    /// page with the previous copy.
+    fn commit_target(&self) {
+        let logical = self.logical.get();
+        if let Some(old) = self.physical_page(logical) {
+            let mut info = self.pages[old].get();
+            info.logical = None;
+            self.pages[old].set(info);
+        }
+        let target = self.target.get();
+        let mut info = self.pages[target].get();
+        info.logical = Some(logical as u16);
+        info.valid = true;
+        self.pages[target].set(info);
+    }
+
+    /// Ends the operation, reporting the bytes handled so far.
+    fn finish(&self, write: bool) {
+        let length = self.length.get() - self.remaining_length.get();
+        self.state.set(State::Idle);
+        self.buffer.take().map(|buffer| {
+            self.client.map(move |client| {
+                if write {
+                    client.write_done(buffer, length);
+                } else {
+                    client.read_done(buffer, length);
+                }
+            });
+        });
+    }
+}
+
+impl<'a, F: hil::flash::FlashRange<'a>> hil::nonvolatile_storage::NonvolatileStorage<'static>
+    for FlashTranslationLayer<'a, F>
+{
+    fn set_client(&self, client: &'static dyn hil::nonvolatile_storage::NonvolatileStorageClient) {
+        self.client.set(client);
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        self.start_operation(State::Read, buffer, address, length)
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        self.start_operation(State::Write, buffer, address, length)
+    }
+}
+
+impl<'a, F: hil::flash::FlashRange<'a>> hil::flash::RangeClient for FlashTranslationLayer<'a, F> {
+    fn read_done(
+        &self,
+        pagebuffer: &'static mut [u8],
+        _length: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        let ok = result.is_ok();
+        match self.state.get() {
+            State::Mount { page } => {
+                if ok {
+                    self.pages[page].set(parse_header(pagebuffer));
+                }
+                self.pagebuffer.replace(pagebuffer);
+                if page + 1 < self.pages.len() {
+                    let _ = self.mount_page(page + 1);
+                } else {
+                    self.finish_mount();
+                }
+            }
+            State::Read => {
+                self.pagebuffer.replace(pagebuffer);
+                if !ok {
+                    return self.finish(false);
+                }
+                let (offset, len) = self.current_span();
+                let index = self.buffer_index.get();
+                self.pagebuffer.map(|page| {
+                    self.buffer.map(|buffer| {
+                        buffer[index..index + len]
+                            .copy_from_slice(&page[HEADER_LEN + offset..HEADER_LEN + offset + len]);
+                    });
+                });
+                self.advance(len);
+                self.step();
+            }
+            State::WriteRead => {
+                self.merge(pagebuffer);
+                self.pagebuffer.replace(pagebuffer);
+                if ok {
+                    self.erase_target(false);
+                } else {
+                    self.finish(true);
+                }
+            }
+            State::Relocate => {
+                self.pagebuffer.replace(pagebuffer);
+                if ok {
+                    self.erase_target(true);
+                } else {
+                    self.finish(true);
+                }
+            }
+            _ => {
+                self.pagebuffer.replace(pagebuffer);
+            }
+        }
+    }
+
+    fn write_done(
+        &self,
+        pagebuffer: &'static mut [u8],
+        _length: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.pagebuffer.replace(pagebuffer);
+        if self.state.get() != State::Program {
+            return;
+        }
+        if result.is_err() {
+            return self.finish(true);
+        }
+
+        self.commit_target();
+        if self.remaining_length.get() == 0 {
+            // A relocation after the last page of the write
+            self.finish(true);
+        } else {
+            let (_, len) = self.current_span();
+            self.advance(len);
+            self.state.set(State::Write);
+            self.step();
+        }
+    }
+
+    fn erase_done(&self, result: Result<(), ErrorCode>) {
+        if self.state.get() != State::Erase {
+            return;
+        }
+        if result.is_ok() {
+            self.program_target();
+        } else {
+            self.finish(true);
+        }
+    }
+}
+
+impl<'a, F: hil::flash::FlashRange<'a>> DynamicDeferredCallClient for FlashTranslationLayer<'a, F> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.step();
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,212 @@
+//! Tests of the flash translation layer against an emulated flash that counts
+//! erases and can lose power in the middle of a page write.
+
+extern crate std;
+
+use std::cell::Cell;
+use std::vec;
+use std::vec::Vec;
+
+use kernel::common::cells::TakeCell;
+use kernel::common::dynamic_deferred_call::{DeferredCallHandle, DynamicDeferredCallClient};
+use kernel::hil::flash::FlashRange;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::ErrorCode;
+
+use super::{FlashTranslationLayer, HEADER_LEN, UNUSED_PAGE, WEAR_THRESHOLD};
+use crate::emulated_flash::{self, Programming};
+use crate::test_support::{buffer, deferred_caller, leak};
+
+const PAGE_SIZE: usize = 64;
+const PAGES: usize = 8;
+const SPARE: usize = 2;
+/// The region does not start at the beginning of the flash
+const FIRST_PAGE: usize = 4;
+const DATA_SIZE: usize = PAGE_SIZE - HEADER_LEN;
+const CAPACITY: usize = (PAGES - SPARE) * DATA_SIZE;
+
+type EmulatedFlash = emulated_flash::EmulatedFlash<PAGE_SIZE>;
+
+struct Client {
+    buffer: TakeCell<'static, [u8]>,
+    read: Cell<Option<usize>>,
+    written: Cell<Option<usize>>,
+}
+
+impl NonvolatileStorageClient<'static> for Client {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.read.set(Some(length));
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.written.set(Some(length));
+    }
+}
+
+struct Device {
+    ftl: &'static FlashTranslationLayer<'static, EmulatedFlash>,
+    flash: &'static EmulatedFlash,
+    client: &'static Client,
+    handle: DeferredCallHandle,
+}
+
+impl Device {
+    fn blank() -> Device {
+        Device::boot(EmulatedFlash::leak(
+            vec![0xFF; PAGE_SIZE * PAGES],
+            FIRST_PAGE,
+            Programming::ClearBits,
+        ))
+    }
+
+    /// Mount the translation layer over the flash.
+    fn boot(flash: &'static EmulatedFlash) -> Device {
+        let deferred_caller = deferred_caller();
+        let ftl: &'static FlashTranslationLayer<'static, EmulatedFlash> =
+            leak(FlashTranslationLayer::new(
+                flash,
+                buffer(PAGE_SIZE),
+                FIRST_PAGE * PAGE_SIZE,
+                leak([UNUSED_PAGE; PAGES]),
+                SPARE,
+                deferred_caller,
+            ));
+        flash.set_range_client(ftl);
+        let handle = deferred_caller.register(ftl).unwrap();
+        ftl.initialize_callback_handle(handle);
+
+        let client = leak(Client {
+            buffer: TakeCell::new(buffer(CAPACITY)),
+            read: Cell::new(None),
+            written: Cell::new(None),
+        });
+        ftl.set_client(client);
+
+        ftl.mount().unwrap();
+        flash.run();
+
+        Device {
+            ftl,
+            flash,
+            client,
+            handle,
+        }
+    }
+
+    fn reboot(&self) -> Device {
+        Device::boot(self.flash.power_cycle())
+    }
+
+    /// Let the deferred call that starts an operation and all flash
+    /// operations run.
+    fn run(&self) {
+        self.ftl.call(self.handle);
+        self.flash.run();
+    }
+
+    fn write(&self, address: usize, data: &[u8]) {
+        let buffer = self.client.buffer.take().unwrap();
+        buffer[..data.len()].copy_from_slice(data);
+        self.ftl.write(buffer, address, data.len()).unwrap();
+        self.run();
+        assert_eq!(self.client.written.take(), Some(data.len()));
+    }
+
+    /// Write, but lose power after `written` bytes of the first page reached
+    /// flash.
+    fn write_torn(&self, address: usize, data: &[u8], written: usize) {
+        self.flash.tear_after.set(Some(written));
+        let buffer = self.client.buffer.take().unwrap();
+        buffer[..data.len()].copy_from_slice(data);
+        self.ftl.write(buffer, address, data.len()).unwrap();
+        self.run();
+        assert_eq!(self.client.written.take(), None);
+    }
+
+    fn read(&self, address: usize, length: usize) -> Vec<u8> {
+        let buffer = self.client.buffer.take().unwrap();
+        self.ftl.read(buffer, address, length).unwrap();
+        self.run();
+        assert_eq!(self.client.read.take(), Some(length));
+        self.client.buffer.map(|b| b[..length].to_vec()).unwrap()
+    }
+}
+
+fn pattern(seed: u8, length: usize) -> Vec<u8> {
+    (0..length).map(|i| seed.wrapping_add(i as u8)).collect()
+}
+
+#[test]
+fn writes_survive_remount() {
+    let device = Device::blank();
+    assert_eq!(device.read(0, CAPACITY), vec![0xFF; CAPACITY]);
+
+    // Unaligned write across three logical pages
+    let data = pattern(1, 2 * DATA_SIZE);
+    device.write(30, &data);
+    device.write(DATA_SIZE * 5, &pattern(7, DATA_SIZE));
+
+    let device = device.reboot();
+    let mut expected = vec![0xFF; CAPACITY];
+    expected[30..30 + data.len()].copy_from_slice(&data);
+    expected[DATA_SIZE * 5..].copy_from_slice(&pattern(7, DATA_SIZE));
+    assert_eq!(device.read(0, CAPACITY), expected);
+}
+
+#[test]
+fn rejects_out_of_range_requests() {
+    let device = Device::blank();
+    let buffer = device.client.buffer.take().unwrap();
+    assert_eq!(
+        device.ftl.write(buffer, CAPACITY - 4, 8),
+        Err(ErrorCode::INVAL)
+    );
+}
+
+#[test]
+fn torn_write_keeps_previous_copy() {
+    let device = Device::blank();
+    device.write(DATA_SIZE, &pattern(1, DATA_SIZE));
+    // Power fails after the header and part of the data reached flash
+    device.write_torn(DATA_SIZE + 4, &pattern(9, 8), HEADER_LEN + 10);
+
+    let device = device.reboot();
+    assert_eq!(device.read(DATA_SIZE, DATA_SIZE), pattern(1, DATA_SIZE));
+
+    device.write(DATA_SIZE + 4, &pattern(9, 8));
+    let device = device.reboot();
+    let mut expected = pattern(1, DATA_SIZE);
+    expected[4..12].copy_from_slice(&pattern(9, 8));
+    assert_eq!(device.read(DATA_SIZE, DATA_SIZE), expected);
+}
+
+#[test]
+fn hot_page_wear_is_spread() {
+    let device = Device::blank();
+    let cold = pattern(3, CAPACITY);
+    device.write(0, &cold);
+
+    // Keep rewriting a single byte
+    for n in 0..500 {
+        device.write(10, &[n as u8]);
+    }
+
+    let erases = device.flash.erases.borrow().clone();
+    let most = *erases.iter().max().unwrap();
+    let least = *erases.iter().min().unwrap();
+    assert!(
+        most - least <= WEAR_THRESHOLD + 1,
+        "uneven wear: {:?}",
+        erases
+    );
+
+    let device = device.reboot();
+    for (page, &count) in device.flash.erases.borrow().iter().enumerate() {
+        assert_eq!(device.ftl.erase_count(page), Some(count));
+    }
+    let mut expected = cold;
+    expected[10] = (499 % 256) as u8;
+    assert_eq!(device.read(0, CAPACITY), expected);
+}
@@ -4,6 +4,15 @@
 
 pub mod test;
 
+#[cfg(test)]
+mod emulated_alarm;
+#[cfg(test)]
+mod emulated_flash;
+#[cfg(test)]
+mod emulated_usb;
+#[cfg(test)]
+mod test_support;
+
 #[macro_use]
 pub mod net;
 
@@ -16,6 +25,8 @@ pub mod analog_sensor;
 pub mod apds9960;
 pub mod app_flash_driver;
 pub mod ble_advertising_driver;
+pub mod ble_gatt;
+pub mod ble_link_layer;
 pub mod bus;
 pub mod button;
 pub mod buzzer_driver;
@@ -25,8 +36,11 @@ pub mod ctap;
 pub mod dac;
 pub mod debug_process_restart;
 pub mod driver;
+pub mod encrypted_flash;
+pub mod fat;
 pub mod fm25cl;
 pub mod ft6x06;
+pub mod ftl;
 pub mod fxos8700cq;
 pub mod gpio;
 pub mod gpio_async;
@@ -42,6 +56,7 @@ pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
 pub mod log;
+pub mod log_driver;
 pub mod low_level_debug;
 pub mod lps25hb;
 pub mod lsm303agr;
@@ -70,6 +85,7 @@ pub mod sht3x;
 pub mod si7021;
 pub mod sound_pressure;
 pub mod spi_controller;
+pub mod spi_nor;
 pub mod spi_peripheral;
 pub mod st77xx;
 pub mod temperature;
@@ -79,6 +95,7 @@ pub mod tickv;
 pub mod touch;
 pub mod tsl2561;
 pub mod usb;
+pub mod usb_hid_driver;
 pub mod virtual_adc;
 pub mod virtual_aes_ccm;
 pub mod virtual_alarm;
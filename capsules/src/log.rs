@@ -33,14 +33,35 @@
 //!     * Erase:    Erase a log in its entirety, clearing the underlying flash volume.
 //! See the documentation for each individual function for more detail on how they operate.
 //!
-//! Note that while logs persist across reboots, they will be erased upon flashing a new kernel.
+//! Note that while logs persist across reboots, they will be erased upon flashing a new kernel,
+//! unless their volume is the `region()` of a partition from `kernel::partitions`.
+//!
+//! Logs are written through the `FlashRange` interface, and read directly from the memory-mapped
+//! volume. The flash controller addresses the volume from the address passed to `Log::new()`,
+//! such as the `offset()` of the partition whose region is the volume. Every page of the log is a
+//! single erase unit of the flash, which is erased before its first write. Later syncs to the
+//! same page only write the bytes appended since the previous sync, starting at the write size of
+//! the flash that holds the end of the previous sync. That unit is written again with the same
+//! data, which must be allowed by flash that limits how often a word is written between erases.
+//!
+//! Integrity
+//! ---------
+//!
+//! Logs can optionally store a CRC-16 (CCITT) of every entry in the upper half of its length
+//! header, which limits pages to 64 KiB. The CRC is computed in software, with a lookup table, so
+//! that it can be checked synchronously while the log is reconstructed during boot. When a log is
+//! reconstructed, entries in the newest page are only kept up to the first invalid one, which
+//! truncates a tail torn by a power loss during a write. If the torn tail left bytes that are not
+//! erased, the page is erased and written again on the next sync. Reading an entry that fails its check reports `FAIL` to the read client
+//! and moves on to the following entry (or the next page, if the entry's length cannot be
+//! trusted). Logs without checksums only detect invalid lengths.
 //!
 //! Usage
 //! -----
 //!
 //! ```
 //!     storage_volume!(VOLUME, 2);
-//!     static mut PAGEBUFFER: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//!     static mut PAGEBUFFER: [u8; 512] = [0; 512];
 //!
 //!     let dynamic_deferred_call_clients =
 //!         static_init!([DynamicDeferredCallClientState; 2], Default::default());
@@ -53,13 +74,16 @@
 //!         capsules::log::Log,
 //!         capsules::log::Log::new(
 //!             &VOLUME,
+//!             // The SAM4L maps its flash at address 0.
+//!             VOLUME.as_ptr() as usize,
 //!             &mut sam4l::flashcalw::FLASH_CONTROLLER,
 //!             &mut PAGEBUFFER,
 //!             dynamic_deferred_caller,
+//!             true,
 //!             true
 //!         )
 //!     );
-//!     kernel::hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, log);
+//!     kernel::hil::flash::FlashRange::set_range_client(&sam4l::flashcalw::FLASH_CONTROLLER, log);
 //!     log.initialize_callback_handle(dynamic_deferred_caller.register(log).expect("no deferred call slot available for log storage"));
 //!
 //!     log.set_read_client(log_storage_read_client);
@@ -74,7 +98,7 @@ use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::dynamic_deferred_call::{
     DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
 };
-use kernel::hil::flash::{self, Flash};
+use kernel::hil::flash::{FlashRange, RangeClient};
 use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
 use kernel::ErrorCode;
 
@@ -89,6 +113,43 @@ pub const ENTRY_HEADER_SIZE: usize = size_of::<usize>();
 /// Byte used to pad the end of a page.
 const PAD_BYTE: u8 = 0xFF;
 
+/// Bits of an entry header holding the length, if the log stores checksums.
+const CHECKSUM_LENGTH_MASK: usize = 0xFFFF;
+
+/// CRC-16 (CCITT) of every byte value, so that entries are checked a byte at a time.
+const CRC_TABLE: [u16; 256] = crc_table();
+
+const fn crc_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = (byte as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// CRC-16 (CCITT) of an entry, covering the low half of its length and its data.
+fn entry_crc(length: usize, data: &[u8]) -> u16 {
+    (length as u16)
+        .to_le_bytes()
+        .iter()
+        .chain(data.iter())
+        .fold(0xFFFF, |crc: u16, &byte| {
+            crc << 8 ^ CRC_TABLE[(crc >> 8) as usize ^ byte as usize]
+        })
+}
+
 /// Log state keeps track of any in-progress asynchronous operations.
 #[derive(Clone, Copy, PartialEq)]
 enum State {
@@ -100,19 +161,23 @@ enum State {
     Erase,
 }
 
-pub struct Log<'a, F: Flash + 'static> {
+pub struct Log<'a, F: FlashRange<'a>> {
     /// Underlying storage volume.
     volume: &'static [u8],
+    /// Address of the volume for the flash controller.
+    flash_address: usize,
     /// Capacity of log in bytes.
     capacity: usize,
     /// Flash interface.
     driver: &'a F,
-    /// Buffer for a flash page.
-    pagebuffer: TakeCell<'static, F::Page>,
-    /// Size of a flash page.
+    /// Buffer for a page of the log.
+    pagebuffer: TakeCell<'static, [u8]>,
+    /// Size of a page of the log, which is an erase unit of the flash.
     page_size: usize,
     /// Whether or not the log is circular.
     circular: bool,
+    /// Whether or not entry headers carry a CRC.
+    checksums: bool,
     /// Read client using Log.
     read_client: OptionalCell<&'a dyn LogReadClient>,
     /// Append client using Log.
@@ -126,6 +191,9 @@ pub struct Log<'a, F: Flash + 'static> {
     read_entry_id: Cell<EntryID>,
     /// Entry ID of next entry to append.
     append_entry_id: Cell<EntryID>,
+    /// Offset within the page of the pagebuffer up to which it is written to flash, or 0 if the
+    /// page must be erased before it is written.
+    flushed: Cell<usize>,
 
     /// Deferred caller for deferring client callbacks.
     deferred_caller: &'a DynamicDeferredCall,
@@ -143,30 +211,41 @@ pub struct Log<'a, F: Flash + 'static> {
     error: Cell<Result<(), ErrorCode>>,
 }
 
-impl<'a, F: Flash + 'static> Log<'a, F> {
+impl<'a, F: FlashRange<'a>> Log<'a, F> {
+    /// Creates a log over `volume`, reconstructing its state from the entries already in flash.
+    /// `flash_address` is the address of `volume` for `driver`. Pages are the size of
+    /// `pagebuffer`, which must be the size of the erase units of the flash that `volume` is made
+    /// of.
+    /// The length header of a log with checksums only holds 16 bits, so such a log supports pages
+    /// of at most 64 KiB; appending to a log with larger pages fails with `SIZE`.
     pub fn new(
         volume: &'static [u8],
+        flash_address: usize,
         driver: &'a F,
-        pagebuffer: &'static mut F::Page,
+        pagebuffer: &'static mut [u8],
         deferred_caller: &'a DynamicDeferredCall,
         circular: bool,
+        checksums: bool,
     ) -> Log<'a, F> {
-        let page_size = pagebuffer.as_mut().len();
+        let page_size = pagebuffer.len();
         let capacity = volume.len() - PAGE_HEADER_SIZE * (volume.len() / page_size);
 
         let log: Log<'a, F> = Log {
             volume,
+            flash_address,
             capacity,
             driver,
             pagebuffer: TakeCell::new(pagebuffer),
             page_size,
             circular,
+            checksums,
             read_client: OptionalCell::empty(),
             append_client: OptionalCell::empty(),
             state: Cell::new(State::Idle),
             oldest_entry_id: Cell::new(PAGE_HEADER_SIZE),
             read_entry_id: Cell::new(PAGE_HEADER_SIZE),
             append_entry_id: Cell::new(PAGE_HEADER_SIZE),
+            flushed: Cell::new(0),
             deferred_caller,
             handle: OptionalCell::empty(),
             buffer: TakeCell::empty(),
@@ -179,32 +258,70 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         log
     }
 
-    /// Returns the page number of the page containing the entry with the given ID.
-    fn page_number(&self, entry_id: EntryID) -> usize {
-        (self.volume.as_ptr() as usize + entry_id % self.volume.len()) / self.page_size
+    /// Returns the flash address of the page containing the entry with the given ID.
+    fn page_address(&self, entry_id: EntryID) -> usize {
+        self.flash_address + (entry_id - entry_id % self.page_size) % self.volume.len()
+    }
+
+    /// Returns the ID of the start of the page that the pagebuffer is flushed to.
+    fn flushed_page(&self) -> EntryID {
+        // The append entry ID is at the start of the next page if the pagebuffer is full.
+        (self.append_entry_id.get() - 1) / self.page_size * self.page_size
+    }
+
+    /// Returns the offset within the flushed page up to which the pagebuffer holds entries.
+    fn append_offset(&self) -> usize {
+        (self.append_entry_id.get() - 1) % self.page_size + 1
+    }
+
+    /// Returns the offset within the flushed page that the next write of the pagebuffer starts
+    /// at, which is where the previous write ended, aligned down to the write size of the flash.
+    fn write_start(&self) -> usize {
+        let write_size = self.driver.geometry().write_size;
+        self.flushed.get() / write_size * write_size
+    }
+
+    /// Returns the length stored in an entry header.
+    fn header_length(&self, header: usize) -> usize {
+        if self.checksums {
+            header & CHECKSUM_LENGTH_MASK
+        } else {
+            header
+        }
+    }
+
+    /// Returns whether an entry header holds a length that fits within a page.
+    fn header_valid(&self, header: usize) -> bool {
+        let length = self.header_length(header);
+        length != 0 && length <= self.page_size - PAGE_HEADER_SIZE - ENTRY_HEADER_SIZE
+    }
+
+    /// Returns whether the data of an entry matches the checksum in its header.
+    fn checksum_valid(&self, header: usize, data: &[u8]) -> bool {
+        !self.checksums || (header >> 16) as u16 == entry_crc(self.header_length(header), data)
     }
 
     /// Gets the buffer containing the byte at the given position in the log.
-    fn get_buffer<'b>(&self, pos: usize, pagebuffer: &'b mut F::Page) -> &'b [u8] {
+    fn get_buffer<'b>(&self, pos: usize, pagebuffer: &'b mut [u8]) -> &'b [u8] {
         // Subtract 1 from append entry ID to get position of last bit written. This is needed
         // because the pagebuffer always contains the last written bit, but not necessarily the
         // position represented by the append entry ID (i.e. the pagebuffer isn't flushed yet when
         // `append_entry_id % page_size == 0`).
         if pos / self.page_size == (self.append_entry_id.get() - 1) / self.page_size {
-            pagebuffer.as_mut()
+            pagebuffer
         } else {
             self.volume
         }
     }
 
     /// Gets the byte at the given position in the log.
-    fn get_byte(&self, pos: usize, pagebuffer: &mut F::Page) -> u8 {
+    fn get_byte(&self, pos: usize, pagebuffer: &mut [u8]) -> u8 {
         let buffer = self.get_buffer(pos, pagebuffer);
         buffer[pos % buffer.len()]
     }
 
     /// Gets a `num_bytes` long slice of bytes starting from a position within the log.
-    fn get_bytes<'b>(&self, pos: usize, num_bytes: usize, pagebuffer: &'b mut F::Page) -> &'b [u8] {
+    fn get_bytes<'b>(&self, pos: usize, num_bytes: usize, pagebuffer: &'b mut [u8]) -> &'b [u8] {
         let buffer = self.get_buffer(pos, pagebuffer);
         let offset = pos % buffer.len();
         &buffer[offset..offset + num_bytes]
@@ -215,68 +332,95 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         self.oldest_entry_id.set(PAGE_HEADER_SIZE);
         self.read_entry_id.set(PAGE_HEADER_SIZE);
         self.append_entry_id.set(PAGE_HEADER_SIZE);
+        self.flushed.set(0);
         self.pagebuffer.take().map_or(false, move |pagebuffer| {
-            for e in pagebuffer.as_mut().iter_mut() {
+            // The first page has ID 0, and everything after its header is unwritten.
+            for e in pagebuffer[..PAGE_HEADER_SIZE].iter_mut() {
                 *e = 0;
             }
+            for e in pagebuffer[PAGE_HEADER_SIZE..].iter_mut() {
+                *e = PAD_BYTE;
+            }
             self.pagebuffer.replace(pagebuffer);
             true
         })
     }
 
+    /// Returns the ID in the header of the page at the given position in the volume, if it is
+    /// valid for that position.
+    fn page_id(&self, header_pos: usize) -> Option<EntryID> {
+        const ID_SIZE: usize = size_of::<EntryID>();
+        let id_bytes = &self.volume[header_pos..header_pos + ID_SIZE];
+        let id_bytes = <[u8; ID_SIZE]>::try_from(id_bytes).unwrap();
+        let page_id = usize::from_ne_bytes(id_bytes);
+        if page_id % self.volume.len() == header_pos {
+            Some(page_id)
+        } else {
+            None
+        }
+    }
+
     /// Reconstructs a log from flash.
     fn reconstruct(&self) {
-        // Read page headers, get IDs of oldest and newest pages.
-        let mut oldest_page_id: EntryID = core::usize::MAX;
+        // Find the newest page. A header torn by an interrupted page write can hold an ID that
+        // happens to match the page's position, so the newest page must also directly follow the
+        // page before it, unless the log has not wrapped around yet.
         let mut newest_page_id: EntryID = 0;
-        for header_pos in (0..self.volume.len()).step_by(self.page_size) {
-            let page_id = {
-                const ID_SIZE: usize = size_of::<EntryID>();
-                let id_bytes = &self.volume[header_pos..header_pos + ID_SIZE];
-                let id_bytes = <[u8; ID_SIZE]>::try_from(id_bytes).unwrap();
-                usize::from_ne_bytes(id_bytes)
-            };
-
-            // Validate page ID read from header.
-            if page_id % self.volume.len() == header_pos {
-                if page_id < oldest_page_id {
-                    oldest_page_id = page_id;
-                }
-                if page_id > newest_page_id {
-                    newest_page_id = page_id;
-                }
+        let mut limit = core::usize::MAX;
+        while let Some(page_id) = (0..self.volume.len())
+            .step_by(self.page_size)
+            .filter_map(|header_pos| self.page_id(header_pos))
+            .filter(|&page_id| page_id < limit)
+            .max()
+        {
+            let previous_pos = (page_id + self.volume.len() - self.page_size) % self.volume.len();
+            if page_id < self.volume.len()
+                || self.page_id(previous_pos) == Some(page_id - self.page_size)
+            {
+                newest_page_id = page_id;
+                break;
             }
+            limit = page_id;
         }
 
+        // Get ID of oldest page, ignoring pages that are not part of the log.
+        let oldest_page_id = (0..self.volume.len())
+            .step_by(self.page_size)
+            .filter_map(|header_pos| self.page_id(header_pos))
+            .filter(|&page_id| page_id <= newest_page_id && page_id < limit)
+            .min()
+            .unwrap_or(core::usize::MAX);
+
         // Reconstruct log if at least one valid page was found (meaning oldest page ID was set to
         // something not usize::MAX).
         if oldest_page_id != core::usize::MAX {
-            // Walk entries in last (newest) page to calculate last page length.
+            // Walk entries in last (newest) page to calculate last page length. The walk stops at
+            // the first invalid entry, which drops a tail torn by an interrupted page write.
             let mut last_page_len = PAGE_HEADER_SIZE;
-            loop {
-                // Check if next byte is start of valid entry.
+            while last_page_len + ENTRY_HEADER_SIZE <= self.page_size {
+                // Get next entry header. Padding and unwritten space have no valid length.
                 let volume_offset = newest_page_id % self.volume.len() + last_page_len;
-                if self.volume[volume_offset] == 0 || self.volume[volume_offset] == PAD_BYTE {
-                    break;
-                }
-
-                // Get next entry length.
-                let entry_length = {
+                let header = {
                     const LENGTH_SIZE: usize = size_of::<usize>();
                     let length_bytes = &self.volume[volume_offset..volume_offset + LENGTH_SIZE];
                     let length_bytes = <[u8; LENGTH_SIZE]>::try_from(length_bytes).unwrap();
                     usize::from_ne_bytes(length_bytes)
-                } + ENTRY_HEADER_SIZE;
+                };
+                if !self.header_valid(header) {
+                    break;
+                }
 
-                // Add to page length if length is valid (fits within remainder of page.
-                if last_page_len + entry_length <= self.page_size {
-                    last_page_len += entry_length;
-                    if last_page_len == self.page_size {
-                        break;
-                    }
-                } else {
+                // Add to page length if entry fits within remainder of page and is intact.
+                let length = self.header_length(header);
+                let entry_length = length + ENTRY_HEADER_SIZE;
+                if last_page_len + entry_length > self.page_size {
+                    break;
+                }
+                let data_offset = volume_offset + ENTRY_HEADER_SIZE;
+                if !self.checksum_valid(header, &self.volume[data_offset..data_offset + length]) {
                     break;
                 }
+                last_page_len += entry_length;
             }
 
             // Set tracked entry IDs.
@@ -295,11 +439,17 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
                         copy_pagebuffer = !self.reset_pagebuffer(pagebuffer);
                     }
                     if copy_pagebuffer {
-                        // Copy last page into pagebuffer.
-                        for i in 0..self.page_size {
-                            pagebuffer.as_mut()[i] =
-                                self.volume[newest_page_id % self.volume.len() + i];
+                        // Copy the entries of the last page into pagebuffer. Bytes after them that
+                        // are not erased, such as a torn entry, cannot be written over, so then
+                        // the page is erased before it is written again.
+                        let page_pos = newest_page_id % self.volume.len();
+                        let page = &self.volume[page_pos..page_pos + self.page_size];
+                        pagebuffer[..last_page_len].copy_from_slice(&page[..last_page_len]);
+                        for e in pagebuffer[last_page_len..].iter_mut() {
+                            *e = PAD_BYTE;
                         }
+                        let erased = page[last_page_len..].iter().all(|&byte| byte == PAD_BYTE);
+                        self.flushed.set(if erased { last_page_len } else { 0 });
                     }
                     self.pagebuffer.replace(pagebuffer);
                 })
@@ -346,24 +496,25 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         self.pagebuffer
             .take()
             .map_or(Err(Err(ErrorCode::RESERVE)), move |pagebuffer| {
-                // Get length.
+                // Get header.
                 const LENGTH_SIZE: usize = size_of::<usize>();
                 let length_bytes = self.get_bytes(entry_id, LENGTH_SIZE, pagebuffer);
                 let length_bytes = <[u8; LENGTH_SIZE]>::try_from(length_bytes).unwrap();
-                let length = usize::from_ne_bytes(length_bytes);
+                let header = usize::from_ne_bytes(length_bytes);
 
-                // Return length of next entry.
+                // Return header of next entry.
                 self.pagebuffer.replace(pagebuffer);
-                if length == 0 || length > self.page_size - PAGE_HEADER_SIZE - ENTRY_HEADER_SIZE {
-                    Err(Err(ErrorCode::FAIL))
+                if self.header_valid(header) {
+                    Ok(header)
                 } else {
-                    Ok(length)
+                    Err(Err(ErrorCode::FAIL))
                 }
             })
     }
 
     /// Reads the next entry into a buffer. Returns the number of bytes read on success, or an
-    /// error otherwise.
+    /// error otherwise. Returns 0 bytes if the entry is corrupt, in which case the read entry ID is
+    /// moved past it.
     /// Result<(), ErrorCode>s used:
     ///     * FAIL: reached end of log, nothing to read.
     ///     * RESERVE: internal pagebuffer missing, log is presumably broken.
@@ -371,7 +522,18 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
     fn read_entry(&self, buffer: &mut [u8], length: usize) -> Result<usize, Result<(), ErrorCode>> {
         // Get next entry to read. Immediately returns FAIL in event of failure.
         let entry_id = self.get_next_entry()?;
-        let entry_length = self.read_entry_header(entry_id)?;
+        let header = match self.read_entry_header(entry_id) {
+            Ok(header) => header,
+            Err(Err(ErrorCode::FAIL)) => {
+                // The length cannot be trusted, skip to the next page.
+                let next_page = entry_id + self.page_size - entry_id % self.page_size;
+                self.read_entry_id
+                    .set(core::cmp::min(next_page, self.append_entry_id.get()));
+                return Ok(0);
+            }
+            Err(error) => return Err(error),
+        };
+        let entry_length = self.header_length(header);
 
         // Read entry into buffer.
         self.pagebuffer
@@ -386,6 +548,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
 
                 // Copy data into client buffer.
                 let data = self.get_bytes(entry_id, entry_length, pagebuffer);
+                let intact = self.checksum_valid(header, data);
                 for i in 0..entry_length {
                     buffer[i] = data[i];
                 }
@@ -393,16 +556,21 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
                 // Update read entry ID and return number of bytes read.
                 self.read_entry_id.set(entry_id + entry_length);
                 self.pagebuffer.replace(pagebuffer);
-                Ok(entry_length)
+                Ok(if intact { entry_length } else { 0 })
             })
     }
 
     /// Writes an entry header at the given position within a page. Must write at most
     /// ENTRY_HEADER_SIZE bytes.
-    fn write_entry_header(&self, length: usize, pos: usize, pagebuffer: &mut F::Page) {
+    fn write_entry_header(&self, length: usize, data: &[u8], pos: usize, pagebuffer: &mut [u8]) {
+        let header = if self.checksums {
+            length | (entry_crc(length, data) as usize) << 16
+        } else {
+            length
+        };
         let mut offset = 0;
-        for byte in &length.to_ne_bytes() {
-            pagebuffer.as_mut()[pos + offset] = *byte;
+        for byte in &header.to_ne_bytes() {
+            pagebuffer[pos + offset] = *byte;
             offset += 1;
         }
     }
@@ -413,19 +581,19 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         &self,
         buffer: &'static mut [u8],
         length: usize,
-        pagebuffer: &'static mut F::Page,
+        pagebuffer: &'static mut [u8],
     ) {
         // Offset within page to append to.
         let append_entry_id = self.append_entry_id.get();
         let mut page_offset = append_entry_id % self.page_size;
 
         // Write entry header to pagebuffer.
-        self.write_entry_header(length, page_offset, pagebuffer);
+        self.write_entry_header(length, &buffer[..length], page_offset, pagebuffer);
         page_offset += ENTRY_HEADER_SIZE;
 
         // Copy data to pagebuffer.
         for offset in 0..length {
-            pagebuffer.as_mut()[page_offset + offset] = buffer[offset];
+            pagebuffer[page_offset + offset] = buffer[offset];
         }
 
         // Increment append offset by number of bytes appended.
@@ -447,23 +615,29 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
     ///     * Ok(()): flush started successfully.
     ///     * FAIL: flash driver not configured.
     ///     * BUSY: flash driver busy.
-    fn flush_pagebuffer(&self, pagebuffer: &'static mut F::Page) -> Result<(), ErrorCode> {
-        // Pad end of page.
-        let mut pad_ptr = self.append_entry_id.get();
-        while pad_ptr % self.page_size != 0 {
-            pagebuffer.as_mut()[pad_ptr % self.page_size] = PAD_BYTE;
-            pad_ptr += 1;
+    ///     * INVAL: the page is not an erase unit of the flash.
+    fn flush_pagebuffer(&self, pagebuffer: &'static mut [u8]) -> Result<(), ErrorCode> {
+        let address = self.page_address(self.flushed_page());
+        self.pagebuffer.replace(pagebuffer);
+        if self.driver.erase_unit(address) != Some((address, self.page_size)) {
+            return Err(ErrorCode::INVAL);
+        }
+
+        // Only write the rest of the pagebuffer if the start of the page is already in flash.
+        if self.flushed.get() != 0 {
+            return self.write_pagebuffer();
         }
 
-        // Get flash page to write to and log page being overwritten. Subtract page_size since
-        // padding pointer points to start of the page following the one we want to flush after the
-        // padding operation.
-        let page_number = self.page_number(pad_ptr - self.page_size);
-        let overwritten_page = (pad_ptr - self.volume.len() - self.page_size) / self.page_size;
+        // Get log page being overwritten. No page is overwritten before the log first wraps
+        // around.
+        let overwritten_page = self
+            .flushed_page()
+            .checked_sub(self.volume.len())
+            .map(|entry_id| entry_id / self.page_size);
 
         // Advance read and oldest entry IDs, if within flash page being overwritten.
         let read_entry_id = self.read_entry_id.get();
-        if read_entry_id / self.page_size == overwritten_page {
+        if Some(read_entry_id / self.page_size) == overwritten_page {
             // Move read entry ID to start of next page.
             self.read_entry_id.set(
                 read_entry_id + self.page_size + PAGE_HEADER_SIZE - read_entry_id % self.page_size,
@@ -471,25 +645,51 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         }
 
         let oldest_entry_id = self.oldest_entry_id.get();
-        if oldest_entry_id / self.page_size == overwritten_page {
+        if Some(oldest_entry_id / self.page_size) == overwritten_page {
             self.oldest_entry_id.set(oldest_entry_id + self.page_size);
         }
 
-        // Sync page to flash.
-        match self.driver.write_page(page_number, pagebuffer) {
-            Ok(()) => Ok(()),
-            Err((ecode, pagebuffer)) => {
+        // Erase the page, it is written once the erase completes.
+        self.driver.erase(address)
+    }
+
+    /// Writes the part of the pagebuffer that is not in flash yet. Writes start at the beginning
+    /// of the buffer, so that part is rotated to the front of the pagebuffer until the write is
+    /// done.
+    fn write_pagebuffer(&self) -> Result<(), ErrorCode> {
+        let pagebuffer = self.pagebuffer.take().ok_or(ErrorCode::RESERVE)?;
+        let write_size = self.driver.geometry().write_size;
+        let start = self.write_start();
+        let end = core::cmp::min(
+            (self.append_offset() + write_size - 1) / write_size * write_size,
+            self.page_size,
+        );
+        pagebuffer[..end].rotate_left(start);
+        let address = self.page_address(self.flushed_page()) + start;
+        self.driver
+            .write(address, pagebuffer, end - start)
+            .map_err(|(ecode, pagebuffer)| {
+                pagebuffer[..end].rotate_right(start);
                 self.pagebuffer.replace(pagebuffer);
-                Err(ecode)
-            }
+                ecode
+            })
+    }
+
+    /// Makes the client callback of an append or sync whose flush failed.
+    fn flush_failed(&self) {
+        if self.state.get() == State::Append {
+            self.length.set(0);
+            self.records_lost.set(false);
         }
+        self.error.set(Err(ErrorCode::FAIL));
+        self.client_callback();
     }
 
     /// Resets the pagebuffer so that new data can be written. Note that this also increments the
     /// append entry ID to point to the start of writable data in this new page. Does not reset
     /// pagebuffer or modify append entry ID if the end of a non-circular log is reached. Returns
     /// whether or not the pagebuffer was reset.
-    fn reset_pagebuffer(&self, pagebuffer: &mut F::Page) -> bool {
+    fn reset_pagebuffer(&self, pagebuffer: &mut [u8]) -> bool {
         // Make sure this is not the last page of a non-circular buffer.
         let mut append_entry_id = self.append_entry_id.get();
         if !self.circular && append_entry_id + self.page_size > self.volume.len() {
@@ -501,11 +701,15 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             append_entry_id += self.page_size - append_entry_id % self.page_size;
         }
 
-        // Write page header to pagebuffer.
+        // Write page header to pagebuffer, the rest of the page is unwritten.
         let id_bytes = append_entry_id.to_ne_bytes();
         for index in 0..id_bytes.len() {
-            pagebuffer.as_mut()[index] = id_bytes[index];
+            pagebuffer[index] = id_bytes[index];
+        }
+        for e in pagebuffer[id_bytes.len()..].iter_mut() {
+            *e = PAD_BYTE;
         }
+        self.flushed.set(0);
 
         // Note: this is the only place where the append entry ID can cross page boundaries.
         self.append_entry_id.set(append_entry_id + PAGE_HEADER_SIZE);
@@ -518,7 +722,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         // erased first and the log will remain in a valid state even if it fails to be erased
         // completely.
         self.driver
-            .erase_page(self.page_number(self.oldest_entry_id.get()))
+            .erase(self.page_address(self.oldest_entry_id.get()))
     }
 
     /// Initializes a callback handle for deferred callbacks.
@@ -579,7 +783,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
     }
 }
 
-impl<'a, F: Flash + 'static> LogRead<'a> for Log<'a, F> {
+impl<'a, F: FlashRange<'a>> LogRead<'a> for Log<'a, F> {
     type EntryID = EntryID;
 
     /// Set the client for read operation callbacks.
@@ -602,6 +806,7 @@ impl<'a, F: Flash + 'static> LogRead<'a> for Log<'a, F> {
     ///     * SIZE: buffer not large enough to contain entry being read.
     /// Result<(), ErrorCode>s used in read_done callback:
     ///     * Ok(()): read succeeded.
+    ///     * FAIL: entry is corrupt, the next read continues after it.
     fn read(
         &self,
         buffer: &'static mut [u8],
@@ -629,7 +834,11 @@ impl<'a, F: Flash + 'static> LogRead<'a> for Log<'a, F> {
                 self.state.set(State::Read);
                 self.buffer.replace(buffer);
                 self.length.set(bytes_read);
-                self.error.set(Ok(()));
+                self.error.set(if bytes_read == 0 {
+                    Err(ErrorCode::FAIL)
+                } else {
+                    Ok(())
+                });
                 self.deferred_client_callback();
                 Ok(())
             }
@@ -677,7 +886,7 @@ impl<'a, F: Flash + 'static> LogRead<'a> for Log<'a, F> {
     }
 }
 
-impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
+impl<'a, F: FlashRange<'a>> LogWrite<'a> for Log<'a, F> {
     /// Set the client for append operation callbacks.
     fn set_append_client(&self, append_client: &'a dyn LogWriteClient) {
         self.append_client.set(append_client);
@@ -694,7 +903,7 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
     ///     * BUSY: log busy with another operation, try again later.
     ///     * INVAL: provided client buffer is too small.
     ///     * RESERVE: client or internal pagebuffer missing.
-    ///     * SIZE: entry too large to append to log.
+    ///     * SIZE: entry too large to append to log, or pages too large for checksums.
     /// Result<(), ErrorCode>s used in append_done callback:
     ///     * Ok(()): append succeeded.
     ///     * FAIL: write failed due to flash error.
@@ -716,6 +925,9 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
         } else if entry_size + PAGE_HEADER_SIZE > self.page_size {
             // Entry too big, won't fit within a single page.
             return Err((ErrorCode::SIZE, buffer));
+        } else if self.checksums && self.page_size > CHECKSUM_LENGTH_MASK + 1 {
+            // Pages too big for the length header, entries could not be read back.
+            return Err((ErrorCode::SIZE, buffer));
         } else if !self.circular && self.append_entry_id.get() + entry_size > self.volume.len() {
             // End of non-circular log has been reached.
             return Err((ErrorCode::FAIL, buffer));
@@ -765,12 +977,17 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
     ///     * Ok(()): append succeeded.
     ///     * FAIL: write failed due to flash error.
     fn sync(&self) -> Result<(), ErrorCode> {
-        if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE {
-            // Pagebuffer empty, don't need to flush.
-            return Ok(());
-        } else if self.state.get() != State::Idle {
+        if self.state.get() != State::Idle {
             // Log busy, try appending again later.
             return Err(ErrorCode::BUSY);
+        } else if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE
+            || self.flushed.get() == self.append_offset()
+        {
+            // Pagebuffer empty or already in flash, don't need to flush.
+            self.state.set(State::Sync);
+            self.error.set(Ok(()));
+            self.deferred_client_callback();
+            return Ok(());
         }
 
         self.pagebuffer
@@ -803,73 +1020,78 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
     }
 }
 
-impl<'a, F: Flash + 'static> flash::Client<F> for Log<'a, F> {
-    fn read_complete(&self, _read_buffer: &'static mut F::Page, _error: flash::Error) {
+impl<'a, F: FlashRange<'a>> RangeClient for Log<'a, F> {
+    fn read_done(
+        &self,
+        _buffer: &'static mut [u8],
+        _length: usize,
+        _result: Result<(), ErrorCode>,
+    ) {
         // Reads are made directly from the storage volume, not through the flash interface.
         unreachable!();
     }
 
     /// If in the middle of a write operation, reset pagebuffer and finish write. If syncing, make
     /// successful client callback.
-    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: flash::Error) {
-        match error {
-            flash::Error::CommandComplete => {
-                match self.state.get() {
-                    State::Append => {
-                        // Reset pagebuffer and finish writing on the new page.
-                        if self.reset_pagebuffer(pagebuffer) {
-                            self.buffer
-                                .take()
-                                .map(move |buffer| {
-                                    self.append_entry(buffer, self.length.get(), pagebuffer);
-                                })
-                                .unwrap();
-                        } else {
-                            self.pagebuffer.replace(pagebuffer);
-                            self.length.set(0);
-                            self.records_lost.set(false);
-                            self.error.set(Err(ErrorCode::CANCEL));
-                            self.client_callback();
-                        }
-                    }
-                    State::Sync => {
-                        // Reset pagebuffer if synced page was full.
-                        if self.append_entry_id.get() % self.page_size == 0 {
-                            self.reset_pagebuffer(pagebuffer);
-                        }
-
-                        self.pagebuffer.replace(pagebuffer);
-                        self.error.set(Ok(()));
-                        self.client_callback();
-                    }
-                    _ => unreachable!(),
+    fn write_done(
+        &self,
+        pagebuffer: &'static mut [u8],
+        length: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        let start = self.write_start();
+        pagebuffer[..start + length].rotate_right(start);
+        if result.is_err() {
+            // Make client callback with FAIL return code.
+            self.pagebuffer.replace(pagebuffer);
+            self.flush_failed();
+            return;
+        }
+        self.flushed.set(self.append_offset());
+        match self.state.get() {
+            State::Append => {
+                // Reset pagebuffer and finish writing on the new page.
+                if self.reset_pagebuffer(pagebuffer) {
+                    self.buffer
+                        .take()
+                        .map(move |buffer| {
+                            self.append_entry(buffer, self.length.get(), pagebuffer);
+                        })
+                        .unwrap();
+                } else {
+                    self.pagebuffer.replace(pagebuffer);
+                    self.length.set(0);
+                    self.records_lost.set(false);
+                    self.error.set(Err(ErrorCode::CANCEL));
+                    self.client_callback();
                 }
             }
-            flash::Error::FlashError => {
-                // Make client callback with FAIL return code.
-                self.pagebuffer.replace(pagebuffer);
-                match self.state.get() {
-                    State::Append => {
-                        self.length.set(0);
-                        self.records_lost.set(false);
-                        self.error.set(Err(ErrorCode::FAIL));
-                        self.client_callback();
-                    }
-                    State::Sync => {
-                        self.error.set(Err(ErrorCode::FAIL));
-                        self.client_callback();
-                    }
-                    _ => unreachable!(),
+            State::Sync => {
+                // Reset pagebuffer if synced page was full.
+                if self.append_entry_id.get() % self.page_size == 0 {
+                    self.reset_pagebuffer(pagebuffer);
                 }
+
+                self.pagebuffer.replace(pagebuffer);
+                self.error.set(Ok(()));
+                self.client_callback();
             }
+            _ => unreachable!(),
         }
     }
 
-    /// Erase next page if log erase complete, else make client callback. Fails with BUSY if flash
-    /// is busy and erase cannot be completed.
-    fn erase_complete(&self, error: flash::Error) {
-        match error {
-            flash::Error::CommandComplete => {
+    /// Write the pagebuffer if a page was erased to flush it. Otherwise erase next page if log
+    /// erase complete, else make client callback. Fails with BUSY if flash is busy and erase
+    /// cannot be completed.
+    fn erase_done(&self, result: Result<(), ErrorCode>) {
+        if self.state.get() != State::Erase {
+            if result.and_then(|()| self.write_pagebuffer()).is_err() {
+                self.flush_failed();
+            }
+            return;
+        }
+        match result {
+            Ok(()) => {
                 let oldest_entry_id = self.oldest_entry_id.get();
                 if oldest_entry_id >= self.append_entry_id.get() - self.page_size {
                     // Erased all pages. Reset state and callback client.
@@ -893,7 +1115,7 @@ impl<'a, F: Flash + 'static> flash::Client<F> for Log<'a, F> {
                     }
                 }
             }
-            flash::Error::FlashError => {
+            Err(_) => {
                 self.error.set(Err(ErrorCode::FAIL));
                 self.client_callback();
             }
@@ -901,8 +1123,11 @@ impl<'a, F: Flash + 'static> flash::Client<F> for Log<'a, F> {
     }
 }
 
-impl<'a, F: Flash + 'static> DynamicDeferredCallClient for Log<'a, F> {
+impl<'a, F: FlashRange<'a>> DynamicDeferredCallClient for Log<'a, F> {
     fn call(&self, _handle: DeferredCallHandle) {
         self.client_callback();
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,418 @@
+//! Fault-injection tests of log recovery against an emulated flash.
+//!
+//! Every "boot" maps a fresh copy of the flash contents as the log volume, so
+//! a test can interrupt a page write, corrupt bytes, and then reconstruct the
+//! log from what actually reached the flash.
+
+extern crate std;
+
+use std::cell::Cell;
+use std::vec;
+use std::vec::Vec;
+
+use kernel::common::cells::TakeCell;
+use kernel::common::dynamic_deferred_call::{DeferredCallHandle, DynamicDeferredCallClient};
+use kernel::hil::flash::FlashRange;
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::ErrorCode;
+
+use super::{entry_crc, Log, ENTRY_HEADER_SIZE, PAGE_HEADER_SIZE};
+use crate::emulated_flash::{self, Programming};
+use crate::test_support::{buffer, deferred_caller, leak};
+
+const PAGE_SIZE: usize = 64;
+const PAGES: usize = 4;
+
+type EmulatedFlash = emulated_flash::EmulatedFlash<PAGE_SIZE>;
+
+/// Map a copy of `image` as a volume, at flash address 0.
+fn map_volume(image: &[u8]) -> &'static [u8] {
+    let volume = buffer(image.len());
+    volume.copy_from_slice(image);
+    volume
+}
+
+struct Client {
+    buffer: TakeCell<'static, [u8]>,
+    read: Cell<Option<(usize, Result<(), ErrorCode>)>>,
+    appended: Cell<Option<Result<(), ErrorCode>>>,
+    synced: Cell<Option<Result<(), ErrorCode>>>,
+}
+
+impl LogReadClient for Client {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: Result<(), ErrorCode>) {
+        self.buffer.replace(buffer);
+        self.read.set(Some((length, error)));
+    }
+
+    fn seek_done(&self, _error: Result<(), ErrorCode>) {}
+}
+
+impl LogWriteClient for Client {
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        _length: usize,
+        _records_lost: bool,
+        error: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+        self.appended.set(Some(error));
+    }
+
+    fn sync_done(&self, error: Result<(), ErrorCode>) {
+        self.synced.set(Some(error));
+    }
+
+    fn erase_done(&self, _error: Result<(), ErrorCode>) {}
+}
+
+struct Device {
+    log: &'static Log<'static, EmulatedFlash>,
+    flash: &'static EmulatedFlash,
+    client: &'static Client,
+    handle: DeferredCallHandle,
+}
+
+impl Device {
+    fn erased() -> Device {
+        Device::boot(vec![0xFF; PAGE_SIZE * PAGES])
+    }
+
+    /// Construct a log over a copy of `image`, as after a reboot.
+    fn boot(image: Vec<u8>) -> Device {
+        let volume = map_volume(&image);
+        let flash = EmulatedFlash::leak(image, 0, Programming::Replace);
+        let deferred_caller = deferred_caller();
+        let log: &'static Log<'static, EmulatedFlash> = leak(Log::new(
+            volume,
+            0,
+            flash,
+            buffer(PAGE_SIZE),
+            deferred_caller,
+            true,
+            true,
+        ));
+        flash.set_range_client(log);
+        let handle = deferred_caller.register(log).unwrap();
+        log.initialize_callback_handle(handle);
+
+        let client = leak(Client {
+            buffer: TakeCell::new(buffer(PAGE_SIZE)),
+            read: Cell::new(None),
+            appended: Cell::new(None),
+            synced: Cell::new(None),
+        });
+        log.set_read_client(client);
+        log.set_append_client(client);
+
+        Device {
+            log,
+            flash,
+            client,
+            handle,
+        }
+    }
+
+    fn reboot(&self) -> Device {
+        Device::boot(self.flash.image.borrow().clone())
+    }
+
+    /// Let pending flash operations and deferred calls run.
+    fn run(&self) {
+        self.flash.run();
+        self.log.call(self.handle);
+    }
+
+    fn append(&self, data: &[u8]) {
+        let buffer = self.client.buffer.take().unwrap();
+        buffer[..data.len()].copy_from_slice(data);
+        self.log.append(buffer, data.len()).unwrap();
+        self.run();
+        assert_eq!(self.client.appended.take(), Some(Ok(())));
+    }
+
+    fn sync(&self) {
+        self.log.sync().unwrap();
+        self.run();
+        assert_eq!(self.client.synced.take(), Some(Ok(())));
+    }
+
+    /// Sync, but lose power once the page holds `written` bytes.
+    fn sync_torn(&self, written: usize) {
+        // The write starts where the previous sync ended
+        let start = self.log.write_start();
+        self.flash.tear_after.set(Some(written - start));
+        self.log.sync().unwrap();
+        // A fresh page is erased, then written
+        self.flash.run();
+    }
+
+    /// Bytes written to flash by the next sync.
+    fn sync_writes(&self) -> usize {
+        let before = self.flash.image.borrow().clone();
+        let erases = self.flash.erases.borrow().clone();
+        self.sync();
+        assert_eq!(*self.flash.erases.borrow(), erases);
+        let after = self.flash.image.borrow();
+        before
+            .iter()
+            .zip(after.iter())
+            .filter(|(b, a)| b != a)
+            .count()
+    }
+
+    /// The next entry, `None` at the end of the log.
+    fn read(&self) -> Option<Result<Vec<u8>, ErrorCode>> {
+        let buffer = self.client.buffer.take().unwrap();
+        let len = buffer.len();
+        if let Err((e, buffer)) = self.log.read(buffer, len) {
+            self.client.buffer.replace(buffer);
+            assert_eq!(e, ErrorCode::FAIL);
+            return None;
+        }
+        self.run();
+        let (length, result) = self.client.read.take().unwrap();
+        Some(result.map(|()| self.client.buffer.map(|b| b[..length].to_vec()).unwrap()))
+    }
+
+    fn read_all(&self) -> Vec<Result<Vec<u8>, ErrorCode>> {
+        let mut entries = Vec::new();
+        while let Some(entry) = self.read() {
+            entries.push(entry);
+        }
+        entries
+    }
+
+    fn flip(&self, pos: usize) {
+        self.flash.image.borrow_mut()[pos] ^= 0x10;
+    }
+}
+
+fn entry(n: u8) -> Vec<u8> {
+    vec![n; 10]
+}
+
+/// Volume offset of the data of entry `index` within the first page.
+fn data_offset(index: usize) -> usize {
+    PAGE_HEADER_SIZE + index * (ENTRY_HEADER_SIZE + 10) + ENTRY_HEADER_SIZE
+}
+
+#[test]
+fn entries_survive_reboot() {
+    let device = Device::erased();
+    for n in 0..6 {
+        device.append(&entry(n));
+    }
+    device.sync();
+
+    let device = device.reboot();
+    let expected: Vec<_> = (0..6).map(|n| Ok(entry(n))).collect();
+    assert_eq!(device.read_all(), expected);
+}
+
+#[test]
+fn sync_without_pending_data_completes() {
+    let device = Device::erased();
+    device.sync();
+    device.append(&entry(1));
+    device.sync();
+    device.sync();
+}
+
+#[test]
+fn syncs_write_appended_bytes() {
+    let device = Device::erased();
+    device.append(&entry(1));
+    device.sync();
+    // Entry 2 is written without erasing the page, and entry 1 is not
+    // written again
+    device.append(&entry(2));
+    assert_eq!(device.sync_writes(), ENTRY_HEADER_SIZE + 10);
+
+    let device = device.reboot();
+    assert_eq!(device.read_all(), vec![Ok(entry(1)), Ok(entry(2))]);
+    // After a reboot, syncs keep appending to the page
+    device.append(&entry(3));
+    assert_eq!(device.sync_writes(), ENTRY_HEADER_SIZE + 10);
+    let device = device.reboot();
+    assert_eq!(
+        device.read_all(),
+        vec![Ok(entry(1)), Ok(entry(2)), Ok(entry(3))]
+    );
+}
+
+#[test]
+fn torn_entry_is_truncated() {
+    let device = Device::erased();
+    device.append(&entry(1));
+    device.sync();
+    device.append(&entry(2));
+    device.append(&entry(3));
+    // Power fails after the header and half of the data of entry 3
+    device.sync_torn(data_offset(2) + 5);
+
+    let device = device.reboot();
+    assert_eq!(device.read_all(), vec![Ok(entry(1)), Ok(entry(2))]);
+
+    // The log keeps working after the torn tail
+    device.append(&entry(4));
+    device.sync();
+    let device = device.reboot();
+    assert_eq!(
+        device.read_all(),
+        vec![Ok(entry(1)), Ok(entry(2)), Ok(entry(4))]
+    );
+}
+
+#[test]
+fn torn_header_is_truncated() {
+    let device = Device::erased();
+    device.append(&entry(1));
+    device.append(&entry(2));
+    // Power fails within the length of entry 2
+    device.sync_torn(data_offset(1) - ENTRY_HEADER_SIZE + 1);
+
+    let device = device.reboot();
+    assert_eq!(device.read_all(), vec![Ok(entry(1))]);
+}
+
+#[test]
+fn torn_page_header_drops_page() {
+    let device = Device::erased();
+    for n in 0..3 {
+        device.append(&entry(n));
+    }
+    device.sync();
+    // Entry 3 starts the second page, whose header is cut short
+    device.append(&entry(3));
+    device.sync_torn(2);
+
+    let device = device.reboot();
+    let expected: Vec<_> = (0..3).map(|n| Ok(entry(n))).collect();
+    assert_eq!(device.read_all(), expected);
+    device.append(&entry(4));
+    device.sync();
+    let device = device.reboot();
+    let mut expected = expected;
+    expected.push(Ok(entry(4)));
+    assert_eq!(device.read_all(), expected);
+}
+
+#[test]
+fn corrupt_entry_reports_fail() {
+    let device = Device::erased();
+    for n in 0..5 {
+        device.append(&entry(n));
+    }
+    device.sync();
+    device.flip(data_offset(1) + 3);
+
+    let device = device.reboot();
+    assert_eq!(
+        device.read_all(),
+        vec![
+            Ok(entry(0)),
+            Err(ErrorCode::FAIL),
+            Ok(entry(2)),
+            Ok(entry(3)),
+            Ok(entry(4)),
+        ]
+    );
+}
+
+#[test]
+fn corrupt_length_skips_rest_of_page() {
+    let device = Device::erased();
+    for n in 0..5 {
+        device.append(&entry(n));
+    }
+    device.sync();
+    // Make the length of entry 1 point past the end of the page
+    device.flip(data_offset(1) - ENTRY_HEADER_SIZE + 1);
+
+    let device = device.reboot();
+    assert_eq!(
+        device.read_all(),
+        vec![
+            Ok(entry(0)),
+            Err(ErrorCode::FAIL),
+            Ok(entry(3)),
+            Ok(entry(4)),
+        ]
+    );
+}
+
+#[test]
+fn torn_page_header_after_wrap() {
+    let device = Device::erased();
+    for n in 0..12 {
+        device.append(&entry(n));
+    }
+    device.sync();
+    // Entry 12 overwrites the oldest page, and the write is cut short within
+    // the page header
+    device.append(&entry(12));
+    device.sync_torn(2);
+
+    let device = device.reboot();
+    let expected: Vec<_> = (3..12).map(|n| Ok(entry(n))).collect();
+    assert_eq!(device.read_all(), expected);
+}
+
+/// A log with checksums over pages of `N` bytes.
+fn large_page_log<const N: usize>() -> &'static Log<'static, emulated_flash::EmulatedFlash<N>> {
+    let volume = map_volume(&vec![0xFF; 2 * N]);
+    let flash = emulated_flash::EmulatedFlash::<N>::leak(volume.to_vec(), 0, Programming::Replace);
+    let deferred_caller = deferred_caller();
+    let log: &'static Log<'static, emulated_flash::EmulatedFlash<N>> = leak(Log::new(
+        volume,
+        0,
+        flash,
+        buffer(N),
+        deferred_caller,
+        true,
+        true,
+    ));
+    flash.set_range_client(log);
+    log.initialize_callback_handle(deferred_caller.register(log).unwrap());
+    let client = leak(Client {
+        buffer: TakeCell::empty(),
+        read: Cell::new(None),
+        appended: Cell::new(None),
+        synced: Cell::new(None),
+    });
+    log.set_append_client(client);
+    log
+}
+
+#[test]
+fn checksums_limit_page_size() {
+    // The largest entry of a 64 KiB page still fits in the length header
+    const LARGEST: usize = 0x10000;
+    let log = large_page_log::<LARGEST>();
+    let length = LARGEST - PAGE_HEADER_SIZE - ENTRY_HEADER_SIZE;
+    let data = buffer(length);
+    assert!(log.append(data, length).is_ok());
+
+    // Lengths past 16 bits would be truncated, so even small entries are
+    // refused on larger pages
+    let log = large_page_log::<{ 2 * LARGEST }>();
+    let data = buffer(0x10008);
+    assert_eq!(
+        log.append(data, 0x10008).map_err(|(e, _)| e),
+        Err(ErrorCode::SIZE)
+    );
+    let data = buffer(10);
+    assert_eq!(
+        log.append(data, 10).map_err(|(e, _)| e),
+        Err(ErrorCode::SIZE)
+    );
+}
+
+#[test]
+fn entry_crc_is_crc16_ccitt() {
+    // CRC-16/CCITT-FALSE of 09 00 "123456789", as computed bit by bit
+    assert_eq!(entry_crc(9, b"123456789"), 0x9F0B);
+    assert_eq!(entry_crc(0, &[]), 0x1D0F);
+}
@@ -0,0 +1,917 @@
+//! Syscall driver that gives every app its own named logs on top of a shared
+//! persistent log (see `capsules::log`).
+//!
+//! All apps share one underlying log volume. Each entry the driver appends
+//! starts with the full name of the owning process and the name the app chose
+//! for its log, so an app only ever sees the entries of the log it opened.
+//! Entry IDs are those of the underlying log, which keeps them ordered and
+//! stable across reboots.
+//!
+//! Every log is limited to a quota of bytes, which includes the per-entry
+//! overhead of the names. Erasing a log hides its existing entries and resets its usage;
+//! the space itself is reclaimed when a circular log wraps around. Usage is
+//! recomputed every time a log is opened, so entries that were overwritten by
+//! a circular log no longer count.
+//!
+//! Entries the underlying log reports as corrupt are skipped.
+//!
+//! Only one operation runs at a time; commands issued while another one is in
+//! progress fail with `BUSY`.
+//!
+//! The whole log, or the log of a single app, can also be written to a UART
+//! for field diagnostics, e.g. with the `log` command of the process console.
+//! Each entry is printed on its own line as
+//! `<entry id> <process>/<log name>: <data in hex>`.
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! * read-only 0: log name, terminated by a NUL byte or the end of the
+//!   buffer
+//! * read-only 1: data to append
+//! * read-write 0: buffer for entries read from the log
+//!
+//! ### Subscribe
+//!
+//! * 0: completion of an operation. The first argument is the status (0 or
+//!   an `ErrorCode`), the other two depend on the command.
+//!
+//! ### Command
+//!
+//! * 0: driver check.
+//! * 1: open the named log, creating it if it does not exist yet. The upcall
+//!   carries the number of bytes used and the quota. Fails with `SIZE` if the
+//!   process name is longer than `MAX_PROCESS_NAME_LEN`.
+//! * 2: append the first `arg1` bytes of the read-only buffer as a new entry.
+//!   The upcall carries the entry ID and whether older entries of the log
+//!   volume were overwritten. Fails with `NOMEM` if the quota is exhausted.
+//! * 3: read the next entry. The upcall carries the length of the entry, 0
+//!   once there are no more entries, and its ID. Entries longer than the
+//!   buffer are truncated.
+//! * 4: seek to entry ID `arg1`, which must have been returned by an append,
+//!   read or command 5. Completes immediately.
+//! * 5: the entry ID the next read starts at. Completes immediately.
+//! * 6: sync the log to flash.
+//! * 7: erase the log.
+
+use core::cell::Cell;
+use core::cmp;
+use core::mem;
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::hil::uart;
+use kernel::introspection::KernelInfo;
+use kernel::{CommandReturn, Driver, ErrorCode, Grant, Kernel, ProcessId, Upcall};
+use kernel::{Read, ReadOnlyAppSlice, ReadWrite, ReadWriteAppSlice};
+
+use crate::log::ENTRY_HEADER_SIZE;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Log as usize;
+
+/// Size of the buffer entries are staged in. Entries can hold up to
+/// `BUFFER_LEN - HEADER_LEN` bytes of app data, minus the length of the
+/// process and log names.
+pub const BUFFER_LEN: usize = 256;
+
+/// Size of the buffer used to print the log on a UART. Holds at least the
+/// entry ID and both names.
+pub const DUMP_BUFFER_LEN: usize = 96;
+
+/// Bytes the driver adds in front of every entry besides the names: the kind
+/// and the length of each name.
+pub const HEADER_LEN: usize = 3;
+
+/// Longest log name an app can use.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Longest process name that can own logs.
+pub const MAX_PROCESS_NAME_LEN: usize = 32;
+
+const MAX_ID_LEN: usize = 2 + MAX_PROCESS_NAME_LEN + MAX_NAME_LEN;
+
+const KIND_DATA: u8 = 0;
+const KIND_ERASED: u8 = 1;
+
+/// Something that can write the contents of a log to a console.
+pub trait LogDump {
+    /// Print all entries, or only those of the log `name` of process
+    /// `process`.
+    fn dump(&self, filter: Option<(&str, &str)>) -> Result<(), ErrorCode>;
+}
+
+/// Identifies the log `name` of a process, as stored after the kind of each
+/// entry: the lengths of both names followed by the names.
+#[derive(Clone, Copy, PartialEq)]
+struct LogId {
+    bytes: [u8; MAX_ID_LEN],
+    len: usize,
+}
+
+impl LogId {
+    fn new(process: &[u8], name: &[u8]) -> Result<LogId, ErrorCode> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(ErrorCode::INVAL);
+        }
+        if process.len() > MAX_PROCESS_NAME_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        let mut bytes = [0; MAX_ID_LEN];
+        bytes[0] = process.len() as u8;
+        bytes[1] = name.len() as u8;
+        bytes[2..2 + process.len()].copy_from_slice(process);
+        bytes[2 + process.len()..2 + process.len() + name.len()].copy_from_slice(name);
+        Ok(LogId {
+            bytes,
+            len: 2 + process.len() + name.len(),
+        })
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Length of the header of entries of this log.
+    fn header_len(&self) -> usize {
+        1 + self.len
+    }
+
+    /// Write the header of an entry of `kind` to `buffer`.
+    fn write_header(&self, buffer: &mut [u8], kind: u8) {
+        buffer[0] = kind;
+        buffer[1..self.header_len()].copy_from_slice(self.as_bytes());
+    }
+}
+
+/// Split an entry written by this driver into its kind, the encoded
+/// `LogId` of its log, and its data.
+fn parse_entry(entry: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&kind, rest) = entry.split_first()?;
+    if rest.len() < 2 {
+        return None;
+    }
+    let id_len = 2 + rest[0] as usize + rest[1] as usize;
+    if rest.len() < id_len {
+        return None;
+    }
+    Some((kind, &rest[..id_len], &rest[id_len..]))
+}
+
+#[derive(Clone, Copy)]
+struct OpenLog {
+    id: LogId,
+    /// Entries before this ID were erased
+    start: usize,
+    /// Where the next read starts
+    position: usize,
+    used: usize,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    name: ReadOnlyAppSlice,
+    write_buffer: ReadOnlyAppSlice,
+    read_buffer: ReadWriteAppSlice,
+    log: Option<OpenLog>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    /// Scanning the whole volume for the entries of a log
+    Open {
+        id: LogId,
+        start: usize,
+        used: usize,
+    },
+    Append,
+    Read,
+    Sync,
+    Erase,
+    /// Printing entries. `sent` is the number of data bytes of the current
+    /// entry already printed, `None` while no entry is being printed.
+    Dump {
+        filter: Option<LogId>,
+        len: usize,
+        sent: Option<usize>,
+    },
+}
+
+pub struct LogDriver<
+    'a,
+    L: LogRead<'a, EntryID = usize> + LogWrite<'a>,
+    C: ProcessManagementCapability,
+> {
+    log: &'a L,
+    buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    quota: usize,
+    current_app: OptionalCell<ProcessId>,
+    operation: Cell<Operation>,
+    uart: &'a dyn uart::Transmit<'a>,
+    dump_buffer: TakeCell<'static, [u8]>,
+    kernel: &'static Kernel,
+    capability: C,
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>, C: ProcessManagementCapability>
+    LogDriver<'a, L, C>
+{
+    pub fn new(
+        log: &'a L,
+        buffer: &'static mut [u8],
+        apps: Grant<App>,
+        quota: usize,
+        uart: &'a dyn uart::Transmit<'a>,
+        dump_buffer: &'static mut [u8],
+        kernel: &'static Kernel,
+        capability: C,
+    ) -> LogDriver<'a, L, C> {
+        LogDriver {
+            log,
+            buffer: TakeCell::new(buffer),
+            apps,
+            quota,
+            current_app: OptionalCell::empty(),
+            operation: Cell::new(Operation::Idle),
+            uart,
+            dump_buffer: TakeCell::new(dump_buffer),
+            kernel,
+            capability,
+        }
+    }
+
+    fn open_log(&self, appid: ProcessId) -> Result<OpenLog, ErrorCode> {
+        self.apps
+            .enter(appid, |app| app.log.ok_or(ErrorCode::RESERVE))
+            .unwrap_or(Err(ErrorCode::NOMEM))
+    }
+
+    fn update_log<F: FnOnce(&mut OpenLog)>(&self, appid: ProcessId, f: F) {
+        let _ = self.apps.enter(appid, |app| app.log.as_mut().map(f));
+    }
+
+    /// Start `operation` for `appid` by seeking to `entry`.
+    fn start_seek(
+        &self,
+        appid: Option<ProcessId>,
+        operation: Operation,
+        entry: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        // Entries before the start of the volume were overwritten
+        let entry = cmp::max(entry, self.log.log_start());
+        self.operation.set(operation);
+        appid.map(|appid| self.current_app.set(appid));
+        self.log.seek(entry).map_err(|e| {
+            self.operation.set(Operation::Idle);
+            self.current_app.clear();
+            e
+        })
+    }
+
+    /// Append an entry of `kind` for `appid`, with data taken from the app's
+    /// read-only buffer.
+    fn start_append(&self, appid: ProcessId, kind: u8, len: usize) -> Result<(), ErrorCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let open = match self.open_log(appid) {
+            Ok(open) => open,
+            Err(e) => return Err(e),
+        };
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return Err(ErrorCode::RESERVE),
+        };
+        let header_len = open.id.header_len();
+        if header_len + len > buffer.len() {
+            self.buffer.replace(buffer);
+            return Err(ErrorCode::SIZE);
+        }
+        if kind == KIND_DATA && open.used + header_len + len + ENTRY_HEADER_SIZE > self.quota {
+            self.buffer.replace(buffer);
+            return Err(ErrorCode::NOMEM);
+        }
+
+        open.id.write_header(buffer, kind);
+        let copied = self
+            .apps
+            .enter(appid, |app| {
+                app.write_buffer.map_or(Err(ErrorCode::RESERVE), |data| {
+                    if data.len() < len {
+                        Err(ErrorCode::SIZE)
+                    } else {
+                        buffer[header_len..header_len + len].copy_from_slice(&data[..len]);
+                        Ok(())
+                    }
+                })
+            })
+            .unwrap_or(Err(ErrorCode::NOMEM));
+        if let Err(e) = copied {
+            self.buffer.replace(buffer);
+            return Err(e);
+        }
+
+        // The log may report completion before `append()` returns
+        self.operation.set(if kind == KIND_DATA {
+            Operation::Append
+        } else {
+            Operation::Erase
+        });
+        self.current_app.set(appid);
+        self.log
+            .append(buffer, header_len + len)
+            .map_err(|(e, buffer)| {
+                self.buffer.replace(buffer);
+                self.operation.set(Operation::Idle);
+                self.current_app.clear();
+                e
+            })
+    }
+
+    /// Read the next entry of the volume.
+    fn read_next(&self) {
+        match self.buffer.take() {
+            Some(buffer) => {
+                let len = buffer.len();
+                match self.log.read(buffer, len) {
+                    Ok(()) => {}
+                    Err((ErrorCode::FAIL, buffer)) => {
+                        self.buffer.replace(buffer);
+                        self.end_of_log();
+                    }
+                    Err((e, buffer)) => {
+                        self.buffer.replace(buffer);
+                        self.finish(Err(e));
+                    }
+                }
+            }
+            None => self.finish(Err(ErrorCode::RESERVE)),
+        }
+    }
+
+    /// Handle an entry of `length` bytes that was read into the buffer while
+    /// scanning the volume.
+    fn entry_read(&self, length: usize) {
+        let header = self.buffer.map_or(None, |buffer| {
+            parse_entry(&buffer[..length]).map(|(kind, _, data)| (kind, data.len()))
+        });
+        let (kind, data_len) = match header {
+            Some(header) => header,
+            // Not written by this driver
+            None => return self.read_next(),
+        };
+        let is_log = |id: &LogId| {
+            self.buffer.map_or(false, |buffer| {
+                parse_entry(&buffer[..length]).map_or(false, |(_, log, _)| log == id.as_bytes())
+            })
+        };
+        let next = self.log.next_read_entry_id();
+        let entry = next - length - ENTRY_HEADER_SIZE;
+
+        match self.operation.get() {
+            Operation::Open { id, start, used } => {
+                if is_log(&id) {
+                    let (start, used) = if kind == KIND_ERASED {
+                        (next, 0)
+                    } else {
+                        (start, used + length + ENTRY_HEADER_SIZE)
+                    };
+                    self.operation.set(Operation::Open { id, start, used });
+                }
+                self.read_next();
+            }
+            Operation::Read => {
+                let appid = match self.current_app.extract() {
+                    Some(appid) => appid,
+                    None => return self.finish(Err(ErrorCode::FAIL)),
+                };
+                let wanted = self.open_log(appid).map(|open| open.id);
+                if !wanted.map_or(false, |id| is_log(&id)) || kind != KIND_DATA {
+                    return self.read_next();
+                }
+                self.buffer.map(|buffer| {
+                    let data = &buffer[length - data_len..length];
+                    let _ = self.apps.enter(appid, |app| {
+                        app.read_buffer.mut_map_or((), |out| {
+                            let n = cmp::min(out.len(), data.len());
+                            out[..n].copy_from_slice(&data[..n]);
+                        });
+                    });
+                });
+                self.update_log(appid, |open| open.position = next);
+                self.finish(Ok((data_len, entry)));
+            }
+            Operation::Dump { filter, .. } => {
+                if filter.map_or(true, |id| is_log(&id)) {
+                    self.operation.set(Operation::Dump {
+                        filter,
+                        len: length,
+                        sent: None,
+                    });
+                    self.print_entry();
+                } else {
+                    self.read_next();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn end_of_log(&self) {
+        match self.operation.get() {
+            Operation::Open { id, start, used } => {
+                self.current_app.map(|appid| {
+                    let _ = self.apps.enter(*appid, |app| {
+                        app.log = Some(OpenLog {
+                            id,
+                            start,
+                            position: start,
+                            used,
+                        });
+                    });
+                });
+                self.finish(Ok((used, self.quota)));
+            }
+            Operation::Read => {
+                let end = self.log.log_end();
+                self.current_app
+                    .map(|appid| self.update_log(*appid, |open| open.position = end));
+                self.finish(Ok((0, end)));
+            }
+            _ => self.finish(Ok((0, 0))),
+        }
+    }
+
+    /// Print the next part of the entry in the buffer.
+    fn print_entry(&self) {
+        let (filter, len, sent) = match self.operation.get() {
+            Operation::Dump { filter, len, sent } => (filter, len, sent),
+            _ => return,
+        };
+        let (out, buffer) = match (self.dump_buffer.take(), self.buffer.take()) {
+            (Some(out), Some(buffer)) => (out, buffer),
+            (out, buffer) => {
+                out.map(|out| self.dump_buffer.replace(out));
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                return self.finish(Err(ErrorCode::RESERVE));
+            }
+        };
+        let id = self.log.next_read_entry_id() - len - ENTRY_HEADER_SIZE;
+        // The entry was checked when it was read
+        let (kind, log, data) = parse_entry(&buffer[..len]).unwrap_or((KIND_ERASED, &[], &[]));
+        let mut n = 0;
+        let mut done = match sent {
+            Some(done) => done,
+            None => {
+                n += write_hex(&mut out[n..], id as u32);
+                out[n] = b' ';
+                n += 1;
+                if log.len() >= 2 {
+                    let (process, name) = log[2..].split_at(log[0] as usize);
+                    for part in [process, b"/", name].iter() {
+                        let len = cmp::min(part.len(), out.len() - 11 - n);
+                        out[n..n + len].copy_from_slice(&part[..len]);
+                        n += len;
+                    }
+                }
+                out[n] = b':';
+                n += 1;
+                if kind == KIND_ERASED {
+                    out[n..n + 7].copy_from_slice(b" erased");
+                    n += 7;
+                }
+                0
+            }
+        };
+        if kind == KIND_DATA {
+            // Leave room for the line break
+            while done < data.len() && n + 3 <= out.len() - 2 {
+                out[n] = b' ';
+                out[n + 1] = HEX[(data[done] >> 4) as usize];
+                out[n + 2] = HEX[(data[done] & 0xf) as usize];
+                n += 3;
+                done += 1;
+            }
+        }
+        if kind != KIND_DATA || done == data.len() {
+            out[n..n + 2].copy_from_slice(b"\r\n");
+            n += 2;
+            self.operation.set(Operation::Dump {
+                filter,
+                len,
+                sent: None,
+            });
+        } else {
+            self.operation.set(Operation::Dump {
+                filter,
+                len,
+                sent: Some(done),
+            });
+        }
+        self.buffer.replace(buffer);
+        if let Err((e, out)) = self.uart.transmit_buffer(out, n) {
+            self.dump_buffer.replace(out);
+            self.finish(Err(e));
+        }
+    }
+
+    fn finish(&self, result: Result<(usize, usize), ErrorCode>) {
+        self.operation.set(Operation::Idle);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app| {
+                let (a, b) = result.unwrap_or((0, 0));
+                app.callback
+                    .schedule(kernel::into_statuscode(result.map(|_| ())), a, b);
+            });
+        });
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// Write `value` as eight hex digits.
+fn write_hex(out: &mut [u8], value: u32) -> usize {
+    for (i, byte) in out[..8].iter_mut().enumerate() {
+        *byte = HEX[(value >> (28 - 4 * i) & 0xf) as usize];
+    }
+    8
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>, C: ProcessManagementCapability> LogDump
+    for LogDriver<'a, L, C>
+{
+    fn dump(&self, filter: Option<(&str, &str)>) -> Result<(), ErrorCode> {
+        let filter = match filter {
+            Some((process, name)) => Some(LogId::new(process.as_bytes(), name.as_bytes())?),
+            None => None,
+        };
+        let operation = Operation::Dump {
+            filter,
+            len: 0,
+            sent: None,
+        };
+        self.start_seek(None, operation, self.log.log_start())
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>, C: ProcessManagementCapability>
+    LogReadClient for LogDriver<'a, L, C>
+{
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: Result<(), ErrorCode>) {
+        match error {
+            Ok(()) => {
+                self.buffer.replace(buffer);
+                self.entry_read(length);
+            }
+            Err(ErrorCode::FAIL) => {
+                // Corrupt entry, which cannot be attributed to any log
+                self.buffer.replace(buffer);
+                self.read_next();
+            }
+            Err(e) => {
+                self.buffer.replace(buffer);
+                self.finish(Err(e));
+            }
+        }
+    }
+
+    fn seek_done(&self, error: Result<(), ErrorCode>) {
+        match error {
+            Ok(()) => self.read_next(),
+            Err(e) => self.finish(Err(e)),
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>, C: ProcessManagementCapability>
+    LogWriteClient for LogDriver<'a, L, C>
+{
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+        let appid = match self.current_app.extract() {
+            Some(appid) => appid,
+            None => return self.finish(Err(ErrorCode::FAIL)),
+        };
+        if let Err(e) = error {
+            return self.finish(Err(e));
+        }
+        let end = self.log.log_end();
+        if self.operation.get() == Operation::Erase {
+            self.update_log(appid, |open| {
+                open.start = end;
+                open.position = end;
+                open.used = 0;
+            });
+            self.finish(Ok((0, 0)));
+        } else {
+            self.update_log(appid, |open| open.used += length + ENTRY_HEADER_SIZE);
+            self.finish(Ok((
+                end - length - ENTRY_HEADER_SIZE,
+                records_lost as usize,
+            )));
+        }
+    }
+
+    fn sync_done(&self, error: Result<(), ErrorCode>) {
+        self.finish(error.map(|()| (0, 0)));
+    }
+
+    fn erase_done(&self, error: Result<(), ErrorCode>) {
+        self.finish(error.map(|()| (0, 0)));
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>, C: ProcessManagementCapability>
+    uart::TransmitClient for LogDriver<'a, L, C>
+{
+    fn transmitted_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        _tx_len: usize,
+        rcode: Result<(), ErrorCode>,
+    ) {
+        self.dump_buffer.replace(buffer);
+        match (rcode, self.operation.get()) {
+            (Err(e), _) => self.finish(Err(e)),
+            (Ok(()), Operation::Dump { sent: None, .. }) => self.read_next(),
+            (Ok(()), Operation::Dump { .. }) => self.print_entry(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>, C: ProcessManagementCapability> Driver
+    for LogDriver<'a, L, C>
+{
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.read_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|e| e.into());
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.name, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.write_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|e| e.into());
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|e| e.into());
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        _arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            // open
+            1 => {
+                let info = KernelInfo::new(self.kernel);
+                let process = info.process_name(appid, &self.capability);
+                let id = self
+                    .apps
+                    .enter(appid, |app| {
+                        app.name.map_or(Err(ErrorCode::INVAL), |name| {
+                            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                            LogId::new(process.as_bytes(), &name[..len])
+                        })
+                    })
+                    .unwrap_or(Err(ErrorCode::NOMEM));
+                match id {
+                    Ok(id) => {
+                        let operation = Operation::Open {
+                            id,
+                            start: self.log.log_start(),
+                            used: 0,
+                        };
+                        self.start_seek(Some(appid), operation, self.log.log_start())
+                            .into()
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            // append
+            2 => {
+                if arg1 == 0 {
+                    CommandReturn::failure(ErrorCode::INVAL)
+                } else {
+                    self.start_append(appid, KIND_DATA, arg1).into()
+                }
+            }
+
+            // read
+            3 => match self.open_log(appid) {
+                Ok(open) => self
+                    .start_seek(
+                        Some(appid),
+                        Operation::Read,
+                        cmp::max(open.position, open.start),
+                    )
+                    .into(),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // seek
+            4 => match self.open_log(appid) {
+                Ok(open) => {
+                    if arg1 < open.start || arg1 > self.log.log_end() {
+                        CommandReturn::failure(ErrorCode::INVAL)
+                    } else {
+                        self.update_log(appid, |open| open.position = arg1);
+                        CommandReturn::success()
+                    }
+                }
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // read position
+            5 => match self.open_log(appid) {
+                Ok(open) => CommandReturn::success_u32(cmp::max(
+                    cmp::max(open.position, open.start),
+                    self.log.log_start(),
+                ) as u32),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            // sync
+            6 => {
+                if let Err(e) = self.open_log(appid) {
+                    return CommandReturn::failure(e);
+                }
+                if self.operation.get() != Operation::Idle {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                self.operation.set(Operation::Sync);
+                self.current_app.set(appid);
+                match self.log.sync() {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => {
+                        self.operation.set(Operation::Idle);
+                        self.current_app.clear();
+                        CommandReturn::failure(e)
+                    }
+                }
+            }
+
+            // erase
+            7 => self.start_append(appid, KIND_ERASED, 0).into(),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn entry(id: &LogId, kind: u8, data: &[u8]) -> Vec<u8> {
+        let mut buffer = std::vec![0; id.header_len() + data.len()];
+        id.write_header(&mut buffer, kind);
+        buffer[id.header_len()..].copy_from_slice(data);
+        buffer
+    }
+
+    #[test]
+    fn entries_carry_both_names() {
+        let id = LogId::new(b"sensor", b"events").unwrap();
+        let buffer = entry(&id, KIND_DATA, b"data");
+        assert_eq!(buffer.len(), HEADER_LEN + 6 + 6 + 4);
+        let (kind, log, data) = parse_entry(&buffer).unwrap();
+        assert_eq!(kind, KIND_DATA);
+        assert!(log == id.as_bytes());
+        assert_eq!(data, b"data");
+
+        // Truncated headers were not written by the driver
+        assert!(parse_entry(&buffer[..HEADER_LEN + 5]).is_none());
+        assert!(parse_entry(&[KIND_DATA, 0]).is_none());
+        let empty = entry(&id, KIND_ERASED, b"");
+        assert_eq!(parse_entry(&empty).unwrap().2, b"");
+    }
+
+    #[test]
+    fn logs_with_colliding_hashes_are_separate() {
+        // Both pairs of names have the same 32 bit FNV-1a hash, which entries
+        // were once tagged with.
+        let fnv = |process: &[u8], name: &[u8]| {
+            process
+                .iter()
+                .chain([0].iter())
+                .chain(name.iter())
+                .fold(0x811c_9dc5u32, |hash, &byte| {
+                    (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+                })
+        };
+        assert_eq!(
+            fnv(b"sensor59888", b"events"),
+            fnv(b"sensor545242", b"events")
+        );
+
+        let first = LogId::new(b"sensor59888", b"events").unwrap();
+        let second = LogId::new(b"sensor545242", b"events").unwrap();
+        let buffer = entry(&first, KIND_DATA, b"secret");
+        let (_, log, _) = parse_entry(&buffer).unwrap();
+        assert!(log == first.as_bytes());
+        assert!(log != second.as_bytes());
+
+        // Names are not simply concatenated
+        let split = LogId::new(b"sensor5", b"9888events").unwrap();
+        assert!(log != split.as_bytes());
+    }
+
+    #[test]
+    fn name_limits() {
+        let long = [b'a'; MAX_NAME_LEN + 1];
+        assert!(LogId::new(b"app", b"").is_err());
+        assert!(LogId::new(b"app", &long).is_err());
+        assert!(LogId::new(&long, b"log").map(|_| ()) == Err(ErrorCode::SIZE));
+        let id = LogId::new(&long[..MAX_PROCESS_NAME_LEN], &long[..MAX_NAME_LEN]).unwrap();
+        assert_eq!(
+            id.header_len(),
+            HEADER_LEN + MAX_PROCESS_NAME_LEN + MAX_NAME_LEN
+        );
+        assert!(HEADER_LEN + MAX_PROCESS_NAME_LEN + MAX_NAME_LEN < BUFFER_LEN);
+    }
+}
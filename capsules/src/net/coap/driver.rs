@@ -0,0 +1,1278 @@
+//! CoAP (RFC 7252) server and client for userspace.
+//!
+//! The driver binds the CoAP port (5683) through the `UdpPortManager` and
+//! shares it between apps:
+//!
+//! - As a server, an app registers resources by URI path. A request for a
+//!   registered path is copied into the app's receive buffer and signalled
+//!   with an upcall, and the app answers it with the `respond` command.
+//!   Requests for paths nobody registered are answered with 4.04 (Not
+//!   Found) by the kernel, and requests for an app that is still busy with
+//!   another one with 5.03 (Service Unavailable).
+//! - As a client, an app sends a request to a remote endpoint and receives
+//!   an upcall once the response arrived.
+//!
+//! Confirmable messages are retransmitted with exponential back-off until
+//! they are acknowledged (RFC 7252, Section 4.2). Only one confirmable
+//! message is outstanding at a time: confirmable requests of other apps wait
+//! until it is acknowledged or times out.
+//!
+//! Responses are piggybacked on the acknowledgement of a confirmable
+//! request. If the app has not responded yet when the client retransmits
+//! the request, the kernel acknowledges it, and the response is later sent
+//! as a separate message.
+//!
+//! Bodies longer than `BLOCK_SIZE` are transferred block-wise (RFC 7959) so
+//! that every message fits into a single 6LoWPAN frame. This works in both
+//! directions and for both requests (Block1) and responses (Block2). Blocks
+//! are reassembled in and served from the app's buffers, so a body is only
+//! limited by the size of the buffers the app shares.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let coap_driver = components::coap_driver::CoapDriverComponent::new(
+//!     board_kernel,
+//!     udp_send_mux,
+//!     udp_recv_mux,
+//!     udp_port_table,
+//!     mux_alarm,
+//!     rng,
+//! )
+//! .finalize(components::coap_driver_component_helper!(nrf52840::rtc::Rtc));
+//! ```
+
+use crate::driver;
+use crate::net::coap::message::{
+    code, option, Block, Message, MessageWriter, MsgType, Token, COAP_PORT,
+};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::rng::Random;
+use kernel::hil::time::{self, Alarm};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+pub const DRIVER_NUM: usize = driver::NUM::Coap as usize;
+
+/// Number of resources each app can register
+pub const MAX_RESOURCES: usize = 4;
+/// Longest URI path of a resource or request, without the leading `/`
+pub const MAX_PATH_LEN: usize = 32;
+/// Size of the blocks of a block-wise transfer
+pub const BLOCK_SIZE: usize = 16 << BLOCK_SZX;
+const BLOCK_SZX: u8 = 2;
+
+/// Size of the buffers passed to `CoapDriver::new`. It fits the header, a
+/// token, a URI path of `MAX_PATH_LEN` bytes, the block options and one
+/// block of payload.
+pub const COAP_BUF_SIZE: usize = 160;
+
+// Transmission parameters (RFC 7252, Section 4.8)
+const ACK_TIMEOUT_MS: u32 = 2000;
+const MAX_RETRANSMIT: u8 = 4;
+
+const TOKEN_LEN: usize = 4;
+
+/// An endpoint, as exchanged with apps: 16 bytes of IPv6 address followed
+/// by the port in network byte order.
+const ENDPOINT_LEN: usize = 18;
+
+/// The link-local and site-local All CoAP Nodes addresses (ff02::fd and
+/// ff05::fd), used for resource discovery
+const ALL_COAP_NODES: [IPAddr; 2] = [
+    IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfd]),
+    IPAddr([0xff, 0x05, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfd]),
+];
+
+/// Critical options the server understands. Requests carrying any other
+/// critical option are rejected with 4.02 (Bad Option).
+const SUPPORTED_OPTIONS: [u16; 5] = [
+    option::URI_HOST,
+    option::URI_PORT,
+    option::URI_PATH,
+    option::BLOCK2,
+    option::BLOCK1,
+];
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+struct Endpoint {
+    addr: IPAddr,
+    port: u16,
+}
+
+impl Endpoint {
+    fn decode(buf: &[u8]) -> Option<Endpoint> {
+        if buf.len() < ENDPOINT_LEN {
+            return None;
+        }
+        let mut addr = IPAddr::new();
+        addr.0.copy_from_slice(&buf[..16]);
+        Some(Endpoint {
+            addr,
+            port: (buf[16] as u16) << 8 | buf[17] as u16,
+        })
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        if buf.len() >= ENDPOINT_LEN {
+            buf[..16].copy_from_slice(&self.addr.0);
+            buf[16] = (self.port >> 8) as u8;
+            buf[17] = self.port as u8;
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Path {
+    bytes: [u8; MAX_PATH_LEN],
+    len: usize,
+}
+
+impl Path {
+    /// Copies `path`, without its leading `/`.
+    fn new(path: &[u8]) -> Result<Path, ErrorCode> {
+        let path = path.strip_prefix(b"/").unwrap_or(path);
+        if path.len() > MAX_PATH_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        let mut bytes = [0; MAX_PATH_LEN];
+        bytes[..path.len()].copy_from_slice(path);
+        Ok(Path {
+            bytes,
+            len: path.len(),
+        })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum ServerState {
+    /// The request was delivered to the app, which has not responded yet
+    Waiting,
+    /// The app responded with this code and body length
+    Ready(u8, usize),
+    /// The response was sent. Kept to resend it if the request is
+    /// duplicated, and to serve further blocks of the body.
+    Done(u8, usize),
+}
+
+/// A request served by an app
+#[derive(Copy, Clone)]
+struct Exchange {
+    peer: Endpoint,
+    msg_id: u16,
+    token: Token,
+    confirmable: bool,
+    /// The request was acknowledged by an empty ACK, so the response is sent
+    /// separately.
+    acked: bool,
+    resource: usize,
+    /// The final block of a block-wise request, which is echoed in the
+    /// response
+    block1: Option<Block>,
+    /// The block of the response body the client asked for
+    block2: Option<Block>,
+    state: ServerState,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum ClientState {
+    /// The next message of the request is waiting to be sent
+    Pending,
+    /// Waiting for the response
+    Sent,
+}
+
+/// A request sent by an app
+#[derive(Copy, Clone)]
+struct ClientRequest {
+    peer: Endpoint,
+    method: u8,
+    confirmable: bool,
+    token: Token,
+    msg_id: u16,
+    path: Path,
+    /// Length of the request body
+    len: usize,
+    /// The next block of the request body, if it is sent block-wise
+    block1: Option<Block>,
+    /// The next block of the response body to ask for
+    block2: Block,
+    state: ClientState,
+}
+
+/// The outstanding confirmable message, which is retransmitted until it is
+/// acknowledged
+#[derive(Copy, Clone)]
+struct Confirmable {
+    peer: Endpoint,
+    msg_id: u16,
+    len: usize,
+    retransmits: u8,
+    timeout_ms: u32,
+    app: ProcessId,
+    /// Whether this is a client request, rather than a separate response
+    client: bool,
+}
+
+/// A message generated by the kernel, rather than on behalf of an app
+#[derive(Copy, Clone)]
+struct Reply {
+    peer: Endpoint,
+    mtype: MsgType,
+    msg_id: u16,
+    token: Token,
+    code: u8,
+    block1: Option<Block>,
+    size1: Option<u32>,
+}
+
+impl Reply {
+    fn empty(peer: Endpoint, mtype: MsgType, msg_id: u16) -> Reply {
+        Reply {
+            peer,
+            mtype,
+            msg_id,
+            token: Token::empty(),
+            code: code::EMPTY,
+            block1: None,
+            size1: None,
+        }
+    }
+
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+        let mut writer = MessageWriter::new(buf, self.mtype, self.code, self.msg_id, &self.token)?;
+        if let Some(block) = self.block1 {
+            writer.block(option::BLOCK1, block)?;
+        }
+        if let Some(size) = self.size1 {
+            writer.option_uint(option::SIZE1, size)?;
+        }
+        Ok(writer.finish())
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    request_callback: Upcall,
+    response_callback: Upcall,
+    rx_buffer: ReadWriteAppSlice,
+    peer_buffer: ReadWriteAppSlice,
+    tx_buffer: ReadOnlyAppSlice,
+    path_buffer: ReadOnlyAppSlice,
+    dest_buffer: ReadOnlyAppSlice,
+    resources: [Option<Path>; MAX_RESOURCES],
+    exchange: Option<Exchange>,
+    /// Resource and number of bytes received of a block-wise request
+    block1: Option<(usize, usize)>,
+    client: Option<ClientRequest>,
+}
+
+/// Copies `data` into `buf` at `offset`. Returns false if it does not fit.
+fn copy_in(buf: &mut ReadWriteAppSlice, offset: usize, data: &[u8]) -> bool {
+    if data.is_empty() {
+        return true;
+    }
+    buf.mut_map_or(false, |buf| {
+        if buf.len() < offset + data.len() {
+            false
+        } else {
+            buf[offset..offset + data.len()].copy_from_slice(data);
+            true
+        }
+    })
+}
+
+/// Returns the part of `body` that goes into `block`, and the block with
+/// its `more` flag set accordingly.
+fn block_of(body: &[u8], block: Block) -> (&[u8], Block) {
+    let start = block.offset().min(body.len());
+    let end = (start + block.size()).min(body.len());
+    (
+        &body[start..end],
+        Block::new(block.num, end < body.len(), block.szx),
+    )
+}
+
+pub struct CoapDriver<'a, A: Alarm<'a>> {
+    sender: &'a dyn UDPSender<'a>,
+    receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    alarm: &'a A,
+    rng: &'a dyn Random<'a>,
+    apps: Grant<App>,
+    net_cap: &'static NetworkCapability,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    /// Copy of the outstanding confirmable message, for retransmissions
+    con_buffer: TakeCell<'static, [u8]>,
+    con: OptionalCell<Confirmable>,
+    retransmit: Cell<bool>,
+    /// Kernel generated reply waiting to be sent. Further replies are dropped
+    /// while one is pending; the peer retransmits confirmable requests.
+    reply: OptionalCell<Reply>,
+    msg_id: Cell<u16>,
+}
+
+impl<'a, A: Alarm<'a>> CoapDriver<'a, A> {
+    pub fn new(
+        sender: &'a dyn UDPSender<'a>,
+        receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        alarm: &'a A,
+        rng: &'a dyn Random<'a>,
+        grant: Grant<App>,
+        tx_buffer: &'static mut [u8],
+        con_buffer: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> CoapDriver<'a, A> {
+        CoapDriver {
+            sender: sender,
+            receiver: receiver,
+            port_table: port_table,
+            alarm: alarm,
+            rng: rng,
+            apps: grant,
+            net_cap: net_cap,
+            tx_buffer: MapCell::new(LeasableBuffer::new(tx_buffer)),
+            con_buffer: TakeCell::new(con_buffer),
+            con: OptionalCell::empty(),
+            retransmit: Cell::new(false),
+            reply: OptionalCell::empty(),
+            msg_id: Cell::new(0),
+        }
+    }
+
+    /// Binds the CoAP port and joins the All CoAP Nodes groups. Returns
+    /// BUSY if the port is already bound, and NOMEM if the port table has no
+    /// free socket.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if self.sender.is_bound() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let socket = self
+            .port_table
+            .create_socket()
+            .map_err(|_| ErrorCode::NOMEM)?;
+        // Dropping the socket on failure frees it again
+        let (send_binding, recv_binding) = self
+            .port_table
+            .bind(socket, COAP_PORT, self.net_cap)
+            .map_err(|_| ErrorCode::BUSY)?;
+        self.sender.set_binding(send_binding);
+        self.receiver.set_binding(recv_binding);
+        for group in ALL_COAP_NODES.iter() {
+            let _ = self.receiver.join_group(*group);
+        }
+        self.msg_id.set(self.rng.random() as u16);
+        Ok(())
+    }
+
+    fn next_msg_id(&self) -> u16 {
+        let msg_id = self.msg_id.get().wrapping_add(1);
+        self.msg_id.set(msg_id);
+        msg_id
+    }
+
+    /// Builds a reply to `msg` with `code`, piggybacked on the
+    /// acknowledgement if `msg` is confirmable.
+    fn reply_to(&self, peer: Endpoint, msg: &Message, code: u8) -> Reply {
+        let (mtype, msg_id) = if msg.mtype == MsgType::Confirmable {
+            (MsgType::Acknowledgement, msg.msg_id)
+        } else {
+            (MsgType::NonConfirmable, self.next_msg_id())
+        };
+        Reply {
+            peer,
+            mtype,
+            msg_id,
+            token: msg.token,
+            code,
+            block1: None,
+            size1: None,
+        }
+    }
+
+    fn queue_reply(&self, reply: Reply) {
+        if self.reply.is_none() {
+            self.reply.set(reply);
+        }
+    }
+
+    /// Stops retransmitting the outstanding confirmable message.
+    fn stop_con(&self) {
+        self.con.clear();
+        self.retransmit.set(false);
+        let _ = self.alarm.disarm();
+    }
+
+    /// Remembers the confirmable message `msg` for retransmission. If it
+    /// does not fit the retransmission buffer, it is only sent once.
+    fn start_con(&self, app: ProcessId, client: bool, peer: Endpoint, msg_id: u16, msg: &[u8]) {
+        let copied = self.con_buffer.map_or(false, |con_buffer| {
+            if con_buffer.len() < msg.len() {
+                false
+            } else {
+                con_buffer[..msg.len()].copy_from_slice(msg);
+                true
+            }
+        });
+        if !copied {
+            return;
+        }
+        // The initial timeout is random between ACK_TIMEOUT and
+        // ACK_TIMEOUT * ACK_RANDOM_FACTOR (1.5)
+        let timeout_ms = ACK_TIMEOUT_MS + self.rng.random() % (ACK_TIMEOUT_MS / 2);
+        self.con.set(Confirmable {
+            peer,
+            msg_id,
+            len: msg.len(),
+            retransmits: 0,
+            timeout_ms,
+            app,
+            client,
+        });
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(timeout_ms));
+    }
+
+    /// Ends the request of the client `appid` with `result`.
+    fn finish_client(&self, appid: ProcessId, result: Result<(), ErrorCode>) {
+        let _ = self.apps.enter(appid, |app| {
+            if app.client.take().is_some() {
+                app.response_callback
+                    .schedule(kernel::into_statuscode(result), 0, 0);
+            }
+        });
+    }
+
+    fn handle_empty(&self, peer: Endpoint, msg: &Message) {
+        match msg.mtype {
+            // A CoAP ping, answered by a reset
+            MsgType::Confirmable => {
+                self.queue_reply(Reply::empty(peer, MsgType::Reset, msg.msg_id));
+            }
+            MsgType::Acknowledgement | MsgType::Reset => {
+                let con = match self
+                    .con
+                    .extract()
+                    .filter(|con| con.peer == peer && con.msg_id == msg.msg_id)
+                {
+                    Some(con) => con,
+                    None => return,
+                };
+                self.stop_con();
+                // An empty ACK to a request means the response will be sent
+                // separately, so the client keeps waiting. A reset means the
+                // peer rejected the message.
+                if msg.mtype == MsgType::Reset && con.client {
+                    self.finish_client(con.app, Err(ErrorCode::FAIL));
+                }
+            }
+            MsgType::NonConfirmable => {}
+        }
+    }
+
+    fn handle_request(&self, peer: Endpoint, multicast: bool, msg: &Message) {
+        // Multicast requests are not answered with errors (RFC 7252,
+        // Section 8.1), so that only servers with the resource respond.
+        let queue_reply = |reply: Reply| {
+            if !multicast {
+                self.queue_reply(reply);
+            }
+        };
+        if msg.unsupported_critical(&SUPPORTED_OPTIONS).is_some() {
+            queue_reply(self.reply_to(peer, msg, code::BAD_OPTION));
+            return;
+        }
+
+        let mut target = None;
+        for app in self.apps.iter() {
+            let appid = app.processid();
+            app.enter(|app| {
+                target = app
+                    .resources
+                    .iter()
+                    .position(|resource| {
+                        resource.map_or(false, |path| msg.uri_path_matches(path.as_slice()))
+                    })
+                    .map(|resource| (appid, resource));
+            });
+            if target.is_some() {
+                break;
+            }
+        }
+
+        let reply = match target {
+            Some((appid, resource)) => self
+                .apps
+                .enter(appid, |app| self.deliver_request(app, resource, peer, msg))
+                .unwrap_or_else(|_| Some(self.reply_to(peer, msg, code::NOT_FOUND))),
+            None => Some(self.reply_to(peer, msg, code::NOT_FOUND)),
+        };
+        reply.map(queue_reply);
+    }
+
+    /// Hands a request for `resource` to `app`. Returns the reply if the
+    /// kernel answers the request itself.
+    fn deliver_request(
+        &self,
+        app: &mut App,
+        resource: usize,
+        peer: Endpoint,
+        msg: &Message,
+    ) -> Option<Reply> {
+        if let Some(exchange) = app.exchange.as_mut() {
+            if exchange.peer == peer && exchange.msg_id == msg.msg_id {
+                // A duplicate of the current request
+                match exchange.state {
+                    ServerState::Waiting => {
+                        if exchange.confirmable && !exchange.acked {
+                            exchange.acked = true;
+                            return Some(Reply::empty(peer, MsgType::Acknowledgement, msg.msg_id));
+                        }
+                    }
+                    ServerState::Ready(..) => {}
+                    // The response got lost
+                    ServerState::Done(code, len) => {
+                        exchange.state = ServerState::Ready(code, len);
+                    }
+                }
+                return None;
+            }
+            if let ServerState::Waiting | ServerState::Ready(..) = exchange.state {
+                return Some(self.reply_to(peer, msg, code::SERVICE_UNAVAILABLE));
+            }
+        }
+
+        let confirmable = msg.mtype == MsgType::Confirmable;
+        let block2 = msg.block2().map(|block| block.with_szx(BLOCK_SZX));
+
+        // Further blocks of the last response are served without involving
+        // the app
+        if let Some(block2) = block2.filter(|block| block.num > 0) {
+            return match app.exchange {
+                Some(Exchange {
+                    peer: last_peer,
+                    resource: last_resource,
+                    state: ServerState::Done(code, len),
+                    ..
+                }) if last_peer == peer && last_resource == resource && block2.offset() < len => {
+                    app.exchange = Some(Exchange {
+                        peer,
+                        msg_id: msg.msg_id,
+                        token: msg.token,
+                        confirmable,
+                        acked: false,
+                        resource,
+                        block1: None,
+                        block2: Some(block2),
+                        state: ServerState::Ready(code, len),
+                    });
+                    None
+                }
+                _ => Some(self.reply_to(peer, msg, code::BAD_REQUEST)),
+            };
+        }
+
+        let mut len = msg.payload.len();
+        let mut block1_echo = None;
+        if let Some(block1) = msg.block1() {
+            let received = match app.block1 {
+                Some((last_resource, received)) if last_resource == resource && block1.num > 0 => {
+                    received
+                }
+                _ => 0,
+            };
+            let offset = block1.offset();
+            let end = offset + msg.payload.len();
+            let mut reply = self.reply_to(peer, msg, code::CONTINUE);
+            reply.block1 = Some(Block::new(block1.num, true, block1.szx));
+
+            if block1.more && offset < received && end == received {
+                // A duplicate of the last block, whose 2.31 got lost
+                return Some(reply);
+            }
+            if offset != received || (block1.more && msg.payload.len() != block1.size()) {
+                app.block1 = None;
+                reply.code = code::REQUEST_ENTITY_INCOMPLETE;
+                reply.block1 = None;
+                return Some(reply);
+            }
+            if !copy_in(&mut app.rx_buffer, offset, msg.payload) {
+                app.block1 = None;
+                reply.code = code::REQUEST_ENTITY_TOO_LARGE;
+                reply.block1 = None;
+                reply.size1 = Some(app.rx_buffer.len() as u32);
+                return Some(reply);
+            }
+            if block1.more {
+                app.block1 = Some((resource, end));
+                return Some(reply);
+            }
+            app.block1 = None;
+            len = end;
+            block1_echo = Some(Block::new(block1.num, false, block1.szx));
+        } else if !copy_in(&mut app.rx_buffer, 0, msg.payload) {
+            let mut reply = self.reply_to(peer, msg, code::REQUEST_ENTITY_TOO_LARGE);
+            reply.size1 = Some(app.rx_buffer.len() as u32);
+            return Some(reply);
+        }
+
+        app.peer_buffer.mut_map_or((), |buf| peer.encode(buf));
+        app.exchange = Some(Exchange {
+            peer,
+            msg_id: msg.msg_id,
+            token: msg.token,
+            confirmable,
+            acked: false,
+            resource,
+            block1: block1_echo,
+            block2,
+            state: ServerState::Waiting,
+        });
+        app.request_callback
+            .schedule(resource, msg.code as usize, len);
+        None
+    }
+
+    fn handle_response(&self, peer: Endpoint, msg: &Message) {
+        if msg.mtype == MsgType::Reset {
+            return;
+        }
+        if msg.mtype == MsgType::Acknowledgement
+            && self
+                .con
+                .map_or(false, |con| con.peer == peer && con.msg_id == msg.msg_id)
+        {
+            self.stop_con();
+        }
+
+        let mut matched = false;
+        for app in self.apps.iter() {
+            let appid = app.processid();
+            app.enter(|app| match app.client {
+                Some(request)
+                    if request.state == ClientState::Sent
+                        && request.peer == peer
+                        && request.token == msg.token =>
+                {
+                    matched = true;
+                    // The response implies the request arrived, even if its
+                    // acknowledgement did not
+                    if self.con.map_or(false, |con| con.client && con.app == appid) {
+                        self.stop_con();
+                    }
+                    self.handle_client_response(app, request, msg);
+                }
+                _ => {}
+            });
+            if matched {
+                break;
+            }
+        }
+
+        if msg.mtype == MsgType::Confirmable {
+            let mtype = if matched {
+                MsgType::Acknowledgement
+            } else {
+                MsgType::Reset
+            };
+            self.queue_reply(Reply::empty(peer, mtype, msg.msg_id));
+        }
+    }
+
+    fn handle_client_response(&self, app: &mut App, mut request: ClientRequest, msg: &Message) {
+        // The server asks for the next block of the request body
+        if let Some(block1) = request.block1 {
+            if msg.code == code::CONTINUE && (block1.num as usize + 1) * block1.size() < request.len
+            {
+                request.block1 = Some(Block::new(block1.num + 1, false, block1.szx));
+                request.state = ClientState::Pending;
+                app.client = Some(request);
+                return;
+            }
+        }
+
+        let block2 = msg.block2();
+        if block2.map_or(false, |block| {
+            block.num != request.block2.with_szx(block.szx).num
+        }) {
+            app.client = None;
+            app.response_callback
+                .schedule(kernel::into_statuscode(Err(ErrorCode::FAIL)), 0, 0);
+            return;
+        }
+        let offset = block2.map_or(0, |block| block.offset());
+        if !copy_in(&mut app.rx_buffer, offset, msg.payload) {
+            app.client = None;
+            app.response_callback
+                .schedule(kernel::into_statuscode(Err(ErrorCode::SIZE)), 0, 0);
+            return;
+        }
+
+        match block2 {
+            Some(block) if block.more => {
+                // Ask for the next block of the response. The request body
+                // was already transferred.
+                request.len = 0;
+                request.block1 = None;
+                request.block2 = Block::new(block.num + 1, false, block.szx);
+                request.state = ClientState::Pending;
+                app.client = Some(request);
+            }
+            _ => {
+                app.client = None;
+                app.response_callback.schedule(
+                    kernel::into_statuscode(Ok(())),
+                    msg.code as usize,
+                    offset + msg.payload.len(),
+                );
+            }
+        }
+    }
+
+    /// Builds the response of `app` into `buf`, if it has one ready.
+    fn prepare_response(
+        &self,
+        appid: ProcessId,
+        app: &mut App,
+        buf: &mut [u8],
+    ) -> Option<(Endpoint, usize)> {
+        let mut exchange = app.exchange?;
+        let (code, len) = match exchange.state {
+            ServerState::Ready(code, len) => (code, len),
+            _ => return None,
+        };
+        // A separate response is confirmable if the request was, unless
+        // another confirmable message is outstanding.
+        let (mtype, msg_id) = if exchange.confirmable && !exchange.acked {
+            (MsgType::Acknowledgement, exchange.msg_id)
+        } else if exchange.confirmable && self.con.is_none() {
+            (MsgType::Confirmable, self.next_msg_id())
+        } else {
+            (MsgType::NonConfirmable, self.next_msg_id())
+        };
+
+        let result = app.tx_buffer.map_or(Err(ErrorCode::NOMEM), |body| {
+            let body = &body[..len.min(body.len())];
+            let (body, block2) = match exchange.block2 {
+                Some(block) => {
+                    let (body, block) = block_of(body, block);
+                    (body, Some(block))
+                }
+                None if body.len() > BLOCK_SIZE => {
+                    let (body, block) = block_of(body, Block::new(0, false, BLOCK_SZX));
+                    (body, Some(block))
+                }
+                None => (body, None),
+            };
+            let mut writer = MessageWriter::new(buf, mtype, code, msg_id, &exchange.token)?;
+            if let Some(block) = block2 {
+                writer.block(option::BLOCK2, block)?;
+            }
+            if let Some(block) = exchange.block1 {
+                writer.block(option::BLOCK1, block)?;
+            }
+            writer.payload(body)
+        });
+        let msg_len = result
+            .or_else(|_| {
+                MessageWriter::new(
+                    buf,
+                    mtype,
+                    code::INTERNAL_SERVER_ERROR,
+                    msg_id,
+                    &exchange.token,
+                )
+                .map(|writer| writer.finish())
+            })
+            .ok()?;
+
+        exchange.state = ServerState::Done(code, len);
+        app.exchange = Some(exchange);
+        if mtype == MsgType::Confirmable {
+            self.start_con(appid, false, exchange.peer, msg_id, &buf[..msg_len]);
+        }
+        Some((exchange.peer, msg_len))
+    }
+
+    /// Builds the next message of the request of `app` into `buf`, if one is
+    /// waiting to be sent.
+    fn prepare_request(
+        &self,
+        appid: ProcessId,
+        app: &mut App,
+        buf: &mut [u8],
+    ) -> Option<(Endpoint, usize)> {
+        let mut request = app.client?;
+        if request.state != ClientState::Pending || (request.confirmable && self.con.is_some()) {
+            return None;
+        }
+        request.msg_id = self.next_msg_id();
+        let mtype = if request.confirmable {
+            MsgType::Confirmable
+        } else {
+            MsgType::NonConfirmable
+        };
+
+        let result = app.tx_buffer.map_or(Err(ErrorCode::NOMEM), |body| {
+            let body = &body[..request.len.min(body.len())];
+            let mut writer =
+                MessageWriter::new(buf, mtype, request.method, request.msg_id, &request.token)?;
+            writer.uri_path(request.path.as_slice())?;
+            writer.block(option::BLOCK2, request.block2)?;
+            match request.block1 {
+                Some(block) => {
+                    let (body, block) = block_of(body, block);
+                    writer.block(option::BLOCK1, block)?;
+                    writer.payload(body)
+                }
+                None => writer.payload(body),
+            }
+        });
+
+        match result {
+            Ok(msg_len) => {
+                request.state = ClientState::Sent;
+                app.client = Some(request);
+                if request.confirmable {
+                    self.start_con(appid, true, request.peer, request.msg_id, &buf[..msg_len]);
+                }
+                Some((request.peer, msg_len))
+            }
+            Err(e) => {
+                app.client = None;
+                app.response_callback
+                    .schedule(kernel::into_statuscode(Err(e)), 0, 0);
+                None
+            }
+        }
+    }
+
+    /// Builds the next message to send into `buf`. Retransmissions go
+    /// first, then kernel generated replies, then responses and requests of
+    /// apps.
+    fn prepare_next(&self, buf: &mut [u8]) -> Option<(Endpoint, usize)> {
+        if self.retransmit.replace(false) {
+            let retransmission = self.con.and_then(|con| {
+                self.con_buffer.map(|con_buffer| {
+                    buf[..con.len].copy_from_slice(&con_buffer[..con.len]);
+                    (con.peer, con.len)
+                })
+            });
+            if retransmission.is_some() {
+                return retransmission;
+            }
+        }
+
+        if let Some(reply) = self.reply.take() {
+            if let Ok(len) = reply.encode(buf) {
+                return Some((reply.peer, len));
+            }
+        }
+
+        for app in self.apps.iter() {
+            let appid = app.processid();
+            let next = app.enter(|app| {
+                self.prepare_response(appid, app, buf)
+                    .or_else(|| self.prepare_request(appid, app, buf))
+            });
+            if next.is_some() {
+                return next;
+            }
+        }
+        None
+    }
+
+    /// Sends messages until the transmit buffer is in use or nothing is
+    /// left to send.
+    fn send_next(&self) {
+        while let Some(mut buffer) = self.tx_buffer.take() {
+            buffer.reset();
+            match self.prepare_next(&mut buffer[..]) {
+                Some((peer, len)) => {
+                    buffer.slice(0..len);
+                    if let Err(mut buffer) =
+                        self.sender
+                            .send_to(peer.addr, peer.port, buffer, self.net_cap)
+                    {
+                        // The message is lost. Confirmable messages are
+                        // retransmitted later.
+                        buffer.reset();
+                        self.tx_buffer.replace(buffer);
+                    }
+                }
+                None => {
+                    self.tx_buffer.replace(buffer);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for CoapDriver<'a, A> {
+    /// Setup buffers to read/write from.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Receive buffer. Contains the body of requests to the app's
+    ///        resources, and of responses to its requests.
+    /// - `1`: Peer buffer (18 bytes). Contains the endpoint (IPv6 address and
+    ///        port in network byte order) that sent the last request to the
+    ///        app's resources.
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.rx_buffer, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.peer_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Transmit buffer. Contains the body of responses and requests.
+    ///        It must stay shared while a response body is transferred
+    ///        block-wise.
+    /// - `1`: Path buffer. Contains the URI path to register, or to send a
+    ///        request to, e.g. `sensors/temp`.
+    /// - `2`: Destination buffer (18 bytes). Contains the endpoint (IPv6
+    ///        address and port in network byte order) to send requests to.
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(appid, |app| match allow_num {
+                0 => {
+                    mem::swap(&mut app.tx_buffer, &mut slice);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.path_buffer, &mut slice);
+                    Ok(())
+                }
+                2 => {
+                    mem::swap(&mut app.dest_buffer, &mut slice);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if let Err(e) = res {
+            Err((slice, e))
+        } else {
+            Ok(slice)
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Request callback, called with the index of the resource, the
+    ///        method and the length of the body in the receive buffer. The
+    ///        app must answer with command `3`.
+    /// - `1`: Response callback, called with the status of the request, the
+    ///        response code and the length of the body in the receive
+    ///        buffer. NOACK means a confirmable request was never
+    ///        acknowledged.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        app_id: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = self
+            .apps
+            .enter(app_id, |app| match subscribe_num {
+                0 => {
+                    mem::swap(&mut app.request_callback, &mut callback);
+                    Ok(())
+                }
+                1 => {
+                    mem::swap(&mut app.response_callback, &mut callback);
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NOSUPPORT),
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        if let Err(e) = res {
+            Err((callback, e))
+        } else {
+            Ok(callback)
+        }
+    }
+
+    /// CoAP control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Register the path in the path buffer as a resource. Returns the
+    ///        index of the resource. Returns BUSY if the path is registered
+    ///        already, NOMEM if the app registered `MAX_RESOURCES` resources
+    ///        and SIZE if the path is too long.
+    /// - `2`: Unregister resource `arg1`.
+    /// - `3`: Respond to the current request with code `arg1` and the first
+    ///        `arg2` bytes of the transmit buffer as body. Returns INVAL if
+    ///        no request is waiting for a response or the code is not a
+    ///        response code.
+    /// - `4`: Send a request with method `arg1 & 0xff` to the path in the
+    ///        path buffer at the endpoint in the destination buffer, with the
+    ///        first `arg2` bytes of the transmit buffer as body. The request
+    ///        is confirmable if bit 8 of `arg1` is set. Returns BUSY if the
+    ///        app has a request in progress.
+    /// - `5`: Cancel the request in progress.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        let res = match command_num {
+            0 => return CommandReturn::success(),
+
+            1 => {
+                let path = self
+                    .apps
+                    .enter(appid, |app| {
+                        app.path_buffer
+                            .map_or(Err(ErrorCode::INVAL), |path| Path::new(path))
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                let path = match path {
+                    Ok(path) => path,
+                    Err(e) => return CommandReturn::failure(e),
+                };
+                let mut registered = false;
+                for app in self.apps.iter() {
+                    app.enter(|app| {
+                        registered |= app.resources.iter().any(|resource| {
+                            resource.map_or(false, |r| r.as_slice() == path.as_slice())
+                        });
+                    });
+                }
+                if registered {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                return self
+                    .apps
+                    .enter(appid, |app| {
+                        match app.resources.iter().position(|r| r.is_none()) {
+                            Some(idx) => {
+                                app.resources[idx] = Some(path);
+                                CommandReturn::success_u32(idx as u32)
+                            }
+                            None => CommandReturn::failure(ErrorCode::NOMEM),
+                        }
+                    })
+                    .unwrap_or_else(|err| CommandReturn::failure(err.into()));
+            }
+
+            2 => self
+                .apps
+                .enter(appid, |app| {
+                    if app.resources.get(arg1).map_or(true, |r| r.is_none()) {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    app.resources[arg1] = None;
+                    if app.exchange.map_or(false, |e| e.resource == arg1) {
+                        app.exchange = None;
+                    }
+                    if app.block1.map_or(false, |(resource, _)| resource == arg1) {
+                        app.block1 = None;
+                    }
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+
+            3 => self
+                .apps
+                .enter(appid, |app| {
+                    let response_code = arg1 as u8;
+                    if arg1 > 0xff || !code::is_response(response_code) {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    if arg2 > app.tx_buffer.len() {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    match app.exchange.as_mut() {
+                        Some(exchange) if exchange.state == ServerState::Waiting => {
+                            exchange.state = ServerState::Ready(response_code, arg2);
+                            Ok(())
+                        }
+                        _ => Err(ErrorCode::INVAL),
+                    }
+                })
+                .unwrap_or_else(|err| Err(err.into())),
+
+            4 => {
+                let token = Token::new(&self.rng.random().to_be_bytes()[..TOKEN_LEN]);
+                self.apps
+                    .enter(appid, |app| {
+                        if app.client.is_some() {
+                            return Err(ErrorCode::BUSY);
+                        }
+                        let method = (arg1 & 0xff) as u8;
+                        if !code::is_request(method) {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        if arg2 > app.tx_buffer.len() {
+                            return Err(ErrorCode::SIZE);
+                        }
+                        let peer = app
+                            .dest_buffer
+                            .map_or(None, |dest| Endpoint::decode(dest))
+                            .ok_or(ErrorCode::INVAL)?;
+                        let path = app
+                            .path_buffer
+                            .map_or(Err(ErrorCode::INVAL), |path| Path::new(path))?;
+                        app.client = Some(ClientRequest {
+                            peer,
+                            method,
+                            confirmable: arg1 & 0x100 != 0,
+                            token,
+                            msg_id: 0,
+                            path,
+                            len: arg2,
+                            block1: if arg2 > BLOCK_SIZE {
+                                Some(Block::new(0, true, BLOCK_SZX))
+                            } else {
+                                None
+                            },
+                            block2: Block::new(0, false, BLOCK_SZX),
+                            state: ClientState::Pending,
+                        });
+                        Ok(())
+                    })
+                    .unwrap_or_else(|err| Err(err.into()))
+            }
+
+            5 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app| {
+                        app.client.take().map_or(Err(ErrorCode::INVAL), |_| Ok(()))
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                if self.con.map_or(false, |con| con.client && con.app == appid) {
+                    self.stop_con();
+                }
+                res
+            }
+
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => {
+                self.send_next();
+                CommandReturn::success()
+            }
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for CoapDriver<'a, A> {
+    fn send_done(&self, _result: Result<(), ErrorCode>, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buffer.replace(dgram);
+        self.send_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for CoapDriver<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        // Malformed messages are silently ignored
+        let msg = match Message::decode(payload).done() {
+            Some((_, msg)) => msg,
+            None => return,
+        };
+        let peer = Endpoint {
+            addr: src_addr,
+            port: src_port,
+        };
+        if code::is_request(msg.code) {
+            if let MsgType::Confirmable | MsgType::NonConfirmable = msg.mtype {
+                self.handle_request(peer, dst_addr.is_multicast(), &msg);
+            }
+        } else if msg.code == code::EMPTY {
+            self.handle_empty(peer, &msg);
+        } else if code::is_response(msg.code) {
+            self.handle_response(peer, &msg);
+        }
+        self.send_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for CoapDriver<'a, A> {
+    fn alarm(&self) {
+        if let Some(mut con) = self.con.extract() {
+            if con.retransmits < MAX_RETRANSMIT {
+                con.retransmits += 1;
+                con.timeout_ms = con.timeout_ms.saturating_mul(2);
+                self.con.set(con);
+                self.retransmit.set(true);
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(con.timeout_ms));
+            } else {
+                self.con.clear();
+                if con.client {
+                    self.finish_client(con.app, Err(ErrorCode::NOACK));
+                }
+            }
+        }
+        self.send_next();
+    }
+}
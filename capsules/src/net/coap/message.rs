@@ -0,0 +1,695 @@
+//! CoAP message format (RFC 7252, Section 3) and the Block1/Block2 options
+//! used for block-wise transfers (RFC 7959).
+//!
+//! A CoAP message is a fixed four byte header, followed by a token of up to
+//! eight bytes, a sequence of options and an optional payload:
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |Ver| T |  TKL  |      Code     |          Message ID           |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |   Token (if any, TKL bytes) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |   Options (if any) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |1 1 1 1 1 1 1 1|    Payload (if any) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! Options are stored in order of their option number, and each option only
+//! encodes the difference (delta) to the number of the previous one.
+//! `Message::decode` validates the whole option sequence, so iterating over
+//! the options of a decoded message cannot fail. `MessageWriter` serializes
+//! a message and computes the deltas, as long as options are added in
+//! ascending order.
+
+use crate::net::stream::SResult;
+use kernel::ErrorCode;
+
+/// The well-known UDP port of CoAP
+pub const COAP_PORT: u16 = 5683;
+
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 4;
+pub const MAX_TOKEN_LEN: usize = 8;
+const PAYLOAD_MARKER: u8 = 0xff;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MsgType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl MsgType {
+    fn from_u8(t: u8) -> MsgType {
+        match t & 0x3 {
+            0 => MsgType::Confirmable,
+            1 => MsgType::NonConfirmable,
+            2 => MsgType::Acknowledgement,
+            _ => MsgType::Reset,
+        }
+    }
+}
+
+/// Method and response codes, in their `c.dd` encoding (three bit class,
+/// five bit detail).
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+
+    pub const GET: u8 = 0x01;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+
+    pub const CREATED: u8 = 0x41;
+    pub const DELETED: u8 = 0x42;
+    pub const VALID: u8 = 0x43;
+    pub const CHANGED: u8 = 0x44;
+    pub const CONTENT: u8 = 0x45;
+    pub const CONTINUE: u8 = 0x5f;
+
+    pub const BAD_REQUEST: u8 = 0x80;
+    pub const BAD_OPTION: u8 = 0x82;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const REQUEST_ENTITY_INCOMPLETE: u8 = 0x88;
+    pub const REQUEST_ENTITY_TOO_LARGE: u8 = 0x8d;
+
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+    pub const SERVICE_UNAVAILABLE: u8 = 0xa3;
+
+    pub fn class(code: u8) -> u8 {
+        code >> 5
+    }
+
+    pub fn is_request(code: u8) -> bool {
+        code != EMPTY && class(code) == 0
+    }
+
+    pub fn is_response(code: u8) -> bool {
+        class(code) >= 2 && class(code) <= 5
+    }
+}
+
+/// Option numbers
+pub mod option {
+    pub const IF_MATCH: u16 = 1;
+    pub const URI_HOST: u16 = 3;
+    pub const ETAG: u16 = 4;
+    pub const IF_NONE_MATCH: u16 = 5;
+    pub const URI_PORT: u16 = 7;
+    pub const LOCATION_PATH: u16 = 8;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const MAX_AGE: u16 = 14;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+    pub const LOCATION_QUERY: u16 = 20;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+    pub const SIZE2: u16 = 28;
+    pub const PROXY_URI: u16 = 35;
+    pub const PROXY_SCHEME: u16 = 39;
+    pub const SIZE1: u16 = 60;
+
+    /// Critical options must be understood by the receiver, which rejects
+    /// the message otherwise. They have odd option numbers.
+    pub fn is_critical(number: u16) -> bool {
+        number & 1 == 1
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Token {
+    len: u8,
+    bytes: [u8; MAX_TOKEN_LEN],
+}
+
+impl Token {
+    pub fn empty() -> Token {
+        Token {
+            len: 0,
+            bytes: [0; MAX_TOKEN_LEN],
+        }
+    }
+
+    /// Creates a token from the first `MAX_TOKEN_LEN` bytes of `bytes`.
+    pub fn new(bytes: &[u8]) -> Token {
+        let len = bytes.len().min(MAX_TOKEN_LEN);
+        let mut token = Token::empty();
+        token.bytes[..len].copy_from_slice(&bytes[..len]);
+        token.len = len as u8;
+        token
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// The value of a Block1 or Block2 option: the number of the block, whether
+/// more blocks follow and the size exponent. Blocks are `16 << szx` bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Block {
+    pub num: u32,
+    pub more: bool,
+    pub szx: u8,
+}
+
+impl Block {
+    /// The largest size exponent, for blocks of 1024 bytes.
+    pub const MAX_SZX: u8 = 6;
+
+    pub fn new(num: u32, more: bool, szx: u8) -> Block {
+        Block { num, more, szx }
+    }
+
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    /// Offset of this block in the whole body
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// Returns the same position in the body, described with blocks of the
+    /// (smaller or equal) size exponent `szx`.
+    pub fn with_szx(&self, szx: u8) -> Block {
+        if szx < self.szx {
+            Block::new(self.num << (self.szx - szx), self.more, szx)
+        } else {
+            *self
+        }
+    }
+
+    pub fn decode(value: &[u8]) -> Option<Block> {
+        let v = decode_uint(value)?;
+        let szx = (v & 0x7) as u8;
+        if szx > Block::MAX_SZX || v >> 4 >= 1 << 20 {
+            return None;
+        }
+        Some(Block::new(v >> 4, v & 0x8 != 0, szx))
+    }
+
+    pub fn value(&self) -> u32 {
+        self.num << 4 | (self.more as u32) << 3 | self.szx as u32
+    }
+}
+
+/// Decodes a `uint` option value, which is big endian without leading zero
+/// bytes.
+pub fn decode_uint(value: &[u8]) -> Option<u32> {
+    if value.len() > 4 {
+        return None;
+    }
+    Some(value.iter().fold(0, |acc, b| acc << 8 | *b as u32))
+}
+
+/// Decodes the option header at the start of `buf`. Returns the length of
+/// the header, the delta and the length of the value.
+fn decode_option_header(buf: &[u8]) -> Option<(usize, u16, usize)> {
+    let first = *buf.get(0)?;
+    let mut off = 1;
+    let mut ext = |nibble: u8| -> Option<usize> {
+        match nibble {
+            13 => {
+                let b = *buf.get(off)? as usize;
+                off += 1;
+                Some(b + 13)
+            }
+            14 => {
+                let b = (*buf.get(off)? as usize) << 8 | *buf.get(off + 1)? as usize;
+                off += 2;
+                Some(b + 269)
+            }
+            15 => None,
+            n => Some(n as usize),
+        }
+    };
+    let delta = ext(first >> 4)?;
+    let len = ext(first & 0xf)?;
+    if delta > u16::MAX as usize {
+        return None;
+    }
+    Some((off, delta as u16, len))
+}
+
+fn option_nibble(value: usize) -> (u8, usize) {
+    if value < 13 {
+        (value as u8, 0)
+    } else if value < 269 {
+        (13, 1)
+    } else {
+        (14, 2)
+    }
+}
+
+/// Iterator over the `(number, value)` pairs of the options of a message.
+#[derive(Clone)]
+pub struct Options<'b> {
+    buf: &'b [u8],
+    number: u16,
+}
+
+impl<'b> Iterator for Options<'b> {
+    type Item = (u16, &'b [u8]);
+
+    fn next(&mut self) -> Option<(u16, &'b [u8])> {
+        let (hdr_len, delta, len) = decode_option_header(self.buf)?;
+        let value = self.buf.get(hdr_len..hdr_len + len)?;
+        self.number = self.number.checked_add(delta)?;
+        self.buf = &self.buf[hdr_len + len..];
+        Some((self.number, value))
+    }
+}
+
+/// A decoded CoAP message, borrowing its options and payload from the
+/// receive buffer.
+pub struct Message<'b> {
+    pub mtype: MsgType,
+    pub code: u8,
+    pub msg_id: u16,
+    pub token: Token,
+    options: &'b [u8],
+    pub payload: &'b [u8],
+}
+
+impl<'b> Message<'b> {
+    pub fn decode(buf: &'b [u8]) -> SResult<Message<'b>> {
+        stream_len_cond!(buf, HEADER_LEN);
+        stream_cond!(buf[0] >> 6 == VERSION);
+        let tkl = (buf[0] & 0xf) as usize;
+        stream_cond!(tkl <= MAX_TOKEN_LEN);
+        let code = buf[1];
+        let msg_id = (buf[2] as u16) << 8 | buf[3] as u16;
+        stream_len_cond!(buf, HEADER_LEN + tkl);
+        let token = Token::new(&buf[HEADER_LEN..HEADER_LEN + tkl]);
+
+        // Walk the options to find where they end
+        let start = HEADER_LEN + tkl;
+        let mut off = start;
+        let mut number: u16 = 0;
+        while off < buf.len() && buf[off] != PAYLOAD_MARKER {
+            let (hdr_len, delta, len) = stream_from_option!(decode_option_header(&buf[off..]));
+            number = stream_from_option!(number.checked_add(delta));
+            off += hdr_len + len;
+            stream_len_cond!(buf, off);
+        }
+        let options = &buf[start..off];
+        let payload = if off < buf.len() {
+            // A payload marker followed by an empty payload is an error
+            stream_cond!(off + 1 < buf.len());
+            &buf[off + 1..]
+        } else {
+            &buf[off..]
+        };
+        // An empty message is only a header
+        stream_cond!(code != code::EMPTY || buf.len() == HEADER_LEN);
+
+        stream_done!(
+            buf.len(),
+            Message {
+                mtype: MsgType::from_u8(buf[0] >> 4),
+                code,
+                msg_id,
+                token,
+                options,
+                payload,
+            }
+        );
+    }
+
+    pub fn options(&self) -> Options<'b> {
+        Options {
+            buf: self.options,
+            number: 0,
+        }
+    }
+
+    /// Returns the value of the first occurrence of option `number`.
+    pub fn option(&self, number: u16) -> Option<&'b [u8]> {
+        self.options()
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| value)
+    }
+
+    pub fn block1(&self) -> Option<Block> {
+        self.option(option::BLOCK1).and_then(Block::decode)
+    }
+
+    pub fn block2(&self) -> Option<Block> {
+        self.option(option::BLOCK2).and_then(Block::decode)
+    }
+
+    /// Returns the first critical option whose number is not in
+    /// `supported`, if there is one.
+    pub fn unsupported_critical(&self, supported: &[u16]) -> Option<u16> {
+        self.options()
+            .map(|(n, _)| n)
+            .find(|n| option::is_critical(*n) && !supported.contains(n))
+    }
+
+    /// Checks whether the Uri-Path options of this message spell out `path`,
+    /// whose segments are separated by `/` and which has no leading `/`.
+    pub fn uri_path_matches(&self, path: &[u8]) -> bool {
+        let mut segments = self
+            .options()
+            .filter(|(n, _)| *n == option::URI_PATH)
+            .map(|(_, value)| value);
+        if path.is_empty() {
+            return segments.next().is_none();
+        }
+        let mut expected = path.split(|b| *b == b'/');
+        loop {
+            match (expected.next(), segments.next()) {
+                (None, None) => return true,
+                (Some(e), Some(s)) if e == s => {}
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// Serializes a CoAP message into a buffer. Options must be added in
+/// ascending order of their numbers, followed by the payload.
+pub struct MessageWriter<'b> {
+    buf: &'b mut [u8],
+    off: usize,
+    number: u16,
+}
+
+impl<'b> MessageWriter<'b> {
+    pub fn new(
+        buf: &'b mut [u8],
+        mtype: MsgType,
+        code: u8,
+        msg_id: u16,
+        token: &Token,
+    ) -> Result<MessageWriter<'b>, ErrorCode> {
+        let token = token.as_slice();
+        let len = HEADER_LEN + token.len();
+        if buf.len() < len {
+            return Err(ErrorCode::SIZE);
+        }
+        buf[0] = VERSION << 6 | (mtype as u8) << 4 | token.len() as u8;
+        buf[1] = code;
+        buf[2] = (msg_id >> 8) as u8;
+        buf[3] = msg_id as u8;
+        buf[HEADER_LEN..len].copy_from_slice(token);
+        Ok(MessageWriter {
+            buf,
+            off: len,
+            number: 0,
+        })
+    }
+
+    /// Appends an option. Returns INVAL if `number` is smaller than the
+    /// number of the previous option, and SIZE if the buffer is full.
+    pub fn option(&mut self, number: u16, value: &[u8]) -> Result<(), ErrorCode> {
+        if number < self.number {
+            return Err(ErrorCode::INVAL);
+        }
+        let delta = (number - self.number) as usize;
+        let (delta_nibble, delta_ext) = option_nibble(delta);
+        let (len_nibble, len_ext) = option_nibble(value.len());
+        let total = 1 + delta_ext + len_ext + value.len();
+        if self.buf.len() < self.off + total {
+            return Err(ErrorCode::SIZE);
+        }
+
+        self.buf[self.off] = delta_nibble << 4 | len_nibble;
+        let mut off = self.off + 1;
+        for &(ext, v) in [(delta_ext, delta), (len_ext, value.len())].iter() {
+            match ext {
+                1 => self.buf[off] = (v - 13) as u8,
+                2 => {
+                    self.buf[off] = ((v - 269) >> 8) as u8;
+                    self.buf[off + 1] = (v - 269) as u8;
+                }
+                _ => {}
+            }
+            off += ext;
+        }
+        self.buf[off..off + value.len()].copy_from_slice(value);
+        self.off = off + value.len();
+        self.number = number;
+        Ok(())
+    }
+
+    /// Appends an option with a `uint` value, using as few bytes as possible.
+    pub fn option_uint(&mut self, number: u16, value: u32) -> Result<(), ErrorCode> {
+        let bytes = value.to_be_bytes();
+        let skip = (value.leading_zeros() / 8) as usize;
+        self.option(number, &bytes[skip..])
+    }
+
+    pub fn block(&mut self, number: u16, block: Block) -> Result<(), ErrorCode> {
+        self.option_uint(number, block.value())
+    }
+
+    /// Appends one Uri-Path option for each segment of `path`. Empty
+    /// segments are skipped.
+    pub fn uri_path(&mut self, path: &[u8]) -> Result<(), ErrorCode> {
+        for segment in path.split(|b| *b == b'/').filter(|s| !s.is_empty()) {
+            self.option(option::URI_PATH, segment)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the payload, if there is one, and returns the length of the
+    /// complete message.
+    pub fn payload(self, payload: &[u8]) -> Result<usize, ErrorCode> {
+        if payload.is_empty() {
+            return Ok(self.off);
+        }
+        let len = self.off + 1 + payload.len();
+        if self.buf.len() < len {
+            return Err(ErrorCode::SIZE);
+        }
+        self.buf[self.off] = PAYLOAD_MARKER;
+        self.buf[self.off + 1..len].copy_from_slice(payload);
+        Ok(len)
+    }
+
+    /// Returns the length of a message without payload.
+    pub fn finish(self) -> usize {
+        self.off
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A confirmable GET with message ID 1 and no token
+    const HEADER: [u8; 4] = [0x40, code::GET, 0x00, 0x01];
+
+    /// Decodes a header followed by `options`, keeping only the outcome.
+    fn decode_with(options: &[u8]) -> SResult {
+        let mut buf = [0; 64];
+        buf[..HEADER_LEN].copy_from_slice(&HEADER);
+        buf[HEADER_LEN..HEADER_LEN + options.len()].copy_from_slice(options);
+        match Message::decode(&buf[..HEADER_LEN + options.len()]) {
+            SResult::Done(off, _) => SResult::Done(off, ()),
+            SResult::Needed(bytes) => SResult::Needed(bytes),
+            SResult::Error(err) => SResult::Error(err),
+        }
+    }
+
+    #[test]
+    fn token_lengths() {
+        let bytes = [0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7];
+        for len in 0..=MAX_TOKEN_LEN {
+            let token = Token::new(&bytes[..len]);
+            let mut buf = [0; 16];
+            let writer =
+                MessageWriter::new(&mut buf, MsgType::NonConfirmable, code::POST, 7, &token);
+            let msg_len = writer.unwrap().finish();
+            assert_eq!(msg_len, HEADER_LEN + len);
+            assert_eq!(buf[0], 0x50 | len as u8);
+
+            let (off, message) = Message::decode(&buf[..msg_len]).done().unwrap();
+            assert_eq!(off, msg_len);
+            assert_eq!(message.token.as_slice(), &bytes[..len]);
+            assert_eq!(message.mtype, MsgType::NonConfirmable);
+            assert_eq!(message.msg_id, 7);
+
+            if len > 0 {
+                // The token does not fit
+                let mut short = [0; 16];
+                let writer = MessageWriter::new(
+                    &mut short[..HEADER_LEN + len - 1],
+                    MsgType::Confirmable,
+                    code::GET,
+                    7,
+                    &token,
+                );
+                assert_eq!(writer.err(), Some(ErrorCode::SIZE));
+
+                // The token is cut short
+                assert!(Message::decode(&buf[..msg_len - 1]).is_needed());
+            }
+        }
+        assert_eq!(Token::new(&[1; 12]).as_slice(), &[1; MAX_TOKEN_LEN]);
+
+        // Token lengths 9 to 15 are reserved
+        let mut buf = [0x41; HEADER_LEN + 15];
+        buf[1] = code::GET;
+        for tkl in 9..16 {
+            buf[0] = 0x40 | tkl;
+            assert!(Message::decode(&buf).is_err());
+        }
+    }
+
+    #[test]
+    fn extended_option_delta_and_length() {
+        let values: [&[u8]; 5] = [&[], &[0xaa; 12], &[1; 13], &[2; 268], &[3; 269]];
+        // Deltas of 12, 13, 268, 269 and 1000
+        let numbers = [12, 25, 293, 562, 1562];
+        let mut buf = [0; 1024];
+        let mut writer = MessageWriter::new(
+            &mut buf,
+            MsgType::Confirmable,
+            code::PUT,
+            2,
+            &Token::empty(),
+        )
+        .unwrap();
+        for (&number, value) in numbers.iter().zip(values.iter()) {
+            writer.option(number, value).unwrap();
+        }
+        let len = writer.payload(b"body").unwrap();
+
+        let mut off = HEADER_LEN;
+        let headers: [&[u8]; 5] = [
+            &[0xc0],
+            &[0xdc, 0x00],
+            &[0xdd, 0xff, 0x00],
+            &[0xed, 0x00, 0x00, 0xff],
+            &[0xee, 0x02, 0xdb, 0x00, 0x00],
+        ];
+        for (header, value) in headers.iter().zip(values.iter()) {
+            assert_eq!(&buf[off..off + header.len()], *header);
+            off += header.len() + value.len();
+        }
+        assert_eq!(buf[off], PAYLOAD_MARKER);
+        assert_eq!(len, off + 5);
+
+        let (_, message) = Message::decode(&buf[..len]).done().unwrap();
+        let mut options = message.options();
+        for (&number, &value) in numbers.iter().zip(values.iter()) {
+            assert_eq!(options.next(), Some((number, value)));
+        }
+        assert_eq!(options.next(), None);
+        assert_eq!(message.option(293), Some(&[1; 13][..]));
+        assert_eq!(message.payload, b"body");
+    }
+
+    #[test]
+    fn options_are_written_in_order() {
+        let mut buf = [0; 12];
+        let mut writer = MessageWriter::new(
+            &mut buf,
+            MsgType::Confirmable,
+            code::GET,
+            3,
+            &Token::empty(),
+        )
+        .unwrap();
+        writer.option(option::URI_PATH, b"ab").unwrap();
+        writer.option(option::URI_PATH, b"cd").unwrap();
+        assert_eq!(writer.option(option::URI_HOST, b"x"), Err(ErrorCode::INVAL));
+        assert_eq!(writer.option(option::ACCEPT, b"xy"), Err(ErrorCode::SIZE));
+        assert_eq!(writer.payload(b"long"), Err(ErrorCode::SIZE));
+        assert_eq!(
+            &buf[HEADER_LEN..],
+            &[0xb2, b'a', b'b', 0x02, b'c', b'd', 0, 0]
+        );
+
+        let (_, message) = Message::decode(&buf[..10]).done().unwrap();
+        assert!(message.uri_path_matches(b"ab/cd"));
+        assert!(!message.uri_path_matches(b"ab"));
+        assert_eq!(message.unsupported_critical(&[]), Some(option::URI_PATH));
+        assert_eq!(message.unsupported_critical(&[option::URI_PATH]), None);
+    }
+
+    #[test]
+    fn block_options() {
+        let mut buf = [0; 32];
+        let mut writer = MessageWriter::new(
+            &mut buf,
+            MsgType::Confirmable,
+            code::PUT,
+            4,
+            &Token::empty(),
+        )
+        .unwrap();
+        // Block 20 of 1024 bytes, with more to follow
+        writer
+            .block(option::BLOCK2, Block::new(20, true, 6))
+            .unwrap();
+        // The first block of 16 bytes, which is encoded without any bytes
+        writer
+            .block(option::BLOCK1, Block::new(0, false, 0))
+            .unwrap();
+        let len = writer.finish();
+        assert_eq!(&buf[HEADER_LEN..len], &[0xd2, 10, 0x01, 0x4e, 0x40]);
+
+        let (_, message) = Message::decode(&buf[..len]).done().unwrap();
+        let block2 = message.block2().unwrap();
+        assert_eq!(block2, Block::new(20, true, 6));
+        assert_eq!(block2.size(), 1024);
+        assert_eq!(block2.offset(), 20 * 1024);
+        assert_eq!(block2.with_szx(4), Block::new(80, true, 4));
+        assert_eq!(block2.with_szx(4).offset(), block2.offset());
+        assert_eq!(message.block1(), Some(Block::new(0, false, 0)));
+
+        // The largest block number fits in three bytes
+        let last = Block::new((1 << 20) - 1, false, 2);
+        assert_eq!(Block::decode(&[0xff, 0xff, 0xf2]), Some(last));
+        assert_eq!(Block::decode(&[0x01, 0x00, 0x00, 0x02]), None);
+        // Size exponent 7 is reserved
+        assert_eq!(Block::decode(&[0x17]), None);
+        assert_eq!(Block::decode(&[0; 5]), None);
+    }
+
+    #[test]
+    fn malformed_input() {
+        assert!(decode_with(&[]).is_done());
+        assert!(Message::decode(&HEADER[..3]).is_needed());
+
+        // An option value cut short
+        assert!(decode_with(&[0x35, b'a']).is_needed());
+        assert!(decode_with(&[0xb3, b'a', b'b']).is_needed());
+        // Extended deltas and lengths cut short
+        assert!(decode_with(&[0xd0]).is_err());
+        assert!(decode_with(&[0xe0, 0x00]).is_err());
+        assert!(decode_with(&[0x0d]).is_err());
+        assert!(decode_with(&[0x0e, 0x00]).is_err());
+
+        // Nibble 15 is reserved for deltas and lengths, except in the
+        // payload marker
+        assert!(decode_with(&[0xf0]).is_err());
+        assert!(decode_with(&[0x1f, b'a']).is_err());
+        assert!(decode_with(&[0xff, b'a']).is_done());
+
+        // A payload marker without a payload
+        assert!(decode_with(&[0xff]).is_err());
+        assert!(decode_with(&[0xb1, b'a', 0xff]).is_err());
+
+        // Option numbers beyond 65535
+        assert!(decode_with(&[0xe0, 0xff, 0xff]).is_err());
+        assert!(decode_with(&[0xe0, 0xfd, 0xf2]).is_done());
+        assert!(decode_with(&[0xe0, 0xfd, 0xf2, 0xe0, 0x00, 0x01]).is_err());
+
+        // Wrong version, and an empty message with more than a header
+        assert!(Message::decode(&[0x80, code::GET, 0, 1]).is_err());
+        assert!(Message::decode(&[0x40, code::EMPTY, 0, 1]).is_done());
+        assert!(Message::decode(&[0x40, code::EMPTY, 0, 1, 0xff, 0]).is_err());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod driver;
+pub mod message;
+
+pub use self::driver::CoapDriver;
+pub use self::driver::DRIVER_NUM;
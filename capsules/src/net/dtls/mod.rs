@@ -0,0 +1,8 @@
+pub mod prf;
+pub mod record;
+pub mod session;
+
+pub use self::session::{DtlsClient, DtlsSession, DtlsTransport};
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,321 @@
+//! The TLS 1.2 pseudorandom function (RFC 5246, Section 5) on top of a
+//! SHA-256 `Digest` engine.
+//!
+//! ```text
+//! PRF(secret, label, seed) = P_SHA256(secret, label + seed)
+//! P_SHA256(secret, seed) = HMAC(secret, A(1) + seed) +
+//!                          HMAC(secret, A(2) + seed) + ...
+//! A(0) = seed, A(i) = HMAC(secret, A(i - 1))
+//! ```
+//!
+//! Digest engines only offer HMAC with 32 byte keys, while the DTLS master
+//! secret is 48 bytes long. HMAC is therefore computed from two plain SHA-256
+//! passes, HMAC(K, m) = H((K ^ opad) + H((K ^ ipad) + m)), where every pass
+//! hands a single buffer to the engine. The same engine hashes the handshake
+//! transcript for the Finished messages.
+
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::hil::digest::{Digest, Sha256};
+use kernel::ErrorCode;
+
+pub const SHA256_LEN: usize = 32;
+/// SHA-256 block size, which is also the longest HMAC key we support
+/// without hashing it first
+pub const MAX_SECRET_LEN: usize = 64;
+pub const MAX_LABEL_LEN: usize = 15;
+pub const MAX_SEED_LEN: usize = MAX_LABEL_LEN + 64;
+pub const MAX_OUTPUT_LEN: usize = 48;
+/// Size of the buffer handed to the engine, which bounds the data
+/// `Prf::sha256()` can hash. An HMAC pass needs at most
+/// MAX_SECRET_LEN + SHA256_LEN + MAX_SEED_LEN bytes.
+pub const HASH_BUF_LEN: usize = 384;
+
+const IPAD: u8 = 0x36;
+const OPAD: u8 = 0x5c;
+
+pub trait PrfClient {
+    /// A `Prf::compute()` finished. `output` holds the requested number of
+    /// bytes on success.
+    fn prf_done(&self, result: Result<(), ErrorCode>, output: &[u8]);
+
+    /// A `Prf::sha256()` finished.
+    fn sha256_done(&self, result: Result<(), ErrorCode>, hash: &[u8; SHA256_LEN]);
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Step {
+    Idle,
+    Sha256,
+    /// Inner and outer pass of A(i)
+    InnerA,
+    OuterA,
+    /// Inner and outer pass of HMAC(secret, A(i) + seed)
+    InnerOutput,
+    OuterOutput,
+}
+
+struct PrfState {
+    /// Zero padded to the block size, as HMAC does
+    secret: [u8; MAX_SECRET_LEN],
+    /// label + seed
+    seed: [u8; MAX_SEED_LEN],
+    seed_len: usize,
+    a: [u8; SHA256_LEN],
+    inner: [u8; SHA256_LEN],
+    output: [u8; MAX_OUTPUT_LEN],
+    output_len: usize,
+    produced: usize,
+}
+
+impl PrfState {
+    const fn new() -> PrfState {
+        PrfState {
+            secret: [0; MAX_SECRET_LEN],
+            seed: [0; MAX_SEED_LEN],
+            seed_len: 0,
+            a: [0; SHA256_LEN],
+            inner: [0; SHA256_LEN],
+            output: [0; MAX_OUTPUT_LEN],
+            output_len: 0,
+            produced: 0,
+        }
+    }
+
+    /// Writes the engine input of `step` into `buf` and returns its length.
+    fn fill(&self, step: Step, buf: &mut [u8]) -> usize {
+        let pad = match step {
+            Step::InnerA | Step::InnerOutput => IPAD,
+            _ => OPAD,
+        };
+        for (b, s) in buf.iter_mut().zip(self.secret.iter()) {
+            *b = s ^ pad;
+        }
+        let mut off = MAX_SECRET_LEN;
+        let mut append = |data: &[u8]| {
+            buf[off..off + data.len()].copy_from_slice(data);
+            off += data.len();
+        };
+        match step {
+            Step::InnerA if self.produced == 0 => append(&self.seed[..self.seed_len]),
+            Step::InnerA => append(&self.a),
+            Step::InnerOutput => {
+                append(&self.a);
+                append(&self.seed[..self.seed_len]);
+            }
+            _ => append(&self.inner),
+        }
+        off
+    }
+}
+
+pub struct Prf<'a, D: Digest<'a, [u8; SHA256_LEN]> + Sha256> {
+    digest: &'a D,
+    client: OptionalCell<&'a dyn PrfClient>,
+    step: Cell<Step>,
+    state: MapCell<PrfState>,
+    hash_buf: TakeCell<'static, [u8]>,
+    digest_buf: TakeCell<'static, [u8; SHA256_LEN]>,
+}
+
+impl<'a, D: Digest<'a, [u8; SHA256_LEN]> + Sha256> Prf<'a, D> {
+    pub fn new(
+        digest: &'a D,
+        hash_buf: &'static mut [u8],
+        digest_buf: &'static mut [u8; SHA256_LEN],
+    ) -> Prf<'a, D> {
+        Prf {
+            digest,
+            client: OptionalCell::empty(),
+            step: Cell::new(Step::Idle),
+            state: MapCell::new(PrfState::new()),
+            hash_buf: TakeCell::new(hash_buf),
+            digest_buf: TakeCell::new(digest_buf),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn PrfClient) {
+        self.client.set(client);
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.step.get() != Step::Idle
+    }
+
+    /// Computes `len` bytes of PRF(secret, label, seed1 + seed2). Returns
+    /// SIZE if any of the inputs or the output is too long.
+    pub fn compute(
+        &self,
+        secret: &[u8],
+        label: &[u8],
+        seed1: &[u8],
+        seed2: &[u8],
+        len: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.is_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        let seed_len = label.len() + seed1.len() + seed2.len();
+        if secret.len() > MAX_SECRET_LEN
+            || label.len() > MAX_LABEL_LEN
+            || seed_len > MAX_SEED_LEN
+            || len > MAX_OUTPUT_LEN
+        {
+            return Err(ErrorCode::SIZE);
+        }
+        self.state.map(|state| {
+            state.secret = [0; MAX_SECRET_LEN];
+            state.secret[..secret.len()].copy_from_slice(secret);
+            let mut off = 0;
+            for part in [label, seed1, seed2].iter() {
+                state.seed[off..off + part.len()].copy_from_slice(part);
+                off += part.len();
+            }
+            state.seed_len = seed_len;
+            state.output_len = len;
+            state.produced = 0;
+        });
+        self.digest.set_mode_sha256()?;
+        self.hash_pass(Step::InnerA).map_err(|e| {
+            self.digest.clear_data();
+            e
+        })
+    }
+
+    /// Computes the SHA-256 hash of `data`. Returns SIZE if `data` is longer
+    /// than HASH_BUF_LEN.
+    pub fn sha256(&self, data: &[u8]) -> Result<(), ErrorCode> {
+        if self.is_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        let mut buf = match self.hash_buf.take() {
+            Some(buf) => LeasableBuffer::new(buf),
+            None => return Err(ErrorCode::BUSY),
+        };
+        if data.len() > buf.len() {
+            self.hash_buf.replace(buf.take());
+            return Err(ErrorCode::SIZE);
+        }
+        buf[..data.len()].copy_from_slice(data);
+        buf.slice(0..data.len());
+        if let Err(e) = self.digest.set_mode_sha256() {
+            self.hash_buf.replace(buf.take());
+            return Err(e);
+        }
+        self.add_data(Step::Sha256, buf).map_err(|e| {
+            self.digest.clear_data();
+            e
+        })
+    }
+
+    fn hash_pass(&self, step: Step) -> Result<(), ErrorCode> {
+        let mut buf = match self.hash_buf.take() {
+            Some(buf) => LeasableBuffer::new(buf),
+            None => return Err(ErrorCode::BUSY),
+        };
+        let len = self.state.map_or(0, |state| state.fill(step, &mut buf[..]));
+        buf.slice(0..len);
+        self.add_data(step, buf)
+    }
+
+    fn add_data(&self, step: Step, buf: LeasableBuffer<'static, u8>) -> Result<(), ErrorCode> {
+        match self.digest.add_data(buf) {
+            Ok(_) => {
+                self.step.set(step);
+                Ok(())
+            }
+            Err((e, buf)) => {
+                self.hash_buf.replace(buf);
+                Err(e)
+            }
+        }
+    }
+
+    /// Releases the engine and reports the result of the current operation.
+    fn finish(&self, result: Result<(), ErrorCode>) {
+        let step = self.step.get();
+        self.step.set(Step::Idle);
+        self.digest.clear_data();
+        if step == Step::Sha256 {
+            let hash = self.state.map_or([0; SHA256_LEN], |state| state.inner);
+            self.client.map(|client| client.sha256_done(result, &hash));
+        } else {
+            let mut output = [0; MAX_OUTPUT_LEN];
+            let len = self.state.map_or(0, |state| {
+                output.copy_from_slice(&state.output);
+                state.output_len
+            });
+            self.client
+                .map(|client| client.prf_done(result, &output[..len]));
+        }
+    }
+}
+
+impl<'a, D: Digest<'a, [u8; SHA256_LEN]> + Sha256> digest::Client<'a, [u8; SHA256_LEN]>
+    for Prf<'a, D>
+{
+    fn add_data_done(&'a self, result: Result<(), ErrorCode>, data: &'static mut [u8]) {
+        self.hash_buf.replace(data);
+        if result.is_err() {
+            self.finish(result);
+            return;
+        }
+        let res = self.digest_buf.take().map_or(Err(ErrorCode::NOMEM), |buf| {
+            self.digest.run(buf).map_err(|(e, buf)| {
+                self.digest_buf.replace(buf);
+                e
+            })
+        });
+        if res.is_err() {
+            self.finish(res);
+        }
+    }
+
+    fn hash_done(&'a self, result: Result<(), ErrorCode>, digest: &'static mut [u8; SHA256_LEN]) {
+        let hash = *digest;
+        self.digest_buf.replace(digest);
+        if result.is_err() {
+            self.finish(result);
+            return;
+        }
+        let next = self.state.map_or(None, |state| match self.step.get() {
+            Step::InnerA => {
+                state.inner = hash;
+                Some(Step::OuterA)
+            }
+            Step::InnerOutput => {
+                state.inner = hash;
+                Some(Step::OuterOutput)
+            }
+            Step::OuterA => {
+                state.a = hash;
+                Some(Step::InnerOutput)
+            }
+            Step::OuterOutput => {
+                let n = core::cmp::min(SHA256_LEN, state.output_len - state.produced);
+                state.output[state.produced..state.produced + n].copy_from_slice(&hash[..n]);
+                state.produced += n;
+                if state.produced < state.output_len {
+                    Some(Step::InnerA)
+                } else {
+                    None
+                }
+            }
+            Step::Sha256 => {
+                state.inner = hash;
+                None
+            }
+            Step::Idle => None,
+        });
+        match next {
+            Some(step) => {
+                if let Err(e) = self.hash_pass(step) {
+                    self.finish(Err(e));
+                }
+            }
+            None => self.finish(Ok(())),
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! DTLS 1.2 (RFC 6347) record and handshake message formats, limited to the
+//! messages a pre-shared key client using TLS_PSK_WITH_AES_128_CCM_8
+//! (RFC 6655) sends and receives.
+//!
+//! Every record starts with a 13 byte header. Records protected with
+//! AES-128-CCM-8 carry an 8 byte explicit nonce before the ciphertext and an
+//! 8 byte authentication tag after it:
+//!
+//! ```text
+//! +------+---------+-------+-----------------+--------+
+//! | type | version | epoch | sequence number | length |
+//! |  1   |    2    |   2   |        6        |   2    |
+//! +------+---------+-------+-----------------+--------+
+//! | explicit nonce (8) | ciphertext ... | tag (8)     |
+//! +--------------------+----------------+-------------+
+//! ```
+//!
+//! The explicit nonce is the epoch and sequence number of the record, which
+//! makes it unique per key. Handshake messages have a 12 byte header of their
+//! own. Fragmented handshake messages are not supported: the PSK handshake
+//! messages are far shorter than any path MTU.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u8, encode_bytes, encode_u16, encode_u8};
+
+/// DTLS 1.2 on the wire
+pub const VERSION: u16 = 0xfefd;
+pub const RECORD_HEADER_LEN: usize = 13;
+pub const HANDSHAKE_HEADER_LEN: usize = 12;
+pub const EXPLICIT_NONCE_LEN: usize = 8;
+/// AES-128-CCM-8 authentication tag length
+pub const TAG_LEN: usize = 8;
+/// Bytes a protected record adds to its plaintext
+pub const RECORD_OVERHEAD: usize = RECORD_HEADER_LEN + EXPLICIT_NONCE_LEN + TAG_LEN;
+/// Length of the additional authenticated data of a protected record
+pub const AAD_LEN: usize = 13;
+pub const RANDOM_LEN: usize = 32;
+pub const VERIFY_DATA_LEN: usize = 12;
+/// Longest HelloVerifyRequest cookie we keep. RFC 6347 allows up to 255
+/// bytes, common implementations use 32 or less.
+pub const MAX_COOKIE_LEN: usize = 64;
+pub const TLS_PSK_WITH_AES_128_CCM_8: u16 = 0xc0a8;
+
+pub mod content_type {
+    pub const CHANGE_CIPHER_SPEC: u8 = 20;
+    pub const ALERT: u8 = 21;
+    pub const HANDSHAKE: u8 = 22;
+    pub const APPLICATION_DATA: u8 = 23;
+}
+
+pub mod handshake_type {
+    pub const CLIENT_HELLO: u8 = 1;
+    pub const SERVER_HELLO: u8 = 2;
+    pub const HELLO_VERIFY_REQUEST: u8 = 3;
+    pub const SERVER_KEY_EXCHANGE: u8 = 12;
+    pub const SERVER_HELLO_DONE: u8 = 14;
+    pub const CLIENT_KEY_EXCHANGE: u8 = 16;
+    pub const FINISHED: u8 = 20;
+}
+
+pub mod alert {
+    pub const LEVEL_WARNING: u8 = 1;
+    pub const LEVEL_FATAL: u8 = 2;
+    pub const CLOSE_NOTIFY: u8 = 0;
+}
+
+fn encode_u24(buf: &mut [u8], v: u32) -> SResult {
+    stream_len_cond!(buf, 3);
+    buf[0] = (v >> 16) as u8;
+    buf[1] = (v >> 8) as u8;
+    buf[2] = v as u8;
+    stream_done!(3);
+}
+
+fn decode_u24(buf: &[u8]) -> SResult<u32> {
+    stream_len_cond!(buf, 3);
+    stream_done!(
+        3,
+        (buf[0] as u32) << 16 | (buf[1] as u32) << 8 | buf[2] as u32
+    );
+}
+
+fn encode_u48(buf: &mut [u8], v: u64) -> SResult {
+    stream_len_cond!(buf, 6);
+    for i in 0..6 {
+        buf[i] = (v >> (40 - 8 * i)) as u8;
+    }
+    stream_done!(6);
+}
+
+fn decode_u48(buf: &[u8]) -> SResult<u64> {
+    stream_len_cond!(buf, 6);
+    let v = buf[..6].iter().fold(0u64, |v, b| v << 8 | *b as u64);
+    stream_done!(6, v);
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RecordHeader {
+    pub content_type: u8,
+    pub version: u16,
+    pub epoch: u16,
+    /// 48 bit sequence number
+    pub seq: u64,
+    pub len: u16,
+}
+
+impl RecordHeader {
+    pub fn new(content_type: u8, epoch: u16, seq: u64, len: usize) -> RecordHeader {
+        RecordHeader {
+            content_type,
+            version: VERSION,
+            epoch,
+            seq,
+            len: len as u16,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let mut off = enc_consume!(buf; encode_u8, self.content_type);
+        off = enc_consume!(buf, off; encode_u16, self.version);
+        off = enc_consume!(buf, off; self; encode_explicit_nonce);
+        off = enc_consume!(buf, off; encode_u16, self.len);
+        stream_done!(off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<RecordHeader> {
+        let (off, content_type) = dec_try!(buf; decode_u8);
+        let (off, version) = dec_try!(buf, off; decode_u16);
+        let (off, epoch) = dec_try!(buf, off; decode_u16);
+        let (off, seq) = dec_try!(buf, off; decode_u48);
+        let (off, len) = dec_try!(buf, off; decode_u16);
+        stream_done!(
+            off,
+            RecordHeader {
+                content_type,
+                version,
+                epoch,
+                seq,
+                len,
+            }
+        );
+    }
+
+    /// The explicit part of the CCM nonce: epoch and sequence number.
+    pub fn encode_explicit_nonce(&self, buf: &mut [u8]) -> SResult {
+        let off = enc_consume!(buf; encode_u16, self.epoch);
+        let off = enc_consume!(buf, off; encode_u48, self.seq);
+        stream_done!(off);
+    }
+
+    /// The additional authenticated data of a protected record with
+    /// `plain_len` bytes of plaintext (RFC 5246, Section 6.2.3.3).
+    pub fn encode_aad(&self, buf: &mut [u8], plain_len: usize) -> SResult {
+        let off = enc_consume!(buf; self; encode_explicit_nonce);
+        let off = enc_consume!(buf, off; encode_u8, self.content_type);
+        let off = enc_consume!(buf, off; encode_u16, self.version);
+        let off = enc_consume!(buf, off; encode_u16, plain_len as u16);
+        stream_done!(off);
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct HandshakeHeader {
+    pub msg_type: u8,
+    pub len: u32,
+    pub msg_seq: u16,
+    pub frag_off: u32,
+    pub frag_len: u32,
+}
+
+impl HandshakeHeader {
+    /// Header of an unfragmented message with a `len` byte body
+    pub fn new(msg_type: u8, msg_seq: u16, len: usize) -> HandshakeHeader {
+        HandshakeHeader {
+            msg_type,
+            len: len as u32,
+            msg_seq,
+            frag_off: 0,
+            frag_len: len as u32,
+        }
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.frag_off != 0 || self.frag_len != self.len
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let mut off = enc_consume!(buf; encode_u8, self.msg_type);
+        off = enc_consume!(buf, off; encode_u24, self.len);
+        off = enc_consume!(buf, off; encode_u16, self.msg_seq);
+        off = enc_consume!(buf, off; encode_u24, self.frag_off);
+        off = enc_consume!(buf, off; encode_u24, self.frag_len);
+        stream_done!(off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<HandshakeHeader> {
+        let (off, msg_type) = dec_try!(buf; decode_u8);
+        let (off, len) = dec_try!(buf, off; decode_u24);
+        let (off, msg_seq) = dec_try!(buf, off; decode_u16);
+        let (off, frag_off) = dec_try!(buf, off; decode_u24);
+        let (off, frag_len) = dec_try!(buf, off; decode_u24);
+        stream_done!(
+            off,
+            HandshakeHeader {
+                msg_type,
+                len,
+                msg_seq,
+                frag_off,
+                frag_len,
+            }
+        );
+    }
+}
+
+/// Encodes a ClientHello offering only TLS_PSK_WITH_AES_128_CCM_8, without
+/// session ID or extensions.
+pub fn encode_client_hello(
+    buf: &mut [u8],
+    msg_seq: u16,
+    random: &[u8; RANDOM_LEN],
+    cookie: &[u8],
+) -> SResult {
+    let body_len = 2 + RANDOM_LEN + 1 + 1 + cookie.len() + 2 + 2 + 2;
+    let hdr = HandshakeHeader::new(handshake_type::CLIENT_HELLO, msg_seq, body_len);
+    let mut off = enc_consume!(buf; hdr; encode);
+    off = enc_consume!(buf, off; encode_u16, VERSION);
+    off = enc_consume!(buf, off; encode_bytes, random);
+    // Empty session ID
+    off = enc_consume!(buf, off; encode_u8, 0);
+    off = enc_consume!(buf, off; encode_u8, cookie.len() as u8);
+    off = enc_consume!(buf, off; encode_bytes, cookie);
+    off = enc_consume!(buf, off; encode_u16, 2);
+    off = enc_consume!(buf, off; encode_u16, TLS_PSK_WITH_AES_128_CCM_8);
+    // Only the null compression method
+    off = enc_consume!(buf, off; encode_u8, 1);
+    off = enc_consume!(buf, off; encode_u8, 0);
+    stream_done!(off);
+}
+
+/// Returns the cookie of a HelloVerifyRequest body.
+pub fn decode_hello_verify_request(body: &[u8]) -> SResult<&[u8]> {
+    // The server version may be DTLS 1.0 here (RFC 6347, Section 4.2.1)
+    let (off, _version) = dec_try!(body; decode_u16);
+    let (off, cookie_len) = dec_try!(body, off; decode_u8);
+    let end = off + cookie_len as usize;
+    stream_len_cond!(body, end);
+    stream_cond!(cookie_len as usize <= MAX_COOKIE_LEN);
+    stream_done!(end, &body[off..end]);
+}
+
+/// The fields of a ServerHello the client acts on. Extensions are ignored,
+/// as we do not offer any.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ServerHello {
+    pub version: u16,
+    pub random: [u8; RANDOM_LEN],
+    pub cipher_suite: u16,
+    pub compression: u8,
+}
+
+impl ServerHello {
+    pub fn decode(body: &[u8]) -> SResult<ServerHello> {
+        let (off, version) = dec_try!(body; decode_u16);
+        stream_len_cond!(body, off + RANDOM_LEN);
+        let mut random = [0; RANDOM_LEN];
+        random.copy_from_slice(&body[off..off + RANDOM_LEN]);
+        let (off, session_id_len) = dec_try!(body, off + RANDOM_LEN; decode_u8);
+        let off = off + session_id_len as usize;
+        let (off, cipher_suite) = dec_try!(body, off; decode_u16);
+        let (off, compression) = dec_try!(body, off; decode_u8);
+        stream_done!(
+            off,
+            ServerHello {
+                version,
+                random,
+                cipher_suite,
+                compression,
+            }
+        );
+    }
+}
+
+/// Encodes a ClientKeyExchange carrying the PSK identity (RFC 4279).
+pub fn encode_client_key_exchange(buf: &mut [u8], msg_seq: u16, identity: &[u8]) -> SResult {
+    let hdr = HandshakeHeader::new(
+        handshake_type::CLIENT_KEY_EXCHANGE,
+        msg_seq,
+        2 + identity.len(),
+    );
+    let mut off = enc_consume!(buf; hdr; encode);
+    off = enc_consume!(buf, off; encode_u16, identity.len() as u16);
+    off = enc_consume!(buf, off; encode_bytes, identity);
+    stream_done!(off);
+}
+
+pub fn encode_finished(
+    buf: &mut [u8],
+    msg_seq: u16,
+    verify_data: &[u8; VERIFY_DATA_LEN],
+) -> SResult {
+    let hdr = HandshakeHeader::new(handshake_type::FINISHED, msg_seq, VERIFY_DATA_LEN);
+    let mut off = enc_consume!(buf; hdr; encode);
+    off = enc_consume!(buf, off; encode_bytes, verify_data);
+    stream_done!(off);
+}
+
+/// Anti-replay window over the last 32 sequence numbers of an epoch
+/// (RFC 6347, Section 4.1.2.6).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ReplayWindow {
+    /// Highest sequence number accepted so far
+    latest: u64,
+    /// Bit `i` is set if `latest - i` was accepted
+    bitmap: u32,
+}
+
+impl ReplayWindow {
+    /// Whether a record with sequence number `seq` is new and recent enough
+    /// to be accepted.
+    pub fn check(&self, seq: u64) -> bool {
+        if self.bitmap == 0 || seq > self.latest {
+            return true;
+        }
+        let age = self.latest - seq;
+        age < 32 && self.bitmap & (1 << age) == 0
+    }
+
+    /// Marks `seq` as received. Only call this for authenticated records.
+    pub fn update(&mut self, seq: u64) {
+        if self.bitmap == 0 {
+            self.latest = seq;
+            self.bitmap = 1;
+        } else if seq > self.latest {
+            let shift = seq - self.latest;
+            self.bitmap = if shift < 32 { self.bitmap << shift } else { 0 } | 1;
+            self.latest = seq;
+        } else if self.latest - seq < 32 {
+            self.bitmap |= 1 << (self.latest - seq);
+        }
+    }
+}
@@ -0,0 +1,1108 @@
+//! DTLS 1.2 client sessions with pre-shared keys (RFC 6347, RFC 4279) using
+//! TLS_PSK_WITH_AES_128_CCM_8 (RFC 6655), the cipher suite CoAP mandates for
+//! its PSK mode (RFC 7252, Section 9.1.3.1).
+//!
+//! A `DtlsSession` wraps a bound UDP socket and talks to one server at a
+//! time. It performs the handshake below, after which datagrams passed to
+//! `send()` are protected and protected datagrams from the server are handed
+//! to the client decrypted.
+//!
+//! ```text
+//! Client                                           Server
+//! ClientHello                  -------->
+//!                              <--------   HelloVerifyRequest (cookie)
+//! ClientHello (with cookie)    -------->
+//!                                                   ServerHello
+//!                                           ServerKeyExchange*
+//!                              <--------        ServerHelloDone
+//! ClientKeyExchange (identity)
+//! ChangeCipherSpec
+//! Finished                     -------->
+//!                                              ChangeCipherSpec
+//!                              <--------               Finished
+//! Application Data             <------->       Application Data
+//! ```
+//!
+//! Keys are derived with the PRF in `prf.rs`, and records are protected with
+//! an `AES128CCM` implementation that accepts the 12 byte TLS nonce. Our
+//! flights are retransmitted with exponential backoff until the server's
+//! next flight arrives (RFC 6347, Section 4.2.4). A retransmitted flight is
+//! sent unchanged, so the server treats it like a duplicated datagram.
+//!
+//! Handshake messages split into fragments are dropped, and only the first
+//! protected record of a datagram is processed. Renegotiation and session
+//! resumption are not supported.
+
+use crate::net::dtls::prf::{Prf, PrfClient, MAX_SECRET_LEN, SHA256_LEN};
+use crate::net::dtls::record::{
+    alert, content_type, decode_hello_verify_request, encode_client_hello,
+    encode_client_key_exchange, encode_finished, handshake_type, HandshakeHeader, RecordHeader,
+    ReplayWindow, ServerHello, AAD_LEN, EXPLICIT_NONCE_LEN, HANDSHAKE_HEADER_LEN, MAX_COOKIE_LEN,
+    RANDOM_LEN, RECORD_HEADER_LEN, RECORD_OVERHEAD, TAG_LEN, TLS_PSK_WITH_AES_128_CCM_8,
+    VERIFY_DATA_LEN, VERSION,
+};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest::{Digest, Sha256};
+use kernel::hil::rng::Random;
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, AES128_KEY_SIZE};
+use kernel::hil::time::{self, Alarm};
+use kernel::ErrorCode;
+
+/// Size of the transmit, receive and flight buffers
+pub const DTLS_BUF_SIZE: usize = 256;
+/// Longest datagram `send()` accepts
+pub const MAX_PAYLOAD_LEN: usize = DTLS_BUF_SIZE - RECORD_OVERHEAD;
+pub const MAX_IDENTITY_LEN: usize = 32;
+/// The premaster secret, two length fields and two copies of the length of
+/// the key, must fit in one HMAC block.
+pub const MAX_PSK_LEN: usize = (MAX_SECRET_LEN - 4) / 2;
+
+const MASTER_SECRET_LEN: usize = 48;
+const IV_LEN: usize = 4;
+/// client_write_key | server_write_key | client_write_IV | server_write_IV
+const KEY_BLOCK_LEN: usize = 2 * AES128_KEY_SIZE + 2 * IV_LEN;
+const NONCE_LEN: usize = IV_LEN + EXPLICIT_NONCE_LEN;
+/// All handshake messages from the last ClientHello on
+const TRANSCRIPT_LEN: usize = crate::net::dtls::prf::HASH_BUF_LEN;
+/// Offset of the plaintext in a protected record
+const PLAINTEXT_OFF: usize = RECORD_HEADER_LEN + EXPLICIT_NONCE_LEN;
+
+const RETRANSMIT_MS: u32 = 1000;
+const MAX_RETRANSMIT: u8 = 4;
+
+pub trait DtlsClient {
+    /// The handshake started by `connect()` completed. Fails with NOACK if
+    /// the server did not answer, and with FAIL if it rejected the handshake
+    /// or its Finished message did not verify.
+    fn connected(&self, result: Result<(), ErrorCode>);
+
+    /// Application data arrived from the server.
+    fn received(&self, payload: &[u8]);
+
+    /// A `send()` completed.
+    fn send_done(&self, result: Result<(), ErrorCode>);
+
+    /// An established session ended: Ok(()) after a close_notify from
+    /// either side, FAIL after a fatal alert from the server.
+    fn closed(&self, result: Result<(), ErrorCode>);
+}
+
+/// The encrypted datagram interface a DTLS session offers its users.
+pub trait DtlsTransport<'a> {
+    fn set_client(&self, client: &'a dyn DtlsClient);
+
+    /// Sets the identity and key used by the next `connect()`. Returns BUSY
+    /// while a session is open and SIZE if the identity is longer than
+    /// MAX_IDENTITY_LEN or the key longer than MAX_PSK_LEN.
+    fn set_psk(&self, identity: &[u8], key: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Starts a handshake with the server at `addr`:`port`. Returns BUSY if
+    /// a session is open already and RESERVE if no PSK is set.
+    fn connect(&self, addr: IPAddr, port: u16) -> Result<(), ErrorCode>;
+
+    /// Protects and sends `payload`. Returns OFF if the session is not
+    /// established, SIZE if `payload` is longer than MAX_PAYLOAD_LEN, and
+    /// BUSY if a previous datagram is still being sent.
+    fn send(&self, payload: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Ends the session. An established session sends a close_notify alert
+    /// and reports `closed()` once it is sent; a handshake in progress is
+    /// aborted without a callback.
+    fn close(&self) -> Result<(), ErrorCode>;
+
+    fn is_connected(&self) -> bool;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Closed,
+    /// ClientHello sent, waiting for a HelloVerifyRequest or ServerHello
+    Hello,
+    /// Waiting for the rest of the server's flight up to ServerHelloDone
+    ServerHello,
+    MasterSecret,
+    KeyExpansion,
+    /// Hashing the transcript, then computing our Finished message
+    ClientTranscript,
+    ClientVerify,
+    /// Our last flight is sent, waiting for the server's Finished
+    ServerFinished,
+    ServerTranscript,
+    ServerVerify,
+    Connected,
+}
+
+impl State {
+    fn is_handshake(&self) -> bool {
+        *self != State::Closed && *self != State::Connected
+    }
+}
+
+/// What the CCM engine is working on
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Crypt {
+    Idle,
+    Finished,
+    Data,
+    CloseNotify,
+    Decrypt,
+}
+
+/// What the UDP sender is transmitting
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Tx {
+    Idle,
+    Flight,
+    Data,
+    CloseNotify,
+}
+
+struct Psk {
+    identity: [u8; MAX_IDENTITY_LEN],
+    identity_len: usize,
+    key: [u8; MAX_PSK_LEN],
+    key_len: usize,
+}
+
+impl Psk {
+    /// Writes the premaster secret (RFC 4279, Section 2) into `buf` and
+    /// returns its length.
+    fn premaster_secret(&self, buf: &mut [u8; MAX_SECRET_LEN]) -> usize {
+        let n = self.key_len;
+        let len = (n as u16).to_be_bytes();
+        *buf = [0; MAX_SECRET_LEN];
+        buf[..2].copy_from_slice(&len);
+        buf[2 + n..4 + n].copy_from_slice(&len);
+        buf[4 + n..4 + 2 * n].copy_from_slice(&self.key[..n]);
+        4 + 2 * n
+    }
+}
+
+/// Secrets and state of the current handshake
+struct Handshake {
+    client_random: [u8; RANDOM_LEN],
+    server_random: [u8; RANDOM_LEN],
+    cookie: [u8; MAX_COOKIE_LEN],
+    cookie_len: usize,
+    master_secret: [u8; MASTER_SECRET_LEN],
+    key_block: [u8; KEY_BLOCK_LEN],
+    server_verify: [u8; VERIFY_DATA_LEN],
+    transcript: [u8; TRANSCRIPT_LEN],
+    transcript_len: usize,
+}
+
+impl Handshake {
+    const fn new() -> Handshake {
+        Handshake {
+            client_random: [0; RANDOM_LEN],
+            server_random: [0; RANDOM_LEN],
+            cookie: [0; MAX_COOKIE_LEN],
+            cookie_len: 0,
+            master_secret: [0; MASTER_SECRET_LEN],
+            key_block: [0; KEY_BLOCK_LEN],
+            server_verify: [0; VERIFY_DATA_LEN],
+            transcript: [0; TRANSCRIPT_LEN],
+            transcript_len: 0,
+        }
+    }
+
+    fn append(&mut self, msg: &[u8]) -> Result<(), ErrorCode> {
+        let end = self.transcript_len + msg.len();
+        if end > TRANSCRIPT_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        self.transcript[self.transcript_len..end].copy_from_slice(msg);
+        self.transcript_len = end;
+        Ok(())
+    }
+
+    fn transcript(&self) -> &[u8] {
+        &self.transcript[..self.transcript_len]
+    }
+
+    /// The key and nonce protecting records sent by us (`client`) or by the
+    /// server with explicit nonce `explicit`.
+    fn key_nonce(&self, client: bool, explicit: &[u8]) -> (&[u8], [u8; NONCE_LEN]) {
+        let (key_off, iv_off) = if client {
+            (0, 2 * AES128_KEY_SIZE)
+        } else {
+            (AES128_KEY_SIZE, 2 * AES128_KEY_SIZE + IV_LEN)
+        };
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..IV_LEN].copy_from_slice(&self.key_block[iv_off..iv_off + IV_LEN]);
+        nonce[IV_LEN..].copy_from_slice(&explicit[..EXPLICIT_NONCE_LEN]);
+        (&self.key_block[key_off..key_off + AES128_KEY_SIZE], nonce)
+    }
+}
+
+pub struct DtlsSession<'a, A: Alarm<'a>, C: AES128CCM<'a>, D: Digest<'a, [u8; SHA256_LEN]> + Sha256>
+{
+    sender: &'a dyn UDPSender<'a>,
+    alarm: &'a A,
+    ccm: &'a C,
+    prf: &'a Prf<'a, D>,
+    rng: &'a dyn Random<'a>,
+    net_cap: &'static NetworkCapability,
+    client: OptionalCell<&'a dyn DtlsClient>,
+
+    state: Cell<State>,
+    peer: OptionalCell<(IPAddr, u16)>,
+    psk: MapCell<Psk>,
+    handshake: MapCell<Handshake>,
+    tx_epoch: Cell<u16>,
+    tx_seq: Cell<u64>,
+    /// message_seq of our next handshake message
+    msg_seq: Cell<u16>,
+    /// message_seq of the next handshake message expected from the server
+    rx_msg_seq: Cell<u16>,
+    ccs_received: Cell<bool>,
+    rx_window: Cell<ReplayWindow>,
+    retransmits: Cell<u8>,
+
+    /// Record being encrypted or decrypted, and its offset in the buffer
+    crypt: Cell<Crypt>,
+    crypt_record: Cell<(usize, RecordHeader)>,
+    tx: Cell<Tx>,
+    tx_buf: TakeCell<'static, [u8]>,
+    /// Our last handshake flight, kept for retransmissions
+    flight_buf: TakeCell<'static, [u8]>,
+    flight_len: Cell<usize>,
+    /// The flight is waiting for tx_buf to come back
+    flight_pending: Cell<bool>,
+    rx_buf: TakeCell<'static, [u8]>,
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>, D: Digest<'a, [u8; SHA256_LEN]> + Sha256>
+    DtlsSession<'a, A, C, D>
+{
+    pub fn new(
+        sender: &'a dyn UDPSender<'a>,
+        alarm: &'a A,
+        ccm: &'a C,
+        prf: &'a Prf<'a, D>,
+        rng: &'a dyn Random<'a>,
+        tx_buf: &'static mut [u8],
+        flight_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> DtlsSession<'a, A, C, D> {
+        DtlsSession {
+            sender,
+            alarm,
+            ccm,
+            prf,
+            rng,
+            net_cap,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Closed),
+            peer: OptionalCell::empty(),
+            psk: MapCell::new(Psk {
+                identity: [0; MAX_IDENTITY_LEN],
+                identity_len: 0,
+                key: [0; MAX_PSK_LEN],
+                key_len: 0,
+            }),
+            handshake: MapCell::new(Handshake::new()),
+            tx_epoch: Cell::new(0),
+            tx_seq: Cell::new(0),
+            msg_seq: Cell::new(0),
+            rx_msg_seq: Cell::new(0),
+            ccs_received: Cell::new(false),
+            rx_window: Cell::new(ReplayWindow::default()),
+            retransmits: Cell::new(0),
+            crypt: Cell::new(Crypt::Idle),
+            crypt_record: Cell::new((0, RecordHeader::new(0, 0, 0, 0))),
+            tx: Cell::new(Tx::Idle),
+            tx_buf: TakeCell::new(tx_buf),
+            flight_buf: TakeCell::new(flight_buf),
+            flight_len: Cell::new(0),
+            flight_pending: Cell::new(false),
+            rx_buf: TakeCell::new(rx_buf),
+        }
+    }
+
+    fn next_seq(&self) -> u64 {
+        let seq = self.tx_seq.get();
+        self.tx_seq.set(seq + 1);
+        seq
+    }
+
+    fn next_msg_seq(&self) -> u16 {
+        let seq = self.msg_seq.get();
+        self.msg_seq.set(seq + 1);
+        seq
+    }
+
+    /// Ends the session after an error, reporting it to the client.
+    fn fail(&self, err: ErrorCode) {
+        let state = self.state.get();
+        if state == State::Closed {
+            return;
+        }
+        let _ = self.alarm.disarm();
+        self.state.set(State::Closed);
+        self.handshake.put(Handshake::new());
+        self.client.map(|client| {
+            if state.is_handshake() {
+                client.connected(Err(err));
+            } else {
+                client.closed(Err(err));
+            }
+        });
+    }
+
+    fn check(&self, res: Result<(), ErrorCode>) {
+        if let Err(e) = res {
+            self.fail(e);
+        }
+    }
+
+    fn arm_retransmit(&self) {
+        let timeout_ms = RETRANSMIT_MS << self.retransmits.get();
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(timeout_ms));
+    }
+
+    /// Sends the flight in flight_buf and retransmits it until the server
+    /// answers.
+    fn start_flight(&self, len: usize) {
+        self.flight_len.set(len);
+        self.retransmits.set(0);
+        self.send_flight();
+        self.arm_retransmit();
+    }
+
+    fn send_flight(&self) {
+        let (addr, port) = match self.peer.extract() {
+            Some(peer) => peer,
+            None => return,
+        };
+        let mut buf = match self.tx_buf.take() {
+            Some(buf) => LeasableBuffer::new(buf),
+            None => {
+                self.flight_pending.set(true);
+                return;
+            }
+        };
+        let len = self.flight_len.get();
+        self.flight_buf
+            .map(|flight| buf[..len].copy_from_slice(&flight[..len]));
+        buf.slice(0..len);
+        match self.sender.send_to(addr, port, buf, self.net_cap) {
+            Ok(()) => self.tx.set(Tx::Flight),
+            Err(mut buf) => {
+                // Try again on the next retransmission
+                buf.reset();
+                self.tx_buf.replace(buf.take());
+            }
+        }
+    }
+
+    /// Writes a record with our (latest) ClientHello into flight_buf, which
+    /// also starts the transcript over. Returns the length of the flight.
+    fn client_hello(&self) -> Result<usize, ErrorCode> {
+        let msg_seq = self.next_msg_seq();
+        let seq = self.next_seq();
+        let buf = self.flight_buf.take().ok_or(ErrorCode::BUSY)?;
+        let res = self.handshake.map_or(Err(ErrorCode::FAIL), |hs| {
+            let cookie = &hs.cookie[..hs.cookie_len];
+            let len = match encode_client_hello(
+                &mut buf[RECORD_HEADER_LEN..],
+                msg_seq,
+                &hs.client_random,
+                cookie,
+            )
+            .done()
+            {
+                Some((len, ())) => len,
+                None => return Err(ErrorCode::SIZE),
+            };
+            RecordHeader::new(content_type::HANDSHAKE, 0, seq, len).encode(buf);
+            // The transcript starts with the ClientHello the server accepts
+            hs.transcript_len = 0;
+            hs.append(&buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len])?;
+            Ok(RECORD_HEADER_LEN + len)
+        });
+        self.flight_buf.replace(buf);
+        res
+    }
+
+    /// Lays out the protected record starting at `off` in `buf`, whose
+    /// `plain_len` bytes of plaintext are at `off + PLAINTEXT_OFF`, and
+    /// starts encrypting it.
+    fn encrypt_record(
+        &self,
+        buf: &'static mut [u8],
+        off: usize,
+        ctype: u8,
+        plain_len: usize,
+        crypt: Crypt,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let hdr = RecordHeader::new(
+            ctype,
+            self.tx_epoch.get(),
+            self.next_seq(),
+            EXPLICIT_NONCE_LEN + plain_len + TAG_LEN,
+        );
+        let m_off = off + PLAINTEXT_OFF;
+        // The additional data goes right before the plaintext, where the
+        // header and explicit nonce are written once the record is
+        // encrypted.
+        hdr.encode_aad(&mut buf[m_off - AAD_LEN..m_off], plain_len);
+        let mut explicit = [0; EXPLICIT_NONCE_LEN];
+        hdr.encode_explicit_nonce(&mut explicit);
+        let res = self.handshake.map_or(Err(ErrorCode::FAIL), |hs| {
+            let (key, nonce) = hs.key_nonce(true, &explicit);
+            self.ccm.set_key(key)?;
+            self.ccm.set_nonce(&nonce)
+        });
+        if let Err(e) = res {
+            return Err((e, buf));
+        }
+        self.ccm
+            .crypt(buf, m_off - AAD_LEN, m_off, plain_len, TAG_LEN, true, true)?;
+        self.crypt.set(crypt);
+        self.crypt_record.set((off, hdr));
+        Ok(())
+    }
+
+    /// Decrypts the protected record `record`.
+    fn decrypt_record(&self, hdr: RecordHeader, record: &[u8]) {
+        if record.len() < RECORD_OVERHEAD
+            || self.crypt.get() != Crypt::Idle
+            || !self.rx_window.get().check(hdr.seq)
+        {
+            return;
+        }
+        let buf = match self.rx_buf.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+        if record.len() > buf.len() {
+            self.rx_buf.replace(buf);
+            return;
+        }
+        buf[..record.len()].copy_from_slice(record);
+        let m_len = record.len() - RECORD_OVERHEAD;
+        let explicit = &record[RECORD_HEADER_LEN..PLAINTEXT_OFF];
+        hdr.encode_aad(&mut buf[PLAINTEXT_OFF - AAD_LEN..PLAINTEXT_OFF], m_len);
+        let res = self.handshake.map_or(Err(ErrorCode::FAIL), |hs| {
+            let (key, nonce) = hs.key_nonce(false, explicit);
+            self.ccm.set_key(key)?;
+            self.ccm.set_nonce(&nonce)
+        });
+        let res = match res {
+            Ok(()) => self.ccm.crypt(
+                buf,
+                PLAINTEXT_OFF - AAD_LEN,
+                PLAINTEXT_OFF,
+                m_len,
+                TAG_LEN,
+                true,
+                false,
+            ),
+            Err(e) => Err((e, buf)),
+        };
+        match res {
+            Ok(()) => {
+                self.crypt.set(Crypt::Decrypt);
+                self.crypt_record.set((0, hdr));
+            }
+            Err((_, buf)) => {
+                self.rx_buf.replace(buf);
+            }
+        }
+    }
+
+    fn receive_plaintext(&self, hdr: RecordHeader, body: &[u8]) {
+        match hdr.content_type {
+            content_type::HANDSHAKE => {
+                let mut off = 0;
+                while off < body.len() && self.state.get() != State::Closed {
+                    let hh = match HandshakeHeader::decode(&body[off..]).done() {
+                        Some((_, hh)) => hh,
+                        None => return,
+                    };
+                    let end = off + HANDSHAKE_HEADER_LEN + hh.frag_len as usize;
+                    if end > body.len() {
+                        return;
+                    }
+                    self.receive_handshake(hh, &body[off..end]);
+                    off = end;
+                }
+            }
+            content_type::CHANGE_CIPHER_SPEC => {
+                if self.state.get() == State::ServerFinished && body == [1] {
+                    self.ccs_received.set(true);
+                }
+            }
+            content_type::ALERT => {
+                // Unprotected alerts are only believed during the handshake
+                if self.state.get().is_handshake()
+                    && body.len() >= 2
+                    && body[0] == alert::LEVEL_FATAL
+                {
+                    self.fail(ErrorCode::FAIL);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the unprotected handshake message `msg`, including its header.
+    fn receive_handshake(&self, hh: HandshakeHeader, msg: &[u8]) {
+        if hh.is_fragment() {
+            return;
+        }
+        let body = &msg[HANDSHAKE_HEADER_LEN..];
+        match (self.state.get(), hh.msg_type) {
+            (State::Hello, handshake_type::HELLO_VERIFY_REQUEST) => {
+                let cookie = match decode_hello_verify_request(body).done() {
+                    Some((_, cookie)) => cookie,
+                    None => return self.fail(ErrorCode::FAIL),
+                };
+                let first = self.handshake.map_or(false, |hs| {
+                    if hs.cookie_len != 0 {
+                        // Answer to a retransmission of our first ClientHello
+                        return false;
+                    }
+                    hs.cookie[..cookie.len()].copy_from_slice(cookie);
+                    hs.cookie_len = cookie.len();
+                    true
+                });
+                if first {
+                    match self.client_hello() {
+                        Ok(len) => self.start_flight(len),
+                        Err(e) => self.fail(e),
+                    }
+                }
+            }
+            (State::Hello, handshake_type::SERVER_HELLO) => {
+                let hello = match ServerHello::decode(body).done() {
+                    Some((_, hello)) => hello,
+                    None => return self.fail(ErrorCode::FAIL),
+                };
+                if hello.version != VERSION
+                    || hello.cipher_suite != TLS_PSK_WITH_AES_128_CCM_8
+                    || hello.compression != 0
+                {
+                    return self.fail(ErrorCode::FAIL);
+                }
+                let res = self.handshake.map_or(Err(ErrorCode::FAIL), |hs| {
+                    hs.server_random = hello.random;
+                    hs.append(msg)
+                });
+                self.rx_msg_seq.set(hh.msg_seq.wrapping_add(1));
+                self.state.set(State::ServerHello);
+                self.check(res);
+            }
+            (State::ServerHello, msg_type) if hh.msg_seq == self.rx_msg_seq.get() => {
+                self.rx_msg_seq.set(hh.msg_seq.wrapping_add(1));
+                let res = match msg_type {
+                    // The identity hint is not used
+                    handshake_type::SERVER_KEY_EXCHANGE | handshake_type::SERVER_HELLO_DONE => self
+                        .handshake
+                        .map_or(Err(ErrorCode::FAIL), |hs| hs.append(msg)),
+                    _ => Err(ErrorCode::FAIL),
+                };
+                if res.is_ok() && msg_type == handshake_type::SERVER_HELLO_DONE {
+                    let _ = self.alarm.disarm();
+                    self.state.set(State::MasterSecret);
+                    self.check(self.derive_master_secret());
+                } else {
+                    self.check(res);
+                }
+            }
+            // Retransmissions and messages out of order
+            _ => {}
+        }
+    }
+
+    fn derive_master_secret(&self) -> Result<(), ErrorCode> {
+        let mut premaster = [0; MAX_SECRET_LEN];
+        let len = self
+            .psk
+            .map_or(0, |psk| psk.premaster_secret(&mut premaster));
+        let res = self.handshake.map_or(Err(ErrorCode::FAIL), |hs| {
+            self.prf.compute(
+                &premaster[..len],
+                b"master secret",
+                &hs.client_random,
+                &hs.server_random,
+                MASTER_SECRET_LEN,
+            )
+        });
+        res
+    }
+
+    /// Writes the ClientKeyExchange and ChangeCipherSpec records of our
+    /// last flight into flight_buf and adds the ClientKeyExchange to the
+    /// transcript.
+    fn client_key_exchange(&self) -> Result<(), ErrorCode> {
+        let msg_seq = self.next_msg_seq();
+        let cke_seq = self.next_seq();
+        let ccs_seq = self.next_seq();
+        let buf = self.flight_buf.take().ok_or(ErrorCode::BUSY)?;
+        let res = self.psk.map_or(Err(ErrorCode::FAIL), |psk| {
+            let identity = &psk.identity[..psk.identity_len];
+            let len =
+                match encode_client_key_exchange(&mut buf[RECORD_HEADER_LEN..], msg_seq, identity)
+                    .done()
+                {
+                    Some((len, ())) => len,
+                    None => return Err(ErrorCode::SIZE),
+                };
+            RecordHeader::new(content_type::HANDSHAKE, 0, cke_seq, len).encode(buf);
+            let msg = &buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len];
+            self.handshake
+                .map_or(Err(ErrorCode::FAIL), |hs| hs.append(msg))?;
+            let off = RECORD_HEADER_LEN + len;
+            RecordHeader::new(content_type::CHANGE_CIPHER_SPEC, 0, ccs_seq, 1)
+                .encode(&mut buf[off..]);
+            buf[off + RECORD_HEADER_LEN] = 1;
+            Ok(off + RECORD_HEADER_LEN + 1)
+        });
+        self.flight_buf.replace(buf);
+        let len = res?;
+        self.flight_len.set(len);
+        // Everything after the ChangeCipherSpec is protected
+        self.tx_epoch.set(1);
+        self.tx_seq.set(0);
+        Ok(())
+    }
+
+    /// Appends our Finished message to the flight and starts encrypting it.
+    fn client_finished(&self, verify_data: &[u8]) -> Result<(), ErrorCode> {
+        let mut verify = [0; VERIFY_DATA_LEN];
+        verify.copy_from_slice(&verify_data[..VERIFY_DATA_LEN]);
+        let msg_seq = self.next_msg_seq();
+        let buf = self.flight_buf.take().ok_or(ErrorCode::BUSY)?;
+        let off = self.flight_len.get();
+        let len = match encode_finished(&mut buf[off + PLAINTEXT_OFF..], msg_seq, &verify).done() {
+            Some((len, ())) => len,
+            None => {
+                self.flight_buf.replace(buf);
+                return Err(ErrorCode::SIZE);
+            }
+        };
+        let msg = &buf[off + PLAINTEXT_OFF..off + PLAINTEXT_OFF + len];
+        if let Err(e) = self
+            .handshake
+            .map_or(Err(ErrorCode::FAIL), |hs| hs.append(msg))
+        {
+            self.flight_buf.replace(buf);
+            return Err(e);
+        }
+        self.encrypt_record(buf, off, content_type::HANDSHAKE, len, Crypt::Finished)
+            .map_err(|(e, buf)| {
+                self.flight_buf.replace(buf);
+                e
+            })
+    }
+
+    fn server_finished(&self, plaintext: &[u8]) {
+        let hh = match HandshakeHeader::decode(plaintext).done() {
+            Some((_, hh)) => hh,
+            None => return,
+        };
+        if hh.msg_type != handshake_type::FINISHED
+            || hh.msg_seq != self.rx_msg_seq.get()
+            || hh.is_fragment()
+            || hh.len as usize != VERIFY_DATA_LEN
+            || plaintext.len() < HANDSHAKE_HEADER_LEN + VERIFY_DATA_LEN
+        {
+            return;
+        }
+        let _ = self.alarm.disarm();
+        self.state.set(State::ServerTranscript);
+        let res = self.handshake.map_or(Err(ErrorCode::FAIL), |hs| {
+            hs.server_verify
+                .copy_from_slice(&plaintext[HANDSHAKE_HEADER_LEN..][..VERIFY_DATA_LEN]);
+            self.prf.sha256(hs.transcript())
+        });
+        self.check(res);
+    }
+
+    fn receive_protected(&self, ctype: u8, plaintext: &[u8]) {
+        match (self.state.get(), ctype) {
+            (State::ServerFinished, content_type::HANDSHAKE) => self.server_finished(plaintext),
+            (State::Connected, content_type::APPLICATION_DATA) => {
+                self.client.map(|client| client.received(plaintext));
+            }
+            (_, content_type::ALERT) if plaintext.len() >= 2 => {
+                if plaintext[1] == alert::CLOSE_NOTIFY {
+                    if self.state.get() == State::Connected {
+                        self.state.set(State::Closed);
+                        self.handshake.put(Handshake::new());
+                        self.client.map(|client| client.closed(Ok(())));
+                    } else {
+                        self.fail(ErrorCode::FAIL);
+                    }
+                } else if plaintext[0] == alert::LEVEL_FATAL {
+                    self.fail(ErrorCode::FAIL);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes the header and explicit nonce of the record that was just
+    /// encrypted over its additional data.
+    fn finish_record(&self, buf: &mut [u8]) -> usize {
+        let (off, hdr) = self.crypt_record.get();
+        hdr.encode(&mut buf[off..]);
+        hdr.encode_explicit_nonce(&mut buf[off + RECORD_HEADER_LEN..]);
+        off + RECORD_HEADER_LEN + hdr.len as usize
+    }
+
+    /// Sends the protected record at the start of `buf`.
+    fn send_record(&self, buf: &'static mut [u8], tx: Tx) -> Result<(), ErrorCode> {
+        let len = self.finish_record(buf);
+        let (addr, port) = match self.peer.extract() {
+            Some(peer) => peer,
+            None => {
+                self.tx_buf.replace(buf);
+                return Err(ErrorCode::FAIL);
+            }
+        };
+        let mut lbuf = LeasableBuffer::new(buf);
+        lbuf.slice(0..len);
+        match self.sender.send_to(addr, port, lbuf, self.net_cap) {
+            Ok(()) => {
+                self.tx.set(tx);
+                Ok(())
+            }
+            Err(mut buf) => {
+                buf.reset();
+                self.tx_buf.replace(buf.take());
+                Err(ErrorCode::FAIL)
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>, D: Digest<'a, [u8; SHA256_LEN]> + Sha256> DtlsTransport<'a>
+    for DtlsSession<'a, A, C, D>
+{
+    fn set_client(&self, client: &'a dyn DtlsClient) {
+        self.client.set(client);
+    }
+
+    fn set_psk(&self, identity: &[u8], key: &[u8]) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Closed {
+            return Err(ErrorCode::BUSY);
+        }
+        if identity.len() > MAX_IDENTITY_LEN || key.len() > MAX_PSK_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        self.psk.map(|psk| {
+            psk.identity[..identity.len()].copy_from_slice(identity);
+            psk.identity_len = identity.len();
+            psk.key[..key.len()].copy_from_slice(key);
+            psk.key_len = key.len();
+        });
+        Ok(())
+    }
+
+    fn connect(&self, addr: IPAddr, port: u16) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Closed {
+            return Err(ErrorCode::BUSY);
+        }
+        if self.psk.map_or(0, |psk| psk.key_len) == 0 {
+            return Err(ErrorCode::RESERVE);
+        }
+        self.peer.set((addr, port));
+        self.tx_epoch.set(0);
+        self.tx_seq.set(0);
+        self.msg_seq.set(0);
+        self.ccs_received.set(false);
+        self.rx_window.set(ReplayWindow::default());
+        self.flight_pending.set(false);
+        self.handshake.put(Handshake::new());
+        self.handshake.map(|hs| {
+            for word in hs.client_random.chunks_mut(4) {
+                word.copy_from_slice(&self.rng.random().to_be_bytes());
+            }
+        });
+        let len = self.client_hello()?;
+        self.state.set(State::Hello);
+        self.start_flight(len);
+        Ok(())
+    }
+
+    fn send(&self, payload: &[u8]) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Connected {
+            return Err(ErrorCode::OFF);
+        }
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        if self.crypt.get() != Crypt::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let buf = self.tx_buf.take().ok_or(ErrorCode::BUSY)?;
+        buf[PLAINTEXT_OFF..PLAINTEXT_OFF + payload.len()].copy_from_slice(payload);
+        self.encrypt_record(
+            buf,
+            0,
+            content_type::APPLICATION_DATA,
+            payload.len(),
+            Crypt::Data,
+        )
+        .map_err(|(e, buf)| {
+            self.tx_buf.replace(buf);
+            e
+        })
+    }
+
+    fn close(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Closed => Err(ErrorCode::ALREADY),
+            State::Connected => {
+                if self.crypt.get() != Crypt::Idle {
+                    return Err(ErrorCode::BUSY);
+                }
+                let buf = self.tx_buf.take().ok_or(ErrorCode::BUSY)?;
+                buf[PLAINTEXT_OFF] = alert::LEVEL_WARNING;
+                buf[PLAINTEXT_OFF + 1] = alert::CLOSE_NOTIFY;
+                self.encrypt_record(buf, 0, content_type::ALERT, 2, Crypt::CloseNotify)
+                    .map_err(|(e, buf)| {
+                        self.tx_buf.replace(buf);
+                        e
+                    })?;
+                self.state.set(State::Closed);
+                Ok(())
+            }
+            _ => {
+                let _ = self.alarm.disarm();
+                self.state.set(State::Closed);
+                self.handshake.put(Handshake::new());
+                Ok(())
+            }
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state.get() == State::Connected
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>, D: Digest<'a, [u8; SHA256_LEN]> + Sha256> PrfClient
+    for DtlsSession<'a, A, C, D>
+{
+    fn prf_done(&self, result: Result<(), ErrorCode>, output: &[u8]) {
+        if self.state.get() == State::Closed {
+            return;
+        }
+        if let Err(e) = result {
+            return self.fail(e);
+        }
+        let res = match self.state.get() {
+            State::MasterSecret => {
+                self.state.set(State::KeyExpansion);
+                self.handshake.map_or(Err(ErrorCode::FAIL), |hs| {
+                    hs.master_secret.copy_from_slice(output);
+                    self.prf.compute(
+                        &hs.master_secret,
+                        b"key expansion",
+                        &hs.server_random,
+                        &hs.client_random,
+                        KEY_BLOCK_LEN,
+                    )
+                })
+            }
+            State::KeyExpansion => {
+                self.handshake
+                    .map(|hs| hs.key_block.copy_from_slice(output));
+                self.state.set(State::ClientTranscript);
+                self.client_key_exchange().and_then(|()| {
+                    self.handshake
+                        .map_or(Err(ErrorCode::FAIL), |hs| self.prf.sha256(hs.transcript()))
+                })
+            }
+            State::ClientVerify => self.client_finished(output),
+            State::ServerVerify => {
+                let verified = self
+                    .handshake
+                    .map_or(false, |hs| hs.server_verify[..] == output[..]);
+                if verified {
+                    self.state.set(State::Connected);
+                    self.client.map(|client| client.connected(Ok(())));
+                    Ok(())
+                } else {
+                    Err(ErrorCode::FAIL)
+                }
+            }
+            _ => Ok(()),
+        };
+        self.check(res);
+    }
+
+    fn sha256_done(&self, result: Result<(), ErrorCode>, hash: &[u8; SHA256_LEN]) {
+        if self.state.get() == State::Closed {
+            return;
+        }
+        if let Err(e) = result {
+            return self.fail(e);
+        }
+        let (next, label) = match self.state.get() {
+            State::ClientTranscript => (State::ClientVerify, b"client finished"),
+            State::ServerTranscript => (State::ServerVerify, b"server finished"),
+            _ => return,
+        };
+        self.state.set(next);
+        let res = self.handshake.map_or(Err(ErrorCode::FAIL), |hs| {
+            self.prf
+                .compute(&hs.master_secret, label, hash, &[], VERIFY_DATA_LEN)
+        });
+        self.check(res);
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>, D: Digest<'a, [u8; SHA256_LEN]> + Sha256> CCMClient
+    for DtlsSession<'a, A, C, D>
+{
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        match self.crypt.replace(Crypt::Idle) {
+            Crypt::Finished => {
+                let len = self.finish_record(buf);
+                self.flight_buf.replace(buf);
+                if self.state.get() != State::ClientVerify {
+                    return;
+                }
+                match res {
+                    Ok(()) => {
+                        self.state.set(State::ServerFinished);
+                        self.start_flight(len);
+                    }
+                    Err(e) => self.fail(e),
+                }
+            }
+            Crypt::Data => {
+                let res = match res {
+                    Ok(()) => self.send_record(buf, Tx::Data),
+                    Err(e) => {
+                        self.tx_buf.replace(buf);
+                        Err(e)
+                    }
+                };
+                if let Err(e) = res {
+                    self.client.map(|client| client.send_done(Err(e)));
+                }
+            }
+            Crypt::CloseNotify => {
+                let res = match res {
+                    Ok(()) => self.send_record(buf, Tx::CloseNotify),
+                    Err(e) => {
+                        self.tx_buf.replace(buf);
+                        Err(e)
+                    }
+                };
+                if res.is_err() {
+                    self.handshake.put(Handshake::new());
+                    self.client.map(|client| client.closed(Ok(())));
+                }
+            }
+            Crypt::Decrypt => {
+                let (_, hdr) = self.crypt_record.get();
+                if res.is_ok() && tag_is_valid {
+                    let mut window = self.rx_window.get();
+                    window.update(hdr.seq);
+                    self.rx_window.set(window);
+                    let m_len = hdr.len as usize - EXPLICIT_NONCE_LEN - TAG_LEN;
+                    self.receive_protected(
+                        hdr.content_type,
+                        &buf[PLAINTEXT_OFF..PLAINTEXT_OFF + m_len],
+                    );
+                }
+                self.rx_buf.replace(buf);
+            }
+            Crypt::Idle => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>, D: Digest<'a, [u8; SHA256_LEN]> + Sha256> UDPSendClient
+    for DtlsSession<'a, A, C, D>
+{
+    fn send_done(&self, result: Result<(), ErrorCode>, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buf.replace(dgram.take());
+        match self.tx.replace(Tx::Idle) {
+            Tx::Data => {
+                self.client.map(|client| client.send_done(result));
+            }
+            Tx::CloseNotify => {
+                self.handshake.put(Handshake::new());
+                self.client.map(|client| client.closed(Ok(())));
+            }
+            Tx::Flight | Tx::Idle => {}
+        }
+        if self.flight_pending.get() && self.state.get().is_handshake() {
+            self.flight_pending.set(false);
+            self.send_flight();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>, D: Digest<'a, [u8; SHA256_LEN]> + Sha256> UDPRecvClient
+    for DtlsSession<'a, A, C, D>
+{
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        if self.state.get() == State::Closed || self.peer.extract() != Some((src_addr, src_port)) {
+            return;
+        }
+        let mut off = 0;
+        while off < payload.len() && self.state.get() != State::Closed {
+            let hdr = match RecordHeader::decode(&payload[off..]).done() {
+                Some((_, hdr)) => hdr,
+                None => return,
+            };
+            let end = off + RECORD_HEADER_LEN + hdr.len as usize;
+            if end > payload.len() {
+                return;
+            }
+            match hdr.epoch {
+                0 => self.receive_plaintext(hdr, &payload[off + RECORD_HEADER_LEN..end]),
+                1 if self.ccs_received.get() => {
+                    // Only one record can be decrypted at a time
+                    self.decrypt_record(hdr, &payload[off..end]);
+                    return;
+                }
+                _ => {}
+            }
+            off = end;
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>, D: Digest<'a, [u8; SHA256_LEN]> + Sha256> time::AlarmClient
+    for DtlsSession<'a, A, C, D>
+{
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Hello | State::ServerHello | State::ServerFinished => {}
+            _ => return,
+        }
+        let retransmits = self.retransmits.get() + 1;
+        if retransmits > MAX_RETRANSMIT {
+            return self.fail(ErrorCode::NOACK);
+        }
+        self.retransmits.set(retransmits);
+        self.send_flight();
+        self.arm_retransmit();
+    }
+}
@@ -0,0 +1,811 @@
+//! Handshake tests against a recorded session with OpenSSL 3.5:
+//!
+//! ```text
+//! openssl s_server -dtls1_2 -listen -nocert -psk_identity tock \
+//!     -psk 0123456789abcdef0123456789abcdef \
+//!     -cipher 'PSK-AES128-CCM8@SECLEVEL=0' -keylogfile keys.txt
+//! ```
+//!
+//! The client random was fixed to 00 01 .. 1f, so our ClientHello and all
+//! records we send are deterministic and must match the recorded datagrams
+//! byte for byte. The master secret is the one OpenSSL logged. The digest
+//! and CCM engines are software models that complete when `Harness::run()`
+//! is called, like hardware completing after an interrupt.
+
+extern crate std;
+
+use super::prf::{Prf, PrfClient, SHA256_LEN};
+use super::record::{decode_hello_verify_request, RecordHeader, ReplayWindow, ServerHello};
+use super::session::{DtlsClient, DtlsSession, DtlsTransport};
+use crate::emulated_alarm::EmulatedAlarm;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::stream::SResult;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::UDPSender;
+use crate::test_support::{buffer, leak, leak_mut, run, CountingRng, Events, MockUdp, Pending};
+use core::cell::{Cell, RefCell};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest::{self, Digest, Sha256};
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM};
+use kernel::hil::time::Alarm;
+use kernel::ErrorCode;
+use std::vec::Vec;
+
+const IDENTITY: &[u8] = b"tock";
+const PSK: &str = "0123456789abcdef0123456789abcdef";
+const SERVER_RANDOM: &str = "d7bea9b0bb859fa1f35fcf58728ebdfe5070bc4d124c34568bec40fdb5fabae0";
+const MASTER_SECRET: &str = "2eec5ec0abdc086d64d2831de4464ad4790fe6003e1ab2f141cc6173e910082c\
+                             c94c0d8c3c2a93e9f3f25426e81be69c";
+const KEY_BLOCK: &str = "7f5870d702f0a8eac7880a51c5ce1b53f2c99470d36173803deb22fcd71e4aa9\
+                         f60c2f31f4a51de5";
+
+/// ClientHello without cookie
+const CLIENT_1: &str = "16fefd000000000000000000360100002a000000000000002afefd000102030405\
+                        060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f00000002c0a80100";
+/// HelloVerifyRequest (DTLS 1.0 record version, as RFC 6347 allows)
+const SERVER_1: &str = "16feff00000000000000000023030000170000000000000017feff149009b74888\
+                        b50476fa33a484cc136ef3b33901e9";
+/// ClientHello with cookie
+const CLIENT_2: &str = "16fefd0000000000000001004a0100003e000100000000003efefd000102030405\
+                        060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f00149009b74888\
+                        b50476fa33a484cc136ef3b33901e90002c0a80100";
+/// ServerHello, ServerHelloDone
+const SERVER_2: &str = "16fefd00000000000000010052020000460001000000000046fefdd7bea9b0bb85\
+                        9fa1f35fcf58728ebdfe5070bc4d124c34568bec40fdb5fabae020bfa1d6fa8650\
+                        3cf03e7bb0a93cef33c8e36da236b75397d9e900604fd351b4cfc0a80016fefd00\
+                        00000000000002000c0e0000000002000000000000";
+/// ClientKeyExchange, ChangeCipherSpec, Finished
+const CLIENT_3: &str = "16fefd000000000000000200121000000600020000000000060004746f636b14fe\
+                        fd000000000000000300010116fefd000100000000000000280001000000000000\
+                        39ef9376545525b5571ea6e83d4d6d22fd1fbdfe4efb9a3f99f0ddc1e39fda3e";
+/// ChangeCipherSpec, Finished
+const SERVER_3: &str = "14fefd000000000000000300010116fefd00010000000000000028000100000000\
+                        0000d5c38645f864b68f466d3960c63a30f2bf59aefc549b911d45744e49d9d946c1";
+/// "hello tock\n"
+const CLIENT_DATA: &str = "17fefd0001000000000001001b0001000000000001b1432215f708f4f17e5899\
+                           4ad7730015fb6b74";
+/// "hi from openssl\n"
+const SERVER_DATA: &str = "17fefd000100000000000100200001000000000001751b33e412003a635bf55f\
+                           d32cc8df0bad9e8ec45eb45068";
+const CLIENT_CLOSE: &str = "15fefd0001000000000002001200010000000000025a9902b104672701d492";
+
+const SERVER_PORT: u16 = 5684;
+
+fn hex(s: &str) -> Vec<u8> {
+    let digits: Vec<u8> = s
+        .bytes()
+        .filter(|b| b.is_ascii_hexdigit())
+        .map(|b| (b as char).to_digit(16).unwrap() as u8)
+        .collect();
+    digits.chunks(2).map(|d| d[0] << 4 | d[1]).collect()
+}
+
+fn server_addr() -> IPAddr {
+    let mut addr = IPAddr([0; 16]);
+    addr.0[0] = 0xfd;
+    addr.0[15] = 1;
+    addr
+}
+
+// Software models of the hardware the session runs on
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    fn primes(n: usize) -> Vec<f64> {
+        (2u32..)
+            .filter(|p| (2..*p).all(|d| p % d != 0))
+            .take(n)
+            .map(|p| p as f64)
+            .collect()
+    }
+    let frac = |x: f64| ((x - x.floor()) * 4294967296.0) as u32;
+    let k: Vec<u32> = primes(64).iter().map(|p| frac(p.cbrt())).collect();
+    let mut h: Vec<u32> = primes(8).iter().map(|p| frac(p.sqrt())).collect();
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([
+                block[4 * i],
+                block[4 * i + 1],
+                block[4 * i + 2],
+                block[4 * i + 3],
+            ]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let mut v = [h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7]];
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(k[i])
+                .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [
+                t1.wrapping_add(t2),
+                v[0],
+                v[1],
+                v[2],
+                v[3].wrapping_add(t1),
+                v[4],
+                v[5],
+                v[6],
+            ];
+        }
+        for i in 0..8 {
+            h[i] = h[i].wrapping_add(v[i]);
+        }
+    }
+    let mut out = [0; 32];
+    for i in 0..8 {
+        out[4 * i..4 * i + 4].copy_from_slice(&h[i].to_be_bytes());
+    }
+    out
+}
+
+fn aes_sbox() -> [u8; 256] {
+    let mut sbox = [0u8; 256];
+    let (mut p, mut q) = (1u8, 1u8);
+    loop {
+        // p walks through the multiplicative group, q through its inverses
+        p = p ^ (p << 1) ^ if p & 0x80 != 0 { 0x1b } else { 0 };
+        q ^= q << 1;
+        q ^= q << 2;
+        q ^= q << 4;
+        if q & 0x80 != 0 {
+            q ^= 0x09;
+        }
+        sbox[p as usize] =
+            q ^ q.rotate_left(1) ^ q.rotate_left(2) ^ q.rotate_left(3) ^ q.rotate_left(4) ^ 0x63;
+        if p == 1 {
+            break;
+        }
+    }
+    sbox[0] = 0x63;
+    sbox
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn aes128_encrypt(key: &[u8; 16], block: &[u8; 16]) -> [u8; 16] {
+    let sbox = aes_sbox();
+    let mut w = [[0u8; 4]; 44];
+    for i in 0..4 {
+        w[i].copy_from_slice(&key[4 * i..4 * i + 4]);
+    }
+    let mut rcon = 1u8;
+    for i in 4..44 {
+        let mut t = w[i - 1];
+        if i % 4 == 0 {
+            t = [
+                sbox[t[1] as usize] ^ rcon,
+                sbox[t[2] as usize],
+                sbox[t[3] as usize],
+                sbox[t[0] as usize],
+            ];
+            rcon = xtime(rcon);
+        }
+        for j in 0..4 {
+            w[i][j] = w[i - 4][j] ^ t[j];
+        }
+    }
+    let add_round_key = |s: &mut [u8; 16], round: usize| {
+        for i in 0..16 {
+            s[i] ^= w[4 * round + i / 4][i % 4];
+        }
+    };
+    let mut s = *block;
+    add_round_key(&mut s, 0);
+    for round in 1..11 {
+        let mut t = [0u8; 16];
+        for c in 0..4 {
+            for r in 0..4 {
+                t[r + 4 * c] = sbox[s[r + 4 * ((c + r) % 4)] as usize];
+            }
+        }
+        if round != 10 {
+            for c in 0..4 {
+                let a = [t[4 * c], t[4 * c + 1], t[4 * c + 2], t[4 * c + 3]];
+                for r in 0..4 {
+                    t[4 * c + r] = xtime(a[r])
+                        ^ xtime(a[(r + 1) % 4])
+                        ^ a[(r + 1) % 4]
+                        ^ a[(r + 2) % 4]
+                        ^ a[(r + 3) % 4];
+                }
+            }
+        }
+        s = t;
+        add_round_key(&mut s, round);
+    }
+    s
+}
+
+/// AES-CCM (RFC 3610) over `m` in place. Returns the encrypted tag when
+/// encrypting, or whether the tag at the end of `m` is valid.
+fn aes_ccm(
+    key: &[u8; 16],
+    nonce: &[u8],
+    aad: &[u8],
+    m: &mut [u8],
+    mic_len: usize,
+    encrypting: bool,
+) -> bool {
+    let l = 15 - nonce.len();
+    let block = |flags: u8, counter: usize| {
+        let mut b = [0u8; 16];
+        b[0] = flags;
+        b[1..1 + nonce.len()].copy_from_slice(nonce);
+        let c = (counter as u64).to_be_bytes();
+        b[16 - l..].copy_from_slice(&c[8 - l..]);
+        b
+    };
+    let msg_len = m.len() - mic_len;
+    let ctr = |m: &mut [u8]| {
+        for (i, chunk) in m.chunks_mut(16).enumerate() {
+            let s = aes128_encrypt(key, &block((l - 1) as u8, i + 1));
+            for (b, k) in chunk.iter_mut().zip(s.iter()) {
+                *b ^= k;
+            }
+        }
+    };
+    if !encrypting {
+        ctr(&mut m[..msg_len]);
+    }
+    // CBC-MAC over B_0 | L(a) | a | pad | m | pad
+    let flags =
+        if aad.is_empty() { 0 } else { 0x40 } | (((mic_len - 2) / 2) << 3) as u8 | (l - 1) as u8;
+    let mut input = block(flags, msg_len).to_vec();
+    if !aad.is_empty() {
+        input.extend_from_slice(&(aad.len() as u16).to_be_bytes());
+        input.extend_from_slice(aad);
+        while input.len() % 16 != 0 {
+            input.push(0);
+        }
+    }
+    input.extend_from_slice(&m[..msg_len]);
+    while input.len() % 16 != 0 {
+        input.push(0);
+    }
+    let mut x = [0u8; 16];
+    for chunk in input.chunks(16) {
+        for i in 0..16 {
+            x[i] ^= chunk[i];
+        }
+        x = aes128_encrypt(key, &x);
+    }
+    let s0 = aes128_encrypt(key, &block((l - 1) as u8, 0));
+    let mut tag = [0u8; 16];
+    for i in 0..mic_len {
+        tag[i] = x[i] ^ s0[i];
+    }
+    if encrypting {
+        ctr(&mut m[..msg_len]);
+        m[msg_len..].copy_from_slice(&tag[..mic_len]);
+        true
+    } else {
+        m[msg_len..] == tag[..mic_len]
+    }
+}
+
+struct SoftSha256 {
+    client: OptionalCell<&'static dyn digest::Client<'static, [u8; 32]>>,
+    data: RefCell<Vec<u8>>,
+    added: TakeCell<'static, [u8]>,
+    hashed: TakeCell<'static, [u8; 32]>,
+}
+
+impl SoftSha256 {
+    fn new() -> SoftSha256 {
+        SoftSha256 {
+            client: OptionalCell::empty(),
+            data: RefCell::new(Vec::new()),
+            added: TakeCell::empty(),
+            hashed: TakeCell::empty(),
+        }
+    }
+}
+
+impl Pending for SoftSha256 {
+    fn complete(&self) -> bool {
+        if let Some(buf) = self.added.take() {
+            self.client
+                .map(move |client| client.add_data_done(Ok(()), buf));
+            true
+        } else if let Some(digest) = self.hashed.take() {
+            self.client
+                .map(move |client| client.hash_done(Ok(()), digest));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Digest<'static, [u8; 32]> for SoftSha256 {
+    fn set_client(&'static self, client: &'static dyn digest::Client<'static, [u8; 32]>) {
+        self.client.set(client);
+    }
+
+    fn add_data(
+        &self,
+        data: LeasableBuffer<'static, u8>,
+    ) -> Result<usize, (ErrorCode, &'static mut [u8])> {
+        let len = data.len();
+        self.data.borrow_mut().extend_from_slice(&data[..]);
+        self.added.replace(data.take());
+        Ok(len)
+    }
+
+    fn run(
+        &'static self,
+        digest: &'static mut [u8; 32],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; 32])> {
+        *digest = sha256(&self.data.borrow());
+        self.data.borrow_mut().clear();
+        self.hashed.replace(digest);
+        Ok(())
+    }
+
+    fn clear_data(&self) {
+        self.data.borrow_mut().clear();
+    }
+}
+
+impl Sha256 for SoftSha256 {
+    fn set_mode_sha256(&self) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+}
+
+struct SoftCcm {
+    client: OptionalCell<&'static dyn CCMClient>,
+    key: Cell<[u8; 16]>,
+    nonce: RefCell<Vec<u8>>,
+    buf: TakeCell<'static, [u8]>,
+    job: Cell<(usize, usize, usize, usize, bool)>,
+}
+
+impl SoftCcm {
+    fn new() -> SoftCcm {
+        SoftCcm {
+            client: OptionalCell::empty(),
+            key: Cell::new([0; 16]),
+            nonce: RefCell::new(Vec::new()),
+            buf: TakeCell::empty(),
+            job: Cell::new((0, 0, 0, 0, false)),
+        }
+    }
+}
+
+impl Pending for SoftCcm {
+    fn complete(&self) -> bool {
+        let buf = match self.buf.take() {
+            Some(buf) => buf,
+            None => return false,
+        };
+        let (a_off, m_off, m_len, mic_len, encrypting) = self.job.get();
+        let aad = buf[a_off..m_off].to_vec();
+        let valid = aes_ccm(
+            &self.key.get(),
+            &self.nonce.borrow(),
+            &aad,
+            &mut buf[m_off..m_off + m_len + mic_len],
+            mic_len,
+            encrypting,
+        );
+        self.client
+            .map(move |client| client.crypt_done(buf, Ok(()), valid));
+        true
+    }
+}
+
+impl AES128CCM<'static> for SoftCcm {
+    fn set_client(&'static self, client: &'static dyn CCMClient) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        let mut k = [0; 16];
+        k.copy_from_slice(key);
+        self.key.set(k);
+        Ok(())
+    }
+
+    fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode> {
+        *self.nonce.borrow_mut() = nonce.to_vec();
+        Ok(())
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        mic_len: usize,
+        _confidential: bool,
+        encrypting: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.buf.is_some() {
+            return Err((ErrorCode::BUSY, buf));
+        }
+        self.job.set((a_off, m_off, m_len, mic_len, encrypting));
+        self.buf.replace(buf);
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Connected(Result<(), ErrorCode>),
+    Received(Vec<u8>),
+    SendDone(Result<(), ErrorCode>),
+    Closed(Result<(), ErrorCode>),
+}
+
+struct Recorder {
+    events: Events<Event>,
+}
+
+impl DtlsClient for Recorder {
+    fn connected(&self, result: Result<(), ErrorCode>) {
+        self.events.push(Event::Connected(result));
+    }
+
+    fn received(&self, payload: &[u8]) {
+        self.events.push(Event::Received(payload.to_vec()));
+    }
+
+    fn send_done(&self, result: Result<(), ErrorCode>) {
+        self.events.push(Event::SendDone(result));
+    }
+
+    fn closed(&self, result: Result<(), ErrorCode>) {
+        self.events.push(Event::Closed(result));
+    }
+}
+
+struct PrfRecorder {
+    output: RefCell<Vec<u8>>,
+}
+
+impl PrfClient for PrfRecorder {
+    fn prf_done(&self, result: Result<(), ErrorCode>, output: &[u8]) {
+        assert_eq!(result, Ok(()));
+        *self.output.borrow_mut() = output.to_vec();
+    }
+
+    fn sha256_done(&self, result: Result<(), ErrorCode>, hash: &[u8; SHA256_LEN]) {
+        assert_eq!(result, Ok(()));
+        *self.output.borrow_mut() = hash.to_vec();
+    }
+}
+
+type TestSession = DtlsSession<'static, EmulatedAlarm<'static>, SoftCcm, SoftSha256>;
+
+struct Harness {
+    sha: &'static SoftSha256,
+    ccm: &'static SoftCcm,
+    udp: &'static MockUdp,
+    alarm: &'static EmulatedAlarm<'static>,
+    session: &'static TestSession,
+    client: &'static Recorder,
+}
+
+/// Large enough for any record of the handshake.
+const BUFFER_LEN: usize = 384;
+
+fn prf() -> (&'static SoftSha256, &'static Prf<'static, SoftSha256>) {
+    let sha = leak(SoftSha256::new());
+    let prf = leak(Prf::new(sha, buffer(BUFFER_LEN), leak_mut([0; 32])));
+    sha.set_client(prf);
+    (sha, prf)
+}
+
+impl Harness {
+    fn new() -> Harness {
+        let (sha, prf) = prf();
+        let ccm = leak(SoftCcm::new());
+        let udp = leak(MockUdp::new());
+        let alarm = leak(EmulatedAlarm::new());
+        // Produces the client random 00 01 02 .. 1f
+        let rng = leak(CountingRng::new(0x00010203, 0x04040404));
+        let net_cap = leak(NetworkCapability::unrestricted());
+        let session = leak(DtlsSession::new(
+            udp,
+            alarm,
+            ccm,
+            prf,
+            rng,
+            buffer(BUFFER_LEN),
+            buffer(BUFFER_LEN),
+            buffer(BUFFER_LEN),
+            net_cap,
+        ));
+        let client = leak(Recorder {
+            events: Events::new(),
+        });
+        prf.set_client(session);
+        alarm.set_alarm_client(session);
+        ccm.set_client(session);
+        udp.set_client(session);
+        session.set_client(client);
+        session.set_psk(IDENTITY, &hex(PSK)).expect("PSK rejected");
+        Harness {
+            sha,
+            ccm,
+            udp,
+            alarm,
+            session,
+            client,
+        }
+    }
+
+    /// Completes pending operations until everything is idle.
+    fn run(&self) {
+        run(&[self.sha, self.ccm, self.udp]);
+    }
+
+    /// Lets the retransmission timer expire.
+    fn timeout(&self) {
+        self.alarm.advance(self.alarm.remaining().unwrap());
+    }
+
+    fn sent(&self) -> Vec<Vec<u8>> {
+        self.udp
+            .sent()
+            .into_iter()
+            .map(|datagram| datagram.payload)
+            .collect()
+    }
+
+    fn events(&self) -> Vec<Event> {
+        self.client.events.take()
+    }
+
+    fn deliver(&self, dgram: &str) {
+        self.session.receive(
+            server_addr(),
+            IPAddr([0; 16]),
+            SERVER_PORT,
+            SERVER_PORT,
+            &hex(dgram),
+        );
+        self.run();
+    }
+
+    /// Runs the handshake up to the point where our last flight is sent.
+    fn handshake(&self) {
+        self.session.connect(server_addr(), SERVER_PORT).unwrap();
+        self.run();
+        assert_eq!(self.sent(), [hex(CLIENT_1)]);
+        self.deliver(SERVER_1);
+        assert_eq!(self.sent(), [hex(CLIENT_2)]);
+        self.deliver(SERVER_2);
+        assert_eq!(self.sent(), [hex(CLIENT_3)]);
+        assert!(self.alarm.is_armed());
+    }
+}
+
+#[test]
+fn software_models() {
+    assert_eq!(
+        sha256(b"abc").to_vec(),
+        hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    );
+    // FIPS-197, Appendix C.1
+    let mut key = [0; 16];
+    key.copy_from_slice(&hex("000102030405060708090a0b0c0d0e0f"));
+    let mut block = [0; 16];
+    block.copy_from_slice(&hex("00112233445566778899aabbccddeeff"));
+    assert_eq!(
+        aes128_encrypt(&key, &block).to_vec(),
+        hex("69c4e0d86a7b0430d8cdb78070b4c55a")
+    );
+}
+
+#[test]
+fn record_codec() {
+    let hvr = hex(SERVER_1);
+    let (off, hdr) = RecordHeader::decode(&hvr).done().unwrap();
+    assert_eq!(off, 13);
+    assert_eq!(
+        (hdr.content_type, hdr.epoch, hdr.seq, hdr.len),
+        (22, 0, 0, 35)
+    );
+    let (_, cookie) = decode_hello_verify_request(&hvr[25..]).done().unwrap();
+    assert_eq!(cookie, &hex("9009b74888b50476fa33a484cc136ef3b33901e9")[..]);
+
+    let flight = hex(SERVER_2);
+    let hello = match ServerHello::decode(&flight[25..]) {
+        SResult::Done(_, hello) => hello,
+        _ => panic!("ServerHello not decoded"),
+    };
+    assert_eq!(hello.random.to_vec(), hex(SERVER_RANDOM));
+    assert_eq!(hello.cipher_suite, 0xc0a8);
+    assert!(ServerHello::decode(&flight[25..40]).is_needed());
+
+    let mut buf = [0; 13];
+    hdr.encode(&mut buf);
+    assert_eq!(buf[..], hvr[..13]);
+}
+
+#[test]
+fn replay_window() {
+    let mut window = ReplayWindow::default();
+    assert!(window.check(5));
+    window.update(5);
+    assert!(!window.check(5));
+    assert!(window.check(4));
+    assert!(window.check(40));
+    window.update(40);
+    // 5 fell out of the window, and anything that old is rejected
+    assert!(!window.check(5));
+    assert!(window.check(39));
+    window.update(39);
+    assert!(!window.check(39));
+}
+
+#[test]
+fn prf_derives_recorded_master_secret() {
+    let (sha, prf) = prf();
+    let recorder = leak(PrfRecorder {
+        output: RefCell::new(Vec::new()),
+    });
+    prf.set_client(recorder);
+
+    // premaster secret: len | zeros | len | psk
+    let psk = hex(PSK);
+    let mut premaster = Vec::new();
+    premaster.extend_from_slice(&[0, 16]);
+    premaster.extend_from_slice(&[0; 16]);
+    premaster.extend_from_slice(&[0, 16]);
+    premaster.extend_from_slice(&psk);
+    let client_random: Vec<u8> = (0..32).collect();
+    let server_random = hex(SERVER_RANDOM);
+
+    prf.compute(
+        &premaster,
+        b"master secret",
+        &client_random,
+        &server_random,
+        48,
+    )
+    .unwrap();
+    assert_eq!(prf.compute(&[], b"", &[], &[], 1), Err(ErrorCode::BUSY));
+    run(&[sha]);
+    assert_eq!(*recorder.output.borrow(), hex(MASTER_SECRET));
+
+    let master = hex(MASTER_SECRET);
+    prf.compute(
+        &master,
+        b"key expansion",
+        &server_random,
+        &client_random,
+        40,
+    )
+    .unwrap();
+    run(&[sha]);
+    assert_eq!(*recorder.output.borrow(), hex(KEY_BLOCK));
+
+    assert_eq!(
+        prf.compute(&[0; 65], b"master secret", &[], &[], 48),
+        Err(ErrorCode::SIZE)
+    );
+}
+
+#[test]
+fn handshake_against_openssl() {
+    let h = Harness::new();
+    h.handshake();
+    assert!(h.events().is_empty());
+
+    h.deliver(SERVER_3);
+    assert_eq!(h.events(), [Event::Connected(Ok(()))]);
+    assert!(h.session.is_connected());
+    assert!(!h.alarm.is_armed());
+
+    h.session.send(b"hello tock\n").unwrap();
+    assert_eq!(h.session.send(b"again"), Err(ErrorCode::BUSY));
+    h.run();
+    assert_eq!(h.sent(), [hex(CLIENT_DATA)]);
+    assert_eq!(h.events(), [Event::SendDone(Ok(()))]);
+
+    h.deliver(SERVER_DATA);
+    assert_eq!(h.events(), [Event::Received(b"hi from openssl\n".to_vec())]);
+    // A replayed record is dropped
+    h.deliver(SERVER_DATA);
+    assert!(h.events().is_empty());
+
+    h.session.close().unwrap();
+    h.run();
+    assert_eq!(h.sent(), [hex(CLIENT_CLOSE)]);
+    assert_eq!(h.events(), [Event::Closed(Ok(()))]);
+    assert_eq!(h.session.send(b"late"), Err(ErrorCode::OFF));
+}
+
+#[test]
+fn tampered_finished_is_ignored_until_timeout() {
+    let h = Harness::new();
+    h.handshake();
+
+    let mut flight = hex(SERVER_3);
+    let last = flight.len() - 1;
+    flight[last] ^= 1;
+    h.session.receive(
+        server_addr(),
+        IPAddr([0; 16]),
+        SERVER_PORT,
+        SERVER_PORT,
+        &flight,
+    );
+    h.run();
+    assert!(h.events().is_empty());
+
+    // Our last flight is retransmitted unchanged until we give up
+    for _ in 0..4 {
+        h.timeout();
+        h.run();
+        assert_eq!(h.sent(), [hex(CLIENT_3)]);
+    }
+    h.timeout();
+    assert_eq!(h.events(), [Event::Connected(Err(ErrorCode::NOACK))]);
+    assert!(!h.session.is_connected());
+}
+
+#[test]
+fn handshake_ignores_other_peers_and_rejects_bad_suites() {
+    let h = Harness::new();
+    h.session.connect(server_addr(), SERVER_PORT).unwrap();
+    h.run();
+    h.sent();
+    assert_eq!(
+        h.session.connect(server_addr(), SERVER_PORT),
+        Err(ErrorCode::BUSY)
+    );
+
+    // Same datagram from another port
+    h.session.receive(
+        server_addr(),
+        IPAddr([0; 16]),
+        SERVER_PORT + 1,
+        SERVER_PORT,
+        &hex(SERVER_1),
+    );
+    h.run();
+    assert!(h.sent().is_empty());
+
+    // A ServerHello selecting a suite we did not offer
+    let mut flight = hex(SERVER_2);
+    let suite = flight.len() - 25 - 3;
+    flight[suite] = 0x00;
+    h.session.receive(
+        server_addr(),
+        IPAddr([0; 16]),
+        SERVER_PORT,
+        SERVER_PORT,
+        &flight,
+    );
+    assert_eq!(h.events(), [Event::Connected(Err(ErrorCode::FAIL))]);
+}
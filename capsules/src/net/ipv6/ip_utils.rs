@@ -111,6 +111,15 @@ impl IPAddr {
     pub fn is_multicast(&self) -> bool {
         self.0[0] == 0xff
     }
+
+    /// Whether this is the interface-local or link-local all-nodes
+    /// multicast address (ff01::1 or ff02::1), which every node receives.
+    pub fn is_all_nodes(&self) -> bool {
+        self.0[0] == 0xff
+            && (self.0[1] == 0x01 || self.0[1] == 0x02)
+            && self.0[2..15].iter().all(|&b| b == 0)
+            && self.0[15] == 1
+    }
 }
 
 pub fn compute_udp_checksum(
@@ -195,6 +204,9 @@ pub fn compute_icmp_checksum(
     let lsb = icmp_header.get_code() as u32;
     sum += msb + lsb;
 
+    // add checksum, which is zero when computing it for a packet to be sent
+    sum += icmp_header.get_cksum() as u32;
+
     // add options
     match icmp_header.get_options() {
         ICMP6HeaderOptions::Type1 { unused } | ICMP6HeaderOptions::Type3 { unused } => {
@@ -205,6 +217,7 @@ pub fn compute_icmp_checksum(
             sum += id as u32;
             sum += seqno as u32;
         }
+        ICMP6HeaderOptions::Type155 => {}
     }
 
     // add icmp payload
@@ -215,7 +228,7 @@ pub fn compute_icmp_checksum(
     while sum > 0xffff {
         let sum_upper = sum >> 16;
         let sum_lower = sum & 0xffff;
-        sum += sum_upper + sum_lower;
+        sum = sum_upper + sum_lower;
     }
 
     sum = !sum;
@@ -253,7 +266,12 @@ pub fn compute_sum(buf: &[u8], len: u16) -> u32 {
     let mut i: usize = 0;
     while i < (len as usize) {
         let msb = (buf[i] as u32) << 8;
-        let lsb = buf[i + 1] as u32;
+        // An odd trailing byte is padded with zero
+        let lsb = if i + 1 < len as usize {
+            buf[i + 1] as u32
+        } else {
+            0
+        };
         sum += msb + lsb;
         i += 2;
     }
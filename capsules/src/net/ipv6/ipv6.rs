@@ -281,13 +281,12 @@ impl IP6Header {
                 Ok(())
             }
             ip6_nh::ICMP => {
-                // Untested (10/5/18)
-                let mut icmp_header: [u8; ICMP_HDR_LEN] = [0; ICMP_HDR_LEN];
-                icmp_header.copy_from_slice(&buf[..ICMP_HDR_LEN]);
-                let checksum = match ICMP6Header::decode(&icmp_header).done() {
+                // The ICMPv6 header size depends on the message type
+                let checksum = match ICMP6Header::decode(buf).done() {
                     Some((_offset, mut hdr)) => {
                         hdr.set_len(buf.len() as u16);
-                        u16::from_be(compute_icmp_checksum(&self, &hdr, &buf[ICMP_HDR_LEN..]))
+                        let hdr_size = hdr.get_hdr_size();
+                        u16::from_be(compute_icmp_checksum(&self, &hdr, &buf[hdr_size..]))
                     }
                     None => 0xffff, //Will be dropped, as ones comp -0 checksum is invalid
                 };
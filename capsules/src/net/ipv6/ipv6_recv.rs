@@ -1,3 +1,4 @@
+use crate::net::ipv6::ip_utils::ip6_nh;
 use crate::net::ipv6::IP6Header;
 use crate::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
 use kernel::common::cells::OptionalCell;
@@ -25,6 +26,8 @@ use kernel::ErrorCode;
   udp_recv, a `UDPReceive` struct.
 - The UDPReceive struct is a field of the UDPDriver, which ultimately passes the
   packets up to userland.
+- Optionally, `IP6RecvStruct` has a second client that receives all ICMPv6
+  packets instead (e.g. the RPL routing capsule).
 */
 
 pub trait IP6RecvClient {
@@ -38,22 +41,32 @@ pub trait IP6RecvClient {
 /// that are not among the local addresses of this device.
 pub trait IP6Receiver<'a> {
     fn set_client(&self, client: &'a dyn IP6RecvClient);
+
+    /// Sets a client that receives ICMPv6 packets. If no ICMPv6 client is
+    /// set, ICMPv6 packets are passed to the regular client.
+    fn set_icmp_client(&self, client: &'a dyn IP6RecvClient);
 }
 
 pub struct IP6RecvStruct<'a> {
     client: OptionalCell<&'a dyn IP6RecvClient>,
+    icmp_client: OptionalCell<&'a dyn IP6RecvClient>,
 }
 
 impl<'a> IP6Receiver<'a> for IP6RecvStruct<'a> {
     fn set_client(&self, client: &'a dyn IP6RecvClient) {
         self.client.set(client);
     }
+
+    fn set_icmp_client(&self, client: &'a dyn IP6RecvClient) {
+        self.icmp_client.set(client);
+    }
 }
 
 impl<'a> IP6RecvStruct<'a> {
     pub fn new() -> IP6RecvStruct<'a> {
         IP6RecvStruct {
             client: OptionalCell::empty(),
+            icmp_client: OptionalCell::empty(),
         }
     }
 }
@@ -74,8 +87,13 @@ impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
                 // Note: Protocols for which checksum verification is not implemented (TCP, etc.)
                 // are automatically assumed as fine, rather than dropped
 
-                self.client
-                    .map(|client| client.receive(ip6_header, &buf[offset..len]));
+                let client =
+                    if ip6_header.get_next_header() == ip6_nh::ICMP && self.icmp_client.is_some() {
+                        &self.icmp_client
+                    } else {
+                        &self.client
+                    };
+                client.map(|client| client.receive(ip6_header, &buf[offset..len]));
             }
             None => {
                 debug!("failed to decode ipv6 header");
@@ -29,6 +29,9 @@ use kernel::debug;
 use kernel::hil::time;
 use kernel::ErrorCode;
 
+/// The IEEE 802.15.4 short address used for link-layer broadcasts.
+const BROADCAST_SHORT_ADDR: u16 = 0xffff;
+
 /// This trait must be implemented by upper layers in order to receive
 /// the `send_done` callback when a transmission has completed. The upper
 /// layer must then call `IP6Sender.set_client` in order to receive this
@@ -37,6 +40,15 @@ pub trait IP6SendClient {
     fn send_done(&self, result: Result<(), ErrorCode>);
 }
 
+/// This trait is implemented by routing protocols (e.g. RPL) to choose the
+/// link-layer next hop of outgoing packets. It is installed on an
+/// `IP6SendStruct` with `set_router`.
+pub trait IP6Router {
+    /// Returns the MAC address of the next hop towards `dst`, or `None` if
+    /// the packet should be sent to the configured gateway instead.
+    fn next_hop(&self, dst: IPAddr) -> Option<MacAddress>;
+}
+
 /// This trait provides a basic IPv6 sending interface. It exposes basic
 /// configuration information for the IPv6 layer (setting the source address,
 /// setting the gateway MAC address), as well as a way to send an IPv6
@@ -101,9 +113,9 @@ pub struct IP6SendStruct<'a, A: time::Alarm<'a>> {
     tx_buf: TakeCell<'static, [u8]>,
     sixlowpan: TxState<'a>,
     radio: &'a dyn MacDevice<'a>,
-    dst_mac_addr: MacAddress,
     src_mac_addr: MacAddress,
     client: OptionalCell<&'a dyn IP6SendClient>,
+    router: OptionalCell<&'a dyn IP6Router>,
     ip_vis: &'static IpVisibilityCapability,
 }
 
@@ -135,12 +147,19 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
         if !net_cap.remote_addr_valid(dst, self.ip_vis) {
             return Err(ErrorCode::FAIL);
         }
-        let _ = self.sixlowpan.init(
-            self.src_mac_addr,
-            self.dst_mac_addr,
-            self.radio.get_pan(),
-            None,
-        );
+        // Multicast packets are sent as link-layer broadcasts, everything
+        // else goes to the next hop chosen by the router, if any, or to the
+        // configured gateway.
+        let dst_mac_addr = if dst.is_multicast() {
+            MacAddress::Short(BROADCAST_SHORT_ADDR)
+        } else {
+            self.router
+                .and_then(|router| router.next_hop(dst))
+                .unwrap_or_else(|| self.gateway.get())
+        };
+        let _ = self
+            .sixlowpan
+            .init(self.src_mac_addr, dst_mac_addr, self.radio.get_pan(), None);
         self.init_packet(dst, transport_header, payload);
         let ret = self.send_next_fragment();
         ret
@@ -166,13 +185,18 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
             tx_buf: TakeCell::new(tx_buf),
             sixlowpan: sixlowpan,
             radio: radio,
-            dst_mac_addr: dst_mac_addr,
             src_mac_addr: src_mac_addr,
             client: OptionalCell::empty(),
+            router: OptionalCell::empty(),
             ip_vis: ip_vis,
         }
     }
 
+    /// Sets the router that chooses the next hop for unicast packets.
+    pub fn set_router(&self, router: &'a dyn IP6Router) {
+        self.router.set(router);
+    }
+
     fn init_packet(
         &self,
         dst_addr: IPAddr,
@@ -98,6 +98,15 @@ impl UdpVisibilityCapability {
     }
 }
 
+/// Capsule unit tests cannot create a `NetworkCapabilityCreationCapability`
+/// since that requires `unsafe`.
+#[cfg(test)]
+impl UdpVisibilityCapability {
+    pub(crate) fn unrestricted() -> &'static UdpVisibilityCapability {
+        &UdpVisibilityCapability { _priv: () }
+    }
+}
+
 impl IpVisibilityCapability {
     pub fn new(
         _create_net_cap: &dyn NetworkCapabilityCreationCapability,
@@ -116,6 +125,19 @@ pub struct NetworkCapability {
     local_ports: PortRange,  // ports from which the holder may send
 }
 
+/// Capsule unit tests cannot create a `NetworkCapabilityCreationCapability`
+/// since that requires `unsafe`.
+#[cfg(test)]
+impl NetworkCapability {
+    pub(crate) fn unrestricted() -> NetworkCapability {
+        NetworkCapability {
+            remote_addrs: AddrRange::Any,
+            remote_ports: PortRange::Any,
+            local_ports: PortRange::Any,
+        }
+    }
+}
+
 impl NetworkCapability {
     pub fn new(
         remote_addrs: AddrRange,
@@ -0,0 +1,1094 @@
+//! The state of a node in an RPL DODAG (Destination-Oriented Directed
+//! Acyclic Graph), independent of timers and of the network stack.
+//!
+//! `Dodag` keeps the parent set, computes the node's rank with Objective
+//! Function Zero (RFC 6552) and selects the preferred parent. It also keeps
+//! the table of downward routes learned from DAOs:
+//!
+//! - In storing mode, every node keeps a route for each target in its
+//!   sub-DODAG, pointing to the child the DAO was received from.
+//! - In non-storing mode, only the root keeps routes. Each entry records the
+//!   parent of a target, so the root can compute the complete path to it
+//!   and place it in a Source Routing Header.
+//!
+//! Keeping this logic separate from the `Rpl` capsule allows it to be
+//! tested by simulating small topologies on the host.
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::rpl::messages::{Dao, DaoAck, Dio, DodagConfig, Mop, RplOption, RplOptions};
+use crate::net::rpl::messages::{Target, Transit};
+use crate::net::stream::SResult;
+
+/// The rank advertised by nodes that have no route to the root.
+pub const INFINITE_RANK: u16 = 0xffff;
+
+pub const DEFAULT_MIN_HOP_RANK_INCREASE: u16 = 256;
+pub const DEFAULT_MAX_RANK_INCREASE: u16 = 7 * DEFAULT_MIN_HOP_RANK_INCREASE;
+
+/// The step of rank used by OF0 for a link of normal quality. Steps range
+/// from 1 (excellent link) to 9 (poor link).
+pub const DEFAULT_STEP_OF_RANK: u16 = 3;
+
+/// Trickle parameters used when the root does not send a DODAG
+/// Configuration option. `Imin` is 2^12 ms (about 4 seconds), which suits
+/// low-power networks better than the 8 ms suggested by RFC 6550.
+pub const DEFAULT_DIO_INTERVAL_MIN: u8 = 12;
+pub const DEFAULT_DIO_INTERVAL_DOUBLINGS: u8 = 8;
+pub const DEFAULT_DIO_REDUNDANCY: u8 = 10;
+
+/// Route lifetimes are expressed in units of one minute, and default to
+/// 30 minutes.
+pub const DEFAULT_LIFETIME_UNIT: u16 = 60;
+pub const DEFAULT_LIFETIME: u8 = 30;
+
+/// Objective Code Point of OF0.
+pub const OCP_OF0: u16 = 0;
+
+/// Maximum number of parents kept in the parent set.
+pub const MAX_PARENTS: usize = 4;
+
+/// Maximum number of downward routes.
+pub const MAX_ROUTES: usize = 16;
+
+/// Maximum number of hops in a source route.
+pub const MAX_SOURCE_ROUTE_LEN: usize = 8;
+
+/// Maximum number of targets advertised in a single DAO.
+const MAX_DAO_TARGETS: usize = MAX_ROUTES + 1;
+
+impl Default for DodagConfig {
+    fn default() -> DodagConfig {
+        DodagConfig {
+            dio_int_doublings: DEFAULT_DIO_INTERVAL_DOUBLINGS,
+            dio_int_min: DEFAULT_DIO_INTERVAL_MIN,
+            dio_redundancy: DEFAULT_DIO_REDUNDANCY,
+            max_rank_increase: DEFAULT_MAX_RANK_INCREASE,
+            min_hop_rank_increase: DEFAULT_MIN_HOP_RANK_INCREASE,
+            ocp: OCP_OF0,
+            default_lifetime: DEFAULT_LIFETIME,
+            lifetime_unit: DEFAULT_LIFETIME_UNIT,
+        }
+    }
+}
+
+/// Derives the link-layer address of a neighbor from the interface
+/// identifier of its IPv6 address. This is the inverse of
+/// `IPAddr::generate_from_mac`.
+pub fn mac_from_addr(addr: &IPAddr) -> MacAddress {
+    if addr.0[8..14] == [0, 0, 0, 0xff, 0xfe, 0] {
+        MacAddress::Short((addr.0[14] as u16) << 8 | addr.0[15] as u16)
+    } else {
+        let mut long_addr = [0; 8];
+        long_addr.copy_from_slice(&addr.0[8..16]);
+        long_addr[0] ^= 0b00000010;
+        MacAddress::Long(long_addr)
+    }
+}
+
+// Returns whether lollipop counter `a` is newer than `b` (RFC 6550,
+// Section 7.2), using the simple circular comparison.
+fn is_newer(a: u8, b: u8) -> bool {
+    (a.wrapping_sub(b) as i8) > 0
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Parent {
+    pub addr: IPAddr,
+    pub rank: u16,
+    pub dtsn: u8,
+    /// The OF0 step of rank of the link to this parent.
+    pub step: u16,
+}
+
+/// The result of processing a DIO.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DioEvent {
+    /// The DIO was not for our DODAG, or came from an unsuitable parent.
+    Ignored,
+    /// The DIO is consistent with our state (for Trickle).
+    Consistent,
+    /// The DIO reveals an inconsistency, e.g. an outdated DODAG version;
+    /// Trickle should be reset.
+    Inconsistent,
+    /// We joined the DODAG.
+    Joined,
+    /// The preferred parent changed, or our rank changed. Trickle should be
+    /// reset and a new DAO sent.
+    ParentChanged,
+    /// The preferred parent asked for new DAOs by incrementing its DTSN.
+    DaoRequested,
+    /// We lost all parents. The node should poison its sub-DODAG by sending
+    /// a DIO with an infinite rank.
+    Detached,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Route {
+    pub target: IPAddr,
+    /// The next hop towards the target in storing mode, or the parent of
+    /// the target in non-storing mode.
+    pub via: IPAddr,
+    /// Remaining lifetime, in lifetime units.
+    pub lifetime: u8,
+}
+
+/// A fixed-size table of downward routes.
+pub struct RoutingTable {
+    routes: [Option<Route>; MAX_ROUTES],
+}
+
+impl RoutingTable {
+    pub fn new() -> RoutingTable {
+        RoutingTable {
+            routes: [None; MAX_ROUTES],
+        }
+    }
+
+    /// Adds or updates the route to `target`. Returns `Err(())` if the
+    /// table is full.
+    pub fn add(&mut self, target: IPAddr, via: IPAddr, lifetime: u8) -> Result<(), ()> {
+        let route = Route {
+            target,
+            via,
+            lifetime,
+        };
+        if let Some(slot) = self
+            .routes
+            .iter_mut()
+            .find(|r| r.map_or(false, |r| r.target == target))
+        {
+            *slot = Some(route);
+            return Ok(());
+        }
+        match self.routes.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some(route);
+                Ok(())
+            }
+            None => Err(()),
+        }
+    }
+
+    pub fn remove(&mut self, target: &IPAddr) {
+        for slot in self.routes.iter_mut() {
+            if slot.map_or(false, |r| r.target == *target) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Removes all routes through `via`.
+    pub fn remove_via(&mut self, via: &IPAddr) {
+        for slot in self.routes.iter_mut() {
+            if slot.map_or(false, |r| r.via == *via) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn lookup(&self, target: &IPAddr) -> Option<IPAddr> {
+        self.iter().find(|r| r.target == *target).map(|r| r.via)
+    }
+
+    pub fn clear(&mut self) {
+        self.routes = [None; MAX_ROUTES];
+    }
+
+    /// Ages all routes by `units` lifetime units and removes the expired
+    /// ones.
+    pub fn expire(&mut self, units: u8) {
+        for slot in self.routes.iter_mut() {
+            if let Some(route) = slot {
+                if route.lifetime <= units {
+                    *slot = None;
+                } else {
+                    route.lifetime -= units;
+                }
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Route> {
+        self.routes.iter().filter_map(|r| r.as_ref())
+    }
+}
+
+/// The state of this node in a DODAG.
+pub struct Dodag {
+    instance_id: u8,
+    dodag_id: IPAddr,
+    version: u8,
+    mop: Mop,
+    grounded: bool,
+    preference: u8,
+    dtsn: u8,
+    config: DodagConfig,
+
+    is_root: bool,
+    joined: bool,
+    rank: u16,
+    /// The lowest rank advertised since joining the current version,
+    /// which bounds how far our rank may increase (RFC 6550, 8.2.2.4).
+    lowest_rank: u16,
+    parents: [Option<Parent>; MAX_PARENTS],
+    preferred: Option<usize>,
+    routes: RoutingTable,
+}
+
+impl Dodag {
+    /// Creates the state of a node that has not joined a DODAG yet.
+    pub fn new() -> Dodag {
+        Dodag {
+            instance_id: 0,
+            dodag_id: IPAddr::new(),
+            version: 0,
+            mop: Mop::NoDownward,
+            grounded: false,
+            preference: 0,
+            dtsn: 0,
+            config: DodagConfig::default(),
+            is_root: false,
+            joined: false,
+            rank: INFINITE_RANK,
+            lowest_rank: INFINITE_RANK,
+            parents: [None; MAX_PARENTS],
+            preferred: None,
+            routes: RoutingTable::new(),
+        }
+    }
+
+    /// Creates the state of the root of a new, grounded DODAG.
+    pub fn new_root(instance_id: u8, dodag_id: IPAddr, mop: Mop, config: DodagConfig) -> Dodag {
+        let mut dodag = Dodag::new();
+        dodag.instance_id = instance_id;
+        dodag.dodag_id = dodag_id;
+        // Start the version close to the end of the lollipop's linear
+        // region, as suggested by RFC 6550
+        dodag.version = 240;
+        dodag.mop = mop;
+        dodag.grounded = true;
+        dodag.config = config;
+        dodag.is_root = true;
+        dodag.joined = true;
+        dodag.rank = config.min_hop_rank_increase;
+        dodag.lowest_rank = dodag.rank;
+        dodag
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.is_root
+    }
+
+    /// Whether the node is part of a DODAG and has a finite rank.
+    pub fn is_joined(&self) -> bool {
+        self.joined && self.rank != INFINITE_RANK
+    }
+
+    pub fn rank(&self) -> u16 {
+        self.rank
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn instance_id(&self) -> u8 {
+        self.instance_id
+    }
+
+    pub fn dodag_id(&self) -> IPAddr {
+        self.dodag_id
+    }
+
+    pub fn mop(&self) -> Mop {
+        self.mop
+    }
+
+    pub fn config(&self) -> DodagConfig {
+        self.config
+    }
+
+    pub fn routes(&self) -> &RoutingTable {
+        &self.routes
+    }
+
+    pub fn preferred_parent(&self) -> Option<Parent> {
+        self.preferred.and_then(|i| self.parents[i])
+    }
+
+    /// The DIO advertising the current state.
+    pub fn dio(&self) -> Dio {
+        Dio {
+            instance_id: self.instance_id,
+            version: self.version,
+            rank: self.rank,
+            grounded: self.grounded,
+            mop: self.mop,
+            preference: self.preference,
+            dtsn: self.dtsn,
+            dodag_id: self.dodag_id,
+        }
+    }
+
+    /// Starts a new version of the DODAG, which makes all nodes reselect
+    /// their parents (global repair). Only valid at the root.
+    pub fn increment_version(&mut self) {
+        if self.is_root {
+            self.version = self.version.wrapping_add(1);
+            self.routes.clear();
+        }
+    }
+
+    /// Requests all children to send new DAOs, by incrementing the DTSN.
+    pub fn increment_dtsn(&mut self) {
+        self.dtsn = self.dtsn.wrapping_add(1);
+    }
+
+    /// Leaves the DODAG, forgetting all parents and routes.
+    pub fn leave(&mut self) {
+        *self = Dodag::new();
+    }
+
+    // OF0 rank computation
+    fn path_rank(&self, parent: &Parent) -> u16 {
+        let increase = parent
+            .step
+            .saturating_mul(self.config.min_hop_rank_increase);
+        parent.rank.saturating_add(increase)
+    }
+
+    fn find_parent(&self, addr: &IPAddr) -> Option<usize> {
+        self.parents
+            .iter()
+            .position(|p| p.map_or(false, |p| p.addr == *addr))
+    }
+
+    /// Processes a DIO received from `from`. `config` is the DODAG
+    /// Configuration option carried by the DIO, if any, and `step` the OF0
+    /// step of rank of the link the DIO was received on.
+    pub fn process_dio(
+        &mut self,
+        from: IPAddr,
+        dio: &Dio,
+        config: Option<DodagConfig>,
+        step: u16,
+    ) -> DioEvent {
+        if self.is_root {
+            if dio.instance_id != self.instance_id || dio.dodag_id != self.dodag_id {
+                return DioEvent::Ignored;
+            }
+            if is_newer(self.version, dio.version) {
+                return DioEvent::Inconsistent;
+            }
+            return DioEvent::Consistent;
+        }
+
+        let parent = Parent {
+            addr: from,
+            rank: dio.rank,
+            dtsn: dio.dtsn,
+            step,
+        };
+
+        if !self.joined {
+            if dio.rank == INFINITE_RANK {
+                return DioEvent::Ignored;
+            }
+            self.instance_id = dio.instance_id;
+            self.dodag_id = dio.dodag_id;
+            self.adopt_dio(dio, config);
+            self.joined = true;
+            self.parents[0] = Some(parent);
+            self.select_parent();
+            return if self.is_joined() {
+                DioEvent::Joined
+            } else {
+                self.joined = false;
+                DioEvent::Ignored
+            };
+        }
+
+        if dio.instance_id != self.instance_id || dio.dodag_id != self.dodag_id {
+            return DioEvent::Ignored;
+        }
+
+        if is_newer(dio.version, self.version) {
+            // Global repair: rejoin the new version through the sender
+            let was_joined = self.is_joined();
+            self.adopt_dio(dio, config);
+            self.parents = [None; MAX_PARENTS];
+            self.preferred = None;
+            self.routes.clear();
+            self.rank = INFINITE_RANK;
+            self.lowest_rank = INFINITE_RANK;
+            if dio.rank != INFINITE_RANK {
+                self.parents[0] = Some(parent);
+            }
+            self.select_parent();
+            return if self.is_joined() {
+                DioEvent::ParentChanged
+            } else if was_joined {
+                DioEvent::Detached
+            } else {
+                DioEvent::Ignored
+            };
+        }
+        if is_newer(self.version, dio.version) {
+            // The sender has not heard of the current version yet
+            return DioEvent::Inconsistent;
+        }
+
+        let old_preferred = self.preferred_parent();
+        let old_rank = self.rank;
+        let mut dtsn_changed = false;
+
+        match self.find_parent(&from) {
+            Some(i) if dio.rank == INFINITE_RANK => {
+                self.parents[i] = None;
+            }
+            Some(i) => {
+                if let Some(old) = self.parents[i] {
+                    dtsn_changed = old.dtsn != dio.dtsn;
+                }
+                self.parents[i] = Some(parent);
+            }
+            None => {
+                // Only nodes with a lower rank than ours may become parents,
+                // which prevents loops.
+                if dio.rank >= self.rank {
+                    return if !self.is_joined() {
+                        DioEvent::Ignored
+                    } else if dio.rank == INFINITE_RANK {
+                        DioEvent::Inconsistent
+                    } else {
+                        DioEvent::Consistent
+                    };
+                }
+                if !self.insert_parent(parent) {
+                    return DioEvent::Ignored;
+                }
+            }
+        }
+
+        let was_joined = self.is_joined();
+        self.select_parent();
+        let new_preferred = self.preferred_parent();
+
+        if !self.is_joined() {
+            return if was_joined {
+                DioEvent::Detached
+            } else {
+                DioEvent::Ignored
+            };
+        }
+        if old_preferred.map(|p| p.addr) != new_preferred.map(|p| p.addr) || old_rank != self.rank {
+            return DioEvent::ParentChanged;
+        }
+        if dtsn_changed && new_preferred.map_or(false, |p| p.addr == from) {
+            return DioEvent::DaoRequested;
+        }
+        if dio.rank == INFINITE_RANK {
+            return DioEvent::Inconsistent;
+        }
+        DioEvent::Consistent
+    }
+
+    fn adopt_dio(&mut self, dio: &Dio, config: Option<DodagConfig>) {
+        self.version = dio.version;
+        self.mop = dio.mop;
+        self.grounded = dio.grounded;
+        self.preference = dio.preference;
+        if let Some(config) = config {
+            self.config = config;
+        }
+        if self.config.min_hop_rank_increase == 0 {
+            self.config.min_hop_rank_increase = DEFAULT_MIN_HOP_RANK_INCREASE;
+        }
+    }
+
+    // Inserts a new parent, replacing the worst one if the set is full.
+    // Returns false if the new parent is worse than all existing ones.
+    fn insert_parent(&mut self, parent: Parent) -> bool {
+        if let Some(slot) = self.parents.iter_mut().find(|p| p.is_none()) {
+            *slot = Some(parent);
+            return true;
+        }
+        let new_rank = self.path_rank(&parent);
+        let mut worst: Option<(usize, u16)> = None;
+        for (i, p) in self.parents.iter().enumerate() {
+            if let Some(p) = p {
+                let rank = self.path_rank(p);
+                if Some(i) != self.preferred && worst.map_or(true, |(_, r)| rank > r) {
+                    worst = Some((i, rank));
+                }
+            }
+        }
+        match worst {
+            Some((i, rank)) if rank > new_rank => {
+                self.parents[i] = Some(parent);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes a parent, e.g. after a link failure. Returns `true` if this
+    /// changed the preferred parent.
+    pub fn remove_parent(&mut self, addr: &IPAddr) -> bool {
+        match self.find_parent(addr) {
+            Some(i) => {
+                self.parents[i] = None;
+                let was_preferred = self.preferred == Some(i);
+                self.select_parent();
+                was_preferred
+            }
+            None => false,
+        }
+    }
+
+    /// Selects the parent through which our rank is the lowest. The current
+    /// preferred parent is kept on ties. Parents that would raise our rank
+    /// by more than `MaxRankIncrease` above the lowest rank we advertised
+    /// are not considered.
+    fn select_parent(&mut self) {
+        let max_rank = if self.config.max_rank_increase == 0 || self.lowest_rank == INFINITE_RANK {
+            INFINITE_RANK
+        } else {
+            self.lowest_rank
+                .saturating_add(self.config.max_rank_increase)
+        };
+
+        let mut best: Option<(usize, u16)> = None;
+        for (i, p) in self.parents.iter().enumerate() {
+            if let Some(p) = p {
+                let rank = self.path_rank(p);
+                if rank == INFINITE_RANK || rank > max_rank {
+                    continue;
+                }
+                let better = match best {
+                    None => true,
+                    Some((_, best_rank)) => {
+                        rank < best_rank || (rank == best_rank && self.preferred == Some(i))
+                    }
+                };
+                if better {
+                    best = Some((i, rank));
+                }
+            }
+        }
+
+        match best {
+            Some((i, rank)) => {
+                self.preferred = Some(i);
+                self.rank = rank;
+                self.lowest_rank = self.lowest_rank.min(rank);
+            }
+            None => {
+                self.preferred = None;
+                self.rank = INFINITE_RANK;
+            }
+        }
+
+        // Drop parents whose rank is no longer lower than ours
+        let own_rank = self.rank;
+        for (i, slot) in self.parents.iter_mut().enumerate() {
+            if Some(i) != self.preferred && slot.map_or(false, |p| p.rank >= own_rank) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Chooses the next hop for a packet to `dst`. Returns `None` if the
+    /// node has no route to `dst`.
+    pub fn next_hop(&self, dst: &IPAddr) -> Option<IPAddr> {
+        if dst.is_unicast_link_local() || self.find_parent(dst).is_some() {
+            return Some(*dst);
+        }
+        if self.is_root {
+            return if self.mop.is_storing() {
+                self.routes.lookup(dst)
+            } else {
+                let mut path = [IPAddr::new(); MAX_SOURCE_ROUTE_LEN];
+                self.source_route(dst, &mut path).map(|_| path[0])
+            };
+        }
+        if self.mop.is_storing() {
+            if let Some(via) = self.routes.lookup(dst) {
+                return Some(via);
+            }
+        }
+        self.preferred_parent().map(|p| p.addr)
+    }
+
+    /// Computes the path from the root to `dst` in non-storing mode. The
+    /// hops are written to `path` in order, starting with the first hop
+    /// and ending with `dst`. Returns the number of hops, or `None` if no
+    /// complete path is known or it does not fit in `path`.
+    pub fn source_route(&self, dst: &IPAddr, path: &mut [IPAddr]) -> Option<usize> {
+        if !self.is_root || self.mop.is_storing() {
+            return None;
+        }
+        let mut len = 0;
+        let mut hop = *dst;
+        // Walk up the parent pointers until reaching the root. Bounding the
+        // walk by the size of `path` also breaks routing loops.
+        loop {
+            if len == path.len() {
+                return None;
+            }
+            path[len] = hop;
+            len += 1;
+            let parent = self.routes.lookup(&hop)?;
+            if parent == self.dodag_id {
+                break;
+            }
+            hop = parent;
+        }
+        path[..len].reverse();
+        Some(len)
+    }
+
+    /// Processes a DAO received from `from`. Returns the status of the
+    /// DAO-ACK to send if one was requested: 0 if the DAO was accepted,
+    /// and 128 or higher if it was rejected.
+    pub fn process_dao(&mut self, from: IPAddr, dao: &Dao, options: &[u8]) -> u8 {
+        const ACCEPTED: u8 = 0;
+        const REJECTED: u8 = 128;
+        const TABLE_FULL: u8 = 129;
+
+        if !self.is_joined() || dao.instance_id != self.instance_id {
+            return REJECTED;
+        }
+        if dao.dodag_id.map_or(false, |id| id != self.dodag_id) {
+            return REJECTED;
+        }
+        // In non-storing mode only the root maintains routes
+        let storing = self.mop.is_storing();
+        if self.mop == Mop::NoDownward || (!storing && !self.is_root) {
+            return REJECTED;
+        }
+
+        let mut targets = [IPAddr::new(); MAX_DAO_TARGETS];
+        let mut num_targets = 0;
+        // Whether the last option was a Transit Information option; a
+        // target following one starts a new group.
+        let mut after_transit = false;
+        let mut status = ACCEPTED;
+        for option in RplOptions::new(options) {
+            match option {
+                RplOption::Target(target) => {
+                    if after_transit {
+                        num_targets = 0;
+                        after_transit = false;
+                    }
+                    if target.prefix_len == 128 && num_targets < MAX_DAO_TARGETS {
+                        targets[num_targets] = target.prefix;
+                        num_targets += 1;
+                    }
+                }
+                RplOption::Transit(transit) => {
+                    after_transit = true;
+                    let via = if storing {
+                        from
+                    } else {
+                        match transit.parent {
+                            Some(parent) => parent,
+                            None => continue,
+                        }
+                    };
+                    for target in targets[..num_targets].iter() {
+                        if transit.path_lifetime == 0 {
+                            // No-Path DAO
+                            self.routes.remove(target);
+                        } else if self
+                            .routes
+                            .add(*target, via, transit.path_lifetime)
+                            .is_err()
+                        {
+                            status = TABLE_FULL;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        status
+    }
+
+    /// Encodes the body of a DAO advertising `addr`. In storing mode, all
+    /// targets of our routing table are advertised as well. Returns the
+    /// destination of the DAO along with its length.
+    pub fn encode_dao(
+        &self,
+        buf: &mut [u8],
+        addr: IPAddr,
+        sequence: u8,
+        expect_ack: bool,
+    ) -> SResult<IPAddr> {
+        let parent = stream_from_option!(self.preferred_parent());
+        let dao = Dao {
+            instance_id: self.instance_id,
+            expect_ack,
+            sequence,
+            dodag_id: Some(self.dodag_id),
+        };
+        let mut off = enc_consume!(buf; dao; encode);
+        off = enc_consume!(buf, off; Target::new(addr); encode);
+        let storing = self.mop.is_storing();
+        if storing {
+            for route in self.routes.iter() {
+                off = enc_consume!(buf, off; Target::new(route.target); encode);
+            }
+        }
+        let transit = Transit {
+            external: false,
+            path_control: 0,
+            path_sequence: sequence,
+            path_lifetime: self.config.default_lifetime,
+            parent: if storing { None } else { Some(parent.addr) },
+        };
+        off = enc_consume!(buf, off; transit; encode);
+        let dst = if storing { parent.addr } else { self.dodag_id };
+        stream_done!(off, dst);
+    }
+
+    /// Encodes the body of a DAO-ACK for a DAO with sequence `sequence`.
+    pub fn encode_dao_ack(&self, buf: &mut [u8], sequence: u8, status: u8) -> SResult {
+        let ack = DaoAck {
+            instance_id: self.instance_id,
+            sequence,
+            status,
+            dodag_id: Some(self.dodag_id),
+        };
+        ack.encode(buf)
+    }
+
+    /// Encodes the body of a DIO advertising our state, followed by a
+    /// DODAG Configuration option.
+    pub fn encode_dio(&self, buf: &mut [u8]) -> SResult {
+        let mut off = enc_consume!(buf; self.dio(); encode);
+        off = enc_consume!(buf, off; self.config; encode);
+        stream_done!(off);
+    }
+
+    /// Ages all downward routes by `units` lifetime units.
+    pub fn expire_routes(&mut self, units: u8) {
+        self.routes.expire(units);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::rpl::messages::{decode_srh, encode_srh};
+
+    const MAX_NODES: usize = 8;
+
+    fn addr(id: usize) -> IPAddr {
+        let mut addr = IPAddr([0; 16]);
+        addr.0[0] = 0xfd;
+        addr.0[15] = id as u8;
+        addr
+    }
+
+    /// A simulated network of nodes. Node 0 is the root. `links[a][b]`
+    /// holds the OF0 step of rank of the link between `a` and `b`, or 0 if
+    /// they cannot hear each other.
+    struct Network {
+        nodes: [Dodag; MAX_NODES],
+        links: [[u16; MAX_NODES]; MAX_NODES],
+        n: usize,
+    }
+
+    impl Network {
+        fn new(n: usize, mop: Mop) -> Network {
+            let mut nodes = [
+                Dodag::new(),
+                Dodag::new(),
+                Dodag::new(),
+                Dodag::new(),
+                Dodag::new(),
+                Dodag::new(),
+                Dodag::new(),
+                Dodag::new(),
+            ];
+            nodes[0] = Dodag::new_root(1, addr(0), mop, DodagConfig::default());
+            Network {
+                nodes,
+                links: [[0; MAX_NODES]; MAX_NODES],
+                n,
+            }
+        }
+
+        fn link(&mut self, a: usize, b: usize, step: u16) {
+            self.links[a][b] = step;
+            self.links[b][a] = step;
+        }
+
+        /// Delivers a DIO from `from` to all its neighbors.
+        fn broadcast_dio(&mut self, from: usize) -> [DioEvent; MAX_NODES] {
+            let mut events = [DioEvent::Ignored; MAX_NODES];
+            let mut buf = [0; 64];
+            let len = self.nodes[from].encode_dio(&mut buf).done().unwrap().0;
+            let (dio_len, dio) = Dio::decode(&buf[..len]).done().unwrap();
+            let config = RplOptions::new(&buf[dio_len..len]).find_map(|o| match o {
+                RplOption::DodagConfig(config) => Some(config),
+                _ => None,
+            });
+            for to in 0..self.n {
+                let step = self.links[from][to];
+                if to != from && step != 0 {
+                    events[to] = self.nodes[to].process_dio(addr(from), &dio, config, step);
+                }
+            }
+            events
+        }
+
+        /// Lets every joined node send a DIO, `rounds` times.
+        fn converge(&mut self, rounds: usize) {
+            for _ in 0..rounds {
+                for from in 0..self.n {
+                    if self.nodes[from].is_joined() {
+                        self.broadcast_dio(from);
+                    }
+                }
+            }
+        }
+
+        fn parent_of(&self, node: usize) -> Option<IPAddr> {
+            self.nodes[node].preferred_parent().map(|p| p.addr)
+        }
+
+        /// Sends a DAO from `from` and delivers it to its destination,
+        /// assuming the destination is reachable.
+        fn send_dao(&mut self, from: usize, seq: u8) -> u8 {
+            let mut buf = [0; 256];
+            let (len, dst) = self.nodes[from]
+                .encode_dao(&mut buf, addr(from), seq, true)
+                .done()
+                .unwrap();
+            let to = dst.0[15] as usize;
+            let (off, dao) = Dao::decode(&buf[..len]).done().unwrap();
+            assert_eq!(dao.sequence, seq);
+            self.nodes[to].process_dao(addr(from), &dao, &buf[off..len])
+        }
+    }
+
+    #[test]
+    fn line_topology_ranks() {
+        // 0 - 1 - 2 - 3
+        let mut net = Network::new(4, Mop::Storing);
+        net.link(0, 1, 3);
+        net.link(1, 2, 3);
+        net.link(2, 3, 3);
+        net.converge(3);
+
+        assert_eq!(net.nodes[0].rank(), 256);
+        assert_eq!(net.nodes[1].rank(), 256 + 768);
+        assert_eq!(net.nodes[2].rank(), 256 + 2 * 768);
+        assert_eq!(net.nodes[3].rank(), 256 + 3 * 768);
+        assert_eq!(net.parent_of(1), Some(addr(0)));
+        assert_eq!(net.parent_of(2), Some(addr(1)));
+        assert_eq!(net.parent_of(3), Some(addr(2)));
+        // Upward traffic goes to the preferred parent
+        assert_eq!(net.nodes[3].next_hop(&addr(0)), Some(addr(2)));
+    }
+
+    #[test]
+    fn parent_selection_by_rank() {
+        //     0
+        //    / \
+        //   1   2
+        //    \ /
+        //     3
+        // The link 3-1 is worse than 3-2.
+        let mut net = Network::new(4, Mop::Storing);
+        net.link(0, 1, 3);
+        net.link(0, 2, 3);
+        net.link(1, 3, 6);
+        net.link(2, 3, 2);
+        net.converge(2);
+
+        assert_eq!(net.parent_of(3), Some(addr(2)));
+        assert_eq!(net.nodes[3].rank(), 1024 + 512);
+
+        // 2 loses its link to the root and poisons its sub-DODAG
+        net.link(0, 2, 0);
+        assert!(net.nodes[2].remove_parent(&addr(0)));
+        assert_eq!(net.nodes[2].rank(), INFINITE_RANK);
+        let events = net.broadcast_dio(2);
+        assert_eq!(events[3], DioEvent::ParentChanged);
+        assert_eq!(net.parent_of(3), Some(addr(1)));
+        assert_eq!(net.nodes[3].rank(), 1024 + 6 * 256);
+    }
+
+    #[test]
+    fn no_parent_from_sub_dodag() {
+        // 0 - 1 - 2: node 2 must never become a parent of node 1
+        let mut net = Network::new(3, Mop::Storing);
+        net.link(0, 1, 3);
+        net.link(1, 2, 1);
+        net.converge(2);
+        assert_eq!(net.broadcast_dio(2)[1], DioEvent::Consistent);
+        assert_eq!(net.parent_of(1), Some(addr(0)));
+
+        // Once 1 loses the root it detaches, and its poisoning DIO detaches
+        // the child instead of the child becoming its parent
+        net.link(0, 1, 0);
+        net.nodes[1].remove_parent(&addr(0));
+        assert!(!net.nodes[1].is_joined());
+        assert_eq!(net.broadcast_dio(1)[2], DioEvent::Detached);
+        assert!(!net.nodes[2].is_joined());
+        assert_eq!(net.broadcast_dio(2)[1], DioEvent::Ignored);
+        assert!(!net.nodes[1].is_joined());
+    }
+
+    #[test]
+    fn global_repair() {
+        let mut net = Network::new(3, Mop::Storing);
+        net.link(0, 1, 3);
+        net.link(1, 2, 3);
+        net.converge(2);
+        let version = net.nodes[2].version();
+
+        // Node 1 moves next to node 2 only
+        net.link(0, 1, 0);
+        net.link(0, 2, 3);
+        net.nodes[0].increment_version();
+        assert_eq!(net.broadcast_dio(0)[2], DioEvent::ParentChanged);
+        assert_eq!(net.nodes[2].version(), version.wrapping_add(1));
+        assert_eq!(net.parent_of(2), Some(addr(0)));
+        // Node 1 still runs the old version: 2 considers it inconsistent
+        assert_eq!(net.broadcast_dio(1)[2], DioEvent::Inconsistent);
+        net.converge(1);
+        assert_eq!(net.parent_of(1), Some(addr(2)));
+        assert_eq!(net.nodes[1].rank(), 1024 + 768);
+    }
+
+    #[test]
+    fn storing_mode_routes() {
+        //     0
+        //    / \
+        //   1   4
+        //   |
+        //   2
+        //   |
+        //   3
+        let mut net = Network::new(5, Mop::Storing);
+        net.link(0, 1, 3);
+        net.link(1, 2, 3);
+        net.link(2, 3, 3);
+        net.link(0, 4, 3);
+        net.converge(3);
+
+        // DAOs propagate upwards, deepest nodes first
+        for node in [3, 2, 1, 4].iter() {
+            assert_eq!(net.send_dao(*node, 1), 0);
+        }
+
+        assert_eq!(net.nodes[0].next_hop(&addr(3)), Some(addr(1)));
+        assert_eq!(net.nodes[0].next_hop(&addr(4)), Some(addr(4)));
+        assert_eq!(net.nodes[1].next_hop(&addr(3)), Some(addr(2)));
+        assert_eq!(net.nodes[2].next_hop(&addr(3)), Some(addr(3)));
+        // Unknown destinations go up
+        assert_eq!(net.nodes[1].next_hop(&addr(4)), Some(addr(0)));
+        assert_eq!(net.nodes[0].next_hop(&addr(7)), None);
+
+        // Routes expire unless refreshed
+        net.nodes[0].expire_routes(DEFAULT_LIFETIME - 1);
+        assert_eq!(net.nodes[0].next_hop(&addr(3)), Some(addr(1)));
+        net.nodes[0].expire_routes(1);
+        assert_eq!(net.nodes[0].next_hop(&addr(3)), None);
+    }
+
+    #[test]
+    fn non_storing_source_route() {
+        //     0
+        //    / \
+        //   1   4
+        //   |
+        //   2
+        //   |
+        //   3
+        let mut net = Network::new(5, Mop::NonStoring);
+        net.link(0, 1, 3);
+        net.link(1, 2, 3);
+        net.link(2, 3, 3);
+        net.link(0, 4, 3);
+        net.converge(3);
+
+        // Each DAO is addressed to the root
+        for node in 1..5 {
+            assert_eq!(net.send_dao(node, 1), 0);
+        }
+        // Intermediate nodes keep no routes
+        assert_eq!(net.nodes[1].routes().iter().count(), 0);
+        assert_eq!(net.nodes[1].next_hop(&addr(3)), Some(addr(0)));
+
+        let mut path = [IPAddr::new(); MAX_SOURCE_ROUTE_LEN];
+        assert_eq!(net.nodes[0].source_route(&addr(3), &mut path), Some(3));
+        assert_eq!(&path[..3], &[addr(1), addr(2), addr(3)]);
+        assert_eq!(net.nodes[0].next_hop(&addr(3)), Some(addr(1)));
+        assert_eq!(net.nodes[0].source_route(&addr(4), &mut path), Some(1));
+
+        // The root sends to the first hop, the rest goes in the header
+        let mut buf = [0; 64];
+        let (len, _) = encode_srh(&mut buf, 17, &path[0], &[addr(2), addr(3)])
+            .done()
+            .unwrap();
+        let mut addrs = [IPAddr::new(); MAX_SOURCE_ROUTE_LEN];
+        let (_, srh) = decode_srh(&buf[..len], &addr(1), &mut addrs)
+            .done()
+            .unwrap();
+        assert_eq!(srh.segments_left, 2);
+        assert_eq!(&addrs[..2], &[addr(2), addr(3)]);
+
+        // A path that does not fit is rejected
+        let mut short = [IPAddr::new(); 2];
+        assert_eq!(net.nodes[0].source_route(&addr(3), &mut short), None);
+    }
+
+    #[test]
+    fn no_path_dao() {
+        let mut net = Network::new(2, Mop::NonStoring);
+        net.link(0, 1, 3);
+        net.converge(1);
+        net.send_dao(1, 1);
+        assert_eq!(net.nodes[0].next_hop(&addr(1)), Some(addr(1)));
+
+        let mut buf = [0; 64];
+        let dao = Dao {
+            instance_id: 1,
+            expect_ack: false,
+            sequence: 2,
+            dodag_id: None,
+        };
+        let mut off = dao.encode(&mut buf).done().unwrap().0;
+        let start = off;
+        off += Target::new(addr(1))
+            .encode(&mut buf[off..])
+            .done()
+            .unwrap()
+            .0;
+        let transit = Transit {
+            external: false,
+            path_control: 0,
+            path_sequence: 2,
+            path_lifetime: 0,
+            parent: Some(addr(0)),
+        };
+        off += transit.encode(&mut buf[off..]).done().unwrap().0;
+        assert_eq!(net.nodes[0].process_dao(addr(1), &dao, &buf[start..off]), 0);
+        let mut path = [IPAddr::new(); MAX_SOURCE_ROUTE_LEN];
+        assert_eq!(net.nodes[0].source_route(&addr(1), &mut path), None);
+    }
+
+    #[test]
+    fn mac_from_iid() {
+        let short = MacAddress::Short(0x1234);
+        assert_eq!(mac_from_addr(&IPAddr::generate_from_mac(short)), short);
+        let long = MacAddress::Long([1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(mac_from_addr(&IPAddr::generate_from_mac(long)), long);
+    }
+}
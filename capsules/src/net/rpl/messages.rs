@@ -0,0 +1,735 @@
+//! Encoding and decoding of RPL control messages (RFC 6550, Section 6) and
+//! of the RPL Source Routing Header (RFC 6554).
+//!
+//! RPL control messages are ICMPv6 messages of type 155. The ICMPv6 code
+//! selects the message (DIS, DIO, DAO or DAO-ACK); the ICMPv6 header itself
+//! is handled by `ICMP6Header`, so the encoders and decoders in this file
+//! operate on the message body that follows it. Each message body consists
+//! of a fixed base object followed by a series of options:
+//!
+//! ```txt
+//! ---------------------------------------------------------
+//! | Type = 155 | Code | Checksum | Base | Options ...     |
+//! ---------------------------------------------------------
+//!  \__________ ICMP6Header ____/
+//! ```
+//!
+//! Only the options needed to build and maintain a DODAG are interpreted:
+//! Pad1, PadN, DODAG Configuration, RPL Target and Transit Information.
+//! Other options are skipped.
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+/// ICMPv6 codes of the RPL control messages.
+pub mod rpl_code {
+    pub const DIS: u8 = 0x00;
+    pub const DIO: u8 = 0x01;
+    pub const DAO: u8 = 0x02;
+    pub const DAO_ACK: u8 = 0x03;
+}
+
+/// Types of the RPL control message options.
+pub mod rpl_opt {
+    pub const PAD1: u8 = 0x00;
+    pub const PADN: u8 = 0x01;
+    pub const DAG_METRIC_CONTAINER: u8 = 0x02;
+    pub const ROUTE_INFORMATION: u8 = 0x03;
+    pub const DODAG_CONFIGURATION: u8 = 0x04;
+    pub const TARGET: u8 = 0x05;
+    pub const TRANSIT_INFORMATION: u8 = 0x06;
+    pub const SOLICITED_INFORMATION: u8 = 0x07;
+    pub const PREFIX_INFORMATION: u8 = 0x08;
+}
+
+/// The link-local all-RPL-nodes multicast address (ff02::1a).
+pub const ALL_RPL_NODES: IPAddr = IPAddr([
+    0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1a,
+]);
+
+/// Routing type of the RPL Source Routing Header.
+pub const SRH_ROUTING_TYPE: u8 = 3;
+
+// DIO base object flags
+const DIO_GROUNDED: u8 = 0x80;
+const DIO_MOP_SHIFT: u8 = 3;
+const DIO_MOP_MASK: u8 = 0x38;
+const DIO_PRF_MASK: u8 = 0x07;
+
+// DAO and DAO-ACK base object flags
+const DAO_K: u8 = 0x80;
+const DAO_D: u8 = 0x40;
+const DAO_ACK_D: u8 = 0x80;
+
+// Transit Information option flags
+const TRANSIT_E: u8 = 0x80;
+
+/// The Mode of Operation of a DODAG, advertised by the root.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mop {
+    /// Upward routes only, no DAOs are sent.
+    NoDownward = 0,
+    /// Downward routes are maintained by the root only; packets to nodes
+    /// in the DODAG carry a Source Routing Header.
+    NonStoring = 1,
+    /// Every node keeps a routing table for its sub-DODAG.
+    Storing = 2,
+    /// Storing mode with multicast support.
+    StoringMulticast = 3,
+}
+
+impl Mop {
+    pub fn from_u8(mop: u8) -> Option<Mop> {
+        match mop {
+            0 => Some(Mop::NoDownward),
+            1 => Some(Mop::NonStoring),
+            2 => Some(Mop::Storing),
+            3 => Some(Mop::StoringMulticast),
+            _ => None,
+        }
+    }
+
+    pub fn is_storing(&self) -> bool {
+        *self == Mop::Storing || *self == Mop::StoringMulticast
+    }
+}
+
+/// The DODAG Information Object base object.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Dio {
+    pub instance_id: u8,
+    pub version: u8,
+    pub rank: u16,
+    pub grounded: bool,
+    pub mop: Mop,
+    pub preference: u8,
+    pub dtsn: u8,
+    pub dodag_id: IPAddr,
+}
+
+impl Dio {
+    pub const LEN: usize = 24;
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        stream_len_cond!(buf, Dio::LEN);
+        let mut flags = ((self.mop as u8) << DIO_MOP_SHIFT) & DIO_MOP_MASK;
+        flags |= self.preference & DIO_PRF_MASK;
+        if self.grounded {
+            flags |= DIO_GROUNDED;
+        }
+        let mut off = enc_consume!(buf; encode_u8, self.instance_id);
+        off = enc_consume!(buf, off; encode_u8, self.version);
+        off = enc_consume!(buf, off; encode_u16, self.rank);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, self.dtsn);
+        // Flags and reserved
+        off = enc_consume!(buf, off; encode_u16, 0);
+        off = enc_consume!(buf, off; encode_bytes, &self.dodag_id.0);
+        stream_done!(off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<Dio> {
+        stream_len_cond!(buf, Dio::LEN);
+        let (off, instance_id) = dec_try!(buf; decode_u8);
+        let (off, version) = dec_try!(buf, off; decode_u8);
+        let (off, rank) = dec_try!(buf, off; decode_u16);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, dtsn) = dec_try!(buf, off; decode_u8);
+        let off = off + 2;
+        let mut dodag_id = IPAddr::new();
+        let off = dec_consume!(buf, off; decode_bytes, &mut dodag_id.0);
+        let mop = stream_from_option!(Mop::from_u8((flags & DIO_MOP_MASK) >> DIO_MOP_SHIFT));
+        stream_done!(
+            off,
+            Dio {
+                instance_id,
+                version,
+                rank,
+                grounded: flags & DIO_GROUNDED != 0,
+                mop,
+                preference: flags & DIO_PRF_MASK,
+                dtsn,
+                dodag_id,
+            }
+        );
+    }
+}
+
+/// Encodes the DODAG Information Solicitation base object, which only
+/// consists of reserved fields.
+pub fn encode_dis(buf: &mut [u8]) -> SResult {
+    let off = enc_consume!(buf; encode_u16, 0);
+    stream_done!(off);
+}
+
+/// The Destination Advertisement Object base object.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Dao {
+    pub instance_id: u8,
+    /// Whether the recipient should reply with a DAO-ACK.
+    pub expect_ack: bool,
+    pub sequence: u8,
+    /// The DODAG ID, which must be present when a local instance is used.
+    pub dodag_id: Option<IPAddr>,
+}
+
+impl Dao {
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let mut flags = 0;
+        if self.expect_ack {
+            flags |= DAO_K;
+        }
+        if self.dodag_id.is_some() {
+            flags |= DAO_D;
+        }
+        let mut off = enc_consume!(buf; encode_u8, self.instance_id);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.sequence);
+        if let Some(dodag_id) = self.dodag_id {
+            off = enc_consume!(buf, off; encode_bytes, &dodag_id.0);
+        }
+        stream_done!(off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<Dao> {
+        let (off, instance_id) = dec_try!(buf; decode_u8);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, _reserved) = dec_try!(buf, off; decode_u8);
+        let (off, sequence) = dec_try!(buf, off; decode_u8);
+        let (off, dodag_id) = if flags & DAO_D != 0 {
+            let mut dodag_id = IPAddr::new();
+            let off = dec_consume!(buf, off; decode_bytes, &mut dodag_id.0);
+            (off, Some(dodag_id))
+        } else {
+            (off, None)
+        };
+        stream_done!(
+            off,
+            Dao {
+                instance_id,
+                expect_ack: flags & DAO_K != 0,
+                sequence,
+                dodag_id,
+            }
+        );
+    }
+}
+
+/// The Destination Advertisement Object Acknowledgement base object.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DaoAck {
+    pub instance_id: u8,
+    pub sequence: u8,
+    /// 0 means the DAO was accepted, values of 128 and above indicate a
+    /// rejection.
+    pub status: u8,
+    pub dodag_id: Option<IPAddr>,
+}
+
+impl DaoAck {
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let flags = if self.dodag_id.is_some() {
+            DAO_ACK_D
+        } else {
+            0
+        };
+        let mut off = enc_consume!(buf; encode_u8, self.instance_id);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, self.sequence);
+        off = enc_consume!(buf, off; encode_u8, self.status);
+        if let Some(dodag_id) = self.dodag_id {
+            off = enc_consume!(buf, off; encode_bytes, &dodag_id.0);
+        }
+        stream_done!(off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<DaoAck> {
+        let (off, instance_id) = dec_try!(buf; decode_u8);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, sequence) = dec_try!(buf, off; decode_u8);
+        let (off, status) = dec_try!(buf, off; decode_u8);
+        let (off, dodag_id) = if flags & DAO_ACK_D != 0 {
+            let mut dodag_id = IPAddr::new();
+            let off = dec_consume!(buf, off; decode_bytes, &mut dodag_id.0);
+            (off, Some(dodag_id))
+        } else {
+            (off, None)
+        };
+        stream_done!(
+            off,
+            DaoAck {
+                instance_id,
+                sequence,
+                status,
+                dodag_id,
+            }
+        );
+    }
+}
+
+/// The DODAG Configuration option, sent by the root in DIOs.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DodagConfig {
+    pub dio_int_doublings: u8,
+    pub dio_int_min: u8,
+    pub dio_redundancy: u8,
+    pub max_rank_increase: u16,
+    pub min_hop_rank_increase: u16,
+    pub ocp: u16,
+    pub default_lifetime: u8,
+    pub lifetime_unit: u16,
+}
+
+impl DodagConfig {
+    const LEN: u8 = 14;
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let mut off = enc_consume!(buf; encode_u8, rpl_opt::DODAG_CONFIGURATION);
+        off = enc_consume!(buf, off; encode_u8, DodagConfig::LEN);
+        // Flags, A and PCS: no authentication, PCS of 0
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.dio_int_doublings);
+        off = enc_consume!(buf, off; encode_u8, self.dio_int_min);
+        off = enc_consume!(buf, off; encode_u8, self.dio_redundancy);
+        off = enc_consume!(buf, off; encode_u16, self.max_rank_increase);
+        off = enc_consume!(buf, off; encode_u16, self.min_hop_rank_increase);
+        off = enc_consume!(buf, off; encode_u16, self.ocp);
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.default_lifetime);
+        off = enc_consume!(buf, off; encode_u16, self.lifetime_unit);
+        stream_done!(off);
+    }
+
+    // Decodes the option value, i.e. without type and length.
+    fn decode_value(buf: &[u8]) -> SResult<DodagConfig> {
+        stream_len_cond!(buf, DodagConfig::LEN as usize);
+        let (off, dio_int_doublings) = dec_try!(buf, 1; decode_u8);
+        let (off, dio_int_min) = dec_try!(buf, off; decode_u8);
+        let (off, dio_redundancy) = dec_try!(buf, off; decode_u8);
+        let (off, max_rank_increase) = dec_try!(buf, off; decode_u16);
+        let (off, min_hop_rank_increase) = dec_try!(buf, off; decode_u16);
+        let (off, ocp) = dec_try!(buf, off; decode_u16);
+        let (off, default_lifetime) = dec_try!(buf, off + 1; decode_u8);
+        let (off, lifetime_unit) = dec_try!(buf, off; decode_u16);
+        stream_done!(
+            off,
+            DodagConfig {
+                dio_int_doublings,
+                dio_int_min,
+                dio_redundancy,
+                max_rank_increase,
+                min_hop_rank_increase,
+                ocp,
+                default_lifetime,
+                lifetime_unit,
+            }
+        );
+    }
+}
+
+/// The RPL Target option, carrying an address (or prefix) reachable
+/// through the sender of a DAO.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Target {
+    pub prefix_len: u8,
+    pub prefix: IPAddr,
+}
+
+impl Target {
+    pub fn new(addr: IPAddr) -> Target {
+        Target {
+            prefix_len: 128,
+            prefix: addr,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let prefix_bytes = ((self.prefix_len as usize) + 7) / 8;
+        stream_cond!(prefix_bytes <= 16);
+        let mut off = enc_consume!(buf; encode_u8, rpl_opt::TARGET);
+        off = enc_consume!(buf, off; encode_u8, (2 + prefix_bytes) as u8);
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.prefix_len);
+        off = enc_consume!(buf, off; encode_bytes, &self.prefix.0[..prefix_bytes]);
+        stream_done!(off);
+    }
+
+    fn decode_value(buf: &[u8]) -> SResult<Target> {
+        let (off, prefix_len) = dec_try!(buf, 1; decode_u8);
+        let prefix_bytes = ((prefix_len as usize) + 7) / 8;
+        stream_cond!(prefix_bytes <= 16);
+        let mut prefix = IPAddr::new();
+        let off = dec_consume!(buf, off; decode_bytes, &mut prefix.0[..prefix_bytes]);
+        stream_done!(off, Target { prefix_len, prefix });
+    }
+}
+
+/// The Transit Information option, which follows the targets it applies
+/// to in a DAO. In non-storing mode it carries the address of the
+/// sender's parent.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Transit {
+    pub external: bool,
+    pub path_control: u8,
+    pub path_sequence: u8,
+    pub path_lifetime: u8,
+    pub parent: Option<IPAddr>,
+}
+
+impl Transit {
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let len = if self.parent.is_some() { 20 } else { 4 };
+        let flags = if self.external { TRANSIT_E } else { 0 };
+        let mut off = enc_consume!(buf; encode_u8, rpl_opt::TRANSIT_INFORMATION);
+        off = enc_consume!(buf, off; encode_u8, len);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, self.path_control);
+        off = enc_consume!(buf, off; encode_u8, self.path_sequence);
+        off = enc_consume!(buf, off; encode_u8, self.path_lifetime);
+        if let Some(parent) = self.parent {
+            off = enc_consume!(buf, off; encode_bytes, &parent.0);
+        }
+        stream_done!(off);
+    }
+
+    fn decode_value(buf: &[u8]) -> SResult<Transit> {
+        let (off, flags) = dec_try!(buf; decode_u8);
+        let (off, path_control) = dec_try!(buf, off; decode_u8);
+        let (off, path_sequence) = dec_try!(buf, off; decode_u8);
+        let (off, path_lifetime) = dec_try!(buf, off; decode_u8);
+        let (off, parent) = if buf.len() >= off + 16 {
+            let mut parent = IPAddr::new();
+            let off = dec_consume!(buf, off; decode_bytes, &mut parent.0);
+            (off, Some(parent))
+        } else {
+            (off, None)
+        };
+        stream_done!(
+            off,
+            Transit {
+                external: flags & TRANSIT_E != 0,
+                path_control,
+                path_sequence,
+                path_lifetime,
+                parent,
+            }
+        );
+    }
+}
+
+/// An option of an RPL control message.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RplOption {
+    Pad,
+    DodagConfig(DodagConfig),
+    Target(Target),
+    Transit(Transit),
+    /// An option that is not interpreted, identified by its type.
+    Other(u8),
+}
+
+impl RplOption {
+    /// Decodes the option at the beginning of `buf`. The returned offset is
+    /// the start of the next option.
+    pub fn decode(buf: &[u8]) -> SResult<RplOption> {
+        let (off, opt_type) = dec_try!(buf; decode_u8);
+        if opt_type == rpl_opt::PAD1 {
+            stream_done!(off, RplOption::Pad);
+        }
+        let (off, len) = dec_try!(buf, off; decode_u8);
+        let end = off + len as usize;
+        stream_len_cond!(buf, end);
+        let value = &buf[off..end];
+        let option = match opt_type {
+            rpl_opt::PADN => RplOption::Pad,
+            rpl_opt::DODAG_CONFIGURATION => {
+                let (_, config) = dec_try!(DodagConfig::decode_value(value));
+                RplOption::DodagConfig(config)
+            }
+            rpl_opt::TARGET => {
+                let (_, target) = dec_try!(Target::decode_value(value));
+                RplOption::Target(target)
+            }
+            rpl_opt::TRANSIT_INFORMATION => {
+                let (_, transit) = dec_try!(Transit::decode_value(value));
+                RplOption::Transit(transit)
+            }
+            _ => RplOption::Other(opt_type),
+        };
+        stream_done!(end, option);
+    }
+}
+
+/// Iterates over the options of an RPL control message. Iteration stops
+/// at the first malformed option.
+pub struct RplOptions<'b> {
+    buf: &'b [u8],
+}
+
+impl<'b> RplOptions<'b> {
+    pub fn new(buf: &'b [u8]) -> RplOptions<'b> {
+        RplOptions { buf }
+    }
+}
+
+impl<'b> Iterator for RplOptions<'b> {
+    type Item = RplOption;
+
+    fn next(&mut self) -> Option<RplOption> {
+        match RplOption::decode(self.buf).done() {
+            Some((off, option)) => {
+                self.buf = &self.buf[off..];
+                Some(option)
+            }
+            None => {
+                self.buf = &[];
+                None
+            }
+        }
+    }
+}
+
+/// A decoded RPL Source Routing Header.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SourceRoute {
+    pub next_header: u8,
+    pub segments_left: u8,
+    /// Number of addresses in the header.
+    pub num_addrs: usize,
+    /// Total length of the header in bytes.
+    pub len: usize,
+}
+
+// Length of the common part of two addresses, in bytes, at most 15.
+fn common_prefix_len(a: &IPAddr, b: &IPAddr) -> usize {
+    a.0.iter()
+        .zip(b.0.iter())
+        .take(15)
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+/// Encodes an RPL Source Routing Header (RFC 6554).
+///
+/// `ip_dst` is the destination of the IPv6 header, i.e. the first hop of
+/// the route. `route` contains the remaining hops in order, ending with
+/// the final destination of the packet. Address prefixes shared with
+/// `ip_dst` are elided.
+pub fn encode_srh(buf: &mut [u8], next_header: u8, ip_dst: &IPAddr, route: &[IPAddr]) -> SResult {
+    stream_cond!(!route.is_empty() && route.len() < 256);
+    let n = route.len();
+    let last = &route[n - 1];
+    let cmpr_i = route[..n - 1]
+        .iter()
+        .map(|addr| common_prefix_len(addr, ip_dst))
+        .min()
+        .unwrap_or(0);
+    let cmpr_e = common_prefix_len(last, ip_dst);
+    let addr_bytes = (n - 1) * (16 - cmpr_i) + (16 - cmpr_e);
+    let pad = (8 - (addr_bytes % 8)) % 8;
+    let len = 8 + addr_bytes + pad;
+    stream_len_cond!(buf, len);
+
+    let mut off = enc_consume!(buf; encode_u8, next_header);
+    off = enc_consume!(buf, off; encode_u8, ((len - 8) / 8) as u8);
+    off = enc_consume!(buf, off; encode_u8, SRH_ROUTING_TYPE);
+    off = enc_consume!(buf, off; encode_u8, n as u8);
+    off = enc_consume!(buf, off; encode_u8, ((cmpr_i as u8) << 4) | cmpr_e as u8);
+    off = enc_consume!(buf, off; encode_u8, (pad as u8) << 4);
+    off = enc_consume!(buf, off; encode_u16, 0);
+    for addr in route[..n - 1].iter() {
+        off = enc_consume!(buf, off; encode_bytes, &addr.0[cmpr_i..]);
+    }
+    off = enc_consume!(buf, off; encode_bytes, &last.0[cmpr_e..]);
+    for _ in 0..pad {
+        off = enc_consume!(buf, off; encode_u8, 0);
+    }
+    stream_done!(off);
+}
+
+/// Decodes an RPL Source Routing Header. The addresses in the header are
+/// written to `addrs`, with the elided prefixes restored from `ip_dst`,
+/// which must be the destination of the IPv6 header carrying it.
+pub fn decode_srh(buf: &[u8], ip_dst: &IPAddr, addrs: &mut [IPAddr]) -> SResult<SourceRoute> {
+    let (off, next_header) = dec_try!(buf; decode_u8);
+    let (off, ext_len) = dec_try!(buf, off; decode_u8);
+    let (off, routing_type) = dec_try!(buf, off; decode_u8);
+    let (off, segments_left) = dec_try!(buf, off; decode_u8);
+    let (off, cmpr) = dec_try!(buf, off; decode_u8);
+    let (off, pad) = dec_try!(buf, off; decode_u8);
+    let off = off + 2;
+    stream_cond!(routing_type == SRH_ROUTING_TYPE);
+
+    let len = 8 + (ext_len as usize) * 8;
+    stream_len_cond!(buf, len);
+    let cmpr_i = (cmpr >> 4) as usize;
+    let cmpr_e = (cmpr & 0x0f) as usize;
+    let pad = (pad >> 4) as usize;
+    // The last address is (16 - cmpr_e) bytes long, all others are
+    // (16 - cmpr_i) bytes long.
+    let addr_bytes = len - 8 - pad;
+    stream_cond!(addr_bytes >= 16 - cmpr_e);
+    stream_cond!((addr_bytes - (16 - cmpr_e)) % (16 - cmpr_i) == 0);
+    let num_addrs = (addr_bytes - (16 - cmpr_e)) / (16 - cmpr_i) + 1;
+    stream_cond!(num_addrs <= addrs.len());
+    stream_cond!(segments_left as usize <= num_addrs);
+
+    let mut off = off;
+    for (i, addr) in addrs[..num_addrs].iter_mut().enumerate() {
+        let elided = if i == num_addrs - 1 { cmpr_e } else { cmpr_i };
+        addr.0[..elided].copy_from_slice(&ip_dst.0[..elided]);
+        off = dec_consume!(buf, off; decode_bytes, &mut addr.0[elided..]);
+    }
+    stream_done!(
+        len,
+        SourceRoute {
+            next_header,
+            segments_left,
+            num_addrs,
+            len,
+        }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> IPAddr {
+        let mut addr = IPAddr([0; 16]);
+        addr.0[0] = 0xfd;
+        addr.0[15] = last;
+        addr
+    }
+
+    #[test]
+    fn dio_roundtrip() {
+        let dio = Dio {
+            instance_id: 1,
+            version: 240,
+            rank: 512,
+            grounded: true,
+            mop: Mop::NonStoring,
+            preference: 3,
+            dtsn: 7,
+            dodag_id: addr(1),
+        };
+        let mut buf = [0; 32];
+        let (len, _) = dio.encode(&mut buf).done().unwrap();
+        assert_eq!(len, Dio::LEN);
+        assert_eq!(&buf[..6], &[1, 240, 0x02, 0x00, 0x8b, 7]);
+        assert_eq!(Dio::decode(&buf).done().unwrap(), (Dio::LEN, dio));
+    }
+
+    #[test]
+    fn dao_with_options() {
+        let dao = Dao {
+            instance_id: 1,
+            expect_ack: true,
+            sequence: 9,
+            dodag_id: Some(addr(1)),
+        };
+        let transit = Transit {
+            external: false,
+            path_control: 0,
+            path_sequence: 3,
+            path_lifetime: 30,
+            parent: Some(addr(2)),
+        };
+        let mut buf = [0; 128];
+        let mut off = dao.encode(&mut buf).done().unwrap().0;
+        assert_eq!(off, 20);
+        off += Target::new(addr(5))
+            .encode(&mut buf[off..])
+            .done()
+            .unwrap()
+            .0;
+        // A Pad1 option between the two
+        off += 1;
+        off += transit.encode(&mut buf[off..]).done().unwrap().0;
+
+        let (dao_len, decoded) = Dao::decode(&buf[..off]).done().unwrap();
+        assert_eq!(decoded, dao);
+        let mut options = RplOptions::new(&buf[dao_len..off]);
+        assert_eq!(
+            options.next(),
+            Some(RplOption::Target(Target::new(addr(5))))
+        );
+        assert_eq!(options.next(), Some(RplOption::Pad));
+        assert_eq!(options.next(), Some(RplOption::Transit(transit)));
+        assert_eq!(options.next(), None);
+    }
+
+    #[test]
+    fn dodag_config_roundtrip() {
+        let config = DodagConfig {
+            dio_int_doublings: 8,
+            dio_int_min: 12,
+            dio_redundancy: 10,
+            max_rank_increase: 1792,
+            min_hop_rank_increase: 256,
+            ocp: 0,
+            default_lifetime: 30,
+            lifetime_unit: 60,
+        };
+        let mut buf = [0; 16];
+        assert_eq!(config.encode(&mut buf).done().unwrap().0, 16);
+        let mut options = RplOptions::new(&buf);
+        assert_eq!(options.next(), Some(RplOption::DodagConfig(config)));
+        assert_eq!(options.next(), None);
+    }
+
+    #[test]
+    fn truncated_option() {
+        // PadN claiming more bytes than available
+        let buf = [rpl_opt::PADN, 4, 0, 0];
+        assert!(RplOption::decode(&buf).is_needed());
+    }
+
+    #[test]
+    fn srh_roundtrip_compressed() {
+        // All addresses share 15 bytes with the first hop
+        let first = addr(2);
+        let route = [addr(3), addr(4), addr(5)];
+        let mut buf = [0; 64];
+        let (len, _) = encode_srh(&mut buf, 17, &first, &route).done().unwrap();
+        // 8 byte header + 3 one-byte addresses + 5 bytes of padding
+        assert_eq!(len, 16);
+        assert_eq!(buf[1], 1);
+        assert_eq!(buf[3], 3);
+        assert_eq!(buf[4], 0xff);
+        assert_eq!(buf[5], 5 << 4);
+
+        let mut addrs = [IPAddr::new(); 4];
+        let (off, srh) = decode_srh(&buf, &first, &mut addrs).done().unwrap();
+        assert_eq!(off, len);
+        assert_eq!(srh.next_header, 17);
+        assert_eq!(srh.segments_left, 3);
+        assert_eq!(srh.num_addrs, 3);
+        assert_eq!(&addrs[..3], &route);
+    }
+
+    #[test]
+    fn srh_roundtrip_uncompressed_last() {
+        let first = addr(2);
+        let mut last = addr(9);
+        last.0[0] = 0x20;
+        let route = [addr(3), last];
+        let mut buf = [0; 64];
+        let (len, _) = encode_srh(&mut buf, 58, &first, &route).done().unwrap();
+        assert_eq!(len, 8 + 1 + 16 + 7);
+        assert_eq!(buf[4], 0xf0);
+
+        let mut addrs = [IPAddr::new(); 2];
+        let (_, srh) = decode_srh(&buf, &first, &mut addrs).done().unwrap();
+        assert_eq!(srh.num_addrs, 2);
+        assert_eq!(addrs, route);
+
+        // Not enough room for the addresses
+        let mut small = [IPAddr::new(); 1];
+        assert!(decode_srh(&buf, &first, &mut small).is_err());
+    }
+}
@@ -0,0 +1,9 @@
+pub mod dodag;
+pub mod messages;
+pub mod trickle;
+
+// Reexport the exports of the [`rpl`] module, to avoid redundant
+// module paths (e.g. `capsules::net::rpl::rpl::Rpl`)
+mod rpl;
+pub use rpl::Rpl;
+pub use rpl::RPL_BUF_SIZE;
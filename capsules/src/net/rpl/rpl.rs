@@ -0,0 +1,631 @@
+//! Implements the RPL routing protocol for 6LoWPAN networks (RFC 6550).
+//!
+//! RPL organizes the nodes of a mesh into a DODAG (Destination-Oriented
+//! Directed Acyclic Graph) rooted at a border router:
+//!
+//! - The root, and every node that joined the DODAG, periodically multicasts
+//!   DODAG Information Objects (DIOs) paced by a Trickle timer. A DIO
+//!   advertises the sender's rank, i.e. its distance from the root.
+//! - Nodes that have not joined a DODAG yet solicit DIOs with DODAG
+//!   Information Solicitations (DISs).
+//! - From the DIOs it hears, a node selects the neighbor through which its
+//!   own rank is the lowest as its preferred parent (see `dodag.rs`).
+//!   Upward traffic is sent to the preferred parent.
+//! - Nodes advertise the addresses they can be reached at with Destination
+//!   Advertisement Objects (DAOs). In storing mode, DAOs are sent to the
+//!   preferred parent, and every node keeps routes to its sub-DODAG. In
+//!   non-storing mode, DAOs are sent to the root, which uses the parent
+//!   information they contain to compute source routes.
+//!
+//! `Rpl` implements `IP6Router`; installing it on an `IP6SendStruct` with
+//! `set_router` makes the IPv6 layer send unicast packets to the next hop
+//! chosen by RPL instead of the configured gateway. The link-layer address
+//! of a next hop is derived from the interface identifier of its IPv6
+//! address, so every node must use addresses generated from its MAC
+//! address.
+//!
+//! Limitations
+//! -----------
+//!
+//! The IPv6 layer has no support for forwarding packets and for extension
+//! headers yet. Hence, this capsule only chooses next hops for packets
+//! originating at this node, and DAOs in non-storing mode only reach a
+//! root within radio range. The root can compute the Source Routing
+//! Header for a destination with `source_route` and `encode_srh` in
+//! `messages.rs`, but it is not inserted into outgoing packets. Only a
+//! single DODAG is joined, and DIOs are sent from the address configured on
+//! the `IP6Sender` that `Rpl` uses.
+//!
+//! Usage
+//! -----
+//!
+//! `Rpl` needs its own `IP6Sender`, and receives ICMPv6 packets from the
+//! `IP6RecvStruct`:
+//!
+//! ```rust
+//! let rpl = static_init!(
+//!     capsules::net::rpl::Rpl<'static, VirtualMuxAlarm<'static, Ast>>,
+//!     capsules::net::rpl::Rpl::new(
+//!         rpl_ip_send,
+//!         rpl_alarm,
+//!         rng,
+//!         local_ip_addr,
+//!         &mut RPL_TX_BUF,
+//!         net_cap,
+//!     )
+//! );
+//! rpl_ip_send.set_client(rpl);
+//! rpl_alarm.set_alarm_client(rpl);
+//! ip_receive.set_icmp_client(rpl);
+//! udp_ip_send.set_router(rpl);
+//! rpl_ip_send.set_router(rpl);
+//! rpl.start();
+//! ```
+
+use crate::net::icmpv6::{ICMP6Header, ICMP6Type};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::{IP6Router, IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, TransportHeader};
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::rpl::dodag::{mac_from_addr, DioEvent, Dodag};
+use crate::net::rpl::dodag::{DEFAULT_STEP_OF_RANK, INFINITE_RANK};
+use crate::net::rpl::messages::{encode_dis, rpl_code, Dao, DaoAck, Dio, DodagConfig, Mop};
+use crate::net::rpl::messages::{RplOption, RplOptions, ALL_RPL_NODES};
+use crate::net::rpl::trickle::Trickle;
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::rng::Random;
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+use kernel::ErrorCode;
+
+/// Size of the transmit buffer passed to `Rpl::new`. It must hold a DAO
+/// with a target for every route of a storing mode node.
+pub const RPL_BUF_SIZE: usize = 512;
+
+// Interval between DISs while not part of a DODAG.
+const DIS_INTERVAL_MS: u32 = 10_000;
+// Delay before sending a DAO after a parent change, to let the DODAG
+// settle.
+const DAO_DELAY_MS: u32 = 1000;
+const DAO_ACK_TIMEOUT_MS: u32 = 2000;
+const MAX_DAO_TRANSMISSIONS: u8 = 3;
+
+// DAO-ACK status reported when a DAO is rejected because we are not part
+// of a DODAG that accepts it.
+const DAO_ACK_REJECTED: u8 = 128;
+
+#[derive(Copy, Clone)]
+enum Timer {
+    Trickle = 0,
+    Dis = 1,
+    Dao = 2,
+    Lifetime = 3,
+}
+
+const NUM_TIMERS: usize = 4;
+
+pub struct Rpl<'a, A: Alarm<'a>> {
+    ip_sender: &'a dyn IP6Sender<'a>,
+    alarm: &'a A,
+    rng: &'a dyn Random<'a>,
+    addr: Cell<IPAddr>,
+    net_cap: &'static NetworkCapability,
+    running: Cell<bool>,
+
+    dodag: MapCell<Dodag>,
+    trickle: MapCell<Trickle>,
+
+    // Remaining time of each timer, in ms, relative to `armed_at`. All
+    // timers share a single alarm.
+    timers: Cell<[Option<u32>; NUM_TIMERS]>,
+    armed_at: Cell<A::Ticks>,
+
+    tx_buf: TakeCell<'static, [u8]>,
+    sending: Cell<bool>,
+    pending_dio: Cell<bool>,
+    pending_dis: Cell<bool>,
+    pending_dao: Cell<bool>,
+    pending_dao_ack: Cell<Option<(IPAddr, u8, u8)>>,
+
+    dao_sequence: Cell<u8>,
+    dao_transmissions: Cell<u8>,
+    dao_acked: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>> Rpl<'a, A> {
+    pub fn new(
+        ip_sender: &'a dyn IP6Sender<'a>,
+        alarm: &'a A,
+        rng: &'a dyn Random<'a>,
+        addr: IPAddr,
+        tx_buf: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> Rpl<'a, A> {
+        Rpl {
+            ip_sender: ip_sender,
+            alarm: alarm,
+            rng: rng,
+            addr: Cell::new(addr),
+            net_cap: net_cap,
+            running: Cell::new(false),
+            dodag: MapCell::new(Dodag::new()),
+            trickle: MapCell::empty(),
+            timers: Cell::new([None; NUM_TIMERS]),
+            armed_at: Cell::new(A::Ticks::from(0)),
+            tx_buf: TakeCell::new(tx_buf),
+            sending: Cell::new(false),
+            pending_dio: Cell::new(false),
+            pending_dis: Cell::new(false),
+            pending_dao: Cell::new(false),
+            pending_dao_ack: Cell::new(None),
+            dao_sequence: Cell::new(0),
+            dao_transmissions: Cell::new(0),
+            dao_acked: Cell::new(false),
+        }
+    }
+
+    /// Sets the address advertised as DAO target. It must be the address
+    /// other nodes use to reach this node.
+    pub fn set_address(&self, addr: IPAddr) {
+        self.addr.set(addr);
+    }
+
+    /// Starts looking for a DODAG to join.
+    ///
+    /// Returns `ALREADY` if RPL is already running.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if self.running.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.running.set(true);
+        self.dodag.map(|dodag| dodag.leave());
+        self.detached();
+        Ok(())
+    }
+
+    /// Starts a new DODAG with this node as its root. The DODAG is
+    /// identified by this node's address.
+    ///
+    /// Returns `ALREADY` if RPL is already running.
+    pub fn start_root(
+        &self,
+        instance_id: u8,
+        mop: Mop,
+        config: DodagConfig,
+    ) -> Result<(), ErrorCode> {
+        if self.running.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.running.set(true);
+        let root = Dodag::new_root(instance_id, self.addr.get(), mop, config);
+        self.dodag.map(|dodag| *dodag = root);
+        self.start_trickle();
+        self.set_timer(Timer::Lifetime, self.lifetime_unit_ms());
+        Ok(())
+    }
+
+    /// Stops RPL and leaves the DODAG. Routes are no longer provided to
+    /// the IPv6 layer.
+    pub fn stop(&self) {
+        self.running.set(false);
+        self.dodag.map(|dodag| dodag.leave());
+        self.trickle.take();
+        self.timers.set([None; NUM_TIMERS]);
+        let _ = self.alarm.disarm();
+        self.pending_dio.set(false);
+        self.pending_dis.set(false);
+        self.pending_dao.set(false);
+        self.pending_dao_ack.set(None);
+    }
+
+    /// Increments the DODAG version, which makes all nodes reselect their
+    /// parents. Only valid at the root.
+    ///
+    /// Returns `INVAL` if this node is not the root.
+    pub fn global_repair(&self) -> Result<(), ErrorCode> {
+        let is_root = self.dodag.map_or(false, |dodag| dodag.is_root());
+        if !is_root {
+            return Err(ErrorCode::INVAL);
+        }
+        self.dodag.map(|dodag| {
+            dodag.increment_version();
+            dodag.increment_dtsn();
+        });
+        self.reset_trickle();
+        Ok(())
+    }
+
+    pub fn is_joined(&self) -> bool {
+        self.dodag.map_or(false, |dodag| dodag.is_joined())
+    }
+
+    pub fn get_rank(&self) -> u16 {
+        self.dodag.map_or(INFINITE_RANK, |dodag| dodag.rank())
+    }
+
+    pub fn preferred_parent(&self) -> Option<IPAddr> {
+        self.dodag
+            .and_then(|dodag| dodag.preferred_parent().map(|p| p.addr))
+    }
+
+    /// Computes the source route to `dst` at the root of a non-storing
+    /// DODAG. See `Dodag::source_route`.
+    pub fn source_route(&self, dst: &IPAddr, path: &mut [IPAddr]) -> Option<usize> {
+        self.dodag.and_then(|dodag| dodag.source_route(dst, path))
+    }
+
+    fn lifetime_unit_ms(&self) -> u32 {
+        let unit = self.dodag.map_or(0, |dodag| dodag.config().lifetime_unit);
+        (unit as u32).max(1).saturating_mul(1000)
+    }
+
+    fn dao_refresh_ms(&self) -> u32 {
+        let lifetime = self
+            .dodag
+            .map_or(0, |dodag| dodag.config().default_lifetime);
+        (lifetime as u32)
+            .saturating_mul(self.lifetime_unit_ms())
+            .max(2)
+            / 2
+    }
+
+    // Timers
+
+    fn elapsed_ms(&self) -> u32 {
+        let elapsed = self.alarm.now().wrapping_sub(self.armed_at.get());
+        ((elapsed.into_u32() as u64) * 1000 / (A::Frequency::frequency() as u64)) as u32
+    }
+
+    // Charges the time elapsed since the alarm was last armed to all
+    // timers.
+    fn advance_timers(&self) {
+        let elapsed = self.elapsed_ms();
+        let mut timers = self.timers.get();
+        for timer in timers.iter_mut() {
+            *timer = timer.map(|remaining| remaining.saturating_sub(elapsed));
+        }
+        self.timers.set(timers);
+        self.armed_at.set(self.alarm.now());
+    }
+
+    fn rearm(&self) {
+        match self.timers.get().iter().filter_map(|t| *t).min() {
+            Some(ms) => self
+                .alarm
+                .set_alarm(self.armed_at.get(), A::ticks_from_ms(ms)),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+
+    fn set_timer(&self, timer: Timer, ms: u32) {
+        self.advance_timers();
+        let mut timers = self.timers.get();
+        timers[timer as usize] = Some(ms);
+        self.timers.set(timers);
+        self.rearm();
+    }
+
+    fn cancel_timer(&self, timer: Timer) {
+        let mut timers = self.timers.get();
+        timers[timer as usize] = None;
+        self.timers.set(timers);
+    }
+
+    // Trickle
+
+    fn start_trickle(&self) {
+        let config = self
+            .dodag
+            .map_or(DodagConfig::default(), |dodag| dodag.config());
+        let imin_ms = 1u32
+            .checked_shl(config.dio_int_min as u32)
+            .unwrap_or(u32::MAX);
+        let mut trickle = Trickle::new(imin_ms, config.dio_int_doublings, config.dio_redundancy);
+        let delay = trickle.start(self.rng.random());
+        self.trickle.replace(trickle);
+        self.set_timer(Timer::Trickle, delay);
+    }
+
+    fn reset_trickle(&self) {
+        let rand = self.rng.random();
+        match self.trickle.map(|trickle| trickle.inconsistent(rand)) {
+            Some(Some(delay)) => self.set_timer(Timer::Trickle, delay),
+            Some(None) => {}
+            None => self.start_trickle(),
+        }
+    }
+
+    // Called when the node is not part of a DODAG: stop advertising and
+    // solicit DIOs.
+    fn detached(&self) {
+        self.trickle.take();
+        self.cancel_timer(Timer::Trickle);
+        self.cancel_timer(Timer::Dao);
+        self.cancel_timer(Timer::Lifetime);
+        self.pending_dis.set(true);
+        self.set_timer(Timer::Dis, DIS_INTERVAL_MS);
+        self.send_next();
+    }
+
+    fn schedule_dao(&self, delay_ms: u32) {
+        let mop = self.dodag.map_or(Mop::NoDownward, |dodag| dodag.mop());
+        if mop == Mop::NoDownward {
+            return;
+        }
+        self.dao_acked.set(false);
+        self.dao_transmissions.set(0);
+        self.dao_sequence
+            .set(self.dao_sequence.get().wrapping_add(1));
+        let jitter = self.rng.random() % (delay_ms / 2 + 1);
+        self.set_timer(Timer::Dao, delay_ms / 2 + jitter);
+    }
+
+    fn dao_timer(&self) {
+        if self.dao_acked.get() || self.dao_transmissions.get() >= MAX_DAO_TRANSMISSIONS {
+            // Time to refresh our routes
+            self.schedule_dao(DAO_DELAY_MS);
+            return;
+        }
+        self.dao_transmissions.set(self.dao_transmissions.get() + 1);
+        self.pending_dao.set(true);
+        if self.dao_transmissions.get() < MAX_DAO_TRANSMISSIONS {
+            self.set_timer(Timer::Dao, DAO_ACK_TIMEOUT_MS);
+        } else {
+            self.set_timer(Timer::Dao, self.dao_refresh_ms());
+        }
+        self.send_next();
+    }
+
+    // Receiving
+
+    fn handle_dis(&self, header: &IP6Header) {
+        if !self.is_joined() {
+            return;
+        }
+        if header.get_dst_addr().is_multicast() {
+            self.reset_trickle();
+        } else {
+            // The reply is multicast as well, since the sender may not be
+            // reachable through our routes yet; Trickle is not reset.
+            self.pending_dio.set(true);
+            self.send_next();
+        }
+    }
+
+    fn handle_dio(&self, header: &IP6Header, body: &[u8]) {
+        let (off, dio) = match Dio::decode(body).done() {
+            Some(result) => result,
+            None => return,
+        };
+        let config = RplOptions::new(&body[off..]).find_map(|option| match option {
+            RplOption::DodagConfig(config) => Some(config),
+            _ => None,
+        });
+        let was_joined = self.is_joined();
+        let event = self.dodag.map_or(DioEvent::Ignored, |dodag| {
+            dodag.process_dio(header.get_src_addr(), &dio, config, DEFAULT_STEP_OF_RANK)
+        });
+        match event {
+            DioEvent::Ignored => {}
+            DioEvent::Consistent => {
+                self.trickle.map(|trickle| trickle.consistent());
+            }
+            DioEvent::Inconsistent => self.reset_trickle(),
+            DioEvent::Joined | DioEvent::ParentChanged => {
+                if !was_joined {
+                    self.cancel_timer(Timer::Dis);
+                    self.pending_dis.set(false);
+                    self.start_trickle();
+                    self.set_timer(Timer::Lifetime, self.lifetime_unit_ms());
+                } else {
+                    self.reset_trickle();
+                }
+                self.schedule_dao(DAO_DELAY_MS);
+            }
+            DioEvent::DaoRequested => self.schedule_dao(DAO_DELAY_MS),
+            DioEvent::Detached => {
+                // Poison the sub-DODAG before soliciting new parents
+                self.pending_dio.set(true);
+                self.detached();
+            }
+        }
+    }
+
+    fn handle_dao(&self, header: &IP6Header, body: &[u8]) {
+        let (off, dao) = match Dao::decode(body).done() {
+            Some(result) => result,
+            None => return,
+        };
+        let src = header.get_src_addr();
+        let status = self.dodag.map_or(DAO_ACK_REJECTED, |dodag| {
+            dodag.process_dao(src, &dao, &body[off..])
+        });
+        if dao.expect_ack {
+            self.pending_dao_ack.set(Some((src, dao.sequence, status)));
+        }
+        // In storing mode, propagate the new routes towards the root
+        let propagate = self
+            .dodag
+            .map_or(false, |dodag| !dodag.is_root() && dodag.mop().is_storing());
+        if status < DAO_ACK_REJECTED && propagate {
+            self.schedule_dao(DAO_DELAY_MS);
+        }
+        self.send_next();
+    }
+
+    fn handle_dao_ack(&self, body: &[u8]) {
+        if let Some((_, ack)) = DaoAck::decode(body).done() {
+            if ack.sequence == self.dao_sequence.get() && ack.status < DAO_ACK_REJECTED {
+                self.dao_acked.set(true);
+                self.set_timer(Timer::Dao, self.dao_refresh_ms());
+            }
+        }
+    }
+
+    // Sending
+
+    // Encodes the next pending message into `buf`. Returns its
+    // destination, ICMPv6 code and length.
+    fn next_message(&self, buf: &mut [u8]) -> Option<(IPAddr, u8, usize)> {
+        if let Some((dst, sequence, status)) = self.pending_dao_ack.take() {
+            let len = self
+                .dodag
+                .and_then(|dodag| dodag.encode_dao_ack(buf, sequence, status).done())
+                .map(|(len, _)| len);
+            if let Some(len) = len {
+                return Some((dst, rpl_code::DAO_ACK, len));
+            }
+        }
+        if self.pending_dio.replace(false) {
+            let len = self
+                .dodag
+                .and_then(|dodag| dodag.encode_dio(buf).done())
+                .map(|(len, _)| len);
+            if let Some(len) = len {
+                return Some((ALL_RPL_NODES, rpl_code::DIO, len));
+            }
+        }
+        if self.pending_dao.replace(false) {
+            let addr = self.addr.get();
+            let sequence = self.dao_sequence.get();
+            let result = self
+                .dodag
+                .and_then(|dodag| dodag.encode_dao(buf, addr, sequence, true).done());
+            if let Some((len, dst)) = result {
+                return Some((dst, rpl_code::DAO, len));
+            }
+        }
+        if self.pending_dis.replace(false) {
+            if let Some((len, _)) = encode_dis(buf).done() {
+                return Some((ALL_RPL_NODES, rpl_code::DIS, len));
+            }
+        }
+        None
+    }
+
+    fn send_next(&self) {
+        // A send may complete synchronously, in which case `send_done` is
+        // called before `send_to` returns and the loop continues with the
+        // next message.
+        while self.running.get() && !self.sending.get() {
+            let buf = match self.tx_buf.take() {
+                Some(buf) => buf,
+                None => return,
+            };
+            let (dst, code, len) = match self.next_message(buf) {
+                Some(message) => message,
+                None => {
+                    self.tx_buf.replace(buf);
+                    return;
+                }
+            };
+            let mut icmp_header = ICMP6Header::new(ICMP6Type::Type155);
+            icmp_header.set_code(code);
+            let mut payload = LeasableBuffer::new(buf);
+            payload.slice(0..len);
+            self.sending.set(true);
+            let result = self.ip_sender.send_to(
+                dst,
+                TransportHeader::ICMP(icmp_header),
+                &payload,
+                self.net_cap,
+            );
+            // The payload has been copied into the IPv6 packet
+            self.tx_buf.replace(payload.take());
+            if result.is_err() {
+                // The message is dropped; DIOs and DAOs are retransmitted
+                // by their timers.
+                self.sending.set(false);
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6Router for Rpl<'a, A> {
+    fn next_hop(&self, dst: IPAddr) -> Option<MacAddress> {
+        if !self.running.get() {
+            return None;
+        }
+        self.dodag
+            .and_then(|dodag| dodag.next_hop(&dst))
+            .map(|hop| mac_from_addr(&hop))
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6RecvClient for Rpl<'a, A> {
+    fn receive(&self, header: IP6Header, payload: &[u8]) {
+        if !self.running.get() || header.get_next_header() != ip6_nh::ICMP {
+            return;
+        }
+        let icmp_header = match ICMP6Header::decode(payload).done() {
+            Some((_, icmp_header)) => icmp_header,
+            None => return,
+        };
+        if let ICMP6Type::Type155 = icmp_header.get_type() {
+            let body = &payload[icmp_header.get_hdr_size()..];
+            match icmp_header.get_code() {
+                rpl_code::DIS => self.handle_dis(&header),
+                rpl_code::DIO => self.handle_dio(&header, body),
+                rpl_code::DAO => self.handle_dao(&header, body),
+                rpl_code::DAO_ACK => self.handle_dao_ack(body),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6SendClient for Rpl<'a, A> {
+    fn send_done(&self, _result: Result<(), ErrorCode>) {
+        self.sending.set(false);
+        self.send_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Rpl<'a, A> {
+    fn alarm(&self) {
+        self.advance_timers();
+        let mut timers = self.timers.get();
+        let mut expired = [false; NUM_TIMERS];
+        for (timer, expired) in timers.iter_mut().zip(expired.iter_mut()) {
+            if *timer == Some(0) {
+                *timer = None;
+                *expired = true;
+            }
+        }
+        self.timers.set(timers);
+
+        if expired[Timer::Trickle as usize] {
+            let rand = self.rng.random();
+            if let Some((transmit, delay)) = self.trickle.map(|trickle| trickle.fire(rand)) {
+                let mut timers = self.timers.get();
+                timers[Timer::Trickle as usize] = Some(delay);
+                self.timers.set(timers);
+                if transmit {
+                    self.pending_dio.set(true);
+                }
+            }
+        }
+        if expired[Timer::Dis as usize] && !self.is_joined() {
+            self.pending_dis.set(true);
+            let mut timers = self.timers.get();
+            timers[Timer::Dis as usize] = Some(DIS_INTERVAL_MS);
+            self.timers.set(timers);
+        }
+        if expired[Timer::Lifetime as usize] {
+            self.dodag.map(|dodag| dodag.expire_routes(1));
+            let mut timers = self.timers.get();
+            timers[Timer::Lifetime as usize] = Some(self.lifetime_unit_ms());
+            self.timers.set(timers);
+        }
+        if expired[Timer::Dao as usize] {
+            self.dao_timer();
+        }
+        self.rearm();
+        self.send_next();
+    }
+}
@@ -0,0 +1,150 @@
+//! The Trickle algorithm (RFC 6206), which RPL uses to pace DIO
+//! transmissions.
+//!
+//! Trickle divides time into intervals of length `I`, which start at
+//! `Imin` and double after every interval up to `Imax`. In each interval a
+//! message is transmitted at a random time `t` in `[I/2, I)`, unless `k`
+//! consistent messages were already heard in this interval. Detecting an
+//! inconsistency resets the interval to `Imin`.
+//!
+//! This struct only implements the state machine. The owner is expected
+//! to arm a timer for the delays returned by `start`, `fire` and
+//! `inconsistent`, and to call `fire` when it expires.
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Phase {
+    /// Waiting for the transmission time `t` of the current interval.
+    BeforeT,
+    /// Waiting for the end of the current interval.
+    AfterT,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Trickle {
+    imin_ms: u32,
+    imax_ms: u32,
+    k: u8,
+    interval_ms: u32,
+    t_ms: u32,
+    counter: u8,
+    phase: Phase,
+}
+
+impl Trickle {
+    /// Creates a Trickle timer with a minimum interval of `imin_ms`, which
+    /// may be doubled `doublings` times, and a redundancy constant of `k`.
+    /// A `k` of 0 disables suppression.
+    pub fn new(imin_ms: u32, doublings: u8, k: u8) -> Trickle {
+        let imin_ms = imin_ms.max(2);
+        let imax_ms = imin_ms
+            .checked_shl(doublings as u32)
+            .filter(|imax| *imax >= imin_ms)
+            .unwrap_or(u32::MAX);
+        Trickle {
+            imin_ms,
+            imax_ms,
+            k,
+            interval_ms: imin_ms,
+            t_ms: 0,
+            counter: 0,
+            phase: Phase::BeforeT,
+        }
+    }
+
+    pub fn interval_ms(&self) -> u32 {
+        self.interval_ms
+    }
+
+    /// Starts Trickle with an interval of `Imin`. Returns the delay until
+    /// the next call to `fire`.
+    pub fn start(&mut self, rand: u32) -> u32 {
+        self.interval_ms = self.imin_ms;
+        self.begin_interval(rand)
+    }
+
+    fn begin_interval(&mut self, rand: u32) -> u32 {
+        let half = self.interval_ms / 2;
+        self.counter = 0;
+        self.t_ms = half + rand % (self.interval_ms - half);
+        self.phase = Phase::BeforeT;
+        self.t_ms
+    }
+
+    /// Records a consistent transmission heard from a neighbor.
+    pub fn consistent(&mut self) {
+        self.counter = self.counter.saturating_add(1);
+    }
+
+    /// Records an inconsistency. If the interval is longer than `Imin`,
+    /// a new interval is started and the delay until the next call to
+    /// `fire` is returned. Otherwise nothing changes and `None` is
+    /// returned.
+    pub fn inconsistent(&mut self, rand: u32) -> Option<u32> {
+        if self.interval_ms > self.imin_ms {
+            Some(self.start(rand))
+        } else {
+            None
+        }
+    }
+
+    /// Advances the state machine when the delay returned previously
+    /// expired. Returns whether a message should be transmitted now, and
+    /// the delay until the next call to `fire`.
+    pub fn fire(&mut self, rand: u32) -> (bool, u32) {
+        match self.phase {
+            Phase::BeforeT => {
+                self.phase = Phase::AfterT;
+                let transmit = self.k == 0 || self.counter < self.k;
+                (transmit, self.interval_ms - self.t_ms)
+            }
+            Phase::AfterT => {
+                self.interval_ms = self
+                    .interval_ms
+                    .checked_mul(2)
+                    .unwrap_or(self.imax_ms)
+                    .min(self.imax_ms);
+                (false, self.begin_interval(rand))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_imax() {
+        let mut trickle = Trickle::new(100, 2, 1);
+        let t = trickle.start(0);
+        assert_eq!(t, 50);
+        assert_eq!(trickle.fire(0), (true, 50));
+        assert_eq!(trickle.fire(10), (false, 110));
+        assert_eq!(trickle.interval_ms(), 200);
+        assert_eq!(trickle.fire(0), (true, 90));
+        trickle.fire(0);
+        assert_eq!(trickle.interval_ms(), 400);
+        trickle.fire(0);
+        trickle.fire(0);
+        assert_eq!(trickle.interval_ms(), 400);
+    }
+
+    #[test]
+    fn suppression_and_reset() {
+        let mut trickle = Trickle::new(100, 4, 2);
+        trickle.start(0);
+        trickle.consistent();
+        trickle.consistent();
+        assert!(!trickle.fire(0).0);
+        trickle.fire(0);
+        // Counter is reset at the start of each interval
+        assert!(trickle.fire(0).0);
+        // Already at Imin after the reset: nothing changes
+        let mut fresh = Trickle::new(100, 4, 2);
+        fresh.start(0);
+        assert_eq!(fresh.inconsistent(0), None);
+        assert_eq!(trickle.interval_ms(), 200);
+        assert_eq!(trickle.inconsistent(7), Some(57));
+        assert_eq!(trickle.interval_ms(), 100);
+    }
+}
@@ -0,0 +1,1063 @@
+//! Implements Mesh Link Establishment (MLE) attach for a Sleepy End Device
+//! (SED), as outlined in Chapter 4 of the Thread 1.1.1 Specification.
+//!
+//! MLE messages are exchanged over UDP on port 19788 and consist of a
+//! security suite byte, an auxiliary security header, a command type and a
+//! series of TLV parameters (see `tlv.rs`), followed by a message integrity
+//! code:
+//!
+//! ```txt
+//! -------------------------------------------------------------------
+//! | 0x00 | Aux Security Header | Command | TLVs ... | MIC (4 bytes) |
+//! -------------------------------------------------------------------
+//!                              \_____ encrypted ___/
+//! ```
+//!
+//! The command and TLVs are secured with AES-CCM* at security level 5
+//! (ENC-MIC-32). The authentication data is the IPv6 source address,
+//! followed by the IPv6 destination address and the auxiliary security
+//! header. The nonce is the sender's extended MAC address, the MLE frame
+//! counter and the security level, exactly as for IEEE 802.15.4 frames.
+//!
+//! MLE for network attaching comprises a four-step handshake that works
+//! as follows:
+//!
+//! 1. A child device multicasts a Parent Request MLE command.
+//! 2. Each potential parent device on the network unicasts a Parent
+//!    Response MLE command.
+//! 3. The child device selects a parent based on a hierarchy of
+//!    connectivity metrics and unicasts a Child ID Request MLE
+//!    command.
+//! 4. The selected parent unicasts a Child ID Response MLE command.
+//!
+//! The Parent Request is first sent to routers only, and if no router
+//! responds, again to routers and router-eligible end devices. Once
+//! attached, the child periodically sends a Child Update Request to its
+//! parent as a keep-alive. If the parent stops responding, the child
+//! detaches and restarts the attach process.
+//!
+//! This implementation does not derive the MLE key from the Thread master
+//! key; the MLE key and the key sequence it belongs to must be provided
+//! with `set_key`. Network Data carried in the Child ID Response is not
+//! interpreted.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let mle = static_init!(
+//!     capsules::net::thread::mle::MleSed<'static, VirtualMuxAlarm<'static, Ast>, AESCCMCLIENT>,
+//!     capsules::net::thread::mle::MleSed::new(
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         radio_mac,
+//!         ip_send,
+//!         aes_ccm,
+//!         mle_alarm,
+//!         rng,
+//!         &mut MLE_TX_BUF,
+//!         &mut MLE_RX_BUF,
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(mle);
+//! udp_recv.set_client(mle);
+//! aes_ccm.set_client(mle);
+//! mle_alarm.set_alarm_client(mle);
+//! mle.set_key(MLE_KEY, 0);
+//! mle.start();
+//! ```
+
+use crate::ieee802154::device::MacDevice;
+use crate::net::ieee802154::{KeyId, MacAddress, Security, SecurityLevel};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6_send::IP6Sender;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::stream::SResult;
+use crate::net::stream::{encode_bytes, encode_u32, encode_u8};
+use crate::net::thread::tlv::{LinkMode, MulticastResponder, Tlv, TlvType};
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::debug;
+use kernel::hil::rng::Random;
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, AES128_KEY_SIZE, CCM_NONCE_LENGTH};
+use kernel::hil::time::{self, Alarm};
+use kernel::ErrorCode;
+
+/// UDP port used for all MLE messages.
+pub const MLE_PORT: u16 = 19788;
+
+/// Size of the transmit and receive buffers passed to `MleSed::new`. The
+/// buffers hold the CCM* authentication data in front of the MLE message,
+/// so they must be larger than the largest expected MLE payload.
+pub const MLE_BUF_SIZE: usize = 256;
+
+/// Link-local All Routers multicast address (ff02::2), the destination of
+/// Parent Requests.
+const LINK_LOCAL_ALL_ROUTERS: IPAddr = IPAddr([
+    0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+]);
+
+// Values of the security suite byte in front of every MLE message.
+const SECURITY_SUITE_154: u8 = 0;
+const SECURITY_SUITE_NONE: u8 = 255;
+
+// MLE messages are always secured at ENC-MIC-32 with key identifier mode 2.
+const MLE_SECURITY_LEVEL: SecurityLevel = SecurityLevel::EncMic32;
+const MIC_LEN: usize = 4;
+
+// Layout of the transmit/receive buffers. The CCM* authentication data
+// (source address, destination address, auxiliary security header) is
+// placed in front of the message.
+const SRC_ADDR_OFF: usize = 0;
+const DST_ADDR_OFF: usize = 16;
+const AUX_HDR_OFF: usize = 32;
+// Security control (1) + frame counter (4) + key source (4) + key index (1)
+const AUX_HDR_LEN: usize = 10;
+const TX_MSG_OFF: usize = AUX_HDR_OFF + AUX_HDR_LEN;
+
+/// Version TLV value for Thread 1.1.
+const THREAD_VERSION: u16 = 2;
+
+// Timing parameters, see Section 4.7.1 and 5.16 of the Thread 1.1.1
+// Specification.
+const PARENT_REQUEST_ROUTER_TIMEOUT_MS: u32 = 750;
+const PARENT_REQUEST_REED_TIMEOUT_MS: u32 = 1250;
+const PARENT_REQUEST_MAX_JITTER_MS: u32 = 50;
+const CHILD_ID_RESPONSE_TIMEOUT_MS: u32 = 1250;
+const CHILD_UPDATE_RESPONSE_TIMEOUT_MS: u32 = 1000;
+const ATTACH_BACKOFF_MS: u32 = 5000;
+const MAX_ATTACH_ATTEMPTS: u8 = 3;
+const MAX_KEEPALIVE_FAILURES: u8 = 3;
+
+/// Default child timeout advertised in the Timeout TLV, in seconds.
+pub const DEFAULT_CHILD_TIMEOUT_S: u32 = 240;
+
+/// MLE command types, see Section 4.4.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MleCommand {
+    LinkRequest = 0,
+    LinkAccept = 1,
+    LinkAcceptAndRequest = 2,
+    LinkReject = 3,
+    Advertisement = 4,
+    DataRequest = 7,
+    DataResponse = 8,
+    ParentRequest = 9,
+    ParentResponse = 10,
+    ChildIdRequest = 11,
+    ChildIdResponse = 12,
+    ChildUpdateRequest = 13,
+    ChildUpdateResponse = 14,
+    Announce = 15,
+    DiscoveryRequest = 16,
+    DiscoveryResponse = 17,
+}
+
+impl MleCommand {
+    pub fn from_u8(command: u8) -> Option<MleCommand> {
+        match command {
+            0 => Some(MleCommand::LinkRequest),
+            1 => Some(MleCommand::LinkAccept),
+            2 => Some(MleCommand::LinkAcceptAndRequest),
+            3 => Some(MleCommand::LinkReject),
+            4 => Some(MleCommand::Advertisement),
+            7 => Some(MleCommand::DataRequest),
+            8 => Some(MleCommand::DataResponse),
+            9 => Some(MleCommand::ParentRequest),
+            10 => Some(MleCommand::ParentResponse),
+            11 => Some(MleCommand::ChildIdRequest),
+            12 => Some(MleCommand::ChildIdResponse),
+            13 => Some(MleCommand::ChildUpdateRequest),
+            14 => Some(MleCommand::ChildUpdateResponse),
+            15 => Some(MleCommand::Announce),
+            16 => Some(MleCommand::DiscoveryRequest),
+            17 => Some(MleCommand::DiscoveryResponse),
+            _ => None,
+        }
+    }
+}
+
+/// Contents of a Leader Data TLV.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct LeaderData {
+    pub partition_id: u32,
+    pub weighting: u8,
+    pub data_version: u8,
+    pub stable_data_version: u8,
+    pub leader_router_id: u8,
+}
+
+impl LeaderData {
+    fn to_tlv(&self) -> Tlv<'static> {
+        Tlv::LeaderData {
+            partition_id: self.partition_id,
+            weighting: self.weighting,
+            data_version: self.data_version,
+            stable_data_version: self.stable_data_version,
+            leader_router_id: self.leader_router_id,
+        }
+    }
+}
+
+/// A potential parent, collected from a Parent Response.
+#[derive(Copy, Clone, Debug)]
+struct ParentCandidate {
+    addr: IPAddr,
+    rloc16: u16,
+    /// The parent's challenge, to be echoed in the Child ID Request.
+    challenge: [u8; 8],
+    link_quality: u8,
+    parent_priority: u8,
+    link_quality_3: u8,
+    link_quality_2: u8,
+    link_quality_1: u8,
+    leader_data: LeaderData,
+    frame_counter: u32,
+}
+
+impl ParentCandidate {
+    /// Parent selection, Section 4.7.2: prefer better two-way link quality,
+    /// then higher parent priority, then more neighbors with good link
+    /// quality.
+    fn is_better_than(&self, other: &ParentCandidate) -> bool {
+        let key = |c: &ParentCandidate| {
+            (
+                c.link_quality,
+                // Parent priority is a signed two-bit value in bits 6-7
+                ((c.parent_priority as i8) >> 6),
+                c.link_quality_3,
+                c.link_quality_2,
+                c.link_quality_1,
+            )
+        };
+        key(self) > key(other)
+    }
+}
+
+/// Maps a link margin in dB to a link quality value, Section 4.7.2.1.
+fn link_quality_from_margin(link_margin: u8) -> u8 {
+    if link_margin > 20 {
+        3
+    } else if link_margin > 10 {
+        2
+    } else if link_margin > 2 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Recovers the extended MAC address from the interface identifier of a
+/// link-local IPv6 address (RFC 4944, Section 6).
+fn ext_addr_from_iid(addr: &IPAddr) -> [u8; 8] {
+    let mut ext_addr = [0u8; 8];
+    ext_addr.copy_from_slice(&addr.0[8..16]);
+    ext_addr[0] ^= 0b00000010;
+    ext_addr
+}
+
+/// The CCM* nonce is the same as for IEEE 802.15.4 frames.
+fn get_ccm_nonce(ext_addr: &[u8; 8], frame_counter: u32, level: SecurityLevel) -> [u8; 13] {
+    let mut nonce = [0u8; CCM_NONCE_LENGTH];
+    let encode_ccm_nonce = |buf: &mut [u8]| {
+        let off = enc_consume!(buf; encode_bytes, ext_addr.as_ref());
+        let off = enc_consume!(buf, off; encode_u32, frame_counter);
+        let off = enc_consume!(buf, off; encode_u8, level as u8);
+        stream_done!(off);
+    };
+    match encode_ccm_nonce(&mut nonce).done() {
+        None => {
+            // This should not be possible
+            panic!("Failed to produce ccm nonce");
+        }
+        Some(_) => nonce,
+    }
+}
+
+/// Iterates over a sequence of TLVs, calling `f` on each TLV that
+/// `Tlv::decode` understands. Unknown TLVs are skipped.
+fn for_each_tlv<'b, F>(buf: &'b [u8], mut f: F)
+where
+    F: FnMut(Tlv<'b>),
+{
+    let mut off = 0;
+    while off + 2 <= buf.len() {
+        let end = off + 2 + buf[off + 1] as usize;
+        if end > buf.len() {
+            break;
+        }
+        if let Some((_, tlv)) = Tlv::decode(&buf[off..end]).done() {
+            f(tlv);
+        }
+        off = end;
+    }
+}
+
+/// Attach state of the device.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MleState {
+    /// Not attached, and not attempting to attach.
+    Disabled,
+    /// A Parent Request was sent, and Parent Responses are being collected.
+    /// `reeds` is true if router-eligible end devices were included in the
+    /// scan mask.
+    ParentRequest { reeds: bool },
+    /// A Child ID Request was sent to the selected parent.
+    ChildIdRequest,
+    /// Attached to a parent as a child with the given RLOC16.
+    Child { rloc16: u16 },
+    /// Waiting to restart the attach process after a failed attempt.
+    Backoff,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum CryptOp {
+    Idle,
+    Encrypting { dst: IPAddr, msg_len: usize },
+    Decrypting(RxInfo),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct RxInfo {
+    src: IPAddr,
+    frame_counter: u32,
+    msg_off: usize,
+    msg_len: usize,
+}
+
+/// Implement this trait and use `set_client()` to be notified when the
+/// device attaches to or detaches from a Thread network.
+pub trait MleClient {
+    /// Called when the Child ID Response has been received and the device
+    /// is attached to `parent` with short address `rloc16`.
+    fn attached(&self, parent: IPAddr, rloc16: u16);
+
+    /// Called when the device lost its parent, or when an attach attempt
+    /// failed after `MAX_ATTACH_ATTEMPTS` tries. The device automatically
+    /// retries attaching after a backoff.
+    fn detached(&self);
+}
+
+pub struct MleSed<'a, A: Alarm<'a>, C: AES128CCM<'a>> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    radio: &'a dyn MacDevice<'a>,
+    ip_sender: &'a dyn IP6Sender<'a>,
+    aes_ccm: &'a C,
+    alarm: &'a A,
+    rng: &'a dyn Random<'a>,
+    client: OptionalCell<&'a dyn MleClient>,
+    net_cap: &'static NetworkCapability,
+
+    tx_buf: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+    crypt_op: Cell<CryptOp>,
+    /// An encryption that could not start while a decryption was running.
+    pending_encrypt: Cell<Option<(IPAddr, usize)>>,
+
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    key_sequence: Cell<u32>,
+    key_set: Cell<bool>,
+    frame_counter: Cell<u32>,
+
+    state: Cell<MleState>,
+    attach_attempts: Cell<u8>,
+    keepalive_failures: Cell<u8>,
+    awaiting_response: Cell<bool>,
+    challenge: Cell<[u8; 8]>,
+    child_timeout: Cell<u32>,
+    best_parent: Cell<Option<ParentCandidate>>,
+    parent: Cell<Option<ParentCandidate>>,
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> MleSed<'a, A, C> {
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        radio: &'a dyn MacDevice<'a>,
+        ip_sender: &'a dyn IP6Sender<'a>,
+        aes_ccm: &'a C,
+        alarm: &'a A,
+        rng: &'a dyn Random<'a>,
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> MleSed<'a, A, C> {
+        MleSed {
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            radio: radio,
+            ip_sender: ip_sender,
+            aes_ccm: aes_ccm,
+            alarm: alarm,
+            rng: rng,
+            client: OptionalCell::empty(),
+            net_cap: net_cap,
+            tx_buf: TakeCell::new(tx_buf),
+            rx_buf: TakeCell::new(rx_buf),
+            crypt_op: Cell::new(CryptOp::Idle),
+            pending_encrypt: Cell::new(None),
+            key: Cell::new([0; AES128_KEY_SIZE]),
+            key_sequence: Cell::new(0),
+            key_set: Cell::new(false),
+            frame_counter: Cell::new(0),
+            state: Cell::new(MleState::Disabled),
+            attach_attempts: Cell::new(0),
+            keepalive_failures: Cell::new(0),
+            awaiting_response: Cell::new(false),
+            challenge: Cell::new([0; 8]),
+            child_timeout: Cell::new(DEFAULT_CHILD_TIMEOUT_S),
+            best_parent: Cell::new(None),
+            parent: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn MleClient) {
+        self.client.set(client);
+    }
+
+    /// Sets the MLE key and the key sequence counter it was derived for.
+    pub fn set_key(&self, key: [u8; AES128_KEY_SIZE], key_sequence: u32) {
+        self.key.set(key);
+        self.key_sequence.set(key_sequence);
+        self.key_set.set(true);
+    }
+
+    /// Sets the child timeout advertised to the parent, in seconds. Takes
+    /// effect on the next attach.
+    pub fn set_child_timeout(&self, timeout_s: u32) {
+        self.child_timeout.set(timeout_s);
+    }
+
+    pub fn get_state(&self) -> MleState {
+        self.state.get()
+    }
+
+    pub fn is_attached(&self) -> bool {
+        match self.state.get() {
+            MleState::Child { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Binds to the MLE port and starts the attach process.
+    ///
+    /// Returns `OFF` if no key has been set, `ALREADY` if the device is
+    /// already attaching or attached, and `BUSY` if the MLE port could not
+    /// be bound.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if !self.key_set.get() {
+            return Err(ErrorCode::OFF);
+        }
+        if self.state.get() != MleState::Disabled {
+            return Err(ErrorCode::ALREADY);
+        }
+        if !self.udp_sender.is_bound() {
+            let socket = self
+                .port_table
+                .create_socket()
+                .map_err(|_| ErrorCode::NOMEM)?;
+            match self.port_table.bind(socket, MLE_PORT, self.net_cap) {
+                Ok((send_bind, rcv_bind)) => {
+                    self.udp_sender.set_binding(send_bind);
+                    self.udp_receiver.set_binding(rcv_bind);
+                }
+                Err(_socket) => {
+                    // Dropping the socket frees its slot in the port table.
+                    return Err(ErrorCode::BUSY);
+                }
+            }
+        }
+        self.attach_attempts.set(0);
+        self.start_attach();
+        Ok(())
+    }
+
+    /// Stops attaching, or detaches from the current parent. The MLE port
+    /// remains bound.
+    pub fn stop(&self) {
+        let _ = self.alarm.disarm();
+        let was_attached = self.is_attached();
+        self.state.set(MleState::Disabled);
+        self.parent.set(None);
+        self.best_parent.set(None);
+        if was_attached {
+            self.client.map(|client| client.detached());
+        }
+    }
+
+    fn start_attach(&self) {
+        self.best_parent.set(None);
+        self.send_parent_request(false);
+    }
+
+    fn set_timeout_ms(&self, ms: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    fn new_challenge(&self) -> [u8; 8] {
+        let mut challenge = [0u8; 8];
+        challenge[..4].copy_from_slice(&self.rng.random().to_le_bytes());
+        challenge[4..].copy_from_slice(&self.rng.random().to_le_bytes());
+        self.challenge.set(challenge);
+        challenge
+    }
+
+    fn link_local_addr(&self) -> IPAddr {
+        IPAddr::generate_from_mac(MacAddress::Long(self.radio.get_address_long()))
+    }
+
+    fn send_parent_request(&self, reeds: bool) {
+        let scan_mask = if reeds {
+            MulticastResponder::Router as u8 | MulticastResponder::EndDevice as u8
+        } else {
+            MulticastResponder::Router as u8
+        };
+        let challenge = self.new_challenge();
+        let tlvs = [
+            Tlv::Mode(LinkMode::SecureDataRequests as u8),
+            Tlv::Challenge(challenge),
+            Tlv::ScanMask(scan_mask),
+            Tlv::Version(THREAD_VERSION),
+        ];
+        self.state.set(MleState::ParentRequest { reeds: reeds });
+        if self.send_message(LINK_LOCAL_ALL_ROUTERS, MleCommand::ParentRequest, &tlvs) != Ok(()) {
+            debug!("[MLE] Failed to send Parent Request");
+        }
+        let timeout = if reeds {
+            PARENT_REQUEST_REED_TIMEOUT_MS
+        } else {
+            PARENT_REQUEST_ROUTER_TIMEOUT_MS
+        };
+        let jitter = self.rng.random() % PARENT_REQUEST_MAX_JITTER_MS;
+        self.set_timeout_ms(timeout + jitter);
+    }
+
+    fn send_child_id_request(&self, parent: &ParentCandidate) {
+        let request_tlvs = [TlvType::Address16 as u8, TlvType::NetworkData as u8];
+        let tlvs = [
+            Tlv::Response(parent.challenge),
+            // The link-layer frame counter is not tracked by the MAC layer.
+            Tlv::LinkLayerFrameCounter(0),
+            Tlv::MleFrameCounter(self.frame_counter.get()),
+            Tlv::Mode(LinkMode::SecureDataRequests as u8),
+            Tlv::Timeout(self.child_timeout.get()),
+            Tlv::Version(THREAD_VERSION),
+            Tlv::TlvRequest(&request_tlvs),
+        ];
+        self.state.set(MleState::ChildIdRequest);
+        if self.send_message(parent.addr, MleCommand::ChildIdRequest, &tlvs) != Ok(()) {
+            debug!("[MLE] Failed to send Child ID Request");
+        }
+        self.set_timeout_ms(CHILD_ID_RESPONSE_TIMEOUT_MS);
+    }
+
+    fn send_child_update_request(&self) {
+        let (parent, rloc16) = match (self.parent.get(), self.state.get()) {
+            (Some(parent), MleState::Child { rloc16 }) => (parent, rloc16),
+            _ => return,
+        };
+        let challenge = self.new_challenge();
+        let tlvs = [
+            Tlv::SourceAddress(rloc16),
+            Tlv::Mode(LinkMode::SecureDataRequests as u8),
+            Tlv::Timeout(self.child_timeout.get()),
+            Tlv::Challenge(challenge),
+            parent.leader_data.to_tlv(),
+        ];
+        self.awaiting_response.set(true);
+        if self.send_message(parent.addr, MleCommand::ChildUpdateRequest, &tlvs) != Ok(()) {
+            debug!("[MLE] Failed to send Child Update Request");
+        }
+        self.set_timeout_ms(CHILD_UPDATE_RESPONSE_TIMEOUT_MS);
+    }
+
+    /// Interval between keep-alive Child Update Requests. A quarter of the
+    /// child timeout leaves room for `MAX_KEEPALIVE_FAILURES` retries.
+    /// Timeouts longer than the alarm can count saturate.
+    fn keepalive_interval_ms(&self) -> u32 {
+        self.child_timeout.get().saturating_mul(1000) / 4
+    }
+
+    /// Serializes an MLE message to `dst` into the transmit buffer and
+    /// starts securing it. The message is passed to the UDP layer once
+    /// encryption completes.
+    fn send_message(
+        &self,
+        dst: IPAddr,
+        command: MleCommand,
+        tlvs: &[Tlv],
+    ) -> Result<(), ErrorCode> {
+        let msg_len = self.tx_buf.map_or(Err(ErrorCode::BUSY), |buf| {
+            let src = self.link_local_addr();
+            buf[SRC_ADDR_OFF..SRC_ADDR_OFF + 16].copy_from_slice(&src.0);
+            buf[DST_ADDR_OFF..DST_ADDR_OFF + 16].copy_from_slice(&dst.0);
+            buf[TX_MSG_OFF] = command as u8;
+            let mut off = TX_MSG_OFF + 1;
+            for tlv in tlvs.iter() {
+                // Leave room for the MIC
+                let end = buf.len() - MIC_LEN;
+                match tlv.encode(&mut buf[off..end]).done() {
+                    Some((len, _)) => off += len,
+                    None => return Err(ErrorCode::SIZE),
+                }
+            }
+            Ok(off - TX_MSG_OFF)
+        })?;
+
+        if self.crypt_op.get() == CryptOp::Idle {
+            self.start_encrypt(dst, msg_len)
+        } else {
+            self.pending_encrypt.set(Some((dst, msg_len)));
+            Ok(())
+        }
+    }
+
+    fn start_encrypt(&self, dst: IPAddr, msg_len: usize) -> Result<(), ErrorCode> {
+        let buf = self.tx_buf.take().ok_or(ErrorCode::BUSY)?;
+        let frame_counter = self.frame_counter.get();
+        self.frame_counter.set(frame_counter.wrapping_add(1));
+
+        // Key identifier mode 2: the key source is the key sequence in
+        // network byte order (`KeyId::encode` reverses the byte order).
+        let key_sequence = self.key_sequence.get();
+        let security = Security {
+            level: MLE_SECURITY_LEVEL,
+            asn_in_nonce: false,
+            frame_counter: Some(frame_counter),
+            key_id: KeyId::Source4Index(
+                key_sequence.to_le_bytes(),
+                ((key_sequence & 0x7f) + 1) as u8,
+            ),
+        };
+        match security.encode(&mut buf[AUX_HDR_OFF..TX_MSG_OFF]).done() {
+            Some((AUX_HDR_LEN, _)) => {}
+            _ => {
+                self.tx_buf.replace(buf);
+                return Err(ErrorCode::FAIL);
+            }
+        }
+
+        let nonce = get_ccm_nonce(
+            &self.radio.get_address_long(),
+            frame_counter,
+            MLE_SECURITY_LEVEL,
+        );
+        if self.aes_ccm.set_key(&self.key.get()) != Ok(())
+            || self.aes_ccm.set_nonce(&nonce) != Ok(())
+        {
+            self.tx_buf.replace(buf);
+            return Err(ErrorCode::FAIL);
+        }
+        match self
+            .aes_ccm
+            .crypt(buf, SRC_ADDR_OFF, TX_MSG_OFF, msg_len, MIC_LEN, true, true)
+        {
+            Ok(()) => {
+                self.crypt_op.set(CryptOp::Encrypting {
+                    dst: dst,
+                    msg_len: msg_len,
+                });
+                Ok(())
+            }
+            Err((ecode, buf)) => {
+                self.tx_buf.replace(buf);
+                Err(ecode)
+            }
+        }
+    }
+
+    /// Passes a secured message to the UDP layer.
+    fn transmit(&self, buf: &'static mut [u8], dst: IPAddr, msg_len: usize) {
+        // The security suite byte replaces the last byte of the destination
+        // address, which is no longer needed after authentication.
+        buf[AUX_HDR_OFF - 1] = SECURITY_SUITE_154;
+        let mut dgram = LeasableBuffer::new(buf);
+        dgram.slice(AUX_HDR_OFF - 1..TX_MSG_OFF + msg_len + MIC_LEN);
+        if let Err(dgram) = self.udp_sender.send_to(dst, MLE_PORT, dgram, self.net_cap) {
+            debug!("[MLE] UDP send failed");
+            self.tx_buf.replace(dgram.take());
+        }
+    }
+
+    /// Starts authenticating and decrypting a received MLE message.
+    fn start_decrypt(&self, src: IPAddr, dst: IPAddr, payload: &[u8]) -> Result<(), ErrorCode> {
+        let (aux_len, security) = Security::decode(payload).done().ok_or(ErrorCode::INVAL)?;
+        let frame_counter = security.frame_counter.ok_or(ErrorCode::INVAL)?;
+        if security.level != MLE_SECURITY_LEVEL {
+            return Err(ErrorCode::INVAL);
+        }
+        match security.key_id {
+            KeyId::Source4Index(key_source, _) => {
+                // Only the current key sequence is supported.
+                if u32::from_le_bytes(key_source) != self.key_sequence.get() {
+                    return Err(ErrorCode::INVAL);
+                }
+            }
+            _ => return Err(ErrorCode::INVAL),
+        }
+        if payload.len() < aux_len + 1 + MIC_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        let msg_len = payload.len() - aux_len - MIC_LEN;
+        let msg_off = AUX_HDR_OFF + aux_len;
+
+        let buf = self.rx_buf.take().ok_or(ErrorCode::BUSY)?;
+        if msg_off + msg_len + MIC_LEN > buf.len() {
+            self.rx_buf.replace(buf);
+            return Err(ErrorCode::SIZE);
+        }
+        buf[SRC_ADDR_OFF..SRC_ADDR_OFF + 16].copy_from_slice(&src.0);
+        buf[DST_ADDR_OFF..DST_ADDR_OFF + 16].copy_from_slice(&dst.0);
+        buf[AUX_HDR_OFF..msg_off + msg_len + MIC_LEN].copy_from_slice(payload);
+
+        let nonce = get_ccm_nonce(&ext_addr_from_iid(&src), frame_counter, security.level);
+        if self.aes_ccm.set_key(&self.key.get()) != Ok(())
+            || self.aes_ccm.set_nonce(&nonce) != Ok(())
+        {
+            self.rx_buf.replace(buf);
+            return Err(ErrorCode::FAIL);
+        }
+        match self
+            .aes_ccm
+            .crypt(buf, SRC_ADDR_OFF, msg_off, msg_len, MIC_LEN, true, false)
+        {
+            Ok(()) => {
+                self.crypt_op.set(CryptOp::Decrypting(RxInfo {
+                    src: src,
+                    frame_counter: frame_counter,
+                    msg_off: msg_off,
+                    msg_len: msg_len,
+                }));
+                Ok(())
+            }
+            Err((ecode, buf)) => {
+                self.rx_buf.replace(buf);
+                Err(ecode)
+            }
+        }
+    }
+
+    /// Handles an authenticated and decrypted MLE message.
+    fn handle_message(&self, info: &RxInfo, msg: &[u8]) {
+        if msg.is_empty() {
+            return;
+        }
+        let tlvs = &msg[1..];
+        match (MleCommand::from_u8(msg[0]), self.state.get()) {
+            (Some(MleCommand::ParentResponse), MleState::ParentRequest { .. }) => {
+                self.handle_parent_response(info, tlvs)
+            }
+            (Some(MleCommand::ChildIdResponse), MleState::ChildIdRequest) => {
+                self.handle_child_id_response(info, tlvs)
+            }
+            (Some(MleCommand::ChildUpdateResponse), MleState::Child { .. }) => {
+                self.handle_child_update_response(info, tlvs)
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_parent_response(&self, info: &RxInfo, tlvs: &[u8]) {
+        let mut response_valid = false;
+        let mut rloc16 = None;
+        let mut challenge = None;
+        let mut link_margin = None;
+        let mut connectivity = None;
+        let mut leader_data = None;
+        let our_challenge = self.challenge.get();
+        for_each_tlv(tlvs, |tlv| match tlv {
+            Tlv::Response(response) => response_valid = response == our_challenge,
+            Tlv::SourceAddress(addr) => rloc16 = Some(addr),
+            Tlv::Challenge(c) => challenge = Some(c),
+            Tlv::LinkMargin(margin) => link_margin = Some(margin),
+            Tlv::Connectivity {
+                parent_priority,
+                link_quality_3,
+                link_quality_2,
+                link_quality_1,
+                ..
+            } => {
+                connectivity = Some((
+                    parent_priority,
+                    link_quality_3,
+                    link_quality_2,
+                    link_quality_1,
+                ))
+            }
+            Tlv::LeaderData {
+                partition_id,
+                weighting,
+                data_version,
+                stable_data_version,
+                leader_router_id,
+            } => {
+                leader_data = Some(LeaderData {
+                    partition_id: partition_id,
+                    weighting: weighting,
+                    data_version: data_version,
+                    stable_data_version: stable_data_version,
+                    leader_router_id: leader_router_id,
+                })
+            }
+            _ => {}
+        });
+        if !response_valid {
+            return;
+        }
+        if let (
+            Some(rloc16),
+            Some(challenge),
+            Some(link_margin),
+            Some((parent_priority, lq3, lq2, lq1)),
+            Some(leader_data),
+        ) = (rloc16, challenge, link_margin, connectivity, leader_data)
+        {
+            let candidate = ParentCandidate {
+                addr: info.src,
+                rloc16: rloc16,
+                challenge: challenge,
+                link_quality: link_quality_from_margin(link_margin),
+                parent_priority: parent_priority,
+                link_quality_3: lq3,
+                link_quality_2: lq2,
+                link_quality_1: lq1,
+                leader_data: leader_data,
+                frame_counter: info.frame_counter,
+            };
+            let better = self
+                .best_parent
+                .get()
+                .map_or(true, |best| candidate.is_better_than(&best));
+            if better {
+                self.best_parent.set(Some(candidate));
+            }
+        }
+    }
+
+    fn handle_child_id_response(&self, info: &RxInfo, tlvs: &[u8]) {
+        let mut parent = match self.best_parent.get() {
+            Some(parent) if parent.addr == info.src => parent,
+            _ => return,
+        };
+        let mut rloc16 = None;
+        let mut rejected = false;
+        for_each_tlv(tlvs, |tlv| match tlv {
+            Tlv::Address16(addr) => rloc16 = Some(addr),
+            Tlv::Status(_) => rejected = true,
+            Tlv::LeaderData {
+                partition_id,
+                weighting,
+                data_version,
+                stable_data_version,
+                leader_router_id,
+            } => {
+                parent.leader_data = LeaderData {
+                    partition_id: partition_id,
+                    weighting: weighting,
+                    data_version: data_version,
+                    stable_data_version: stable_data_version,
+                    leader_router_id: leader_router_id,
+                }
+            }
+            _ => {}
+        });
+        let rloc16 = match (rloc16, rejected) {
+            (Some(rloc16), false) => rloc16,
+            _ => {
+                // Try again with the remaining candidates on the next attempt
+                let _ = self.alarm.disarm();
+                self.attach_failed();
+                return;
+            }
+        };
+
+        let _ = self.alarm.disarm();
+        parent.frame_counter = info.frame_counter;
+        self.parent.set(Some(parent));
+        self.state.set(MleState::Child { rloc16: rloc16 });
+        self.attach_attempts.set(0);
+        self.keepalive_failures.set(0);
+        self.awaiting_response.set(false);
+
+        // Take on the assigned short address and route everything through
+        // the parent.
+        self.radio.set_address(rloc16);
+        self.radio.config_commit();
+        self.ip_sender
+            .set_gateway(MacAddress::Long(ext_addr_from_iid(&parent.addr)));
+
+        self.set_timeout_ms(self.keepalive_interval_ms());
+        self.client
+            .map(|client| client.attached(parent.addr, rloc16));
+    }
+
+    fn handle_child_update_response(&self, info: &RxInfo, tlvs: &[u8]) {
+        let mut parent = match self.parent.get() {
+            Some(parent) if parent.addr == info.src => parent,
+            _ => return,
+        };
+        // Reject replayed messages from the parent
+        if info.frame_counter <= parent.frame_counter {
+            return;
+        }
+        let mut response_valid = false;
+        let our_challenge = self.challenge.get();
+        for_each_tlv(tlvs, |tlv| {
+            if let Tlv::Response(response) = tlv {
+                response_valid = response == our_challenge;
+            }
+        });
+        if !response_valid || !self.awaiting_response.get() {
+            return;
+        }
+        parent.frame_counter = info.frame_counter;
+        self.parent.set(Some(parent));
+        self.awaiting_response.set(false);
+        self.keepalive_failures.set(0);
+        self.set_timeout_ms(self.keepalive_interval_ms());
+    }
+
+    /// Called when an attach attempt did not lead to a parent.
+    fn attach_failed(&self) {
+        let attempts = self.attach_attempts.get() + 1;
+        self.attach_attempts.set(attempts);
+        self.best_parent.set(None);
+        if attempts >= MAX_ATTACH_ATTEMPTS {
+            self.attach_attempts.set(0);
+            self.state.set(MleState::Backoff);
+            self.set_timeout_ms(ATTACH_BACKOFF_MS);
+            self.client.map(|client| client.detached());
+        } else {
+            self.start_attach();
+        }
+    }
+
+    /// Called when the parent stopped answering keep-alives.
+    fn parent_lost(&self) {
+        self.parent.set(None);
+        self.state.set(MleState::Disabled);
+        self.client.map(|client| client.detached());
+        self.attach_attempts.set(0);
+        self.start_attach();
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> time::AlarmClient for MleSed<'a, A, C> {
+    fn alarm(&self) {
+        match self.state.get() {
+            MleState::Disabled => {}
+            MleState::ParentRequest { reeds } => match self.best_parent.get() {
+                Some(parent) => self.send_child_id_request(&parent),
+                None => {
+                    if !reeds {
+                        self.send_parent_request(true);
+                    } else {
+                        self.attach_failed();
+                    }
+                }
+            },
+            MleState::ChildIdRequest => self.attach_failed(),
+            MleState::Child { .. } => {
+                if self.awaiting_response.get() {
+                    let failures = self.keepalive_failures.get() + 1;
+                    self.keepalive_failures.set(failures);
+                    if failures >= MAX_KEEPALIVE_FAILURES {
+                        self.parent_lost();
+                        return;
+                    }
+                }
+                self.send_child_update_request();
+            }
+            MleState::Backoff => self.start_attach(),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> CCMClient for MleSed<'a, A, C> {
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        let op = self.crypt_op.replace(CryptOp::Idle);
+        match op {
+            CryptOp::Idle => {
+                debug!("[MLE] Unexpected crypt_done");
+            }
+            CryptOp::Encrypting { dst, msg_len } => {
+                if res == Ok(()) {
+                    self.transmit(buf, dst, msg_len);
+                } else {
+                    self.tx_buf.replace(buf);
+                }
+            }
+            CryptOp::Decrypting(info) => {
+                if res == Ok(()) && tag_is_valid {
+                    self.handle_message(&info, &buf[info.msg_off..info.msg_off + info.msg_len]);
+                }
+                self.rx_buf.replace(buf);
+            }
+        }
+
+        if self.crypt_op.get() == CryptOp::Idle {
+            if let Some((dst, msg_len)) = self.pending_encrypt.take() {
+                if self.start_encrypt(dst, msg_len) != Ok(()) {
+                    debug!("[MLE] Failed to start queued encryption");
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> UDPSendClient for MleSed<'a, A, C> {
+    fn send_done(&self, result: Result<(), ErrorCode>, dgram: LeasableBuffer<'static, u8>) {
+        if result != Ok(()) {
+            debug!("[MLE] send_done error: {:?}", result);
+        }
+        self.tx_buf.replace(dgram.take());
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128CCM<'a>> UDPRecvClient for MleSed<'a, A, C> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        if src_port != MLE_PORT || payload.is_empty() || self.state.get() == MleState::Disabled {
+            return;
+        }
+        // MLE messages must be sent from link-local addresses
+        if !src_addr.is_unicast_link_local() {
+            return;
+        }
+        match payload[0] {
+            SECURITY_SUITE_154 => {
+                if self.crypt_op.get() != CryptOp::Idle {
+                    // MLE tolerates message loss; the sender retransmits.
+                    return;
+                }
+                if let Err(e) = self.start_decrypt(src_addr, dst_addr, &payload[1..]) {
+                    debug!("[MLE] Dropping message: {:?}", e);
+                }
+            }
+            // Unsecured messages are only used for network discovery,
+            // which this implementation does not support.
+            SECURITY_SUITE_NONE => {}
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,640 @@
+//! Attach, keep-alive and timeout tests of the Sleepy End Device.
+//!
+//! The CCM* engine is a model that leaves messages in the clear and accepts
+//! every MIC, so the tests can read the commands and TLVs the device sends
+//! and write the ones its parent answers with. Encryption and UDP sends
+//! complete when `Harness::run()` is called, and time only passes through
+//! the emulated alarm.
+
+extern crate std;
+
+use super::*;
+use crate::emulated_alarm::EmulatedAlarm;
+use crate::ieee802154::device::{RxClient, TxClient};
+use crate::ieee802154::framer::Frame;
+use crate::net::ieee802154::PanID;
+use crate::net::ipv6::ipv6_send::IP6SendClient;
+use crate::net::ipv6::IP6Header;
+use crate::net::ipv6::TransportHeader;
+use crate::net::udp::udp_port_table::MAX_NUM_BOUND_PORTS;
+use crate::test_support::{leak, leak_mut, run, CountingRng, Events, MockUdp, Pending};
+use std::vec::Vec;
+
+const KEY: [u8; AES128_KEY_SIZE] = [0x5a; AES128_KEY_SIZE];
+const OWN_EXT_ADDR: [u8; 8] = [0x02, 0, 0, 0, 0, 0, 0, 0x01];
+const PARENT_EXT_ADDR: [u8; 8] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0];
+const PARENT_RLOC16: u16 = 0x0400;
+const PARENT_CHALLENGE: [u8; 8] = [0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7];
+const RLOC16: u16 = 0x0401;
+const LEADER_DATA: LeaderData = LeaderData {
+    partition_id: 0x1234_5678,
+    weighting: 64,
+    data_version: 1,
+    stable_data_version: 1,
+    leader_router_id: 0,
+};
+
+fn parent_addr() -> IPAddr {
+    IPAddr::generate_from_mac(MacAddress::Long(PARENT_EXT_ADDR))
+}
+
+fn own_addr() -> IPAddr {
+    IPAddr::generate_from_mac(MacAddress::Long(OWN_EXT_ADDR))
+}
+
+struct MockRadio {
+    address: Cell<u16>,
+}
+
+impl MacDevice<'static> for MockRadio {
+    fn set_transmit_client(&self, _client: &'static dyn TxClient) {}
+
+    fn set_receive_client(&self, _client: &'static dyn RxClient) {}
+
+    fn get_address(&self) -> u16 {
+        self.address.get()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        OWN_EXT_ADDR
+    }
+
+    fn get_pan(&self) -> u16 {
+        0xabcd
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.address.set(addr);
+    }
+
+    fn set_address_long(&self, _addr: [u8; 8]) {}
+
+    fn set_pan(&self, _id: u16) {}
+
+    fn config_commit(&self) {}
+
+    fn is_on(&self) -> bool {
+        true
+    }
+
+    fn prepare_data_frame(
+        &self,
+        buf: &'static mut [u8],
+        _dst_pan: PanID,
+        _dst_addr: MacAddress,
+        _src_pan: PanID,
+        _src_addr: MacAddress,
+        _security_needed: Option<(SecurityLevel, KeyId)>,
+    ) -> Result<Frame, &'static mut [u8]> {
+        Err(buf)
+    }
+
+    fn transmit(&self, frame: Frame) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        Err((ErrorCode::NOSUPPORT, frame.into_buf()))
+    }
+}
+
+struct MockIpSender {
+    gateway: Cell<Option<MacAddress>>,
+}
+
+impl IP6Sender<'static> for MockIpSender {
+    fn set_client(&self, _client: &'static dyn IP6SendClient) {}
+
+    fn set_addr(&self, _src_addr: IPAddr) {}
+
+    fn set_gateway(&self, gateway: MacAddress) {
+        self.gateway.set(Some(gateway));
+    }
+
+    fn set_header(&mut self, _ip6_header: IP6Header) {}
+
+    fn send_to(
+        &self,
+        _dst: IPAddr,
+        _transport_header: TransportHeader,
+        _payload: &LeasableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+/// Leaves messages in the clear and accepts every MIC.
+struct ClearCcm {
+    client: OptionalCell<&'static dyn CCMClient>,
+    pending: TakeCell<'static, [u8]>,
+}
+
+impl Pending for ClearCcm {
+    fn complete(&self) -> bool {
+        match self.pending.take() {
+            Some(buf) => {
+                self.client
+                    .map(move |client| client.crypt_done(buf, Ok(()), true));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl AES128CCM<'static> for ClearCcm {
+    fn set_client(&'static self, client: &'static dyn CCMClient) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        assert_eq!(key, KEY);
+        Ok(())
+    }
+
+    fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode> {
+        assert_eq!(nonce.len(), CCM_NONCE_LENGTH);
+        Ok(())
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        _a_off: usize,
+        _m_off: usize,
+        _m_len: usize,
+        mic_len: usize,
+        _confidential: bool,
+        _encrypting: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        assert_eq!(mic_len, MIC_LEN);
+        if self.pending.is_some() {
+            return Err((ErrorCode::BUSY, buf));
+        }
+        self.pending.replace(buf);
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Attached([u8; 16], u16),
+    Detached,
+}
+
+struct Recorder {
+    events: Events<Event>,
+}
+
+impl MleClient for Recorder {
+    fn attached(&self, parent: IPAddr, rloc16: u16) {
+        self.events.push(Event::Attached(parent.0, rloc16));
+    }
+
+    fn detached(&self) {
+        self.events.push(Event::Detached);
+    }
+}
+
+/// An MLE message the device sent.
+struct Sent {
+    dst: IPAddr,
+    frame_counter: u32,
+    command: Option<MleCommand>,
+    tlvs: Vec<u8>,
+}
+
+impl Sent {
+    fn challenge(&self) -> Option<[u8; 8]> {
+        let mut challenge = None;
+        for_each_tlv(&self.tlvs, |tlv| {
+            if let Tlv::Challenge(c) = tlv {
+                challenge = Some(c);
+            }
+        });
+        challenge
+    }
+
+    fn response(&self) -> Option<[u8; 8]> {
+        let mut response = None;
+        for_each_tlv(&self.tlvs, |tlv| {
+            if let Tlv::Response(r) = tlv {
+                response = Some(r);
+            }
+        });
+        response
+    }
+
+    fn scan_mask(&self) -> Option<u8> {
+        let mut scan_mask = None;
+        for_each_tlv(&self.tlvs, |tlv| {
+            if let Tlv::ScanMask(mask) = tlv {
+                scan_mask = Some(mask);
+            }
+        });
+        scan_mask
+    }
+
+    fn timeout(&self) -> Option<u32> {
+        let mut timeout = None;
+        for_each_tlv(&self.tlvs, |tlv| {
+            if let Tlv::Timeout(t) = tlv {
+                timeout = Some(t);
+            }
+        });
+        timeout
+    }
+}
+
+type TestMle = MleSed<'static, EmulatedAlarm<'static>, ClearCcm>;
+
+struct Harness {
+    alarm: &'static EmulatedAlarm<'static>,
+    ccm: &'static ClearCcm,
+    udp: &'static MockUdp,
+    radio: &'static MockRadio,
+    ip_sender: &'static MockIpSender,
+    mle: &'static TestMle,
+    client: &'static Recorder,
+}
+
+impl Harness {
+    fn new() -> Harness {
+        let h = Harness::without_key();
+        h.mle.set_key(KEY, 0);
+        h
+    }
+
+    fn without_key() -> Harness {
+        let alarm = leak(EmulatedAlarm::new());
+        let ccm = leak(ClearCcm {
+            client: OptionalCell::empty(),
+            pending: TakeCell::empty(),
+        });
+        let udp = leak(MockUdp::new());
+        let radio = leak(MockRadio {
+            address: Cell::new(0xfffe),
+        });
+        let ip_sender = leak(MockIpSender {
+            gateway: Cell::new(None),
+        });
+        let rng = leak(CountingRng::new(0x00010203, 0x01010101));
+        let port_table = leak(UdpPortManager::unrestricted(leak_mut(
+            [None; MAX_NUM_BOUND_PORTS],
+        )));
+        let mle = leak(MleSed::new(
+            udp,
+            leak(UDPReceiver::new()),
+            port_table,
+            radio,
+            ip_sender,
+            ccm,
+            alarm,
+            rng,
+            leak_mut([0; MLE_BUF_SIZE]),
+            leak_mut([0; MLE_BUF_SIZE]),
+            leak(NetworkCapability::unrestricted()),
+        ));
+        let client = leak(Recorder {
+            events: Events::new(),
+        });
+        udp.set_client(mle);
+        ccm.set_client(mle);
+        alarm.set_alarm_client(mle);
+        mle.set_client(client);
+        Harness {
+            alarm,
+            ccm,
+            udp,
+            radio,
+            ip_sender,
+            mle,
+            client,
+        }
+    }
+
+    /// Completes pending operations until everything is idle.
+    fn run(&self) {
+        run(&[self.ccm, self.udp]);
+    }
+
+    fn advance(&self, ms: u32) {
+        self.alarm.advance(ms);
+        self.run();
+    }
+
+    /// Lets time pass until a Parent Request times out, whatever its
+    /// jitter.
+    fn parent_request_timeout(&self, timeout: u32) {
+        let remaining = self.alarm.remaining().unwrap();
+        assert!(remaining >= timeout && remaining < timeout + PARENT_REQUEST_MAX_JITTER_MS);
+        self.advance(remaining);
+    }
+
+    fn sent(&self) -> Vec<Sent> {
+        self.udp
+            .sent()
+            .into_iter()
+            .map(|datagram| {
+                assert_eq!(datagram.dst_port, MLE_PORT);
+                let dst = datagram.dst;
+                let dgram = datagram.payload;
+                assert_eq!(dgram[0], SECURITY_SUITE_154);
+                let (aux_len, security) = Security::decode(&dgram[1..]).done().unwrap();
+                assert_eq!(aux_len, AUX_HDR_LEN);
+                assert_eq!(security.level, MLE_SECURITY_LEVEL);
+                let msg = &dgram[1 + aux_len..dgram.len() - MIC_LEN];
+                Sent {
+                    dst,
+                    frame_counter: security.frame_counter.unwrap(),
+                    command: MleCommand::from_u8(msg[0]),
+                    tlvs: msg[1..].to_vec(),
+                }
+            })
+            .collect()
+    }
+
+    /// The one message sent since the last call, which must be `command`.
+    fn sent_one(&self, command: MleCommand) -> Sent {
+        let mut sent = self.sent();
+        assert_eq!(sent.len(), 1);
+        let sent = sent.pop().unwrap();
+        assert_eq!(sent.command, Some(command));
+        sent
+    }
+
+    fn events(&self) -> Vec<Event> {
+        self.client.events.take()
+    }
+
+    /// Delivers a secured MLE message from `src` and lets the device
+    /// handle it.
+    fn deliver(&self, src: IPAddr, frame_counter: u32, command: MleCommand, tlvs: &[Tlv]) {
+        let mut dgram = [0u8; MLE_BUF_SIZE];
+        dgram[0] = SECURITY_SUITE_154;
+        let security = Security {
+            level: MLE_SECURITY_LEVEL,
+            asn_in_nonce: false,
+            frame_counter: Some(frame_counter),
+            key_id: KeyId::Source4Index([0; 4], 1),
+        };
+        let (mut off, _) = security.encode(&mut dgram[1..]).done().unwrap();
+        off += 1;
+        dgram[off] = command as u8;
+        off += 1;
+        for tlv in tlvs.iter() {
+            let (len, _) = tlv.encode(&mut dgram[off..]).done().unwrap();
+            off += len;
+        }
+        // The MIC, which the model accepts whatever it is
+        off += MIC_LEN;
+        self.mle
+            .receive(src, own_addr(), MLE_PORT, MLE_PORT, &dgram[..off]);
+        self.run();
+    }
+
+    fn parent_response(&self, src: IPAddr, challenge: [u8; 8], link_margin: u8) {
+        self.deliver(
+            src,
+            1,
+            MleCommand::ParentResponse,
+            &[
+                Tlv::SourceAddress(PARENT_RLOC16),
+                LEADER_DATA.to_tlv(),
+                Tlv::Response(challenge),
+                Tlv::Challenge(PARENT_CHALLENGE),
+                Tlv::LinkMargin(link_margin),
+                Tlv::Connectivity {
+                    parent_priority: 0,
+                    link_quality_3: 1,
+                    link_quality_2: 0,
+                    link_quality_1: 0,
+                    leader_cost: 1,
+                    id_sequence: 1,
+                    active_routers: 2,
+                    sed_buffer_size: None,
+                    sed_datagram_count: None,
+                },
+                Tlv::Version(THREAD_VERSION),
+            ],
+        );
+    }
+
+    /// Attaches to the parent through the four-step handshake.
+    fn attach(&self) {
+        assert_eq!(self.mle.start(), Ok(()));
+        self.run();
+        let request = self.sent_one(MleCommand::ParentRequest);
+        assert_eq!(
+            self.mle.get_state(),
+            MleState::ParentRequest { reeds: false }
+        );
+        self.parent_response(parent_addr(), request.challenge().unwrap(), 30);
+
+        // Responses are collected until the Parent Request times out.
+        assert_eq!(self.sent().len(), 0);
+        self.parent_request_timeout(PARENT_REQUEST_ROUTER_TIMEOUT_MS);
+        let request = self.sent_one(MleCommand::ChildIdRequest);
+        assert_eq!(request.dst, parent_addr());
+        assert_eq!(request.response(), Some(PARENT_CHALLENGE));
+        assert_eq!(self.mle.get_state(), MleState::ChildIdRequest);
+
+        self.deliver(
+            parent_addr(),
+            2,
+            MleCommand::ChildIdResponse,
+            &[
+                Tlv::SourceAddress(PARENT_RLOC16),
+                Tlv::Address16(RLOC16),
+                LEADER_DATA.to_tlv(),
+            ],
+        );
+        assert_eq!(self.mle.get_state(), MleState::Child { rloc16: RLOC16 });
+        assert_eq!(self.events(), [Event::Attached(parent_addr().0, RLOC16)]);
+    }
+}
+
+#[test]
+fn attach() {
+    let h = Harness::new();
+    assert_eq!(h.mle.start(), Ok(()));
+    h.run();
+    let request = h.sent_one(MleCommand::ParentRequest);
+    assert_eq!(request.dst, LINK_LOCAL_ALL_ROUTERS);
+    assert_eq!(request.frame_counter, 0);
+    assert_eq!(request.scan_mask(), Some(MulticastResponder::Router as u8));
+
+    // A response to another challenge is ignored, and a worse parent loses
+    // against the better one.
+    let challenge = request.challenge().unwrap();
+    let mut other = parent_addr();
+    other.0[15] ^= 1;
+    h.parent_response(other, [0; 8], 40);
+    h.parent_response(other, challenge, 5);
+    h.parent_response(parent_addr(), challenge, 30);
+
+    h.parent_request_timeout(PARENT_REQUEST_ROUTER_TIMEOUT_MS);
+    let request = h.sent_one(MleCommand::ChildIdRequest);
+    assert_eq!(request.dst, parent_addr());
+    assert_eq!(request.frame_counter, 1);
+    assert_eq!(request.response(), Some(PARENT_CHALLENGE));
+    assert_eq!(request.timeout(), Some(DEFAULT_CHILD_TIMEOUT_S));
+
+    // The Child ID Response must come from the selected parent.
+    let response = [Tlv::Address16(RLOC16), LEADER_DATA.to_tlv()];
+    h.deliver(other, 2, MleCommand::ChildIdResponse, &response);
+    assert_eq!(h.mle.get_state(), MleState::ChildIdRequest);
+    h.deliver(parent_addr(), 2, MleCommand::ChildIdResponse, &response);
+    assert_eq!(h.mle.get_state(), MleState::Child { rloc16: RLOC16 });
+    assert!(h.mle.is_attached());
+    assert_eq!(h.events(), [Event::Attached(parent_addr().0, RLOC16)]);
+    assert_eq!(h.radio.get_address(), RLOC16);
+    assert_eq!(
+        h.ip_sender.gateway.get(),
+        Some(MacAddress::Long(PARENT_EXT_ADDR))
+    );
+    assert_eq!(
+        h.alarm.remaining(),
+        Some(DEFAULT_CHILD_TIMEOUT_S * 1000 / 4)
+    );
+}
+
+#[test]
+fn attach_needs_a_key() {
+    let h = Harness::without_key();
+    assert_eq!(h.mle.start(), Err(ErrorCode::OFF));
+    h.mle.set_key(KEY, 0);
+    assert_eq!(h.mle.start(), Ok(()));
+    assert_eq!(h.mle.start(), Err(ErrorCode::ALREADY));
+}
+
+#[test]
+fn failed_attach_backs_off() {
+    let h = Harness::new();
+    assert_eq!(h.mle.start(), Ok(()));
+    h.run();
+    for _ in 0..MAX_ATTACH_ATTEMPTS {
+        let request = h.sent_one(MleCommand::ParentRequest);
+        assert_eq!(request.scan_mask(), Some(MulticastResponder::Router as u8));
+        assert_eq!(h.mle.get_state(), MleState::ParentRequest { reeds: false });
+
+        // Without routers, router-eligible end devices are asked too.
+        h.parent_request_timeout(PARENT_REQUEST_ROUTER_TIMEOUT_MS);
+        let request = h.sent_one(MleCommand::ParentRequest);
+        assert_eq!(
+            request.scan_mask(),
+            Some(MulticastResponder::Router as u8 | MulticastResponder::EndDevice as u8)
+        );
+        assert_eq!(h.mle.get_state(), MleState::ParentRequest { reeds: true });
+        assert_eq!(h.events(), []);
+        h.parent_request_timeout(PARENT_REQUEST_REED_TIMEOUT_MS);
+    }
+    assert_eq!(h.mle.get_state(), MleState::Backoff);
+    assert_eq!(h.events(), [Event::Detached]);
+    assert_eq!(h.alarm.remaining(), Some(ATTACH_BACKOFF_MS));
+
+    h.advance(ATTACH_BACKOFF_MS);
+    h.sent_one(MleCommand::ParentRequest);
+    assert_eq!(h.mle.get_state(), MleState::ParentRequest { reeds: false });
+}
+
+#[test]
+fn rejected_child_id_request_retries() {
+    let h = Harness::new();
+    assert_eq!(h.mle.start(), Ok(()));
+    h.run();
+    let request = h.sent_one(MleCommand::ParentRequest);
+    h.parent_response(parent_addr(), request.challenge().unwrap(), 30);
+    h.parent_request_timeout(PARENT_REQUEST_ROUTER_TIMEOUT_MS);
+    h.sent_one(MleCommand::ChildIdRequest);
+
+    h.deliver(
+        parent_addr(),
+        2,
+        MleCommand::ChildIdResponse,
+        &[Tlv::Status(1)],
+    );
+    h.sent_one(MleCommand::ParentRequest);
+    assert_eq!(h.mle.get_state(), MleState::ParentRequest { reeds: false });
+    assert_eq!(h.events(), []);
+}
+
+#[test]
+fn keepalive() {
+    let h = Harness::new();
+    h.attach();
+    let interval = DEFAULT_CHILD_TIMEOUT_S * 1000 / 4;
+    for frame_counter in 3..6 {
+        h.advance(interval);
+        let request = h.sent_one(MleCommand::ChildUpdateRequest);
+        assert_eq!(request.dst, parent_addr());
+        assert_eq!(request.timeout(), Some(DEFAULT_CHILD_TIMEOUT_S));
+        assert_eq!(h.alarm.remaining(), Some(CHILD_UPDATE_RESPONSE_TIMEOUT_MS));
+
+        // Replayed responses and ones to another challenge are ignored.
+        let challenge = request.challenge().unwrap();
+        h.deliver(
+            parent_addr(),
+            frame_counter - 1,
+            MleCommand::ChildUpdateResponse,
+            &[Tlv::Response(challenge)],
+        );
+        h.deliver(
+            parent_addr(),
+            frame_counter,
+            MleCommand::ChildUpdateResponse,
+            &[Tlv::Response([0; 8])],
+        );
+        assert_eq!(h.alarm.remaining(), Some(CHILD_UPDATE_RESPONSE_TIMEOUT_MS));
+
+        h.deliver(
+            parent_addr(),
+            frame_counter,
+            MleCommand::ChildUpdateResponse,
+            &[Tlv::Response(challenge), LEADER_DATA.to_tlv()],
+        );
+        assert_eq!(h.alarm.remaining(), Some(interval));
+        assert_eq!(h.mle.get_state(), MleState::Child { rloc16: RLOC16 });
+    }
+    assert_eq!(h.events(), []);
+}
+
+#[test]
+fn parent_lost_after_missed_keepalives() {
+    let h = Harness::new();
+    h.attach();
+    h.advance(DEFAULT_CHILD_TIMEOUT_S * 1000 / 4);
+    h.sent_one(MleCommand::ChildUpdateRequest);
+    for _ in 1..MAX_KEEPALIVE_FAILURES {
+        h.advance(CHILD_UPDATE_RESPONSE_TIMEOUT_MS);
+        h.sent_one(MleCommand::ChildUpdateRequest);
+        assert!(h.mle.is_attached());
+    }
+    assert_eq!(h.events(), []);
+
+    h.advance(CHILD_UPDATE_RESPONSE_TIMEOUT_MS);
+    assert_eq!(h.events(), [Event::Detached]);
+    let request = h.sent_one(MleCommand::ParentRequest);
+    assert_eq!(request.dst, LINK_LOCAL_ALL_ROUTERS);
+    assert_eq!(h.mle.get_state(), MleState::ParentRequest { reeds: false });
+}
+
+#[test]
+fn stop_detaches() {
+    let h = Harness::new();
+    h.attach();
+    h.mle.stop();
+    assert_eq!(h.mle.get_state(), MleState::Disabled);
+    assert_eq!(h.events(), [Event::Detached]);
+    assert_eq!(h.alarm.remaining(), None);
+    assert_eq!(h.mle.start(), Ok(()));
+}
+
+#[test]
+fn long_child_timeouts_saturate() {
+    let h = Harness::new();
+    h.mle.set_child_timeout(u32::MAX);
+    h.attach();
+    assert_eq!(h.alarm.remaining(), Some(u32::MAX / 4));
+
+    h.advance(u32::MAX / 4);
+    let request = h.sent_one(MleCommand::ChildUpdateRequest);
+    assert_eq!(request.timeout(), Some(u32::MAX));
+}
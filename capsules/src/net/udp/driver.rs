@@ -5,14 +5,27 @@
 //! and bind to UDP ports for receiving packets.
 //! Also exposes a list of interface addresses to the application (currently
 //! hard-coded).
+//!
+//! Bound apps can join multicast groups, restrict the remote address and
+//! port they receive from, and opt into a receive queue, so that packets
+//! arriving while the app is still processing an earlier one are not lost.
+//! Queued packets are kept in a buffer the app allows for this purpose, so
+//! apps that do not use the queue pay nothing for it.
+//!
+//! If the board provides a DTLS session, one app at a time can use it to
+//! exchange encrypted datagrams with a server (commands 10 to 12). The session
+//! is released when that app faults or is restarted.
 
+use crate::net::dtls::{DtlsClient, DtlsTransport};
 use crate::net::ipv6::ip_utils::IPAddr;
-use crate::net::network_capabilities::NetworkCapability;
+use crate::net::network_capabilities::{
+    IpVisibilityCapability, NetworkCapability, UdpVisibilityCapability,
+};
 use crate::net::stream::encode_u16;
 use crate::net::stream::encode_u8;
 use crate::net::stream::SResult;
 use crate::net::udp::udp_port_table::{PortQuery, UdpPortManager};
-use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_recv::{MulticastGroups, UDPRecvClient};
 use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
 use crate::net::util::host_slice_to_u16;
 use core::cell::Cell;
@@ -21,7 +34,7 @@ use core::convert::TryInto;
 use core::mem::size_of;
 use core::{cmp, mem};
 use kernel::capabilities::UdpDriverCapability;
-use kernel::common::cells::MapCell;
+use kernel::common::cells::{MapCell, OptionalCell};
 use kernel::common::leasable_buffer::LeasableBuffer;
 use kernel::{
     debug, CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
@@ -31,6 +44,19 @@ use kernel::{
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Udp as usize;
 
+/// Every packet in the receive queue buffer is preceded by its length
+/// (2 bytes, little endian), the sender address (16 bytes) and the sender
+/// port (2 bytes, little endian).
+pub const RX_QUEUE_HEADER_LEN: usize = 20;
+
+/// Events passed as the first argument of the DTLS callback
+pub mod dtls_event {
+    pub const CONNECTED: usize = 0;
+    pub const RECEIVED: usize = 1;
+    pub const SEND_DONE: usize = 2;
+    pub const CLOSED: usize = 3;
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct UDPEndpoint {
     addr: IPAddr,
@@ -64,6 +90,13 @@ impl UDPEndpoint {
     pub fn is_zero(&self) -> bool {
         self.addr.is_unspecified() && self.port == 0
     }
+
+    /// Checks whether `other` matches this endpoint used as a filter, where
+    /// the unspecified address and port 0 match anything.
+    pub fn matches(&self, other: &UDPEndpoint) -> bool {
+        (self.addr.is_unspecified() || self.addr == other.addr)
+            && (self.port == 0 || self.port == other.port)
+    }
 }
 
 #[derive(Default)]
@@ -76,6 +109,151 @@ pub struct App {
     app_rx_cfg: ReadWriteAppSlice,
     pending_tx: Option<[UDPEndpoint; 2]>,
     bound_port: Option<UDPEndpoint>,
+    remote_filter: Option<UDPEndpoint>,
+    groups: MulticastGroups,
+    rx_queueing: bool,
+    /// A packet was delivered and the app has not signalled it is done
+    /// with it yet
+    rx_pending: bool,
+    /// Packets waiting for the app to process the previous one, see
+    /// `RX_QUEUE_HEADER_LEN`
+    app_rx_queue: ReadWriteAppSlice,
+    /// Bytes of `app_rx_queue` in use
+    rx_queue_used: usize,
+    dtls_callback: Upcall,
+    /// `[identity length][identity][key]`
+    app_psk: ReadOnlyAppSlice,
+}
+
+/// Copies a received packet into the read buffer, writes the sender into
+/// the rx config buffer and notifies the app. Returns false if the packet
+/// does not fit.
+fn deliver(
+    app_read: &mut ReadWriteAppSlice,
+    app_rx_cfg: &mut ReadWriteAppSlice,
+    rx_callback: &mut Upcall,
+    sender: UDPEndpoint,
+    payload: &[u8],
+) -> bool {
+    let len = payload.len();
+    let res = app_read.mut_map_or(Ok(()), |rbuf| {
+        if rbuf.len() >= len {
+            rbuf[..len].copy_from_slice(&payload[..len]);
+            Ok(())
+        } else {
+            Err(ErrorCode::SIZE) //packet does not fit
+        }
+    });
+    if res.is_err() {
+        return false;
+    }
+    // Write address of sender into rx_cfg so it can be read by client
+    rx_callback.schedule(len, 0, 0);
+    let cfg_len = 2 * size_of::<UDPEndpoint>();
+    let _ = app_rx_cfg.mut_map_or(Err(ErrorCode::INVAL), |cfg| {
+        if cfg.len() != cfg_len {
+            return Err(ErrorCode::INVAL);
+        }
+        sender.encode(cfg, 0);
+        Ok(())
+    });
+    true
+}
+
+impl App {
+    fn deliver(&mut self, sender: UDPEndpoint, payload: &[u8]) -> bool {
+        deliver(
+            &mut self.app_read,
+            &mut self.app_rx_cfg,
+            &mut self.rx_callback,
+            sender,
+            payload,
+        )
+    }
+
+    /// Appends a received packet to the queue buffer. It is dropped if the
+    /// buffer is full.
+    fn enqueue(&mut self, sender: UDPEndpoint, payload: &[u8]) {
+        let used = self.rx_queue_used;
+        let end = used + RX_QUEUE_HEADER_LEN + payload.len();
+        let queued = self.app_rx_queue.mut_map_or(false, |queue| {
+            if end > queue.len() || payload.len() > u16::MAX as usize {
+                return false;
+            }
+            let header = &mut queue[used..used + RX_QUEUE_HEADER_LEN];
+            header[0..2].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+            header[2..18].copy_from_slice(&sender.addr.0);
+            header[18..20].copy_from_slice(&sender.port.to_le_bytes());
+            queue[used + RX_QUEUE_HEADER_LEN..end].copy_from_slice(payload);
+            true
+        });
+        if queued {
+            self.rx_queue_used = end;
+        }
+    }
+
+    /// Delivers the oldest queued packet, if there is one.
+    fn deliver_next(&mut self) {
+        self.rx_pending = false;
+        let App {
+            app_read,
+            app_rx_cfg,
+            rx_callback,
+            app_rx_queue,
+            rx_queue_used,
+            rx_pending,
+            ..
+        } = self;
+        let used = *rx_queue_used;
+        *rx_queue_used = app_rx_queue.mut_map_or(0, |queue| {
+            let mut used = cmp::min(used, queue.len());
+            while used >= RX_QUEUE_HEADER_LEN {
+                let header = &queue[..RX_QUEUE_HEADER_LEN];
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let end = RX_QUEUE_HEADER_LEN + len;
+                if end > used {
+                    // The app overwrote the queue
+                    return 0;
+                }
+                let mut sender = UDPEndpoint {
+                    addr: IPAddr::new(),
+                    port: u16::from_le_bytes([header[18], header[19]]),
+                };
+                sender.addr.0.copy_from_slice(&header[2..18]);
+                let delivered = deliver(
+                    app_read,
+                    app_rx_cfg,
+                    rx_callback,
+                    sender,
+                    &queue[RX_QUEUE_HEADER_LEN..end],
+                );
+                queue.copy_within(end..used, 0);
+                used -= end;
+                if delivered {
+                    *rx_pending = true;
+                    break;
+                }
+            }
+            used
+        });
+    }
+
+    fn clear_rx_queue(&mut self) {
+        self.rx_queue_used = 0;
+        self.rx_pending = false;
+    }
+
+    /// Reads the address at the start of the config buffer.
+    fn cfg_addr(&self) -> Option<IPAddr> {
+        self.app_cfg.map_or(None, |cfg| {
+            if cfg.len() < size_of::<IPAddr>() {
+                return None;
+            }
+            let mut addr = IPAddr::new();
+            addr.0.copy_from_slice(&cfg.as_ref()[..size_of::<IPAddr>()]);
+            Some(addr)
+        })
+    }
 }
 
 #[allow(dead_code)]
@@ -102,6 +280,14 @@ pub struct UDPDriver<'a> {
     driver_send_cap: &'static dyn UdpDriverCapability,
 
     net_cap: &'static NetworkCapability,
+
+    ip_vis: &'static IpVisibilityCapability,
+
+    udp_vis: &'static UdpVisibilityCapability,
+
+    dtls: OptionalCell<&'a dyn DtlsTransport<'a>>,
+    /// App using the DTLS session
+    dtls_app: OptionalCell<ProcessId>,
 }
 
 impl<'a> UDPDriver<'a> {
@@ -114,6 +300,8 @@ impl<'a> UDPDriver<'a> {
         kernel_buffer: LeasableBuffer<'static, u8>,
         driver_send_cap: &'static dyn UdpDriverCapability,
         net_cap: &'static NetworkCapability,
+        ip_vis: &'static IpVisibilityCapability,
+        udp_vis: &'static UdpVisibilityCapability,
     ) -> UDPDriver<'a> {
         UDPDriver {
             sender: sender,
@@ -125,6 +313,60 @@ impl<'a> UDPDriver<'a> {
             kernel_buffer: MapCell::new(kernel_buffer),
             driver_send_cap: driver_send_cap,
             net_cap: net_cap,
+            ip_vis: ip_vis,
+            udp_vis: udp_vis,
+            dtls: OptionalCell::empty(),
+            dtls_app: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_dtls(&self, dtls: &'a dyn DtlsTransport<'a>) {
+        self.dtls.set(dtls);
+    }
+
+    /// Runs `f` on the DTLS session if `appid` may use it. Apps can use the
+    /// session unless another app has connected it.
+    fn with_dtls<F>(&self, appid: ProcessId, f: F) -> Result<(), ErrorCode>
+    where
+        F: FnOnce(&'a dyn DtlsTransport<'a>) -> Result<(), ErrorCode>,
+    {
+        let dtls = self.dtls.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        self.release_dtls_of_dead_app();
+        if self.dtls_app.map_or(false, |owner| *owner != appid) {
+            return Err(ErrorCode::BUSY);
+        }
+        f(dtls)
+    }
+
+    /// Schedules the DTLS callback of the app using the session.
+    fn dtls_event(&self, event: usize, result: Result<(), ErrorCode>, len: usize) {
+        self.dtls_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app| {
+                app.dtls_callback
+                    .schedule(event, kernel::into_statuscode(result), len);
+            });
+        });
+        self.release_dtls_of_dead_app();
+    }
+
+    /// Closes the DTLS session if the app using it faulted or was restarted,
+    /// so that other apps can use it.
+    fn release_dtls_of_dead_app(&self) {
+        let dead =
+            self.dtls_app
+                .map_or(false, |appid| match self.apps.enter(*appid, |_| ()) {
+                    Err(kernel::procs::Error::NoSuchApp)
+                    | Err(kernel::procs::Error::InactiveApp) => true,
+                    _ => false,
+                });
+        if dead {
+            self.dtls.map(|dtls| {
+                let connected = dtls.is_connected();
+                // An established session is released by closed()
+                if dtls.close().is_ok() && !connected {
+                    self.dtls_app.clear();
+                }
+            });
         }
     }
 
@@ -292,6 +534,9 @@ impl<'a> Driver for UDPDriver<'a> {
     /// - `2`: Rx config buffer. Used to contain source/destination addresses
     ///        and ports for receives (separate from `2` because receives may
     ///        be waiting for an incoming packet asynchronously).
+    /// - `3`: Receive queue buffer. Holds the packets queued while the receive
+    ///        queue is enabled, each taking `RX_QUEUE_HEADER_LEN` bytes plus
+    ///        its payload. Allowing a new buffer drops the queued packets.
     fn allow_readwrite(
         &self,
         appid: ProcessId,
@@ -313,6 +558,12 @@ impl<'a> Driver for UDPDriver<'a> {
                     mem::swap(&mut app.app_rx_cfg, &mut slice);
                     Ok(())
                 }
+                3 => {
+                    mem::swap(&mut app.app_rx_queue, &mut slice);
+                    // Packets queued in the old buffer are dropped
+                    app.rx_queue_used = 0;
+                    Ok(())
+                }
                 _ => Err(ErrorCode::NOSUPPORT),
             })
             .map_err(ErrorCode::from);
@@ -331,6 +582,8 @@ impl<'a> Driver for UDPDriver<'a> {
     /// - `0`: Write buffer. Contains the UDP payload to be transmitted.
     ///        Returns SIZE if the passed buffer is too long, and NOSUPPORT
     ///        if an invalid `allow_num` is passed.
+    /// - `1`: DTLS pre-shared key. One byte holding the length of the
+    ///        identity, the identity, and the key.
     fn allow_readonly(
         &self,
         appid: ProcessId,
@@ -349,6 +602,13 @@ impl<'a> Driver for UDPDriver<'a> {
                     }
                 })
                 .map_err(ErrorCode::from),
+            1 => self
+                .apps
+                .enter(appid, |app| {
+                    mem::swap(&mut app.app_psk, &mut slice);
+                    Ok(())
+                })
+                .map_err(ErrorCode::from),
             _ => Err(ErrorCode::NOSUPPORT),
         };
 
@@ -370,6 +630,10 @@ impl<'a> Driver for UDPDriver<'a> {
     ///        this callback receives the result of the send_done callback
     ///        from udp_send.rs, which does not currently pass information
     ///        regarding whether packets were acked at the link layer.
+    /// - `2`: Setup callback for DTLS session events. Its arguments are the
+    ///        event (see `dtls_event`), the status and, for received
+    ///        datagrams, the length of the payload copied into the read
+    ///        buffer.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -394,9 +658,13 @@ impl<'a> Driver for UDPDriver<'a> {
                     },
                 }
             }
-            1 => {
+            1 | 2 => {
                 let res = self.apps.enter(app_id, |app| {
-                    mem::swap(&mut app.tx_callback, &mut callback);
+                    if subscribe_num == 1 {
+                        mem::swap(&mut app.tx_callback, &mut callback);
+                    } else {
+                        mem::swap(&mut app.dtls_callback, &mut callback);
+                    }
                 });
                 if let Err(e) = res {
                     Err((callback, e.into()))
@@ -453,7 +721,39 @@ impl<'a> Driver for UDPDriver<'a> {
     ///        /// - `4`: Returns the maximum payload that can be transmitted by apps using this driver.
     ///        This represents the size of the payload buffer in the kernel. Apps can use this
     ///        syscall to ensure they do not attempt to send too-large messages.
-
+    /// - `5`: Join the multicast group whose address is at the start of the cfg buffer.
+    ///        Packets sent to a joined group on the bound port are received like
+    ///        unicast packets; the all-nodes groups (ff01::1, ff02::1) are always
+    ///        received. Returns INVAL if the address is not a multicast address,
+    ///        ALREADY if the group was joined before and NOMEM if too many groups are
+    ///        joined.
+    /// - `6`: Leave the multicast group whose address is at the start of the cfg buffer.
+    ///        Returns INVAL if the group was not joined.
+    /// - `7`: Enable (`arg1` != 0) or disable (`arg1` == 0) the receive queue. While it is
+    ///        enabled, packets that arrive before the app called command `8` for the
+    ///        previous packet are queued in the kernel instead of overwriting the read
+    ///        buffer. Packets are queued in the receive queue buffer; packets that do not
+    ///        fit are dropped.
+    /// - `8`: Signal that the app is done with the last received packet. The next queued
+    ///        packet, if any, is copied into the read buffer and the receive callback
+    ///        is scheduled again. Returns INVAL if the receive queue is disabled.
+    /// - `9`: Only receive packets from the address/port pair in the first half of the
+    ///        cfg buffer. The unspecified address and port 0 match any address and port,
+    ///        so an all-zero pair removes the filter. The filter is removed when the
+    ///        app unbinds. Returns RESERVE if the app is not bound.
+    /// - `10`: Open a DTLS session to the address/port pair in the second half of
+    ///        the cfg buffer, using the pre-shared key in allow buffer `1`. The
+    ///        DTLS callback reports CONNECTED when the handshake is done. Returns
+    ///        NOSUPPORT if the board has no DTLS session, BUSY if another app is
+    ///        using it or a session is open, and INVAL if the cfg or key buffer
+    ///        is malformed or the driver's network capability does not allow
+    ///        the server.
+    /// - `11`: Send the contents of the write buffer over the DTLS session. The
+    ///        DTLS callback reports SEND_DONE. Datagrams from the server are
+    ///        copied into the read buffer and reported as RECEIVED. Returns OFF
+    ///        if the session is not established.
+    /// - `12`: Close the DTLS session. An established session reports CLOSED once
+    ///        the server has been notified.
     fn command(
         &self,
         command_num: usize,
@@ -553,6 +853,8 @@ impl<'a> Driver for UDPDriver<'a> {
                             // If zero address, close any already bound socket
                             if requested_addr.is_zero() {
                                 app.bound_port = None;
+                                app.remote_filter = None;
+                                app.clear_rx_queue();
                                 return Ok(None);
                             }
                             // Check that requested addr is a local interface
@@ -597,6 +899,127 @@ impl<'a> Driver for UDPDriver<'a> {
                 }
             }
             4 => CommandReturn::success_u32(self.max_tx_pyld_len as u32),
+            5 | 6 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app| {
+                        let group = app.cfg_addr().ok_or(ErrorCode::INVAL)?;
+                        if command_num == 5 {
+                            app.groups.join(group)
+                        } else {
+                            app.groups.leave(group)
+                        }
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+            7 => self
+                .apps
+                .enter(appid, |app| {
+                    app.rx_queueing = arg1 != 0;
+                    if !app.rx_queueing {
+                        app.clear_rx_queue();
+                    }
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+            8 => self
+                .apps
+                .enter(appid, |app| {
+                    if !app.rx_queueing {
+                        return CommandReturn::failure(ErrorCode::INVAL);
+                    }
+                    app.deliver_next();
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+            9 => self
+                .apps
+                .enter(appid, |app| {
+                    if app.bound_port.is_none() {
+                        return CommandReturn::failure(ErrorCode::RESERVE);
+                    }
+                    let filter = app.app_cfg.map_or(None, |cfg| {
+                        if cfg.len() < size_of::<UDPEndpoint>() {
+                            None
+                        } else {
+                            self.parse_ip_port_pair(&cfg.as_ref()[..size_of::<UDPEndpoint>()])
+                        }
+                    });
+                    match filter {
+                        Some(filter) => {
+                            app.remote_filter = if filter.is_zero() { None } else { Some(filter) };
+                            CommandReturn::success()
+                        }
+                        None => CommandReturn::failure(ErrorCode::INVAL),
+                    }
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+            10 => {
+                let res = self.with_dtls(appid, |dtls| {
+                    self.do_with_app(appid, |app| {
+                        let server = app.app_cfg.map_or(None, |cfg| {
+                            if cfg.len() != 2 * size_of::<UDPEndpoint>() {
+                                None
+                            } else {
+                                self.parse_ip_port_pair(&cfg.as_ref()[size_of::<UDPEndpoint>()..])
+                            }
+                        });
+                        let server = server.ok_or(ErrorCode::INVAL)?;
+                        if !self.net_cap.remote_addr_valid(server.addr, self.ip_vis)
+                            || !self.net_cap.remote_port_valid(server.port, self.udp_vis)
+                        {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        app.app_psk.map_or(Err(ErrorCode::INVAL), |psk| {
+                            let psk = psk.as_ref();
+                            let id_len = *psk.first().ok_or(ErrorCode::INVAL)? as usize;
+                            if psk.len() <= 1 + id_len {
+                                return Err(ErrorCode::INVAL);
+                            }
+                            dtls.set_psk(&psk[1..1 + id_len], &psk[1 + id_len..])
+                        })?;
+                        dtls.connect(server.addr, server.port)
+                    })
+                });
+                match res {
+                    Ok(()) => {
+                        self.dtls_app.set(appid);
+                        CommandReturn::success()
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+            11 => {
+                let res = self.with_dtls(appid, |dtls| {
+                    self.do_with_app(appid, |app| {
+                        app.app_write
+                            .map_or(Err(ErrorCode::INVAL), |payload| dtls.send(payload.as_ref()))
+                    })
+                });
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+            12 => {
+                let res = self.with_dtls(appid, |dtls| {
+                    let connected = dtls.is_connected();
+                    dtls.close()?;
+                    if !connected {
+                        // An aborted handshake does not report closed()
+                        self.dtls_app.clear();
+                    }
+                    Ok(())
+                });
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -627,40 +1050,24 @@ impl<'a> UDPRecvClient for UDPDriver<'a> {
         dst_port: u16,
         payload: &[u8],
     ) {
+        let sender = UDPEndpoint {
+            addr: src_addr,
+            port: src_port,
+        };
         self.apps.each(|_, app| {
-            if app.bound_port.is_some() {
-                let mut for_me = false;
-                app.bound_port.as_ref().map(|requested_addr| {
-                    if requested_addr.addr == dst_addr && requested_addr.port == dst_port {
-                        for_me = true;
-                    }
-                });
-                if for_me {
-                    let len = payload.len();
-                    let res = app.app_read.mut_map_or(Ok(()), |rbuf| {
-                        if rbuf.len() >= len {
-                            rbuf[..len].copy_from_slice(&payload[..len]);
-                            Ok(())
-                        } else {
-                            Err(ErrorCode::SIZE) //packet does not fit
-                        }
-                    });
-                    if res.is_ok() {
-                        // Write address of sender into rx_cfg so it can be read by client
-                        let sender_addr = UDPEndpoint {
-                            addr: src_addr,
-                            port: src_port,
-                        };
-                        app.rx_callback.schedule(len, 0, 0);
-                        let cfg_len = 2 * size_of::<UDPEndpoint>();
-                        let _ = app.app_rx_cfg.mut_map_or(Err(ErrorCode::INVAL), |cfg| {
-                            if cfg.len() != cfg_len {
-                                return Err(ErrorCode::INVAL);
-                            }
-                            sender_addr.encode(cfg, 0);
-                            Ok(())
-                        });
-                    }
+            let for_me = app.bound_port.map_or(false, |requested_addr| {
+                requested_addr.port == dst_port
+                    && (requested_addr.addr == dst_addr
+                        || (dst_addr.is_multicast() && app.groups.accepts(dst_addr)))
+            }) && app
+                .remote_filter
+                .map_or(true, |filter| filter.matches(&sender));
+            if for_me {
+                if app.rx_queueing && app.rx_pending {
+                    // The app is still processing an earlier packet
+                    app.enqueue(sender, payload);
+                } else if app.deliver(sender, payload) && app.rx_queueing {
+                    app.rx_pending = true;
                 }
             }
         });
@@ -685,3 +1092,36 @@ impl<'a> PortQuery for UDPDriver<'a> {
         port_bound
     }
 }
+
+impl<'a> DtlsClient for UDPDriver<'a> {
+    fn connected(&self, result: Result<(), ErrorCode>) {
+        self.dtls_event(dtls_event::CONNECTED, result, 0);
+        if result.is_err() {
+            self.dtls_app.clear();
+        }
+    }
+
+    fn received(&self, payload: &[u8]) {
+        let res = self.dtls_app.map_or(Err(ErrorCode::FAIL), |appid| {
+            self.do_with_app(*appid, |app| {
+                app.app_read.mut_map_or(Err(ErrorCode::SIZE), |rbuf| {
+                    if rbuf.len() < payload.len() {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    rbuf[..payload.len()].copy_from_slice(payload);
+                    Ok(())
+                })
+            })
+        });
+        self.dtls_event(dtls_event::RECEIVED, res, payload.len());
+    }
+
+    fn send_done(&self, result: Result<(), ErrorCode>) {
+        self.dtls_event(dtls_event::SEND_DONE, result, 0);
+    }
+
+    fn closed(&self, result: Result<(), ErrorCode>) {
+        self.dtls_event(dtls_event::CLOSED, result, 0);
+        self.dtls_app.clear();
+    }
+}
@@ -144,6 +144,21 @@ impl UdpPortBindingRx {
     }
 }
 
+/// Capsule unit tests cannot create a `CreatePortTableCapability` since that
+/// requires `unsafe`.
+#[cfg(test)]
+impl UdpPortManager {
+    pub(crate) fn unrestricted(
+        used_kernel_ports: &'static mut [Option<SocketBindingEntry>],
+    ) -> UdpPortManager {
+        UdpPortManager {
+            port_array: TakeCell::new(used_kernel_ports),
+            user_ports: OptionalCell::empty(),
+            udp_vis: UdpVisibilityCapability::unrestricted(),
+        }
+    }
+}
+
 impl UdpPortManager {
     // Require capability so that the port table is only created by kernel
     pub fn new(
@@ -4,6 +4,13 @@
 //! appropriate capsule / app. Once again, port binding for userspace apps is managed seperately
 //! by the UDP userspace driver, which must correctly check bindings of kernel apps to ensure
 //! correctness when dispatching received packets to the appropriate client.
+//!
+//! Receivers that never joined a multicast group get every packet sent to
+//! their port, whatever the destination address, as they always have. Once a
+//! receiver joins a group (see `UDPReceiver::join_group`), packets sent to a
+//! multicast address are only dispatched to it if it joined that group or the
+//! address is an all-nodes address. Receivers can additionally restrict the
+//! remote address and port they accept packets from.
 
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::ipv6::ipv6_recv::IP6RecvClient;
@@ -11,9 +18,58 @@ use crate::net::ipv6::IP6Header;
 use crate::net::udp::driver::UDPDriver;
 use crate::net::udp::udp_port_table::{PortQuery, UdpPortBindingRx};
 use crate::net::udp::UDPHeader;
+use core::cell::Cell;
 use kernel::common::cells::{MapCell, OptionalCell};
 use kernel::common::{List, ListLink, ListNode};
 use kernel::debug;
+use kernel::ErrorCode;
+
+/// Maximum number of multicast groups a socket can join
+pub const MAX_MULTICAST_GROUPS: usize = 4;
+
+/// The multicast groups joined by a socket.
+#[derive(Copy, Clone, Default)]
+pub struct MulticastGroups {
+    groups: [Option<IPAddr>; MAX_MULTICAST_GROUPS],
+}
+
+impl MulticastGroups {
+    /// Returns INVAL if `group` is not a multicast address, ALREADY if it
+    /// was joined before and NOMEM if `MAX_MULTICAST_GROUPS` groups are
+    /// joined already.
+    pub fn join(&mut self, group: IPAddr) -> Result<(), ErrorCode> {
+        if !group.is_multicast() {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.groups.contains(&Some(group)) {
+            return Err(ErrorCode::ALREADY);
+        }
+        let slot = self
+            .groups
+            .iter_mut()
+            .find(|g| g.is_none())
+            .ok_or(ErrorCode::NOMEM)?;
+        *slot = Some(group);
+        Ok(())
+    }
+
+    /// Returns INVAL if `group` was not joined.
+    pub fn leave(&mut self, group: IPAddr) -> Result<(), ErrorCode> {
+        let slot = self
+            .groups
+            .iter_mut()
+            .find(|g| **g == Some(group))
+            .ok_or(ErrorCode::INVAL)?;
+        *slot = None;
+        Ok(())
+    }
+
+    /// Whether packets sent to `dst_addr` are meant for this socket, as
+    /// far as multicast is concerned.
+    pub fn accepts(&self, dst_addr: IPAddr) -> bool {
+        !dst_addr.is_multicast() || dst_addr.is_all_nodes() || self.groups.contains(&Some(dst_addr))
+    }
+}
 
 pub struct MuxUdpReceiver<'a> {
     rcvr_list: List<'a, UDPReceiver<'a>>,
@@ -51,15 +107,21 @@ impl<'a> IP6RecvClient for MuxUdpReceiver<'a> {
                     match rcvr.binding.take() {
                         Some(binding) => {
                             if binding.get_port() == dst_port {
-                                rcvr.client.map(|client| {
-                                    client.receive(
-                                        ip_header.get_src_addr(),
-                                        ip_header.get_dst_addr(),
-                                        udp_header.get_src_port(),
-                                        udp_header.get_dst_port(),
-                                        &payload[offset..],
-                                    );
-                                });
+                                if rcvr.accepts(
+                                    ip_header.get_src_addr(),
+                                    udp_header.get_src_port(),
+                                    ip_header.get_dst_addr(),
+                                ) {
+                                    rcvr.client.map(|client| {
+                                        client.receive(
+                                            ip_header.get_src_addr(),
+                                            ip_header.get_dst_addr(),
+                                            udp_header.get_src_port(),
+                                            udp_header.get_dst_port(),
+                                            &payload[offset..],
+                                        );
+                                    });
+                                }
                                 rcvr.binding.replace(binding);
                                 break;
                             }
@@ -113,6 +175,11 @@ pub trait UDPRecvClient {
 pub struct UDPReceiver<'a> {
     client: OptionalCell<&'a dyn UDPRecvClient>,
     binding: MapCell<UdpPortBindingRx>,
+    groups: Cell<MulticastGroups>,
+    /// Set once a group was joined, from then on other groups are filtered
+    filter_multicast: Cell<bool>,
+    remote_addr: OptionalCell<IPAddr>,
+    remote_port: OptionalCell<u16>,
     next: ListLink<'a, UDPReceiver<'a>>,
 }
 
@@ -127,6 +194,10 @@ impl<'a> UDPReceiver<'a> {
         UDPReceiver {
             client: OptionalCell::empty(),
             binding: MapCell::empty(),
+            groups: Cell::new(MulticastGroups::default()),
+            filter_multicast: Cell::new(false),
+            remote_addr: OptionalCell::empty(),
+            remote_port: OptionalCell::empty(),
             next: ListLink::empty(),
         }
     }
@@ -146,4 +217,36 @@ impl<'a> UDPReceiver<'a> {
     pub fn set_binding(&self, binding: UdpPortBindingRx) -> Option<UdpPortBindingRx> {
         self.binding.replace(binding)
     }
+
+    /// Joins the multicast group `group`, so that packets sent to it on the
+    /// bound port are received. From then on, packets sent to groups that
+    /// were not joined are dropped, even after leaving all groups. See
+    /// `MulticastGroups::join` for errors.
+    pub fn join_group(&self, group: IPAddr) -> Result<(), ErrorCode> {
+        let mut groups = self.groups.get();
+        groups.join(group)?;
+        self.groups.set(groups);
+        self.filter_multicast.set(true);
+        Ok(())
+    }
+
+    pub fn leave_group(&self, group: IPAddr) -> Result<(), ErrorCode> {
+        let mut groups = self.groups.get();
+        groups.leave(group)?;
+        self.groups.set(groups);
+        Ok(())
+    }
+
+    /// Only accept packets from `addr` and `port`. `None` accepts any
+    /// address or port.
+    pub fn set_remote_filter(&self, addr: Option<IPAddr>, port: Option<u16>) {
+        self.remote_addr.insert(addr);
+        self.remote_port.insert(port);
+    }
+
+    fn accepts(&self, src_addr: IPAddr, src_port: u16, dst_addr: IPAddr) -> bool {
+        (!self.filter_multicast.get() || self.groups.get().accepts(dst_addr))
+            && self.remote_addr.map_or(true, |addr| *addr == src_addr)
+            && self.remote_port.map_or(true, |port| *port == src_port)
+    }
 }
@@ -13,6 +13,8 @@
 //!  - 'start n' starts the stopped process with name n
 //!  - 'fault n' forces the process with name n into a fault state
 //!  - 'panic' causes the kernel to run the panic handler
+//!  - 'log' prints the persistent log, if one is attached with `set_log()`.
+//!    'log p n' prints only the log named n of process p
 //!
 //! ### `list` Command Fields:
 //!
@@ -65,6 +67,12 @@
 //! to maintain a correct ordering with debug!() calls. The write buffer of
 //! `ProcessConsole` is used solely for echoing what someone types.
 //!
+//! Input selection
+//! ---------------
+//! When the console for apps is multiplexed, the process console only acts on
+//! input while it is selected, if `enable_input_selection()` was called. See
+//! `capsules::console` for how input is selected.
+//!
 //! Using ProcessConsole
 //! --------------------
 //!
@@ -116,13 +124,16 @@ use core::cell::Cell;
 use core::cmp;
 use core::str;
 use kernel::capabilities::ProcessManagementCapability;
-use kernel::common::cells::TakeCell;
+use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::debug;
 use kernel::hil::uart;
 use kernel::introspection::KernelInfo;
 use kernel::ErrorCode;
 use kernel::Kernel;
 
+use crate::console::{Input, InputSelector};
+use crate::log_driver::LogDump;
+
 // Since writes are character echoes, we do not need more than 4 bytes:
 // the longest write is 3 bytes for a backspace (backspace, space, backspace).
 pub static mut WRITE_BUF: [u8; 4] = [0; 4];
@@ -149,6 +160,11 @@ pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     /// Internal flag that the process console should parse the command it just
     /// received after finishing echoing the last newline character.
     execute: Cell<bool>,
+    /// Log printed by the `log` command.
+    log: OptionalCell<&'a dyn LogDump>,
+    /// Whether input is only used while the process console is selected.
+    select_input: Cell<bool>,
+    input: InputSelector,
     kernel: &'static Kernel,
     capability: C,
 }
@@ -172,11 +188,46 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             command_index: Cell::new(0),
             running: Cell::new(false),
             execute: Cell::new(false),
+            log: OptionalCell::empty(),
+            select_input: Cell::new(false),
+            input: InputSelector::new(),
             kernel: kernel,
             capability: capability,
         }
     }
 
+    /// Attach a log for the `log` command to print.
+    pub fn set_log(&self, log: &'a dyn LogDump) {
+        self.log.set(log);
+    }
+
+    /// Only act on input while the process console is selected, for boards
+    /// that multiplex the console for apps.
+    pub fn enable_input_selection(&self) {
+        self.select_input.set(true);
+    }
+
+    /// Whether a byte of input is for the process console.
+    fn for_console(&self, byte: u8) -> bool {
+        if !self.select_input.get() {
+            return true;
+        }
+        match self.input.receive(byte) {
+            Input::Byte(_) => self.input.console_selected(),
+            Input::Selected => {
+                self.input.map_selected(|name| {
+                    if name.is_empty() {
+                        debug!("Console input goes to the process console");
+                    } else {
+                        debug!("Console input goes to process {}", name);
+                    }
+                });
+                false
+            }
+            Input::Selecting => false,
+        }
+    }
+
     pub fn start(&self) -> Result<(), ErrorCode> {
         if self.running.get() == false {
             self.rx_buffer.take().map(|buffer| {
@@ -211,7 +262,7 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                         let clean_str = s.trim();
                         if clean_str.starts_with("help") {
                             debug!("Welcome to the process console.");
-                            debug!("Valid commands are: help status list stop start fault panic");
+                            debug!("Valid commands are: help status list stop start fault panic log");
                         } else if clean_str.starts_with("start") {
                             let argument = clean_str.split_whitespace().nth(1);
                             argument.map(|name| {
@@ -291,10 +342,18 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                 "Timeslice expirations: {}",
                                 info.timeslice_expirations(&self.capability)
                             );
+                        } else if clean_str.starts_with("log") {
+                            let mut arguments = clean_str.split_whitespace().skip(1);
+                            let filter = arguments.next().zip(arguments.next());
+                            match self.log.map(|log| log.dump(filter)) {
+                                Some(Ok(())) => (),
+                                Some(Err(e)) => debug!("Log dump failed: {:?}", e),
+                                None => debug!("No log attached"),
+                            }
                         } else if clean_str.starts_with("panic") {
                             panic!("ProcessConsole forced a kernel panic.");
                         } else {
-                            debug!("Valid commands are: help status list stop start fault");
+                            debug!("Valid commands are: help status list stop start fault log");
                         }
                     }
                     Err(_e) => debug!("Invalid command: {:?}", command),
@@ -365,6 +424,8 @@ impl<'a, C: ProcessManagementCapability> uart::ReceiveClient for ProcessConsole<
         if error == uart::Error::None {
             match rx_len {
                 0 => debug!("ProcessConsole had read of 0 bytes"),
+                // Input for a process is ignored.
+                1 if !self.for_console(read_buf[0]) => {}
                 1 => {
                     self.command_buffer.map(|command| {
                         let index = self.command_index.get() as usize;
@@ -1,6 +1,20 @@
 //! Provides driver for accessing an SD Card and a userspace Driver.
 //!
-//! This allows initialization and block reads or writes on top of SPI.
+//! This allows initialization and block reads or writes on top of SPI. Other
+//! capsules, such as a filesystem, can use the card through the
+//! `hil::block_storage::BlockStorage` interface once it is initialized.
+//!
+//! Commands always carry a valid CRC7 and data blocks a CRC16. During
+//! initialization CRC checking is switched on in the card with CMD59, and
+//! received blocks are then checked as well. A failed read or write is
+//! aborted (CMD12 or a stop transmission token) and restarted from its first
+//! block up to `MAX_RETRIES` times, waiting twice as long before each
+//! attempt. Multiple blocks are written with a single CMD25, sending each
+//! block as soon as the card finished programming the previous one.
+//!
+//! Removing the card aborts any transfer in progress and hands its buffer
+//! back with an error. Once a card is inserted and has settled, it is
+//! initialized again without waiting for a request from the client.
 //!
 //! Usage
 //! -----
@@ -74,6 +88,7 @@ pub struct SDCard<'a, A: hil::time::Alarm<'a>> {
 
     is_initialized: Cell<bool>,
     card_type: Cell<SDCardType>,
+    total_size: Cell<u64>,
 
     detect_pin: Cell<Option<&'a dyn hil::gpio::InterruptPin<'a>>>,
 
@@ -83,6 +98,13 @@ pub struct SDCard<'a, A: hil::time::Alarm<'a>> {
     client: OptionalCell<&'static dyn SDCardClient>,
     client_buffer: TakeCell<'static, [u8]>,
     client_offset: Cell<usize>,
+
+    block_client: OptionalCell<&'a dyn hil::block_storage::BlockStorageClient>,
+    block_request: Cell<BlockRequest>,
+
+    crc_enabled: Cell<bool>,
+    transfer: Cell<Transfer>,
+    retries: Cell<u8>,
 }
 
 /// SD card command codes
@@ -101,6 +123,7 @@ enum SDCmd {
     CMD25_WriteMultiple = 25,             //        Write multiple blocks
     CMD55_ManufSpecificCommand = 55,      // Next command will be manufacturer specific
     CMD58_ReadOCR = 58,                   //              Read operation condition register (OCR)
+    CMD59_CrcOnOff = 59,                  //             Turn CRC checking on or off
     ACMD41_ManufSpecificInit = 0x80 + 41, // Manufacturer specific Init
 }
 
@@ -122,6 +145,7 @@ enum SpiState {
     SendManufSpecificCmd { cmd: SDCmd, arg: u32 },
 
     InitReset,
+    InitEnableCrc,
     InitCheckVersion,
     InitRepeatHCSInit,
     InitCheckCapacity,
@@ -139,9 +163,13 @@ enum SpiState {
     ReadBlocksComplete,
 
     StartWriteBlocks { count: u32 },
-    WriteBlockResponse,
-    WriteBlockBusy,
-    WaitWriteBlockBusy,
+    WriteBlockResponse { count: u32 },
+    WriteBlockBusy { count: u32 },
+    WaitWriteBlockBusy { count: u32 },
+    WriteStopTran,
+
+    AbortTransfer,
+    WaitAbortBusy,
 }
 
 /// Alarm states
@@ -158,7 +186,10 @@ enum AlarmState {
     WaitForDataBlock,
     WaitForDataBlocks { count: u32 },
 
-    WaitForWriteBusy,
+    WaitForWriteBusy { count: u32 },
+
+    WaitForAbortBusy,
+    RetryTransfer,
 }
 
 /// Error codes returned if an SD card transaction fails
@@ -171,6 +202,24 @@ enum SdCardError {
     TimeoutFailure = -5,
 }
 
+/// Block storage operation in progress, if the current transfer was started
+/// through the `BlockStorage` interface rather than `SDCardClient`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockRequest {
+    None,
+    Read,
+    Write,
+}
+
+/// Block transfer in progress, kept so that it can be restarted after an
+/// error
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transfer {
+    None,
+    Read { sector: u32, count: u32 },
+    Write { sector: u32, count: u32 },
+}
+
 /// SD card types, determined during initialization
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum SDCardType {
@@ -185,6 +234,43 @@ enum SDCardType {
 const SUCCESS_STATUS: u8 = 0x00;
 const INITIALIZING_STATUS: u8 = 0x01;
 const DATA_TOKEN: u8 = 0xFE;
+const WRITE_MULTIPLE_TOKEN: u8 = 0xFC;
+const STOP_TRAN_TOKEN: u8 = 0xFD;
+const DATA_ACCEPTED: u8 = 0x05;
+
+/// Number of times a failed transfer is restarted before reporting an error
+const MAX_RETRIES: u8 = 3;
+
+/// CRC7 used to protect commands, polynomial x^7 + x^3 + 1
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let feedback = ((byte >> bit) ^ (crc >> 6)) & 0x01;
+            crc = (crc << 1) & 0x7F;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// CRC16 (CCITT, initial value 0) used to protect data blocks
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if (crc & 0x8000) != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
 
 /// Callback functions from SDCard
 pub trait SDCardClient {
@@ -192,7 +278,9 @@ pub trait SDCardClient {
     fn init_done(&self, block_size: u32, total_size: u64);
     fn read_done(&self, data: &'static mut [u8], len: usize);
     fn write_done(&self, buffer: &'static mut [u8]);
-    fn error(&self, error: u32);
+    /// A transfer or initialization failed. The buffer of a failed transfer
+    /// is handed back
+    fn error(&self, error: u32, buffer: Option<&'static mut [u8]>);
 }
 
 /// Functions for initializing and accessing an SD card
@@ -238,12 +326,18 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
             alarm_count: Cell::new(0),
             is_initialized: Cell::new(false),
             card_type: Cell::new(SDCardType::Uninitialized),
+            total_size: Cell::new(0),
             detect_pin: Cell::new(pin),
             txbuffer: TakeCell::new(txbuffer),
             rxbuffer: TakeCell::new(rxbuffer),
             client: OptionalCell::empty(),
             client_buffer: TakeCell::empty(),
             client_offset: Cell::new(0),
+            block_client: OptionalCell::empty(),
+            block_request: Cell::new(BlockRequest::None),
+            crc_enabled: Cell::new(false),
+            transfer: Cell::new(Transfer::None),
+            retries: Cell::new(0),
         }
     }
 
@@ -309,14 +403,10 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         write_buffer[5] = ((arg >> 8) & 0xFF) as u8;
         write_buffer[6] = ((arg >> 0) & 0xFF) as u8;
 
-        // CRC is ignored except for CMD0 and maybe CMD8
-        // Established practice it to just always use the CMD0 CRC unless we
-        // are sending a CMD8
-        if cmd == SDCmd::CMD8_CheckVoltage {
-            write_buffer[7] = 0x87; // valid crc for CMD8(0x1AA)
-        } else {
-            write_buffer[7] = 0x95; // valid crc for CMD0
-        }
+        // CRC7 of command and argument, followed by the end bit
+        // Cards only check it for CMD0 and CMD8 until CRC checking is enabled
+        // with CMD59
+        write_buffer[7] = (crc7(&write_buffer[2..7]) << 1) | 0x01;
 
         // append dummy bytes to transmission after command bytes
         // Limit to minimum length between write_buffer and recv_len
@@ -433,20 +523,9 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                 // only continue if we are in idle state
                 if r1 == INITIALIZING_STATUS {
-                    // next send Check Voltage Range command that is only valid
-                    //  on SDv2 cards. This is used to check which SD card
-                    //  version is installed. Note that 0xAA is an arbitrary
-                    //  check pattern that will be duplicated in the response
-                    //  and 0x100 specifies that the card is running between
-                    //  2.7 and 3.6 volts
-                    self.state.set(SpiState::InitCheckVersion);
-                    self.send_command(
-                        SDCmd::CMD8_CheckVoltage,
-                        0x1AA,
-                        write_buffer,
-                        read_buffer,
-                        10,
-                    );
+                    // turn on CRC checking of commands and data blocks
+                    self.state.set(SpiState::InitEnableCrc);
+                    self.send_command(SDCmd::CMD59_CrcOnOff, 0x1, write_buffer, read_buffer, 10);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -455,11 +534,35 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
+                        client.error(SdCardError::InitializationFailure as u32, None);
                     });
                 }
             }
 
+            SpiState::InitEnableCrc => {
+                // check response
+                let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
+
+                // cards that do not support CMD59 are used without CRC
+                //  checking of received blocks
+                self.crc_enabled.set(r1 == INITIALIZING_STATUS);
+
+                // next send Check Voltage Range command that is only valid
+                //  on SDv2 cards. This is used to check which SD card
+                //  version is installed. Note that 0xAA is an arbitrary
+                //  check pattern that will be duplicated in the response
+                //  and 0x100 specifies that the card is running between
+                //  2.7 and 3.6 volts
+                self.state.set(SpiState::InitCheckVersion);
+                self.send_command(
+                    SDCmd::CMD8_CheckVoltage,
+                    0x1AA,
+                    write_buffer,
+                    read_buffer,
+                    10,
+                );
+            }
+
             SpiState::InitCheckVersion => {
                 // check response
                 let (r1, _, r7) = self.get_response(SDResponse::R7_CheckVoltage, read_buffer);
@@ -525,7 +628,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
+                        client.error(SdCardError::InitializationFailure as u32, None);
                     });
                 }
             }
@@ -554,7 +657,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
+                        client.error(SdCardError::InitializationFailure as u32, None);
                     });
                 }
             }
@@ -621,7 +724,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
+                        client.error(SdCardError::InitializationFailure as u32, None);
                     });
                 }
             }
@@ -659,7 +762,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
+                        client.error(SdCardError::InitializationFailure as u32, None);
                     });
                 }
             }
@@ -682,7 +785,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
+                        client.error(SdCardError::InitializationFailure as u32, None);
                     });
                 }
             }
@@ -731,6 +834,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     // initialization complete
                     self.state.set(SpiState::Idle);
                     self.is_initialized.set(true);
+                    self.total_size.set(total_size);
 
                     // perform callback
                     self.client.map(move |client| {
@@ -744,7 +848,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
                     self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
+                        client.error(SdCardError::InitializationFailure as u32, None);
                     });
                 }
             }
@@ -764,15 +868,8 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                         self.read_bytes(write_buffer, read_buffer, 1);
                     }
                 } else {
-                    // error, send callback and quit
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::ReadFailure as u32);
-                    });
+                    // error, try again or send callback and quit
+                    self.transfer_failed(SdCardError::ReadFailure, write_buffer, read_buffer);
                 }
             }
 
@@ -794,19 +891,18 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     let delay = A::ticks_from_ms(1);
                     self.alarm.set_alarm(self.alarm.now(), delay);
                 } else {
-                    // error, send callback and quit
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::ReadFailure as u32);
-                    });
+                    // error token, try again or send callback and quit
+                    self.transfer_failed(SdCardError::ReadFailure, write_buffer, read_buffer);
                 }
             }
 
             SpiState::ReadBlockComplete => {
+                if !self.block_crc_valid(read_buffer) {
+                    // corrupted block, try again or send callback and quit
+                    self.transfer_failed(SdCardError::ReadFailure, write_buffer, read_buffer);
+                    return;
+                }
+
                 // replace buffers
                 self.txbuffer.replace(write_buffer);
                 self.rxbuffer.replace(read_buffer);
@@ -826,9 +922,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                         // callback
                         let read_len = cmp::min(read_buffer.len(), cmp::min(buffer.len(), 512));
-                        self.client.map(move |client| {
-                            client.read_done(buffer, read_len);
-                        });
+                        self.read_complete(buffer, read_len);
                     });
                 });
             }
@@ -852,19 +946,18 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     let delay = A::ticks_from_ms(1);
                     self.alarm.set_alarm(self.alarm.now(), delay);
                 } else {
-                    // error, send callback and quit
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::ReadFailure as u32);
-                    });
+                    // error token, try again or send callback and quit
+                    self.transfer_failed(SdCardError::ReadFailure, write_buffer, read_buffer);
                 }
             }
 
             SpiState::ReceivedBlock { count } => {
+                if !self.block_crc_valid(read_buffer) {
+                    // corrupted block, try again or send callback and quit
+                    self.transfer_failed(SdCardError::ReadFailure, write_buffer, read_buffer);
+                    return;
+                }
+
                 // copy block over to client buffer
                 self.client_buffer.map(|buffer| {
                     // copy block into client buffer
@@ -909,20 +1002,11 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                     // read finished, perform callback
                     self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.read_done(buffer, self.client_offset.get());
-                        });
+                        self.read_complete(buffer, self.client_offset.get());
                     });
                 } else {
-                    // error, send callback and quit
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::ReadFailure as u32);
-                    });
+                    // error, try again or send callback and quit
+                    self.transfer_failed(SdCardError::ReadFailure, write_buffer, read_buffer);
                 }
             }
 
@@ -931,111 +1015,101 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
                 if r1 == SUCCESS_STATUS {
-                    if count <= 1 {
-                        let bytes_written = self.client_buffer.map_or(0, |buffer| {
-                            // copy over data from client buffer
-                            // Limit to minimum length between write_buffer,
-                            // buffer, and 512 (block size)
-                            for (write_byte, &client_byte) in
-                                write_buffer.iter_mut().skip(1).zip(buffer.iter()).take(512)
-                            {
-                                *write_byte = client_byte;
-                            }
-
-                            // calculate number of bytes written
-                            cmp::min(write_buffer.len(), cmp::min(buffer.len(), 512))
-                        });
+                    // send the first data packet
+                    self.write_data_block(count, write_buffer, read_buffer);
+                } else {
+                    // error, try again or send callback and quit
+                    self.transfer_failed(SdCardError::WriteFailure, write_buffer, read_buffer);
+                }
+            }
 
-                        // set a known value for remaining bytes
-                        for write_byte in write_buffer
-                            .iter_mut()
-                            .skip(1)
-                            .skip(bytes_written)
-                            .take(512)
-                        {
-                            *write_byte = 0xFF;
-                        }
+            SpiState::WriteBlockResponse { count } => {
+                // Get data packet
+                self.state.set(SpiState::WriteBlockBusy { count: count });
+                self.read_bytes(write_buffer, read_buffer, 1);
+            }
 
-                        // set up remainder of data packet
-                        write_buffer[0] = DATA_TOKEN; // Data token
-                        write_buffer[513] = 0xFF; // dummy CRC
-                        write_buffer[514] = 0xFF; // dummy CRC
+            SpiState::WriteBlockBusy { count } => {
+                if (read_buffer[0] & 0x1F) == DATA_ACCEPTED {
+                    // check if sd card is busy
+                    self.state
+                        .set(SpiState::WaitWriteBlockBusy { count: count });
+                    self.read_bytes(write_buffer, read_buffer, 1);
+                } else {
+                    // block rejected because of a CRC or write error, try
+                    //  again or send callback and quit
+                    self.transfer_failed(SdCardError::WriteFailure, write_buffer, read_buffer);
+                }
+            }
 
-                        // write data packet
-                        self.state.set(SpiState::WriteBlockResponse);
-                        self.write_bytes(write_buffer, read_buffer, 515);
+            SpiState::WaitWriteBlockBusy { count } => {
+                // check if line is still held low (busy state)
+                if read_buffer[0] != 0x00 {
+                    self.alarm_count.set(0);
+                    if count > 1 {
+                        // send the next block of a multiple block write
+                        self.write_data_block(count - 1, write_buffer, read_buffer);
+                    } else if count == 1 && self.is_multiple_block_write() {
+                        // all blocks written. Terminate multiple write, the
+                        //  card is busy again afterwards
+                        write_buffer[0] = STOP_TRAN_TOKEN;
+                        write_buffer[1] = 0xFF;
+                        self.state.set(SpiState::WriteStopTran);
+                        self.write_bytes(write_buffer, read_buffer, 2);
                     } else {
-                        // multi-block SD card writes are unimplemented
-                        // This should have returned an error already, but if
-                        // we got here somehow, return an error and quit now
+                        // replace buffers
                         self.txbuffer.replace(write_buffer);
                         self.rxbuffer.replace(read_buffer);
+
+                        // write finished, perform callback
                         self.state.set(SpiState::Idle);
-                        self.alarm_state.set(AlarmState::Idle);
-                        self.alarm_count.set(0);
-                        self.client.map(move |client| {
-                            client.error(SdCardError::WriteFailure as u32);
+                        self.client_buffer.take().map(move |buffer| {
+                            self.write_complete(buffer);
                         });
                     }
                 } else {
-                    // error, send callback and quit
+                    // replace buffers
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::WriteFailure as u32);
-                    });
+
+                    // try again after 1 ms
+                    self.alarm_state
+                        .set(AlarmState::WaitForWriteBusy { count: count });
+                    let delay = A::ticks_from_ms(1);
+                    self.alarm.set_alarm(self.alarm.now(), delay);
                 }
             }
 
-            SpiState::WriteBlockResponse => {
-                // Get data packet
-                self.state.set(SpiState::WriteBlockBusy);
+            SpiState::WriteStopTran => {
+                // wait for the card to finish programming
+                self.state.set(SpiState::WaitWriteBlockBusy { count: 0 });
                 self.read_bytes(write_buffer, read_buffer, 1);
             }
 
-            SpiState::WriteBlockBusy => {
-                if (read_buffer[0] & 0x1F) == 0x05 {
-                    // check if sd card is busy
-                    self.state.set(SpiState::WaitWriteBlockBusy);
-                    self.read_bytes(write_buffer, read_buffer, 1);
-                } else {
-                    // error, send callback and quit
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::WriteFailure as u32);
-                    });
-                }
+            SpiState::AbortTransfer => {
+                // wait for the card to return to the transfer state
+                self.state.set(SpiState::WaitAbortBusy);
+                self.read_bytes(write_buffer, read_buffer, 1);
             }
 
-            SpiState::WaitWriteBlockBusy => {
+            SpiState::WaitAbortBusy => {
                 // check if line is still held low (busy state)
-                if read_buffer[0] != 0x00 {
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
+                let ready = read_buffer[0] != 0x00;
 
-                    // read finished, perform callback
-                    self.state.set(SpiState::Idle);
+                // replace buffers
+                self.txbuffer.replace(write_buffer);
+                self.rxbuffer.replace(read_buffer);
+
+                if ready {
+                    // back off before restarting the transfer. The delay
+                    //  doubles with every attempt
                     self.alarm_count.set(0);
-                    self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.write_done(buffer);
-                        });
-                    });
+                    self.alarm_state.set(AlarmState::RetryTransfer);
+                    let delay = A::ticks_from_ms(1 << self.retries.get());
+                    self.alarm.set_alarm(self.alarm.now(), delay);
                 } else {
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-
                     // try again after 1 ms
-                    self.alarm_state.set(AlarmState::WaitForWriteBusy);
+                    self.alarm_state.set(AlarmState::WaitForAbortBusy);
                     let delay = A::ticks_from_ms(1);
                     self.alarm.set_alarm(self.alarm.now(), delay);
                 }
@@ -1051,6 +1125,221 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         }
     }
 
+    /// checks the CRC16 that follows a received data block, if the card was
+    /// told to send valid ones
+    fn block_crc_valid(&self, read_buffer: &[u8]) -> bool {
+        !self.crc_enabled.get()
+            || crc16(&read_buffer[..512])
+                == u16::from_be_bytes([read_buffer[512], read_buffer[513]])
+    }
+
+    fn is_multiple_block_write(&self) -> bool {
+        match self.transfer.get() {
+            Transfer::Write { count, .. } => count > 1,
+            _ => false,
+        }
+    }
+
+    /// sends the command that starts the current transfer from its first
+    /// block
+    fn start_transfer(&self, write_buffer: &'static mut [u8], read_buffer: &'static mut [u8]) {
+        self.client_offset.set(0);
+
+        let (cmd, sector) = match self.transfer.get() {
+            Transfer::Read { sector, count } => {
+                self.state.set(SpiState::StartReadBlocks { count: count });
+                if count == 1 {
+                    (SDCmd::CMD17_ReadSingle, sector)
+                } else {
+                    (SDCmd::CMD18_ReadMultiple, sector)
+                }
+            }
+            Transfer::Write { sector, count } => {
+                self.state.set(SpiState::StartWriteBlocks { count: count });
+                if count == 1 {
+                    (SDCmd::CMD24_WriteSingle, sector)
+                } else {
+                    (SDCmd::CMD25_WriteMultiple, sector)
+                }
+            }
+            Transfer::None => {
+                // nothing to do
+                self.txbuffer.replace(write_buffer);
+                self.rxbuffer.replace(read_buffer);
+                self.state.set(SpiState::Idle);
+                return;
+            }
+        };
+
+        // convert block address to byte address for non-block access cards
+        let mut address = sector;
+        if self.card_type.get() != SDCardType::SDv2BlockAddressable {
+            address *= 512;
+        }
+
+        self.send_command(cmd, address, write_buffer, read_buffer, 10);
+    }
+
+    /// sends the next data packet of the current write. `count` is the
+    /// number of blocks left to write, including this one
+    fn write_data_block(
+        &self,
+        count: u32,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+    ) {
+        let offset = self.client_offset.get();
+        let bytes_written = self.client_buffer.map_or(0, |buffer| {
+            // copy over data from client buffer
+            // Limit to minimum length between write_buffer, buffer, and 512
+            // (block size)
+            for (write_byte, &client_byte) in write_buffer
+                .iter_mut()
+                .skip(1)
+                .zip(buffer.iter().skip(offset))
+                .take(512)
+            {
+                *write_byte = client_byte;
+            }
+
+            // calculate number of bytes written
+            cmp::min(
+                write_buffer.len(),
+                cmp::min(buffer.len().saturating_sub(offset), 512),
+            )
+        });
+        self.client_offset.set(offset + 512);
+
+        // set a known value for remaining bytes
+        for write_byte in write_buffer
+            .iter_mut()
+            .skip(1)
+            .skip(bytes_written)
+            .take(512 - bytes_written)
+        {
+            *write_byte = 0xFF;
+        }
+
+        // set up remainder of data packet
+        if self.is_multiple_block_write() {
+            write_buffer[0] = WRITE_MULTIPLE_TOKEN;
+        } else {
+            write_buffer[0] = DATA_TOKEN;
+        }
+        let crc = crc16(&write_buffer[1..513]).to_be_bytes();
+        write_buffer[513] = crc[0];
+        write_buffer[514] = crc[1];
+
+        // write data packet
+        self.state
+            .set(SpiState::WriteBlockResponse { count: count });
+        self.write_bytes(write_buffer, read_buffer, 515);
+    }
+
+    /// handles an error during a block transfer. The transfer is stopped and
+    /// restarted after a delay, or reported once it failed `MAX_RETRIES`
+    /// times
+    fn transfer_failed(
+        &self,
+        error: SdCardError,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+    ) {
+        let retries = self.retries.get();
+        self.alarm_count.set(0);
+
+        match self.transfer.get() {
+            Transfer::Read { .. } if retries < MAX_RETRIES => {
+                // stop a multiple block read that may still be running
+                self.retries.set(retries + 1);
+                self.state.set(SpiState::AbortTransfer);
+                self.send_command(SDCmd::CMD12_StopRead, 0x0, write_buffer, read_buffer, 10);
+            }
+            Transfer::Write { .. } if retries < MAX_RETRIES => {
+                // stop a multiple block write that may still be running. The
+                //  token is ignored by a card that is not receiving blocks
+                self.retries.set(retries + 1);
+                write_buffer[0] = STOP_TRAN_TOKEN;
+                write_buffer[1] = 0xFF;
+                self.state.set(SpiState::AbortTransfer);
+                self.write_bytes(write_buffer, read_buffer, 2);
+            }
+            _ => {
+                // send callback and quit
+                self.txbuffer.replace(write_buffer);
+                self.rxbuffer.replace(read_buffer);
+                self.state.set(SpiState::Idle);
+                self.alarm_state.set(AlarmState::Idle);
+                self.report_error(error);
+            }
+        }
+    }
+
+    /// hands a finished read back to whichever interface started it
+    fn read_complete(&self, buffer: &'static mut [u8], len: usize) {
+        self.transfer.set(Transfer::None);
+        self.retries.set(0);
+        if self.block_request.replace(BlockRequest::None) == BlockRequest::Read {
+            self.block_client.map(move |client| {
+                client.read_done(buffer, Ok(()));
+            });
+        } else {
+            self.client.map(move |client| {
+                client.read_done(buffer, len);
+            });
+        }
+    }
+
+    /// hands a finished write back to whichever interface started it
+    fn write_complete(&self, buffer: &'static mut [u8]) {
+        self.transfer.set(Transfer::None);
+        self.retries.set(0);
+        if self.block_request.replace(BlockRequest::None) == BlockRequest::Write {
+            self.block_client.map(move |client| {
+                client.write_done(buffer, Ok(()));
+            });
+        } else {
+            self.client.map(move |client| {
+                client.write_done(buffer);
+            });
+        }
+    }
+
+    /// reports a failed transfer. Block storage clients get their buffer
+    /// back, `SDCardClient`s get an error callback
+    fn report_error(&self, error: SdCardError) {
+        self.transfer.set(Transfer::None);
+        self.retries.set(0);
+
+        let code = if error == SdCardError::CardStateChanged {
+            ErrorCode::NODEVICE
+        } else {
+            ErrorCode::FAIL
+        };
+        match self.block_request.replace(BlockRequest::None) {
+            BlockRequest::Read => {
+                self.client_buffer.take().map(|buffer| {
+                    self.block_client.map(move |client| {
+                        client.read_done(buffer, Err(code));
+                    });
+                });
+            }
+            BlockRequest::Write => {
+                self.client_buffer.take().map(|buffer| {
+                    self.block_client.map(move |client| {
+                        client.write_done(buffer, Err(code));
+                    });
+                });
+            }
+            BlockRequest::None => {
+                let buffer = self.client_buffer.take();
+                self.client.map(move |client| {
+                    client.error(error as u32, buffer);
+                });
+            }
+        }
+    }
+
     /// updates SD card state upon timer alarm fired
     fn process_alarm_states(&self) {
         // keep track of how many times the alarm has been called in a row
@@ -1060,9 +1349,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
             self.alarm_count.set(0);
-            self.client.map(move |client| {
-                client.error(SdCardError::TimeoutFailure as u32);
-            });
+            self.report_error(SdCardError::TimeoutFailure);
         } else {
             self.alarm_count.set(repeats + 1);
         }
@@ -1078,6 +1365,12 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                 self.detect_changes();
                 self.alarm_count.set(0);
                 self.alarm_state.set(AlarmState::Idle);
+
+                // bring a newly inserted card up again. Errors are reported
+                //  through the client
+                if self.is_installed() {
+                    let _ = self.initialize();
+                }
             }
 
             AlarmState::RepeatHCSInit => {
@@ -1165,12 +1458,25 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                 self.alarm_state.set(AlarmState::Idle);
             }
 
-            AlarmState::WaitForWriteBusy => {
+            AlarmState::WaitForWriteBusy { count } => {
                 // check card initialization again
                 self.txbuffer.take().map(|write_buffer| {
                     self.rxbuffer.take().map(move |read_buffer| {
                         // check if sd card is busy
-                        self.state.set(SpiState::WaitWriteBlockBusy);
+                        self.state
+                            .set(SpiState::WaitWriteBlockBusy { count: count });
+                        self.read_bytes(write_buffer, read_buffer, 1);
+                    });
+                });
+
+                self.alarm_state.set(AlarmState::Idle);
+            }
+
+            AlarmState::WaitForAbortBusy => {
+                self.txbuffer.take().map(|write_buffer| {
+                    self.rxbuffer.take().map(move |read_buffer| {
+                        // check if sd card is busy
+                        self.state.set(SpiState::WaitAbortBusy);
                         self.read_bytes(write_buffer, read_buffer, 1);
                     });
                 });
@@ -1178,6 +1484,17 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                 self.alarm_state.set(AlarmState::Idle);
             }
 
+            AlarmState::RetryTransfer => {
+                self.alarm_state.set(AlarmState::Idle);
+
+                self.txbuffer.take().map(|write_buffer| {
+                    self.rxbuffer.take().map(move |read_buffer| {
+                        // start the failed transfer over
+                        self.start_transfer(write_buffer, read_buffer);
+                    });
+                });
+            }
+
             AlarmState::Idle => {
                 // receiving an event from Idle means something was killed
                 // do nothing
@@ -1211,6 +1528,10 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
     pub fn initialize(&self) -> Result<(), ErrorCode> {
         // if not already, set card to uninitialized again
         self.is_initialized.set(false);
+        self.crc_enabled.set(false);
+        self.block_request.set(BlockRequest::None);
+        self.transfer.set(Transfer::None);
+        self.retries.set(0);
 
         // no point in initializing if the card is not installed
         if self.is_installed() {
@@ -1234,63 +1555,34 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         }
     }
 
+    /// checks whether a block transfer can be started right now, so that the
+    /// buffer can be handed back to the caller instead of being dropped
+    fn check_block_request(&self, buffer: &[u8], count: u32) -> Result<(), ErrorCode> {
+        if !self.is_installed() {
+            Err(ErrorCode::UNINSTALLED)
+        } else if !self.is_initialized() {
+            Err(ErrorCode::RESERVE)
+        } else if self.state.get() != SpiState::Idle
+            || self.txbuffer.is_none()
+            || self.rxbuffer.is_none()
+        {
+            Err(ErrorCode::BUSY)
+        } else if count == 0 {
+            Err(ErrorCode::INVAL)
+        } else if buffer.len() < count as usize * 512 {
+            Err(ErrorCode::SIZE)
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn read_blocks(
         &self,
         buffer: &'static mut [u8],
         sector: u32,
         count: u32,
-    ) -> Result<(), ErrorCode> {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer
-                    .take()
-                    .map_or(Err(ErrorCode::NOMEM), |txbuffer| {
-                        self.rxbuffer
-                            .take()
-                            .map_or(Err(ErrorCode::NOMEM), move |rxbuffer| {
-                                // save the user buffer for later
-                                self.client_buffer.replace(buffer);
-                                self.client_offset.set(0);
-
-                                // convert block address to byte address for non-block
-                                //  access cards
-                                let mut address = sector;
-                                if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                    address *= 512;
-                                }
-
-                                self.state.set(SpiState::StartReadBlocks { count: count });
-                                if count == 1 {
-                                    self.send_command(
-                                        SDCmd::CMD17_ReadSingle,
-                                        address,
-                                        txbuffer,
-                                        rxbuffer,
-                                        10,
-                                    );
-                                } else {
-                                    self.send_command(
-                                        SDCmd::CMD18_ReadMultiple,
-                                        address,
-                                        txbuffer,
-                                        rxbuffer,
-                                        10,
-                                    );
-                                }
-
-                                // command started successfully
-                                Ok(())
-                            })
-                    })
-            } else {
-                // sd card not initialized
-                Err(ErrorCode::RESERVE)
-            }
-        } else {
-            // sd card not installed
-            Err(ErrorCode::UNINSTALLED)
-        }
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_blocks(buffer, Transfer::Read { sector, count })
     }
 
     pub fn write_blocks(
@@ -1298,56 +1590,96 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         buffer: &'static mut [u8],
         sector: u32,
         count: u32,
-    ) -> Result<(), ErrorCode> {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer
-                    .take()
-                    .map_or(Err(ErrorCode::NOMEM), |txbuffer| {
-                        self.rxbuffer
-                            .take()
-                            .map_or(Err(ErrorCode::NOMEM), move |rxbuffer| {
-                                // save the user buffer for later
-                                self.client_buffer.replace(buffer);
-                                self.client_offset.set(0);
-
-                                // convert block address to byte address for non-block
-                                //  access cards
-                                let mut address = sector;
-                                if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                    address *= 512;
-                                }
-
-                                self.state.set(SpiState::StartWriteBlocks { count: count });
-                                if count == 1 {
-                                    self.send_command(
-                                        SDCmd::CMD24_WriteSingle,
-                                        address,
-                                        txbuffer,
-                                        rxbuffer,
-                                        10,
-                                    );
-
-                                    // command started successfully
-                                    Ok(())
-                                } else {
-                                    // can't write multiple blocks yet
-                                    Err(ErrorCode::NOSUPPORT)
-                                }
-                            })
-                    })
-            } else {
-                // sd card not initialized
-                Err(ErrorCode::RESERVE)
-            }
-        } else {
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_blocks(buffer, Transfer::Write { sector, count })
+    }
+
+    fn start_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        transfer: Transfer,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if !self.is_installed() {
             // sd card not installed
-            Err(ErrorCode::UNINSTALLED)
+            Err((ErrorCode::UNINSTALLED, buffer))
+        } else if !self.is_initialized() {
+            // sd card not initialized
+            Err((ErrorCode::RESERVE, buffer))
+        } else if self.txbuffer.is_none() || self.rxbuffer.is_none() {
+            Err((ErrorCode::NOMEM, buffer))
+        } else {
+            // save the user buffer for later
+            self.client_buffer.replace(buffer);
+            self.transfer.set(transfer);
+            self.retries.set(0);
+
+            self.txbuffer.take().map(|txbuffer| {
+                self.rxbuffer.take().map(move |rxbuffer| {
+                    self.start_transfer(txbuffer, rxbuffer);
+                });
+            });
+
+            // command started successfully
+            Ok(())
         }
     }
 }
 
+/// Block storage interface, used by filesystems layered on top of the card
+impl<'a, A: hil::time::Alarm<'a>> hil::block_storage::BlockStorage<'a> for SDCard<'a, A> {
+    fn set_client(&self, client: &'a dyn hil::block_storage::BlockStorageClient) {
+        self.block_client.set(client);
+    }
+
+    fn block_size(&self) -> usize {
+        if self.is_initialized() {
+            512
+        } else {
+            0
+        }
+    }
+
+    fn block_count(&self) -> u32 {
+        if self.is_initialized() {
+            (self.total_size.get() / 512) as u32
+        } else {
+            0
+        }
+    }
+
+    fn read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.check_block_request(buffer, count) {
+            return Err((e, buffer));
+        }
+        self.block_request.set(BlockRequest::Read);
+        SDCard::read_blocks(self, buffer, block, count).map_err(|error| {
+            self.block_request.set(BlockRequest::None);
+            error
+        })
+    }
+
+    fn write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.check_block_request(buffer, count) {
+            return Err((e, buffer));
+        }
+        self.block_request.set(BlockRequest::Write);
+        SDCard::write_blocks(self, buffer, block, count).map_err(|error| {
+            self.block_request.set(BlockRequest::None);
+            error
+        })
+    }
+}
+
 /// Handle callbacks from the SPI peripheral
 impl<'a, A: hil::time::Alarm<'a>> hil::spi::SpiMasterClient for SDCard<'a, A> {
     fn read_write_done(
@@ -1376,12 +1708,13 @@ impl<'a, A: hil::time::Alarm<'a>> hil::gpio::Client for SDCard<'a, A> {
         // check if there was an open transaction with the sd card
         if self.alarm_state.get() != AlarmState::Idle || self.state.get() != SpiState::Idle {
             // something was running when this occurred. Kill the transaction and
-            //  send an error callback
+            //  send an error callback with the buffer of the transfer. The SPI
+            //  buffers of a transaction in flight are replaced once it
+            //  returns in the Idle state
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
-            self.client.map(move |client| {
-                client.error(SdCardError::CardStateChanged as u32);
-            });
+            self.alarm_count.set(0);
+            self.report_error(SdCardError::CardStateChanged);
         }
 
         // either the card is new or gone, in either case it isn't initialized
@@ -1502,7 +1835,9 @@ impl<'a, A: hil::time::Alarm<'a>> SDCardClient for SDCardDriver<'a, A> {
         });
     }
 
-    fn error(&self, error: u32) {
+    fn error(&self, error: u32, buffer: Option<&'static mut [u8]>) {
+        buffer.map(|buffer| self.kernel_buf.replace(buffer));
+
         self.current_process.map(|process_id| {
             let _ = self.grants.enter(*process_id, |app| {
                 app.callback.schedule(4, error as usize, 0);
@@ -1631,8 +1966,12 @@ impl<'a, A: hil::time::Alarm<'a>> Driver for SDCardDriver<'a, A> {
             // read_block
             3 => self.kernel_buf.take().map_or(
                 CommandReturn::failure(ErrorCode::BUSY),
-                |kernel_buf| {
-                    CommandReturn::from(self.sdcard.read_blocks(kernel_buf, data as u32, 1))
+                |kernel_buf| match self.sdcard.read_blocks(kernel_buf, data as u32, 1) {
+                    Ok(()) => CommandReturn::success(),
+                    Err((e, kernel_buf)) => {
+                        self.kernel_buf.replace(kernel_buf);
+                        CommandReturn::failure(e)
+                    }
                 },
             ),
 
@@ -1656,7 +1995,12 @@ impl<'a, A: hil::time::Alarm<'a>> Driver for SDCardDriver<'a, A> {
                                         }
 
                                         // begin writing
-                                        self.sdcard.write_blocks(kernel_buf, data as u32, 1)
+                                        self.sdcard
+                                            .write_blocks(kernel_buf, data as u32, 1)
+                                            .map_err(|(e, kernel_buf)| {
+                                                self.kernel_buf.replace(kernel_buf);
+                                                e
+                                            })
                                     })
                             })
                     })
@@ -1668,3 +2012,22 @@ impl<'a, A: hil::time::Alarm<'a>> Driver for SDCardDriver<'a, A> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_crc_matches_well_known_commands() {
+        // CMD0 and CMD8(0x1AA) are sent with fixed CRC bytes by most drivers
+        assert_eq!((crc7(&[0x40, 0x00, 0x00, 0x00, 0x00]) << 1) | 0x01, 0x95);
+        assert_eq!((crc7(&[0x48, 0x00, 0x00, 0x01, 0xAA]) << 1) | 0x01, 0x87);
+    }
+
+    #[test]
+    fn data_crc_matches_reference() {
+        // example from the SD physical layer specification
+        assert_eq!(crc16(&[0xFF; 512]), 0x7FA1);
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+}
@@ -0,0 +1,814 @@
+//! Driver for SPI NOR flash chips that describe themselves through SFDP.
+//!
+//! Unlike `capsules::mx25r6435f`, which knows one part, this driver discovers
+//! the chip when `initialize()` is called: it wakes the chip from deep power
+//! down, reads the JEDEC ID, and reads the Serial Flash Discoverable
+//! Parameters (see the `sfdp` module) to find the capacity, the program page
+//! size, the opcode of the 4 KiB sector erase, typical erase and program
+//! times, and whether 3 or 4 byte addresses are used. Parts without SFDP fall
+//! back to common defaults and the capacity encoded in their JEDEC ID.
+//!
+//! The chip is exposed through `hil::flash::Flash` in 4 KiB sectors, the
+//! smallest unit that can be erased. In addition, the chip can be put into
+//! deep power down, and the block protect bits of the status register can be
+//! set to protect part of the chip from writes and erases. Which blocks a
+//! given protection level covers is chip specific, see the datasheet. The
+//! chip ignores writes to protected blocks without reporting an error.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let spi_nor = static_init!(
+//!     capsules::spi_nor::SpiNor<
+//!         'static,
+//!         capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52::spi::SPIM>,
+//!         VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+//!     >,
+//!     capsules::spi_nor::SpiNor::new(
+//!         spi_nor_spi,
+//!         spi_nor_virtual_alarm,
+//!         &mut capsules::spi_nor::TXBUFFER,
+//!         &mut capsules::spi_nor::RXBUFFER,
+//!     )
+//! );
+//! spi_nor_spi.set_client(spi_nor);
+//! spi_nor_virtual_alarm.set_alarm_client(spi_nor);
+//! spi_nor.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::ops::{Index, IndexMut};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ErrorCode;
+
+pub mod sfdp;
+
+/// Bytes read or programmed by a single SPI transfer.
+const CHUNK: usize = 256;
+/// The opcode, up to four address bytes, and the payload.
+pub const BUFFER_LEN: usize = CHUNK + 5;
+
+pub static mut TXBUFFER: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+pub static mut RXBUFFER: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+
+/// Offset of the data in the receive buffer of an SFDP read.
+const SFDP_DATA: usize = 5;
+
+/// Size of the pages exposed through `hil::flash::Flash`.
+pub const SECTOR_SIZE: usize = 4096;
+
+const SPI_SPEED: u32 = 8000000;
+/// Time the chip takes to leave deep power down (tRES1).
+const RELEASE_TIME_US: u32 = 50;
+/// Time a write of the status register takes at most (tW).
+const STATUS_WRITE_TIME_US: u32 = 15_000;
+
+/// Write in progress bit of the status register.
+const STATUS_WIP: u8 = 0x01;
+/// Block protect bits of the status register.
+const STATUS_BLOCK_PROTECT: u8 = 0x3C;
+const BLOCK_PROTECT_SHIFT: u32 = 2;
+
+/// How addresses above 16 MiB are reached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FourByteMode {
+    /// The chip only takes 4 byte addresses.
+    Always,
+    /// EN4B switches the chip to 4 byte addresses.
+    Enter,
+    /// EN4B switches the chip to 4 byte addresses, after a write enable.
+    WriteEnableEnter,
+    /// Separate opcodes take 4 byte addresses.
+    Opcodes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Addressing {
+    ThreeByte,
+    FourByte(FourByteMode),
+}
+
+/// What was discovered about the chip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Geometry {
+    /// Capacity in bytes.
+    pub size: u32,
+    /// Bytes that a single page program can write.
+    pub page_size: u32,
+    /// Opcode that erases a 4 KiB sector.
+    pub erase_opcode: u8,
+    pub addressing: Addressing,
+    /// Typical time of a sector erase.
+    pub erase_time_us: u32,
+    /// Typical time of a page program.
+    pub program_time_us: u32,
+}
+
+impl Geometry {
+    fn address_bytes(&self) -> usize {
+        match self.addressing {
+            Addressing::ThreeByte => 3,
+            Addressing::FourByte(_) => 4,
+        }
+    }
+
+    fn four_byte_opcodes(&self) -> bool {
+        self.addressing == Addressing::FourByte(FourByteMode::Opcodes)
+    }
+
+    fn read_opcode(&self) -> u8 {
+        if self.four_byte_opcodes() {
+            Opcodes::READ4B as u8
+        } else {
+            Opcodes::READ as u8
+        }
+    }
+
+    fn program_opcode(&self) -> u8 {
+        if self.four_byte_opcodes() {
+            Opcodes::PP4B as u8
+        } else {
+            Opcodes::PP as u8
+        }
+    }
+
+    fn sector_erase_opcode(&self) -> u8 {
+        if self.four_byte_opcodes() {
+            Opcodes::SE4B as u8
+        } else {
+            self.erase_opcode
+        }
+    }
+
+    /// Bytes programmed by one page program, which must not cross a page.
+    fn program_chunk(&self) -> usize {
+        (self.page_size as usize).min(CHUNK).max(1)
+    }
+}
+
+/// A 4 KiB sector of the flash chip.
+pub struct SpiNorSector(pub [u8; SECTOR_SIZE]);
+
+impl Default for SpiNorSector {
+    fn default() -> Self {
+        Self {
+            0: [0; SECTOR_SIZE],
+        }
+    }
+}
+
+impl Index<usize> for SpiNorSector {
+    type Output = u8;
+
+    fn index(&self, idx: usize) -> &u8 {
+        &self.0[idx]
+    }
+}
+
+impl IndexMut<usize> for SpiNorSector {
+    fn index_mut(&mut self, idx: usize) -> &mut u8 {
+        &mut self.0[idx]
+    }
+}
+
+impl AsMut<[u8]> for SpiNorSector {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Notifications about operations that are not part of `hil::flash`.
+pub trait SpiNorClient {
+    /// `initialize()` finished. The chip is usable if `result` is `Ok`.
+    fn initialized(&self, result: Result<(), ErrorCode>);
+
+    /// The chip entered or left deep power down.
+    fn power_changed(&self, result: Result<(), ErrorCode>);
+
+    /// The block protection was written. Returns the level the chip reports
+    /// afterwards, or `FAIL` if it did not take the requested level, for
+    /// instance because the status register is locked by the WP# pin.
+    fn protection_changed(&self, result: Result<u8, ErrorCode>);
+}
+
+enum Opcodes {
+    WREN = 0x06,   // Write Enable
+    RDSR = 0x05,   // Read Status Register
+    WRSR = 0x01,   // Write Status Register
+    READ = 0x03,   // Normal Read
+    READ4B = 0x13, // Read with 4 byte address
+    PP = 0x02,     // Page Program (write)
+    PP4B = 0x12,   // Page Program with 4 byte address
+    SE4B = 0x21,   // Sector Erase with 4 byte address
+    RDID = 0x9f,   // Read Identification
+    RDSFDP = 0x5a, // Read SFDP
+    EN4B = 0xb7,   // Enter 4 byte address mode
+    DP = 0xb9,     // Deep Power Down
+    RDP = 0xab,    // Release from Deep Power Down
+}
+
+/// Operations that modify the chip: each is a write enable, the command, and
+/// waiting for the write in progress bit to clear.
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Erase { sector: u32, then_write: bool },
+    Program { sector: u32, offset: usize },
+    Protect { level: u8 },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+
+    ReleasePowerDown { initializing: bool },
+    WaitReleasePowerDown { initializing: bool },
+    ReadId,
+    ReadSfdpHeader,
+    ReadParameterHeaders { count: usize },
+    ReadBasicTable { len: usize },
+    Enter4ByteWriteEnable,
+    Enter4Byte,
+    ReadStatus,
+
+    PowerDown,
+
+    ReadSector { sector: u32, offset: usize },
+
+    WriteEnable(Operation),
+    Command(Operation),
+    WaitBusy(Operation),
+}
+
+pub struct SpiNor<'a, S: hil::spi::SpiMasterDevice + 'a, A: hil::time::Alarm<'a> + 'a> {
+    spi: &'a S,
+    alarm: &'a A,
+    state: Cell<State>,
+    jedec_id: OptionalCell<[u8; 3]>,
+    geometry: OptionalCell<Geometry>,
+    /// Last value read from the status register.
+    status: Cell<u8>,
+    powered_down: Cell<bool>,
+    txbuffer: TakeCell<'static, [u8]>,
+    rxbuffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn hil::flash::Client<SpiNor<'a, S, A>>>,
+    nor_client: OptionalCell<&'a dyn SpiNorClient>,
+    client_sector: TakeCell<'static, SpiNorSector>,
+}
+
+impl<'a, S: hil::spi::SpiMasterDevice + 'a, A: hil::time::Alarm<'a> + 'a> SpiNor<'a, S, A> {
+    pub fn new(
+        spi: &'a S,
+        alarm: &'a A,
+        txbuffer: &'static mut [u8],
+        rxbuffer: &'static mut [u8],
+    ) -> SpiNor<'a, S, A> {
+        SpiNor {
+            spi,
+            alarm,
+            state: Cell::new(State::Idle),
+            jedec_id: OptionalCell::empty(),
+            geometry: OptionalCell::empty(),
+            status: Cell::new(0),
+            powered_down: Cell::new(false),
+            txbuffer: TakeCell::new(txbuffer),
+            rxbuffer: TakeCell::new(rxbuffer),
+            client: OptionalCell::empty(),
+            nor_client: OptionalCell::empty(),
+            client_sector: TakeCell::empty(),
+        }
+    }
+
+    pub fn set_nor_client(&self, client: &'a dyn SpiNorClient) {
+        self.nor_client.set(client);
+    }
+
+    /// Discover the chip. `SpiNorClient::initialized()` is called when done.
+    pub fn initialize(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.spi.configure(
+            hil::spi::ClockPolarity::IdleLow,
+            hil::spi::ClockPhase::SampleLeading,
+            SPI_SPEED,
+        );
+        self.geometry.clear();
+        // The chip may have been left in deep power down before a reset
+        self.start_command(State::ReleasePowerDown { initializing: true }, Opcodes::RDP)
+    }
+
+    /// Manufacturer, memory type and capacity bytes of the chip.
+    pub fn jedec_id(&self) -> Option<[u8; 3]> {
+        self.jedec_id.extract()
+    }
+
+    /// The geometry of the chip, once it has been initialized.
+    pub fn geometry(&self) -> Option<Geometry> {
+        self.geometry.extract()
+    }
+
+    /// Number of sectors of the chip, once it has been initialized.
+    pub fn sectors(&self) -> usize {
+        self.geometry
+            .map_or(0, |geometry| geometry.size as usize / SECTOR_SIZE)
+    }
+
+    /// Put the chip into its lowest power state. All other operations fail
+    /// with `OFF` until `release_power_down()` completes.
+    pub fn deep_power_down(&self) -> Result<(), ErrorCode> {
+        if self.powered_down.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.start_command(State::PowerDown, Opcodes::DP)
+    }
+
+    pub fn release_power_down(&self) -> Result<(), ErrorCode> {
+        if !self.powered_down.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.start_command(
+            State::ReleasePowerDown {
+                initializing: false,
+            },
+            Opcodes::RDP,
+        )
+    }
+
+    /// Current block protection level, as read from the status register.
+    pub fn block_protection(&self) -> Option<u8> {
+        self.geometry
+            .map(|_| (self.status.get() & STATUS_BLOCK_PROTECT) >> BLOCK_PROTECT_SHIFT)
+    }
+
+    /// Write the block protect bits of the status register. Level 0 leaves
+    /// the whole chip writable.
+    pub fn set_block_protection(&self, level: u8) -> Result<(), ErrorCode> {
+        if level > STATUS_BLOCK_PROTECT >> BLOCK_PROTECT_SHIFT {
+            return Err(ErrorCode::INVAL);
+        }
+        self.check_ready()?;
+        self.start_operation(Operation::Protect { level })
+    }
+
+    fn check_ready(&self) -> Result<(), ErrorCode> {
+        if self.geometry.is_none() || self.powered_down.get() {
+            Err(ErrorCode::OFF)
+        } else if self.state.get() != State::Idle {
+            Err(ErrorCode::BUSY)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_sector(&self, sector: usize) -> Result<(), ErrorCode> {
+        self.check_ready()?;
+        if sector >= self.sectors() {
+            return Err(ErrorCode::INVAL);
+        }
+        Ok(())
+    }
+
+    /// Send a command without address or data.
+    fn start_command(&self, state: State, opcode: Opcodes) -> Result<(), ErrorCode> {
+        self.txbuffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |txbuffer| {
+                txbuffer[0] = opcode as u8;
+                self.state.set(state);
+                self.spi.read_write_bytes(txbuffer, None, 1)
+            })
+    }
+
+    fn start_operation(&self, operation: Operation) -> Result<(), ErrorCode> {
+        self.start_command(State::WriteEnable(operation), Opcodes::WREN)
+    }
+
+    /// Send `len` bytes of `txbuffer`, and read the answer if `read`.
+    fn transfer(&self, state: State, txbuffer: &'static mut [u8], len: usize, read: bool) {
+        self.state.set(state);
+        let rxbuffer = if read { self.rxbuffer.take() } else { None };
+        let _ = self.spi.read_write_bytes(txbuffer, rxbuffer, len);
+    }
+
+    /// Fill in the opcode and address of a command, and return its length.
+    fn command_header(&self, buffer: &mut [u8], opcode: u8, address: u32) -> usize {
+        let address_bytes = self.geometry.map_or(3, |geometry| geometry.address_bytes());
+        buffer[0] = opcode;
+        for i in 0..address_bytes {
+            buffer[1 + i] = (address >> (8 * (address_bytes - 1 - i))) as u8;
+        }
+        1 + address_bytes
+    }
+
+    /// Read `len` bytes of the SFDP area. The data starts at `SFDP_DATA` in
+    /// the receive buffer.
+    fn read_sfdp(&self, state: State, txbuffer: &'static mut [u8], address: u32, len: usize) {
+        txbuffer[0] = Opcodes::RDSFDP as u8;
+        txbuffer[1] = (address >> 16) as u8;
+        txbuffer[2] = (address >> 8) as u8;
+        txbuffer[3] = address as u8;
+        // Dummy byte
+        txbuffer[4] = 0;
+        self.transfer(state, txbuffer, SFDP_DATA + len, true);
+    }
+
+    fn read_status(&self, state: State, txbuffer: &'static mut [u8]) {
+        txbuffer[0] = Opcodes::RDSR as u8;
+        self.transfer(state, txbuffer, 2, true);
+    }
+
+    fn read_chunk(&self, txbuffer: &'static mut [u8], sector: u32, offset: usize) {
+        let opcode = self
+            .geometry
+            .map_or(Opcodes::READ as u8, |geometry| geometry.read_opcode());
+        let address = sector * SECTOR_SIZE as u32 + offset as u32;
+        let header = self.command_header(txbuffer, opcode, address);
+        self.transfer(
+            State::ReadSector { sector, offset },
+            txbuffer,
+            header + CHUNK,
+            true,
+        );
+    }
+
+    /// Use the geometry, and finish setting up the chip.
+    fn discovered(&self, txbuffer: &'static mut [u8], geometry: Result<Geometry, ErrorCode>) {
+        match geometry {
+            Ok(geometry) => {
+                self.geometry.set(geometry);
+                match geometry.addressing {
+                    Addressing::FourByte(FourByteMode::Enter) => {
+                        txbuffer[0] = Opcodes::EN4B as u8;
+                        self.transfer(State::Enter4Byte, txbuffer, 1, false);
+                    }
+                    Addressing::FourByte(FourByteMode::WriteEnableEnter) => {
+                        txbuffer[0] = Opcodes::WREN as u8;
+                        self.transfer(State::Enter4ByteWriteEnable, txbuffer, 1, false);
+                    }
+                    _ => self.read_status(State::ReadStatus, txbuffer),
+                }
+            }
+            Err(e) => {
+                self.state.set(State::Idle);
+                self.txbuffer.replace(txbuffer);
+                self.nor_client.map(|client| client.initialized(Err(e)));
+            }
+        }
+    }
+
+    /// Send the command of an operation after the write enable.
+    fn operation_command(&self, txbuffer: &'static mut [u8], operation: Operation) {
+        let geometry = match self.geometry.extract() {
+            Some(geometry) => geometry,
+            None => return self.operation_done(txbuffer, operation, Err(ErrorCode::OFF)),
+        };
+        let len = match operation {
+            Operation::Erase { sector, .. } => self.command_header(
+                txbuffer,
+                geometry.sector_erase_opcode(),
+                sector * SECTOR_SIZE as u32,
+            ),
+            Operation::Program { sector, offset } => {
+                let chunk = geometry.program_chunk();
+                let header = self.command_header(
+                    txbuffer,
+                    geometry.program_opcode(),
+                    sector * SECTOR_SIZE as u32 + offset as u32,
+                );
+                self.client_sector.map(|sector| {
+                    txbuffer[header..header + chunk]
+                        .copy_from_slice(&sector.0[offset..offset + chunk]);
+                });
+                header + chunk
+            }
+            Operation::Protect { level } => {
+                txbuffer[0] = Opcodes::WRSR as u8;
+                txbuffer[1] =
+                    (self.status.get() & !STATUS_BLOCK_PROTECT) | (level << BLOCK_PROTECT_SHIFT);
+                2
+            }
+        };
+        self.transfer(State::Command(operation), txbuffer, len, false);
+    }
+
+    /// Typical duration of an operation, after which the chip is polled.
+    fn operation_time_us(&self, operation: Operation) -> u32 {
+        self.geometry.map_or(0, |geometry| match operation {
+            Operation::Erase { .. } => geometry.erase_time_us,
+            Operation::Program { .. } => geometry.program_time_us,
+            Operation::Protect { .. } => STATUS_WRITE_TIME_US,
+        })
+    }
+
+    /// The chip finished an operation, so continue with the next step of the
+    /// request or report it.
+    fn operation_done(
+        &self,
+        txbuffer: &'static mut [u8],
+        operation: Operation,
+        result: Result<(), ErrorCode>,
+    ) {
+        let next = match (operation, result) {
+            (Operation::Erase { sector, then_write }, Ok(())) if then_write => {
+                Some(Operation::Program { sector, offset: 0 })
+            }
+            (Operation::Program { sector, offset }, Ok(())) => {
+                let offset = offset + self.geometry.map_or(CHUNK, |g| g.program_chunk());
+                if offset < SECTOR_SIZE {
+                    Some(Operation::Program { sector, offset })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        if let Some(operation) = next {
+            // Every erase and program needs its own write enable
+            txbuffer[0] = Opcodes::WREN as u8;
+            return self.transfer(State::WriteEnable(operation), txbuffer, 1, false);
+        }
+
+        self.state.set(State::Idle);
+        self.txbuffer.replace(txbuffer);
+        let error = match result {
+            Ok(()) => hil::flash::Error::CommandComplete,
+            Err(_) => hil::flash::Error::FlashError,
+        };
+        match operation {
+            Operation::Erase {
+                then_write: false, ..
+            } => {
+                self.client.map(|client| client.erase_complete(error));
+            }
+            Operation::Erase { .. } | Operation::Program { .. } => {
+                self.client_sector.take().map(|sector| {
+                    self.client
+                        .map(move |client| client.write_complete(sector, error));
+                });
+            }
+            Operation::Protect { level } => {
+                let result = result.and_then(|()| match self.block_protection() {
+                    Some(actual) if actual == level => Ok(actual),
+                    _ => Err(ErrorCode::FAIL),
+                });
+                self.nor_client
+                    .map(|client| client.protection_changed(result));
+            }
+        }
+    }
+}
+
+impl<'a, S: hil::spi::SpiMasterDevice + 'a, A: hil::time::Alarm<'a> + 'a> hil::spi::SpiMasterClient
+    for SpiNor<'a, S, A>
+{
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        _len: usize,
+    ) {
+        if let Some(read_buffer) = read_buffer {
+            self.rxbuffer.replace(read_buffer);
+        }
+
+        match self.state.get() {
+            State::Idle => {
+                self.txbuffer.replace(write_buffer);
+            }
+            State::ReleasePowerDown { initializing } => {
+                self.txbuffer.replace(write_buffer);
+                self.state.set(State::WaitReleasePowerDown { initializing });
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_us(RELEASE_TIME_US));
+            }
+            State::WaitReleasePowerDown { .. } => {
+                self.txbuffer.replace(write_buffer);
+            }
+            State::ReadId => {
+                let id = self.rxbuffer.map_or([0; 3], |rx| [rx[1], rx[2], rx[3]]);
+                if id == [0; 3] || id == [0xFF; 3] {
+                    // Nothing answered
+                    return self.discovered(write_buffer, Err(ErrorCode::NODEVICE));
+                }
+                self.jedec_id.set(id);
+                self.read_sfdp(State::ReadSfdpHeader, write_buffer, 0, sfdp::HEADER_LEN);
+            }
+            State::ReadSfdpHeader => {
+                let headers = self.rxbuffer.map_or(Err(ErrorCode::RESERVE), |rx| {
+                    sfdp::parameter_headers(&rx[SFDP_DATA..SFDP_DATA + sfdp::HEADER_LEN])
+                });
+                match headers {
+                    Ok(count) => {
+                        let count = count.min(CHUNK / sfdp::PARAMETER_HEADER_LEN);
+                        self.read_sfdp(
+                            State::ReadParameterHeaders { count },
+                            write_buffer,
+                            sfdp::HEADER_LEN as u32,
+                            count * sfdp::PARAMETER_HEADER_LEN,
+                        );
+                    }
+                    Err(_) => {
+                        let id = self.jedec_id.extract().unwrap_or([0; 3]);
+                        self.discovered(write_buffer, sfdp::from_jedec_id(id));
+                    }
+                }
+            }
+            State::ReadParameterHeaders { count } => {
+                let table = self.rxbuffer.map_or(None, |rx| {
+                    sfdp::basic_table(
+                        &rx[SFDP_DATA..SFDP_DATA + count * sfdp::PARAMETER_HEADER_LEN],
+                    )
+                });
+                match table {
+                    Some(table) => {
+                        let len = table.dwords.min(sfdp::BASIC_TABLE_DWORDS) * 4;
+                        self.read_sfdp(
+                            State::ReadBasicTable { len },
+                            write_buffer,
+                            table.address,
+                            len,
+                        );
+                    }
+                    None => self.discovered(write_buffer, Err(ErrorCode::NOSUPPORT)),
+                }
+            }
+            State::ReadBasicTable { len } => {
+                let geometry = self.rxbuffer.map_or(Err(ErrorCode::RESERVE), |rx| {
+                    sfdp::parse_basic_table(&rx[SFDP_DATA..SFDP_DATA + len])
+                });
+                self.discovered(write_buffer, geometry);
+            }
+            State::Enter4ByteWriteEnable => {
+                write_buffer[0] = Opcodes::EN4B as u8;
+                self.transfer(State::Enter4Byte, write_buffer, 1, false);
+            }
+            State::Enter4Byte => {
+                self.read_status(State::ReadStatus, write_buffer);
+            }
+            State::ReadStatus => {
+                self.rxbuffer.map(|rx| self.status.set(rx[1]));
+                self.state.set(State::Idle);
+                self.txbuffer.replace(write_buffer);
+                self.nor_client.map(|client| client.initialized(Ok(())));
+            }
+            State::PowerDown => {
+                self.powered_down.set(true);
+                self.state.set(State::Idle);
+                self.txbuffer.replace(write_buffer);
+                self.nor_client.map(|client| client.power_changed(Ok(())));
+            }
+            State::ReadSector { sector, offset } => {
+                let header = self.geometry.map_or(4, |g| 1 + g.address_bytes());
+                self.client_sector.map(|client_sector| {
+                    self.rxbuffer.map(|rx| {
+                        client_sector.0[offset..offset + CHUNK]
+                            .copy_from_slice(&rx[header..header + CHUNK]);
+                    });
+                });
+                if offset + CHUNK < SECTOR_SIZE {
+                    self.read_chunk(write_buffer, sector, offset + CHUNK);
+                } else {
+                    self.state.set(State::Idle);
+                    self.txbuffer.replace(write_buffer);
+                    self.client_sector.take().map(|client_sector| {
+                        self.client.map(move |client| {
+                            client.read_complete(client_sector, hil::flash::Error::CommandComplete)
+                        });
+                    });
+                }
+            }
+            State::WriteEnable(operation) => {
+                self.operation_command(write_buffer, operation);
+            }
+            State::Command(operation) => {
+                self.txbuffer.replace(write_buffer);
+                self.state.set(State::WaitBusy(operation));
+                self.alarm.set_alarm(
+                    self.alarm.now(),
+                    A::ticks_from_us(self.operation_time_us(operation)),
+                );
+            }
+            State::WaitBusy(operation) => {
+                let status = self.rxbuffer.map_or(STATUS_WIP, |rx| rx[1]);
+                self.status.set(status);
+                if status & STATUS_WIP != 0 {
+                    // Still busy, check again a little later
+                    self.txbuffer.replace(write_buffer);
+                    self.alarm.set_alarm(
+                        self.alarm.now(),
+                        A::ticks_from_us((self.operation_time_us(operation) / 4).max(100)),
+                    );
+                } else {
+                    self.operation_done(write_buffer, operation, Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl<'a, S: hil::spi::SpiMasterDevice + 'a, A: hil::time::Alarm<'a> + 'a> hil::time::AlarmClient
+    for SpiNor<'a, S, A>
+{
+    fn alarm(&self) {
+        let txbuffer = match self.txbuffer.take() {
+            Some(txbuffer) => txbuffer,
+            None => return,
+        };
+        match self.state.get() {
+            State::WaitReleasePowerDown { initializing: true } => {
+                self.powered_down.set(false);
+                txbuffer[0] = Opcodes::RDID as u8;
+                self.transfer(State::ReadId, txbuffer, 4, true);
+            }
+            State::WaitReleasePowerDown {
+                initializing: false,
+            } => {
+                self.powered_down.set(false);
+                self.state.set(State::Idle);
+                self.txbuffer.replace(txbuffer);
+                self.nor_client.map(|client| client.power_changed(Ok(())));
+            }
+            State::WaitBusy(operation) => {
+                self.read_status(State::WaitBusy(operation), txbuffer);
+            }
+            _ => {
+                self.txbuffer.replace(txbuffer);
+            }
+        }
+    }
+}
+
+impl<
+        'a,
+        S: hil::spi::SpiMasterDevice + 'a,
+        A: hil::time::Alarm<'a> + 'a,
+        C: hil::flash::Client<Self>,
+    > hil::flash::HasClient<'a, C> for SpiNor<'a, S, A>
+{
+    fn set_client(&self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl<'a, S: hil::spi::SpiMasterDevice + 'a, A: hil::time::Alarm<'a> + 'a> hil::flash::Flash
+    for SpiNor<'a, S, A>
+{
+    type Page = SpiNorSector;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if let Err(e) = self.check_sector(page_number) {
+            return Err((e, buf));
+        }
+        match self.txbuffer.take() {
+            Some(txbuffer) => {
+                self.client_sector.replace(buf);
+                self.read_chunk(txbuffer, page_number as u32, 0);
+                Ok(())
+            }
+            None => Err((ErrorCode::RESERVE, buf)),
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        if let Err(e) = self.check_sector(page_number) {
+            return Err((e, buf));
+        }
+        match self.start_operation(Operation::Erase {
+            sector: page_number as u32,
+            then_write: true,
+        }) {
+            Ok(()) => {
+                self.client_sector.replace(buf);
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        self.check_sector(page_number)?;
+        self.start_operation(Operation::Erase {
+            sector: page_number as u32,
+            then_write: false,
+        })
+    }
+}
@@ -0,0 +1,376 @@
+//! Parser for the Serial Flash Discoverable Parameters (SFDP) of SPI NOR
+//! flash, as defined by JEDEC JESD216.
+//!
+//! The SFDP area starts with an 8 byte header, followed by a list of 8 byte
+//! parameter headers that point to parameter tables. Only the Basic Flash
+//! Parameter Table (BFPT), which every part has to provide, is used here. It
+//! is a list of little-endian 32 bit DWORDs; JESD216 defines the first 9,
+//! JESD216A and B extend it to 16.
+//!
+//! These functions work on bytes read from the SFDP area, so they do not
+//! depend on the bus.
+
+use super::{Addressing, FourByteMode, Geometry, SECTOR_SIZE};
+use kernel::ErrorCode;
+
+/// Length of the SFDP header at address 0.
+pub const HEADER_LEN: usize = 8;
+/// Length of a parameter header. The first one follows the SFDP header.
+pub const PARAMETER_HEADER_LEN: usize = 8;
+/// DWORDs of the basic table that are used.
+pub const BASIC_TABLE_DWORDS: usize = 16;
+
+/// Typical time of a sector erase if the part does not specify it.
+const DEFAULT_ERASE_TIME_US: u32 = 50_000;
+/// Typical time of a page program if the part does not specify it.
+const DEFAULT_PROGRAM_TIME_US: u32 = 1_000;
+const DEFAULT_PAGE_SIZE: u32 = 256;
+
+const SIGNATURE: &[u8] = b"SFDP";
+const BASIC_TABLE_ID: u16 = 0xFF00;
+/// Parts above this size need more than three address bytes.
+const THREE_BYTE_LIMIT: u32 = 1 << 24;
+
+/// Location of a parameter table in the SFDP area.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TableLocation {
+    pub address: u32,
+    pub dwords: usize,
+}
+
+/// Checks the SFDP header and returns the number of parameter headers that
+/// follow it.
+pub fn parameter_headers(header: &[u8]) -> Result<usize, ErrorCode> {
+    if header.len() < HEADER_LEN || &header[0..4] != SIGNATURE {
+        return Err(ErrorCode::NOSUPPORT);
+    }
+    // Only the major revision changes the layout
+    if header[5] != 1 {
+        return Err(ErrorCode::NOSUPPORT);
+    }
+    Ok(header[6] as usize + 1)
+}
+
+/// Finds the basic table in the parameter headers. Parts may list revisions
+/// of it, in which case the newest one is returned.
+pub fn basic_table(headers: &[u8]) -> Option<TableLocation> {
+    headers
+        .chunks_exact(PARAMETER_HEADER_LEN)
+        .filter(|h| u16::from_le_bytes([h[0], h[7]]) == BASIC_TABLE_ID && h[2] == 1)
+        .max_by_key(|h| h[1])
+        .map(|h| TableLocation {
+            address: u32::from_le_bytes([h[4], h[5], h[6], 0]),
+            dwords: h[3] as usize,
+        })
+}
+
+/// DWORD `n` of a table, counting from 1 like JESD216.
+fn dword(table: &[u8], n: usize) -> Option<u32> {
+    let offset = (n - 1) * 4;
+    table
+        .get(offset..offset + 4)
+        .map(|d| u32::from_le_bytes([d[0], d[1], d[2], d[3]]))
+}
+
+/// Reads the geometry of the part from its basic table.
+pub fn parse_basic_table(table: &[u8]) -> Result<Geometry, ErrorCode> {
+    let first = dword(table, 1).ok_or(ErrorCode::INVAL)?;
+    let density = dword(table, 2).ok_or(ErrorCode::INVAL)?;
+    // The erase types are part of the original 9 DWORDs
+    let erase_types = [
+        dword(table, 8).ok_or(ErrorCode::INVAL)?,
+        dword(table, 9).ok_or(ErrorCode::INVAL)?,
+    ];
+
+    let size = if density & 0x8000_0000 == 0 {
+        ((density as u64 + 1) / 8) as u32
+    } else {
+        let bits = density & 0x7FFF_FFFF;
+        if bits < 3 || bits > 34 {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        1 << (bits - 3)
+    };
+
+    // Find the erase type that clears a sector
+    let mut erase = None;
+    for n in 0..4 {
+        let descriptor = (erase_types[n / 2] >> (16 * (n % 2))) & 0xFFFF;
+        let (exponent, opcode) = (descriptor & 0xFF, (descriptor >> 8) as u8);
+        if exponent == SECTOR_SIZE.trailing_zeros() && opcode != 0 {
+            erase = Some((n, opcode));
+            break;
+        }
+    }
+    let (erase_opcode, erase_time_us) = match erase {
+        Some((n, opcode)) => {
+            let time = dword(table, 10).map_or(DEFAULT_ERASE_TIME_US, |times| {
+                let field = times >> (4 + 7 * n);
+                let unit_ms = match (field >> 5) & 0x3 {
+                    0 => 1,
+                    1 => 16,
+                    2 => 128,
+                    _ => 1000,
+                };
+                ((field & 0x1F) + 1) * unit_ms * 1000
+            });
+            (opcode, time)
+        }
+        // Parts listing no erase types may still announce a 4 KiB erase
+        None if first & 0x3 == 0x1 => ((first >> 8) as u8, DEFAULT_ERASE_TIME_US),
+        None => return Err(ErrorCode::NOSUPPORT),
+    };
+
+    let (page_size, program_time_us) =
+        dword(table, 11).map_or((DEFAULT_PAGE_SIZE, DEFAULT_PROGRAM_TIME_US), |timing| {
+            let unit_us = if timing & (1 << 13) == 0 { 8 } else { 64 };
+            (
+                1 << ((timing >> 4) & 0xF),
+                (((timing >> 8) & 0x1F) + 1) * unit_us,
+            )
+        });
+
+    let address_bytes = (first >> 17) & 0x3;
+    let (size, addressing) = match address_bytes {
+        // Four byte addresses only
+        0b10 => (size, Addressing::FourByte(FourByteMode::Always)),
+        // Three byte addresses only, anything above is out of reach
+        0b00 => (size.min(THREE_BYTE_LIMIT), Addressing::ThreeByte),
+        _ if size <= THREE_BYTE_LIMIT => (size, Addressing::ThreeByte),
+        _ => (
+            size,
+            Addressing::FourByte(four_byte_mode(dword(table, 16), erase_opcode)?),
+        ),
+    };
+
+    Ok(Geometry {
+        size,
+        page_size,
+        erase_opcode,
+        addressing,
+        erase_time_us,
+        program_time_us,
+    })
+}
+
+/// Picks how to reach the upper part of a large part, from the "enter 4-byte
+/// addressing" field of DWORD 16.
+fn four_byte_mode(sixteenth: Option<u32>, erase_opcode: u8) -> Result<FourByteMode, ErrorCode> {
+    let methods = match sixteenth {
+        Some(dword) => (dword >> 24) as u8,
+        // Older tables do not say, but nearly all parts implement EN4B
+        None => return Ok(FourByteMode::Enter),
+    };
+    if methods & 0x40 != 0 {
+        Ok(FourByteMode::Always)
+    } else if methods & 0x01 != 0 {
+        Ok(FourByteMode::Enter)
+    } else if methods & 0x02 != 0 {
+        Ok(FourByteMode::WriteEnableEnter)
+    } else if methods & 0x10 != 0 && erase_opcode == 0x20 {
+        // The dedicated instruction set only maps the common opcodes
+        Ok(FourByteMode::Opcodes)
+    } else {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+/// Guesses the geometry of a part without SFDP from its JEDEC ID, whose last
+/// byte encodes the capacity as a power of two for most vendors.
+pub fn from_jedec_id(id: [u8; 3]) -> Result<Geometry, ErrorCode> {
+    let capacity = id[2];
+    if capacity < 16 || capacity > 24 {
+        return Err(ErrorCode::NOSUPPORT);
+    }
+    Ok(Geometry {
+        size: 1 << capacity,
+        page_size: DEFAULT_PAGE_SIZE,
+        erase_opcode: 0x20,
+        addressing: Addressing::ThreeByte,
+        erase_time_us: DEFAULT_ERASE_TIME_US,
+        program_time_us: DEFAULT_PROGRAM_TIME_US,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    /// SFDP area of a 64 Mbit part with a JESD216 basic table at 0x30 and a
+    /// vendor table at 0x60.
+    const SFDP_64MBIT: [u8; 16] = [
+        0x53, 0x46, 0x44, 0x50, 0x00, 0x01, 0x01, 0xFF, // SFDP header
+        0x00, 0x00, 0x01, 0x09, 0x30, 0x00, 0x00, 0xFF, // basic table
+    ];
+    const VENDOR_HEADER: [u8; 8] = [0xC2, 0x00, 0x01, 0x04, 0x60, 0x00, 0x00, 0xFF];
+    const BASIC_64MBIT: [u32; 9] = [
+        0xFFF3_20E5,
+        0x03FF_FFFF,
+        0x6B08_EB44,
+        0x3B04_BB08,
+        0xFFFF_FFFE,
+        0xFF00_FFFF,
+        0xFF00_FFFF,
+        0x520F_200C,
+        0xFF00_D810,
+    ];
+    /// JESD216B basic table of a 256 Mbit part that takes 3 or 4 byte
+    /// addresses.
+    const BASIC_256MBIT: [u32; 16] = [
+        0xFFFB_20E5,
+        0x0FFF_FFFF,
+        0x6B08_EB44,
+        0xBB04_3B08,
+        0xFFFF_FFFE,
+        0xFF00_FFFF,
+        0xEB04_FFFF,
+        0x520F_200C,
+        0xFF00_D810,
+        0x00A6_0222,
+        0x0051_2981,
+        0x33EA_14C4,
+        0x38A3_8344,
+        0x1F5C_D4F7,
+        0x5CD2_3D30,
+        0x11F0_30F8,
+    ];
+
+    fn bytes(dwords: &[u32]) -> Vec<u8> {
+        dwords
+            .iter()
+            .flat_map(|d| d.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn finds_basic_table() {
+        assert_eq!(parameter_headers(&SFDP_64MBIT[..HEADER_LEN]), Ok(2));
+        let mut headers = SFDP_64MBIT[HEADER_LEN..].to_vec();
+        headers.extend_from_slice(&VENDOR_HEADER);
+        assert_eq!(
+            basic_table(&headers),
+            Some(TableLocation {
+                address: 0x30,
+                dwords: 9
+            })
+        );
+        assert_eq!(basic_table(&VENDOR_HEADER), None);
+
+        // A later revision of the basic table wins
+        headers.extend_from_slice(&[0x00, 0x06, 0x01, 0x10, 0x80, 0x00, 0x00, 0xFF]);
+        assert_eq!(
+            basic_table(&headers),
+            Some(TableLocation {
+                address: 0x80,
+                dwords: 16
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_missing_signature() {
+        assert_eq!(
+            parameter_headers(&[0xFF; HEADER_LEN]),
+            Err(ErrorCode::NOSUPPORT)
+        );
+        let mut header = SFDP_64MBIT;
+        header[5] = 2;
+        assert_eq!(
+            parameter_headers(&header[..HEADER_LEN]),
+            Err(ErrorCode::NOSUPPORT)
+        );
+    }
+
+    #[test]
+    fn parses_jesd216_table() {
+        assert_eq!(
+            parse_basic_table(&bytes(&BASIC_64MBIT)),
+            Ok(Geometry {
+                size: 8 * 1024 * 1024,
+                page_size: 256,
+                erase_opcode: 0x20,
+                addressing: Addressing::ThreeByte,
+                erase_time_us: DEFAULT_ERASE_TIME_US,
+                program_time_us: DEFAULT_PROGRAM_TIME_US,
+            })
+        );
+        assert_eq!(
+            parse_basic_table(&bytes(&BASIC_64MBIT[..8])),
+            Err(ErrorCode::INVAL)
+        );
+    }
+
+    #[test]
+    fn parses_jesd216b_table() {
+        assert_eq!(
+            parse_basic_table(&bytes(&BASIC_256MBIT)),
+            Ok(Geometry {
+                size: 32 * 1024 * 1024,
+                page_size: 256,
+                erase_opcode: 0x20,
+                addressing: Addressing::FourByte(FourByteMode::Enter),
+                erase_time_us: 48_000,
+                program_time_us: 640,
+            })
+        );
+    }
+
+    #[test]
+    fn picks_four_byte_mode() {
+        let mut table = BASIC_256MBIT;
+        for &(methods, mode) in &[
+            (0x40, Ok(FourByteMode::Always)),
+            (0x02, Ok(FourByteMode::WriteEnableEnter)),
+            (0x10, Ok(FourByteMode::Opcodes)),
+            (0x04, Err(ErrorCode::NOSUPPORT)),
+        ] {
+            table[15] = (table[15] & 0x00FF_FFFF) | (methods << 24);
+            assert_eq!(
+                parse_basic_table(&bytes(&table)).map(|g| g.addressing),
+                mode.map(Addressing::FourByte)
+            );
+        }
+
+        // A 1 Gbit part with four byte addresses only and the density as a
+        // power of two
+        table[0] = (table[0] & !(0x3 << 17)) | (0b10 << 17);
+        table[1] = 0x8000_0000 | 30;
+        let geometry = parse_basic_table(&bytes(&table)).unwrap();
+        assert_eq!(geometry.size, 128 * 1024 * 1024);
+        assert_eq!(
+            geometry.addressing,
+            Addressing::FourByte(FourByteMode::Always)
+        );
+    }
+
+    #[test]
+    fn requires_sector_erase() {
+        let mut table = BASIC_64MBIT;
+        // Only 32 and 64 KiB erases
+        table[0] |= 0x3;
+        table[7] = 0xD810_520F;
+        table[8] = 0;
+        assert_eq!(parse_basic_table(&bytes(&table)), Err(ErrorCode::NOSUPPORT));
+
+        // No erase types, but the 4 KiB erase in DWORD 1
+        table[0] &= !0x3;
+        table[0] |= 0x1;
+        table[7] = 0;
+        assert_eq!(
+            parse_basic_table(&bytes(&table)).map(|g| g.erase_opcode),
+            Ok(0x20)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_jedec_id() {
+        assert_eq!(
+            from_jedec_id([0xC2, 0x28, 0x17]).map(|g| g.size),
+            Ok(8 * 1024 * 1024)
+        );
+        assert_eq!(from_jedec_id([0xFF, 0xFF, 0xFF]), Err(ErrorCode::NOSUPPORT));
+    }
+}
@@ -0,0 +1,247 @@
+//! Fixtures shared by the host tests of capsules.
+//!
+//! Capsules hold `'static` references to the layers around them, so tests
+//! leak every part they construct. Emulated parts complete operations only
+//! when the test lets them, like hardware raising an interrupt: `run()` lets
+//! every part complete its operations until all of them are idle.
+
+extern crate std;
+
+use std::boxed::Box;
+use std::cell::{Cell, RefCell};
+use std::vec;
+use std::vec::Vec;
+
+use kernel::capabilities::UdpDriverCapability;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::rng::{self, Random};
+
+use crate::emulated_flash::EmulatedFlash;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortBindingTx;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use crate::net::udp::UDPHeader;
+
+pub fn leak<T>(t: T) -> &'static T {
+    Box::leak(Box::new(t))
+}
+
+pub fn leak_mut<T>(t: T) -> &'static mut T {
+    Box::leak(Box::new(t))
+}
+
+/// A zeroed buffer of `len` bytes.
+pub fn buffer(len: usize) -> &'static mut [u8] {
+    Box::leak(vec![0; len].into_boxed_slice())
+}
+
+/// A deferred caller with room for one client.
+pub fn deferred_caller() -> &'static DynamicDeferredCall {
+    let states: &'static [DynamicDeferredCallClientState] =
+        leak([DynamicDeferredCallClientState::default()]);
+    leak(DynamicDeferredCall::new(states))
+}
+
+/// An emulated part whose operations complete when the test lets them.
+pub trait Pending {
+    /// Complete the pending operations, calling back the client. Returns
+    /// whether there were any.
+    fn complete(&self) -> bool;
+}
+
+/// Let the parts complete operations, including the ones started from their
+/// callbacks, until all of them are idle.
+pub fn run(parts: &[&dyn Pending]) {
+    while parts.iter().any(|part| part.complete()) {}
+}
+
+impl<const N: usize> Pending for EmulatedFlash<N> {
+    fn complete(&self) -> bool {
+        self.run()
+    }
+}
+
+/// Records the callbacks of a client, for the test to check in order.
+pub struct Events<E>(RefCell<Vec<E>>);
+
+impl<E> Events<E> {
+    pub fn new() -> Events<E> {
+        Events(RefCell::new(Vec::new()))
+    }
+
+    pub fn push(&self, event: E) {
+        self.0.borrow_mut().push(event);
+    }
+
+    /// The events since the last call.
+    pub fn take(&self) -> Vec<E> {
+        self.0.borrow_mut().drain(..).collect()
+    }
+}
+
+/// Produces `first`, `first + step`, `first + 2 * step`, ... both
+/// synchronously and through the asynchronous `Rng` interface.
+pub struct CountingRng {
+    next: Cell<u32>,
+    step: u32,
+    client: OptionalCell<&'static dyn rng::Client>,
+    requested: Cell<bool>,
+}
+
+impl CountingRng {
+    pub fn new(first: u32, step: u32) -> CountingRng {
+        CountingRng {
+            next: Cell::new(first),
+            step,
+            client: OptionalCell::empty(),
+            requested: Cell::new(false),
+        }
+    }
+}
+
+impl Random<'static> for CountingRng {
+    fn initialize(&'static self) {}
+
+    fn reseed(&self, seed: u32) {
+        self.next.set(seed);
+    }
+
+    fn random(&self) -> u32 {
+        let v = self.next.get();
+        self.next.set(v.wrapping_add(self.step));
+        v
+    }
+}
+
+impl rng::Rng<'static> for CountingRng {
+    fn get(&self) -> Result<(), kernel::ErrorCode> {
+        self.requested.set(true);
+        Ok(())
+    }
+
+    fn cancel(&self) -> Result<(), kernel::ErrorCode> {
+        self.requested.set(false);
+        Ok(())
+    }
+
+    fn set_client(&'static self, client: &'static dyn rng::Client) {
+        self.client.set(client);
+    }
+}
+
+impl Pending for CountingRng {
+    fn complete(&self) -> bool {
+        if !self.requested.take() {
+            return false;
+        }
+        let mut values = (0..).map(|_| self.random());
+        self.client
+            .map(|client| client.randomness_available(&mut values, Ok(())));
+        true
+    }
+}
+
+/// A datagram passed to `MockUdp`.
+#[derive(Debug)]
+pub struct Datagram {
+    pub dst: IPAddr,
+    pub dst_port: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Takes datagrams from the capsule under test. The port counts as bound,
+/// since binding needs the UDP driver.
+pub struct MockUdp {
+    client: OptionalCell<&'static dyn UDPSendClient>,
+    sent: RefCell<Vec<Datagram>>,
+    pending: MapCell<LeasableBuffer<'static, u8>>,
+}
+
+impl MockUdp {
+    pub fn new() -> MockUdp {
+        MockUdp {
+            client: OptionalCell::empty(),
+            sent: RefCell::new(Vec::new()),
+            pending: MapCell::empty(),
+        }
+    }
+
+    /// The datagrams sent since the last call.
+    pub fn sent(&self) -> Vec<Datagram> {
+        self.sent.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Pending for MockUdp {
+    fn complete(&self) -> bool {
+        match self.pending.take() {
+            Some(buf) => {
+                self.client.map(|client| client.send_done(Ok(()), buf));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl UDPSender<'static> for MockUdp {
+    fn set_client(&self, client: &'static dyn UDPSendClient) {
+        self.client.set(client);
+    }
+
+    fn send_to(
+        &'static self,
+        dst: IPAddr,
+        dst_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        if self.pending.is_some() {
+            return Err(buf);
+        }
+        self.sent.borrow_mut().push(Datagram {
+            dst,
+            dst_port,
+            payload: buf[..].to_vec(),
+        });
+        self.pending.replace(buf);
+        Ok(())
+    }
+
+    fn driver_send_to(
+        &'static self,
+        _dest: IPAddr,
+        _dst_port: u16,
+        _src_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        _driver_send_cap: &dyn UdpDriverCapability,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    fn send(
+        &'static self,
+        _dest: IPAddr,
+        _udp_header: UDPHeader,
+        buf: LeasableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    fn get_binding(&self) -> Option<UdpPortBindingTx> {
+        None
+    }
+
+    fn is_bound(&self) -> bool {
+        true
+    }
+
+    fn set_binding(&self, _binding: UdpPortBindingTx) -> Option<UdpPortBindingTx> {
+        None
+    }
+}
@@ -29,6 +29,9 @@
 //! +-----------------------+
 //!
 //!    hil::flash
+//!
+//! TicKV uses the page-based `Flash` interface, not `FlashRange`, because
+//! the flash controllers it runs on are shared through `MuxFlash`.
 
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
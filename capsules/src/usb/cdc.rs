@@ -145,6 +145,9 @@ pub struct CdcAcm<'a, U: 'a, A: 'a + Alarm<'a>> {
     /// useful for ensuring debug messages early in the boot process can be
     /// delivered over the console).
     boot_period: Cell<bool>,
+    /// Whether the host suspended the bus. A connected host keeps the
+    /// connection across a suspend, so we keep queuing messages for it.
+    suspended: Cell<bool>,
 
     /// Deferred Caller
     deferred_caller: &'a DynamicDeferredCall,
@@ -301,6 +304,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
             rx_client: OptionalCell::empty(),
             timeout_alarm,
             boot_period: Cell::new(true),
+            suspended: Cell::new(false),
             deferred_caller,
             handle: OptionalCell::empty(),
             deferred_call_pending_droptx: Cell::new(false),
@@ -370,10 +374,40 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     }
 
     fn bus_reset(&'a self) {
+        self.client_ctrl.bus_reset();
+        self.suspended.set(false);
+
+        // A host may reset the bus when it resumes. Keep the pending message
+        // for a while in case the host reconnects, as during boot.
+        if self.state.get() == State::Connected
+            && self.tx_buffer.is_some()
+            && !self.boot_period.get()
+        {
+            self.timeout_alarm.set_alarm(
+                self.timeout_alarm.now(),
+                A::ticks_from_ms(CDC_BUFFER_TIMEOUT_MS),
+            );
+        }
+
         // We take a bus reset to mean the enumeration has finished.
         self.state.set(State::Enumerated);
     }
 
+    fn suspend(&'a self) {
+        // The controller holds back the IN transfers until the bus resumes.
+        self.suspended.set(true);
+    }
+
+    fn resume(&'a self) {
+        // Make sure that a message queued while suspended gets sent.
+        if self.suspended.replace(false)
+            && self.state.get() == State::Connected
+            && self.tx_buffer.is_some()
+        {
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        }
+    }
+
     /// Handle a Control Setup transaction.
     ///
     /// CDC uses special values here, and we can use these to know when a CDC
@@ -642,7 +676,8 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::Transmit<'a>
             // Don't try to send if there is no CDC client connected.
             if self.state.get() == State::Connected {
                 // Then signal to the lower layer that we are ready to do a TX
-                // by putting data in the IN endpoint.
+                // by putting data in the IN endpoint. While the bus is
+                // suspended, the message is queued until it resumes.
                 self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
                 Ok(())
             } else if self.boot_period.get() {
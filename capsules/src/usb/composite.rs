@@ -0,0 +1,830 @@
+//! Composite USB devices: several USB classes on one controller
+//!
+//! The USB class capsules (`cdc`, `ctap`, `msc`, ...) each expect to own a
+//! `hil::usb::UsbController`. This module lets several of them share one
+//! controller, e.g. to run a CDC console, a CTAP authenticator and a mass
+//! storage drive at once.
+//!
+//! Every class is given a `UsbFunction`, a virtual USB controller, instead of
+//! the real controller. The `CompositeDevice` is the client of the real
+//! controller and:
+//!
+//! - builds the configuration descriptor of the device when it is enabled, by
+//!   requesting the configuration descriptor of every function, renumbering
+//!   its interfaces and endpoints, and grouping the interfaces of functions
+//!   with more than one interface under an Interface Association Descriptor,
+//! - answers the standard device requests (device, configuration and string
+//!   descriptors, addressing) itself,
+//! - forwards requests for an interface or an endpoint to the function that
+//!   owns it, translating the interface or endpoint number,
+//! - forwards the events of each endpoint to the function that owns it.
+//!
+//! The interfaces of a function are numbered after those of the functions
+//! added before it. The endpoints of a function are shifted by the endpoint
+//! offset given to its `UsbFunction`, so that e.g. endpoint 1 of a function
+//! with offset 3 is endpoint 4 of the device. The offsets must be chosen so
+//! that no two functions use the same endpoint. A function that sets up or
+//! describes an endpoint already used by another function, or one past the
+//! last endpoint, is left out of the device, and so is a function whose
+//! descriptors do not fit the configuration descriptor. `enable()` then
+//! returns an error, and the other functions work as usual.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let composite = static_init!(
+//!     capsules::usb::composite::CompositeDevice<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::composite::CompositeDevice::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x503a,
+//!         STRINGS,
+//!     )
+//! );
+//! nrf52840::usbd::USBD.set_client(composite);
+//!
+//! // CDC-ACM uses endpoints 2 to 4
+//! let cdc_function = static_init!(
+//!     capsules::usb::composite::UsbFunction<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::composite::UsbFunction::new(composite, 0)
+//! );
+//! composite.add_function(cdc_function).unwrap();
+//! // Mass storage endpoints 1 and 2 become endpoints 5 and 6
+//! let msc_function = static_init!(
+//!     capsules::usb::composite::UsbFunction<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::composite::UsbFunction::new(composite, 4)
+//! );
+//! composite.add_function(msc_function).unwrap();
+//!
+//! // Create the classes with `cdc_function` and `msc_function` as their
+//! // controllers, then enable and attach the composite device instead of
+//! // the classes.
+//! composite.enable().expect("USB functions use the same endpoints");
+//! composite.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+use core::ptr;
+
+use super::descriptors;
+use super::descriptors::ConfigurationDescriptor;
+use super::descriptors::Descriptor;
+use super::descriptors::DescriptorType;
+use super::descriptors::InterfaceAssociationDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::{OptionalCell, VolatileCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
+
+/// Size of the buffer holding the configuration descriptor of the device.
+pub const CONFIGURATION_BUFLEN: usize = 256;
+
+/// Endpoint addresses only have 4 bits.
+const N_ENDPOINTS: usize = 16;
+
+/// Size of a configuration descriptor, without its related descriptors.
+const CONFIGURATION_LEN: usize = 9;
+/// Size of an Interface Association Descriptor.
+const IAD_LEN: usize = 8;
+/// Subtypes of the CDC functional descriptors that refer to interfaces.
+const CDC_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_UNION: u8 = 0x06;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Who handles the current control transfer.
+#[derive(Copy, Clone, PartialEq)]
+enum CtrlState {
+    /// The standard requests handler of the device.
+    Device,
+    /// We are sending `configuration[start..end]`.
+    Configuration(usize, usize),
+    /// The function that owns the interface or endpoint the request is for.
+    Function,
+}
+
+/// A function of a composite device, given to a USB class in place of the
+/// USB controller.
+pub struct UsbFunction<'a, U: hil::usb::UsbController<'a>> {
+    device: &'a CompositeDevice<'a, U>,
+    client: OptionalCell<&'a dyn hil::usb::Client<'a>>,
+    /// The control endpoint buffer of the class. Requests are copied in and
+    /// out of it.
+    ctrl_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    /// Endpoint `n` of the function is endpoint `n + endpoint_offset` of the
+    /// device.
+    endpoint_offset: usize,
+    /// Bitmasks of the IN and OUT endpoints the function enabled, by
+    /// function endpoint number.
+    in_endpoints: Cell<u16>,
+    out_endpoints: Cell<u16>,
+    /// Bitmasks of the IN and OUT endpoints of the device the function set
+    /// up or described.
+    in_claimed: Cell<u16>,
+    out_claimed: Cell<u16>,
+    /// Why the function was left out of the device, if it was.
+    rejected: OptionalCell<ErrorCode>,
+    first_interface: Cell<u8>,
+    num_interfaces: Cell<u8>,
+    next: ListLink<'a, UsbFunction<'a, U>>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a, U> {
+    pub fn new(device: &'a CompositeDevice<'a, U>, endpoint_offset: usize) -> Self {
+        UsbFunction {
+            device,
+            client: OptionalCell::empty(),
+            ctrl_buffer: OptionalCell::empty(),
+            endpoint_offset,
+            in_endpoints: Cell::new(0),
+            out_endpoints: Cell::new(0),
+            in_claimed: Cell::new(0),
+            out_claimed: Cell::new(0),
+            rejected: OptionalCell::empty(),
+            first_interface: Cell::new(0),
+            num_interfaces: Cell::new(0),
+            next: ListLink::empty(),
+        }
+    }
+
+    fn owns_interface(&self, interface: u8) -> bool {
+        interface >= self.first_interface.get()
+            && interface - self.first_interface.get() < self.num_interfaces.get()
+    }
+
+    /// Translates a device endpoint to an endpoint of this function, if the
+    /// function enabled it in the given direction.
+    fn local_endpoint(&self, endpoint: usize, transfer_in: bool) -> Option<usize> {
+        if self.rejected.is_some() {
+            return None;
+        }
+        let mask = if transfer_in {
+            self.in_endpoints.get()
+        } else {
+            self.out_endpoints.get()
+        };
+        endpoint
+            .checked_sub(self.endpoint_offset)
+            .filter(|&local| local > 0 && local < N_ENDPOINTS && mask & (1 << local) != 0)
+    }
+
+    /// Whether the function set up or described a device endpoint.
+    fn claimed(&self, endpoint: usize, transfer_in: bool) -> bool {
+        let claimed = if transfer_in {
+            self.in_claimed.get()
+        } else {
+            self.out_claimed.get()
+        };
+        claimed & 1 << endpoint != 0
+    }
+
+    /// Records that the function uses a device endpoint. Rejects the
+    /// function and returns `false` if the endpoint does not exist or
+    /// another function uses it.
+    fn claim(&self, endpoint: usize, transfer_in: bool) -> bool {
+        if endpoint == 0
+            || endpoint >= N_ENDPOINTS
+            || self
+                .device
+                .functions
+                .iter()
+                .any(|f| !ptr::eq(f, self) && f.claimed(endpoint, transfer_in))
+        {
+            self.rejected.set(ErrorCode::INVAL);
+            return false;
+        }
+        let claimed = if transfer_in {
+            &self.in_claimed
+        } else {
+            &self.out_claimed
+        };
+        claimed.set(claimed.get() | 1 << endpoint);
+        true
+    }
+
+    /// Claims an endpoint of the function in the given directions, and
+    /// returns whether the class may use it.
+    fn enable_endpoint(&self, endpoint: usize, transfer_in: bool, transfer_out: bool) -> bool {
+        let device_endpoint = endpoint + self.endpoint_offset;
+        if endpoint == 0
+            || (transfer_in && !self.claim(device_endpoint, true))
+            || (transfer_out && !self.claim(device_endpoint, false))
+        {
+            self.rejected.set(ErrorCode::INVAL);
+            return false;
+        }
+        if transfer_in {
+            self.in_endpoints
+                .set(self.in_endpoints.get() | 1 << endpoint);
+        }
+        if transfer_out {
+            self.out_endpoints
+                .set(self.out_endpoints.get() | 1 << endpoint);
+        }
+        true
+    }
+
+    /// Copies `len` bytes between the control buffers of the device and of
+    /// this function.
+    fn copy_ctrl(&self, len: usize, to_function: bool) {
+        self.ctrl_buffer.map(|buf| {
+            let device_buf = &self.device.client_ctrl.ctrl_buffer.buf;
+            for i in 0..min(len, min(buf.len(), device_buf.len())) {
+                if to_function {
+                    buf[i].set(device_buf[i].get());
+                } else {
+                    device_buf[i].set(buf[i].get());
+                }
+            }
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> ListNode<'a, UsbFunction<'a, U>> for UsbFunction<'a, U> {
+    fn next(&'a self) -> &'a ListLink<'a, UsbFunction<'a, U>> {
+        &self.next
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::UsbController<'a> for UsbFunction<'a, U> {
+    fn set_client(&self, client: &'a dyn hil::usb::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn endpoint_set_ctrl_buffer(&self, buf: &'a [VolatileCell<u8>]) {
+        self.ctrl_buffer.set(buf);
+    }
+
+    fn endpoint_set_in_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        if self.claim(endpoint + self.endpoint_offset, true) {
+            self.device
+                .controller()
+                .endpoint_set_in_buffer(endpoint + self.endpoint_offset, buf);
+        }
+    }
+
+    fn endpoint_set_out_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        if self.claim(endpoint + self.endpoint_offset, false) {
+            self.device
+                .controller()
+                .endpoint_set_out_buffer(endpoint + self.endpoint_offset, buf);
+        }
+    }
+
+    // The composite device enables, attaches and addresses the controller.
+
+    fn enable_as_device(&self, _speed: hil::usb::DeviceSpeed) {}
+
+    fn attach(&self) {}
+
+    fn detach(&self) {}
+
+    fn set_address(&self, _addr: u16) {}
+
+    fn enable_address(&self) {}
+
+    fn endpoint_in_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        if let TransferType::Control = transfer_type {
+            return;
+        }
+        if self.enable_endpoint(endpoint, true, false) {
+            self.device
+                .controller()
+                .endpoint_in_enable(transfer_type, endpoint + self.endpoint_offset);
+        }
+    }
+
+    fn endpoint_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        if let TransferType::Control = transfer_type {
+            return;
+        }
+        if self.enable_endpoint(endpoint, false, true) {
+            self.device
+                .controller()
+                .endpoint_out_enable(transfer_type, endpoint + self.endpoint_offset);
+        }
+    }
+
+    fn endpoint_in_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        if let TransferType::Control = transfer_type {
+            return;
+        }
+        if self.enable_endpoint(endpoint, true, true) {
+            self.device
+                .controller()
+                .endpoint_in_out_enable(transfer_type, endpoint + self.endpoint_offset);
+        }
+    }
+
+    fn endpoint_resume_in(&self, endpoint: usize) {
+        self.device
+            .controller()
+            .endpoint_resume_in(endpoint + self.endpoint_offset);
+    }
+
+    fn endpoint_resume_out(&self, endpoint: usize) {
+        self.device
+            .controller()
+            .endpoint_resume_out(endpoint + self.endpoint_offset);
+    }
+
+    fn remote_wakeup(&self) -> Result<(), ErrorCode> {
+        self.device.client_ctrl.remote_wakeup()
+    }
+}
+
+/// A USB device made of several functions.
+pub struct CompositeDevice<'a, U: hil::usb::UsbController<'a>> {
+    /// Handles the standard device requests.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+    functions: List<'a, UsbFunction<'a, U>>,
+    ctrl_state: Cell<CtrlState>,
+    /// The function handling the current control transfer.
+    ctrl_function: OptionalCell<&'a UsbFunction<'a, U>>,
+    /// The configuration descriptor of the device, followed by the
+    /// descriptors of all functions.
+    configuration: [Cell<u8>; CONFIGURATION_BUFLEN],
+    configuration_len: Cell<usize>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CompositeDevice<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        // The configuration descriptor is built when the device is enabled.
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0xef,   // Miscellaneous
+                    subclass: 0x2, // Common Class
+                    protocol: 0x1, // Interface Association Descriptor
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                ConfigurationDescriptor::default(),
+                &mut [],
+                &[],
+                None, // No HID descriptor
+                None, // No CDC descriptor array
+            );
+
+        const ZERO: Cell<u8> = Cell::new(0);
+        CompositeDevice {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            functions: List::new(),
+            ctrl_state: Cell::new(CtrlState::Device),
+            ctrl_function: OptionalCell::empty(),
+            configuration: [ZERO; CONFIGURATION_BUFLEN],
+            configuration_len: Cell::new(0),
+        }
+    }
+
+    /// Adds a function to the device. Functions must be added before the
+    /// device is enabled.
+    ///
+    /// Returns `ALREADY` if the function was already added, and `INVAL` if
+    /// its endpoint offset leaves it no endpoint.
+    pub fn add_function(&self, function: &'a UsbFunction<'a, U>) -> Result<(), ErrorCode> {
+        if self.functions.iter().any(|f| ptr::eq(f, function)) {
+            return Err(ErrorCode::ALREADY);
+        }
+        if function.endpoint_offset + 1 >= N_ENDPOINTS {
+            return Err(ErrorCode::INVAL);
+        }
+        self.functions.push_tail(function);
+        Ok(())
+    }
+
+    /// Enables the classes of the functions and builds the configuration
+    /// descriptor of the device. This is what `hil::usb::Client::enable()`
+    /// does, but it also reports functions left out of the device: `INVAL`
+    /// if a function uses an endpoint of another function or past the last
+    /// endpoint, `SIZE` if its descriptors do not fit the configuration
+    /// descriptor.
+    pub fn enable(&'a self) -> Result<(), ErrorCode> {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Let the classes set up their endpoints, then collect their
+        // descriptors.
+        for function in self.functions.iter() {
+            function.client.map(|client| client.enable());
+        }
+        self.build_configuration();
+
+        match self.functions.iter().find_map(|f| f.rejected.extract()) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    /// Builds the configuration descriptor from the descriptors of all
+    /// functions that were not rejected.
+    fn build_configuration(&self) {
+        let mut len = CONFIGURATION_LEN;
+        let mut num_interfaces = 0;
+        for function in self.functions.iter() {
+            function.first_interface.set(num_interfaces);
+            if function.rejected.is_none() {
+                len = self.append_function(function, len);
+            }
+            num_interfaces += function.num_interfaces.get();
+        }
+        ConfigurationDescriptor {
+            num_interfaces,
+            related_descriptor_length: len - CONFIGURATION_LEN,
+            ..ConfigurationDescriptor::default()
+        }
+        .write_to(&self.configuration);
+        self.configuration_len.set(len);
+    }
+
+    /// Appends the descriptors of `function` at `start` and returns the end
+    /// of the configuration descriptor. If the function is rejected, nothing
+    /// is appended.
+    fn append_function(&self, function: &'a UsbFunction<'a, U>, start: usize) -> usize {
+        // Leave room for an Interface Association Descriptor.
+        let end = match self.read_function_descriptors(function, start + IAD_LEN) {
+            Some(len) => start + IAD_LEN + len,
+            None => {
+                function.rejected.set(ErrorCode::SIZE);
+                return start;
+            }
+        };
+        let descriptors = &self.configuration[start + IAD_LEN..end];
+
+        // Renumber the interfaces and endpoints.
+        let first = function.first_interface.get();
+        let mut num_interfaces = 0;
+        let mut class = (0, 0, 0);
+        let mut i = 0;
+        while i + 2 <= descriptors.len() {
+            let d = &descriptors[i..min(i + descriptors[i].get() as usize, descriptors.len())];
+            if d.len() < 2 {
+                break;
+            }
+            match d[1].get() {
+                t if t == DescriptorType::Interface as u8 && d.len() >= 9 => {
+                    if d[3].get() == 0 {
+                        // Not an alternate setting
+                        if num_interfaces == 0 {
+                            class = (d[5].get(), d[6].get(), d[7].get());
+                        }
+                        num_interfaces += 1;
+                    }
+                    d[2].set(d[2].get() + first);
+                }
+                t if t == DescriptorType::Endpoint as u8 && d.len() >= 3 => {
+                    let address = d[2].get();
+                    let endpoint = (address & 0x0f) as usize + function.endpoint_offset;
+                    if !function.claim(endpoint, address & 0x80 != 0) {
+                        return start;
+                    }
+                    d[2].set((address & 0x80) | endpoint as u8);
+                }
+                t if t == DescriptorType::CdcInterface as u8 && d.len() >= 4 => match d[2].get() {
+                    CDC_CALL_MANAGEMENT if d.len() >= 5 => d[4].set(d[4].get() + first),
+                    CDC_UNION => d[3..].iter().for_each(|b| b.set(b.get() + first)),
+                    _ => {}
+                },
+                _ => {}
+            }
+            i += d.len();
+        }
+        function.num_interfaces.set(num_interfaces);
+
+        if num_interfaces > 1 {
+            InterfaceAssociationDescriptor {
+                first_interface: first,
+                interface_count: num_interfaces,
+                function_class: class.0,
+                function_subclass: class.1,
+                function_protocol: class.2,
+                string_index: 0,
+            }
+            .write_to(&self.configuration[start..]);
+            end
+        } else {
+            // No association needed, move the descriptors back.
+            for i in start..end - IAD_LEN {
+                self.configuration[i].set(self.configuration[i + IAD_LEN].get());
+            }
+            end - IAD_LEN
+        }
+    }
+
+    /// Asks the class of `function` for its configuration descriptor, the
+    /// way a host would, and copies the descriptors that follow the
+    /// configuration descriptor to `configuration[start..]`. Returns their
+    /// length, or `None` if they do not fit.
+    fn read_function_descriptors(
+        &self,
+        function: &'a UsbFunction<'a, U>,
+        start: usize,
+    ) -> Option<usize> {
+        let (client, ctrl_buffer) =
+            match (function.client.extract(), function.ctrl_buffer.extract()) {
+                (Some(client), Some(ctrl_buffer)) if ctrl_buffer.len() >= 8 => {
+                    (client, ctrl_buffer)
+                }
+                _ => return Some(0),
+            };
+
+        // GET_DESCRIPTOR(Configuration 0), for up to 0xffff bytes
+        let setup = [
+            0x80,
+            0x06,
+            0x00,
+            DescriptorType::Configuration as u8,
+            0,
+            0,
+            0xff,
+            0xff,
+        ];
+        for (b, s) in ctrl_buffer.iter().zip(setup.iter()) {
+            b.set(*s);
+        }
+        let mut offset = 0;
+        let mut len = start;
+        let mut fits = true;
+        if let hil::usb::CtrlSetupResult::Ok = client.ctrl_setup(0) {
+            while let hil::usb::CtrlInResult::Packet(n, complete) = client.ctrl_in(0) {
+                for b in ctrl_buffer.iter().take(n) {
+                    // Skip the configuration descriptor of the function
+                    if offset >= CONFIGURATION_LEN {
+                        if len >= CONFIGURATION_BUFLEN {
+                            fits = false;
+                            break;
+                        }
+                        self.configuration[len].set(b.get());
+                        len += 1;
+                    }
+                    offset += 1;
+                }
+                if complete || n == 0 || !fits {
+                    break;
+                }
+            }
+        }
+        client.ctrl_status_complete(0);
+        if fits {
+            Some(len - start)
+        } else {
+            None
+        }
+    }
+
+    /// Finds the function a request for an interface or endpoint is for,
+    /// and translates the index of the request for that function.
+    fn route_request(&self, setup_data: &SetupData) -> Option<(&'a UsbFunction<'a, U>, u16)> {
+        match setup_data.request_type.recipient() {
+            Recipient::Interface => {
+                let interface = (setup_data.index & 0xff) as u8;
+                self.functions
+                    .iter()
+                    .find(|f| f.owns_interface(interface))
+                    .map(|f| {
+                        let local = interface - f.first_interface.get();
+                        (f, (setup_data.index & 0xff00) | local as u16)
+                    })
+            }
+            Recipient::Endpoint => {
+                let endpoint = (setup_data.index & 0x0f) as usize;
+                let transfer_in = setup_data.index & 0x80 != 0;
+                self.functions.iter().find_map(|f| {
+                    f.local_endpoint(endpoint, transfer_in)
+                        .map(|local| (f, (setup_data.index & !0x0f) | local as u16))
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CompositeDevice<'a, U> {
+    fn enable(&'a self) {
+        // Functions left out of the device are reported by the inherent
+        // `enable()` only.
+        let _ = CompositeDevice::enable(self);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.client_ctrl.bus_reset();
+        for function in self.functions.iter() {
+            function.client.map(|client| client.bus_reset());
+        }
+    }
+
+    fn suspend(&'a self) {
+        for function in self.functions.iter() {
+            function.client.map(|client| client.suspend());
+        }
+    }
+
+    fn resume(&'a self) {
+        for function in self.functions.iter() {
+            function.client.map(|client| client.resume());
+        }
+    }
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        self.ctrl_function.clear();
+        self.ctrl_state.set(CtrlState::Device);
+        let setup_data = match SetupData::get(&self.client_ctrl.ctrl_buffer.buf) {
+            Some(setup_data) => setup_data,
+            None => return self.client_ctrl.ctrl_setup(endpoint),
+        };
+
+        if let Some((function, index)) = self.route_request(&setup_data) {
+            return function
+                .client
+                .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |client| {
+                    // Hand the request to the function with its own index
+                    function.copy_ctrl(8, true);
+                    function.ctrl_buffer.map(|buf| {
+                        buf[4].set(index as u8);
+                        buf[5].set((index >> 8) as u8);
+                    });
+                    self.ctrl_function.set(function);
+                    self.ctrl_state.set(CtrlState::Function);
+                    client.ctrl_setup(0)
+                });
+        }
+
+        match (
+            setup_data.get_standard_request(),
+            setup_data.request_type.recipient(),
+        ) {
+            (
+                Some(StandardRequest::GetDescriptor {
+                    descriptor_type: DescriptorType::Configuration,
+                    descriptor_index: 0,
+                    requested_length,
+                    ..
+                }),
+                Recipient::Device,
+            ) => {
+                let end = min(self.configuration_len.get(), requested_length as usize);
+                self.ctrl_state.set(CtrlState::Configuration(0, end));
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.get() {
+            CtrlState::Device => self.client_ctrl.ctrl_in(endpoint),
+            CtrlState::Configuration(start, end) => {
+                let buf = &self.client_ctrl.ctrl_buffer.buf;
+                let packet_bytes = min(buf.len(), end - start);
+                for i in 0..packet_bytes {
+                    buf[i].set(self.configuration[start + i].get());
+                }
+                let start = start + packet_bytes;
+                self.ctrl_state.set(CtrlState::Configuration(start, end));
+                hil::usb::CtrlInResult::Packet(packet_bytes, start == end)
+            }
+            CtrlState::Function => {
+                self.ctrl_function
+                    .map_or(hil::usb::CtrlInResult::Error, |function| {
+                        match function
+                            .client
+                            .map_or(hil::usb::CtrlInResult::Error, |client| client.ctrl_in(0))
+                        {
+                            hil::usb::CtrlInResult::Packet(n, complete) => {
+                                function.copy_ctrl(n, false);
+                                hil::usb::CtrlInResult::Packet(n, complete)
+                            }
+                            result => result,
+                        }
+                    })
+            }
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.ctrl_state.get() {
+            CtrlState::Function => {
+                self.ctrl_function
+                    .map_or(hil::usb::CtrlOutResult::Halted, |function| {
+                        function.copy_ctrl(packet_bytes as usize, true);
+                        function
+                            .client
+                            .map_or(hil::usb::CtrlOutResult::Halted, |client| {
+                                client.ctrl_out(0, packet_bytes)
+                            })
+                    })
+            }
+            _ => self.client_ctrl.ctrl_out(endpoint, packet_bytes),
+        }
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        match self.ctrl_state.get() {
+            CtrlState::Function => {
+                self.ctrl_function.map(|function| {
+                    function.client.map(|client| client.ctrl_status(0));
+                });
+            }
+            _ => self.client_ctrl.ctrl_status(endpoint),
+        }
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        match self.ctrl_state.get() {
+            CtrlState::Function => {
+                self.ctrl_function.map(|function| {
+                    function.client.map(|client| client.ctrl_status_complete(0));
+                });
+            }
+            _ => self.client_ctrl.ctrl_status_complete(endpoint),
+        }
+        self.ctrl_function.clear();
+        self.ctrl_state.set(CtrlState::Device);
+    }
+
+    /// Forward a Bulk/Interrupt IN transaction to the function that owns the
+    /// endpoint.
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        self.functions
+            .iter()
+            .find_map(|f| f.local_endpoint(endpoint, true).map(|local| (f, local)))
+            .and_then(|(f, local)| {
+                f.client
+                    .map(|client| client.packet_in(transfer_type, local))
+            })
+            .unwrap_or(hil::usb::InResult::Error)
+    }
+
+    /// Forward a Bulk/Interrupt OUT transaction to the function that owns the
+    /// endpoint.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        self.functions
+            .iter()
+            .find_map(|f| f.local_endpoint(endpoint, false).map(|local| (f, local)))
+            .and_then(|(f, local)| {
+                f.client
+                    .map(|client| client.packet_out(transfer_type, local, packet_bytes))
+            })
+            .unwrap_or(hil::usb::OutResult::Error)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.functions
+            .iter()
+            .find_map(|f| f.local_endpoint(endpoint, true).map(|local| (f, local)))
+            .map(|(f, local)| {
+                f.client.map(|client| client.packet_transmitted(local));
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,187 @@
+//! Tests of the configuration descriptor of a CDC-ACM and mass storage
+//! composite device, read the way a host reads it.
+
+extern crate std;
+
+use std::vec::Vec;
+
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::usb::UsbController;
+use kernel::ErrorCode;
+
+use super::{CompositeDevice, UsbFunction};
+use crate::emulated_alarm::EmulatedAlarm;
+use crate::emulated_usb::EmulatedUsb;
+use crate::test_support::{buffer, deferred_caller, leak};
+use crate::usb::cdc::CdcAcm;
+use crate::usb::msc::MassStorage;
+
+const STRINGS: &[&str; 3] = &["Tock", "Composite", "0"];
+
+/// GET_DESCRIPTOR(Configuration 0) for up to 255 bytes
+const GET_CONFIGURATION: [u8; 8] = [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00];
+
+const INTERFACE: u8 = 0x04;
+const ENDPOINT: u8 = 0x05;
+const IAD: u8 = 0x0b;
+const CDC_INTERFACE: u8 = 0x24;
+
+/// The mass storage class only needs storage for SCSI commands.
+struct NoStorage;
+
+impl NonvolatileStorage<'static> for NoStorage {
+    fn set_client(&self, _client: &'static dyn NonvolatileStorageClient<'static>) {}
+
+    fn read(
+        &self,
+        _buffer: &'static mut [u8],
+        _address: usize,
+        _length: usize,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn write(
+        &self,
+        _buffer: &'static mut [u8],
+        _address: usize,
+        _length: usize,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+}
+
+type Device = CompositeDevice<'static, EmulatedUsb<'static>>;
+type Function = UsbFunction<'static, EmulatedUsb<'static>>;
+
+/// A composite device with CDC-ACM at endpoint offset 0 and mass storage at
+/// `msc_offset`.
+fn cdc_msc(msc_offset: usize) -> (&'static EmulatedUsb<'static>, &'static Device) {
+    let usb = leak(EmulatedUsb::new());
+    let device: &'static Device = leak(CompositeDevice::new(usb, 64, 0x6667, 0xabcd, STRINGS));
+    usb.set_client(device);
+
+    let cdc_function: &'static Function = leak(UsbFunction::new(device, 0));
+    device.add_function(cdc_function).unwrap();
+    let alarm = leak(EmulatedAlarm::new());
+    let cdc = leak(CdcAcm::new(
+        cdc_function,
+        64,
+        0x6667,
+        0xabcd,
+        STRINGS,
+        alarm,
+        deferred_caller(),
+        None,
+    ));
+    cdc_function.set_client(cdc);
+
+    let msc_function: &'static Function = leak(UsbFunction::new(device, msc_offset));
+    device.add_function(msc_function).unwrap();
+    let msc = leak(MassStorage::new(
+        msc_function,
+        leak(NoStorage),
+        buffer(512),
+        16,
+        false,
+        0x6667,
+        0xabcd,
+        STRINGS,
+    ));
+    msc_function.set_client(msc);
+
+    (usb, device)
+}
+
+/// Splits a configuration descriptor into its descriptors.
+fn descriptors(configuration: &[u8]) -> Vec<&[u8]> {
+    let mut descriptors = Vec::new();
+    let mut rest = configuration;
+    while !rest.is_empty() {
+        let (d, tail) = rest.split_at(rest[0] as usize);
+        descriptors.push(d);
+        rest = tail;
+    }
+    descriptors
+}
+
+fn of_type<'d>(descriptors: &[&'d [u8]], descriptor_type: u8) -> Vec<&'d [u8]> {
+    descriptors
+        .iter()
+        .filter(|d| d[1] == descriptor_type)
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn cdc_and_msc_are_renumbered() {
+    let (usb, device) = cdc_msc(4);
+    assert_eq!(device.enable(), Ok(()));
+
+    let configuration = usb.control_in(GET_CONFIGURATION).unwrap();
+    assert_eq!(
+        u16::from_le_bytes([configuration[2], configuration[3]]) as usize,
+        configuration.len()
+    );
+    // Three interfaces
+    assert_eq!(configuration[4], 3);
+
+    let descriptors = descriptors(&configuration);
+    // Only CDC-ACM has several interfaces, associated as a CDC function
+    assert_eq!(
+        of_type(&descriptors, IAD),
+        [&[0x08, IAD, 0, 2, 0x02, 0x02, 0x01, 0][..]]
+    );
+    let interfaces: Vec<(u8, u8)> = of_type(&descriptors, INTERFACE)
+        .iter()
+        .map(|d| (d[2], d[5]))
+        .collect();
+    assert_eq!(interfaces, [(0, 0x02), (1, 0x0a), (2, 0x08)]);
+    let endpoints: Vec<u8> = of_type(&descriptors, ENDPOINT)
+        .iter()
+        .map(|d| d[2])
+        .collect();
+    assert_eq!(endpoints, [0x84, 0x82, 0x03, 0x85, 0x06]);
+    // The CDC Union and Call Management descriptors refer to the CDC
+    // interfaces
+    for d in of_type(&descriptors, CDC_INTERFACE) {
+        match d[2] {
+            0x01 => assert_eq!(d[4], 1),
+            0x06 => assert_eq!(&d[3..], [0, 1]),
+            _ => {}
+        }
+    }
+
+    // OUT endpoint 0 is the control endpoint
+    assert_eq!(usb.in_endpoints.get(), 1 << 2 | 1 << 5);
+    assert_eq!(usb.out_endpoints.get(), 1 << 0 | 1 << 3 | 1 << 6);
+}
+
+#[test]
+fn overlapping_endpoints_leave_function_out() {
+    // Mass storage endpoints 1 and 2 would be CDC-ACM endpoints 2 and 3
+    let (usb, device) = cdc_msc(1);
+    assert_eq!(device.enable(), Err(ErrorCode::INVAL));
+
+    let configuration = usb.control_in(GET_CONFIGURATION).unwrap();
+    assert_eq!(configuration[4], 2);
+    let descriptors = descriptors(&configuration);
+    let endpoints: Vec<u8> = of_type(&descriptors, ENDPOINT)
+        .iter()
+        .map(|d| d[2])
+        .collect();
+    assert_eq!(endpoints, [0x84, 0x82, 0x03]);
+    assert_eq!(usb.in_endpoints.get(), 1 << 2);
+    assert_eq!(usb.out_endpoints.get(), 1 << 0 | 1 << 3);
+}
+
+#[test]
+fn add_function_checks_function() {
+    let usb = leak(EmulatedUsb::new());
+    let device: &'static Device = leak(CompositeDevice::new(usb, 64, 0x6667, 0xabcd, STRINGS));
+    let function: &'static Function = leak(UsbFunction::new(device, 0));
+    assert_eq!(device.add_function(function), Ok(()));
+    assert_eq!(device.add_function(function), Err(ErrorCode::ALREADY));
+    let function: &'static Function = leak(UsbFunction::new(device, 15));
+    assert_eq!(device.add_function(function), Err(ErrorCode::INVAL));
+}
@@ -99,6 +99,9 @@ pub struct CtapHid<'a, U: 'a> {
     recv_offset: Cell<usize>,
 
     saved_endpoint: OptionalCell<usize>,
+    /// Whether we delayed the OUT endpoint, which must then be resumed to
+    /// receive the next packet.
+    out_delayed: Cell<bool>,
 }
 
 impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
@@ -175,6 +178,7 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
             recv_len: Cell::new(0),
             recv_offset: Cell::new(0),
             saved_endpoint: OptionalCell::empty(),
+            out_delayed: Cell::new(false),
         }
     }
 
@@ -231,7 +235,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for
                 // Reset the offset
                 self.recv_offset.set(0);
             }
-        } else {
+        } else if self.out_delayed.take() {
             // If we have nothing to process, accept more data
             self.controller().endpoint_resume_out(ENDPOINT_NUM);
         }
@@ -266,7 +270,24 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
         self.client_ctrl.attach();
     }
 
-    fn bus_reset(&'a self) {}
+    fn bus_reset(&'a self) {
+        self.client_ctrl.bus_reset();
+        // The controller forgets about delayed packets
+        self.out_delayed.set(false);
+        self.saved_endpoint.clear();
+    }
+
+    fn suspend(&'a self) {
+        // The controller holds back transfers until the bus resumes, and we
+        // keep our send and receive buffers until then.
+    }
+
+    fn resume(&'a self) {
+        // Restart a send that the host did not pick up before suspending.
+        if self.send_buffer.is_some() {
+            self.controller().endpoint_resume_in(ENDPOINT_NUM);
+        }
+    }
 
     /// Handle a Control Setup transaction.
     fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
@@ -375,12 +396,14 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
                                 self.recv_offset.set(0);
                                 // Delay the next packet until we have finished
                                 // processing this packet
+                                self.out_delayed.set(true);
                                 hil::usb::OutResult::Delay
                             } else {
                                 // We can't receive data. Record that we have data to send later
                                 // and apply back pressure to USB
                                 self.saved_endpoint.set(endpoint);
                                 self.recv_buffer.replace(buf);
+                                self.out_delayed.set(true);
                                 hil::usb::OutResult::Delay
                             }
                         } else {
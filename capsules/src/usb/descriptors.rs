@@ -156,6 +156,7 @@ pub enum DescriptorType {
     DeviceQualifier,
     OtherSpeedConfiguration,
     InterfacePower,
+    InterfaceAssociation = 0x0b,
     HID = 0x21,
     Report = 0x22,
     CdcInterface = 0x24,
@@ -171,6 +172,7 @@ fn get_descriptor_type(byte: u8) -> Option<DescriptorType> {
         6 => Some(DescriptorType::DeviceQualifier),
         7 => Some(DescriptorType::OtherSpeedConfiguration),
         8 => Some(DescriptorType::InterfacePower),
+        0x0b => Some(DescriptorType::InterfaceAssociation),
         0x21 => Some(DescriptorType::HID),
         0x22 => Some(DescriptorType::Report),
         0x24 => Some(DescriptorType::CdcInterface),
@@ -406,6 +408,13 @@ impl DescriptorBuffer {
         }
         self.len
     }
+
+    /// Appends a descriptor after the last interface and its endpoints, and
+    /// updates the total length of the configuration.
+    pub fn append(&mut self, descriptor: &dyn Descriptor) {
+        self.len += descriptor.write_to(&self.buf[self.len..]);
+        put_u16(&self.buf[2..4], self.len as u16);
+    }
 }
 
 /// Transform descriptor structs into descriptor buffers that can be
@@ -493,7 +502,11 @@ pub fn create_descriptor_buffers(
 
     // Configuration Descriptor. We assume there is only one configuration
     // descriptor, since this is very common for most USB devices.
-    configuration_descriptor.num_interfaces = interface_descriptor.len() as u8;
+    // Alternate settings of an interface do not count as interfaces.
+    configuration_descriptor.num_interfaces = interface_descriptor
+        .iter()
+        .filter(|d| d.alternate_setting == 0)
+        .count() as u8;
 
     // Calculate the length of all dependent descriptors.
     // TODO should we be erroring here if len > 128? Otherwise we'll probably
@@ -603,6 +616,14 @@ impl ConfigurationAttributes {
                 | if supports_remote_wakeup { 1 << 5 } else { 0 },
         )
     }
+
+    pub fn is_self_powered(&self) -> bool {
+        self.0 & (1 << 6) != 0
+    }
+
+    pub fn supports_remote_wakeup(&self) -> bool {
+        self.0 & (1 << 5) != 0
+    }
 }
 
 impl From<ConfigurationAttributes> for u8 {
@@ -611,6 +632,12 @@ impl From<ConfigurationAttributes> for u8 {
     }
 }
 
+impl From<u8> for ConfigurationAttributes {
+    fn from(attributes: u8) -> Self {
+        ConfigurationAttributes(attributes)
+    }
+}
+
 pub struct InterfaceDescriptor {
     pub interface_number: u8,
     pub alternate_setting: u8,
@@ -654,6 +681,35 @@ impl Descriptor for InterfaceDescriptor {
     }
 }
 
+/// Groups the interfaces of a function of a composite device, such as the
+/// two interfaces of a CDC-ACM serial port.
+pub struct InterfaceAssociationDescriptor {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_index: u8,
+}
+
+impl Descriptor for InterfaceAssociationDescriptor {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(8); // Size of descriptor
+        buf[1].set(DescriptorType::InterfaceAssociation as u8);
+        buf[2].set(self.first_interface);
+        buf[3].set(self.interface_count);
+        buf[4].set(self.function_class);
+        buf[5].set(self.function_subclass);
+        buf[6].set(self.function_protocol);
+        buf[7].set(self.string_index);
+        8
+    }
+}
+
 pub struct EndpointAddress(u8);
 
 impl EndpointAddress {
@@ -859,6 +915,39 @@ impl Descriptor for CdcInterfaceDescriptor {
     }
 }
 
+/// Describes the capabilities of a Device Firmware Upgrade interface.
+pub struct DfuFunctionalDescriptor {
+    pub will_detach: bool,
+    pub manifestation_tolerant: bool,
+    pub can_upload: bool,
+    pub can_download: bool,
+    /// Time in ms the device waits for a USB reset after a DFU_DETACH.
+    pub detach_timeout: u16,
+    /// Maximum number of bytes per DFU_DNLOAD or DFU_UPLOAD request.
+    pub transfer_size: u16,
+}
+
+impl Descriptor for DfuFunctionalDescriptor {
+    fn size(&self) -> usize {
+        9
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(9); // Size of descriptor
+        buf[1].set(0x21); // DFU functional, same number as HID
+        buf[2].set(
+            (self.will_detach as u8) << 3
+                | (self.manifestation_tolerant as u8) << 2
+                | (self.can_upload as u8) << 1
+                | self.can_download as u8,
+        );
+        put_u16(&buf[3..5], self.detach_timeout);
+        put_u16(&buf[5..7], self.transfer_size);
+        put_u16(&buf[7..9], 0x0110); // DFU 1.1
+        9
+    }
+}
+
 /// The data structure sent in a CDC-ACM Set Line Coding message.
 #[derive(Debug, Copy, Clone)]
 pub struct CdcAcmSetLineCodingData {
@@ -0,0 +1,924 @@
+//! Device Firmware Upgrade class for USB
+//!
+//! This capsule implements the DFU 1.1 protocol on the default control
+//! endpoint, so that a host can update the kernel or the apps of a board
+//! with e.g. `dfu-util`. Each region of storage that can be updated is
+//! presented as an alternate setting of the DFU interface:
+//!
+//! ```text
+//! dfu-util -l                             # List the regions
+//! dfu-util -a 1 -D apps.bin               # Download apps to region 1
+//! dfu-util -a 0 -U kernel.bin             # Upload region 0
+//! dfu-util -e                             # Detach
+//! ```
+//!
+//! Downloads are written through a `FlashRange`, in blocks of up to the size
+//! of the buffer passed to `new()`. The blocks of a download are written one
+//! after the other from the start of the region, and every erase unit is
+//! erased when the download reaches its start. Regions must therefore start
+//! at an erase unit, and every block but the last must be a multiple of the
+//! write size of the flash; the last one is padded with 0xFF. If the region
+//! holds apps, the download must be a list of apps in the Tock Binary Format:
+//! every TBF header is checked before it is written, and the download must end
+//! at the end of an app. After an app download, the header following the last
+//! app is cleared so that apps left over from a previous download are not
+//! loaded.
+//!
+//! Since the control endpoint cannot wait for the flash, the next block of
+//! the region is read ahead while the device is idle or uploading. A request
+//! that arrives while the flash is still busy with it fails. The flash hands
+//! the buffer back if it refuses an operation, so a failed operation only
+//! fails the request.
+//!
+//! A `DFU_DETACH` request calls the function passed to `new()`, which can
+//! e.g. reboot into a bootloader to update the kernel.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static STRINGS: &'static [&'static str] = &[
+//!     "XYZ Corp.",     // Manufacturer
+//!     "Data Logger",   // Product
+//!     "Serial No. 5",  // Serial number
+//!     "Kernel",        // Region 0
+//!     "Apps",          // Region 1
+//! ];
+//! static REGIONS: &'static [capsules::usb::dfu::DfuRegion] = &[
+//!     capsules::usb::dfu::DfuRegion { address: 0x00000, length: 0x40000, apps: false },
+//!     capsules::usb::dfu::DfuRegion { address: 0x40000, length: 0x40000, apps: true },
+//! ];
+//!
+//! let dfu = static_init!(
+//!     capsules::usb::dfu::Dfu<'static, nrf52840::usbd::Usbd<'static>, nrf52840::nvmc::Nvmc>,
+//!     capsules::usb::dfu::Dfu::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x521f,
+//!         STRINGS,
+//!         &nrf52840::nvmc::NVMC,
+//!         REGIONS,
+//!         &mut DFU_BUFFER,
+//!         Some(&reboot_into_bootloader),
+//!     )
+//! );
+//! nrf52840::nvmc::NVMC.set_range_client(dfu);
+//! nrf52840::usbd::USBD.set_client(dfu);
+//! dfu.enable();
+//! dfu.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::DfuFunctionalDescriptor;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::flash::{FlashRange, RangeClient};
+use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Maximum number of regions, i.e. alternate settings.
+pub const MAX_REGIONS: usize = 4;
+
+/// Time in ms the host should wait before asking again whether a block was
+/// written.
+const POLL_TIMEOUT_MS: u32 = 20;
+
+/// Time in ms we wait for a USB reset after a `DFU_DETACH`.
+const DETACH_TIMEOUT_MS: u16 = 1000;
+
+/// Size of the header that marks the end of the apps.
+const APPS_END_LEN: usize = 8;
+
+/// Class specific requests
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+/// Standard interface requests that select the region.
+const GET_INTERFACE: u8 = 10;
+const SET_INTERFACE: u8 = 11;
+
+/// A region of flash the host can download to and upload from.
+#[derive(Copy, Clone, Debug)]
+pub struct DfuRegion {
+    /// Start of the region, as a `FlashRange` address. It must be the start
+    /// of an erase unit.
+    pub address: usize,
+    pub length: usize,
+    /// The region holds apps, so downloads must be valid TBF images.
+    pub apps: bool,
+}
+
+/// States of the DFU protocol, with their numbers in the spec.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum DfuState {
+    DfuIdle = 2,
+    DownloadSync = 3,
+    DownloadBusy = 4,
+    DownloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    UploadIdle = 9,
+    DfuError = 10,
+}
+
+/// Status codes of the DFU protocol.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum DfuStatus {
+    Ok = 0x00,
+    /// The file fails a verification test.
+    ErrFile = 0x02,
+    /// The flash failed to write.
+    ErrWrite = 0x03,
+    /// The flash failed to erase.
+    ErrErase = 0x04,
+    /// The address is outside of the region.
+    ErrAddress = 0x08,
+    /// The download ended in the middle of an app.
+    ErrNotDone = 0x09,
+    ErrUnknown = 0x0e,
+    /// The request is not valid in the current state.
+    ErrStalledPkt = 0x0f,
+}
+
+/// States of the Control Endpoint related to DFU.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CtrlState {
+    Idle,
+    /// Receiving a block of `length` bytes.
+    Download {
+        received: usize,
+        length: usize,
+    },
+    /// Sending the first `length` bytes of the buffer. The host asked for
+    /// `requested` bytes.
+    Upload {
+        sent: usize,
+        length: usize,
+        requested: usize,
+    },
+    GetStatus,
+    GetState,
+    GetInterface,
+    Detach,
+}
+
+/// What the flash is doing.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Busy {
+    No,
+    /// Reading ahead the next block to upload.
+    Read,
+    /// Erasing the erase units under a block before writing it.
+    Erase,
+    /// Writing a block or the end of the apps.
+    Write,
+}
+
+/// Checks the TBF headers of an app download as it is received.
+///
+/// The checksum of a header is the XOR of its 32-bit words, except the
+/// checksum itself.
+#[derive(Copy, Clone, Debug, Default)]
+struct TbfCheck {
+    /// Offset of the next header in the download.
+    next_header: usize,
+    /// Size of the current header and app, once known.
+    header_size: usize,
+    total_size: usize,
+    word: u32,
+    checksum: u32,
+    expected: u32,
+}
+
+impl TbfCheck {
+    /// Checks `data`, downloaded at `offset`. Returns `false` if a header is
+    /// invalid.
+    fn check(&mut self, offset: usize, data: &[u8]) -> bool {
+        for (i, b) in data.iter().enumerate() {
+            let pos = match (offset + i).checked_sub(self.next_header) {
+                Some(pos) if pos < 4 || pos < self.header_size => pos,
+                _ => continue,
+            };
+            self.word |= (*b as u32) << (8 * (pos % 4));
+            if pos % 4 != 3 {
+                continue;
+            }
+            let word = self.word;
+            self.word = 0;
+            match pos / 4 {
+                0 => {
+                    // Version and header size
+                    self.header_size = (word >> 16) as usize;
+                    if word & 0xffff != 2 || self.header_size < 16 {
+                        return false;
+                    }
+                    self.checksum ^= word;
+                }
+                1 => {
+                    // Total size
+                    if (word as usize) < self.header_size {
+                        return false;
+                    }
+                    self.total_size = word as usize;
+                    self.checksum ^= word;
+                }
+                3 => self.expected = word,
+                _ => self.checksum ^= word,
+            }
+            if pos + 1 == self.header_size & !3 {
+                if self.checksum != self.expected {
+                    return false;
+                }
+                // The size comes from the host, so it must not wrap around
+                self.next_header = match self.next_header.checked_add(self.total_size) {
+                    Some(next_header) => next_header,
+                    None => return false,
+                };
+                self.header_size = 0;
+                self.checksum = 0;
+            }
+        }
+        true
+    }
+
+    /// Whether a download of `length` bytes ends at the end of an app.
+    fn complete(&self, length: usize) -> bool {
+        self.header_size == 0 && self.next_header == length
+    }
+}
+
+pub struct Dfu<'a, U: 'a, S: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    storage: &'a S,
+    regions: &'a [DfuRegion],
+    /// The selected region, i.e. alternate setting.
+    region: Cell<usize>,
+
+    state: Cell<DfuState>,
+    status: Cell<DfuStatus>,
+    ctrl_state: Cell<CtrlState>,
+    busy: Cell<Busy>,
+
+    /// Holds a block to write or a block read ahead.
+    buffer: TakeCell<'static, [u8]>,
+    /// The address and length of the block being erased and written.
+    writing: Cell<(usize, usize)>,
+    /// The address from which erase units under the block are erased.
+    erase_next: Cell<usize>,
+    /// Offset of the next block to download or upload in the region.
+    offset: Cell<usize>,
+    /// The region and offset of the block being read ahead.
+    read_ahead: Cell<(usize, usize)>,
+    /// The number of bytes read ahead for the next upload.
+    read_ahead_len: OptionalCell<usize>,
+    tbf: Cell<TbfCheck>,
+
+    /// Function called on a `DFU_DETACH` request.
+    detach_function: Option<&'a (dyn Fn() + 'a)>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: FlashRange<'static>> Dfu<'a, U, S> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str],
+        storage: &'a S,
+        regions: &'a [DfuRegion],
+        buffer: &'static mut [u8],
+        detach_function: Option<&'a (dyn Fn() + 'a)>,
+    ) -> Self {
+        if regions.is_empty() || regions.len() > MAX_REGIONS {
+            panic!("DFU needs 1 to {} regions", MAX_REGIONS);
+        }
+
+        let alternate_setting = |i: u8| InterfaceDescriptor {
+            interface_number: 0,
+            alternate_setting: i,
+            interface_class: 0xfe,    // Application specific
+            interface_subclass: 0x01, // Device Firmware Upgrade
+            interface_protocol: 0x02, // DFU mode
+            string_index: 4 + i,      // Name of the region
+            ..InterfaceDescriptor::default()
+        };
+        let interfaces: &mut [InterfaceDescriptor] = &mut [
+            alternate_setting(0),
+            alternate_setting(1),
+            alternate_setting(2),
+            alternate_setting(3),
+        ];
+        let endpoints: &[&[EndpointDescriptor]; MAX_REGIONS] = &[&[]; MAX_REGIONS];
+
+        let (device_descriptor_buffer, mut other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x00, // Class defined by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                &mut interfaces[..regions.len()],
+                &endpoints[..regions.len()],
+                None, // No HID descriptor
+                None, // No CDC descriptor array
+            );
+        other_descriptor_buffer.append(&DfuFunctionalDescriptor {
+            will_detach: detach_function.is_some(),
+            manifestation_tolerant: true,
+            can_upload: true,
+            can_download: true,
+            detach_timeout: DETACH_TIMEOUT_MS,
+            transfer_size: buffer.len() as u16,
+        });
+
+        Dfu {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            storage,
+            regions,
+            region: Cell::new(0),
+            state: Cell::new(DfuState::DfuIdle),
+            status: Cell::new(DfuStatus::Ok),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            busy: Cell::new(Busy::No),
+            buffer: TakeCell::new(buffer),
+            writing: Cell::new((0, 0)),
+            erase_next: Cell::new(0),
+            offset: Cell::new(0),
+            read_ahead: Cell::new((0, 0)),
+            read_ahead_len: OptionalCell::empty(),
+            tbf: Cell::new(TbfCheck::default()),
+            detach_function,
+        }
+    }
+
+    fn region(&self) -> DfuRegion {
+        self.regions[self.region.get()]
+    }
+
+    /// Whether a block or the end of the apps is being erased or written.
+    fn writing_busy(&self) -> bool {
+        match self.busy.get() {
+            Busy::Erase | Busy::Write => true,
+            Busy::No | Busy::Read => false,
+        }
+    }
+
+    /// Stalls the current request and reports `status`.
+    fn fail(&self, status: DfuStatus) -> hil::usb::CtrlSetupResult {
+        self.status.set(status);
+        self.state.set(DfuState::DfuError);
+        hil::usb::CtrlSetupResult::ErrGeneric
+    }
+
+    /// Goes back to the idle state and reads ahead the first block of the
+    /// region.
+    fn idle(&self) {
+        self.state.set(DfuState::DfuIdle);
+        self.offset.set(0);
+        self.read_next_block();
+    }
+
+    /// Reads ahead the block at `offset`, for the next upload.
+    fn read_next_block(&self) {
+        self.read_ahead_len.clear();
+        self.read_ahead.set((self.region.get(), self.offset.get()));
+        if self.busy.get() != Busy::No {
+            // Read once the storage is done
+            return;
+        }
+        let region = self.region();
+        let offset = self.offset.get();
+        self.buffer.take().map(|buffer| {
+            let length = cmp::min(buffer.len(), region.length - offset);
+            if length == 0 {
+                self.buffer.replace(buffer);
+                self.read_ahead_len.set(0);
+            } else {
+                self.busy.set(Busy::Read);
+                if let Err((_, buffer)) = self.storage.read(region.address + offset, buffer, length)
+                {
+                    // Uploads fail until the read is retried
+                    self.buffer.replace(buffer);
+                    self.busy.set(Busy::No);
+                }
+            }
+        });
+    }
+
+    /// Writes the received block to the storage.
+    fn write_block(&self, length: usize) {
+        let region = self.region();
+        let offset = self.offset.get();
+        let valid = !region.apps
+            || self.buffer.map_or(false, |buffer| {
+                let mut tbf = self.tbf.get();
+                let valid = tbf.check(offset, &buffer[..length]);
+                self.tbf.set(tbf);
+                valid
+            });
+        if !valid {
+            self.fail(DfuStatus::ErrFile);
+            return;
+        }
+
+        self.state.set(DfuState::DownloadSync);
+        self.writing.set((region.address + offset, length));
+        self.erase_next.set(region.address + offset);
+        self.erase_or_write();
+    }
+
+    /// Erases the next erase unit that starts under the block being
+    /// written, or writes the block once they are all erased. Units that
+    /// start before the block were erased with an earlier block.
+    fn erase_or_write(&self) {
+        let (address, length) = self.writing.get();
+        let mut next = self.erase_next.get();
+        while next < address + length {
+            match self.storage.erase_unit(next) {
+                Some((start, unit_length)) if start == next => {
+                    self.erase_next.set(start + unit_length);
+                    self.busy.set(Busy::Erase);
+                    if self.storage.erase(start).is_err() {
+                        self.busy.set(Busy::No);
+                        self.fail(DfuStatus::ErrErase);
+                    }
+                    return;
+                }
+                Some((start, unit_length)) => next = start + unit_length,
+                None => {
+                    self.fail(DfuStatus::ErrAddress);
+                    return;
+                }
+            }
+        }
+        self.start_write(address, length);
+    }
+
+    /// Writes the first `length` bytes of the buffer, padded with 0xFF to the
+    /// write size, which leaves the flash under the padding unchanged.
+    fn start_write(&self, address: usize, length: usize) {
+        let write_size = self.storage.geometry().write_size;
+        let padded = (length + write_size - 1) / write_size * write_size;
+        let result = self.buffer.take().map(|buffer| {
+            if address % write_size != 0 || padded > buffer.len() {
+                return Err((DfuStatus::ErrAddress, buffer));
+            }
+            for b in buffer[length..padded].iter_mut() {
+                *b = 0xFF;
+            }
+            self.busy.set(Busy::Write);
+            self.storage
+                .write(address, buffer, padded)
+                .map_err(|(_, buffer)| (DfuStatus::ErrWrite, buffer))
+        });
+        match result {
+            Some(Ok(())) => {}
+            Some(Err((status, buffer))) => {
+                self.buffer.replace(buffer);
+                self.busy.set(Busy::No);
+                self.fail(status);
+            }
+            None => {
+                self.fail(DfuStatus::ErrUnknown);
+            }
+        }
+    }
+
+    /// Handles a zero length `DFU_DNLOAD`, the end of a download.
+    fn manifest(&self) -> hil::usb::CtrlSetupResult {
+        let region = self.region();
+        let end = self.offset.get();
+        if region.apps {
+            if !self.tbf.get().complete(end) {
+                return self.fail(DfuStatus::ErrNotDone);
+            }
+            if end + APPS_END_LEN <= region.length {
+                // Clear the header after the last app. The write starts at
+                // the write size, and the 0xFF before the header leaves the
+                // last app unchanged.
+                let skip = end % self.storage.geometry().write_size;
+                let cleared = self.buffer.map_or(false, |buffer| {
+                    if skip + APPS_END_LEN > buffer.len() {
+                        return false;
+                    }
+                    for (i, b) in buffer.iter_mut().take(skip + APPS_END_LEN).enumerate() {
+                        *b = if i < skip { 0xFF } else { 0 };
+                    }
+                    true
+                });
+                if !cleared {
+                    return self.fail(DfuStatus::ErrUnknown);
+                }
+                self.state.set(DfuState::ManifestSync);
+                self.start_write(region.address + end - skip, skip + APPS_END_LEN);
+                return hil::usb::CtrlSetupResult::Ok;
+            }
+        }
+        self.state.set(DfuState::ManifestSync);
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    /// Handles a `DFU_GETSTATUS` request, which also moves the state
+    /// machine forward once the flash is done.
+    fn get_status(&self) {
+        let busy = self.writing_busy();
+        match self.state.get() {
+            DfuState::DownloadSync | DfuState::DownloadBusy => {
+                if busy {
+                    self.state.set(DfuState::DownloadBusy);
+                } else if self.status.get() != DfuStatus::Ok {
+                    self.state.set(DfuState::DfuError);
+                } else {
+                    self.state.set(DfuState::DownloadIdle);
+                }
+            }
+            DfuState::ManifestSync | DfuState::Manifest => {
+                if busy {
+                    self.state.set(DfuState::Manifest);
+                } else if self.status.get() != DfuStatus::Ok {
+                    self.state.set(DfuState::DfuError);
+                } else {
+                    self.idle();
+                }
+            }
+            _ => {}
+        }
+        self.ctrl_state.set(CtrlState::GetStatus);
+    }
+
+    /// Handles a DFU class request.
+    fn class_request(&self, setup_data: SetupData) -> hil::usb::CtrlSetupResult {
+        let state = self.state.get();
+        let length = setup_data.length as usize;
+        match setup_data.request_code {
+            DFU_DETACH => {
+                self.ctrl_state.set(CtrlState::Detach);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            DFU_DNLOAD => match state {
+                DfuState::DfuIdle | DfuState::DownloadIdle if length > 0 => {
+                    if state == DfuState::DfuIdle {
+                        let address = self.region().address;
+                        if self.storage.erase_unit(address).map(|(start, _)| start) != Some(address)
+                        {
+                            // Erasing the first block would erase what is
+                            // before the region
+                            return self.fail(DfuStatus::ErrAddress);
+                        }
+                        self.offset.set(0);
+                        self.tbf.set(TbfCheck::default());
+                    }
+                    if self.buffer.map_or(0, |buffer| buffer.len()) < length {
+                        // Too long, or the buffer is busy
+                        return self.fail(DfuStatus::ErrUnknown);
+                    }
+                    if self.offset.get() + length > self.region().length {
+                        return self.fail(DfuStatus::ErrAddress);
+                    }
+                    self.ctrl_state.set(CtrlState::Download {
+                        received: 0,
+                        length,
+                    });
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                DfuState::DownloadIdle => self.manifest(),
+                _ => self.fail(DfuStatus::ErrStalledPkt),
+            },
+            DFU_UPLOAD => match state {
+                DfuState::DfuIdle | DfuState::UploadIdle => match self.read_ahead_len.extract() {
+                    Some(ready) if self.busy.get() == Busy::No => {
+                        self.state.set(DfuState::UploadIdle);
+                        self.ctrl_state.set(CtrlState::Upload {
+                            sent: 0,
+                            length: cmp::min(ready, length),
+                            requested: length,
+                        });
+                        hil::usb::CtrlSetupResult::Ok
+                    }
+                    _ => self.fail(DfuStatus::ErrUnknown),
+                },
+                _ => self.fail(DfuStatus::ErrStalledPkt),
+            },
+            DFU_GETSTATUS => {
+                self.get_status();
+                hil::usb::CtrlSetupResult::Ok
+            }
+            DFU_CLRSTATUS if state == DfuState::DfuError => {
+                self.status.set(DfuStatus::Ok);
+                self.idle();
+                hil::usb::CtrlSetupResult::Ok
+            }
+            DFU_GETSTATE => {
+                self.ctrl_state.set(CtrlState::GetState);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            DFU_ABORT => match state {
+                DfuState::DfuIdle | DfuState::DownloadIdle | DfuState::UploadIdle => {
+                    self.idle();
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                _ => self.fail(DfuStatus::ErrStalledPkt),
+            },
+            _ => self.fail(DfuStatus::ErrStalledPkt),
+        }
+    }
+
+    /// Selects the region to download to or upload from.
+    fn set_interface(&self, alternate_setting: usize) -> hil::usb::CtrlSetupResult {
+        if alternate_setting >= self.regions.len() || self.writing_busy() {
+            return hil::usb::CtrlSetupResult::ErrGeneric;
+        }
+        self.region.set(alternate_setting);
+        self.status.set(DfuStatus::Ok);
+        self.idle();
+        hil::usb::CtrlSetupResult::Ok
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: FlashRange<'static>> hil::usb::Client<'a>
+    for Dfu<'a, U, S>
+{
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+        self.idle();
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.client_ctrl.bus_reset();
+        self.ctrl_state.set(CtrlState::Idle);
+        if !self.writing_busy() {
+            self.status.set(DfuStatus::Ok);
+            self.idle();
+        }
+    }
+
+    fn suspend(&'a self) {
+        // Flash operations carry on, only the control requests pause.
+    }
+
+    fn resume(&'a self) {}
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        self.ctrl_state.set(CtrlState::Idle);
+        let setup_data = match SetupData::get(&self.client_ctrl.ctrl_buffer.buf) {
+            Some(setup_data) => setup_data,
+            None => return self.client_ctrl.ctrl_setup(endpoint),
+        };
+        match (
+            setup_data.request_type.request_type(),
+            setup_data.request_type.recipient(),
+        ) {
+            (RequestType::Class, Recipient::Interface) => self.class_request(setup_data),
+            (RequestType::Standard, Recipient::Interface) => match setup_data.request_code {
+                SET_INTERFACE => self.set_interface(setup_data.value as usize),
+                GET_INTERFACE => {
+                    self.ctrl_state.set(CtrlState::GetInterface);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                _ => self.client_ctrl.ctrl_setup(endpoint),
+            },
+            _ => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        let buf = &self.client_ctrl.ctrl_buffer.buf;
+        match self.ctrl_state.get() {
+            CtrlState::GetStatus => {
+                let poll_timeout = match self.state.get() {
+                    DfuState::DownloadBusy | DfuState::Manifest => POLL_TIMEOUT_MS,
+                    _ => 0,
+                };
+                buf[0].set(self.status.get() as u8);
+                buf[1].set(poll_timeout as u8);
+                buf[2].set((poll_timeout >> 8) as u8);
+                buf[3].set((poll_timeout >> 16) as u8);
+                buf[4].set(self.state.get() as u8);
+                buf[5].set(0); // No status description
+                hil::usb::CtrlInResult::Packet(6, true)
+            }
+            CtrlState::GetState => {
+                buf[0].set(self.state.get() as u8);
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            CtrlState::GetInterface => {
+                buf[0].set(self.region.get() as u8);
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            CtrlState::Upload {
+                sent,
+                length,
+                requested,
+            } => {
+                let packet_bytes = cmp::min(buf.len(), length - sent);
+                self.buffer.map(|buffer| {
+                    for i in 0..packet_bytes {
+                        buf[i].set(buffer[sent + i]);
+                    }
+                });
+                let sent = sent + packet_bytes;
+                self.ctrl_state.set(CtrlState::Upload {
+                    sent,
+                    length,
+                    requested,
+                });
+                hil::usb::CtrlInResult::Packet(packet_bytes, sent == length)
+            }
+            _ => self.client_ctrl.ctrl_in(endpoint),
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.ctrl_state.get() {
+            CtrlState::Download { received, length } => {
+                let buf = &self.client_ctrl.ctrl_buffer.buf;
+                let packet_bytes = cmp::min(packet_bytes as usize, length - received);
+                self.buffer
+                    .map_or(hil::usb::CtrlOutResult::Halted, |buffer| {
+                        for i in 0..packet_bytes {
+                            buffer[received + i] = buf[i].get();
+                        }
+                        self.ctrl_state.set(CtrlState::Download {
+                            received: received + packet_bytes,
+                            length,
+                        });
+                        hil::usb::CtrlOutResult::Ok
+                    })
+            }
+            _ => self.client_ctrl.ctrl_out(endpoint, packet_bytes),
+        }
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        match self.ctrl_state.get() {
+            CtrlState::Download { received, length } => {
+                if received == length {
+                    self.write_block(length);
+                } else {
+                    self.fail(DfuStatus::ErrUnknown);
+                }
+            }
+            CtrlState::Upload {
+                length, requested, ..
+            } => {
+                self.offset.set(self.offset.get() + length);
+                if length < requested {
+                    // A short block ends the upload
+                    self.idle();
+                } else {
+                    self.read_next_block();
+                }
+            }
+            CtrlState::Detach => {
+                self.detach_function.map(|f| f());
+            }
+            _ => {}
+        }
+        self.ctrl_state.set(CtrlState::Idle);
+
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    fn packet_in(&'a self, _transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        hil::usb::InResult::Error
+    }
+
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::OutResult::Error
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {}
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: FlashRange<'static>> RangeClient for Dfu<'a, U, S> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        self.buffer.replace(buffer);
+        self.busy.set(Busy::No);
+        if self.read_ahead.get() != (self.region.get(), self.offset.get()) {
+            // The host moved on while we were reading
+            self.read_next_block();
+        } else if result.is_ok() {
+            self.read_ahead_len.set(length);
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize, result: Result<(), ErrorCode>) {
+        self.buffer.replace(buffer);
+        self.busy.set(Busy::No);
+        if result.is_err() {
+            self.status.set(DfuStatus::ErrWrite);
+            return;
+        }
+        match self.state.get() {
+            DfuState::DownloadSync | DfuState::DownloadBusy => {
+                // The block, without the padding
+                self.offset.set(self.offset.get() + self.writing.get().1);
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_done(&self, result: Result<(), ErrorCode>) {
+        self.busy.set(Busy::No);
+        if result.is_err() {
+            self.status.set(DfuStatus::ErrErase);
+        } else {
+            self.erase_or_write();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TBF header of 16 bytes for an app of `total_size` bytes.
+    fn header(total_size: u32) -> [u8; 16] {
+        let words = [0x0010_0002, total_size, 0, 0x0010_0002 ^ total_size];
+        let mut header = [0; 16];
+        for (bytes, word) in header.chunks_mut(4).zip(words.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        header
+    }
+
+    #[test]
+    fn checks_headers_across_blocks() {
+        let mut tbf = TbfCheck::default();
+        let first = header(32);
+        assert!(tbf.check(0, &first[..10]));
+        assert!(tbf.check(10, &first[10..]));
+        assert!(!tbf.complete(16));
+        assert!(tbf.check(16, &[0; 16]));
+        assert!(tbf.complete(32));
+
+        let mut corrupt = header(32);
+        corrupt[12] ^= 1;
+        assert!(!tbf.check(32, &corrupt));
+    }
+
+    #[test]
+    fn rejects_size_past_address_space() {
+        let mut tbf = TbfCheck {
+            next_header: usize::MAX - 15,
+            ..TbfCheck::default()
+        };
+        assert!(!tbf.check(usize::MAX - 15, &header(0x100)));
+    }
+}
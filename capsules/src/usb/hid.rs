@@ -0,0 +1,744 @@
+//! Generic USB HID (Human Interface Device) class
+//!
+//! This capsule presents a single HID interface with an interrupt IN
+//! endpoint for input reports and an interrupt OUT endpoint for output
+//! reports. The reports are described by a report descriptor, which is either
+//! one of the presets in this module (a boot protocol keyboard or mouse) or
+//! supplied by the board or by an app with `set_reports()` before the device
+//! is attached.
+//!
+//! Input reports are sent with `hil::usb_hid::UsbHid::send_buffer()`. Output
+//! reports from the host, whether on the OUT endpoint or with a
+//! `SET_REPORT` request, are passed to the client with `packet_received()`.
+//! Only the first `input_size`/`output_size` bytes of the 64 byte buffers
+//! are used.
+//!
+//! The presets use the boot report format in both protocols, so
+//! `SET_PROTOCOL` has no effect on the reports. `SET_IDLE` is accepted, but
+//! reports are only sent when the client sends them.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let hid = static_init!(
+//!     capsules::usb::hid::Hid<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::hid::Hid::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x521a,
+//!         strings,
+//!         &mut HID_REPORT_DESCRIPTOR_BUFFER,
+//!     )
+//! );
+//! let keyboard = capsules::usb::hid::KEYBOARD;
+//! hid.set_reports(
+//!     keyboard.descriptor,
+//!     keyboard.boot_protocol,
+//!     keyboard.input_size,
+//!     keyboard.output_size,
+//! )
+//! .expect("HID report descriptor does not fit");
+//! nrf52840::usbd::USBD.set_client(hid);
+//! hid.enable();
+//! hid.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::DescriptorType;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::HIDCountryCode;
+use super::descriptors::HIDDescriptor;
+use super::descriptors::HIDSubordinateDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
+
+/// Use 1 Interrupt transfer IN/OUT endpoint
+const ENDPOINT_NUM: usize = 1;
+
+const OUT_BUFFER: usize = 0;
+const IN_BUFFER: usize = 1;
+
+const N_ENDPOINTS: usize = 2;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Largest input or output report.
+pub const MAX_REPORT_SIZE: usize = 64;
+
+/// Size of the report descriptor buffer that fits the presets and most
+/// devices.
+pub const REPORT_DESCRIPTOR_BUFLEN: usize = 128;
+
+/// Offsets in the configuration descriptor of the fields that depend on the
+/// report descriptor: the subclass and protocol of the interface, and the
+/// length of the report descriptor in the HID descriptor.
+const INTERFACE_SUBCLASS_OFFSET: usize = 9 + 6;
+const INTERFACE_PROTOCOL_OFFSET: usize = 9 + 7;
+const REPORT_LENGTH_OFFSET: usize = 9 + 9 + 7;
+
+/// Class specific requests
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+const SET_PROTOCOL: u8 = 0x0b;
+
+/// The boot protocol a device supports, so that it works in a BIOS.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BootProtocol {
+    None = 0,
+    Keyboard = 1,
+    Mouse = 2,
+}
+
+/// The reports of a HID device.
+#[derive(Copy, Clone)]
+pub struct HidReports {
+    pub descriptor: &'static [u8],
+    pub boot_protocol: BootProtocol,
+    /// Size of the input reports, sent to the host.
+    pub input_size: usize,
+    /// Size of the output reports, received from the host.
+    pub output_size: usize,
+}
+
+/// A boot protocol keyboard (HID 1.11, appendix B.1).
+///
+/// The input report is a byte of modifier keys, a reserved byte and up to 6
+/// key codes. The output report is a byte of LEDs.
+pub const KEYBOARD: HidReports = HidReports {
+    descriptor: KEYBOARD_REPORT_DESCRIPTOR,
+    boot_protocol: BootProtocol::Keyboard,
+    input_size: 8,
+    output_size: 1,
+};
+
+/// A boot protocol mouse (HID 1.11, appendix B.2).
+///
+/// The input report is a byte of buttons, and the X and Y movement.
+pub const MOUSE: HidReports = HidReports {
+    descriptor: MOUSE_REPORT_DESCRIPTOR,
+    boot_protocol: BootProtocol::Mouse,
+    input_size: 3,
+    output_size: 0,
+};
+
+const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xE0, //   Usage Minimum (224)
+    0x29, 0xE7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute): Modifier byte
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant): Reserved byte
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute): LED report
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant): LED report padding
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array): Key arrays (6 bytes)
+    0xC0, // End Collection
+];
+
+const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xA1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Buttons)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute): 3 buttons
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant): 5 bit padding
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7F, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x06, //     Input (Data, Variable, Relative): X and Y
+    0xC0, //   End Collection
+    0xC0, // End Collection
+];
+
+/// States of the Control Endpoint related to HID.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CtrlState {
+    Idle,
+    /// Sending the HID descriptor.
+    HidDescriptor(usize),
+    /// Sending the report descriptor.
+    Report(usize, usize),
+    /// Sending the last input report.
+    InputReport(usize),
+    /// Sending a single byte.
+    Value(u8),
+    /// Receiving an output report, with the number of bytes received so far.
+    OutputReport(usize),
+}
+
+/// Implementation of a generic USB HID device
+pub struct Hid<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    report_descriptor: TakeCell<'static, [u8]>,
+    report_descriptor_len: Cell<usize>,
+    input_size: Cell<usize>,
+    output_size: Cell<usize>,
+    attached: Cell<bool>,
+    /// Whether the host suspended the bus.
+    suspended: Cell<bool>,
+
+    ctrl_state: Cell<CtrlState>,
+    /// The last input report sent, for `GET_REPORT` requests.
+    input_report: [Cell<u8>; MAX_REPORT_SIZE],
+    idle_rate: Cell<u8>,
+    protocol: Cell<u8>,
+
+    client: OptionalCell<&'a dyn hil::usb_hid::Client<'a, [u8; 64]>>,
+
+    /// A buffer to hold the data we want to send
+    send_buffer: TakeCell<'static, [u8; 64]>,
+
+    /// A holder for the buffer to receive reports into. We use this as a flag
+    /// as well, if we have a buffer then we are actively doing a receive.
+    recv_buffer: TakeCell<'static, [u8; 64]>,
+
+    /// The size of an output report waiting in the OUT buffer until the
+    /// client can receive it. We delayed the OUT endpoint meanwhile.
+    pending_out: OptionalCell<usize>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Hid<'a, U> {
+    /// The device has no reports until they are set with `set_reports()`,
+    /// which must happen before it is attached. `report_descriptor` holds
+    /// the report descriptor; `REPORT_DESCRIPTOR_BUFLEN` bytes fit the
+    /// presets.
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        report_descriptor: &'static mut [u8],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x03, // HID
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: MAX_REPORT_SIZE as u16,
+                interval: 10,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_NUM,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: MAX_REPORT_SIZE as u16,
+                interval: 10,
+            },
+        ]];
+
+        // The length of the report descriptor is filled in once we have it
+        let hid_descriptor = HIDDescriptor {
+            hid_class: 0x0111,
+            country_code: HIDCountryCode::NotSupported,
+            sub_descriptors: &[HIDSubordinateDescriptor {
+                typ: DescriptorType::Report,
+                len: 0,
+            }],
+        };
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x00, // Class defined by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    // Keyboards and mice may wake up the host.
+                    attributes: descriptors::ConfigurationAttributes::new(true, true),
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                Some(&hid_descriptor),
+                None, // No CDC descriptor array
+            );
+
+        const ZERO: Cell<u8> = Cell::new(0);
+        Hid {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // The HID descriptor is sent by us
+                None, // The report descriptor is sent by us
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            report_descriptor: TakeCell::new(report_descriptor),
+            report_descriptor_len: Cell::new(0),
+            input_size: Cell::new(0),
+            output_size: Cell::new(0),
+            attached: Cell::new(false),
+            suspended: Cell::new(false),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            input_report: [ZERO; MAX_REPORT_SIZE],
+            idle_rate: Cell::new(0),
+            protocol: Cell::new(1),
+            client: OptionalCell::empty(),
+            send_buffer: TakeCell::empty(),
+            recv_buffer: TakeCell::empty(),
+            pending_out: OptionalCell::empty(),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    pub fn set_client(&'a self, client: &'a dyn hil::usb_hid::Client<'a, [u8; 64]>) {
+        self.client.set(client);
+    }
+
+    /// Replaces the report descriptor and the sizes of the reports. This is
+    /// only possible before the device is attached, since the host reads the
+    /// report descriptor once.
+    pub fn set_reports(
+        &self,
+        descriptor: &[u8],
+        boot_protocol: BootProtocol,
+        input_size: usize,
+        output_size: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.attached.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        if input_size > MAX_REPORT_SIZE || output_size > MAX_REPORT_SIZE {
+            return Err(ErrorCode::INVAL);
+        }
+        self.report_descriptor
+            .map_or(Err(ErrorCode::NOMEM), |buffer| {
+                if descriptor.len() > buffer.len() {
+                    return Err(ErrorCode::SIZE);
+                }
+                buffer[..descriptor.len()].copy_from_slice(descriptor);
+                Ok(())
+            })?;
+
+        let buf = &self.client_ctrl.configuration_descriptor().buf;
+        let subclass = match boot_protocol {
+            BootProtocol::None => 0x00,
+            _ => 0x01, // Boot interface
+        };
+        buf[INTERFACE_SUBCLASS_OFFSET].set(subclass);
+        buf[INTERFACE_PROTOCOL_OFFSET].set(boot_protocol as u8);
+        buf[REPORT_LENGTH_OFFSET].set(descriptor.len() as u8);
+        buf[REPORT_LENGTH_OFFSET + 1].set((descriptor.len() >> 8) as u8);
+
+        self.report_descriptor_len.set(descriptor.len());
+        self.input_size.set(input_size);
+        self.output_size.set(output_size);
+        Ok(())
+    }
+
+    /// Whether the device is attached, so the reports cannot change.
+    pub fn is_attached(&self) -> bool {
+        self.attached.get()
+    }
+
+    /// Size of the input reports, sent to the host.
+    pub fn input_size(&self) -> usize {
+        self.input_size.get()
+    }
+
+    /// Size of the output reports, received from the host.
+    pub fn output_size(&self) -> usize {
+        self.output_size.get()
+    }
+
+    fn can_receive(&'a self) -> bool {
+        self.client
+            .map(move |client| client.can_receive())
+            .unwrap_or(false)
+    }
+
+    /// Passes an output report in the OUT buffer to the client, if it can
+    /// receive it.
+    fn deliver_out(&'a self, packet_bytes: usize) -> bool {
+        if !self.can_receive() {
+            return false;
+        }
+        self.recv_buffer.take().map_or(false, |buf| {
+            let packet = &self.buffers[OUT_BUFFER].buf;
+            let len = cmp::min(packet_bytes, buf.len());
+            for i in 0..buf.len() {
+                buf[i] = if i < len { packet[i].get() } else { 0 };
+            }
+            self.client.map(move |client| {
+                client.packet_received(Ok(()), buf, ENDPOINT_NUM);
+            });
+            true
+        })
+    }
+
+    /// Handles a standard or HID class request to the interface.
+    fn interface_request(&'a self, setup_data: SetupData) -> Option<hil::usb::CtrlSetupResult> {
+        let length = setup_data.length as usize;
+        let state = match setup_data.request_type.request_type() {
+            RequestType::Standard => match setup_data.get_standard_request() {
+                Some(StandardRequest::GetDescriptor {
+                    descriptor_type: DescriptorType::HID,
+                    ..
+                }) => CtrlState::HidDescriptor(cmp::min(9, length)),
+                Some(StandardRequest::GetDescriptor {
+                    descriptor_type: DescriptorType::Report,
+                    ..
+                }) => CtrlState::Report(0, cmp::min(self.report_descriptor_len.get(), length)),
+                _ => return None,
+            },
+            RequestType::Class => match setup_data.request_code {
+                GET_REPORT => CtrlState::InputReport(cmp::min(self.input_size.get(), length)),
+                GET_IDLE => CtrlState::Value(self.idle_rate.get()),
+                GET_PROTOCOL => CtrlState::Value(self.protocol.get()),
+                SET_REPORT => CtrlState::OutputReport(0),
+                SET_IDLE => {
+                    self.idle_rate.set((setup_data.value >> 8) as u8);
+                    CtrlState::Idle
+                }
+                SET_PROTOCOL => {
+                    self.protocol.set(setup_data.value as u8);
+                    CtrlState::Idle
+                }
+                _ => return Some(hil::usb::CtrlSetupResult::ErrGeneric),
+            },
+            _ => return None,
+        };
+        self.ctrl_state.set(state);
+        Some(hil::usb::CtrlSetupResult::Ok)
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for Hid<'a, U> {
+    fn send_buffer(
+        &'a self,
+        send: &'static mut [u8; 64],
+    ) -> Result<usize, (ErrorCode, &'static mut [u8; 64])> {
+        if self.send_buffer.is_some() {
+            return Err((ErrorCode::BUSY, send));
+        }
+        let len = self.input_size.get();
+        for i in 0..len {
+            self.input_report[i].set(send[i]);
+        }
+
+        self.send_buffer.replace(send);
+        self.controller().endpoint_resume_in(ENDPOINT_NUM);
+
+        // An input report wakes up the host if it allowed us to. Otherwise
+        // the report is sent once the host resumes the bus.
+        if self.suspended.get() {
+            let _ = self.client_ctrl.remote_wakeup();
+        }
+
+        Ok(len)
+    }
+
+    fn send_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
+        self.send_buffer.take().ok_or(ErrorCode::INVAL)
+    }
+
+    fn receive_buffer(
+        &'a self,
+        recv: &'static mut [u8; 64],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; 64])> {
+        if self.recv_buffer.is_some() {
+            return Err((ErrorCode::BUSY, recv));
+        }
+        self.recv_buffer.replace(recv);
+
+        // Pass a report we held back before, and accept more data
+        if let Some(packet_bytes) = self.pending_out.extract() {
+            if self.deliver_out(packet_bytes) {
+                self.pending_out.clear();
+                self.controller().endpoint_resume_out(ENDPOINT_NUM);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn receive_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
+        self.recv_buffer.take().ok_or(ErrorCode::INVAL)
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for Hid<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_out_buffer(ENDPOINT_NUM, &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_NUM, &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_out_enable(TransferType::Interrupt, ENDPOINT_NUM);
+    }
+
+    fn attach(&'a self) {
+        self.attached.set(true);
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.client_ctrl.bus_reset();
+        self.suspended.set(false);
+        // The host selects the report protocol when it configures the device
+        self.protocol.set(1);
+        self.idle_rate.set(0);
+    }
+
+    fn suspend(&'a self) {
+        self.suspended.set(true);
+    }
+
+    fn resume(&'a self) {
+        self.suspended.set(false);
+        // Restart a send that the host did not pick up before suspending.
+        if self.send_buffer.is_some() {
+            self.controller().endpoint_resume_in(ENDPOINT_NUM);
+        }
+    }
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        self.ctrl_state.set(CtrlState::Idle);
+        let setup_data = match SetupData::get(&self.client_ctrl.ctrl_buffer.buf) {
+            Some(setup_data) => setup_data,
+            None => return self.client_ctrl.ctrl_setup(endpoint),
+        };
+
+        match setup_data.request_type.recipient() {
+            Recipient::Interface => self
+                .interface_request(setup_data)
+                .unwrap_or_else(|| self.client_ctrl.ctrl_setup(endpoint)),
+            _ => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        let buf = &self.client_ctrl.ctrl_buffer.buf;
+        match self.ctrl_state.get() {
+            CtrlState::HidDescriptor(len) => {
+                // The HID descriptor follows the interface descriptor
+                for i in 0..len {
+                    buf[i].set(self.client_ctrl.configuration_descriptor().buf[9 + 9 + i].get());
+                }
+                hil::usb::CtrlInResult::Packet(len, true)
+            }
+            CtrlState::Report(start, end) => {
+                let packet_bytes = cmp::min(buf.len(), end - start);
+                self.report_descriptor.map(|descriptor| {
+                    for i in 0..packet_bytes {
+                        buf[i].set(descriptor[start + i]);
+                    }
+                });
+                let start = start + packet_bytes;
+                self.ctrl_state.set(CtrlState::Report(start, end));
+                hil::usb::CtrlInResult::Packet(packet_bytes, start == end)
+            }
+            CtrlState::InputReport(len) => {
+                for i in 0..len {
+                    buf[i].set(self.input_report[i].get());
+                }
+                hil::usb::CtrlInResult::Packet(len, true)
+            }
+            CtrlState::Value(value) => {
+                buf[0].set(value);
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            _ => self.client_ctrl.ctrl_in(endpoint),
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.ctrl_state.get() {
+            CtrlState::OutputReport(received) => {
+                // Reports the client cannot receive are dropped, since we
+                // cannot hold back the control endpoint.
+                let buf = &self.client_ctrl.ctrl_buffer.buf;
+                self.recv_buffer.map(|recv| {
+                    let len = cmp::min(packet_bytes as usize, recv.len() - received);
+                    for i in 0..len {
+                        recv[received + i] = buf[i].get();
+                    }
+                });
+                let received = cmp::min(received + packet_bytes as usize, MAX_REPORT_SIZE);
+                self.ctrl_state.set(CtrlState::OutputReport(received));
+                hil::usb::CtrlOutResult::Ok
+            }
+            _ => self.client_ctrl.ctrl_out(endpoint, packet_bytes),
+        }
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        if let CtrlState::OutputReport(received) = self.ctrl_state.get() {
+            if self.can_receive() {
+                self.recv_buffer.take().map(|buf| {
+                    for b in buf[received..].iter_mut() {
+                        *b = 0;
+                    }
+                    self.client.map(move |client| {
+                        client.packet_received(Ok(()), buf, 0);
+                    });
+                });
+            }
+        }
+        self.ctrl_state.set(CtrlState::Idle);
+
+        if self.send_buffer.is_some() {
+            self.controller().endpoint_resume_in(ENDPOINT_NUM);
+        }
+
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk/Interrupt IN transaction.
+    ///
+    /// This is called when we can send an input report to the host.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                self.send_buffer
+                    .take()
+                    .map_or(hil::usb::InResult::Delay, |buf| {
+                        let packet = &self.buffers[IN_BUFFER].buf;
+                        let len = self.input_size.get();
+                        for i in 0..len {
+                            packet[i].set(buf[i]);
+                        }
+
+                        // Put the TX buffer back until the packet was sent.
+                        self.send_buffer.replace(buf);
+
+                        hil::usb::InResult::Packet(len)
+                    })
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                panic!("Transfer protocol not supported by HID");
+            }
+        }
+    }
+
+    /// Handle a Bulk/Interrupt OUT transaction
+    ///
+    /// Each packet is an output report. If the client cannot receive it yet,
+    /// we hold it back in the OUT buffer.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                if self.deliver_out(packet_bytes as usize) {
+                    hil::usb::OutResult::Ok
+                } else {
+                    self.pending_out.set(packet_bytes as usize);
+                    hil::usb::OutResult::Delay
+                }
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                panic!("Transfer protocol not supported by HID");
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.send_buffer.take().map(|buf| {
+            self.client.map(move |client| {
+                client.packet_transmitted(Ok(()), buf, endpoint);
+            });
+        });
+    }
+}
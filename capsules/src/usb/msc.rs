@@ -0,0 +1,710 @@
+//! Mass Storage Class device for USB
+//!
+//! This capsule exposes a `NonvolatileStorage` as a USB drive using the
+//! Bulk-Only Transport and the SCSI transparent command set, so a host can
+//! mount an SD card or external flash, e.g. to retrieve logs. There is a
+//! single logical unit with 512 byte blocks; the storage is accessed at
+//! `block * 512`.
+//!
+//! Every command starts with a Command Block Wrapper on the OUT endpoint,
+//! followed by an optional data phase and a Command Status Wrapper on the IN
+//! endpoint. Reads and writes are split into chunks the size of the buffer
+//! passed to `new()`, which must be a multiple of 512 bytes.
+//!
+//! The USB HIL cannot stall a single endpoint, so when the host expects more
+//! data than a command produces, the data phase is ended with a short or
+//! zero length packet, and data the host sends beyond what a command needs
+//! is discarded. The residue in the status tells the host how much was
+//! used.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let msc = static_init!(
+//!     capsules::usb::msc::MassStorage<'static, nrf52840::usbd::Usbd<'static>, Storage>,
+//!     capsules::usb::msc::MassStorage::new(
+//!         &nrf52840::usbd::USBD,
+//!         storage,
+//!         &mut MSC_BUFFER,
+//!         4096,  // blocks
+//!         false, // read only
+//!         0x1915,
+//!         0x503a,
+//!         STRINGS,
+//!     )
+//! );
+//! storage.set_client(msc);
+//! nrf52840::usbd::USBD.set_client(msc);
+//! msc.enable();
+//! msc.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::TransferDirection;
+use super::scsi;
+use super::scsi::{CommandBlockWrapper, CommandStatus, ScsiCommand, SenseData, BLOCK_SIZE};
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::{OptionalCell, TakeCell, VolatileCell};
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::usb::TransferType;
+
+/// Identifying number for the endpoint when transferring data from us to the
+/// host.
+const ENDPOINT_IN_NUM: usize = 1;
+/// Identifying number for the endpoint when transferring data from the host to
+/// us.
+const ENDPOINT_OUT_NUM: usize = 2;
+
+const N_ENDPOINTS: usize = 2;
+
+const PACKET_SIZE: usize = 64;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Max packet size of the control endpoint
+pub const MAX_CTRL_PACKET_SIZE: u8 = 64;
+
+/// Class specific requests of the Bulk-Only Transport
+const BULK_ONLY_RESET: u8 = 0xff;
+const GET_MAX_LUN: u8 = 0xfe;
+
+/// States of the Bulk-Only Transport.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    /// Waiting for a Command Block Wrapper.
+    Command,
+    /// Sending `buffer[buf_offset..buf_len]` to the host.
+    DataIn,
+    /// Receiving data into `buffer[buf_offset..buf_len]`. Data beyond
+    /// `buf_len` is discarded.
+    DataOut,
+    /// Waiting for the storage to finish reading or writing a chunk.
+    Storage,
+    /// The host was reset while the storage was busy.
+    StorageReset,
+    /// The Command Status Wrapper is ready to be sent.
+    Status,
+    /// The Command Status Wrapper was handed to the controller.
+    StatusSent,
+    /// We got an invalid CBW and refuse to work until the host resets us.
+    Stalled,
+}
+
+/// States of the Control Endpoint related to mass storage.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CtrlState {
+    Idle,
+    /// Host has sent a GET_MAX_LUN request.
+    GetMaxLun,
+}
+
+pub struct MassStorage<'a, U: 'a, S: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    storage: &'a S,
+    /// Size of the storage in blocks.
+    num_blocks: u32,
+    read_only: bool,
+    /// Manufacturer and product, also reported in the INQUIRY response.
+    strings: &'static [&'static str; 3],
+
+    state: Cell<State>,
+    ctrl_state: Cell<CtrlState>,
+
+    /// The command being processed.
+    cbw: OptionalCell<CommandBlockWrapper>,
+    status: Cell<CommandStatus>,
+    /// Sense data of the last failed command, for REQUEST SENSE.
+    sense: Cell<SenseData>,
+    /// Bytes of data moved in the data phase of the current command, in
+    /// either direction.
+    moved: Cell<u32>,
+    /// Bytes of the data phase that carried data for the command.
+    transferred: Cell<u32>,
+    /// The host does not expect more data in this data phase.
+    phase_done: Cell<bool>,
+    /// The OUT endpoint NAKs until we resume it.
+    out_delayed: Cell<bool>,
+
+    /// Holds responses and chunks of blocks read or to be written.
+    buffer: TakeCell<'static, [u8]>,
+    buf_offset: Cell<usize>,
+    buf_len: Cell<usize>,
+    /// Next block to read or write, and the blocks left to transfer.
+    lba: Cell<u32>,
+    blocks: Cell<u32>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: NonvolatileStorage<'static>> MassStorage<'a, U, S> {
+    pub fn new(
+        controller: &'a U,
+        storage: &'a S,
+        buffer: &'static mut [u8],
+        num_blocks: u32,
+        read_only: bool,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x08,    // Mass Storage
+            interface_subclass: 0x06, // SCSI transparent command set
+            interface_protocol: 0x50, // Bulk-Only Transport
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_IN_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: PACKET_SIZE as u16,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_OUT_NUM,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: PACKET_SIZE as u16,
+                interval: 0,
+            },
+        ]];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x00, // Class defined by the interface
+                    max_packet_size_ep0: MAX_CTRL_PACKET_SIZE,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                None, // No HID descriptor
+                None, // No CDC descriptor array
+            );
+
+        MassStorage {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            storage,
+            num_blocks,
+            read_only,
+            strings,
+            state: Cell::new(State::Command),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            cbw: OptionalCell::empty(),
+            status: Cell::new(CommandStatus::Passed),
+            sense: Cell::new(SenseData::NO_SENSE),
+            moved: Cell::new(0),
+            transferred: Cell::new(0),
+            phase_done: Cell::new(true),
+            out_delayed: Cell::new(false),
+            buffer: TakeCell::new(buffer),
+            buf_offset: Cell::new(0),
+            buf_len: Cell::new(0),
+            lba: Cell::new(0),
+            blocks: Cell::new(0),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    #[inline]
+    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
+        &self.buffers[i - 1].buf
+    }
+
+    fn host_length(&self) -> u32 {
+        self.cbw.map_or(0, |cbw| cbw.data_transfer_length)
+    }
+
+    /// Handles a Command Block Wrapper from the host.
+    fn start_command(&self, cbw: CommandBlockWrapper) {
+        self.cbw.set(cbw);
+        self.status.set(CommandStatus::Passed);
+        self.moved.set(0);
+        self.transferred.set(0);
+        self.phase_done.set(cbw.data_transfer_length == 0);
+        self.buf_offset.set(0);
+        self.buf_len.set(0);
+        self.blocks.set(0);
+
+        if cbw.lun != 0 {
+            return self.fail(SenseData::INVALID_FIELD);
+        }
+        let command = match ScsiCommand::parse(cbw.command_block()) {
+            Ok(command) => command,
+            Err(sense) => return self.fail(sense),
+        };
+        let (data_in, len) = command.data_phase();
+        if len > cbw.data_transfer_length || (len > 0 && data_in != cbw.data_in) {
+            // The host and we disagree on the data phase
+            self.status.set(CommandStatus::PhaseError);
+            return self.finish_data_phase();
+        }
+
+        match command {
+            ScsiCommand::TestUnitReady | ScsiCommand::Ignored => self.finish_data_phase(),
+            ScsiCommand::RequestSense { .. } => {
+                let sense = self.sense.replace(SenseData::NO_SENSE);
+                self.send_response(len, |buf| sense.encode(buf));
+            }
+            ScsiCommand::Inquiry { .. } => self.send_response(len, |buf| {
+                scsi::encode_inquiry(buf, self.strings[0], self.strings[1])
+            }),
+            ScsiCommand::ModeSense6 { .. } => {
+                self.send_response(len, |buf| scsi::encode_mode_sense(buf, self.read_only))
+            }
+            ScsiCommand::ReadCapacity10 => {
+                self.send_response(len, |buf| scsi::encode_read_capacity(buf, self.num_blocks))
+            }
+            ScsiCommand::Read10 { lba, blocks } | ScsiCommand::Write10 { lba, blocks } => {
+                if lba as u64 + blocks as u64 > self.num_blocks as u64 {
+                    return self.fail(SenseData::LBA_OUT_OF_RANGE);
+                }
+                let write = match command {
+                    ScsiCommand::Write10 { .. } => true,
+                    _ => false,
+                };
+                if write && self.read_only {
+                    return self.fail(SenseData::WRITE_PROTECTED);
+                }
+                self.lba.set(lba);
+                self.blocks.set(blocks as u32);
+                if blocks == 0 {
+                    self.finish_data_phase();
+                } else if write {
+                    self.receive_blocks();
+                } else {
+                    self.read_blocks();
+                }
+            }
+        }
+    }
+
+    /// Fills the buffer with a response of `len` bytes and sends it.
+    fn send_response<F: FnOnce(&mut [u8])>(&self, len: u32, fill: F) {
+        self.buffer.map(|buf| fill(buf));
+        self.buf_offset.set(0);
+        self.buf_len.set(len as usize);
+        self.state.set(State::DataIn);
+        self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+    }
+
+    /// Reads the next chunk of blocks from the storage.
+    fn read_blocks(&self) {
+        let res = self.buffer.take().map(|buf| {
+            let blocks = cmp::min(self.blocks.get() as usize, buf.len() / BLOCK_SIZE);
+            let address = self.lba.get() as usize * BLOCK_SIZE;
+            self.state.set(State::Storage);
+            self.storage
+                .read(buf, address, blocks * BLOCK_SIZE)
+                .map_err(|_| ())
+        });
+        if res != Some(Ok(())) {
+            // The storage may have kept the buffer, so we cannot continue
+            self.fail(SenseData::READ_ERROR);
+        }
+    }
+
+    /// Sets up the buffer to receive the next chunk of blocks.
+    fn receive_blocks(&self) {
+        let chunk = self.buffer.map_or(0, |buf| {
+            cmp::min(self.blocks.get() as usize, buf.len() / BLOCK_SIZE) * BLOCK_SIZE
+        });
+        self.buf_offset.set(0);
+        self.buf_len.set(chunk);
+        self.state.set(State::DataOut);
+        self.resume_out();
+    }
+
+    /// Ends the current command with `sense`.
+    fn fail(&self, sense: SenseData) {
+        self.sense.set(sense);
+        self.status.set(CommandStatus::Failed);
+        self.finish_data_phase();
+    }
+
+    /// Ends the data phase without transferring more data for the command,
+    /// then reports the status.
+    fn finish_data_phase(&self) {
+        self.blocks.set(0);
+        self.buf_offset.set(0);
+        self.buf_len.set(0);
+        if self.phase_done.get() {
+            self.send_status();
+        } else if self.cbw.map_or(false, |cbw| cbw.data_in) {
+            // Sends a zero length packet
+            self.state.set(State::DataIn);
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        } else {
+            self.state.set(State::DataOut);
+            self.resume_out();
+        }
+    }
+
+    /// Lets the host send the next packet if we delayed the last one.
+    fn resume_out(&self) {
+        if self.out_delayed.replace(false) {
+            self.controller().endpoint_resume_out(ENDPOINT_OUT_NUM);
+        }
+    }
+
+    fn send_status(&self) {
+        self.state.set(State::Status);
+        self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+    }
+
+    /// Bulk-Only Mass Storage Reset: abandon the current command and wait
+    /// for the next CBW.
+    fn reset(&self) {
+        self.cbw.clear();
+        self.blocks.set(0);
+        match self.state.get() {
+            State::Storage | State::StorageReset => self.state.set(State::StorageReset),
+            _ => {
+                self.state.set(State::Command);
+                self.resume_out();
+            }
+        }
+    }
+
+    /// Handles a Bulk OUT packet of `packet_bytes` bytes.
+    fn handle_packet_out(&'a self, endpoint: usize, packet_bytes: usize) -> hil::usb::OutResult {
+        let packet = self.buffer(endpoint);
+        let packet_bytes = cmp::min(packet_bytes, packet.len());
+        match self.state.get() {
+            State::Command => {
+                let mut cbw = [0; scsi::CBW_LEN];
+                if packet_bytes != scsi::CBW_LEN {
+                    self.state.set(State::Stalled);
+                    return hil::usb::OutResult::Error;
+                }
+                for (b, p) in cbw.iter_mut().zip(packet.iter()) {
+                    *b = p.get();
+                }
+                match CommandBlockWrapper::parse(&cbw) {
+                    Some(cbw) => self.start_command(cbw),
+                    None => {
+                        self.state.set(State::Stalled);
+                        return hil::usb::OutResult::Error;
+                    }
+                }
+                // Only accept more data if the command has a data phase
+                match self.state.get() {
+                    State::DataOut => hil::usb::OutResult::Ok,
+                    _ => hil::usb::OutResult::Delay,
+                }
+            }
+            State::DataOut => {
+                let offset = self.buf_offset.get();
+                let to_copy = cmp::min(packet_bytes, self.buf_len.get() - offset);
+                self.buffer.map(|buf| {
+                    for i in 0..to_copy {
+                        buf[offset + i] = packet[i].get();
+                    }
+                });
+                self.buf_offset.set(offset + to_copy);
+                self.moved.set(self.moved.get() + packet_bytes as u32);
+                if packet_bytes < packet.len() || self.moved.get() >= self.host_length() {
+                    self.phase_done.set(true);
+                }
+
+                if self.buf_len.get() > 0 && self.buf_offset.get() == self.buf_len.get() {
+                    // A full chunk of blocks, write it out
+                    let res = self.buffer.take().map(|buf| {
+                        let address = self.lba.get() as usize * BLOCK_SIZE;
+                        self.state.set(State::Storage);
+                        self.storage
+                            .write(buf, address, self.buf_len.get())
+                            .map_err(|_| ())
+                    });
+                    if res != Some(Ok(())) {
+                        self.fail(SenseData::WRITE_ERROR);
+                    }
+                } else if self.phase_done.get() {
+                    if self.blocks.get() > 0 {
+                        // The host sent fewer blocks than the command said
+                        self.status.set(CommandStatus::PhaseError);
+                    }
+                    self.send_status();
+                }
+                match self.state.get() {
+                    State::DataOut => hil::usb::OutResult::Ok,
+                    _ => hil::usb::OutResult::Delay,
+                }
+            }
+            State::Stalled => hil::usb::OutResult::Error,
+            _ => hil::usb::OutResult::Delay,
+        }
+    }
+
+    /// Takes back the buffer from the storage. Returns false if the host
+    /// reset us in the meantime.
+    fn storage_done(&self, buffer: &'static mut [u8]) -> bool {
+        self.buffer.replace(buffer);
+        if self.state.get() == State::StorageReset {
+            self.state.set(State::Command);
+            self.resume_out();
+            false
+        } else {
+            true
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: NonvolatileStorage<'static>> hil::usb::Client<'a>
+    for MassStorage<'a, U, S>
+{
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
+
+        self.controller()
+            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, self.buffer(ENDPOINT_OUT_NUM));
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.client_ctrl.bus_reset();
+        // The controller forgets about delayed packets
+        self.out_delayed.set(false);
+        self.reset();
+    }
+
+    fn suspend(&'a self) {
+        // The controller holds back our transfers until the bus resumes.
+    }
+
+    fn resume(&'a self) {}
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let class_request =
+            descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).filter(|setup_data| {
+                match setup_data.request_type.request_type() {
+                    RequestType::Class => true,
+                    _ => false,
+                }
+            });
+        match class_request.map(|setup_data| setup_data.request_code) {
+            Some(GET_MAX_LUN) => {
+                self.ctrl_state.set(CtrlState::GetMaxLun);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            Some(BULK_ONLY_RESET) => {
+                self.reset();
+                hil::usb::CtrlSetupResult::Ok
+            }
+            Some(_) => hil::usb::CtrlSetupResult::ErrGeneric,
+            None => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.get() {
+            CtrlState::GetMaxLun => {
+                // We have a single logical unit, number 0
+                self.client_ctrl.ctrl_buffer.buf[0].set(0);
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            CtrlState::Idle => self.client_ctrl.ctrl_in(endpoint),
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.ctrl_state.set(CtrlState::Idle);
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk IN transaction: data or status going to the host.
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => match self.state.get() {
+                State::DataIn => {
+                    let packet = self.buffer(endpoint);
+                    let offset = self.buf_offset.get();
+                    let to_send = cmp::min(packet.len(), self.buf_len.get() - offset);
+                    if to_send == 0 && self.phase_done.get() {
+                        return hil::usb::InResult::Delay;
+                    }
+                    self.buffer.map(|buf| {
+                        for i in 0..to_send {
+                            packet[i].set(buf[offset + i]);
+                        }
+                    });
+                    self.buf_offset.set(offset + to_send);
+                    self.transferred
+                        .set(self.transferred.get() + to_send as u32);
+                    self.moved.set(self.moved.get() + to_send as u32);
+                    // A short packet ends the data phase for the host
+                    if to_send < packet.len() || self.moved.get() >= self.host_length() {
+                        self.phase_done.set(true);
+                    }
+                    hil::usb::InResult::Packet(to_send)
+                }
+                State::Status => {
+                    let mut csw = [0; scsi::CSW_LEN];
+                    let (tag, host_length) = self
+                        .cbw
+                        .map_or((0, 0), |cbw| (cbw.tag, cbw.data_transfer_length));
+                    scsi::encode_csw(
+                        &mut csw,
+                        tag,
+                        host_length.saturating_sub(self.transferred.get()),
+                        self.status.get(),
+                    );
+                    let packet = self.buffer(endpoint);
+                    for (p, b) in packet.iter().zip(csw.iter()) {
+                        p.set(*b);
+                    }
+                    self.state.set(State::StatusSent);
+                    hil::usb::InResult::Packet(scsi::CSW_LEN)
+                }
+                State::Stalled => hil::usb::InResult::Error,
+                _ => hil::usb::InResult::Delay,
+            },
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                hil::usb::InResult::Delay
+            }
+        }
+    }
+
+    /// Handle a Bulk OUT transaction: a command or data from the host.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {}
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                return hil::usb::OutResult::Ok;
+            }
+        }
+        let result = self.handle_packet_out(endpoint, packet_bytes as usize);
+        if let hil::usb::OutResult::Delay = result {
+            self.out_delayed.set(true);
+        }
+        result
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        match self.state.get() {
+            State::DataIn => {
+                if self.buf_offset.get() < self.buf_len.get() {
+                    self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                } else if self.blocks.get() > 0 && !self.phase_done.get() {
+                    self.read_blocks();
+                } else if !self.phase_done.get() {
+                    // End the data phase with a zero length packet
+                    self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                } else {
+                    self.send_status();
+                }
+            }
+            State::StatusSent => {
+                self.cbw.clear();
+                self.state.set(State::Command);
+                self.resume_out();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, S: NonvolatileStorage<'static>>
+    NonvolatileStorageClient<'static> for MassStorage<'a, U, S>
+{
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        if !self.storage_done(buffer) {
+            return;
+        }
+        let blocks = (length / BLOCK_SIZE) as u32;
+        self.lba.set(self.lba.get() + blocks);
+        self.blocks.set(self.blocks.get().saturating_sub(blocks));
+        self.buf_offset.set(0);
+        self.buf_len.set(length);
+        self.state.set(State::DataIn);
+        self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        if !self.storage_done(buffer) {
+            return;
+        }
+        let blocks = (length / BLOCK_SIZE) as u32;
+        self.lba.set(self.lba.get() + blocks);
+        self.blocks.set(self.blocks.get().saturating_sub(blocks));
+        self.transferred.set(self.transferred.get() + length as u32);
+        if self.blocks.get() > 0 && !self.phase_done.get() {
+            self.receive_blocks();
+        } else {
+            self.finish_data_phase();
+        }
+    }
+}
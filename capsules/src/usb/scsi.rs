@@ -0,0 +1,407 @@
+//! Bulk-Only Transport wrappers and the SCSI commands used by USB mass
+//! storage devices.
+//!
+//! This module only parses and encodes; `msc.rs` moves the data. Everything
+//! here works on plain byte slices so it can be tested on the host.
+//!
+//! References:
+//! - USB Mass Storage Class Bulk-Only Transport, Revision 1.0
+//! - SCSI Primary Commands (SPC-2) and SCSI Block Commands (SBC-2)
+
+/// Length of a Command Block Wrapper
+pub const CBW_LEN: usize = 31;
+/// Length of a Command Status Wrapper
+pub const CSW_LEN: usize = 13;
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+/// Largest command block a CBW can carry
+pub const MAX_CB_LEN: usize = 16;
+
+/// Logical block size we present to the host
+pub const BLOCK_SIZE: usize = 512;
+
+pub const INQUIRY_LEN: usize = 36;
+pub const SENSE_LEN: usize = 18;
+pub const READ_CAPACITY_LEN: usize = 8;
+pub const MODE_SENSE_LEN: usize = 4;
+
+mod opcode {
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const REQUEST_SENSE: u8 = 0x03;
+    pub const INQUIRY: u8 = 0x12;
+    pub const MODE_SENSE_6: u8 = 0x1a;
+    pub const START_STOP_UNIT: u8 = 0x1b;
+    pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+    pub const WRITE_10: u8 = 0x2a;
+}
+
+/// The command block sent by the host at the start of every command.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CommandBlockWrapper {
+    pub tag: u32,
+    /// Number of bytes the host expects to transfer in the data phase
+    pub data_transfer_length: u32,
+    /// The data phase is device to host
+    pub data_in: bool,
+    pub lun: u8,
+    pub cb_len: u8,
+    pub cb: [u8; MAX_CB_LEN],
+}
+
+impl CommandBlockWrapper {
+    /// Parses a CBW. Returns None if `buf` is not a valid and meaningful
+    /// CBW, in which case the device must stall until reset.
+    pub fn parse(buf: &[u8]) -> Option<CommandBlockWrapper> {
+        if buf.len() != CBW_LEN || get_u32_le(&buf[0..4]) != CBW_SIGNATURE {
+            return None;
+        }
+        let cb_len = buf[14] & 0x1f;
+        if cb_len == 0 || cb_len as usize > MAX_CB_LEN || buf[13] & 0xf0 != 0 {
+            return None;
+        }
+        let mut cb = [0; MAX_CB_LEN];
+        cb.copy_from_slice(&buf[15..31]);
+        Some(CommandBlockWrapper {
+            tag: get_u32_le(&buf[4..8]),
+            data_transfer_length: get_u32_le(&buf[8..12]),
+            data_in: buf[12] & 0x80 != 0,
+            lun: buf[13] & 0x0f,
+            cb_len,
+            cb,
+        })
+    }
+
+    pub fn command_block(&self) -> &[u8] {
+        &self.cb[..self.cb_len as usize]
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CommandStatus {
+    Passed = 0,
+    Failed = 1,
+    PhaseError = 2,
+}
+
+/// Writes the status of the command with `tag` into `buf`, which must hold
+/// CSW_LEN bytes. `residue` is the number of bytes of the data phase that
+/// were not used.
+pub fn encode_csw(buf: &mut [u8], tag: u32, residue: u32, status: CommandStatus) {
+    put_u32_le(&mut buf[0..4], CSW_SIGNATURE);
+    put_u32_le(&mut buf[4..8], tag);
+    put_u32_le(&mut buf[8..12], residue);
+    buf[12] = status as u8;
+}
+
+/// The SCSI commands we implement. Anything else is answered with
+/// ILLEGAL REQUEST.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScsiCommand {
+    TestUnitReady,
+    RequestSense {
+        alloc_len: u8,
+    },
+    Inquiry {
+        alloc_len: u16,
+    },
+    ModeSense6 {
+        alloc_len: u8,
+    },
+    /// START STOP UNIT and PREVENT ALLOW MEDIUM REMOVAL, which we accept
+    /// without doing anything
+    Ignored,
+    ReadCapacity10,
+    Read10 {
+        lba: u32,
+        blocks: u16,
+    },
+    Write10 {
+        lba: u32,
+        blocks: u16,
+    },
+}
+
+impl ScsiCommand {
+    /// Parses a command block. Unsupported or truncated commands return the
+    /// sense data to report.
+    pub fn parse(cb: &[u8]) -> Result<ScsiCommand, SenseData> {
+        let need = |len: usize| {
+            if cb.len() < len {
+                Err(SenseData::INVALID_FIELD)
+            } else {
+                Ok(())
+            }
+        };
+        match cb.first().copied() {
+            Some(opcode::TEST_UNIT_READY) => Ok(ScsiCommand::TestUnitReady),
+            Some(opcode::REQUEST_SENSE) => {
+                need(6)?;
+                Ok(ScsiCommand::RequestSense { alloc_len: cb[4] })
+            }
+            Some(opcode::INQUIRY) => {
+                need(6)?;
+                if cb[1] & 0x01 != 0 {
+                    // Vital product data pages are not supported
+                    return Err(SenseData::INVALID_FIELD);
+                }
+                Ok(ScsiCommand::Inquiry {
+                    alloc_len: get_u16_be(&cb[3..5]),
+                })
+            }
+            Some(opcode::MODE_SENSE_6) => {
+                need(6)?;
+                Ok(ScsiCommand::ModeSense6 { alloc_len: cb[4] })
+            }
+            Some(opcode::START_STOP_UNIT) | Some(opcode::PREVENT_ALLOW_MEDIUM_REMOVAL) => {
+                Ok(ScsiCommand::Ignored)
+            }
+            Some(opcode::READ_CAPACITY_10) => {
+                need(10)?;
+                Ok(ScsiCommand::ReadCapacity10)
+            }
+            Some(opcode::READ_10) | Some(opcode::WRITE_10) => {
+                need(10)?;
+                let lba = get_u32_be(&cb[2..6]);
+                let blocks = get_u16_be(&cb[7..9]);
+                if cb[0] == opcode::READ_10 {
+                    Ok(ScsiCommand::Read10 { lba, blocks })
+                } else {
+                    Ok(ScsiCommand::Write10 { lba, blocks })
+                }
+            }
+            _ => Err(SenseData::INVALID_COMMAND),
+        }
+    }
+
+    /// Direction and number of bytes of the data phase this command needs.
+    /// The bool is true for device to host.
+    pub fn data_phase(&self) -> (bool, u32) {
+        match *self {
+            ScsiCommand::RequestSense { alloc_len } => {
+                (true, core::cmp::min(alloc_len as usize, SENSE_LEN) as u32)
+            }
+            ScsiCommand::Inquiry { alloc_len } => {
+                (true, core::cmp::min(alloc_len as usize, INQUIRY_LEN) as u32)
+            }
+            ScsiCommand::ModeSense6 { alloc_len } => (
+                true,
+                core::cmp::min(alloc_len as usize, MODE_SENSE_LEN) as u32,
+            ),
+            ScsiCommand::ReadCapacity10 => (true, READ_CAPACITY_LEN as u32),
+            ScsiCommand::Read10 { blocks, .. } => (true, blocks as u32 * BLOCK_SIZE as u32),
+            ScsiCommand::Write10 { blocks, .. } => (false, blocks as u32 * BLOCK_SIZE as u32),
+            ScsiCommand::TestUnitReady | ScsiCommand::Ignored => (false, 0),
+        }
+    }
+}
+
+/// Fixed format sense data returned by REQUEST SENSE after a failed command.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SenseData {
+    pub key: u8,
+    /// Additional sense code and qualifier
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+impl SenseData {
+    pub const NO_SENSE: SenseData = SenseData::new(0x00, 0x00, 0x00);
+    pub const INVALID_COMMAND: SenseData = SenseData::new(0x05, 0x20, 0x00);
+    pub const LBA_OUT_OF_RANGE: SenseData = SenseData::new(0x05, 0x21, 0x00);
+    pub const INVALID_FIELD: SenseData = SenseData::new(0x05, 0x24, 0x00);
+    pub const READ_ERROR: SenseData = SenseData::new(0x03, 0x11, 0x00);
+    pub const WRITE_ERROR: SenseData = SenseData::new(0x03, 0x0c, 0x00);
+    pub const WRITE_PROTECTED: SenseData = SenseData::new(0x07, 0x27, 0x00);
+
+    pub const fn new(key: u8, asc: u8, ascq: u8) -> SenseData {
+        SenseData { key, asc, ascq }
+    }
+
+    /// Writes SENSE_LEN bytes into `buf`.
+    pub fn encode(&self, buf: &mut [u8]) {
+        for b in buf[..SENSE_LEN].iter_mut() {
+            *b = 0;
+        }
+        buf[0] = 0x70; // Current error, fixed format
+        buf[2] = self.key;
+        buf[7] = (SENSE_LEN - 8) as u8;
+        buf[12] = self.asc;
+        buf[13] = self.ascq;
+    }
+}
+
+/// Writes the INQUIRY response for a removable direct access device into
+/// `buf`, which must hold INQUIRY_LEN bytes. `vendor` and `product` are
+/// truncated to 8 and 16 characters and padded with spaces.
+pub fn encode_inquiry(buf: &mut [u8], vendor: &str, product: &str) {
+    for b in buf[..INQUIRY_LEN].iter_mut() {
+        *b = b' ';
+    }
+    buf[0] = 0x00; // Direct access block device
+    buf[1] = 0x80; // Removable
+    buf[2] = 0x04; // SPC-2
+    buf[3] = 0x02; // Response data format
+    buf[4] = (INQUIRY_LEN - 5) as u8;
+    buf[5] = 0;
+    buf[6] = 0;
+    buf[7] = 0;
+    let copy = |dst: &mut [u8], s: &str| {
+        for (d, c) in dst.iter_mut().zip(s.bytes()) {
+            *d = c;
+        }
+    };
+    copy(&mut buf[8..16], vendor);
+    copy(&mut buf[16..32], product);
+    copy(&mut buf[32..36], "1.0");
+}
+
+/// Writes the READ CAPACITY(10) response for a device with `blocks` blocks.
+pub fn encode_read_capacity(buf: &mut [u8], blocks: u32) {
+    put_u32_be(&mut buf[0..4], blocks.saturating_sub(1));
+    put_u32_be(&mut buf[4..8], BLOCK_SIZE as u32);
+}
+
+/// Writes the MODE SENSE(6) response: a header without block descriptors
+/// or pages.
+pub fn encode_mode_sense(buf: &mut [u8], write_protected: bool) {
+    buf[0] = (MODE_SENSE_LEN - 1) as u8;
+    buf[1] = 0;
+    buf[2] = if write_protected { 0x80 } else { 0 };
+    buf[3] = 0;
+}
+
+fn get_u16_be(b: &[u8]) -> u16 {
+    (b[0] as u16) << 8 | b[1] as u16
+}
+
+fn get_u32_be(b: &[u8]) -> u32 {
+    (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+}
+
+fn put_u32_be(b: &mut [u8], v: u32) {
+    b[0] = (v >> 24) as u8;
+    b[1] = (v >> 16) as u8;
+    b[2] = (v >> 8) as u8;
+    b[3] = v as u8;
+}
+
+fn get_u32_le(b: &[u8]) -> u32 {
+    (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32
+}
+
+fn put_u32_le(b: &mut [u8], v: u32) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+    b[2] = (v >> 16) as u8;
+    b[3] = (v >> 24) as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// READ(10) of 8 blocks at LBA 0x1234, as sent by Linux
+    const READ_CBW: [u8; CBW_LEN] = [
+        0x55, 0x53, 0x42, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x80, 0x00, 0x0a,
+        0x28, 0x00, 0x00, 0x00, 0x12, 0x34, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+
+    #[test]
+    fn parses_read_cbw() {
+        let cbw = CommandBlockWrapper::parse(&READ_CBW).unwrap();
+        assert_eq!(cbw.tag, 1);
+        assert_eq!(cbw.data_transfer_length, 4096);
+        assert!(cbw.data_in);
+        assert_eq!(cbw.lun, 0);
+        let cmd = ScsiCommand::parse(cbw.command_block()).unwrap();
+        assert_eq!(
+            cmd,
+            ScsiCommand::Read10 {
+                lba: 0x1234,
+                blocks: 8
+            }
+        );
+        assert_eq!(cmd.data_phase(), (true, 4096));
+    }
+
+    #[test]
+    fn rejects_invalid_cbw() {
+        assert_eq!(CommandBlockWrapper::parse(&READ_CBW[..30]), None);
+        let mut bad = READ_CBW;
+        bad[0] = 0;
+        assert_eq!(CommandBlockWrapper::parse(&bad), None);
+        let mut bad = READ_CBW;
+        bad[14] = 0;
+        assert_eq!(CommandBlockWrapper::parse(&bad), None);
+        let mut bad = READ_CBW;
+        bad[14] = 17;
+        assert_eq!(CommandBlockWrapper::parse(&bad), None);
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            ScsiCommand::parse(&[0x00, 0, 0, 0, 0, 0]),
+            Ok(ScsiCommand::TestUnitReady)
+        );
+        let inquiry = ScsiCommand::parse(&[0x12, 0, 0, 0, 0x24, 0]).unwrap();
+        assert_eq!(inquiry, ScsiCommand::Inquiry { alloc_len: 36 });
+        assert_eq!(inquiry.data_phase(), (true, 36));
+        // Windows asks for more than the standard INQUIRY data
+        assert_eq!(
+            ScsiCommand::Inquiry { alloc_len: 255 }.data_phase(),
+            (true, INQUIRY_LEN as u32)
+        );
+        assert_eq!(
+            ScsiCommand::parse(&[0x12, 1, 0x80, 0, 0xff, 0]),
+            Err(SenseData::INVALID_FIELD)
+        );
+        assert_eq!(
+            ScsiCommand::parse(&[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Ok(ScsiCommand::ReadCapacity10)
+        );
+        let write = ScsiCommand::parse(&[0x2a, 0, 0, 0, 0, 2, 0, 0, 1, 0]).unwrap();
+        assert_eq!(write, ScsiCommand::Write10 { lba: 2, blocks: 1 });
+        assert_eq!(write.data_phase(), (false, 512));
+        assert_eq!(
+            ScsiCommand::parse(&[0x28, 0, 0, 0]),
+            Err(SenseData::INVALID_FIELD)
+        );
+        assert_eq!(
+            ScsiCommand::parse(&[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(SenseData::INVALID_COMMAND)
+        );
+        assert_eq!(ScsiCommand::parse(&[]), Err(SenseData::INVALID_COMMAND));
+    }
+
+    #[test]
+    fn encodes_responses() {
+        let mut csw = [0; CSW_LEN];
+        encode_csw(&mut csw, 0xdeadbeef, 512, CommandStatus::Failed);
+        assert_eq!(
+            csw,
+            [0x55, 0x53, 0x42, 0x53, 0xef, 0xbe, 0xad, 0xde, 0x00, 0x02, 0x00, 0x00, 0x01]
+        );
+
+        let mut cap = [0; READ_CAPACITY_LEN];
+        encode_read_capacity(&mut cap, 0x10000);
+        assert_eq!(cap, [0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x02, 0x00]);
+
+        let mut sense = [0xff; SENSE_LEN];
+        SenseData::LBA_OUT_OF_RANGE.encode(&mut sense);
+        assert_eq!(sense[0], 0x70);
+        assert_eq!(sense[2], 0x05);
+        assert_eq!(sense[7], 10);
+        assert_eq!((sense[12], sense[13]), (0x21, 0x00));
+
+        let mut inquiry = [0; INQUIRY_LEN];
+        encode_inquiry(&mut inquiry, "Tock", "Log storage device");
+        assert_eq!(&inquiry[..5], &[0x00, 0x80, 0x04, 0x02, 31]);
+        assert_eq!(&inquiry[8..16], b"Tock    ");
+        assert_eq!(&inquiry[16..32], b"Log storage devi");
+        assert_eq!(&inquiry[32..36], b"1.0 ");
+    }
+}
@@ -161,6 +161,16 @@ impl<'a, C: hil::usb::UsbController<'a>> hil::usb::Client<'a> for Client<'a, C>
         // Reset the state for our pair of debugging endpoints
         self.echo_len.set(0);
         self.delayed_out.set(false);
+
+        self.client_ctrl.bus_reset();
+    }
+
+    fn suspend(&'a self) {
+        debug!("Suspend");
+    }
+
+    fn resume(&'a self) {
+        debug!("Resume");
     }
 
     /// Handle a Control Setup transaction
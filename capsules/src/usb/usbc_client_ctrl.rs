@@ -17,10 +17,12 @@
 //! ```
 
 use super::descriptors::Buffer64;
+use super::descriptors::ConfigurationAttributes;
 use super::descriptors::Descriptor;
 use super::descriptors::DescriptorBuffer;
 use super::descriptors::DescriptorType;
 use super::descriptors::DeviceBuffer;
+use super::descriptors::FeatureSelector;
 use super::descriptors::HIDDescriptor;
 use super::descriptors::LanguagesDescriptor;
 use super::descriptors::Recipient;
@@ -33,6 +35,7 @@ use core::cell::Cell;
 use core::cmp::min;
 use kernel::hil;
 use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
 
 const DESCRIPTOR_BUFLEN: usize = 128;
 
@@ -76,6 +79,9 @@ pub struct ClientCtrl<'a, 'b, U: 'a> {
 
     /// USB strings to provide human readable descriptions of certain descriptor attributes.
     strings: &'b [&'b str],
+
+    /// Whether the host allowed us to wake it up from suspend.
+    remote_wakeup_enabled: Cell<bool>,
 }
 
 /// States for the individual endpoints.
@@ -157,9 +163,16 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
             report_descriptor,
             language,
             strings,
+            remote_wakeup_enabled: Cell::new(false),
         }
     }
 
+    /// The configuration descriptor served to the host, for clients whose
+    /// descriptors change after `new`.
+    pub fn configuration_descriptor(&self) -> &DescriptorBuffer {
+        &self.other_descriptor_buffer
+    }
+
     #[inline]
     pub fn controller(&self) -> &'a U {
         self.controller
@@ -184,6 +197,25 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
         self.controller.attach();
     }
 
+    /// A bus reset disables remote wakeup until the host enables it again.
+    pub fn bus_reset(&'a self) {
+        self.remote_wakeup_enabled.set(false);
+    }
+
+    /// Wake up the host from suspend, if it enabled remote wakeup.
+    pub fn remote_wakeup(&'a self) -> Result<(), ErrorCode> {
+        if !self.remote_wakeup_enabled.get() {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        self.controller.remote_wakeup()
+    }
+
+    /// The attributes of the configuration descriptor, which comes first in
+    /// `other_descriptor_buffer`.
+    fn configuration_attributes(&self) -> ConfigurationAttributes {
+        ConfigurationAttributes::from(self.other_descriptor_buffer.buf[7].get())
+    }
+
     /// Handle a Control Setup transaction
     pub fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
         if endpoint != 0 {
@@ -317,6 +349,39 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
                 // We have been assigned a particular configuration: fine!
                 hil::usb::CtrlSetupResult::Ok
             }
+            StandardRequest::GetStatus { .. } => {
+                let attributes = self.configuration_attributes();
+                let buf = self.descriptor_buf();
+                buf[0].set(
+                    if attributes.is_self_powered() { 1 } else { 0 }
+                        | if self.remote_wakeup_enabled.get() {
+                            1 << 1
+                        } else {
+                            0
+                        },
+                );
+                buf[1].set(0);
+                self.state[endpoint].set(State::CtrlIn(0, 2));
+                hil::usb::CtrlSetupResult::Ok
+            }
+            StandardRequest::SetFeature {
+                feature: FeatureSelector::DeviceRemoteWakeup,
+                ..
+            } => {
+                if self.configuration_attributes().supports_remote_wakeup() {
+                    self.remote_wakeup_enabled.set(true);
+                    hil::usb::CtrlSetupResult::Ok
+                } else {
+                    hil::usb::CtrlSetupResult::ErrGeneric
+                }
+            }
+            StandardRequest::ClearFeature {
+                feature: FeatureSelector::DeviceRemoteWakeup,
+                ..
+            } => {
+                self.remote_wakeup_enabled.set(false);
+                hil::usb::CtrlSetupResult::Ok
+            }
             _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
         }
     }
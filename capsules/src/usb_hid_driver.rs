@@ -0,0 +1,329 @@
+//! Provides userspace with access to a USB HID device, so that an app can act
+//! as e.g. a keyboard or a mouse.
+//!
+//! The first app that uses the driver owns the device. It sends input reports
+//! and receives output reports of the sizes given by the report descriptor.
+//! If the board did not attach the device, the app can replace the report
+//! descriptor and then attach it.
+//!
+//! Setup
+//! -----
+//!
+//! ```rust
+//!     let hid_send_buffer = static_init!([u8; 64], [0; 64]);
+//!     let hid_recv_buffer = static_init!([u8; 64], [0; 64]);
+//!
+//!     let (hid, hid_driver) = components::usb_hid::UsbHidComponent::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x521a,
+//!         strings,
+//!         capsules::usb::hid::KEYBOARD,
+//!         board_kernel,
+//!         hid_send_buffer,
+//!         hid_recv_buffer,
+//!     )
+//!     .finalize(components::usb_hid_component_helper!(nrf52840::usbd::Usbd<'static>));
+//!
+//!     hid.enable();
+//!     hid.attach();
+//! ```
+
+use core::cmp;
+use core::mem;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::usb_hid::{self, UsbHid};
+use kernel::{
+    CommandReturn, Driver, ErrorCode, Grant, ProcessId, Read, ReadOnlyAppSlice, ReadWrite,
+    ReadWriteAppSlice, Upcall,
+};
+
+use crate::usb::hid::{BootProtocol, Hid};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::UsbHid as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Upcall,
+    recv_buf: ReadWriteAppSlice,
+    send_buf: ReadOnlyAppSlice,
+    report_descriptor: ReadOnlyAppSlice,
+    receiving: bool,
+}
+
+pub struct UsbHidDriver<'a, U: hil::usb::UsbController<'a>> {
+    hid: &'a Hid<'a, U>,
+
+    app: Grant<App>,
+    appid: OptionalCell<ProcessId>,
+
+    send_buffer: TakeCell<'static, [u8; 64]>,
+    recv_buffer: TakeCell<'static, [u8; 64]>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbHidDriver<'a, U> {
+    pub fn new(
+        hid: &'a Hid<'a, U>,
+        send_buffer: &'static mut [u8; 64],
+        recv_buffer: &'static mut [u8; 64],
+        grant: Grant<App>,
+    ) -> UsbHidDriver<'a, U> {
+        UsbHidDriver {
+            hid,
+            app: grant,
+            appid: OptionalCell::empty(),
+            send_buffer: TakeCell::new(send_buffer),
+            recv_buffer: TakeCell::new(recv_buffer),
+        }
+    }
+
+    fn send(&self, app: &mut App) -> Result<(), ErrorCode> {
+        let len = self.hid.input_size();
+        let buf = self.send_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let copied = app.send_buf.map_or(false, |data| {
+            let data = data.as_ref();
+            if data.len() < len {
+                return false;
+            }
+            buf[..len].copy_from_slice(&data[..len]);
+            true
+        });
+        if !copied {
+            self.send_buffer.replace(buf);
+            return Err(ErrorCode::SIZE);
+        }
+        self.hid.send_buffer(buf).map(|_| ()).map_err(|(err, buf)| {
+            self.send_buffer.replace(buf);
+            err
+        })
+    }
+
+    fn receive(&self, app: &mut App) -> Result<(), ErrorCode> {
+        app.receiving = true;
+        match self.recv_buffer.take() {
+            // We are already waiting for a report
+            None => Ok(()),
+            Some(buf) => self.hid.receive_buffer(buf).map_err(|(err, buf)| {
+                self.recv_buffer.replace(buf);
+                app.receiving = false;
+                err
+            }),
+        }
+    }
+
+    fn set_reports(&self, app: &mut App, input_size: usize, arg2: usize) -> Result<(), ErrorCode> {
+        let output_size = arg2 & 0xff;
+        let boot_protocol = match arg2 >> 8 {
+            0 => BootProtocol::None,
+            1 => BootProtocol::Keyboard,
+            2 => BootProtocol::Mouse,
+            _ => return Err(ErrorCode::INVAL),
+        };
+        app.report_descriptor
+            .map_or(Err(ErrorCode::INVAL), |descriptor| {
+                self.hid
+                    .set_reports(descriptor.as_ref(), boot_protocol, input_size, output_size)
+            })
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> usb_hid::Client<'a, [u8; 64]> for UsbHidDriver<'a, U> {
+    fn packet_received(
+        &'a self,
+        _result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 64],
+        _endpoint: usize,
+    ) {
+        let len = self.hid.output_size();
+        self.appid.map(|id| {
+            let _ = self.app.enter(*id, |app| {
+                let len = app.recv_buf.mut_map_or(0, |dest| {
+                    let len = cmp::min(len, dest.len());
+                    dest.as_mut()[..len].copy_from_slice(&buffer[..len]);
+                    len
+                });
+                app.receiving = false;
+                app.callback.schedule(0, len, 0);
+            });
+        });
+
+        self.recv_buffer.replace(buffer);
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        _result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 64],
+        _endpoint: usize,
+    ) {
+        let len = self.hid.input_size();
+        self.appid.map(|id| {
+            let _ = self.app.enter(*id, |app| {
+                app.callback.schedule(1, len, 0);
+            });
+        });
+
+        // Save our send buffer so we can use it later
+        self.send_buffer.replace(buffer);
+    }
+
+    fn can_receive(&'a self) -> bool {
+        self.appid
+            .map(|id| self.app.enter(*id, |app| app.receiving).unwrap_or(false))
+            .unwrap_or(false)
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Driver for UsbHidDriver<'a, U> {
+    /// Share a buffer with the driver.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer for received output reports
+    fn allow_readwrite(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadWriteAppSlice,
+    ) -> Result<ReadWriteAppSlice, (ReadWriteAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .app
+                .enter(appid, |app| mem::swap(&mut slice, &mut app.recv_buf))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Share a read-only buffer with the driver.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Input report to send
+    /// - `1`: Report descriptor for the `set report descriptor` command
+    fn allow_readonly(
+        &self,
+        appid: ProcessId,
+        allow_num: usize,
+        mut slice: ReadOnlyAppSlice,
+    ) -> Result<ReadOnlyAppSlice, (ReadOnlyAppSlice, ErrorCode)> {
+        let res = match allow_num {
+            0 => self
+                .app
+                .enter(appid, |app| mem::swap(&mut slice, &mut app.send_buf))
+                .map_err(ErrorCode::from),
+            1 => self
+                .app
+                .enter(appid, |app| {
+                    mem::swap(&mut slice, &mut app.report_descriptor)
+                })
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(slice),
+            Err(e) => Err((slice, e)),
+        }
+    }
+
+    /// Subscribe to HID events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Subscribe to reports. The callback signature is
+    ///        `fn(direction: u32, len: u32)`
+    ///        `fn(0, len)` indicates an output report was received
+    ///        `fn(1, len)` indicates an input report was sent
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        mut callback: Upcall,
+        appid: ProcessId,
+    ) -> Result<Upcall, (Upcall, ErrorCode)> {
+        let res = match subscribe_num {
+            0 => self
+                .app
+                .enter(appid, |app| mem::swap(&mut app.callback, &mut callback))
+                .map_err(ErrorCode::from),
+            _ => Err(ErrorCode::NOSUPPORT),
+        };
+
+        match res {
+            Ok(()) => Ok(callback),
+            Err(e) => Err((callback, e)),
+        }
+    }
+
+    /// Control the HID device.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Send the input report in the read-only buffer 0.
+    /// - `2`: Receive an output report into the read-write buffer 0.
+    /// - `3`: Cancel receiving.
+    /// - `4`: Set the report descriptor from the read-only buffer 1. `arg1` is
+    ///        the size of the input reports, `arg2` is the size of the output
+    ///        reports, plus the boot protocol (0 none, 1 keyboard, 2 mouse)
+    ///        shifted left by 8. Fails with `BUSY` once the device is
+    ///        attached.
+    /// - `5`: Attach the device to the bus.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        arg2: usize,
+        appid: ProcessId,
+    ) -> CommandReturn {
+        if command_num == 0 {
+            return CommandReturn::success();
+        }
+
+        let can_access = self.appid.map_or(true, |owning_app| owning_app == &appid);
+        if !can_access {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+
+        let res = self
+            .app
+            .enter(appid, |app| {
+                self.appid.set(appid);
+                match command_num {
+                    1 => self.send(app),
+                    2 => self.receive(app),
+                    3 => {
+                        app.receiving = false;
+                        self.hid.receive_cancel().map(|buf| {
+                            self.recv_buffer.replace(buf);
+                        })
+                    }
+                    4 => self.set_reports(app, arg1, arg2),
+                    5 => {
+                        if self.hid.is_attached() {
+                            Err(ErrorCode::ALREADY)
+                        } else {
+                            hil::usb::Client::attach(self.hid);
+                            Ok(())
+                        }
+                    }
+                    _ => Err(ErrorCode::NOSUPPORT),
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+
+        match res {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => CommandReturn::failure(e),
+        }
+    }
+}
@@ -415,7 +415,7 @@ impl<'a> Ble<'a> {
                         i = i + 4;
                     }
 
-                    client.receive_event(&mut PAYLOAD, 10, Ok(()));
+                    client.receive_event(&mut PAYLOAD, 10, None, Ok(()));
                 }
             });
         }
@@ -430,9 +430,10 @@ impl<'a> Ble<'a> {
         self.registers.inten.set(0x00);
     }
 
-    fn replace_radio_buffer(&self, buf: &'static mut [u8]) -> &'static mut [u8] {
+    fn replace_radio_buffer(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8] {
         // set payload
-        for (i, c) in buf.as_ref().iter().enumerate() {
+        let len = core::cmp::min(len, unsafe { PAYLOAD.len() });
+        for (i, c) in buf[..len].iter().enumerate() {
             unsafe {
                 PAYLOAD[i] = *c;
             }
@@ -443,7 +444,7 @@ impl<'a> Ble<'a> {
 
 impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Ble<'a> {
     fn transmit_advertisement(&self, buf: &'static mut [u8], len: usize, _channel: RadioChannel) {
-        let res = self.replace_radio_buffer(buf);
+        let res = self.replace_radio_buffer(buf, len);
 
         // Setup all of the buffers
         self.buffer.replace(res);
@@ -476,8 +477,34 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Ble<'a> {
     }
 }
 
+impl<'a> ble_advertising::BleExtendedAdvertisementDriver<'a> for Ble<'a> {
+    fn aux_offset_us(&self) -> Option<u32> {
+        None
+    }
+
+    fn transmit_extended_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        _primary_len: usize,
+        _aux_len: usize,
+        _channel: RadioChannel,
+        _aux_channel: RadioChannel,
+    ) {
+        // The BLE controller of the Apollo3 only sends legacy advertisements
+        self.tx_client
+            .map(move |client| client.transmit_event(buf, Err(ErrorCode::NOSUPPORT)));
+    }
+}
+
 impl ble_advertising::BleConfig for Ble<'_> {
     fn set_tx_power(&self, _tx_power: u8) -> Result<(), ErrorCode> {
         Ok(())
     }
+
+    fn set_phy(&self, phy: ble_advertising::Phy) -> Result<(), ErrorCode> {
+        match phy {
+            ble_advertising::Phy::Le1M => Ok(()),
+            _ => Err(ErrorCode::NOSUPPORT),
+        }
+    }
 }
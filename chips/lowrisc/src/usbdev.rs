@@ -8,6 +8,7 @@ use kernel::common::registers::{
 use kernel::common::StaticRef;
 use kernel::hil;
 use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
 
 pub const N_ENDPOINTS: usize = 12;
 pub const N_BUFFERS: usize = 32;
@@ -1160,4 +1161,8 @@ impl<'a> hil::usb::UsbController<'a> for Usb<'a> {
             .rxenable_out
             .set(1 << endpoint | self.registers.rxenable_out.get());
     }
+
+    fn remote_wakeup(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
 }
@@ -32,6 +32,20 @@
 //! * Payload - 2 to 255 bytes
 //!
 //! * CRC - 3 bytes
+//!
+//! ### Extended Advertising
+//!
+//! The auxiliary PDU of an extended advertisement must start a fixed time
+//! after the ADV_EXT_IND that points to it. TIMER0 is started together with
+//! the ADV_EXT_IND and triggers the ramp-up for the auxiliary PDU through the
+//! pre-programmed PPI channel 20 (`TIMER0->EVENTS_COMPARE[0]` to
+//! `RADIO->TASKS_TXEN`). Since the ramp-up takes the same time for both
+//! packets, the auxiliary PDU starts exactly `AUX_OFFSET_US` after the
+//! ADV_EXT_IND. The END interrupt of the ADV_EXT_IND reconfigures the radio
+//! for the auxiliary PDU in the meantime.
+//!
+//! TIMER0 is also used by the IEEE 802.15.4 radio driver, so a board can only
+//! use one of them.
 
 use core::cell::Cell;
 use core::convert::TryFrom;
@@ -40,13 +54,24 @@ use kernel::common::cells::TakeCell;
 use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil::ble_advertising;
-use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_advertising::{Phy, RadioChannel};
+use kernel::hil::ble_connection;
 use kernel::ErrorCode;
 use nrf5x::constants::TxPower;
 
 const RADIO_BASE: StaticRef<RadioRegisters> =
     unsafe { StaticRef::new(0x40001000 as *const RadioRegisters) };
 
+const TIMER0_BASE: StaticRef<TimerRegisters> =
+    unsafe { StaticRef::new(0x40008000 as *const TimerRegisters) };
+
+/// Time from the start of an ADV_EXT_IND to the start of its auxiliary PDU.
+/// It leaves enough time to reconfigure the radio after the ADV_EXT_IND.
+const AUX_OFFSET_US: u32 = 900;
+
+/// Largest PDU: 2 byte header and 255 bytes of payload
+const AUX_PAYLOAD_LENGTH: usize = 257;
+
 #[repr(C)]
 struct RadioRegisters {
     /// Enable Radio in TX mode
@@ -235,6 +260,45 @@ struct RadioRegisters {
     power: ReadWrite<u32, Task::Register>,
 }
 
+/// The registers of TIMER0 used to time auxiliary PDUs
+#[repr(C)]
+struct TimerRegisters {
+    /// Start Timer
+    /// - Address: 0x000 - 0x004
+    tasks_start: WriteOnly<u32, Task::Register>,
+    /// Stop Timer
+    /// - Address: 0x004 - 0x008
+    tasks_stop: WriteOnly<u32, Task::Register>,
+    /// Reserved
+    _reserved0: [u32; 1],
+    /// Clear Timer
+    /// - Address: 0x00c - 0x010
+    tasks_clear: WriteOnly<u32, Task::Register>,
+    /// Reserved
+    _reserved1: [u32; 76],
+    /// Compare event on CC[0] match
+    /// - Address: 0x140 - 0x144
+    events_compare0: ReadWrite<u32, Event::Register>,
+    /// Reserved
+    _reserved2: [u32; 240],
+    /// Timer mode selection
+    /// - Address: 0x504 - 0x508
+    mode: ReadWrite<u32>,
+    /// Number of bits used by the Timer
+    /// - Address: 0x508 - 0x50c
+    bitmode: ReadWrite<u32>,
+    /// Reserved
+    _reserved3: [u32; 1],
+    /// Timer prescaler
+    /// - Address: 0x510 - 0x514
+    prescaler: ReadWrite<u32>,
+    /// Reserved
+    _reserved4: [u32; 11],
+    /// Capture/Compare register 0
+    /// - Address: 0x540 - 0x544
+    cc0: ReadWrite<u32>,
+}
+
 register_bitfields! [u32,
     /// Task register
     Task [
@@ -349,7 +413,12 @@ register_bitfields! [u32,
             NRF_1MBIT = 0,
             NRF_2MBIT = 1,
             NRF_250KBIT = 2,
-            BLE_1MBIT = 3
+            BLE_1MBIT = 3,
+            BLE_2MBIT = 4,
+            /// Coded PHY with S=8, nRF52833 and nRF52840 only
+            BLE_LR125KBIT = 5,
+            /// Coded PHY with S=2, nRF52833 and nRF52840 only
+            BLE_LR500KBIT = 6
         ]
     ],
     /// Packet configuration register 0
@@ -365,11 +434,17 @@ register_bitfields! [u32,
             AUTOMATIC = 0,
             INCLUDE = 1
         ],
+        /// Length of code indicator, long range only
+        CILEN OFFSET(22) NUMBITS(2) [],
         /// Length of preamble on air. Decision point: TASKS_START task
-        PLEN OFFSET(24) NUMBITS(1) [
+        PLEN OFFSET(24) NUMBITS(2) [
             EIGHT = 0,
-            SIXTEEN = 1
-        ]
+            SIXTEEN = 1,
+            /// Long range preamble, nRF52833 and nRF52840 only
+            LONG_RANGE = 3
+        ],
+        /// Length of TERM field in long range operation
+        TERMLEN OFFSET(29) NUMBITS(2) []
     ],
     /// Packet configuration register 1
     PacketConfiguration1 [
@@ -529,22 +604,52 @@ register_bitfields! [u32,
 static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
     [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
 
+static mut AUX_PAYLOAD: [u8; AUX_PAYLOAD_LENGTH] = [0x00; AUX_PAYLOAD_LENGTH];
+
 pub struct Radio<'a> {
     registers: StaticRef<RadioRegisters>,
+    timer: StaticRef<TimerRegisters>,
+    ppi: crate::ppi::Ppi,
     tx_power: Cell<TxPower>,
+    /// PHY of auxiliary PDUs
+    phy: Cell<Phy>,
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
     tx_client: OptionalCell<&'a dyn ble_advertising::TxClient>,
+    data_client: OptionalCell<&'a dyn ble_connection::DataRadioClient>,
     buffer: TakeCell<'static, [u8]>,
+    access_address: Cell<u32>,
+    crc_init: Cell<u32>,
+    /// The current operation was started through `BleDataRadio`
+    data_mode: Cell<bool>,
+    /// Direction of the current or next `BleDataRadio` operation
+    data_receiving: Cell<bool>,
+    /// The radio is switching direction after the last packet
+    turnaround: Cell<bool>,
+    /// The current operation is an extended advertisement
+    extended: Cell<bool>,
+    /// Channel of the auxiliary PDU while sending the ADV_EXT_IND
+    aux_channel: OptionalCell<RadioChannel>,
 }
 
 impl<'a> Radio<'a> {
     pub const fn new() -> Radio<'a> {
         Radio {
             registers: RADIO_BASE,
+            timer: TIMER0_BASE,
+            ppi: crate::ppi::Ppi::new(),
             tx_power: Cell::new(TxPower::ZerodBm),
+            phy: Cell::new(Phy::Le1M),
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
+            data_client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
+            access_address: Cell::new(ble_connection::ADVERTISING_ACCESS_ADDRESS),
+            crc_init: Cell::new(ble_connection::ADVERTISING_CRC_INIT),
+            data_mode: Cell::new(false),
+            data_receiving: Cell::new(false),
+            turnaround: Cell::new(false),
+            extended: Cell::new(false),
+            aux_channel: OptionalCell::empty(),
         }
     }
 
@@ -600,8 +705,12 @@ impl<'a> Radio<'a> {
 
         if self.registers.event_ready.is_set(Event::READY) {
             self.registers.event_ready.write(Event::READY::CLEAR);
-            self.registers.event_end.write(Event::READY::CLEAR);
-            self.registers.task_start.write(Task::ENABLE::SET);
+            // In data mode and for extended advertisements the READY_START
+            // shortcut starts the radio
+            if !self.registers.shorts.is_set(Shortcut::READY_START) {
+                self.registers.event_end.write(Event::READY::CLEAR);
+                self.registers.task_start.write(Task::ENABLE::SET);
+            }
         }
 
         if self.registers.event_address.is_set(Event::READY) {
@@ -621,6 +730,17 @@ impl<'a> Radio<'a> {
                 Err(ErrorCode::FAIL)
             };
 
+            if self.data_mode.get() {
+                self.handle_data_end(result);
+                self.enable_interrupts();
+                return;
+            }
+            if self.extended.get() {
+                self.handle_extended_end(result);
+                self.enable_interrupts();
+                return;
+            }
+
             match self.registers.state.get() {
                 nrf5x::constants::RADIO_STATE_TXRU
                 | nrf5x::constants::RADIO_STATE_TXIDLE
@@ -634,13 +754,15 @@ impl<'a> Radio<'a> {
                 | nrf5x::constants::RADIO_STATE_RXIDLE
                 | nrf5x::constants::RADIO_STATE_RXDISABLE
                 | nrf5x::constants::RADIO_STATE_RX => {
+                    // The sample holds the magnitude of the negative RSSI in dBm
+                    let rssi = -(self.registers.rssisample.read(RssiSample::RSSISAMPLE) as i8);
                     self.radio_off();
                     unsafe {
                         self.rx_client.map(|client| {
                             // Length is: S0 (1 Byte) + Length (1 Byte) + S1 (0 Bytes) + Payload
                             // And because the length field is directly read from the packet
                             // We need to add 2 to length to get the total length
-                            client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
+                            client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, Some(rssi), result)
                         });
                     }
                 }
@@ -651,6 +773,74 @@ impl<'a> Radio<'a> {
         self.enable_interrupts();
     }
 
+    // The END_DISABLE and DISABLED_TXEN/DISABLED_RXEN shortcuts have already
+    // started the opposite operation, which the radio delays until T_IFS after
+    // the end of this packet. The client continues or aborts it from within
+    // the callback.
+    fn handle_data_end(&self, result: Result<(), ErrorCode>) {
+        self.turnaround.set(true);
+        if self.data_receiving.get() {
+            self.data_receiving.set(false);
+            unsafe {
+                self.data_client.map(|client| {
+                    // See `handle_interrupt` for the length calculation
+                    client.packet_received(&mut PAYLOAD, PAYLOAD[1].saturating_add(2), result)
+                });
+            }
+        } else {
+            self.data_receiving.set(true);
+            self.buffer.take().map(|buf| {
+                self.data_client
+                    .map(move |client| client.packet_transmitted(buf, Ok(())))
+            });
+        }
+    }
+
+    // After the ADV_EXT_IND, switch to the auxiliary PDU before TIMER0 starts
+    // the ramp-up for it. After the auxiliary PDU the advertisement is done.
+    fn handle_extended_end(&self, result: Result<(), ErrorCode>) {
+        match self.aux_channel.take() {
+            Some(aux_channel) => {
+                unsafe {
+                    self.registers.packetptr.set(AUX_PAYLOAD.as_ptr() as u32);
+                }
+                self.ble_set_channel_freq(aux_channel);
+                self.ble_set_data_whitening(aux_channel);
+                self.ble_set_phy(self.phy.get());
+            }
+            None => {
+                self.extended.set(false);
+                self.stop_aux_timer();
+                self.registers.shorts.set(0);
+                self.radio_off();
+                self.buffer.take().map(|buf| {
+                    self.tx_client
+                        .map(move |client| client.transmit_event(buf, result))
+                });
+            }
+        }
+    }
+
+    // TIMER0 counts µs from the ramp-up of the ADV_EXT_IND and starts the
+    // ramp-up of the auxiliary PDU through PPI channel 20
+    fn start_aux_timer(&self) {
+        self.timer.tasks_stop.write(Task::ENABLE::SET);
+        self.timer.tasks_clear.write(Task::ENABLE::SET);
+        // Timer mode, 32 bit, 16 MHz / 2^4
+        self.timer.mode.set(0);
+        self.timer.bitmode.set(3);
+        self.timer.prescaler.set(4);
+        self.timer.cc0.set(AUX_OFFSET_US);
+        self.timer.events_compare0.write(Event::READY::CLEAR);
+        self.ppi.enable(crate::ppi::Channel::CH20::SET);
+        self.timer.tasks_start.write(Task::ENABLE::SET);
+    }
+
+    fn stop_aux_timer(&self) {
+        self.ppi.disable(crate::ppi::Channel::CH20::SET);
+        self.timer.tasks_stop.write(Task::ENABLE::SET);
+    }
+
     pub fn enable_interrupts(&self) {
         self.registers.intenset.write(
             Interrupt::READY::SET
@@ -673,9 +863,9 @@ impl<'a> Radio<'a> {
         self.registers.intenclr.set(0xffffffff);
     }
 
-    fn replace_radio_buffer(&self, buf: &'static mut [u8]) -> &'static mut [u8] {
+    fn replace_radio_buffer(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8] {
         // set payload
-        for (i, c) in buf.as_ref().iter().enumerate() {
+        for (i, c) in buf.iter().take(len).enumerate() {
             unsafe {
                 PAYLOAD[i] = *c;
             }
@@ -686,6 +876,12 @@ impl<'a> Radio<'a> {
     fn ble_initialize(&self, channel: RadioChannel) {
         self.radio_on();
 
+        self.data_mode.set(false);
+        if self.extended.take() {
+            self.stop_aux_timer();
+        }
+        self.registers.shorts.set(0);
+
         self.ble_set_tx_power();
 
         self.ble_set_channel_rate();
@@ -704,6 +900,34 @@ impl<'a> Radio<'a> {
         self.set_dma_ptr();
     }
 
+    // Same as `ble_initialize`, but with the configured access address and CRC
+    // initial value, and the inter frame space used to switch between RX and
+    // TX
+    fn ble_initialize_data(&self, channel: RadioChannel) {
+        self.ble_initialize(channel);
+
+        self.data_mode.set(true);
+        self.ble_set_access_address(self.access_address.get());
+        self.registers.crcinit.set(self.crc_init.get());
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.1
+        // Inter Frame Space
+        self.registers.tifs.write(InterFrameSpacing::TIFS.val(150));
+    }
+
+    // After receiving a packet the radio ramps up for the response, and vice
+    // versa
+    fn ble_set_data_shorts(&self, receiving: bool) {
+        let turnaround = if receiving {
+            Shortcut::DISABLED_TXEN::SET
+        } else {
+            Shortcut::DISABLED_RXEN::SET
+        };
+        self.registers
+            .shorts
+            .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + turnaround);
+    }
+
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.1.1 CRC Generation
     fn ble_set_crc_config(&self) {
         self.registers
@@ -724,6 +948,14 @@ impl<'a> Radio<'a> {
         self.registers.base0.set(0x89bed600);
     }
 
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
+    // The most significant byte is the prefix and the remaining 3 bytes the
+    // base address
+    fn ble_set_access_address(&self, access_address: u32) {
+        self.registers.prefix0.set(access_address >> 24);
+        self.registers.base0.set(access_address << 8);
+    }
+
     // Packet configuration
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1 Packet Format
     //
@@ -759,6 +991,30 @@ impl<'a> Radio<'a> {
         self.registers.mode.write(Mode::MODE::BLE_1MBIT);
     }
 
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.1 Packet Format
+    // LE 1M uses an 8 bit preamble, LE 2M a 16 bit one and LE Coded a long
+    // range preamble followed by a 2 bit code indicator and a 3 bit TERM1
+    fn ble_set_phy(&self, phy: Phy) {
+        let (mode, plen) = match phy {
+            Phy::Le1M => (Mode::MODE::BLE_1MBIT, PacketConfiguration0::PLEN::EIGHT),
+            Phy::Le2M => (Mode::MODE::BLE_2MBIT, PacketConfiguration0::PLEN::SIXTEEN),
+            Phy::LeCoded => (
+                Mode::MODE::BLE_LR125KBIT,
+                PacketConfiguration0::PLEN::LONG_RANGE,
+            ),
+        };
+        self.registers.mode.write(mode);
+        if phy == Phy::LeCoded {
+            self.registers.pcnf0.modify(
+                plen + PacketConfiguration0::CILEN.val(2) + PacketConfiguration0::TERMLEN.val(3),
+            );
+        } else {
+            self.registers.pcnf0.modify(
+                plen + PacketConfiguration0::CILEN::CLEAR + PacketConfiguration0::TERMLEN::CLEAR,
+            );
+        }
+    }
+
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.2 Data Whitening
     // Configure channel index to the LFSR and the hardware solves the rest
     fn ble_set_data_whitening(&self, channel: RadioChannel) {
@@ -787,8 +1043,8 @@ impl<'a> Radio<'a> {
 }
 
 impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
-    fn transmit_advertisement(&self, buf: &'static mut [u8], _len: usize, channel: RadioChannel) {
-        let res = self.replace_radio_buffer(buf);
+    fn transmit_advertisement(&self, buf: &'static mut [u8], len: usize, channel: RadioChannel) {
+        let res = self.replace_radio_buffer(buf, len);
         self.buffer.replace(res);
         self.ble_initialize(channel);
         self.tx();
@@ -797,6 +1053,10 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
 
     fn receive_advertisement(&self, channel: RadioChannel) {
         self.ble_initialize(channel);
+        // Sample the RSSI of the packet once its address is received
+        self.registers
+            .shorts
+            .write(Shortcut::ADDRESS_RSSISTART::SET + Shortcut::DISABLED_RSSISTOP::SET);
         self.rx();
         self.enable_interrupts();
     }
@@ -810,6 +1070,86 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     }
 }
 
+impl<'a> ble_advertising::BleExtendedAdvertisementDriver<'a> for Radio<'a> {
+    fn aux_offset_us(&self) -> Option<u32> {
+        Some(AUX_OFFSET_US)
+    }
+
+    fn transmit_extended_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        primary_len: usize,
+        aux_len: usize,
+        channel: RadioChannel,
+        aux_channel: RadioChannel,
+    ) {
+        let aux_len = core::cmp::min(aux_len, AUX_PAYLOAD_LENGTH);
+        unsafe {
+            AUX_PAYLOAD[..aux_len].copy_from_slice(&buf[primary_len..primary_len + aux_len]);
+        }
+        let res = self.replace_radio_buffer(buf, primary_len);
+        self.buffer.replace(res);
+        self.ble_initialize(channel);
+
+        self.extended.set(true);
+        self.aux_channel.set(aux_channel);
+        self.registers
+            .shorts
+            .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+        // The timer and the ramp-up must start together
+        self.start_aux_timer();
+        self.tx();
+        self.enable_interrupts();
+    }
+}
+
+impl<'a> ble_connection::BleDataRadio<'a> for Radio<'a> {
+    fn set_access_address(&self, access_address: u32, crc_init: u32) {
+        self.access_address.set(access_address);
+        self.crc_init.set(crc_init);
+    }
+
+    fn transmit_data(&self, buf: &'static mut [u8], len: usize, channel: RadioChannel) {
+        let res = self.replace_radio_buffer(buf, len);
+        self.buffer.replace(res);
+        let ramping_up = self.turnaround.take() && !self.data_receiving.get();
+        self.data_receiving.set(false);
+        if ramping_up {
+            self.ble_set_data_shorts(false);
+        } else {
+            self.ble_initialize_data(channel);
+            self.ble_set_data_shorts(false);
+            self.tx();
+        }
+        self.enable_interrupts();
+    }
+
+    fn receive_data(&self, channel: RadioChannel) {
+        let ramping_up = self.turnaround.take() && self.data_receiving.get();
+        self.data_receiving.set(true);
+        if ramping_up {
+            self.ble_set_data_shorts(true);
+        } else {
+            self.ble_initialize_data(channel);
+            self.ble_set_data_shorts(true);
+            self.rx();
+        }
+        self.enable_interrupts();
+    }
+
+    fn disable(&self) {
+        self.disable_all_interrupts();
+        self.registers.shorts.set(0);
+        self.turnaround.set(false);
+        self.data_mode.set(false);
+        self.radio_off();
+    }
+
+    fn set_data_client(&self, client: &'a dyn ble_connection::DataRadioClient) {
+        self.data_client.set(client);
+    }
+}
+
 impl ble_advertising::BleConfig for Radio<'_> {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
@@ -825,4 +1165,12 @@ impl ble_advertising::BleConfig for Radio<'_> {
             }
         }
     }
+
+    fn set_phy(&self, phy: Phy) -> Result<(), ErrorCode> {
+        if phy == Phy::LeCoded && unsafe { !crate::ficr::FICR_INSTANCE.has_coded_phy() } {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        self.phy.set(phy);
+        Ok(())
+    }
 }
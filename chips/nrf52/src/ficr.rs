@@ -320,6 +320,20 @@ impl Ficr {
         }
     }
 
+    /// Size of the code flash in bytes
+    pub fn code_size(&self) -> usize {
+        self.registers.codepagesize.read(CodePageSize::CODEPAGESIZE) as usize
+            * self.registers.codesize.read(CodeSize::CODESIZE) as usize
+    }
+
+    /// Whether the radio supports the Bluetooth LE Coded PHY
+    pub fn has_coded_phy(&self) -> bool {
+        match self.part() {
+            Part::N52833 | Part::N52840 => true,
+            Part::N52832 | Part::Unspecified => false,
+        }
+    }
+
     fn variant(&self) -> Variant {
         match self.registers.info_variant.get() {
             0x41414130 => Variant::AAA0,
@@ -194,10 +194,13 @@ impl AsMut<[u8]> for NrfPage {
 /// FlashState is used to track the current state and command of the flash.
 #[derive(Clone, Copy, PartialEq)]
 pub enum FlashState {
-    Ready, // Flash is ready to complete a command.
-    Read,  // Performing a read operation.
-    Write, // Performing a write operation.
-    Erase, // Performing an erase operation.
+    Ready,      // Flash is ready to complete a command.
+    Read,       // Performing a read operation.
+    Write,      // Performing a write operation.
+    Erase,      // Performing an erase operation.
+    RangeRead,  // Performing a `FlashRange` read.
+    RangeWrite, // Performing a `FlashRange` write.
+    RangeErase, // Performing a `FlashRange` erase.
 }
 
 pub struct Nvmc {
@@ -205,6 +208,9 @@ pub struct Nvmc {
     client: OptionalCell<&'static dyn hil::flash::Client<Nvmc>>,
     buffer: TakeCell<'static, NrfPage>,
     state: Cell<FlashState>,
+    range_client: OptionalCell<&'static dyn hil::flash::RangeClient>,
+    range_buffer: TakeCell<'static, [u8]>,
+    range_length: Cell<usize>,
 }
 
 impl Nvmc {
@@ -214,6 +220,9 @@ impl Nvmc {
             client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
             state: Cell::new(FlashState::Ready),
+            range_client: OptionalCell::empty(),
+            range_buffer: TakeCell::empty(),
+            range_length: Cell::new(0),
         }
     }
 
@@ -264,6 +273,25 @@ impl Nvmc {
                     client.erase_complete(hil::flash::Error::CommandComplete);
                 });
             }
+            FlashState::RangeRead => {
+                self.range_buffer.take().map(|buffer| {
+                    self.range_client.map(move |client| {
+                        client.read_done(buffer, self.range_length.get(), Ok(()));
+                    });
+                });
+            }
+            FlashState::RangeWrite => {
+                self.range_buffer.take().map(|buffer| {
+                    self.range_client.map(move |client| {
+                        client.write_done(buffer, self.range_length.get(), Ok(()));
+                    });
+                });
+            }
+            FlashState::RangeErase => {
+                self.range_client.map(|client| {
+                    client.erase_done(Ok(()));
+                });
+            }
             _ => {}
         }
     }
@@ -356,6 +384,25 @@ impl Nvmc {
 
         Ok(())
     }
+
+    /// Check that a `FlashRange` operation can start on the given range.
+    fn check_range(
+        &self,
+        address: usize,
+        length: usize,
+        alignment: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.state.get() != FlashState::Ready {
+            return Err(ErrorCode::BUSY);
+        }
+        let size = unsafe { crate::ficr::FICR_INSTANCE.code_size() };
+        match address.checked_add(length) {
+            Some(end) if end <= size && address % alignment == 0 && length % alignment == 0 => {
+                Ok(())
+            }
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
 }
 
 impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for Nvmc {
@@ -387,3 +434,92 @@ impl hil::flash::Flash for Nvmc {
         self.erase_page(page_number)
     }
 }
+
+impl hil::flash::FlashRange<'static> for Nvmc {
+    fn set_range_client(&self, client: &'static dyn hil::flash::RangeClient) {
+        self.range_client.set(client);
+    }
+
+    fn geometry(&self) -> hil::flash::Geometry {
+        hil::flash::Geometry {
+            size: unsafe { crate::ficr::FICR_INSTANCE.code_size() },
+            write_size: 4,
+            min_erase_size: PAGE_SIZE,
+            max_erase_size: PAGE_SIZE,
+        }
+    }
+
+    fn erase_unit(&self, address: usize) -> Option<(usize, usize)> {
+        if address < unsafe { crate::ficr::FICR_INSTANCE.code_size() } {
+            Some((address - address % PAGE_SIZE, PAGE_SIZE))
+        } else {
+            None
+        }
+    }
+
+    fn read(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        if let Err(e) = self.check_range(address, length, 1) {
+            return Err((e, buffer));
+        }
+
+        for (i, byte) in buffer[..length].iter_mut().enumerate() {
+            *byte = unsafe { *((address + i) as *const u8) };
+        }
+
+        self.range_buffer.replace(buffer);
+        self.range_length.set(length);
+        self.state.set(FlashState::RangeRead);
+        DEFERRED_CALL.set();
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        if let Err(e) = self.check_range(address, length, 4) {
+            return Err((e, buffer));
+        }
+
+        // Put the NVMC in write mode.
+        self.registers.config.write(Configuration::WEN::Wen);
+
+        for (i, word) in buffer[..length].chunks_exact(4).enumerate() {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            let location = unsafe { &*((address + 4 * i) as *const VolatileCell<u32>) };
+            location.set(word);
+            while !self.registers.ready.is_set(Ready::READY) {}
+        }
+
+        self.registers.config.write(Configuration::WEN::Ren);
+
+        self.range_buffer.replace(buffer);
+        self.range_length.set(length);
+        self.state.set(FlashState::RangeWrite);
+        DEFERRED_CALL.set();
+        Ok(())
+    }
+
+    fn erase(&self, address: usize) -> Result<(), ErrorCode> {
+        self.check_range(address, PAGE_SIZE, PAGE_SIZE)?;
+
+        self.erase_page_helper(address / PAGE_SIZE);
+
+        self.state.set(FlashState::RangeErase);
+        DEFERRED_CALL.set();
+        Ok(())
+    }
+}
@@ -10,6 +10,7 @@ use kernel::common::registers::{
 use kernel::common::StaticRef;
 use kernel::hil;
 use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
 
 use crate::power;
 
@@ -710,6 +711,11 @@ pub struct Usbd<'a> {
     client: OptionalCell<&'a dyn hil::usb::Client<'a>>,
     descriptors: [Endpoint<'a>; NUM_ENDPOINTS],
     power: OptionalCell<&'a power::Power<'a>>,
+    // Whether the host suspended the bus. DMA transfers are deferred until
+    // the bus resumes.
+    suspended: Cell<bool>,
+    // Whether we requested a remote wakeup and wait for USBWUALLOWED.
+    wakeup_pending: Cell<bool>,
 }
 
 impl<'a> Usbd<'a> {
@@ -730,6 +736,8 @@ impl<'a> Usbd<'a> {
                 Endpoint::new(),
             ],
             power: OptionalCell::empty(),
+            suspended: Cell::new(false),
+            wakeup_pending: Cell::new(false),
         }
     }
 
@@ -832,20 +840,32 @@ impl<'a> Usbd<'a> {
         self.apply_errata_187(0);
     }
 
-    // TODO: unused function
-    fn _suspend(&self) {
+    // Enters low power mode once the host suspended the bus. Pending
+    // transfers are kept and restarted on resume.
+    fn suspend(&self) {
         debug_info!("usbc::suspend()");
-        self.ep_abort_all();
         if self.registers.eventcause.is_set(EventCause::RESUME) {
             return;
         }
         self.enable_lowpower();
         if self.registers.eventcause.is_set(EventCause::RESUME) {
             self.disable_lowpower();
-        } else {
-            self.apply_errata_171(0);
+            return;
+        }
+        self.apply_errata_171(0);
+        self.suspended.set(true);
+        self.client.map(|client| client.suspend());
+    }
+
+    // Leaves low power mode. Transfers requested while suspended are started
+    // by `process_dma_requests()` at the end of the interrupt handler.
+    fn resume(&self) {
+        debug_info!("usbc::resume()");
+        self.disable_lowpower();
+        self.wakeup_pending.set(false);
+        if self.suspended.replace(false) {
+            self.client.map(|client| client.resume());
         }
-        internal_warn!("suspend() not fully implemented");
     }
 
     fn disable_all_interrupts(&self) {
@@ -1120,11 +1140,13 @@ impl<'a> Usbd<'a> {
     }
 
     pub fn enable_lowpower(&self) {
-        internal_warn!("enable_lowpower() not implemented");
+        self.registers.lowpower.write(LowPower::LOWPOWER::LowPower);
     }
 
     pub fn disable_lowpower(&self) {
-        internal_warn!("disable_lowpower() not implemented");
+        self.registers
+            .lowpower
+            .write(LowPower::LOWPOWER::ForceNormal);
     }
 
     pub fn handle_interrupt(&self) {
@@ -1308,6 +1330,12 @@ impl<'a> Usbd<'a> {
 
         self.dma_pending.set(false);
 
+        // A reset also ends a suspend.
+        if self.suspended.replace(false) {
+            self.disable_lowpower();
+        }
+        self.wakeup_pending.set(false);
+
         // Wait for at least T_RSTRCY for the hardware to be ready after the USB
         // RESET (§6.35.6). I measured the loop using GPIO pins from `0..800000`
         // as a 62.5 ms delay, and that was enough to allow the CDC layer to
@@ -1505,15 +1533,20 @@ impl<'a> Usbd<'a> {
         }
         if eventcause.is_set(EventCause::SUSPEND) {
             debug_events!("- usbevent: suspend");
-            internal_warn!("usbc::suspend not implemented");
+            self.suspend();
         }
         if eventcause.is_set(EventCause::RESUME) {
             debug_events!("- usbevent: resume");
-            internal_warn!("usbc::resume not implemented");
+            self.resume();
         }
         if eventcause.is_set(EventCause::USBWUALLOWED) {
             debug_events!("- usbevent: usbwuallowed");
-            internal_warn!("usbc::usbwuallowed not implemented");
+            if self.wakeup_pending.get() {
+                // Drive the resume signaling, the host then takes over and the
+                // RESUME event follows.
+                self.registers.dpdmvalue.write(DpDmValue::STATE::Resume);
+                self.registers.task_dpdmdrive.write(Task::ENABLE::SET);
+            }
         }
         if eventcause.is_set(EventCause::READY) {
             debug_events!("- usbevent: ready");
@@ -1721,7 +1754,7 @@ impl<'a> Usbd<'a> {
     }
 
     fn process_dma_requests(&self) {
-        if self.dma_pending.get() {
+        if self.dma_pending.get() || self.suspended.get() {
             return;
         }
 
@@ -2052,9 +2085,9 @@ impl<'a> hil::usb::UsbController<'a> for Usbd<'a> {
         assert!(in_state.is_some());
 
         // If there is an active DMA request, or we are waiting on finishing up
-        // a previous IN transfer, we queue this request and it will be serviced
-        // after those complete.
-        if self.dma_pending.get() || in_state != Some(BulkInState::Init) {
+        // a previous IN transfer, or the bus is suspended, we queue this
+        // request and it will be serviced after those complete.
+        if self.dma_pending.get() || self.suspended.get() || in_state != Some(BulkInState::Init) {
             debug_events!("requesting resume_in[{}]", endpoint);
             // A DMA is already pending. Schedule the resume for later.
             self.descriptors[endpoint].request_transmit_in.set(true);
@@ -2092,7 +2125,7 @@ impl<'a> hil::usb::UsbController<'a> for Usbd<'a> {
                 // Although the client reported a delay before, an EPDATA event has
                 // happened in the meantime. This pending transaction will now
                 // continue in transmit_out().
-                if self.dma_pending.get() {
+                if self.dma_pending.get() || self.suspended.get() {
                     debug_events!("requesting resume_out[{}]", endpoint);
                     // A DMA is already pending. Schedule the resume for later.
                     self.descriptors[endpoint].request_transmit_out.set(true);
@@ -2106,6 +2139,19 @@ impl<'a> hil::usb::UsbController<'a> for Usbd<'a> {
             }
         }
     }
+
+    fn remote_wakeup(&self) -> Result<(), ErrorCode> {
+        if !self.suspended.get() {
+            return Err(ErrorCode::OFF);
+        }
+        if self.wakeup_pending.replace(true) {
+            return Err(ErrorCode::ALREADY);
+        }
+        // Leaving low power mode raises USBWUALLOWED once the clocks are
+        // running again, and the resume signaling is driven from there.
+        self.disable_lowpower();
+        Ok(())
+    }
 }
 
 fn status_epin(ep: usize) -> Field<u32, EndpointStatus::Register> {
@@ -348,14 +348,19 @@ enum FlashCMD {
 /// FlashState is used to track the current state and command of the flash.
 #[derive(Clone, Copy, PartialEq)]
 enum FlashState {
-    Unconfigured,                 // Flash is unconfigured, call configure().
-    Ready,                        // Flash is ready to complete a command.
-    Read,                         // Performing a read operation.
-    WriteUnlocking { page: i32 }, // Started a write operation.
-    WriteErasing { page: i32 },   // Waiting on the page to erase.
-    WriteWriting,                 // Waiting on the page to actually be written.
-    EraseUnlocking { page: i32 }, // Started an erase operation.
-    EraseErasing,                 // Waiting on the erase to finish.
+    Unconfigured,                          // Flash is unconfigured, call configure().
+    Ready,                                 // Flash is ready to complete a command.
+    Read,                                  // Performing a read operation.
+    WriteUnlocking { page: i32 },          // Started a write operation.
+    WriteErasing { page: i32 },            // Waiting on the page to erase.
+    WriteWriting,                          // Waiting on the page to actually be written.
+    EraseUnlocking { page: i32 },          // Started an erase operation.
+    EraseErasing,                          // Waiting on the erase to finish.
+    RangeRead,                             // Performing a `FlashRange` read.
+    RangeWriteUnlocking { offset: usize }, // Unlocking the next page to write.
+    RangeWriteWriting { offset: usize },   // Waiting on a page to be written.
+    RangeEraseUnlocking { page: i32 },     // Started a `FlashRange` erase.
+    RangeEraseErasing,                     // Waiting on the erase to finish.
 }
 
 /// This is a wrapper around a u8 array that is sized to a single page for the
@@ -416,6 +421,10 @@ pub struct FLASHCALW {
     client: OptionalCell<&'static dyn hil::flash::Client<FLASHCALW>>,
     current_state: Cell<FlashState>,
     buffer: TakeCell<'static, Sam4lPage>,
+    range_client: OptionalCell<&'static dyn hil::flash::RangeClient>,
+    range_buffer: TakeCell<'static, [u8]>,
+    range_address: Cell<usize>,
+    range_length: Cell<usize>,
 }
 
 // Few constants relating to module configuration.
@@ -447,6 +456,10 @@ impl FLASHCALW {
             client: OptionalCell::empty(),
             current_state: Cell::new(FlashState::Unconfigured),
             buffer: TakeCell::empty(),
+            range_client: OptionalCell::empty(),
+            range_buffer: TakeCell::empty(),
+            range_address: Cell::new(0),
+            range_length: Cell::new(0),
         }
     }
 
@@ -514,6 +527,22 @@ impl FLASHCALW {
                 }
                 _ => {}
             });
+            self.range_client.map(|client| match attempted_operation {
+                FlashState::RangeRead => {
+                    self.range_buffer.take().map(|buffer| {
+                        client.read_done(buffer, 0, Err(ErrorCode::FAIL));
+                    });
+                }
+                FlashState::RangeWriteUnlocking { .. } | FlashState::RangeWriteWriting { .. } => {
+                    self.range_buffer.take().map(|buffer| {
+                        client.write_done(buffer, 0, Err(ErrorCode::FAIL));
+                    });
+                }
+                FlashState::RangeEraseUnlocking { .. } | FlashState::RangeEraseErasing => {
+                    client.erase_done(Err(ErrorCode::FAIL));
+                }
+                _ => {}
+            });
         }
 
         // Part of a command succeeded -- continue onto next steps.
@@ -569,6 +598,58 @@ impl FLASHCALW {
                     client.erase_complete(hil::flash::Error::CommandComplete);
                 });
             }
+            FlashState::RangeRead => {
+                self.current_state.set(FlashState::Ready);
+
+                self.range_buffer.take().map(|buffer| {
+                    self.range_client.map(move |client| {
+                        client.read_done(buffer, self.range_length.get(), Ok(()));
+                    });
+                });
+            }
+            FlashState::RangeWriteUnlocking { offset } => {
+                let (page, end) = self.range_chunk(offset);
+                self.clear_page_buffer();
+                self.write_range_to_page_buffer(offset, end);
+
+                self.current_state
+                    .set(FlashState::RangeWriteWriting { offset });
+                self.flashcalw_write_page(page);
+            }
+            FlashState::RangeWriteWriting { offset } => {
+                // Flush the cache
+                self.invalidate_cache();
+
+                let (_, end) = self.range_chunk(offset);
+                if end < self.range_length.get() {
+                    // Continue with the next page
+                    self.current_state
+                        .set(FlashState::RangeWriteUnlocking { offset: end });
+                    self.lock_page_region(self.range_chunk(end).0, false);
+                } else {
+                    self.current_state.set(FlashState::Ready);
+
+                    self.range_buffer.take().map(|buffer| {
+                        self.range_client.map(move |client| {
+                            client.write_done(buffer, self.range_length.get(), Ok(()));
+                        });
+                    });
+                }
+            }
+            FlashState::RangeEraseUnlocking { page } => {
+                self.current_state.set(FlashState::RangeEraseErasing);
+                self.flashcalw_erase_page(page);
+            }
+            FlashState::RangeEraseErasing => {
+                // Flush the cache
+                self.invalidate_cache();
+
+                self.current_state.set(FlashState::Ready);
+
+                self.range_client.map(|client| {
+                    client.erase_done(Ok(()));
+                });
+            }
             _ => {
                 self.current_state.set(FlashState::Ready);
             }
@@ -785,6 +866,37 @@ impl FLASHCALW {
             }
         });
     }
+
+    /// The page that the part of a `FlashRange` write starting at `offset`
+    /// falls into, and the offset where that part ends.
+    fn range_chunk(&self, offset: usize) -> (i32, usize) {
+        let address = self.range_address.get() + offset;
+        let page = address / PAGE_SIZE as usize;
+        let page_end = (page + 1) * PAGE_SIZE as usize - self.range_address.get();
+        (page as i32, page_end.min(self.range_length.get()))
+    }
+
+    /// Copy the part of a `FlashRange` write between `offset` and `end` into
+    /// the page buffer. The rest of the page buffer stays cleared, so it
+    /// leaves the flash unchanged.
+    fn write_range_to_page_buffer(&self, offset: usize, end: usize) {
+        let address = self.range_address.get() + offset;
+        let cleared_double_word: [u8; 8] = [255; 8];
+
+        self.range_buffer.map(|buffer| {
+            for (i, double_word) in buffer[offset..end].chunks_exact(8).enumerate() {
+                let page_buffer = (address + 8 * i) as *mut u8;
+                unsafe {
+                    use core::ptr;
+
+                    // Errata 45.1.7 - Need to write a 64-bit all one word for
+                    // every write to the page buffer.
+                    ptr::copy(cleared_double_word.as_ptr(), page_buffer, 8);
+                    ptr::copy(double_word.as_ptr(), page_buffer, 8);
+                }
+            }
+        });
+    }
 }
 
 // Implementation of high level calls using the low-lv functions.
@@ -933,3 +1045,121 @@ impl hil::flash::Flash for FLASHCALW {
         self.erase_page(page_number as i32)
     }
 }
+
+impl FLASHCALW {
+    /// Check that a `FlashRange` operation can start on the given range.
+    fn check_range(
+        &self,
+        address: usize,
+        length: usize,
+        alignment: usize,
+    ) -> Result<(), ErrorCode> {
+        // Enable clock in case it's off.
+        pm::enable_clock(self.ahb_clock);
+
+        match self.current_state.get() {
+            FlashState::Unconfigured => self.configure(),
+            FlashState::Ready => {}
+            // If we're not ready don't take the command
+            _ => return Err(ErrorCode::BUSY),
+        }
+
+        match address.checked_add(length) {
+            Some(end)
+                if end <= self.get_flash_size() as usize
+                    && address % alignment == 0
+                    && length % alignment == 0 =>
+            {
+                Ok(())
+            }
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+}
+
+impl hil::flash::FlashRange<'static> for FLASHCALW {
+    fn set_range_client(&self, client: &'static dyn hil::flash::RangeClient) {
+        self.range_client.set(client);
+    }
+
+    fn geometry(&self) -> hil::flash::Geometry {
+        hil::flash::Geometry {
+            size: self.get_flash_size() as usize,
+            // The page buffer is written in double words
+            write_size: 8,
+            min_erase_size: PAGE_SIZE as usize,
+            max_erase_size: PAGE_SIZE as usize,
+        }
+    }
+
+    fn erase_unit(&self, address: usize) -> Option<(usize, usize)> {
+        let page_size = PAGE_SIZE as usize;
+        if address < self.get_flash_size() as usize {
+            Some((address - address % page_size, page_size))
+        } else {
+            None
+        }
+    }
+
+    fn read(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        if let Err(e) = self.check_range(address, length, 1) {
+            return Err((e, buffer));
+        }
+
+        for (i, byte) in buffer[..length].iter_mut().enumerate() {
+            *byte = unsafe { *((address + i) as *const u8) };
+        }
+
+        self.range_buffer.replace(buffer);
+        self.range_length.set(length);
+        self.current_state.set(FlashState::RangeRead);
+        DEFERRED_CALL.set();
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        if let Err(e) = self.check_range(address, length, 8) {
+            return Err((e, buffer));
+        }
+        if length == 0 {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+
+        self.range_buffer.replace(buffer);
+        self.range_address.set(address);
+        self.range_length.set(length);
+
+        // Every page is unlocked, then written from the page buffer
+        self.current_state
+            .set(FlashState::RangeWriteUnlocking { offset: 0 });
+        self.lock_page_region(self.range_chunk(0).0, false);
+        Ok(())
+    }
+
+    fn erase(&self, address: usize) -> Result<(), ErrorCode> {
+        let page_size = PAGE_SIZE as usize;
+        self.check_range(address, page_size, page_size)?;
+
+        let page = (address / page_size) as i32;
+        self.current_state
+            .set(FlashState::RangeEraseUnlocking { page });
+        self.lock_page_region(page, false);
+        Ok(())
+    }
+}
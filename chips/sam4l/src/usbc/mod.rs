@@ -19,6 +19,7 @@ use kernel::common::StaticRef;
 use kernel::debug as debugln;
 use kernel::hil;
 use kernel::hil::usb::TransferType;
+use kernel::ErrorCode;
 
 // The following macros provide some diagnostics and panics(!)
 // while this module is experimental and should eventually be removed or
@@ -243,6 +244,9 @@ pub struct Usbc<'a> {
     requests: [Cell<Requests>; N_ENDPOINTS],
     client: OptionalCell<&'a dyn hil::usb::Client<'a>>,
     pm: &'a pm::PowerManager,
+    // Whether the bus is suspended. Endpoint requests are held back until the
+    // bus resumes.
+    suspended: Cell<bool>,
 }
 
 #[derive(Copy, Clone, Default, Debug)]
@@ -450,6 +454,7 @@ impl<'a> Usbc<'a> {
                 Cell::new(Requests::new()),
             ],
             pm,
+            suspended: Cell::new(false),
         }
     }
 
@@ -533,12 +538,11 @@ impl<'a> Usbc<'a> {
 
                 // Enable device global interrupts
                 // Note: SOF has been omitted as it is not presently used,
-                // Note: SUSP has been omitted as SUSPEND/WAKEUP is not yet
-                //   implemented.
-                // Note: SOF and SUSP may nevertheless be enabled here
-                //   without harm, but it makes debugging easier to omit them.
+                //   it may nevertheless be enabled here without harm, but it
+                //   makes debugging easier to omit it.
                 usbc_regs().udinteset.write(
                     DeviceInterrupt::EORST::SET
+                        + DeviceInterrupt::SUSP::SET
                         + DeviceInterrupt::EORSM::SET
                         + DeviceInterrupt::UPRSM::SET,
                 );
@@ -733,6 +737,11 @@ impl<'a> Usbc<'a> {
     }
 
     fn handle_requests(&self) {
+        if self.suspended.get() {
+            // The clock is frozen, keep the requests for the resume
+            return;
+        }
+
         for endpoint in 0..N_ENDPOINTS {
             let mut requests = self.requests[endpoint].get();
 
@@ -795,6 +804,11 @@ impl<'a> Usbc<'a> {
             // Reset our record of the device state
             *device_state = DeviceState::default();
 
+            // A reset also ends a suspend
+            if self.suspended.replace(false) {
+                usbc_regs().usbcon.modify(Control::FRZCLK::CLEAR);
+            }
+
             // Reconfigure and initialize endpoints
             for i in 0..N_ENDPOINTS {
                 if let Some(endpoint_config) = device_config.endpoint_configs[i] {
@@ -836,10 +850,17 @@ impl<'a> Usbc<'a> {
 
             // Acknowledge the "suspend" event
             usbc_regs().udintclr.write(DeviceInterrupt::SUSP::SET);
+
+            if !self.suspended.replace(true) {
+                usbc_regs().usbcon.modify(Control::FRZCLK::SET);
+                self.client.map(|client| client.suspend());
+            }
         }
 
         if udint.is_set(DeviceInterrupt::WAKEUP) {
-            // XX: If we were suspended: Unfreeze the clock (and unsleep the MCU)
+            // Unfreeze the clock. The client is told about the resume once
+            // the End of Resume is received, as this may also be a reset.
+            usbc_regs().usbcon.modify(Control::FRZCLK::CLEAR);
 
             // Unsubscribe from WAKEUP
             usbc_regs().udinteclr.write(DeviceInterrupt::WAKEUP::SET);
@@ -858,11 +879,19 @@ impl<'a> Usbc<'a> {
         if udint.is_set(DeviceInterrupt::EORSM) {
             // Controller received End of Resume
             debug1!("UDINT EORSM");
+
+            usbc_regs().udintclr.write(DeviceInterrupt::EORSM::SET);
+            if self.suspended.replace(false) {
+                usbc_regs().usbcon.modify(Control::FRZCLK::CLEAR);
+                self.client.map(|client| client.resume());
+            }
         }
 
         if udint.is_set(DeviceInterrupt::UPRSM) {
             // Controller sent Upstream Resume
             debug1!("UDINT UPRSM");
+
+            usbc_regs().udintclr.write(DeviceInterrupt::UPRSM::SET);
         }
 
         // Process per-endpoint interrupt flags
@@ -1425,8 +1454,6 @@ impl<'a> Usbc<'a> {
             _ => None,
         }
     }
-
-    // TODO: Remote wakeup (Device -> Host, after receiving DEVICE_REMOTE_WAKEUP)
 }
 
 #[inline]
@@ -1576,4 +1603,18 @@ impl<'a> hil::usb::UsbController<'a> for Usbc<'a> {
         // Immediately handle the request to resume the endpoint.
         self.handle_requests();
     }
+
+    fn remote_wakeup(&self) -> Result<(), ErrorCode> {
+        if !self.suspended.get() {
+            return Err(ErrorCode::OFF);
+        }
+        if usbc_regs().udcon.is_set(DeviceControl::RMWKUP) {
+            return Err(ErrorCode::ALREADY);
+        }
+        // The upstream resume can only be sent with the clock running. The
+        // host answers with a resume, which ends with EORSM.
+        usbc_regs().usbcon.modify(Control::FRZCLK::CLEAR);
+        usbc_regs().udcon.modify(DeviceControl::RMWKUP::SET);
+        Ok(())
+    }
 }
@@ -0,0 +1,365 @@
+//! Embedded flash memory controller.
+//!
+//! The flash is split into sectors of 16, 64 and 128 kB, which are the erase
+//! units of `FlashRange`. Parts with 2 MB of flash have a second bank with
+//! the same layout. Addresses are offsets from the start of the flash at
+//! `0x0800_0000`.
+//!
+//! Bytes are programmed one at a time, which works at every supply voltage,
+//! and the write completes before the callback is deferred. Erasing a sector
+//! can take seconds, so it completes with the flash interrupt.
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell, VolatileCell};
+use kernel::common::deferred_call::DeferredCall;
+use kernel::common::registers::{register_bitfields, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil;
+use kernel::ErrorCode;
+
+use crate::deferred_calls::DeferredCallTask;
+
+const FLASH_BASE: StaticRef<FlashRegisters> =
+    unsafe { StaticRef::new(0x4002_3C00 as *const FlashRegisters) };
+
+/// Where the flash is mapped in memory.
+const FLASH_START: usize = 0x0800_0000;
+
+/// Flash size in kB, programmed by the factory.
+const FLASH_SIZE_KB: *const u16 = 0x1FFF_7A22 as *const u16;
+
+const BANK_SIZE: usize = 1024 * 1024;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+#[repr(C)]
+struct FlashRegisters {
+    /// Flash access control register
+    acr: ReadWrite<u32, ACR::Register>,
+    /// Flash key register
+    keyr: WriteOnly<u32>,
+    /// Flash option key register
+    optkeyr: WriteOnly<u32>,
+    /// Flash status register
+    sr: ReadWrite<u32, SR::Register>,
+    /// Flash control register
+    cr: ReadWrite<u32, CR::Register>,
+    /// Flash option control register
+    optcr: ReadWrite<u32>,
+}
+
+register_bitfields![u32,
+    ACR [
+        /// Data cache reset
+        DCRST OFFSET(12) NUMBITS(1) [],
+        /// Instruction cache reset
+        ICRST OFFSET(11) NUMBITS(1) [],
+        /// Data cache enable
+        DCEN OFFSET(10) NUMBITS(1) [],
+        /// Instruction cache enable
+        ICEN OFFSET(9) NUMBITS(1) [],
+        /// Prefetch enable
+        PRFTEN OFFSET(8) NUMBITS(1) [],
+        /// Latency
+        LATENCY OFFSET(0) NUMBITS(4) []
+    ],
+    SR [
+        /// Busy
+        BSY OFFSET(16) NUMBITS(1) [],
+        /// Read protection error
+        RDERR OFFSET(8) NUMBITS(1) [],
+        /// Programming sequence error
+        PGSERR OFFSET(7) NUMBITS(1) [],
+        /// Programming parallelism error
+        PGPERR OFFSET(6) NUMBITS(1) [],
+        /// Programming alignment error
+        PGAERR OFFSET(5) NUMBITS(1) [],
+        /// Write protection error
+        WRPERR OFFSET(4) NUMBITS(1) [],
+        /// Operation error
+        OPERR OFFSET(1) NUMBITS(1) [],
+        /// End of operation
+        EOP OFFSET(0) NUMBITS(1) []
+    ],
+    CR [
+        /// Lock
+        LOCK OFFSET(31) NUMBITS(1) [],
+        /// Error interrupt enable
+        ERRIE OFFSET(25) NUMBITS(1) [],
+        /// End of operation interrupt enable
+        EOPIE OFFSET(24) NUMBITS(1) [],
+        /// Start
+        STRT OFFSET(16) NUMBITS(1) [],
+        /// Program size
+        PSIZE OFFSET(8) NUMBITS(2) [
+            X8 = 0b00,
+            X16 = 0b01,
+            X32 = 0b10,
+            X64 = 0b11
+        ],
+        /// Sector number
+        SNB OFFSET(3) NUMBITS(5) [],
+        /// Mass erase
+        MER OFFSET(2) NUMBITS(1) [],
+        /// Sector erase
+        SER OFFSET(1) NUMBITS(1) [],
+        /// Programming
+        PG OFFSET(0) NUMBITS(1) []
+    ]
+];
+
+/// This mechanism allows us to schedule "interrupts" even if the hardware
+/// does not support them.
+static DEFERRED_CALL: DeferredCall<DeferredCallTask> =
+    unsafe { DeferredCall::new(DeferredCallTask::Flash) };
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Read,
+    Write,
+    Erase,
+}
+
+/// A flash sector, with the number that selects it in `CR::SNB`.
+struct Sector {
+    start: usize,
+    size: usize,
+    number: u32,
+}
+
+pub struct Flash<'a> {
+    registers: StaticRef<FlashRegisters>,
+    client: OptionalCell<&'a dyn hil::flash::RangeClient>,
+    buffer: TakeCell<'static, [u8]>,
+    length: Cell<usize>,
+    state: Cell<State>,
+}
+
+impl<'a> Flash<'a> {
+    pub fn new() -> Flash<'a> {
+        Flash {
+            registers: FLASH_BASE,
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            length: Cell::new(0),
+            state: Cell::new(State::Idle),
+        }
+    }
+
+    fn size(&self) -> usize {
+        unsafe { core::ptr::read_volatile(FLASH_SIZE_KB) as usize * 1024 }
+    }
+
+    fn sector(&self, address: usize) -> Option<Sector> {
+        if address >= self.size() {
+            return None;
+        }
+        let bank = address / BANK_SIZE;
+        let offset = address % BANK_SIZE;
+        let (index, size) = if offset < 0x1_0000 {
+            (offset / 0x4000, 0x4000)
+        } else if offset < 0x2_0000 {
+            (4, 0x1_0000)
+        } else {
+            (4 + offset / 0x2_0000, 0x2_0000)
+        };
+        // Sectors of the second bank are numbered from 0b10000.
+        Some(Sector {
+            start: address - offset % size,
+            size,
+            number: (bank << 4 | index) as u32,
+        })
+    }
+
+    fn check_range(&self, address: usize, length: usize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        match address.checked_add(length) {
+            Some(end) if end <= self.size() => Ok(()),
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+
+    fn unlock(&self) {
+        if self.registers.cr.is_set(CR::LOCK) {
+            self.registers.keyr.set(KEY1);
+            self.registers.keyr.set(KEY2);
+        }
+    }
+
+    fn lock(&self) {
+        self.registers.cr.modify(CR::LOCK::SET);
+    }
+
+    /// Clear the error flags, returning whether any were set.
+    fn clear_errors(&self) -> bool {
+        let errors = SR::RDERR::SET
+            + SR::PGSERR::SET
+            + SR::PGPERR::SET
+            + SR::PGAERR::SET
+            + SR::WRPERR::SET
+            + SR::OPERR::SET;
+        let set = self.registers.sr.matches_any(errors);
+        self.registers.sr.write(errors);
+        set
+    }
+
+    /// The data cache can hold the old contents of an erased sector.
+    fn reset_data_cache(&self) {
+        if self.registers.acr.is_set(ACR::DCEN) {
+            self.registers.acr.modify(ACR::DCEN::CLEAR);
+            self.registers.acr.modify(ACR::DCRST::SET);
+            self.registers.acr.modify(ACR::DCRST::CLEAR);
+            self.registers.acr.modify(ACR::DCEN::SET);
+        }
+    }
+
+    pub fn handle_interrupt(&self) {
+        match self.state.get() {
+            State::Idle => {}
+            State::Read => {
+                self.state.set(State::Idle);
+                self.buffer.take().map(|buffer| {
+                    self.client.map(move |client| {
+                        client.read_done(buffer, self.length.get(), Ok(()));
+                    });
+                });
+            }
+            State::Write => {
+                self.state.set(State::Idle);
+                self.buffer.take().map(|buffer| {
+                    self.client.map(move |client| {
+                        client.write_done(buffer, self.length.get(), Ok(()));
+                    });
+                });
+            }
+            State::Erase => {
+                if self.registers.sr.is_set(SR::BSY) {
+                    return;
+                }
+                let result = if self.clear_errors() {
+                    Err(ErrorCode::FAIL)
+                } else {
+                    Ok(())
+                };
+                self.registers.sr.write(SR::EOP::SET);
+                self.registers
+                    .cr
+                    .modify(CR::SER::CLEAR + CR::EOPIE::CLEAR + CR::ERRIE::CLEAR);
+                self.lock();
+                self.reset_data_cache();
+
+                self.state.set(State::Idle);
+                self.client.map(|client| {
+                    client.erase_done(result);
+                });
+            }
+        }
+    }
+}
+
+impl<'a> hil::flash::FlashRange<'a> for Flash<'a> {
+    fn set_range_client(&self, client: &'a dyn hil::flash::RangeClient) {
+        self.client.set(client);
+    }
+
+    fn geometry(&self) -> hil::flash::Geometry {
+        hil::flash::Geometry {
+            size: self.size(),
+            write_size: 1,
+            min_erase_size: 0x4000,
+            max_erase_size: 0x2_0000,
+        }
+    }
+
+    fn erase_unit(&self, address: usize) -> Option<(usize, usize)> {
+        self.sector(address)
+            .map(|sector| (sector.start, sector.size))
+    }
+
+    fn read(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        if let Err(e) = self.check_range(address, length) {
+            return Err((e, buffer));
+        }
+
+        for (i, byte) in buffer[..length].iter_mut().enumerate() {
+            *byte = unsafe { *((FLASH_START + address + i) as *const u8) };
+        }
+
+        self.buffer.replace(buffer);
+        self.length.set(length);
+        self.state.set(State::Read);
+        DEFERRED_CALL.set();
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        if let Err(e) = self.check_range(address, length) {
+            return Err((e, buffer));
+        }
+
+        self.unlock();
+        self.clear_errors();
+        self.registers.cr.modify(CR::PSIZE::X8 + CR::PG::SET);
+        for (i, byte) in buffer[..length].iter().enumerate() {
+            let location = unsafe { &*((FLASH_START + address + i) as *const VolatileCell<u8>) };
+            location.set(*byte);
+            while self.registers.sr.is_set(SR::BSY) {}
+        }
+        self.registers.cr.modify(CR::PG::CLEAR);
+        self.lock();
+
+        if self.clear_errors() {
+            return Err((ErrorCode::FAIL, buffer));
+        }
+
+        self.buffer.replace(buffer);
+        self.length.set(length);
+        self.state.set(State::Write);
+        DEFERRED_CALL.set();
+        Ok(())
+    }
+
+    fn erase(&self, address: usize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let sector = match self.sector(address) {
+            Some(sector) if sector.start == address => sector,
+            _ => return Err(ErrorCode::INVAL),
+        };
+
+        self.unlock();
+        self.clear_errors();
+        self.registers.cr.modify(
+            CR::PSIZE::X8
+                + CR::SER::SET
+                + CR::SNB.val(sector.number)
+                + CR::EOPIE::SET
+                + CR::ERRIE::SET,
+        );
+        self.registers.cr.modify(CR::STRT::SET);
+
+        self.state.set(State::Erase);
+        Ok(())
+    }
+}
@@ -70,6 +70,22 @@ pub fn log_base_two_u64(num: u64) -> u32 {
     }
 }
 
+/// Update a CRC-32 (IEEE) with `data`. Start with `0xFFFFFFFF` and invert
+/// the final value, which makes `!crc32(0xFFFFFFFF, data)` the usual CRC-32.
+pub fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
 // f32 log10 function adapted from [micromath](https://github.com/NeoBirth/micromath)
 const EXPONENT_MASK: u32 = 0b01111111_10000000_00000000_00000000;
 const EXPONENT_BIAS: u32 = 127;
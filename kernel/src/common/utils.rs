@@ -166,6 +166,10 @@ macro_rules! static_init_half {
 /// initialize their state. The linker script kernel_layout.ld makes
 /// sure that the .storage section is aligned on a 512-byte boundary
 /// and the next section is aligned as well.
+///
+/// Storage volumes are erased whenever a new kernel is flashed. Storage
+/// that has to survive kernel updates should use a partition from
+/// `kernel::partitions` instead.
 #[macro_export]
 macro_rules! storage_volume {
     ($N:ident, $kB:expr $(,)?) => {
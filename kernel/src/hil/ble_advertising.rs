@@ -56,12 +56,62 @@ pub trait BleAdvertisementDriver<'a> {
     fn set_transmit_client(&self, client: &'a dyn TxClient);
 }
 
+/// Bluetooth 5 extended advertising
+///
+/// Extended advertising events send short ADV_EXT_IND PDUs on the primary
+/// advertising channels, each pointing to an auxiliary PDU with the actual
+/// advertising data on a secondary advertising channel (a data channel).
+pub trait BleExtendedAdvertisementDriver<'a>: BleAdvertisementDriver<'a> {
+    /// The time in µs from the start of the ADV_EXT_IND to the start of the
+    /// auxiliary PDU sent by `transmit_extended_advertisement`, a multiple of
+    /// 30 µs. `None` if the radio does not support extended advertising.
+    fn aux_offset_us(&self) -> Option<u32>;
+
+    /// Transmit the ADV_EXT_IND in the first `primary_len` bytes of `buf` on
+    /// the primary advertising `channel`, followed `aux_offset_us()` later by
+    /// the auxiliary PDU in the next `aux_len` bytes on `aux_channel`, using
+    /// the PHY selected with `BleConfig::set_phy`. `transmit_event` is called
+    /// once both are sent, or with `NOSUPPORT` if the radio does not support
+    /// extended advertising.
+    fn transmit_extended_advertisement(
+        &self,
+        buf: &'static mut [u8],
+        primary_len: usize,
+        aux_len: usize,
+        channel: RadioChannel,
+        aux_channel: RadioChannel,
+    );
+}
+
+/// Physical layers defined by the Bluetooth 5 specification
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Phy {
+    Le1M,
+    Le2M,
+    /// LE Coded with S=8 coding
+    LeCoded,
+}
+
 pub trait BleConfig {
     fn set_tx_power(&self, power: u8) -> Result<(), ErrorCode>;
+
+    /// Select the PHY used for auxiliary PDUs on the secondary advertising
+    /// channels. The primary advertising channels always use LE 1M.
+    ///
+    /// Returns `NOSUPPORT` if the radio does not support `phy`.
+    fn set_phy(&self, phy: Phy) -> Result<(), ErrorCode>;
 }
 
 pub trait RxClient {
-    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: Result<(), ErrorCode>);
+    /// An advertising channel PDU of `len` bytes was received. `rssi` is the
+    /// received signal strength in dBm, if the radio measures it.
+    fn receive_event(
+        &self,
+        buf: &'static mut [u8],
+        len: u8,
+        rssi: Option<i8>,
+        result: Result<(), ErrorCode>,
+    );
 }
 
 pub trait TxClient {
@@ -114,6 +164,51 @@ pub enum RadioChannel {
 }
 
 impl RadioChannel {
+    /// Returns the data channel with channel index `index`, or `None` if
+    /// `index` is not a data channel index (0 - 36).
+    pub fn from_data_channel_index(index: u8) -> Option<RadioChannel> {
+        const DATA_CHANNELS: [RadioChannel; 37] = [
+            RadioChannel::DataChannel0,
+            RadioChannel::DataChannel1,
+            RadioChannel::DataChannel2,
+            RadioChannel::DataChannel3,
+            RadioChannel::DataChannel4,
+            RadioChannel::DataChannel5,
+            RadioChannel::DataChannel6,
+            RadioChannel::DataChannel7,
+            RadioChannel::DataChannel8,
+            RadioChannel::DataChannel9,
+            RadioChannel::DataChannel10,
+            RadioChannel::DataChannel11,
+            RadioChannel::DataChannel12,
+            RadioChannel::DataChannel13,
+            RadioChannel::DataChannel14,
+            RadioChannel::DataChannel15,
+            RadioChannel::DataChannel16,
+            RadioChannel::DataChannel17,
+            RadioChannel::DataChannel18,
+            RadioChannel::DataChannel19,
+            RadioChannel::DataChannel20,
+            RadioChannel::DataChannel21,
+            RadioChannel::DataChannel22,
+            RadioChannel::DataChannel23,
+            RadioChannel::DataChannel24,
+            RadioChannel::DataChannel25,
+            RadioChannel::DataChannel26,
+            RadioChannel::DataChannel27,
+            RadioChannel::DataChannel28,
+            RadioChannel::DataChannel29,
+            RadioChannel::DataChannel30,
+            RadioChannel::DataChannel31,
+            RadioChannel::DataChannel32,
+            RadioChannel::DataChannel33,
+            RadioChannel::DataChannel34,
+            RadioChannel::DataChannel35,
+            RadioChannel::DataChannel36,
+        ];
+        DATA_CHANNELS.get(index as usize).copied()
+    }
+
     pub fn get_channel_index(&self) -> u32 {
         match *self {
             RadioChannel::DataChannel0 => 0,
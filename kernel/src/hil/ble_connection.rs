@@ -0,0 +1,138 @@
+//! Interfaces for Bluetooth Low Energy connections.
+//!
+//! There are two layers of interfaces here:
+//!
+//! - `BleDataRadio` is implemented by radios that can exchange packets with
+//!   an arbitrary access address and CRC initial value, and that can switch
+//!   between receiving and transmitting within the inter frame space (T_IFS,
+//!   150 µs). The link layer uses it for connectable advertising and for
+//!   connection events.
+//! - `BleConnection` is implemented by the link layer in the peripheral role.
+//!   It hands L2CAP fragments of established connections to and from the
+//!   layer above, and lets that layer control connectable advertising.
+//!
+//! ```text
+//!           +-----------------------------------------------+
+//!           | Logical Link and Adaptation Protocol          |
+//!           +-----------------------------------------------+
+//!                               |  BleConnection
+//!           +-----------------------------------------------+
+//!           | Link Layer                                    |
+//!           +-----------------------------------------------+
+//!                               |  BleDataRadio
+//!           +-----------------------------------------------+
+//!           | Physical Layer                                |
+//!           +-----------------------------------------------+
+//! ```
+
+use crate::hil::ble_advertising::RadioChannel;
+use crate::ErrorCode;
+
+/// Access address used on the advertising channels.
+pub const ADVERTISING_ACCESS_ADDRESS: u32 = 0x8E89BED6;
+
+/// CRC initial value used on the advertising channels.
+pub const ADVERTISING_CRC_INIT: u32 = 0x555555;
+
+/// A radio that supports the timing required by the link layer.
+///
+/// After every packet that is received or transmitted, the radio prepares
+/// the opposite operation on the same channel so that it can start exactly
+/// T_IFS after the end of the packet. The client must then, from within the
+/// callback, call `transmit_data` or `receive_data` respectively to continue
+/// the exchange, or `disable` to end it.
+pub trait BleDataRadio<'a> {
+    /// Set the access address and CRC initial value used by subsequent
+    /// operations.
+    fn set_access_address(&self, access_address: u32, crc_init: u32);
+
+    /// Transmit the PDU (header and payload) in `buf` on `channel`.
+    ///
+    /// If called from `packet_received`, the packet is sent T_IFS after the
+    /// end of the received packet and `channel` is ignored.
+    fn transmit_data(&self, buf: &'static mut [u8], len: usize, channel: RadioChannel);
+
+    /// Receive a PDU on `channel`.
+    ///
+    /// If called from `packet_transmitted`, the radio listens T_IFS after the
+    /// end of the transmitted packet and `channel` is ignored. The radio keeps
+    /// listening until a packet is received or `disable` is called.
+    fn receive_data(&self, channel: RadioChannel);
+
+    /// Stop any ongoing operation and turn the radio off. No callback is
+    /// issued for an aborted operation.
+    fn disable(&self);
+
+    fn set_data_client(&self, client: &'a dyn DataRadioClient);
+}
+
+pub trait DataRadioClient {
+    /// A PDU was received. `len` includes the 2 byte header and `result` is
+    /// `Err(FAIL)` if the CRC did not match.
+    fn packet_received(&self, buf: &'static mut [u8], len: u8, result: Result<(), ErrorCode>);
+
+    /// The PDU passed to `transmit_data` was sent.
+    fn packet_transmitted(&self, buf: &'static mut [u8], result: Result<(), ErrorCode>);
+}
+
+/// Link layer connection in the peripheral role.
+///
+/// Data is exchanged as L2CAP fragments of at most `max_payload()` bytes. The
+/// first fragment of an L2CAP PDU is marked with `start`, all others are
+/// continuation fragments.
+pub trait BleConnection<'a> {
+    fn set_client(&self, client: &'a dyn ConnectionClient);
+
+    /// Set the advertising data sent in connectable advertisements. At most
+    /// 31 bytes are supported.
+    fn set_advertising_data(&self, data: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Start connectable advertising. Advertising stops once a central
+    /// connects.
+    ///
+    /// Returns `BUSY` while connected.
+    fn start_advertising(&self) -> Result<(), ErrorCode>;
+
+    /// Stop connectable advertising.
+    fn stop_advertising(&self) -> Result<(), ErrorCode>;
+
+    /// Queue an L2CAP fragment of `len` bytes for transmission.
+    ///
+    /// Only one fragment can be in flight at a time; `transmitted` is called
+    /// once the peer has acknowledged it. Returns `OFF` if not connected,
+    /// `BUSY` if a fragment is already queued and `SIZE` if `len` is bigger
+    /// than `max_payload()`.
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        start: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Terminate the connection with the given error code. `disconnected` is
+    /// called once the peer has acknowledged the termination.
+    fn disconnect(&self, reason: u8) -> Result<(), ErrorCode>;
+
+    fn is_connected(&self) -> bool;
+
+    /// The maximum number of bytes in an L2CAP fragment.
+    fn max_payload(&self) -> usize;
+}
+
+pub trait ConnectionClient {
+    /// A central connected. `peer_random` is set if `peer` is a random device
+    /// address.
+    fn connected(&self, peer: [u8; 6], peer_random: bool);
+
+    /// The connection was closed, either by the peer, by `disconnect` or
+    /// because the supervision timeout expired. `reason` is the HCI error
+    /// code.
+    fn disconnected(&self, reason: u8);
+
+    /// An L2CAP fragment was received.
+    fn received(&self, data: &[u8], start: bool);
+
+    /// A fragment passed to `transmit` was acknowledged by the peer, or the
+    /// connection closed before that (`CANCEL`).
+    fn transmitted(&self, buf: &'static mut [u8], result: Result<(), ErrorCode>);
+}
@@ -0,0 +1,53 @@
+//! Interface for block-addressed storage devices such as SD cards.
+//!
+//! Unlike `NonvolatileStorage`, which is byte addressed, block storage
+//! devices can only be read and written in whole blocks. Transfers are
+//! split-phase: the buffer is handed to the device and returned through the
+//! `BlockStorageClient` once the operation finishes.
+
+use crate::errorcode::ErrorCode;
+
+/// A device that stores data in fixed-size blocks.
+pub trait BlockStorage<'a> {
+    fn set_client(&self, client: &'a dyn BlockStorageClient);
+
+    /// Size of one block in bytes. Returns 0 if the device is not ready.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device. Returns 0 if the device is not ready.
+    fn block_count(&self) -> u32;
+
+    /// Read `count` blocks starting at block `block` into `buffer`. The
+    /// buffer must be at least `count * block_size()` bytes long.
+    ///
+    /// On error the buffer is returned immediately and no callback will
+    /// occur.
+    fn read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Write `count` blocks from `buffer` starting at block `block`. The
+    /// buffer must be at least `count * block_size()` bytes long.
+    ///
+    /// On error the buffer is returned immediately and no callback will
+    /// occur.
+    fn write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
+
+/// Client interface for block storage.
+pub trait BlockStorageClient {
+    /// Called when a read finishes. On success `buffer` holds the requested
+    /// blocks.
+    fn read_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>);
+
+    /// Called when a write finishes.
+    fn write_done(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>);
+}
@@ -70,3 +70,9 @@ pub trait HMACSha256 {
     /// The key used for the HMAC is passed to this function.
     fn set_mode_hmacsha256(&self, key: &[u8; 32]) -> Result<(), ErrorCode>;
 }
+
+pub trait Sha256 {
+    /// Call before `Digest::run()` to compute a plain (unkeyed) SHA256 hash
+    /// of the added data.
+    fn set_mode_sha256(&self) -> Result<(), ErrorCode>;
+}
@@ -89,6 +89,16 @@
 //!     fn erase_complete(&self, error: hil::flash::Error) {}
 //! }
 //! ```
+//!
+//! Chips can also implement `FlashRange`, which reads and writes arbitrary
+//! byte ranges instead of pages, and erases in the units of the chip, which
+//! need not all be the same size.
+//!
+//! The log, the flash translation layer and USB DFU use `FlashRange`. TicKV
+//! and the encrypted flash layer still use `Flash`: TicKV runs on the
+//! OpenTitan flash controller, which only implements `Flash` and is shared
+//! through `virtual_flash::MuxFlash`, and the encrypted flash layer provides
+//! pages to other clients of `Flash`.
 
 use crate::ErrorCode;
 
@@ -142,3 +152,64 @@ pub trait Client<F: Flash> {
     /// Flash erase complete.
     fn erase_complete(&self, error: Error);
 }
+
+/// Layout of a flash that is accessed by address through `FlashRange`.
+/// Addresses start at 0 at the beginning of the flash.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Geometry {
+    /// Size of the flash in bytes.
+    pub size: usize,
+    /// Writes must start and end at a multiple of this.
+    pub write_size: usize,
+    /// Size of the smallest erase unit.
+    pub min_erase_size: usize,
+    /// Size of the largest erase unit. Equal to `min_erase_size` if all erase
+    /// units are the same size.
+    pub max_erase_size: usize,
+}
+
+/// Flash that is read and written by byte ranges, with erase units that can
+/// be larger than the writes.
+///
+/// Like NOR flash, writes can only clear bits: a range has to be erased
+/// before it is written with arbitrary data, but bytes that are still erased
+/// can be written without erasing their neighbours. Some chips limit how
+/// often a word can be written between erases.
+pub trait FlashRange<'a> {
+    fn set_range_client(&self, client: &'a dyn RangeClient);
+
+    fn geometry(&self) -> Geometry;
+
+    /// Start and length of the erase unit that contains `address`.
+    fn erase_unit(&self, address: usize) -> Option<(usize, usize)>;
+
+    /// Read `length` bytes starting at `address` into the buffer.
+    fn read(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Write the first `length` bytes of the buffer starting at `address`.
+    /// Both must be a multiple of `Geometry::write_size`.
+    fn write(
+        &self,
+        address: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Erase the erase unit that starts at `address`, setting every byte to
+    /// 0xFF.
+    fn erase(&self, address: usize) -> Result<(), ErrorCode>;
+}
+
+/// Implement `RangeClient` to receive callbacks from `FlashRange`.
+pub trait RangeClient {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>);
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize, result: Result<(), ErrorCode>);
+
+    fn erase_done(&self, result: Result<(), ErrorCode>);
+}
@@ -112,6 +112,8 @@ pub trait CCMClient {
 }
 
 pub const CCM_NONCE_LENGTH: usize = 13;
+/// Shortest nonce accepted by `AES128CCM::set_nonce()`
+pub const CCM_MIN_NONCE_LENGTH: usize = 7;
 
 pub trait AES128CCM<'a> {
     /// Set the client instance which will receive `crypt_done()` callbacks
@@ -120,7 +122,12 @@ pub trait AES128CCM<'a> {
     /// Set the key to be used for CCM encryption
     fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode>;
 
-    /// Set the nonce (length NONCE_LENGTH) to be used for CCM encryption
+    /// Set the nonce (length NONCE_LENGTH) to be used for CCM encryption.
+    /// Implementations may also accept shorter nonces of at least
+    /// CCM_MIN_NONCE_LENGTH bytes, as used by TLS (12 bytes). The message
+    /// length field then takes up the remaining 15 - `nonce.len()` bytes
+    /// of the first block, as in RFC 3610. Returns INVAL for lengths the
+    /// implementation does not support.
     fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode>;
 
     /// Try to begin the encryption/decryption process
@@ -1,6 +1,7 @@
 //! Interface to USB controller hardware
 
 use crate::common::cells::VolatileCell;
+use crate::ErrorCode;
 
 /// USB controller interface
 pub trait UsbController<'a> {
@@ -31,6 +32,11 @@ pub trait UsbController<'a> {
     fn endpoint_resume_in(&self, endpoint: usize);
 
     fn endpoint_resume_out(&self, endpoint: usize);
+
+    /// Signal resume to a host that suspended the bus. This is only allowed
+    /// while suspended, and only if the host enabled remote wakeup. The
+    /// client's `resume()` is called once the bus is active again.
+    fn remote_wakeup(&self) -> Result<(), ErrorCode>;
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -53,6 +59,12 @@ pub trait Client<'a> {
     fn attach(&'a self);
     fn bus_reset(&'a self);
 
+    /// The host suspended the bus. Pending transfers stay queued until
+    /// `resume()`.
+    fn suspend(&'a self);
+    /// The bus is active again, after a host resume or a remote wakeup.
+    fn resume(&'a self);
+
     fn ctrl_setup(&'a self, endpoint: usize) -> CtrlSetupResult;
     fn ctrl_in(&'a self, endpoint: usize) -> CtrlInResult;
     fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> CtrlOutResult;
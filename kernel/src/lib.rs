@@ -96,6 +96,7 @@ pub mod debug;
 pub mod hil;
 pub mod introspection;
 pub mod ipc;
+pub mod partitions;
 pub mod syscall;
 
 mod config;
@@ -0,0 +1,400 @@
+//! Named partitions of on-chip flash, described by a table in flash.
+//!
+//! Volumes allocated with `storage_volume!` are part of the kernel image:
+//! they are erased whenever a new kernel is flashed, and nothing checks that
+//! they do not overlap flash used by other drivers. Instead, a board can
+//! reserve a flash page outside of the kernel and apps for a partition table.
+//! The table names regions of flash and the capsule that owns each of them,
+//! and is validated once at boot. It is written separately from the kernel,
+//! for example with an image generated by `tools/partition_table`, so the
+//! partitions survive kernel updates.
+//!
+//! Format
+//! ------
+//!
+//! All integers are little endian. The table starts with a 16 byte header:
+//!
+//! | Offset | Size | Field                                                  |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 4    | Magic, `TKPT`                                          |
+//! | 4      | 2    | Version, 1                                             |
+//! | 6      | 2    | Number of partitions                                   |
+//! | 8      | 4    | CRC-32 (IEEE) of the first 8 bytes and of the entries  |
+//! | 12     | 4    | Reserved                                               |
+//!
+//! It is followed by a 32 byte entry for every partition:
+//!
+//! | Offset | Size | Field                                                  |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 16   | Name, UTF-8 padded with zeros                          |
+//! | 16     | 8    | Owner, UTF-8 padded with zeros                         |
+//! | 24     | 4    | Offset from the start of flash                         |
+//! | 28     | 4    | Size in bytes                                          |
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! // Safety: the chip maps its 512 KiB of flash at address 0, and flash is
+//! // only written through the flash controller.
+//! let partitions = unsafe {
+//!     kernel::partitions::PartitionTable::new(0x0000_0000, 0x80000, 0x3F000, 0x1000)
+//! }
+//! .expect("invalid partition table");
+//! let log = partitions.lookup("log", "log").expect("no log partition");
+//! // The log reads the partition from memory and writes it through the
+//! // flash controller, at the offset of the partition in flash.
+//! let log_volume = log.region();
+//! let log_address = log.offset();
+//! ```
+
+use crate::common::math::crc32;
+
+pub const MAGIC: [u8; 4] = *b"TKPT";
+pub const VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 16;
+pub const ENTRY_LEN: usize = 32;
+pub const NAME_LEN: usize = 16;
+pub const OWNER_LEN: usize = 8;
+
+/// Why a partition table could not be used, or a partition was not found.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PartitionError {
+    /// The reserved page does not contain a partition table.
+    NoTable,
+    UnsupportedVersion,
+    BadChecksum,
+    /// A name or owner is empty, too long or not UTF-8.
+    BadName,
+    DuplicateName,
+    /// A partition is empty or does not fit in the flash.
+    OutOfBounds,
+    /// A partition overlaps another partition or the table.
+    Overlap,
+    NotFound,
+    /// The partition belongs to a different owner.
+    WrongOwner,
+}
+
+/// A validated region of flash.
+#[derive(Copy, Clone, Debug)]
+pub struct Partition {
+    name: &'static str,
+    owner: &'static str,
+    flash_start: usize,
+    offset: usize,
+    size: usize,
+}
+
+impl Partition {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn owner(&self) -> &'static str {
+        self.owner
+    }
+
+    /// Offset of the partition from the start of flash, as used by flash
+    /// controllers.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Address of the partition in memory.
+    pub fn address(&self) -> usize {
+        self.flash_start + self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The memory-mapped contents of the partition.
+    pub fn region(&self) -> &'static [u8] {
+        // The table was checked to only contain partitions inside of the
+        // flash that the board declared to be mapped at `flash_start`.
+        unsafe { core::slice::from_raw_parts(self.address() as *const u8, self.size) }
+    }
+}
+
+pub struct PartitionTable {
+    flash_start: usize,
+    entries: &'static [u8],
+}
+
+impl PartitionTable {
+    /// Validate the partition table stored in the `table_size` bytes at
+    /// `table_offset` in the flash.
+    ///
+    /// # Safety
+    ///
+    /// `flash_size` bytes of flash must be mapped at `flash_start` for the
+    /// lifetime of the kernel, and must not be modified except through flash
+    /// controllers. The table and the partitions are read through `'static`
+    /// slices into that memory, so it must never be unmapped or written
+    /// through a Rust reference. Writes through a flash controller do not
+    /// invalidate the slices, but change what they read.
+    pub unsafe fn new(
+        flash_start: usize,
+        flash_size: usize,
+        table_offset: usize,
+        table_size: usize,
+    ) -> Result<PartitionTable, PartitionError> {
+        match table_offset.checked_add(table_size) {
+            Some(end) if end <= flash_size && table_size >= HEADER_LEN => {}
+            _ => return Err(PartitionError::OutOfBounds),
+        }
+        let table =
+            core::slice::from_raw_parts((flash_start + table_offset) as *const u8, table_size);
+
+        if table[0..4] != MAGIC {
+            return Err(PartitionError::NoTable);
+        }
+        if u16::from_le_bytes([table[4], table[5]]) != VERSION {
+            return Err(PartitionError::UnsupportedVersion);
+        }
+        let count = u16::from_le_bytes([table[6], table[7]]) as usize;
+        let entries_end = HEADER_LEN + count * ENTRY_LEN;
+        if entries_end > table_size {
+            return Err(PartitionError::NoTable);
+        }
+        let entries = &table[HEADER_LEN..entries_end];
+        if !crc32(crc32(0xFFFF_FFFF, &table[0..8]), entries) != read_u32(table, 8) {
+            return Err(PartitionError::BadChecksum);
+        }
+
+        let partitions = PartitionTable {
+            flash_start,
+            entries,
+        };
+        for (i, entry) in entries.chunks_exact(ENTRY_LEN).enumerate() {
+            let partition = partitions.parse(entry)?;
+            match partition.offset.checked_add(partition.size) {
+                Some(end) if partition.size > 0 && end <= flash_size => {}
+                _ => return Err(PartitionError::OutOfBounds),
+            }
+            // A partition at address 0 could not be turned into a slice.
+            if partition.address() == 0 {
+                return Err(PartitionError::OutOfBounds);
+            }
+            if overlaps(&partition, table_offset, table_size) {
+                return Err(PartitionError::Overlap);
+            }
+            for other in partitions.iter().take(i) {
+                if other.name == partition.name {
+                    return Err(PartitionError::DuplicateName);
+                }
+                if overlaps(&partition, other.offset, other.size) {
+                    return Err(PartitionError::Overlap);
+                }
+            }
+        }
+        Ok(partitions)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Partition> + '_ {
+        self.entries
+            .chunks_exact(ENTRY_LEN)
+            .filter_map(move |entry| self.parse(entry).ok())
+    }
+
+    /// Find the partition called `name`, which must belong to `owner`.
+    pub fn lookup(&self, name: &str, owner: &str) -> Result<Partition, PartitionError> {
+        let partition = self
+            .iter()
+            .find(|partition| partition.name == name)
+            .ok_or(PartitionError::NotFound)?;
+        if partition.owner != owner {
+            return Err(PartitionError::WrongOwner);
+        }
+        Ok(partition)
+    }
+
+    fn parse(&self, entry: &'static [u8]) -> Result<Partition, PartitionError> {
+        Ok(Partition {
+            name: parse_name(&entry[0..NAME_LEN])?,
+            owner: parse_name(&entry[NAME_LEN..NAME_LEN + OWNER_LEN])?,
+            flash_start: self.flash_start,
+            offset: read_u32(entry, 24) as usize,
+            size: read_u32(entry, 28) as usize,
+        })
+    }
+}
+
+fn parse_name(field: &'static [u8]) -> Result<&'static str, PartitionError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    if len == 0 || field[len..].iter().any(|&b| b != 0) {
+        return Err(PartitionError::BadName);
+    }
+    core::str::from_utf8(&field[..len]).map_err(|_| PartitionError::BadName)
+}
+
+fn overlaps(partition: &Partition, offset: usize, size: usize) -> bool {
+    partition.offset < offset + size && offset < partition.offset + partition.size
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    const FLASH_SIZE: usize = 0x1000;
+    const TABLE_OFFSET: usize = 0xF00;
+    const TABLE_SIZE: usize = 0x100;
+
+    fn entry(name: &str, owner: &str, offset: u32, size: u32) -> Vec<u8> {
+        let mut entry = vec![0; ENTRY_LEN];
+        entry[..name.len()].copy_from_slice(name.as_bytes());
+        entry[NAME_LEN..NAME_LEN + owner.len()].copy_from_slice(owner.as_bytes());
+        entry[24..28].copy_from_slice(&offset.to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    fn table(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut table = Vec::new();
+        table.extend_from_slice(&MAGIC);
+        table.extend_from_slice(&VERSION.to_le_bytes());
+        table.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        table.extend_from_slice(&[0; 8]);
+        entries
+            .iter()
+            .for_each(|entry| table.extend_from_slice(entry));
+        let crc = !crc32(crc32(0xFFFF_FFFF, &table[0..8]), &table[HEADER_LEN..]);
+        table[8..12].copy_from_slice(&crc.to_le_bytes());
+        table
+    }
+
+    /// Validate `table` in a flash whose bytes are their offset.
+    fn load(table: &[u8]) -> Result<PartitionTable, PartitionError> {
+        let flash: &'static mut [u8] = Box::leak(
+            (0..FLASH_SIZE)
+                .map(|i| i as u8)
+                .collect::<Vec<u8>>()
+                .into_boxed_slice(),
+        );
+        flash[TABLE_OFFSET..TABLE_OFFSET + table.len()].copy_from_slice(table);
+        unsafe {
+            PartitionTable::new(
+                flash.as_ptr() as usize,
+                FLASH_SIZE,
+                TABLE_OFFSET,
+                TABLE_SIZE,
+            )
+        }
+    }
+
+    fn error(entries: &[Vec<u8>]) -> Option<PartitionError> {
+        load(&table(entries)).err()
+    }
+
+    #[test]
+    fn valid_table() {
+        let partitions = load(&table(&[
+            entry("log", "log", 0x100, 0x100),
+            entry("storage", "ftl", 0x200, 0x400),
+        ]))
+        .unwrap();
+        assert_eq!(partitions.iter().count(), 2);
+
+        let log = partitions.lookup("log", "log").unwrap();
+        assert_eq!((log.offset(), log.size()), (0x100, 0x100));
+        assert_eq!(&log.region()[..3], &[0, 1, 2]);
+        assert_eq!(
+            partitions.lookup("storage", "log").err(),
+            Some(PartitionError::WrongOwner)
+        );
+        assert_eq!(
+            partitions.lookup("missing", "log").err(),
+            Some(PartitionError::NotFound)
+        );
+    }
+
+    #[test]
+    fn overlap() {
+        assert_eq!(
+            error(&[entry("a", "x", 0x100, 0x100), entry("b", "x", 0x1FF, 0x100),]),
+            Some(PartitionError::Overlap)
+        );
+        assert_eq!(
+            error(&[entry("a", "x", 0xE00, 0x101)]),
+            Some(PartitionError::Overlap)
+        );
+        // Adjacent partitions do not overlap
+        assert_eq!(
+            error(&[
+                entry("a", "x", 0x100, 0x100),
+                entry("b", "x", 0x200, 0x100),
+                entry("c", "x", 0xE00, 0x100),
+            ]),
+            None
+        );
+    }
+
+    #[test]
+    fn duplicate_name() {
+        assert_eq!(
+            error(&[
+                entry("log", "log", 0x100, 0x100),
+                entry("log", "ftl", 0x200, 0x100),
+            ]),
+            Some(PartitionError::DuplicateName)
+        );
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut bytes = table(&[entry("log", "log", 0x100, 0x100)]);
+        bytes[HEADER_LEN + 24] ^= 0x01;
+        assert_eq!(load(&bytes).err(), Some(PartitionError::BadChecksum));
+
+        let mut bytes = table(&[entry("log", "log", 0x100, 0x100)]);
+        bytes[8] ^= 0x01;
+        assert_eq!(load(&bytes).err(), Some(PartitionError::BadChecksum));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        assert_eq!(
+            error(&[entry("log", "log", 0x100, 0)]),
+            Some(PartitionError::OutOfBounds)
+        );
+        assert_eq!(
+            error(&[entry("log", "log", 0x100, FLASH_SIZE as u32)]),
+            Some(PartitionError::OutOfBounds)
+        );
+        assert_eq!(
+            error(&[entry("log", "log", u32::MAX, 0x100)]),
+            Some(PartitionError::OutOfBounds)
+        );
+        // More entries than fit in the table
+        let mut bytes = table(&[entry("log", "log", 0x100, 0x100)]);
+        bytes[6] = ((TABLE_SIZE - HEADER_LEN) / ENTRY_LEN + 1) as u8;
+        assert_eq!(load(&bytes).err(), Some(PartitionError::NoTable));
+    }
+
+    #[test]
+    fn bad_names() {
+        assert_eq!(
+            error(&[entry("", "log", 0x100, 0x100)]),
+            Some(PartitionError::BadName)
+        );
+        let mut bad = entry("log", "log", 0x100, 0x100);
+        bad[1] = 0xFF;
+        assert_eq!(error(&[bad]), Some(PartitionError::BadName));
+    }
+}
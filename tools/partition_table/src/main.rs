@@ -0,0 +1,336 @@
+//! Generates and inspects the flash partition tables read by
+//! `kernel::partitions`.
+//!
+//! The generated image is the size of the page that the board reserves for
+//! the table, and is flashed at the start of that page.
+
+use std::fs;
+use std::process::exit;
+
+use kernel::common::math::crc32;
+use kernel::partitions::{ENTRY_LEN, HEADER_LEN, MAGIC, NAME_LEN, OWNER_LEN, VERSION};
+
+const USAGE: &str = "Usage:
+  partition_table generate [OPTIONS] <OUTPUT> <NAME:OWNER:OFFSET:SIZE>...
+  partition_table inspect [OPTIONS] <INPUT>
+
+Options:
+  --page-size <SIZE>     Size of the generated image (default 4096)
+  --flash-size <SIZE>    Check that partitions fit in the flash
+  --table-offset <OFF>   Check that partitions do not overlap the table
+
+Sizes and offsets are decimal or hexadecimal with a 0x prefix, and may end
+with K or M.
+
+Examples:
+  partition_table generate --page-size 0x1000 table.bin log:log:0x40000:32K
+  partition_table inspect --flash-size 512K --table-offset 0x3F000 table.bin";
+
+struct Partition {
+    name: String,
+    owner: String,
+    offset: u32,
+    size: u32,
+}
+
+struct Options {
+    page_size: usize,
+    flash_size: Option<u64>,
+    table_offset: Option<u64>,
+    args: Vec<String>,
+}
+
+/// Prints an error message and the usage string, and exits.
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    exit(1);
+}
+
+fn parse_number(text: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match text.chars().last() {
+        Some('K') | Some('k') => (&text[..text.len() - 1], 1024),
+        Some('M') | Some('m') => (&text[..text.len() - 1], 1024 * 1024),
+        _ => (text, 1),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        digits.parse()
+    };
+    value
+        .ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid number `{}`", text))
+}
+
+fn parse_options(args: Vec<String>) -> Options {
+    let mut options = Options {
+        page_size: 4096,
+        flash_size: None,
+        table_offset: None,
+        args: Vec::new(),
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next().map(|value| parse_number(&value)) {
+            Some(Ok(value)) => value,
+            Some(Err(message)) => usage_error(&message),
+            None => usage_error(&format!("missing value for {}", arg)),
+        };
+        match arg.as_str() {
+            "--page-size" => options.page_size = value() as usize,
+            "--flash-size" => options.flash_size = Some(value()),
+            "--table-offset" => options.table_offset = Some(value()),
+            _ if arg.starts_with("--") => usage_error(&format!("unknown option {}", arg)),
+            _ => options.args.push(arg),
+        }
+    }
+    options
+}
+
+fn parse_partition(text: &str) -> Result<Partition, String> {
+    let fields: Vec<&str> = text.split(':').collect();
+    if fields.len() != 4 {
+        return Err(format!("expected NAME:OWNER:OFFSET:SIZE, got `{}`", text));
+    }
+    let offset = parse_number(fields[2])?;
+    let size = parse_number(fields[3])?;
+    if offset > u32::MAX as u64 || size > u32::MAX as u64 {
+        return Err(format!("`{}` does not fit in 32 bits", text));
+    }
+    Ok(Partition {
+        name: fields[0].to_string(),
+        owner: fields[1].to_string(),
+        offset: offset as u32,
+        size: size as u32,
+    })
+}
+
+/// The same checks as `PartitionTable::new` in the kernel.
+fn check(partitions: &[Partition], options: &Options, table_size: usize) -> Vec<String> {
+    let mut problems = Vec::new();
+    let table = options
+        .table_offset
+        .map(|offset| (offset, table_size as u64));
+    for (i, p) in partitions.iter().enumerate() {
+        let (start, end) = (p.offset as u64, p.offset as u64 + p.size as u64);
+        if p.name.is_empty() || p.name.len() > NAME_LEN || p.name.contains('\0') {
+            problems.push(format!("{}: name must be 1 to {} bytes", p.name, NAME_LEN));
+        }
+        if p.owner.is_empty() || p.owner.len() > OWNER_LEN || p.owner.contains('\0') {
+            problems.push(format!(
+                "{}: owner must be 1 to {} bytes",
+                p.name, OWNER_LEN
+            ));
+        }
+        if p.size == 0 {
+            problems.push(format!("{}: partition is empty", p.name));
+        }
+        if let Some(flash_size) = options.flash_size {
+            if end > flash_size {
+                problems.push(format!("{}: partition ends after the flash", p.name));
+            }
+        }
+        if let Some((offset, size)) = table {
+            if start < offset + size && offset < end {
+                problems.push(format!("{}: partition overlaps the table", p.name));
+            }
+        }
+        for other in &partitions[..i] {
+            if other.name == p.name {
+                problems.push(format!("{}: duplicate name", p.name));
+            }
+            if start < other.offset as u64 + other.size as u64 && (other.offset as u64) < end {
+                problems.push(format!("{}: partition overlaps {}", p.name, other.name));
+            }
+        }
+    }
+    problems
+}
+
+fn encode(partitions: &[Partition], page_size: usize) -> Vec<u8> {
+    let mut image = Vec::with_capacity(page_size);
+    image.extend_from_slice(&MAGIC);
+    image.extend_from_slice(&VERSION.to_le_bytes());
+    image.extend_from_slice(&(partitions.len() as u16).to_le_bytes());
+    image.extend_from_slice(&[0; 4]);
+    image.extend_from_slice(&[0xFF; 4]);
+    for p in partitions {
+        let mut name = [0; NAME_LEN];
+        name[..p.name.len()].copy_from_slice(p.name.as_bytes());
+        let mut owner = [0; OWNER_LEN];
+        owner[..p.owner.len()].copy_from_slice(p.owner.as_bytes());
+        image.extend_from_slice(&name);
+        image.extend_from_slice(&owner);
+        image.extend_from_slice(&p.offset.to_le_bytes());
+        image.extend_from_slice(&p.size.to_le_bytes());
+    }
+    let crc = !crc32(crc32(0xFFFF_FFFF, &image[0..8]), &image[HEADER_LEN..]);
+    image[8..12].copy_from_slice(&crc.to_le_bytes());
+    image.resize(page_size, 0xFF);
+    image
+}
+
+fn decode(image: &[u8]) -> Result<Vec<Partition>, String> {
+    if image.len() < HEADER_LEN || image[0..4] != MAGIC {
+        return Err("not a partition table".to_string());
+    }
+    let read_u32 = |bytes: &[u8], at: usize| {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    };
+    let version = u16::from_le_bytes([image[4], image[5]]);
+    if version != VERSION {
+        return Err(format!("unsupported version {}", version));
+    }
+    let count = u16::from_le_bytes([image[6], image[7]]) as usize;
+    let end = HEADER_LEN + count * ENTRY_LEN;
+    if end > image.len() {
+        return Err(format!("{} partitions do not fit in the image", count));
+    }
+    let crc = !crc32(crc32(0xFFFF_FFFF, &image[0..8]), &image[HEADER_LEN..end]);
+    if crc != read_u32(image, 8) {
+        return Err(format!(
+            "checksum is {:#010x}, expected {:#010x}",
+            read_u32(image, 8),
+            crc
+        ));
+    }
+    let text = |field: &[u8]| {
+        let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+        String::from_utf8_lossy(&field[..len]).into_owned()
+    };
+    Ok(image[HEADER_LEN..end]
+        .chunks_exact(ENTRY_LEN)
+        .map(|entry| Partition {
+            name: text(&entry[0..NAME_LEN]),
+            owner: text(&entry[NAME_LEN..NAME_LEN + OWNER_LEN]),
+            offset: read_u32(entry, 24),
+            size: read_u32(entry, 28),
+        })
+        .collect())
+}
+
+fn generate(options: &Options) {
+    if options.args.len() < 2 {
+        usage_error("generate needs an output file and at least one partition");
+    }
+    let partitions: Vec<Partition> = options.args[1..]
+        .iter()
+        .map(|arg| parse_partition(arg).unwrap_or_else(|message| usage_error(&message)))
+        .collect();
+    if HEADER_LEN + partitions.len() * ENTRY_LEN > options.page_size {
+        fail("too many partitions for the page size");
+    }
+    let problems = check(&partitions, options, options.page_size);
+    if !problems.is_empty() {
+        fail(&problems.join("\n       "));
+    }
+    let image = encode(&partitions, options.page_size);
+    if let Err(e) = fs::write(&options.args[0], image) {
+        fail(&format!("cannot write {}: {}", options.args[0], e));
+    }
+}
+
+fn inspect(options: &Options) {
+    if options.args.len() != 1 {
+        usage_error("inspect needs one input file");
+    }
+    let image = fs::read(&options.args[0])
+        .unwrap_or_else(|e| fail(&format!("cannot read {}: {}", options.args[0], e)));
+    let partitions = decode(&image).unwrap_or_else(|message| fail(&message));
+
+    println!(
+        "{:<16} {:<8} {:>10} {:>10} {:>10}",
+        "NAME", "OWNER", "OFFSET", "END", "SIZE"
+    );
+    for p in &partitions {
+        println!(
+            "{:<16} {:<8} {:#10x} {:#10x} {:>10}",
+            p.name,
+            p.owner,
+            p.offset,
+            p.offset as u64 + p.size as u64,
+            p.size
+        );
+    }
+    let problems = check(&partitions, options, image.len());
+    for problem in &problems {
+        println!("problem: {}", problem);
+    }
+    if !problems.is_empty() {
+        exit(1);
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+    let options = parse_options(args.collect());
+    match command.as_deref() {
+        Some("generate") => generate(&options),
+        Some("inspect") => inspect(&options),
+        Some(command) => usage_error(&format!("unknown command {}", command)),
+        None => usage_error("missing command"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::partitions::PartitionTable;
+
+    const FLASH_SIZE: usize = 0x10000;
+    const TABLE_OFFSET: usize = 0xF000;
+    const PAGE_SIZE: usize = 0x1000;
+
+    #[test]
+    fn generated_table_is_accepted_by_the_kernel() {
+        let args = [
+            "log:log:0x1000:8K",
+            "storage:ftl:0x3000:0x4000",
+            "keys:hmac:56K:1K",
+        ];
+        let partitions: Vec<Partition> = args
+            .iter()
+            .map(|arg| parse_partition(arg).unwrap())
+            .collect();
+        let options = Options {
+            page_size: PAGE_SIZE,
+            flash_size: Some(FLASH_SIZE as u64),
+            table_offset: Some(TABLE_OFFSET as u64),
+            args: Vec::new(),
+        };
+        assert!(check(&partitions, &options, PAGE_SIZE).is_empty());
+        let image = encode(&partitions, PAGE_SIZE);
+        assert_eq!(image.len(), PAGE_SIZE);
+
+        let flash: &'static mut [u8] = Box::leak(vec![0xFF; FLASH_SIZE].into_boxed_slice());
+        flash[TABLE_OFFSET..TABLE_OFFSET + PAGE_SIZE].copy_from_slice(&image);
+        let table = unsafe {
+            PartitionTable::new(flash.as_ptr() as usize, FLASH_SIZE, TABLE_OFFSET, PAGE_SIZE)
+        }
+        .unwrap();
+        for p in &partitions {
+            let found = table.lookup(&p.name, &p.owner).unwrap();
+            assert_eq!(
+                (found.offset(), found.size()),
+                (p.offset as usize, p.size as usize)
+            );
+        }
+        assert_eq!(table.iter().count(), partitions.len());
+
+        let decoded = decode(&image).unwrap();
+        assert_eq!(decoded.len(), partitions.len());
+        for (decoded, p) in decoded.iter().zip(&partitions) {
+            assert_eq!(
+                (&decoded.name, &decoded.owner, decoded.offset, decoded.size),
+                (&p.name, &p.owner, p.offset, p.size)
+            );
+        }
+    }
+}